@@ -0,0 +1,94 @@
+#![no_main]
+
+#[path = "../../tests/support/mod.rs"]
+mod support;
+
+use libfuzzer_sys::arbitrary::{Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+use support::{Case, EXCLUDES, Entry, Flags, Kind, MAX_ENTRIES, NAMES, Sources, Target};
+
+/// The same cases as the property tests, read from the fuzzer's bytes.
+fn case(u: &mut Unstructured) -> Result<Case> {
+    Ok(Case {
+        trees: [tree(u)?, tree(u)?],
+        flags: flags(u)?,
+        sources: sources(u)?,
+    })
+}
+
+fn tree(u: &mut Unstructured) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for _ in 0..u.int_in_range(1..=MAX_ENTRIES)? {
+        let kind = match u.int_in_range(0..=3)? {
+            0 => Kind::File(u.arbitrary()?),
+            1 => Kind::Dir,
+            2 => Kind::HardLink(u.arbitrary()?),
+            _ => Kind::Symlink(match u.int_in_range(0..=4)? {
+                0 => Target::Missing,
+                1 => Target::Up,
+                2 => Target::Root,
+                3 => Target::Dir(u.arbitrary()?),
+                _ => Target::File(u.arbitrary()?),
+            }),
+        };
+        entries.push(Entry {
+            parent: u.arbitrary()?,
+            name: u.choose_index(NAMES.len())?,
+            kind,
+        });
+    }
+    Ok(entries)
+}
+
+fn flags(u: &mut Unstructured) -> Result<Flags> {
+    let mut excludes = Vec::new();
+    for _ in 0..u.int_in_range(0..=2)? {
+        excludes.push(u.choose_index(EXCLUDES.len())?);
+    }
+    Ok(Flags {
+        follow: u.arbitrary()?,
+        collision: u.arbitrary()?,
+        order: u.arbitrary()?,
+        parents: u.arbitrary()?,
+        flatten: u.arbitrary()?,
+        itemize: u.arbitrary()?,
+        links: u.arbitrary()?,
+        reproducible: u.arbitrary()?,
+        link_mode: u.arbitrary()?,
+        excludes,
+    })
+}
+
+fn sources(u: &mut Unstructured) -> Result<Sources> {
+    let name = |u: &mut Unstructured| Ok((u.choose_index(2)?, u.choose_index(NAMES.len())?));
+    Ok(match u.int_in_range(0..=2)? {
+        0 => Sources::Tree,
+        1 => {
+            let (tree, name) = name(u)?;
+            Sources::One { tree, name }
+        }
+        _ => {
+            let mut names = Vec::new();
+            for _ in 0..u.int_in_range(1..=5)? {
+                names.push(name(u)?);
+            }
+            Sources::Several(names)
+        }
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(case) = case(&mut Unstructured::new(data)) else {
+        return;
+    };
+    let temp = tempfile::TempDir::new().unwrap();
+    if let Some((plan, dest, options)) = case.plan(temp.path()) {
+        let violations = support::violations(&plan, temp.path(), &dest, &options);
+        assert!(
+            violations.is_empty(),
+            "{:?} planned wrongly:\n  {}",
+            case,
+            violations.join("\n  ")
+        );
+    }
+});
@@ -1,18 +1,41 @@
+use crate::cli::clean::CleanArgs;
+use crate::cli::doctor::DoctorArgs;
+use crate::cli::mv::MoveArgs;
+use crate::cli::status::StatusArgs;
+use crate::cli::undo::UndoArgs;
+use crate::cli::verify::VerifyArgs;
 use crate::config::config_command::ConfigCommand;
 use crate::config::loader::{load_config, load_config_file};
 use crate::config::schema::Config;
-use crate::error::{CpxError, CpxResult};
-use crate::utility::helper::parse_progress_bar;
-use crate::utility::progress_bar::ProgressOptions;
+use crate::core::bwlimit::BandwidthLimit;
+use crate::error::{CopyError, CpxError, CpxResult};
+use crate::utility::helper::{parse_progress_bar, strip_trailing_slashes};
+use crate::utility::progress_bar::{ProgressMode, ProgressOptions};
 use crate::utility::{
+    background, backup,
+    chmod::ModeSpec,
+    confine,
     exclude::{ExcludePattern, ExcludeRules, build_exclude_rules, parse_exclude_pattern_list},
-    helper::{parse_backup_mode, parse_follow_symlink, parse_reflink_mode, parse_symlink_mode},
-    preserve::PreserveAttr,
+    files_from::read_files_from,
+    filesystem::{LinkSupport, link_support, shared_device},
+    helper::{
+        has_trailing_slash, parse_backup_mode, parse_engine, parse_follow_symlink,
+        parse_reflink_mode, parse_symlink_mode,
+    },
+    idmap::{IdMap, OwnerMap},
+    itemize::{self, Item},
+    memory,
+    network::{self, NetworkPath, find_network_path},
+    output,
+    preserve::{self, AttrOverrides, PreserveAttr},
 };
 use clap::{Args, Parser, Subcommand, ValueEnum};
-use std::path::PathBuf;
+use indicatif::HumanBytes;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 pub enum SymlinkMode {
@@ -30,6 +53,125 @@ pub enum ReflinkMode {
     Never,
 }
 
+/// How file data is copied; see [`crate::core::engine`].
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+pub enum Engine {
+    /// The fastest method that works for each file.
+    Auto,
+    /// copy_file_range, on Linux.
+    Offload,
+    /// Reads and writes through a buffer.
+    Buffered,
+    /// Writes from a memory map of the source, on Unix.
+    Mmap,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum SparseMode {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum LinkFallback {
+    CopyTarget,
+    Skip,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum CopyOrder {
+    Size,
+    Name,
+    None,
+}
+
+/// What to do when several sources would land on the same destination path,
+/// as with `cpx a/report.pdf b/report.pdf /archive/`.
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum CollisionPolicy {
+    /// Refuse to copy, listing the colliding sources.
+    Error,
+    /// Keep the first name and number the rest: report.pdf, report-1.pdf.
+    Number,
+    /// Copy only the source given first.
+    Skip,
+    /// Copy only the source given last.
+    LastWins,
+}
+
+/// What became of `--preserve=ownership`, from [`CopyOptions::check_ownership`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OwnershipCheck {
+    /// Preserved as asked, or not asked for.
+    Honored,
+    /// Kept only where the system lets an unprivileged user set it.
+    BestEffort,
+    /// The same, but preserved by default rather than asked for on the
+    /// command line, which is not worth a warning on every run.
+    Defaulted,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum InteractiveMode {
+    /// Ask about every file that would be replaced.
+    Files,
+    /// Ask once per directory whether to copy it.
+    Dirs,
+    /// Ask about every file that would be replaced, as `files` does.
+    Always,
+    /// Ask once, before copying, whether to replace what is there.
+    Once,
+    /// Never ask, even when the config turns prompts on.
+    Never,
+}
+
+/// When `-i` asks before replacing files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Prompt {
+    #[default]
+    Never,
+    /// One question about all of them, before copying.
+    Once,
+    /// A question about each one.
+    Always,
+}
+
+/// When `--notify-cmd` and `--notify-desktop` fire.
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum NotifyOn {
+    /// Whenever a copy ends.
+    Always,
+    /// Only when it didn't copy everything.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum StallAction {
+    Warn,
+    Skip,
+}
+
+/// What workers do when the destination runs out of space.
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum OnFull {
+    Abort,
+    Pause,
+    Skip,
+}
+
+/// Which existing destination files `--update` replaces.
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+pub enum UpdateMode {
+    /// Every one, as without `--update`.
+    All,
+    /// None; they are left alone without a word, as with `-n`.
+    None,
+    /// Those older than their source.
+    Older,
+}
+
 #[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
 pub enum BackupMode {
     None,
@@ -45,16 +187,46 @@ pub enum FollowSymlink {
     CommandLineSymlink,
 }
 
+impl FollowSymlink {
+    /// Whether a symlink is followed: one named on the command line when
+    /// `command_line`, otherwise one found inside a directory being copied.
+    pub fn follows(self, command_line: bool) -> bool {
+        match self {
+            Self::NoDereference => false,
+            Self::Dereference => true,
+            Self::CommandLineSymlink => command_line,
+        }
+    }
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Default (Implicit)
-    Copy(CopyArgs),
+    Copy(Box<CopyArgs>),
 
     /// Manage configuration
     Config {
         #[command(subcommand)]
         command: ConfigCommand,
     },
+
+    /// Probe which fast paths the given source and destination support
+    Doctor(DoctorArgs),
+
+    /// Find and remove temp and partial files left by interrupted runs
+    Clean(CleanArgs),
+
+    /// Move files and directories, with mv-compatible defaults
+    Move(MoveArgs),
+
+    /// Check copied files against the checksums stored with them
+    Verify(VerifyArgs),
+
+    /// Put back what the last move moved
+    Undo(UndoArgs),
+
+    /// Show how a destination differs from its source, without copying
+    Status(StatusArgs),
 }
 
 #[derive(Parser, Debug)]
@@ -65,9 +237,15 @@ pub struct CLIArgs {
 }
 
 #[derive(Args, Debug, Clone)]
+// SOURCE may be left out for --files-from, which still needs DESTINATION,
+// and for -t DIR with one source, which then takes DESTINATION's place.
+#[command(allow_missing_positional = true)]
 pub struct CopyArgs {
     // Input/Output Options
-    #[arg(help = "Source file(s) or directory(ies)", required = true)]
+    #[arg(
+        help = "Source file(s) or directory(ies)",
+        required_unless_present_any = ["files_from", "target_directory"]
+    )]
     pub sources: Vec<PathBuf>,
 
     #[arg(help = "Destination file or directory", required = true)]
@@ -81,11 +259,67 @@ pub struct CopyArgs {
     )]
     pub target_directory: Option<PathBuf>,
 
+    #[arg(
+        short = 'T',
+        long = "no-target-directory",
+        conflicts_with = "target_directory",
+        help = "treat DESTINATION as the copy itself, never a directory to copy into"
+    )]
+    pub no_target_directory: bool,
+
+    #[arg(
+        long = "mkdir",
+        conflicts_with = "no_target_directory",
+        help = "create DESTINATION, with any missing parents, as a directory to copy into"
+    )]
+    pub mkdir: bool,
+
+    #[arg(
+        long = "files-from",
+        value_name = "FILE",
+        help = "read the paths to copy from FILE (- for stdin), one per line, \
+                instead of from SOURCE arguments; their relative paths are kept under the destination"
+    )]
+    pub files_from: Option<PathBuf>,
+
+    #[arg(
+        long = "files-from-root",
+        value_name = "DIR",
+        requires = "files_from",
+        help = "directory the --files-from paths are relative to [default: .]; \
+                absolute paths in the list must lie under it"
+    )]
+    pub files_from_root: Option<PathBuf>,
+
+    #[arg(
+        long = "also-to",
+        value_name = "DIR",
+        help = "also write everything copied to DESTINATION to the same place under DIR, \
+                reading each source once (repeatable)"
+    )]
+    pub also_to: Vec<PathBuf>,
+
+    #[arg(
+        long = "staging-dir",
+        value_name = "DIR",
+        help = "write each file under DIR first and move it into place once complete, so no \
+                partial file ever shows up at the destination"
+    )]
+    pub staging_dir: Option<PathBuf>,
+
     #[arg(
         short = 'e',
         long = "exclude",
         value_name = "PATTERN",
-        help = "Exclude files matching pattern (can be specified multiple times, supports comma-separated values)"
+        help = "Exclude files matching pattern (can be specified multiple times, supports comma-separated values)",
+        long_help = "Exclude files matching pattern (can be specified multiple times, supports comma-separated values).\n\
+                     Patterns follow gitignore rules:\n  \
+                     - without a slash (*.tmp, build) they match a name at any depth\n  \
+                     - with a slash (docs/internal) they match the path relative to the source\n  \
+                     - a leading slash (/build) anchors to the source root\n  \
+                     - a trailing slash (cache/) matches directories only\n\
+                     Excluding a directory excludes everything inside it. * does not match /; use ** for that.\n\
+                     A directory whose every entry is excluded is not created at the destination."
     )]
     pub exclude: Vec<String>,
 
@@ -95,23 +329,73 @@ pub struct CopyArgs {
 
     #[arg(
         short = 'j',
-        default_value_t = 4,
-        help = "Number of parallel copy operations for multiple files"
+        help = "Number of parallel copy operations for multiple files [default: 4]"
     )]
-    pub parallel: usize,
+    pub parallel: Option<usize>,
 
     #[arg(long = "resume", help = "resume interrupted transfers")]
     pub resume: bool,
 
+    #[arg(
+        long = "fix-attrs",
+        requires = "resume",
+        help = "with --resume, set the preserved attributes again on files skipped as \
+                complete whose mode, owner or mtime no longer match the source"
+    )]
+    pub fix_attrs: bool,
+
     #[arg(
         short = 'f',
         long,
-        help = "if an existing destination file cannot be opened, remove it and try again"
+        help = "if an existing destination file cannot be opened, remove it and try again; \
+                also replace a symlink at the destination"
     )]
     pub force: bool,
 
-    #[arg(short = 'i', long, help = "prompt before overwrite")]
-    pub interactive: bool,
+    #[arg(
+        short = 'i',
+        long = "interactive",
+        value_name = "WHEN",
+        value_delimiter = ',',
+        default_missing_value = "files",
+        num_args = 0..=1,
+        require_equals = true,
+        action = clap::ArgAction::Append,
+        help = "prompt before each overwrite (files, always), once before copying (once), \
+                not at all (never), or once per directory (dirs); dirs combines with all but never"
+    )]
+    pub interactive: Vec<InteractiveMode>,
+
+    #[arg(
+        long = "interactive-depth",
+        value_name = "N",
+        help = "with --interactive=dirs, ask about directories this many levels below each source [default: 1]"
+    )]
+    pub interactive_depth: Option<usize>,
+
+    #[arg(
+        short = 'n',
+        long = "no-clobber",
+        help = "never overwrite an existing destination; wins over -f, -i and --remove-destination"
+    )]
+    pub no_clobber: bool,
+
+    #[arg(
+        short = 'u',
+        long,
+        value_name = "WHEN",
+        default_missing_value = "older",
+        num_args = 0..=1,
+        require_equals = true,
+        help = "which existing destination files to replace: all, none or older [default: older]"
+    )]
+    pub update: Option<UpdateMode>,
+
+    #[arg(
+        long,
+        help = "only replace files already at the destination; create nothing new there"
+    )]
+    pub existing: bool,
 
     #[arg(long, help = "use full source file name under DIRECTORY")]
     pub parents: bool,
@@ -128,6 +412,107 @@ pub struct CopyArgs {
     )]
     pub remove_destination: bool,
 
+    #[arg(
+        long,
+        help = "don't update the access time of source files while reading them"
+    )]
+    pub noatime: bool,
+
+    #[arg(
+        long,
+        help = "go easy on the rest of the system: lowest CPU and idle I/O priority, -j 2, \
+                copied files dropped from the page cache, fewer progress redraws \
+                (-j still sets the parallelism)"
+    )]
+    pub background: bool,
+
+    #[arg(
+        long,
+        help = "after copying, keep watching SOURCE and copy files as they change"
+    )]
+    pub watch: bool,
+
+    #[arg(
+        long = "order",
+        value_name = "ORDER",
+        help = "order in which files are started (size: largest first [default], name, none)"
+    )]
+    pub order: Option<CopyOrder>,
+
+    #[arg(
+        long = "on-collision",
+        value_name = "POLICY",
+        help = "when several sources, or files gathered by --flatten, would be copied \
+                to the same path (error [default], number, skip, last-wins)"
+    )]
+    pub on_collision: Option<CollisionPolicy>,
+
+    #[arg(
+        long = "flatten",
+        help = "copy the files found under the sources straight into DESTINATION, \
+                without the directories they were in"
+    )]
+    pub flatten: bool,
+
+    #[arg(
+        long = "skip-oversize",
+        help = "skip files larger than the destination filesystem can hold \
+                (e.g. 4 GiB on FAT32) instead of refusing to start"
+    )]
+    pub skip_oversize: bool,
+
+    #[arg(
+        long = "compare-write",
+        help = "when a destination file exists with the same size, write only the chunks \
+                that differ (takes precedence over --sparse for those files)"
+    )]
+    pub compare_write: bool,
+
+    #[arg(
+        long = "store-checksum",
+        help = "store each copied file's checksum and source mtime in its user.cpx.* \
+                extended attributes, for a later `cpx verify --stored`"
+    )]
+    pub store_checksum: bool,
+
+    #[arg(
+        long = "copy-contents",
+        help = "read pipes and devices found while copying recursively into regular files, \
+                instead of recreating them"
+    )]
+    pub copy_contents: bool,
+
+    #[arg(
+        long = "max-bytes",
+        value_name = "SIZE",
+        value_parser = parse_byte_size,
+        help = "stop handing out files once copying the next would take the run past SIZE \
+                bytes (e.g. 2T), letting those started finish; a pipe or device is read \
+                up to SIZE bytes, for sources like /dev/zero that never end"
+    )]
+    pub max_bytes: Option<u64>,
+
+    #[arg(
+        long = "max-files",
+        value_name = "N",
+        help = "stop handing out files after N of them, letting those started finish"
+    )]
+    pub max_files: Option<usize>,
+
+    #[arg(
+        long = "strip-trailing-slashes",
+        help = "remove trailing slashes from each SOURCE, so a symlink named as link/ is \
+                copied as a link rather than followed"
+    )]
+    pub strip_trailing_slashes: bool,
+
+    #[arg(
+        short = 'x',
+        long = "one-file-system",
+        help = "stay on the filesystem of each SOURCE: directories on others are made empty"
+    )]
+    pub one_file_system: bool,
+
     // Link and Symlink Options
     #[arg(
         short = 's',
@@ -146,10 +531,16 @@ pub struct CopyArgs {
     )]
     pub hard_link: bool,
 
+    #[arg(
+        long = "prefer-hardlink",
+        help = "hard link files on the destination's filesystem, copying the rest"
+    )]
+    pub prefer_hardlink: bool,
+
     #[arg(
         short = 'P',
         long = "no-dereference",
-        help = "never follow symbolic links in SOURCE"
+        help = "never follow symbolic links in SOURCE, and replace those at the destination"
     )]
     pub no_dereference: bool,
 
@@ -167,7 +558,34 @@ pub struct CopyArgs {
     )]
     pub dereference_command_line: bool,
 
+    #[arg(
+        short = 'd',
+        help = "same as -P --preserve=links; -L or -H still decide which links are followed"
+    )]
+    pub no_dereference_preserve_links: bool,
+
+    #[arg(
+        long = "symlink-fallback",
+        value_name = "POLICY",
+        help = "when the destination can't store symlinks (copy-target [default], skip, error)"
+    )]
+    pub symlink_fallback: Option<LinkFallback>,
+
+    #[arg(
+        long = "link-fallback",
+        value_name = "POLICY",
+        help = "when the destination can't store hard links (copy-target [default], skip, error)"
+    )]
+    pub link_fallback: Option<LinkFallback>,
+
     // Preservation Options
+    #[arg(
+        short = 'a',
+        long = "archive",
+        help = "same as -r -P --preserve=all; --preserve and --no-preserve add to and take from it"
+    )]
+    pub archive: bool,
+
     #[arg(
         short = 'p',
         long = "preserve",
@@ -177,6 +595,114 @@ pub struct CopyArgs {
     )]
     pub preserve: Option<String>,
 
+    #[arg(
+        long = "no-preserve",
+        value_name = "ATTR_LIST",
+        help = "don't preserve the specified attributes, even those -a or the config preserve"
+    )]
+    pub no_preserve: Option<String>,
+
+    #[arg(
+        short = 'Z',
+        long = "context",
+        help = "give each copy the default SELinux context for where it lands rather than the \
+                source's"
+    )]
+    pub context: bool,
+
+    #[arg(
+        long = "strict-preserve",
+        help = "refuse to start if an attribute to preserve can't be set, such as ownership \
+                without root or CAP_CHOWN, instead of preserving it where possible"
+    )]
+    pub strict_preserve: bool,
+
+    #[arg(
+        long = "keep-dest-attrs",
+        help = "keep the owner, group and mode of files that are overwritten, replacing only \
+                their contents"
+    )]
+    pub keep_dest_attrs: bool,
+
+    #[arg(
+        long = "timestamp",
+        value_name = "TIME",
+        value_parser = parse_timestamp,
+        help = "set the modification time of every copied file and created directory to TIME \
+                (RFC 3339 in UTC, e.g. 2024-01-01T00:00:00Z, or @SECONDS since the epoch)"
+    )]
+    pub timestamp: Option<SystemTime>,
+
+    #[arg(
+        long = "reproducible",
+        help = "make identical sources give identical copies: times clamped to SOURCE_DATE_EPOCH, \
+                files owned by the current user (0:0 as root), tasks in name order \
+                (on by default when SOURCE_DATE_EPOCH is set)"
+    )]
+    pub reproducible: bool,
+
+    #[arg(
+        long = "preserve-sharing",
+        help = "keep extents that source files share with each other shared at the destination \
+                (Linux; cloned where the destination supports it, copied otherwise)"
+    )]
+    pub preserve_sharing: bool,
+
+    #[arg(
+        long = "usermap",
+        value_name = "FROM:TO,...",
+        value_parser = IdMap::parse_users,
+        help = "give copied files owned by user FROM the owner TO instead (ids or names; \
+                FROM may be * for every other user); implies --preserve=ownership"
+    )]
+    pub usermap: Option<IdMap>,
+
+    #[arg(
+        long = "groupmap",
+        value_name = "FROM:TO,...",
+        value_parser = IdMap::parse_groups,
+        help = "give copied files owned by group FROM the group TO instead (ids or names; \
+                FROM may be * for every other group); implies --preserve=ownership"
+    )]
+    pub groupmap: Option<IdMap>,
+
+    #[arg(
+        long = "owner",
+        value_name = "USER",
+        value_parser = IdMap::parse_user,
+        conflicts_with = "usermap",
+        help = "give every copied file, directory and symlink the owner USER (name or uid) \
+                instead of the source's; needs root unless USER is you"
+    )]
+    pub owner: Option<u32>,
+
+    #[arg(
+        long = "group",
+        value_name = "GROUP",
+        value_parser = IdMap::parse_group,
+        conflicts_with = "groupmap",
+        help = "give every copied file, directory and symlink the group GROUP (name or gid) \
+                instead of the source's; needs root unless you are in GROUP"
+    )]
+    pub group: Option<u32>,
+
+    #[arg(
+        long = "chmod",
+        value_name = "MODE",
+        value_parser = ModeSpec::parse,
+        help = "give copied files and directories MODE after anything preserved, octal (644) \
+                or symbolic (u+rwX,go-w); symlinks are left alone"
+    )]
+    pub chmod: Option<ModeSpec>,
+
+    #[arg(
+        long = "chmod-dir",
+        value_name = "MODE",
+        value_parser = ModeSpec::parse,
+        help = "give copied directories MODE instead of the --chmod one"
+    )]
+    pub chmod_dir: Option<ModeSpec>,
+
     // Backup and Reflink Options
     #[arg(
         short = 'b',
@@ -184,10 +710,18 @@ pub struct CopyArgs {
         value_name = "CONTROL",
         default_missing_value = "existing",
         num_args = 0..=1,
-        help = "make a backup of each existing destination file (none, numbered, existing, simple)"
+        help = "make a backup of each existing destination file (none, numbered, existing, simple); a simple backup already there is never replaced, so the file fails instead"
     )]
     pub backup: Option<BackupMode>,
 
+    #[arg(
+        short = 'S',
+        long = "suffix",
+        value_name = "SUFFIX",
+        help = "suffix of simple backups instead of ~ (or $SIMPLE_BACKUP_SUFFIX)"
+    )]
+    pub suffix: Option<String>,
+
     #[arg(
         long = "reflink",
         value_name = "WHEN",
@@ -197,6 +731,192 @@ pub struct CopyArgs {
     )]
     pub reflink: Option<ReflinkMode>,
 
+    #[arg(
+        long = "sparse",
+        value_name = "WHEN",
+        help = "control creation of sparse files (auto, always, never)"
+    )]
+    pub sparse: Option<SparseMode>,
+
+    #[arg(
+        long = "no-preallocate",
+        help = "don't reserve disk space for large destination files before writing"
+    )]
+    pub no_preallocate: bool,
+
+    #[arg(
+        long = "preallocate",
+        overrides_with = "no_preallocate",
+        help = "reserve disk space for large files even on a network filesystem"
+    )]
+    pub preallocate: bool,
+
+    #[arg(
+        long = "stall-timeout",
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        help = "warn about files that make no progress for DURATION (e.g. 30s, 2m)"
+    )]
+    pub stall_timeout: Option<Duration>,
+
+    #[arg(
+        long = "stall-action",
+        value_name = "ACTION",
+        requires = "stall_timeout",
+        help = "what to do with a stalled file (warn, skip)"
+    )]
+    pub stall_action: Option<StallAction>,
+
+    #[arg(
+        long = "on-full",
+        value_name = "ACTION",
+        help = "when the destination fills up: abort (stop starting files), pause until \
+                space is freed, or skip the files that no longer fit [default: abort]"
+    )]
+    pub on_full: Option<OnFull>,
+
+    #[arg(
+        long = "rollback-on-error",
+        help = "if the copy fails, offer to remove the directories it created, \
+                leaving whatever was there before as it was"
+    )]
+    pub rollback_on_error: bool,
+
+    #[arg(
+        short = 'y',
+        long = "yes",
+        requires = "rollback_on_error",
+        help = "roll back without asking"
+    )]
+    pub yes: bool,
+
+    #[arg(
+        long = "sudo",
+        value_name = "PROGRAM",
+        default_missing_value = "sudo",
+        num_args = 0..=1,
+        require_equals = true,
+        help = "if a source can't be read for lack of permission, offer to run the same \
+                command again with PROGRAM (default: sudo; e.g. --sudo=doas), giving the \
+                copies to you (Unix)"
+    )]
+    pub sudo: Option<String>,
+
+    /// Set on the command run again by --sudo: the user it was run for,
+    /// who gets the copies.
+    #[arg(long = "sudo-for", value_name = "UID:GID", value_parser = parse_owner, hide = true)]
+    pub sudo_for: Option<(u32, u32)>,
+
+    #[arg(
+        long = "bwlimit",
+        value_name = "RATE",
+        value_parser = parse_byte_size,
+        help = "read at most RATE bytes per second (e.g. 50M), shared fairly by all workers; \
+                on Unix SIGUSR2 halves the limit and the next SIGUSR2 restores it"
+    )]
+    pub bwlimit: Option<u64>,
+
+    #[arg(
+        long = "offload-chunk-size",
+        value_name = "SIZE",
+        value_parser = parse_byte_size,
+        help = "copy_file_range chunk size (e.g. 128M for high-latency network filesystems) \
+                [default: adapts, starting small and growing while chunks come back quickly]"
+    )]
+    pub offload_chunk_size: Option<u64>,
+
+    #[arg(
+        long = "buffer-size",
+        value_name = "SIZE",
+        value_parser = parse_byte_size,
+        help = "read and write SIZE bytes at a time when copying through a buffer \
+                [default: grows with the file, 4M on network filesystems]"
+    )]
+    pub buffer_size: Option<u64>,
+
+    #[arg(
+        long = "max-memory",
+        value_name = "SIZE",
+        value_parser = parse_byte_size,
+        help = "keep copy buffers within SIZE (e.g. 256M), using smaller buffers and then \
+                fewer workers, and warn when the copy plan alone would need more"
+    )]
+    pub max_memory: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "ENGINE",
+        help = "copy data with this method only, failing files it can't copy, \
+                for debugging and benchmarks [default: auto, trying the fastest first]"
+    )]
+    pub engine: Option<Engine>,
+
+    // Output Options
+    #[arg(
+        long = "progress",
+        value_name = "WHEN",
+        help = "show a progress bar (auto: only for large copies, always, never)"
+    )]
+    pub progress: Option<ProgressMode>,
+
+    #[arg(
+        long,
+        help = "print a stable, line-oriented summary to stdout for scripts"
+    )]
+    pub porcelain: bool,
+
+    #[arg(
+        long = "notify-cmd",
+        value_name = "CMD",
+        help = "run CMD with sh -c when the copy ends, with CPX_STATUS (success, partial, failed), \
+                CPX_FILES_COPIED, CPX_BYTES, CPX_ERRORS, CPX_ELAPSED_SECS and CPX_DEST set"
+    )]
+    pub notify_cmd: Option<String>,
+
+    #[arg(
+        long = "notify-desktop",
+        help = "show a desktop notification when the copy ends"
+    )]
+    pub notify_desktop: bool,
+
+    #[arg(
+        long = "notify-on",
+        value_name = "WHEN",
+        help = "when to notify (always [default], error: only if not everything was copied)"
+    )]
+    pub notify_on: Option<NotifyOn>,
+
+    #[arg(
+        long = "itemize-changes",
+        help = "print a line to stdout for each change as it is made, rsync -i style",
+        long_help = "print a line to stdout for each change as it is made, rsync -i style:\n\
+                     YXcstpogx PATH, where Y is > (data copied), c (created), h (hard link), \
+                     . (attributes only) or * (message, e.g. *excluded); X is f, d or L; \
+                     and each of c (source newer), s (size), t (mtime), p (permissions), \
+                     o (owner), g (group), x (xattrs) marks a difference, + a new item"
+    )]
+    pub itemize_changes: bool,
+
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        help = "print each file, directory, link and backup to stdout as it is made, cp -v style"
+    )]
+    pub verbose: bool,
+
+    #[arg(
+        long = "dry-run",
+        help = "show what would be copied, itemized as with --itemize-changes, \
+                without changing anything"
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        long,
+        help = "trace internal decisions, such as the chunk sizes chosen, to stderr"
+    )]
+    pub debug: bool,
+
     // Config Options (Placed last as meta)
     #[arg(long, value_name = "PATH", help = "Use custom config file")]
     pub config: Option<PathBuf>,
@@ -208,43 +928,288 @@ pub struct CopyArgs {
 #[derive(Debug, Clone)]
 pub struct CopyOptions {
     pub recursive: bool,
+    pub one_file_system: bool,
     pub parallel: usize,
     pub resume: bool,
+    pub fix_attrs: bool,
     pub force: bool,
-    pub interactive: bool,
+    pub interactive: Prompt,
+    pub interactive_dirs: Option<usize>,
+    /// `-n`: leave existing destinations alone, without asking.
+    pub no_clobber: bool,
+    /// `--update`: which existing destination files are replaced.
+    pub update: UpdateMode,
+    /// `--existing`: only files already at the destination are copied.
+    pub existing: bool,
     pub parents: bool,
+    pub files_from_root: Option<PathBuf>,
+    /// `-t`: every source goes into the destination directory, even one.
+    pub into_directory: bool,
+    /// The destination is a directory `--mkdir` made, or would have made
+    /// in a dry run.
+    pub mkdir: bool,
+    /// `-T`: the destination is what the one source is copied to.
+    pub no_target_directory: bool,
+    pub also_to: Vec<PathBuf>,
+    /// `--staging-dir`: where files are written before being moved into
+    /// place.
+    pub staging_dir: Option<PathBuf>,
     pub preserve: PreserveAttr,
+    pub strict_preserve: bool,
+    /// `--keep-dest-attrs`: give overwritten files back their owner, group
+    /// and mode.
+    pub keep_dest_attrs: bool,
+    pub overrides: AttrOverrides,
+    pub preserve_sharing: bool,
     pub attributes_only: bool,
     pub remove_destination: bool,
+    /// Replace a symlink at the destination instead of refusing it; set by
+    /// an explicit `-P`, `-d` or `-a`.
+    pub no_dereference_destination: bool,
     pub symbolic_link: Option<SymlinkMode>,
     pub hard_link: bool,
+    /// `--prefer-hardlink`: link each file the destination's filesystem
+    /// can, copy the others.
+    pub prefer_hardlink: bool,
     pub follow_symlink: FollowSymlink,
+    pub symlink_fallback: LinkFallback,
+    pub link_fallback: LinkFallback,
+    pub link_support: Option<LinkSupport>,
+    /// Directory below which destination files are created without
+    /// following symlinks; set when running as root.
+    pub confine: Option<PathBuf>,
     pub progress_bar: ProgressOptions,
     pub backup: Option<BackupMode>,
+    /// Appended to the name of simple backups.
+    pub backup_suffix: String,
     pub reflink: Option<ReflinkMode>,
+    pub sparse: SparseMode,
+    pub preallocate: bool,
+    pub order: CopyOrder,
+    pub on_collision: CollisionPolicy,
+    pub flatten: bool,
+    pub skip_oversize: bool,
+    pub compare_write: bool,
+    pub store_checksum: bool,
+    pub copy_contents: bool,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<usize>,
+    pub stall_timeout: Option<Duration>,
+    pub stall_action: StallAction,
+    pub on_full: OnFull,
+    pub rollback_on_error: bool,
+    pub assume_yes: bool,
+    /// Program the binary offers to run the command again with when a
+    /// source turns out to be unreadable. The copy itself only checks,
+    /// before copying anything, that every planned source can be read.
+    pub sudo: Option<String>,
+    pub bwlimit: Option<Arc<BandwidthLimit>>,
+    pub offload_chunk_size: Option<u64>,
+    pub buffer_size: Option<usize>,
+    pub max_memory: Option<u64>,
+    pub engine: Engine,
     pub exclude_rules: Option<ExcludeRules>,
+    pub noatime: bool,
+    pub background: bool,
+    pub drop_cache: bool,
+    pub porcelain: bool,
+    pub notify_cmd: Option<String>,
+    pub notify_desktop: bool,
+    pub notify_on: NotifyOn,
+    pub itemize: bool,
+    pub verbose: bool,
+    pub dry_run: bool,
+    pub debug: bool,
+    pub watch: bool,
     pub abort: Arc<AtomicBool>,
 }
 
 impl CopyOptions {
+    /// Whether existing destinations are never replaced, by `-n` or
+    /// `--update=none`.
+    pub fn keeps_existing(&self) -> bool {
+        self.no_clobber || self.update == UpdateMode::None
+    }
+
+    /// Whether the destination can store symlinks; assumed when not probed.
+    pub fn symlinks_supported(&self) -> bool {
+        self.link_support.is_none_or(|s| s.symlinks)
+    }
+
+    /// Whether the destination can store hard links; assumed when not probed.
+    pub fn hardlinks_supported(&self) -> bool {
+        self.link_support.is_none_or(|s| s.hardlinks)
+    }
+
+    /// Largest file the destination can hold, if it is limited at all.
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.link_support.and_then(|s| s.kind.max_file_size())
+    }
+
+    /// The `--background` preset, applied before the individual flags so
+    /// they can still override it.
+    pub fn apply_background(&mut self) {
+        self.background = true;
+        self.parallel = self.parallel.min(background::PARALLEL);
+        self.drop_cache = true;
+        self.progress_bar.refresh_rate = background::REFRESH_RATE;
+    }
+
+    /// Smaller buffers and then fewer workers, until they fit in
+    /// `max_memory`.
+    pub fn apply_max_memory(&mut self, max_memory: u64) {
+        let writers = 1 + self.also_to.len();
+        let limits = memory::fit_buffers(max_memory, self.parallel, self.buffer_size, writers);
+        if limits.parallel < self.parallel {
+            output::status(format!(
+                "Note: --max-memory {} leaves room for {} workers; using -j {}",
+                HumanBytes(max_memory),
+                limits.parallel,
+                limits.parallel
+            ));
+        }
+        self.parallel = limits.parallel;
+        self.buffer_size = limits.buffer_size;
+        if self.debug {
+            output::debug(format!(
+                "--max-memory {}: -j {}, buffers of up to {} ({} for all workers)",
+                HumanBytes(max_memory),
+                limits.parallel,
+                HumanBytes(limits.buffer_size.unwrap_or(memory::LARGEST_BUFFER) as u64),
+                HumanBytes(limits.buffers(writers))
+            ));
+        }
+    }
+
+    /// Settle `--preserve=ownership` for a user who is or isn't `privileged`
+    /// to give files to other users, where `asked` says whether the command
+    /// line asked for it. Without the privilege ownership is set where the
+    /// system allows it, such as a group the user is in, and refusals aren't
+    /// failures; with `--strict-preserve` the copy doesn't start.
+    pub fn check_ownership(
+        &mut self,
+        privileged: bool,
+        asked: bool,
+    ) -> Result<OwnershipCheck, String> {
+        if !self.preserve.ownership || privileged {
+            return Ok(OwnershipCheck::Honored);
+        }
+        if self.strict_preserve {
+            return Err(
+                "preserving ownership needs root or CAP_CHOWN; run as root, or leave \
+                 ownership out of --preserve"
+                    .to_string(),
+            );
+        }
+        self.overrides.best_effort_owner = true;
+        Ok(if asked {
+            OwnershipCheck::BestEffort
+        } else {
+            OwnershipCheck::Defaulted
+        })
+    }
+
+    /// Defaults for a copy involving a network filesystem, for whatever the
+    /// flags in `copy_args` leave open. Returns what was changed.
+    pub fn apply_network(&mut self, copy_args: &CopyArgs) -> Vec<String> {
+        let mut changed = Vec::new();
+        if copy_args.parallel.is_none() && self.parallel > network::PARALLEL {
+            self.parallel = network::PARALLEL;
+            changed.push(format!("-j {}", network::PARALLEL));
+        }
+        if copy_args.buffer_size.is_none() && self.buffer_size.is_none() {
+            self.buffer_size = Some(network::BUFFER_SIZE);
+            changed.push(format!("{} MiB buffers", network::BUFFER_SIZE >> 20));
+        }
+        if copy_args.offload_chunk_size.is_none() && self.offload_chunk_size.is_none() {
+            self.offload_chunk_size = Some(network::OFFLOAD_CHUNK_SIZE);
+            changed.push(format!(
+                "{} MiB offload chunks",
+                network::OFFLOAD_CHUNK_SIZE >> 20
+            ));
+        }
+        if !copy_args.preallocate && self.preallocate {
+            self.preallocate = false;
+            changed.push("no preallocation".to_string());
+        }
+        changed
+    }
+
     pub fn none() -> Self {
         Self {
             recursive: false,
+            one_file_system: false,
             parallel: 4,
             resume: false,
+            fix_attrs: false,
             force: false,
-            interactive: false,
+            interactive: Prompt::Never,
+            interactive_dirs: None,
+            no_clobber: false,
+            update: UpdateMode::All,
+            existing: false,
             parents: false,
+            files_from_root: None,
+            into_directory: false,
+            mkdir: false,
+            no_target_directory: false,
+            also_to: Vec::new(),
+            staging_dir: None,
             preserve: PreserveAttr::none(),
+            strict_preserve: false,
+            keep_dest_attrs: false,
+            overrides: AttrOverrides::default(),
+            preserve_sharing: false,
             attributes_only: false,
             remove_destination: false,
+            no_dereference_destination: false,
             symbolic_link: None,
             hard_link: false,
+            prefer_hardlink: false,
             follow_symlink: FollowSymlink::NoDereference,
+            symlink_fallback: LinkFallback::CopyTarget,
+            link_fallback: LinkFallback::CopyTarget,
+            link_support: None,
+            confine: None,
             progress_bar: ProgressOptions::default(),
             backup: None,
+            backup_suffix: backup::DEFAULT_SUFFIX.to_string(),
             reflink: None,
+            sparse: SparseMode::Auto,
+            preallocate: true,
+            order: CopyOrder::Size,
+            on_collision: CollisionPolicy::Error,
+            flatten: false,
+            skip_oversize: false,
+            compare_write: false,
+            store_checksum: false,
+            copy_contents: false,
+            max_bytes: None,
+            max_files: None,
+            stall_timeout: None,
+            stall_action: StallAction::Warn,
+            on_full: OnFull::Abort,
+            rollback_on_error: false,
+            assume_yes: false,
+            sudo: None,
+            bwlimit: None,
+            offload_chunk_size: None,
+            buffer_size: None,
+            max_memory: None,
+            engine: Engine::Auto,
             exclude_rules: None,
+            noatime: false,
+            background: false,
+            drop_cache: false,
+            porcelain: false,
+            notify_cmd: None,
+            notify_desktop: false,
+            notify_on: NotifyOn::Always,
+            itemize: false,
+            verbose: false,
+            dry_run: false,
+            debug: false,
+            watch: false,
             abort: Arc::new(AtomicBool::new(false)),
         }
     }
@@ -252,22 +1217,83 @@ impl CopyOptions {
     pub fn from_config(config: &Config) -> Self {
         Self {
             recursive: config.copy.recursive,
+            one_file_system: false,
             parallel: config.copy.parallel,
             resume: config.copy.resume,
+            fix_attrs: false,
             force: config.copy.force,
-            interactive: config.copy.interactive,
+            interactive: if config.copy.interactive {
+                Prompt::Always
+            } else {
+                Prompt::Never
+            },
+            interactive_dirs: None,
+            no_clobber: false,
+            update: UpdateMode::All,
+            existing: false,
             parents: config.copy.parents,
+            files_from_root: None,
+            into_directory: false,
+            mkdir: false,
+            no_target_directory: false,
+            also_to: Vec::new(),
+            staging_dir: None,
             preserve: PreserveAttr::from_string(&config.preserve.mode)
                 .unwrap_or_else(|_| PreserveAttr::default()),
+            strict_preserve: false,
+            keep_dest_attrs: false,
+            overrides: AttrOverrides::default(),
+            preserve_sharing: false,
             attributes_only: config.copy.attributes_only,
             remove_destination: config.copy.remove_destination,
+            no_dereference_destination: false,
             symbolic_link: parse_symlink_mode(&config.symlink.mode),
             hard_link: false,
+            prefer_hardlink: false,
             follow_symlink: parse_follow_symlink(&config.symlink.follow),
+            symlink_fallback: LinkFallback::CopyTarget,
+            link_fallback: LinkFallback::CopyTarget,
+            link_support: None,
+            confine: None,
             progress_bar: parse_progress_bar(config),
             backup: parse_backup_mode(&config.backup.mode),
+            backup_suffix: config.backup.suffix.clone(),
             reflink: parse_reflink_mode(&config.reflink.mode),
+            sparse: SparseMode::Auto,
+            preallocate: true,
+            order: CopyOrder::Size,
+            on_collision: CollisionPolicy::Error,
+            flatten: false,
+            skip_oversize: false,
+            compare_write: false,
+            store_checksum: false,
+            copy_contents: false,
+            max_bytes: None,
+            max_files: None,
+            stall_timeout: None,
+            stall_action: StallAction::Warn,
+            on_full: OnFull::Abort,
+            rollback_on_error: false,
+            assume_yes: false,
+            sudo: None,
+            bwlimit: None,
+            offload_chunk_size: None,
+            buffer_size: None,
+            max_memory: None,
+            engine: parse_engine(&config.copy.engine).unwrap_or(Engine::Auto),
             exclude_rules: None,
+            noatime: false,
+            background: false,
+            drop_cache: false,
+            porcelain: false,
+            notify_cmd: None,
+            notify_desktop: false,
+            notify_on: NotifyOn::Always,
+            itemize: false,
+            verbose: false,
+            dry_run: false,
+            debug: false,
+            watch: false,
             abort: Arc::new(AtomicBool::new(false)),
         }
     }
@@ -275,30 +1301,119 @@ impl CopyOptions {
 
 impl From<&CopyArgs> for CopyOptions {
     fn from(cli: &CopyArgs) -> Self {
-        Self {
-            recursive: cli.recursive,
-            parallel: cli.parallel,
+        let mut options = Self {
+            recursive: cli.recursive || cli.archive,
+            one_file_system: cli.one_file_system,
+            parallel: cli.parallel.unwrap_or(4),
             resume: cli.resume,
+            fix_attrs: cli.fix_attrs,
             force: cli.force,
-            interactive: cli.interactive,
+            interactive: file_prompt(&cli.interactive)
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+            interactive_dirs: cli
+                .interactive
+                .contains(&InteractiveMode::Dirs)
+                .then(|| cli.interactive_depth.unwrap_or(1)),
+            no_clobber: cli.no_clobber,
+            update: cli.update.unwrap_or(UpdateMode::All),
+            existing: cli.existing,
             parents: cli.parents,
-            preserve: match &cli.preserve {
-                None => PreserveAttr::none(),
-                Some(s) => {
-                    PreserveAttr::from_string(s).expect("unable to parse preserve attribute")
-                }
+            files_from_root: None,
+            into_directory: false,
+            mkdir: false,
+            no_target_directory: false,
+            also_to: cli.also_to.clone(),
+            staging_dir: cli.staging_dir.clone(),
+            preserve: cli
+                .preserve_attrs(PreserveAttr::none())
+                .expect("unable to parse preserve attribute"),
+            strict_preserve: cli.strict_preserve,
+            keep_dest_attrs: cli.keep_dest_attrs,
+            overrides: AttrOverrides {
+                mtime: cli.timestamp,
+                reproducible: cli.reproducible,
+                owners: OwnerMap {
+                    users: cli
+                        .owner
+                        .map(IdMap::everyone)
+                        .or_else(|| cli.usermap.clone())
+                        .unwrap_or_default(),
+                    groups: cli
+                        .group
+                        .map(IdMap::everyone)
+                        .or_else(|| cli.groupmap.clone())
+                        .unwrap_or_default(),
+                },
+                chmod: cli.chmod.clone(),
+                chmod_dir: cli.chmod_dir.clone(),
+                ..AttrOverrides::default()
             },
+            preserve_sharing: cli.preserve_sharing,
             attributes_only: cli.attributes_only,
             remove_destination: cli.remove_destination,
+            no_dereference_destination: cli.no_dereference_destination(),
             symbolic_link: cli.symbolic_link,
             hard_link: cli.hard_link,
+            prefer_hardlink: cli.prefer_hardlink,
             follow_symlink: FollowSymlink::NoDereference,
-            progress_bar: ProgressOptions::default(),
+            symlink_fallback: cli.symlink_fallback.unwrap_or(LinkFallback::CopyTarget),
+            link_fallback: cli.link_fallback.unwrap_or(LinkFallback::CopyTarget),
+            link_support: None,
+            confine: None,
+            progress_bar: ProgressOptions {
+                mode: cli.progress.unwrap_or_default(),
+                ..ProgressOptions::default()
+            },
             backup: cli.backup,
+            backup_suffix: backup::suffix_from(cli.suffix.as_deref())
+                .unwrap_or_else(|| backup::DEFAULT_SUFFIX.to_string()),
             reflink: cli.reflink,
+            sparse: cli.sparse.unwrap_or(SparseMode::Auto),
+            preallocate: !cli.no_preallocate,
+            order: cli.order.unwrap_or(CopyOrder::Size),
+            on_collision: cli.on_collision.unwrap_or(CollisionPolicy::Error),
+            flatten: cli.flatten,
+            skip_oversize: cli.skip_oversize,
+            compare_write: cli.compare_write,
+            store_checksum: cli.store_checksum,
+            copy_contents: cli.copy_contents,
+            max_bytes: cli.max_bytes,
+            max_files: cli.max_files,
+            stall_timeout: cli.stall_timeout,
+            stall_action: cli.stall_action.unwrap_or(StallAction::Warn),
+            on_full: cli.on_full.unwrap_or(OnFull::Abort),
+            rollback_on_error: cli.rollback_on_error,
+            assume_yes: cli.yes,
+            sudo: cli.sudo.clone(),
+            bwlimit: cli.bwlimit.map(|rate| Arc::new(BandwidthLimit::new(rate))),
+            offload_chunk_size: cli.offload_chunk_size,
+            buffer_size: cli.buffer_size.map(|size| size as usize),
+            max_memory: cli.max_memory,
+            engine: cli.engine.unwrap_or(Engine::Auto),
             exclude_rules: None,
+            noatime: cli.noatime,
+            background: false,
+            drop_cache: false,
+            porcelain: cli.porcelain,
+            notify_cmd: cli.notify_cmd.clone(),
+            notify_desktop: cli.notify_desktop,
+            notify_on: cli.notify_on.unwrap_or(NotifyOn::Always),
+            itemize: cli.itemize_changes || cli.dry_run,
+            verbose: cli.verbose,
+            dry_run: cli.dry_run,
+            debug: cli.debug,
+            watch: cli.watch,
             abort: Arc::new(AtomicBool::new(false)),
+        };
+        if cli.background {
+            options.apply_background();
+            if let Some(parallel) = cli.parallel {
+                options.parallel = parallel;
+            }
         }
+        options
     }
 }
 
@@ -311,7 +1426,18 @@ impl CLIArgs {
             let first_arg = &args[1];
             let is_subcommand = matches!(
                 first_arg.as_str(),
-                "config" | "copy" | "-h" | "--help" | "-V" | "--version"
+                "config"
+                    | "copy"
+                    | "doctor"
+                    | "clean"
+                    | "move"
+                    | "verify"
+                    | "undo"
+                    | "status"
+                    | "-h"
+                    | "--help"
+                    | "-V"
+                    | "--version"
             );
             if !is_subcommand {
                 args.insert(1, "copy".to_string());
@@ -324,15 +1450,82 @@ impl CLIArgs {
     pub fn validate(self) -> CpxResult<(Vec<PathBuf>, PathBuf, CopyOptions)> {
         // Handle config command
         if let Commands::Config { command } = &self.command {
+            output::restore_sigpipe();
             command.execute().map_err(|e| {
                 CpxError::Validation(format!("Failed to execute config command: {}", e))
             })?;
             std::process::exit(0);
         }
 
+        // Handle doctor command
+        if let Commands::Doctor(doctor) = &self.command {
+            output::restore_sigpipe();
+            doctor
+                .execute()
+                .map_err(|e| CpxError::Validation(format!("Failed to run doctor: {}", e)))?;
+            std::process::exit(0);
+        }
+
+        // Handle clean command: exit 0 if nothing was found, 1 if leftovers were, 2 on error
+        if let Commands::Clean(clean) = &self.command {
+            match clean.execute() {
+                Ok(found) => std::process::exit(if found { 1 } else { 0 }),
+                Err(e) => {
+                    output::error(e);
+                    std::process::exit(2);
+                }
+            }
+        }
+
+        // Handle move command: exit 1 if any source could not be moved, 2 on
+        // bad arguments, when nothing was moved
+        if let Commands::Move(args) = &self.command {
+            match args.execute() {
+                Ok(failed) => std::process::exit(if failed > 0 { 1 } else { 0 }),
+                Err(e) => {
+                    output::error(e);
+                    std::process::exit(2);
+                }
+            }
+        }
+
+        // Handle verify command: exit 1 if any file no longer matches its checksum
+        if let Commands::Verify(verify) = &self.command {
+            match verify.execute() {
+                Ok(matched) => std::process::exit(if matched { 0 } else { 1 }),
+                Err(e) => {
+                    output::error(e);
+                    std::process::exit(2);
+                }
+            }
+        }
+
+        // Handle undo command: exit 1 if anything could not be put back
+        if let Commands::Undo(undo) = &self.command {
+            match undo.execute() {
+                Ok(failed) => std::process::exit(if failed > 0 { 1 } else { 0 }),
+                Err(e) => {
+                    output::error(e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        // Handle status command: exit 0 in sync, 1 on differences, 2 on error
+        if let Commands::Status(status) = &self.command {
+            output::restore_sigpipe();
+            match status.execute() {
+                Ok(in_sync) => std::process::exit(if in_sync { 0 } else { 1 }),
+                Err(e) => {
+                    output::error(e);
+                    std::process::exit(2);
+                }
+            }
+        }
+
         // Get copy args from the Copy subcommand
         let copy_args = match self.command {
-            Commands::Copy(args) => args,
+            Commands::Copy(args) => *args,
             _ => unreachable!(),
         };
 
@@ -356,23 +1549,209 @@ impl CLIArgs {
         // Validate conflicts
         validate_conflicts(&options).map_err(CpxError::Validation)?;
 
-        // Handle attributes_only special case
-        if options.attributes_only {
-            options.preserve = PreserveAttr::all();
+        // Clone when possible unless told otherwise; links never copy data,
+        // so they are left alone.
+        if options.reflink.is_none() && !options.hard_link && options.symbolic_link.is_none() {
+            options.reflink = Some(ReflinkMode::Auto);
+        }
+
+        // Handle attributes_only special case
+        if options.attributes_only {
+            let reproducible = options.overrides.reproducible;
+            options.preserve = PreserveAttr::all();
+            options.preserve.timestamps = options.overrides.mtime.is_none() && !reproducible;
+            options.preserve.atimes = !reproducible;
+            options.preserve.ownership = !reproducible;
+            options.preserve.context = !copy_args.context;
+            options.preserve.default_context = copy_args.context;
+            options.preserve = options
+                .preserve
+                .without(copy_args.not_preserved().map_err(CpxError::Validation)?);
+            if options.preserve == PreserveAttr::none() && !options.overrides.stamps() {
+                return Err(CpxError::Validation(
+                    "--attributes-only has nothing to copy once --no-preserve takes every \
+                     attribute"
+                        .to_string(),
+                ));
+            }
+        }
+        // The default preserve set includes ownership, which for files the
+        // user owns costs nothing; only a request is worth a warning.
+        let asked =
+            copy_args.preserve.is_some() || copy_args.maps_owners() || copy_args.attributes_only;
+        // Refused once here rather than for every file: without the
+        // privilege, files can only be given to yourself and your groups.
+        let privileged = can_chown();
+        if !privileged && let Some(refused) = unsettable_owner(copy_args.owner, copy_args.group) {
+            return Err(CpxError::Validation(refused));
+        }
+        // Both given, and allowed, nothing is left to the source.
+        let settled = copy_args.owner.is_some() && copy_args.group.is_some();
+        if options
+            .check_ownership(privileged || settled, asked)
+            .map_err(CpxError::Validation)?
+            == OwnershipCheck::BestEffort
+        {
+            output::warning(
+                "preserving ownership needs root or CAP_CHOWN; files will be owned by you \
+                 where their owner can't be kept (--strict-preserve to stop instead)",
+            );
+        }
+        // Contexts mean nothing without SELinux; -a asks for them only in
+        // passing, so only -Z or --preserve=context is worth a warning.
+        if (options.preserve.context || options.preserve.default_context)
+            && !preserve::selinux_enabled()
+        {
+            if copy_args.context || copy_args.asks_to_preserve("context") {
+                let unavailable = "SELinux is not enabled here, or cpx was built without \
+                                   selinux-support";
+                if options.strict_preserve {
+                    return Err(CpxError::Validation(format!(
+                        "cannot set SELinux contexts: {}",
+                        unavailable
+                    )));
+                }
+                output::warning(format!("ignoring SELinux contexts: {}", unavailable));
+            }
+            options.preserve.context = false;
+            options.preserve.default_context = false;
+        }
+
+        let (sources, destination) = if let Some(list) = &copy_args.files_from {
+            if options.flatten {
+                return Err(CpxError::Validation(
+                    "--flatten cannot be used with --files-from".to_string(),
+                ));
+            }
+            if !copy_args.sources.is_empty()
+                || copy_args.target_directory.is_some()
+                || copy_args.no_target_directory
+            {
+                return Err(CpxError::Validation(
+                    "--files-from takes only a DESTINATION, not SOURCE arguments, -t or -T"
+                        .to_string(),
+                ));
+            }
+            let root = copy_args
+                .files_from_root
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("."));
+            let sources = read_files_from(list, &root).map_err(CpxError::Validation)?;
+            options.files_from_root = Some(root);
+            (sources, copy_args.destination.clone())
+        } else if let Some(target) = &copy_args.target_directory {
+            if !target.is_dir() {
+                return Err(CpxError::Copy(CopyError::InvalidDestination(
+                    target.clone(),
+                )));
+            }
+            options.into_directory = true;
+            let mut sources = copy_args.sources.clone();
+            sources.push(copy_args.destination.clone());
+            (sources, target.clone())
+        } else if copy_args.no_target_directory {
+            if copy_args.sources.len() != 1 {
+                return Err(CpxError::Validation(
+                    "-T takes exactly one SOURCE and a DESTINATION".to_string(),
+                ));
+            }
+            if options.parents {
+                return Err(CpxError::Validation(
+                    "-T and --parents cannot be used together".to_string(),
+                ));
+            }
+            options.no_target_directory = true;
+            (copy_args.sources.clone(), copy_args.destination.clone())
+        } else {
+            (copy_args.sources.clone(), copy_args.destination.clone())
+        };
+        // Without them a symlink named as `link/` is copied as the link.
+        let sources: Vec<PathBuf> = if copy_args.strip_trailing_slashes {
+            sources.iter().map(|s| strip_trailing_slashes(s)).collect()
+        } else {
+            sources
+        };
+        // Made before anything looks at the destination, and before
+        // planning, which copies into it as into -t DIR.
+        if std::fs::symlink_metadata(&destination).is_err()
+            && !options.no_target_directory
+            && (copy_args.mkdir
+                || (options.interactive != Prompt::Never
+                    && needs_directory(&sources, &destination)
+                    && output::confirm(format!("create directory '{}'?", destination.display()))
+                        .map_err(CpxError::Io)?))
+        {
+            options.mkdir = true;
+            options.into_directory = true;
+            if options.dry_run {
+                itemize::print_item(&Item::created_directory(&destination));
+            } else {
+                make_destination(&destination, &options).map_err(|error| {
+                    CpxError::Copy(CopyError::OperationFailed {
+                        operation: "create directory".to_string(),
+                        path: destination.clone(),
+                        error,
+                    })
+                })?;
+            }
+        }
+        options.link_support = Some(link_support(&destination));
+        // A root copy into a tree others can write to must not be steered
+        // elsewhere by a directory swapped for a symlink mid-copy.
+        // Root has nobody to ask for more, and the run --sudo started
+        // must not offer it again.
+        #[cfg(unix)]
+        if unsafe { libc::geteuid() } == 0 {
+            options.sudo = None;
+            options.confine = confine::confine_root(&destination);
+        }
+
+        // Several workers on one spinning disk only make it seek back and
+        // forth between them, so it gets one unless -j says otherwise. A
+        // network filesystem has no disk of ours behind it and gets its own
+        // defaults instead.
+        let many_files = sources.len() > 1 || sources.iter().any(|s| s.is_dir());
+        if let Some(remote) = find_network_path(&sources, &destination) {
+            note_network_profile(&remote, options.apply_network(&copy_args));
+        } else if copy_args.parallel.is_none()
+            && many_files
+            && let Some(device) = sources.iter().find_map(|s| shared_device(s, &destination))
+        {
+            if device.rotational == Some(true) && options.parallel > 1 {
+                options.parallel = 1;
+                output::status(format!(
+                    "Note: source and destination are both on {}, a spinning disk; \
+                     copying one file at a time (give -j to override)",
+                    device.name
+                ));
+            } else if options.debug {
+                output::debug(format!(
+                    "source and destination are both on {}; reads and writes share its bandwidth",
+                    device.describe()
+                ));
+            }
         }
 
-        let (sources, destination) = if let Some(target) = copy_args.target_directory {
-            let mut sources = copy_args.sources;
-            sources.push(copy_args.destination);
-            (sources, target)
-        } else {
-            (copy_args.sources, copy_args.destination)
-        };
+        // Last, since it caps -j whatever set it.
+        if let Some(max_memory) = options.max_memory {
+            options.apply_max_memory(max_memory);
+        }
 
         Ok((sources, destination, options))
     }
 }
 
+fn note_network_profile(remote: &NetworkPath, changed: Vec<String>) {
+    if changed.is_empty() {
+        return;
+    }
+    output::status(format!(
+        "Note: {}, a network filesystem; using {} (flags override)",
+        remote.describe(),
+        changed.join(", ")
+    ));
+}
+
 fn load_config_if_needed(copy_args: &CopyArgs) -> crate::error::ConfigResult<Option<Config>> {
     if copy_args.no_config {
         return Ok(None);
@@ -385,20 +1764,265 @@ fn load_config_if_needed(copy_args: &CopyArgs) -> crate::error::ConfigResult<Opt
     Ok(Some(load_config()))
 }
 
+/// Parse a `--timestamp` value: RFC 3339 in UTC, or `@` followed by seconds
+/// since the Unix epoch.
+fn parse_timestamp(value: &str) -> Result<SystemTime, String> {
+    if let Some(seconds) = value.strip_prefix('@') {
+        let seconds: i64 = seconds
+            .parse()
+            .map_err(|_| format!("invalid epoch time '{}'", value))?;
+        let offset = Duration::from_secs(seconds.unsigned_abs());
+        let time = if seconds < 0 {
+            UNIX_EPOCH.checked_sub(offset)
+        } else {
+            UNIX_EPOCH.checked_add(offset)
+        };
+        return time.ok_or_else(|| format!("epoch time '{}' is out of range", value));
+    }
+    humantime::parse_rfc3339_weak(value).map_err(|e| {
+        format!(
+            "invalid time '{}': {} (expected e.g. 2024-01-01T00:00:00Z or @1704067200)",
+            value, e
+        )
+    })
+}
+
+/// A byte count such as `4096`, `64K`, `10M` or `1GiB`; suffixes are
+/// powers of 1024.
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "invalid size '{}' (expected e.g. 4096, 64K, 10M or 1G)",
+            value
+        )
+    };
+    let trimmed = value.trim();
+    let digits = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let unit = unit
+        .strip_suffix("IB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(&unit);
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(1 << shift).ok_or_else(invalid)
+}
+
+/// Whether copying `sources` to a `destination` that isn't there needs it
+/// to be a directory: several sources do, and so does a file copied to a
+/// name that ends in a slash.
+fn needs_directory(sources: &[PathBuf], destination: &Path) -> bool {
+    sources.len() > 1 || (has_trailing_slash(destination) && sources.iter().all(|s| !s.is_dir()))
+}
+
+/// Create `destination` and its missing parents for `--mkdir`, giving each
+/// the mode and owner the copy gives the directories it makes.
+fn make_destination(destination: &Path, options: &CopyOptions) -> io::Result<()> {
+    let missing: Vec<&Path> = destination
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && std::fs::symlink_metadata(dir).is_err())
+        .collect();
+    std::fs::create_dir_all(destination)?;
+    for dir in missing {
+        preserve::apply_chmod(dir, &options.overrides).map_err(io::Error::other)?;
+        #[cfg(unix)]
+        if !options.overrides.owners.is_empty() {
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            let (uid, gid) = options.overrides.owners.map(uid, gid);
+            std::os::unix::fs::chown(dir, Some(uid), Some(gid))?;
+        }
+    }
+    Ok(())
+}
+
+/// Why a user without the privilege can't give files the owner `uid` or
+/// the group `gid`, if they can't.
+#[cfg(unix)]
+fn unsettable_owner(uid: Option<u32>, gid: Option<u32>) -> Option<String> {
+    use nix::unistd::{Gid, getegid, geteuid, getgroups};
+
+    if let Some(uid) = uid
+        && uid != geteuid().as_raw()
+    {
+        return Some(format!(
+            "--owner={} needs root or CAP_CHOWN; without them files can only be given to yourself",
+            uid
+        ));
+    }
+    if let Some(gid) = gid
+        && gid != getegid().as_raw()
+        && !getgroups().is_ok_and(|groups| groups.contains(&Gid::from_raw(gid)))
+    {
+        return Some(format!(
+            "--group={} needs root or CAP_CHOWN; without them files can only be given to \
+             groups you are in",
+            gid
+        ));
+    }
+    None
+}
+
+#[cfg(not(unix))]
+fn unsettable_owner(_uid: Option<u32>, _gid: Option<u32>) -> Option<String> {
+    None
+}
+
+/// Whether files may be given to any owner: as root, or holding CAP_CHOWN.
+#[cfg(unix)]
+fn can_chown() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 || holds_cap_chown() }
+}
+
+#[cfg(not(unix))]
+fn can_chown() -> bool {
+    // Ownership is never set here, so there is nothing to warn about.
+    true
+}
+
+#[cfg(target_os = "linux")]
+fn holds_cap_chown() -> bool {
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: libc::c_int,
+    }
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+    const CAP_CHOWN: u32 = 0;
+
+    let mut header = Header {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [Data::default(); 2];
+    // SAFETY: version 3 takes a header and two data structs, both valid for
+    // the call; pid 0 is this thread.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_capget,
+            &mut header as *mut Header,
+            data.as_mut_ptr(),
+        )
+    };
+    result == 0 && data[0].effective & (1 << CAP_CHOWN) != 0
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn holds_cap_chown() -> bool {
+    false
+}
+
+/// Parse `UID:GID`, as numbers.
+fn parse_owner(s: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("invalid owner '{}': expected UID:GID", s);
+    let (uid, gid) = s.split_once(':').ok_or_else(invalid)?;
+    Ok((
+        uid.parse().map_err(|_| invalid())?,
+        gid.parse().map_err(|_| invalid())?,
+    ))
+}
+
+/// `SOURCE_DATE_EPOCH`, if set, which turns on `--reproducible`.
+fn source_date_epoch() -> Result<Option<SystemTime>, String> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if !value.trim().is_empty() => parse_timestamp(&format!("@{}", value.trim()))
+            .map(Some)
+            .map_err(|_| {
+                format!(
+                    "SOURCE_DATE_EPOCH must be seconds since the epoch, got '{}'",
+                    value
+                )
+            }),
+        _ => Ok(None),
+    }
+}
+
+/// What the `--interactive` values given say about replacing files, if
+/// anything: `dirs` alone leaves it to the config.
+fn file_prompt(modes: &[InteractiveMode]) -> Result<Option<Prompt>, String> {
+    let mut prompt = None;
+    for mode in modes {
+        let when = match mode {
+            InteractiveMode::Files | InteractiveMode::Always => Prompt::Always,
+            InteractiveMode::Once => Prompt::Once,
+            InteractiveMode::Never => Prompt::Never,
+            InteractiveMode::Dirs => continue,
+        };
+        if prompt.is_some_and(|p| p != when) {
+            return Err("--interactive takes only one of never, once and always".to_string());
+        }
+        prompt = Some(when);
+    }
+    if prompt == Some(Prompt::Never) && modes.contains(&InteractiveMode::Dirs) {
+        return Err(
+            "--interactive=never and --interactive=dirs cannot be used together".to_string(),
+        );
+    }
+    Ok(prompt)
+}
+
 fn apply_cli_overrides(options: &mut CopyOptions, copy_args: &CopyArgs) -> Result<(), String> {
+    // Presets first, so the flags below win over them
+    if copy_args.background {
+        options.apply_background();
+    }
+
     // Boolean flags - when present, they override
-    if copy_args.recursive {
+    if copy_args.recursive || copy_args.archive {
         options.recursive = true;
     }
+    if copy_args.one_file_system {
+        options.one_file_system = true;
+    }
     if copy_args.force {
         options.force = true;
     }
-    if copy_args.interactive {
-        options.interactive = true;
+    if let Some(prompt) = file_prompt(&copy_args.interactive)? {
+        options.interactive = prompt;
+    }
+    if copy_args.interactive.contains(&InteractiveMode::Dirs) {
+        options.interactive_dirs = Some(copy_args.interactive_depth.unwrap_or(1));
+    } else if copy_args.interactive_depth.is_some() {
+        return Err("--interactive-depth requires --interactive=dirs".to_string());
+    }
+    if copy_args.no_clobber {
+        options.no_clobber = true;
+    }
+    if let Some(update) = copy_args.update {
+        options.update = update;
+    }
+    if copy_args.existing {
+        options.existing = true;
     }
     if copy_args.resume {
         options.resume = true;
     }
+    if copy_args.fix_attrs {
+        options.fix_attrs = true;
+    }
+    if copy_args.strict_preserve {
+        options.strict_preserve = true;
+    }
+    if copy_args.keep_dest_attrs {
+        options.keep_dest_attrs = true;
+    }
     if copy_args.parents {
         options.parents = true;
     }
@@ -411,6 +2035,42 @@ fn apply_cli_overrides(options: &mut CopyOptions, copy_args: &CopyArgs) -> Resul
     if copy_args.hard_link {
         options.hard_link = true;
     }
+    if copy_args.prefer_hardlink {
+        options.prefer_hardlink = true;
+    }
+    if copy_args.noatime {
+        options.noatime = true;
+    }
+    if copy_args.porcelain {
+        options.porcelain = true;
+    }
+    if let Some(command) = &copy_args.notify_cmd {
+        options.notify_cmd = Some(command.clone());
+    }
+    if copy_args.notify_desktop {
+        options.notify_desktop = true;
+    }
+    if let Some(when) = copy_args.notify_on {
+        options.notify_on = when;
+    }
+    if copy_args.itemize_changes || copy_args.dry_run {
+        options.itemize = true;
+    }
+    if copy_args.verbose {
+        options.verbose = true;
+    }
+    if copy_args.dry_run {
+        options.dry_run = true;
+    }
+    if copy_args.watch {
+        options.watch = true;
+    }
+    if copy_args.debug {
+        options.debug = true;
+    }
+    if copy_args.preserve_sharing {
+        options.preserve_sharing = true;
+    }
 
     // Optional fields - when Some, they override
     if copy_args.symbolic_link.is_some() {
@@ -419,21 +2079,226 @@ fn apply_cli_overrides(options: &mut CopyOptions, copy_args: &CopyArgs) -> Resul
     if copy_args.backup.is_some() {
         options.backup = copy_args.backup;
     }
+    if let Some(suffix) = backup::suffix_from(copy_args.suffix.as_deref()) {
+        options.backup_suffix = suffix;
+    }
+    if options.backup.is_some_and(|mode| mode != BackupMode::None) {
+        backup::validate_suffix(&options.backup_suffix)?;
+    }
     if copy_args.reflink.is_some() {
         options.reflink = copy_args.reflink;
     }
-    if let Some(preserve_str) = &copy_args.preserve {
-        options.preserve = PreserveAttr::from_string(preserve_str)
-            .map_err(|e| format!("unable to parse preserve attribute: {}", e))?;
+    if let Some(mode) = copy_args.progress {
+        options.progress_bar.mode = mode;
+    } else if options.verbose && !io::stdout().is_terminal() {
+        // The -v lines are what a pipe or log wants, not a bar.
+        options.progress_bar.mode = ProgressMode::Never;
+    }
+    if let Some(sparse) = copy_args.sparse {
+        options.sparse = sparse;
+    }
+    if copy_args.no_preallocate {
+        options.preallocate = false;
+    }
+    if let Some(policy) = copy_args.symlink_fallback {
+        options.symlink_fallback = policy;
+    }
+    if let Some(policy) = copy_args.link_fallback {
+        options.link_fallback = policy;
+    }
+    if let Some(order) = copy_args.order {
+        options.order = order;
+    }
+    if let Some(policy) = copy_args.on_collision {
+        options.on_collision = policy;
+    }
+    if copy_args.flatten {
+        options.flatten = true;
+    }
+    if copy_args.skip_oversize {
+        options.skip_oversize = true;
+    }
+    if copy_args.compare_write {
+        options.compare_write = true;
+    }
+    if copy_args.store_checksum {
+        options.store_checksum = true;
+    }
+    if copy_args.copy_contents {
+        options.copy_contents = true;
+    }
+    if copy_args.max_bytes.is_some() {
+        options.max_bytes = copy_args.max_bytes;
+    }
+    if let Some(count) = copy_args.max_files {
+        if count == 0 {
+            return Err("--max-files must be more than 0".to_string());
+        }
+        options.max_files = Some(count);
+    }
+    if !copy_args.also_to.is_empty() {
+        options.also_to = copy_args.also_to.clone();
+    }
+    if copy_args.staging_dir.is_some() {
+        options.staging_dir = copy_args.staging_dir.clone();
+    }
+    if copy_args.stall_timeout.is_some() {
+        options.stall_timeout = copy_args.stall_timeout;
+    }
+    if let Some(action) = copy_args.stall_action {
+        options.stall_action = action;
+    }
+    if let Some(action) = copy_args.on_full {
+        options.on_full = action;
+    }
+    if copy_args.rollback_on_error {
+        options.rollback_on_error = true;
+        options.assume_yes = copy_args.yes;
+    }
+    if let Some(rate) = copy_args.bwlimit {
+        if rate == 0 {
+            return Err("--bwlimit must be more than 0".to_string());
+        }
+        options.bwlimit = Some(Arc::new(BandwidthLimit::new(rate)));
+    }
+    if let Some(size) = copy_args.offload_chunk_size {
+        if size == 0 {
+            return Err("--offload-chunk-size must be more than 0".to_string());
+        }
+        options.offload_chunk_size = Some(size);
+    }
+    if let Some(size) = copy_args.buffer_size {
+        if size == 0 {
+            return Err("--buffer-size must be more than 0".to_string());
+        }
+        options.buffer_size = Some(size as usize);
+    }
+    if let Some(size) = copy_args.max_memory {
+        if size == 0 {
+            return Err("--max-memory must be more than 0".to_string());
+        }
+        options.max_memory = Some(size);
+    }
+    if let Some(engine) = copy_args.engine {
+        options.engine = engine;
+    }
+    options.preserve = copy_args.preserve_attrs(options.preserve)?;
+    if let Some(time) = copy_args.timestamp {
+        if copy_args.preserve.is_some() && options.preserve.timestamps {
+            return Err(
+                "--timestamp and --preserve=timestamps cannot be used together".to_string(),
+            );
+        }
+        // An explicit time wins over timestamps preserved by the config.
+        options.preserve.timestamps = false;
+        options.overrides.mtime = Some(time);
+    }
+    if let Some(users) = &copy_args.usermap {
+        options.overrides.owners.users = users.clone();
+    }
+    if let Some(groups) = &copy_args.groupmap {
+        options.overrides.owners.groups = groups.clone();
+    }
+    if copy_args.owner.is_some() || copy_args.group.is_some() {
+        if cfg!(not(unix)) {
+            return Err("--owner and --group are only available on Unix".to_string());
+        }
+        if let Some(uid) = copy_args.owner {
+            options.overrides.owners.users = IdMap::everyone(uid);
+        }
+        if let Some(gid) = copy_args.group {
+            options.overrides.owners.groups = IdMap::everyone(gid);
+        }
+    }
+    if copy_args.chmod.is_some() || copy_args.chmod_dir.is_some() {
+        if cfg!(not(unix)) {
+            return Err("--chmod is only available on Unix".to_string());
+        }
+        if copy_args.keep_dest_attrs {
+            return Err("--chmod and --keep-dest-attrs cannot be used together".to_string());
+        }
+        options.overrides.chmod = copy_args.chmod.clone();
+        options.overrides.chmod_dir = copy_args.chmod_dir.clone();
+    }
+    // A map only means something if the owner is carried over.
+    if let Some(program) = &copy_args.sudo {
+        if cfg!(not(unix)) {
+            return Err("--sudo is only available on Unix".to_string());
+        }
+        if program.is_empty() {
+            return Err("--sudo needs a program, such as --sudo=doas".to_string());
+        }
+        options.sudo = Some(program.clone());
+    }
+    // Run again by --sudo: what is copied goes to the user who asked,
+    // unless the command said who should have it.
+    if let Some((uid, gid)) = copy_args.sudo_for
+        && options.overrides.owners.is_empty()
+        && !copy_args.reproducible
+    {
+        options.overrides.owners = OwnerMap {
+            users: IdMap::everyone(uid),
+            groups: IdMap::everyone(gid),
+        };
+    }
+    if !options.overrides.owners.is_empty() {
+        options.preserve.ownership = true;
+    }
+    let epoch = source_date_epoch()?;
+    if copy_args.reproducible || epoch.is_some() {
+        if !options.overrides.owners.is_empty() {
+            return Err(
+                "--reproducible cannot be used with --usermap, --groupmap, --owner or --group"
+                    .to_string(),
+            );
+        }
+        if copy_args.asks_to_preserve("ownership") {
+            return Err(
+                "--reproducible and --preserve=ownership cannot be used together".to_string(),
+            );
+        }
+        // Times come from the source clamped to the epoch, and ownership
+        // from whoever runs the copy, whatever --preserve or the config say.
+        options.preserve.ownership = false;
+        options.preserve.timestamps = false;
+        options.preserve.atimes = false;
+        options.overrides.reproducible = true;
+        options.overrides.epoch = epoch;
+        options.order = CopyOrder::Name;
+    }
+    if copy_args.keep_dest_attrs {
+        let explicit =
+            copy_args.preserve.is_some() && (options.preserve.mode || options.preserve.ownership);
+        if explicit || copy_args.maps_owners() {
+            return Err(
+                "--keep-dest-attrs cannot be used with --preserve of mode or \
+                 ownership, --usermap, --groupmap, --owner or --group"
+                    .to_string(),
+            );
+        }
+        // Owner, group and mode come from the file being replaced, whatever
+        // the config says.
+        options.preserve.mode = false;
+        options.preserve.ownership = false;
     }
 
-    options.parallel = copy_args.parallel;
+    if let Some(parallel) = copy_args.parallel {
+        options.parallel = parallel;
+    }
 
     options.follow_symlink = copy_args.follow_symlink_mode()?;
+    if copy_args.no_dereference_destination() {
+        options.no_dereference_destination = true;
+    }
 
     Ok(())
 }
 
+fn parse_preserve(list: &str) -> Result<PreserveAttr, String> {
+    PreserveAttr::from_string(list)
+        .map_err(|e| format!("unable to parse preserve attribute: {}", e))
+}
+
 fn build_all_exclude_patterns(
     copy_args: &CopyArgs,
     config: Option<&Config>,
@@ -451,6 +2316,19 @@ fn build_all_exclude_patterns(
 }
 
 fn validate_conflicts(options: &CopyOptions) -> Result<(), String> {
+    // Each of these looks for or places files at their paths in the tree.
+    if options.flatten
+        && (options.resume
+            || options.parents
+            || options.hard_link
+            || options.symbolic_link.is_some())
+    {
+        return Err(
+            "--flatten cannot be used with --resume, --parents, --link or --symbolic-link"
+                .to_string(),
+        );
+    }
+
     if options.reflink.is_some() {
         if options.hard_link {
             return Err("--reflink and --link cannot be used together".to_string());
@@ -474,6 +2352,81 @@ fn validate_conflicts(options: &CopyOptions) -> Result<(), String> {
         }
     }
 
+    // Both are about what happens to an existing destination, which -n
+    // never touches.
+    if options.no_clobber {
+        if options.backup.is_some_and(|mode| mode != BackupMode::None) {
+            return Err("--no-clobber and --backup cannot be used together".to_string());
+        }
+        if options.attributes_only {
+            return Err("--no-clobber and --attributes-only cannot be used together".to_string());
+        }
+    }
+    if options.existing && options.keeps_existing() {
+        return Err("--existing with --no-clobber or --update=none copies nothing".to_string());
+    }
+
+    if options.fix_attrs && options.preserve == PreserveAttr::none() {
+        return Err("--fix-attrs needs --preserve to say which attributes to refresh".to_string());
+    }
+
+    if options.compare_write {
+        if options.remove_destination {
+            return Err(
+                "--compare-write and --remove-destination cannot be used together".to_string(),
+            );
+        }
+        if options.reflink == Some(ReflinkMode::Always) {
+            return Err("--compare-write and --reflink=always cannot be used together".to_string());
+        }
+    }
+
+    if !options.also_to.is_empty() {
+        if options.hard_link || options.symbolic_link.is_some() {
+            return Err("--also-to cannot be used with --link or --symbolic-link".to_string());
+        }
+        if options.attributes_only || options.resume || options.dry_run {
+            return Err(
+                "--also-to cannot be used with --attributes-only, --resume or --dry-run"
+                    .to_string(),
+            );
+        }
+    }
+
+    if let Some(dir) = &options.staging_dir {
+        if options.compare_write {
+            return Err("--staging-dir and --compare-write cannot be used together".to_string());
+        }
+        if !dir.is_dir() {
+            return Err(format!(
+                "--staging-dir '{}' is not a directory",
+                dir.display()
+            ));
+        }
+    }
+
+    if options.watch {
+        if options.interactive != Prompt::Never || options.interactive_dirs.is_some() {
+            return Err("--watch and --interactive cannot be used together".to_string());
+        }
+        if options.hard_link || options.symbolic_link.is_some() {
+            return Err("--watch cannot be used with --link or --symbolic-link".to_string());
+        }
+        if options.dry_run {
+            return Err("--watch and --dry-run cannot be used together".to_string());
+        }
+    }
+
+    if matches!(options.engine, Engine::Offload | Engine::Mmap)
+        && (!options.also_to.is_empty() || options.bwlimit.is_some())
+    {
+        let engine = options.engine.to_possible_value().expect("not skipped");
+        return Err(format!(
+            "--engine={} cannot be used with --also-to or --bwlimit",
+            engine.get_name()
+        ));
+    }
+
     if options.hard_link {
         if options.resume {
             return Err("--link and --continue cannot be used together".to_string());
@@ -483,6 +2436,23 @@ fn validate_conflicts(options: &CopyOptions) -> Result<(), String> {
         }
     }
 
+    if options.keep_dest_attrs && !options.also_to.is_empty() {
+        return Err("--keep-dest-attrs and --also-to cannot be used together".to_string());
+    }
+
+    if options.prefer_hardlink {
+        if options.hard_link || options.symbolic_link.is_some() {
+            return Err(
+                "--prefer-hardlink cannot be used with --link or --symbolic-link".to_string(),
+            );
+        }
+        if options.attributes_only {
+            return Err(
+                "--prefer-hardlink and --attributes-only cannot be used together".to_string(),
+            );
+        }
+    }
+
     Ok(())
 }
 
@@ -501,6 +2471,70 @@ impl CopyArgs {
         }
     }
 
+    /// Whether `-P` was asked for, by itself or through `-d` or `-a`, and
+    /// not overruled by `-L` or `-H`. Unlike the default, it also keeps
+    /// symlinks at the destination from being written through.
+    pub fn no_dereference_destination(&self) -> bool {
+        (self.no_dereference || self.no_dereference_preserve_links || self.archive)
+            && !self.dereference
+            && !self.dereference_command_line
+    }
+
+    /// The attributes to preserve, starting from `configured`: `-a` makes
+    /// that all of them, `-p` replaces it or, with `-a`, adds to it, `-d`
+    /// adds links, and `--no-preserve` takes from whatever is left.
+    pub fn preserve_attrs(&self, configured: PreserveAttr) -> Result<PreserveAttr, String> {
+        let mut attrs = if self.archive {
+            PreserveAttr::all()
+        } else {
+            configured
+        };
+        if let Some(list) = &self.preserve {
+            attrs = if self.archive {
+                attrs.with(parse_preserve(list)?)
+            } else {
+                parse_preserve(list)?
+            };
+        }
+        attrs.links |= self.no_dereference_preserve_links;
+        let mut attrs = attrs.without(self.not_preserved()?);
+        if self.context {
+            if self.asks_to_preserve("context") {
+                return Err("-Z and --preserve=context cannot be used together".to_string());
+            }
+            attrs.context = false;
+            attrs.default_context = true;
+        }
+        Ok(attrs)
+    }
+
+    /// Whether the command line says who copies belong to, with
+    /// `--usermap`, `--groupmap`, `--owner` or `--group`.
+    pub fn maps_owners(&self) -> bool {
+        self.usermap.is_some()
+            || self.groupmap.is_some()
+            || self.owner.is_some()
+            || self.group.is_some()
+    }
+
+    /// Whether `--preserve` names `attr` itself.
+    pub fn asks_to_preserve(&self, attr: &str) -> bool {
+        self.preserve
+            .as_deref()
+            .is_some_and(|p| p.split(',').any(|a| a.trim() == attr))
+    }
+
+    /// The attributes `--no-preserve` takes away, none without it. Taken
+    /// away, `all` includes fsflags, so that nothing is left.
+    pub fn not_preserved(&self) -> Result<PreserveAttr, String> {
+        let Some(list) = &self.no_preserve else {
+            return Ok(PreserveAttr::none());
+        };
+        let mut attrs = parse_preserve(list)?;
+        attrs.fsflags |= list.split(',').any(|attr| attr.trim() == "all");
+        Ok(attrs)
+    }
+
     pub fn parse_exclude_patterns(&self) -> crate::error::ExcludeResult<Vec<ExcludePattern>> {
         let mut patterns = Vec::new();
 
@@ -519,15 +2553,16 @@ mod tests {
     #[test]
     fn test_validate_symlink_and_hardlink_conflict() {
         let args = CLIArgs {
-            command: Commands::Copy(CopyArgs {
+            command: Commands::Copy(Box::new(CopyArgs {
                 sources: vec![PathBuf::from("source.txt")],
                 destination: PathBuf::from("dest.txt"),
                 target_directory: None,
+                no_target_directory: false,
                 recursive: false,
-                parallel: 4,
+                parallel: None,
                 resume: false,
                 force: false,
-                interactive: false,
+                interactive: vec![],
                 parents: false,
                 preserve: None,
                 attributes_only: false,
@@ -542,7 +2577,74 @@ mod tests {
                 exclude: Vec::new(),
                 no_config: false,
                 config: None,
-            }),
+                porcelain: false,
+                noatime: false,
+                sparse: None,
+                no_preallocate: false,
+                watch: false,
+                stall_timeout: None,
+                stall_action: None,
+                progress: None,
+                order: None,
+                symlink_fallback: None,
+                link_fallback: None,
+                interactive_depth: None,
+                timestamp: None,
+                reproducible: false,
+                preserve_sharing: false,
+                usermap: None,
+                groupmap: None,
+                on_collision: None,
+                itemize_changes: false,
+                dry_run: false,
+                skip_oversize: false,
+                compare_write: false,
+                files_from: None,
+                files_from_root: None,
+                also_to: Vec::new(),
+                store_checksum: false,
+                on_full: None,
+                copy_contents: false,
+                max_bytes: None,
+                background: false,
+                bwlimit: None,
+                offload_chunk_size: None,
+                buffer_size: None,
+                debug: false,
+                engine: None,
+                preallocate: false,
+                rollback_on_error: false,
+                yes: false,
+                max_files: None,
+                max_memory: None,
+                fix_attrs: false,
+                flatten: false,
+                strict_preserve: false,
+                sudo: None,
+                sudo_for: None,
+                notify_cmd: None,
+                notify_desktop: false,
+                notify_on: None,
+                staging_dir: None,
+                prefer_hardlink: false,
+                keep_dest_attrs: false,
+                no_clobber: false,
+                update: None,
+                verbose: false,
+                archive: false,
+                no_preserve: None,
+                one_file_system: false,
+                strip_trailing_slashes: false,
+                suffix: None,
+                context: false,
+                no_dereference_preserve_links: false,
+                existing: false,
+                chmod: None,
+                chmod_dir: None,
+                owner: None,
+                group: None,
+                mkdir: false,
+            })),
         };
 
         let result = args.validate();
@@ -553,15 +2655,16 @@ mod tests {
     #[test]
     fn test_validate_symlink_and_resume_conflict() {
         let args = CLIArgs {
-            command: Commands::Copy(CopyArgs {
+            command: Commands::Copy(Box::new(CopyArgs {
                 sources: vec![PathBuf::from("source.txt")],
                 destination: PathBuf::from("dest.txt"),
                 target_directory: None,
+                no_target_directory: false,
                 recursive: false,
-                parallel: 4,
+                parallel: None,
                 resume: true,
                 force: false,
-                interactive: false,
+                interactive: vec![],
                 parents: false,
                 preserve: None,
                 attributes_only: false,
@@ -576,7 +2679,74 @@ mod tests {
                 exclude: Vec::new(),
                 no_config: false,
                 config: None,
-            }),
+                porcelain: false,
+                noatime: false,
+                sparse: None,
+                no_preallocate: false,
+                watch: false,
+                stall_timeout: None,
+                stall_action: None,
+                progress: None,
+                order: None,
+                symlink_fallback: None,
+                link_fallback: None,
+                interactive_depth: None,
+                timestamp: None,
+                reproducible: false,
+                preserve_sharing: false,
+                usermap: None,
+                groupmap: None,
+                on_collision: None,
+                itemize_changes: false,
+                dry_run: false,
+                skip_oversize: false,
+                compare_write: false,
+                files_from: None,
+                files_from_root: None,
+                also_to: Vec::new(),
+                store_checksum: false,
+                on_full: None,
+                copy_contents: false,
+                max_bytes: None,
+                background: false,
+                bwlimit: None,
+                offload_chunk_size: None,
+                buffer_size: None,
+                debug: false,
+                engine: None,
+                preallocate: false,
+                rollback_on_error: false,
+                yes: false,
+                max_files: None,
+                max_memory: None,
+                fix_attrs: false,
+                flatten: false,
+                strict_preserve: false,
+                sudo: None,
+                sudo_for: None,
+                notify_cmd: None,
+                notify_desktop: false,
+                notify_on: None,
+                staging_dir: None,
+                prefer_hardlink: false,
+                keep_dest_attrs: false,
+                no_clobber: false,
+                update: None,
+                verbose: false,
+                archive: false,
+                no_preserve: None,
+                one_file_system: false,
+                strip_trailing_slashes: false,
+                suffix: None,
+                context: false,
+                no_dereference_preserve_links: false,
+                existing: false,
+                chmod: None,
+                chmod_dir: None,
+                owner: None,
+                group: None,
+                mkdir: false,
+            })),
         };
 
         let result = args.validate();
@@ -587,15 +2757,16 @@ mod tests {
     #[test]
     fn test_validate_hardlink_and_resume_conflict() {
         let args = CLIArgs {
-            command: Commands::Copy(CopyArgs {
+            command: Commands::Copy(Box::new(CopyArgs {
                 sources: vec![PathBuf::from("source.txt")],
                 destination: PathBuf::from("dest.txt"),
                 target_directory: None,
+                no_target_directory: false,
                 recursive: false,
-                parallel: 4,
+                parallel: None,
                 resume: true,
                 force: false,
-                interactive: false,
+                interactive: vec![],
                 parents: false,
                 preserve: None,
                 attributes_only: false,
@@ -610,7 +2781,74 @@ mod tests {
                 exclude: Vec::new(),
                 no_config: false,
                 config: None,
-            }),
+                porcelain: false,
+                noatime: false,
+                sparse: None,
+                no_preallocate: false,
+                watch: false,
+                stall_timeout: None,
+                stall_action: None,
+                progress: None,
+                order: None,
+                symlink_fallback: None,
+                link_fallback: None,
+                interactive_depth: None,
+                timestamp: None,
+                reproducible: false,
+                preserve_sharing: false,
+                usermap: None,
+                groupmap: None,
+                on_collision: None,
+                itemize_changes: false,
+                dry_run: false,
+                skip_oversize: false,
+                compare_write: false,
+                files_from: None,
+                files_from_root: None,
+                also_to: Vec::new(),
+                store_checksum: false,
+                on_full: None,
+                copy_contents: false,
+                max_bytes: None,
+                background: false,
+                bwlimit: None,
+                offload_chunk_size: None,
+                buffer_size: None,
+                debug: false,
+                engine: None,
+                preallocate: false,
+                rollback_on_error: false,
+                yes: false,
+                max_files: None,
+                max_memory: None,
+                fix_attrs: false,
+                flatten: false,
+                strict_preserve: false,
+                sudo: None,
+                sudo_for: None,
+                notify_cmd: None,
+                notify_desktop: false,
+                notify_on: None,
+                staging_dir: None,
+                prefer_hardlink: false,
+                keep_dest_attrs: false,
+                no_clobber: false,
+                update: None,
+                verbose: false,
+                archive: false,
+                no_preserve: None,
+                one_file_system: false,
+                strip_trailing_slashes: false,
+                suffix: None,
+                context: false,
+                no_dereference_preserve_links: false,
+                existing: false,
+                chmod: None,
+                chmod_dir: None,
+                owner: None,
+                group: None,
+                mkdir: false,
+            })),
         };
 
         let result = args.validate();
@@ -621,15 +2859,16 @@ mod tests {
     #[test]
     fn test_validate_success() {
         let args = CLIArgs {
-            command: Commands::Copy(CopyArgs {
+            command: Commands::Copy(Box::new(CopyArgs {
                 sources: vec![PathBuf::from("source.txt")],
                 destination: PathBuf::from("dest.txt"),
                 target_directory: None,
+                no_target_directory: false,
                 recursive: false,
-                parallel: 4,
+                parallel: None,
                 resume: false,
                 force: false,
-                interactive: false,
+                interactive: vec![],
                 parents: false,
                 preserve: None,
                 attributes_only: false,
@@ -644,10 +2883,384 @@ mod tests {
                 exclude: Vec::new(),
                 no_config: false,
                 config: None,
-            }),
+                porcelain: false,
+                noatime: false,
+                sparse: None,
+                no_preallocate: false,
+                watch: false,
+                stall_timeout: None,
+                stall_action: None,
+                progress: None,
+                order: None,
+                symlink_fallback: None,
+                link_fallback: None,
+                interactive_depth: None,
+                timestamp: None,
+                reproducible: false,
+                preserve_sharing: false,
+                usermap: None,
+                groupmap: None,
+                on_collision: None,
+                itemize_changes: false,
+                dry_run: false,
+                skip_oversize: false,
+                compare_write: false,
+                files_from: None,
+                files_from_root: None,
+                also_to: Vec::new(),
+                store_checksum: false,
+                on_full: None,
+                copy_contents: false,
+                max_bytes: None,
+                background: false,
+                bwlimit: None,
+                offload_chunk_size: None,
+                buffer_size: None,
+                debug: false,
+                engine: None,
+                preallocate: false,
+                rollback_on_error: false,
+                yes: false,
+                max_files: None,
+                max_memory: None,
+                fix_attrs: false,
+                flatten: false,
+                strict_preserve: false,
+                sudo: None,
+                sudo_for: None,
+                notify_cmd: None,
+                notify_desktop: false,
+                notify_on: None,
+                staging_dir: None,
+                prefer_hardlink: false,
+                keep_dest_attrs: false,
+                no_clobber: false,
+                update: None,
+                verbose: false,
+                archive: false,
+                no_preserve: None,
+                one_file_system: false,
+                strip_trailing_slashes: false,
+                suffix: None,
+                context: false,
+                no_dereference_preserve_links: false,
+                existing: false,
+                chmod: None,
+                chmod_dir: None,
+                owner: None,
+                group: None,
+                mkdir: false,
+            })),
         };
 
         let result = args.validate();
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_parse_timestamp_rfc3339_and_epoch() {
+        let expected = UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+        assert_eq!(parse_timestamp("2024-01-01T00:00:00Z").unwrap(), expected);
+        assert_eq!(parse_timestamp("@1704067200").unwrap(), expected);
+        assert_eq!(
+            parse_timestamp("@-1").unwrap(),
+            UNIX_EPOCH - Duration::from_secs(1)
+        );
+        assert!(parse_timestamp("@soon").is_err());
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_byte_size_units() {
+        assert_eq!(parse_byte_size("4096").unwrap(), 4096);
+        assert_eq!(parse_byte_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_byte_size("10m").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_byte_size("1GiB").unwrap(), 1 << 30);
+        assert_eq!(parse_byte_size("2 MB").unwrap(), 2 << 20);
+        assert!(parse_byte_size("ten").is_err());
+        assert!(parse_byte_size("5X").is_err());
+        assert!(parse_byte_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_interactive_when_overrides_the_configured_prompt() {
+        let prompt_for = |flags: &[&str]| {
+            let mut argv = vec!["cpx", "copy"];
+            argv.extend_from_slice(flags);
+            argv.extend_from_slice(&["a", "b"]);
+            let Commands::Copy(copy_args) = <CLIArgs as clap::Parser>::parse_from(argv).command
+            else {
+                unreachable!()
+            };
+            let mut options = CopyOptions::none();
+            options.interactive = Prompt::Always;
+            apply_cli_overrides(&mut options, &copy_args).map(|()| options.interactive)
+        };
+
+        assert_eq!(prompt_for(&[]), Ok(Prompt::Always));
+        assert_eq!(prompt_for(&["--interactive=never"]), Ok(Prompt::Never));
+        assert_eq!(prompt_for(&["--interactive=once"]), Ok(Prompt::Once));
+        assert_eq!(prompt_for(&["--interactive=dirs"]), Ok(Prompt::Always));
+        assert_eq!(prompt_for(&["-i"]), Ok(Prompt::Always));
+        assert!(prompt_for(&["--interactive=once,always"]).is_err());
+        assert!(prompt_for(&["--interactive=never,dirs"]).is_err());
+    }
+
+    #[test]
+    fn test_background_preset_yields_to_explicit_flags() {
+        let options_for = |flags: &[&str]| {
+            let mut argv = vec!["cpx", "copy"];
+            argv.extend_from_slice(flags);
+            argv.extend_from_slice(&["a", "b"]);
+            let Commands::Copy(copy_args) = <CLIArgs as clap::Parser>::parse_from(argv).command
+            else {
+                unreachable!()
+            };
+            let mut options = CopyOptions::none();
+            apply_cli_overrides(&mut options, &copy_args).unwrap();
+            options
+        };
+
+        let preset = options_for(&["--background"]);
+        assert!(preset.background && preset.drop_cache);
+        assert_eq!(preset.parallel, background::PARALLEL);
+        assert_eq!(preset.progress_bar.refresh_rate, background::REFRESH_RATE);
+        assert_eq!(options_for(&["--background", "-j", "8"]).parallel, 8);
+        assert_eq!(options_for(&[]).parallel, 4);
+    }
+
+    #[test]
+    fn test_archive_expands_before_preserve_flags() {
+        let options_for = |flags: &[&str]| {
+            let mut argv = vec!["cpx", "copy"];
+            argv.extend_from_slice(flags);
+            argv.extend_from_slice(&["a", "b"]);
+            let Commands::Copy(copy_args) = <CLIArgs as clap::Parser>::parse_from(argv).command
+            else {
+                unreachable!()
+            };
+            let mut options = CopyOptions::none();
+            apply_cli_overrides(&mut options, &copy_args).unwrap();
+            options
+        };
+
+        let archive = options_for(&["-a"]);
+        assert!(archive.recursive);
+        assert_eq!(archive.preserve, PreserveAttr::all());
+        assert!(matches!(
+            archive.follow_symlink,
+            FollowSymlink::NoDereference
+        ));
+
+        let trimmed = options_for(&["-a", "--no-preserve=ownership"]).preserve;
+        assert!(!trimmed.ownership);
+        assert!(trimmed.mode && trimmed.timestamps && trimmed.links && trimmed.xattr);
+        assert!(options_for(&["-a", "-p", "fsflags"]).preserve.fsflags);
+        assert_eq!(
+            options_for(&["-p", "mode,links", "--no-preserve=links"]).preserve,
+            PreserveAttr::from_string("mode").unwrap()
+        );
+        assert!(matches!(
+            options_for(&["-a", "-L"]).follow_symlink,
+            FollowSymlink::Dereference
+        ));
+
+        let links = options_for(&["-d"]);
+        assert!(links.preserve.links);
+        assert!(matches!(links.follow_symlink, FollowSymlink::NoDereference));
+        let followed = options_for(&["-d", "-L"]);
+        assert!(followed.preserve.links);
+        assert!(matches!(
+            followed.follow_symlink,
+            FollowSymlink::Dereference
+        ));
+        assert_eq!(
+            options_for(&["-d", "-p", "mode"]).preserve,
+            PreserveAttr::from_string("mode,links").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_default_context_replaces_the_preserved_one() {
+        let preserve_for = |flags: &[&str]| {
+            let mut argv = vec!["cpx", "copy"];
+            argv.extend_from_slice(flags);
+            argv.extend_from_slice(&["a", "b"]);
+            let Commands::Copy(copy_args) = <CLIArgs as clap::Parser>::parse_from(argv).command
+            else {
+                unreachable!()
+            };
+            copy_args.preserve_attrs(PreserveAttr::default())
+        };
+
+        let archive = preserve_for(&["-a", "-Z"]).unwrap();
+        assert!(archive.default_context && !archive.context);
+        assert!(archive.xattr && archive.mode);
+        assert!(preserve_for(&["-Z"]).unwrap().default_context);
+        assert!(preserve_for(&["-Z", "-p", "mode,context"]).is_err());
+    }
+
+    #[test]
+    fn test_no_preserve_takes_from_the_configured_set() {
+        let options_for = |flags: &[&str]| {
+            let mut argv = vec!["cpx", "copy"];
+            argv.extend_from_slice(flags);
+            argv.extend_from_slice(&["a", "b"]);
+            let Commands::Copy(copy_args) = <CLIArgs as clap::Parser>::parse_from(argv).command
+            else {
+                unreachable!()
+            };
+            // As a [preserve] section asking for everything would leave it.
+            let mut options = CopyOptions::none();
+            options.preserve = PreserveAttr::from_string("all,fsflags").unwrap();
+            apply_cli_overrides(&mut options, &copy_args).unwrap();
+            options.preserve
+        };
+
+        let timestamps_only = options_for(&["--no-preserve=mode,ownership,links,context,xattr"]);
+        assert!(timestamps_only.timestamps && timestamps_only.fsflags);
+        assert!(!timestamps_only.mode && !timestamps_only.ownership);
+        assert_eq!(options_for(&["--no-preserve=all"]), PreserveAttr::none());
+        assert_eq!(
+            options_for(&["-a", "--no-preserve=all"]),
+            PreserveAttr::none()
+        );
+    }
+
+    #[test]
+    fn test_ownership_without_privilege_is_best_effort_or_refused() {
+        let check = |ownership: bool, privileged: bool, strict: bool| {
+            let mut options = CopyOptions::none();
+            options.preserve.ownership = ownership;
+            options.strict_preserve = strict;
+            options
+                .check_ownership(privileged, true)
+                .map(|check| (check, options.overrides.best_effort_owner))
+        };
+
+        for strict in [false, true] {
+            // Not asked for, or nothing stands in the way.
+            assert_eq!(
+                check(false, false, strict),
+                Ok((OwnershipCheck::Honored, false))
+            );
+            assert_eq!(
+                check(true, true, strict),
+                Ok((OwnershipCheck::Honored, false))
+            );
+            assert_eq!(
+                check(false, true, strict),
+                Ok((OwnershipCheck::Honored, false))
+            );
+        }
+        assert_eq!(
+            check(true, false, false),
+            Ok((OwnershipCheck::BestEffort, true))
+        );
+        let error = check(true, false, true).unwrap_err();
+        assert!(error.contains("CAP_CHOWN"), "{}", error);
+
+        // Ownership from the defaults is kept where possible, quietly.
+        let mut options = CopyOptions::none();
+        options.preserve.ownership = true;
+        assert_eq!(
+            options.check_ownership(false, false),
+            Ok(OwnershipCheck::Defaulted)
+        );
+        assert!(options.overrides.best_effort_owner);
+        options.strict_preserve = true;
+        assert!(options.check_ownership(false, false).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unprivileged_users_may_only_give_files_to_themselves() {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        assert_eq!(unsettable_owner(Some(uid), Some(gid)), None);
+        assert_eq!(unsettable_owner(None, None), None);
+        assert!(unsettable_owner(Some(uid + 1), None).is_some_and(|e| e.contains("--owner")));
+    }
+
+    #[test]
+    fn test_sudo_rerun_gives_copies_to_the_invoking_user() {
+        let options_for = |flags: &[&str]| {
+            let mut argv = vec!["cpx", "copy"];
+            argv.extend_from_slice(flags);
+            argv.extend_from_slice(&["a", "b"]);
+            let Commands::Copy(copy_args) = <CLIArgs as clap::Parser>::parse_from(argv).command
+            else {
+                unreachable!()
+            };
+            let mut options = CopyOptions::none();
+            apply_cli_overrides(&mut options, &copy_args).map(|()| options)
+        };
+
+        assert_eq!(
+            options_for(&["--sudo"]).unwrap().sudo.as_deref(),
+            Some("sudo")
+        );
+        assert_eq!(
+            options_for(&["--sudo=doas"]).unwrap().sudo.as_deref(),
+            Some("doas")
+        );
+        assert!(options_for(&["--sudo="]).is_err());
+
+        let rerun = options_for(&["--sudo-for=1000:100"]).unwrap();
+        assert!(rerun.preserve.ownership);
+        assert_eq!(rerun.overrides.owners.map(0, 0), (1000, 100));
+        // Owners the command asked for win.
+        let mapped = options_for(&["--sudo-for=1000:100", "--usermap=0:7"]).unwrap();
+        assert_eq!(mapped.overrides.owners.map(0, 5), (7, 5));
+
+        assert_eq!(parse_owner("1000:100"), Ok((1000, 100)));
+        assert!(parse_owner("alice:100").is_err());
+        assert!(parse_owner("1000").is_err());
+    }
+
+    #[test]
+    fn test_network_defaults_yield_to_explicit_flags() {
+        let apply = |flags: &[&str], parallel: usize| {
+            let mut argv = vec!["cpx", "copy"];
+            argv.extend_from_slice(flags);
+            argv.extend_from_slice(&["a", "b"]);
+            let Commands::Copy(copy_args) = <CLIArgs as clap::Parser>::parse_from(argv).command
+            else {
+                unreachable!()
+            };
+            let mut options = CopyOptions::none();
+            options.parallel = parallel;
+            apply_cli_overrides(&mut options, &copy_args).unwrap();
+            let changed = options.apply_network(&copy_args);
+            (options, changed)
+        };
+
+        let (profile, changed) = apply(&[], 16);
+        assert_eq!(profile.parallel, network::PARALLEL);
+        assert_eq!(profile.buffer_size, Some(network::BUFFER_SIZE));
+        assert_eq!(
+            profile.offload_chunk_size,
+            Some(network::OFFLOAD_CHUNK_SIZE)
+        );
+        assert!(!profile.preallocate);
+        assert_eq!(changed.len(), 4);
+
+        let flags = [
+            "-j",
+            "16",
+            "--buffer-size",
+            "1M",
+            "--offload-chunk-size",
+            "8M",
+            "--preallocate",
+        ];
+        let (explicit, changed) = apply(&flags, 16);
+        assert_eq!(explicit.parallel, 16);
+        assert_eq!(explicit.buffer_size, Some(1 << 20));
+        assert_eq!(explicit.offload_chunk_size, Some(8 << 20));
+        assert!(explicit.preallocate);
+        assert!(changed.is_empty());
+
+        // A lower -j from the config is already moderate enough.
+        assert_eq!(apply(&[], 2).0.parallel, 2);
+    }
 }
@@ -0,0 +1,218 @@
+use crate::error::{CpxError, CpxResult};
+use crate::utility::exclude::{
+    ExcludeRules, build_exclude_rules, parse_exclude_pattern_list, should_exclude,
+};
+use crate::utility::helper::is_leftover_name;
+use crate::utility::output;
+use clap::Args;
+use indicatif::HumanBytes;
+use jwalk::WalkDir;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug, Clone)]
+pub struct CleanArgs {
+    #[arg(help = "Directory to search for leftover files")]
+    pub destination: PathBuf,
+
+    #[arg(short = 'y', long, help = "remove leftovers without asking")]
+    pub yes: bool,
+
+    #[arg(long = "dry-run", help = "only list leftovers, never remove them")]
+    pub dry_run: bool,
+
+    #[arg(
+        short = 'e',
+        long = "exclude",
+        value_name = "PATTERN",
+        help = "Skip paths matching pattern (can be specified multiple times, supports comma-separated values)"
+    )]
+    pub exclude: Vec<String>,
+}
+
+/// A temp or partial file (or doctor scratch directory) left behind by an
+/// interrupted run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leftover {
+    pub path: PathBuf,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+impl CleanArgs {
+    /// Lists leftovers and removes them unless `--dry-run` is given.
+    ///
+    /// Returns whether anything was found.
+    pub fn execute(&self) -> CpxResult<bool> {
+        let mut patterns = Vec::new();
+        for pattern in &self.exclude {
+            patterns.extend(parse_exclude_pattern_list(pattern).map_err(CpxError::Exclude)?);
+        }
+        let rules = build_exclude_rules(patterns).map_err(CpxError::Exclude)?;
+
+        let leftovers = find_leftovers(&self.destination, rules.as_ref())?;
+        if leftovers.is_empty() {
+            output::status(format!(
+                "No leftover files found in '{}'",
+                self.destination.display()
+            ));
+            return Ok(false);
+        }
+
+        print_leftovers(&leftovers)?;
+        let total: u64 = leftovers.iter().map(|l| l.size).sum();
+        let summary = format!("{} leftovers, {}", leftovers.len(), HumanBytes(total));
+
+        if self.dry_run {
+            output::status(format!("{} (dry run, nothing removed)", summary));
+            return Ok(true);
+        }
+        if !self.yes && !output::confirm(format!("Remove {}?", summary))? {
+            output::status("Nothing removed");
+            return Ok(true);
+        }
+
+        let mut failed = 0;
+        for leftover in &leftovers {
+            let result = if leftover.is_dir {
+                fs::remove_dir_all(&leftover.path)
+            } else {
+                fs::remove_file(&leftover.path)
+            };
+            if let Err(e) = result {
+                failed += 1;
+                output::error(format!(
+                    "failed to remove '{}': {}",
+                    leftover.path.display(),
+                    e
+                ));
+            }
+        }
+        output::status(format!(
+            "Removed {} of {} leftovers",
+            leftovers.len() - failed,
+            leftovers.len()
+        ));
+        Ok(true)
+    }
+}
+
+/// Walks `root` for files and directories named like cpx leftovers.
+///
+/// Leftover directories are reported once, with the total size of their
+/// contents, and are not searched further.
+pub fn find_leftovers(root: &Path, rules: Option<&ExcludeRules>) -> io::Result<Vec<Leftover>> {
+    let mut leftovers: Vec<Leftover> = Vec::new();
+    let mut skipped_dirs: Vec<PathBuf> = Vec::new();
+
+    for entry in WalkDir::new(root).skip_hidden(false).follow_links(false) {
+        let entry = entry.map_err(io::Error::other)?;
+        let path = entry.path();
+        if path == root {
+            continue;
+        }
+        let file_type = entry.file_type();
+
+        if let Some(leftover) = leftovers
+            .iter_mut()
+            .rev()
+            .find(|l| l.is_dir && path.starts_with(&l.path))
+        {
+            if file_type.is_file() {
+                leftover.size += entry.metadata().map(|m| m.len()).unwrap_or(0);
+            }
+            continue;
+        }
+        if skipped_dirs.iter().any(|dir| path.starts_with(dir)) {
+            continue;
+        }
+        if let Some(rules) = rules
+            && should_exclude(&path, root, rules)
+        {
+            if file_type.is_dir() {
+                skipped_dirs.push(path);
+            }
+            continue;
+        }
+
+        let is_leftover = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(is_leftover_name);
+        if is_leftover {
+            let size = if file_type.is_file() {
+                entry.metadata().map(|m| m.len()).unwrap_or(0)
+            } else {
+                0
+            };
+            leftovers.push(Leftover {
+                path,
+                size,
+                is_dir: file_type.is_dir(),
+            });
+        }
+    }
+    Ok(leftovers)
+}
+
+fn print_leftovers(leftovers: &[Leftover]) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    for leftover in leftovers {
+        writeln!(
+            out,
+            "{:>10}  {}",
+            HumanBytes(leftover.size).to_string(),
+            leftover.path.display()
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::helper::{DOCTOR_PREFIX, PARTIAL_SUFFIX, TEMP_PREFIX};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_leftovers_matches_temp_partial_and_doctor_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("keep.txt"), "keep").unwrap();
+        fs::write(root.join(format!("big.iso{}", PARTIAL_SUFFIX)), "12345").unwrap();
+        fs::write(root.join("sub").join(format!("{}a1b2", TEMP_PREFIX)), "123").unwrap();
+        let doctor = root.join(format!("{}42", DOCTOR_PREFIX));
+        fs::create_dir(&doctor).unwrap();
+        fs::write(doctor.join("sparse"), "1234567").unwrap();
+
+        let mut found = find_leftovers(root, None).unwrap();
+        found.sort_by(|a, b| a.path.cmp(&b.path));
+
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0].path, doctor);
+        assert!(found[0].is_dir);
+        assert_eq!(found[0].size, 7);
+        assert_eq!(found[1].size, 5);
+        assert_eq!(found[2].size, 3);
+        assert!(!found.iter().any(|l| l.path.ends_with("keep.txt")));
+    }
+
+    #[test]
+    fn test_find_leftovers_honours_excludes() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir(root.join("vendor")).unwrap();
+        fs::write(root.join("vendor").join(format!("x{}", PARTIAL_SUFFIX)), "").unwrap();
+        fs::write(root.join(format!("y{}", PARTIAL_SUFFIX)), "").unwrap();
+
+        let rules = build_exclude_rules(parse_exclude_pattern_list("vendor").unwrap())
+            .unwrap()
+            .unwrap();
+        let found = find_leftovers(root, Some(&rules)).unwrap();
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, root.join(format!("y{}", PARTIAL_SUFFIX)));
+    }
+}
@@ -0,0 +1,482 @@
+use crate::cli::args::{CopyOptions, Engine};
+use crate::core::fast_copy::fast_copy;
+use crate::error::CopyError;
+use crate::utility::filesystem::{block_device, filesystem_kind, mount_flags};
+use crate::utility::helper::{DOCTOR_PREFIX, describe_io_error};
+use clap::Args;
+use colored::Colorize;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug, Clone)]
+pub struct DoctorArgs {
+    #[arg(help = "Source path to probe (defaults to the current directory)")]
+    pub source: Option<PathBuf>,
+
+    #[arg(help = "Destination path to probe (defaults to the source)")]
+    pub destination: Option<PathBuf>,
+
+    #[arg(long, help = "Print the report as JSON")]
+    pub json: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn from_result(name: &'static str, result: io::Result<String>) -> Self {
+        match result {
+            Ok(detail) => Self {
+                name,
+                ok: true,
+                detail,
+            },
+            Err(e) => Self {
+                name,
+                ok: false,
+                detail: describe_io_error(&e),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LocationReport {
+    pub path: PathBuf,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DoctorReport {
+    pub os: String,
+    pub kernel: String,
+    pub source: LocationReport,
+    pub destination: LocationReport,
+    pub pair: Vec<Check>,
+}
+
+/// Scratch directory created inside a probed location, removed on drop.
+struct ProbeDir {
+    path: PathBuf,
+}
+
+impl ProbeDir {
+    fn create(location: &Path) -> io::Result<Self> {
+        let base = if location.is_dir() {
+            location.to_path_buf()
+        } else {
+            location
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."))
+                .to_path_buf()
+        };
+        let path = base.join(format!("{}{}", DOCTOR_PREFIX, std::process::id()));
+        fs::create_dir(&path)?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for ProbeDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+impl DoctorArgs {
+    pub fn execute(&self) -> io::Result<()> {
+        let source = self.source.clone().unwrap_or_else(|| PathBuf::from("."));
+        let destination = self.destination.clone().unwrap_or_else(|| source.clone());
+
+        let src_probe = ProbeDir::create(&source)?;
+        let dst_probe = if destination == source {
+            None
+        } else {
+            Some(ProbeDir::create(&destination)?)
+        };
+        let dst_dir = dst_probe.as_ref().map_or(&src_probe.path, |p| &p.path);
+
+        let report = DoctorReport {
+            os: std::env::consts::OS.to_string(),
+            kernel: kernel_version(),
+            source: probe_location(&source, &src_probe.path),
+            destination: probe_location(&destination, dst_dir),
+            pair: probe_pair(&src_probe.path, dst_dir),
+        };
+
+        if self.json {
+            let json = serde_json::to_string_pretty(&report).map_err(io::Error::other)?;
+            println!("{}", json);
+        } else {
+            print_report(&report)?;
+        }
+        Ok(())
+    }
+}
+
+fn probe_location(location: &Path, probe_dir: &Path) -> LocationReport {
+    let checks = vec![
+        Check::from_result(
+            "filesystem",
+            filesystem_kind(probe_dir).map(|k| {
+                if k.is_network() {
+                    format!(
+                        "{} (network; copies use fewer workers and larger buffers)",
+                        k.name()
+                    )
+                } else {
+                    k.name().to_string()
+                }
+            }),
+        ),
+        Check::from_result(
+            "mount flags",
+            mount_flags(probe_dir).map(|flags| flags.join(",")),
+        ),
+        Check::from_result("sparse files", probe_sparse(probe_dir)),
+        Check::from_result("xattr", probe_xattr(probe_dir)),
+        Check::from_result("symlinks", probe_symlink(probe_dir)),
+        Check::from_result("hard links", probe_hardlink(probe_dir)),
+        Check::from_result("max path length", probe_path_limits(probe_dir)),
+        Check::from_result("case sensitivity", probe_case_sensitivity(probe_dir)),
+        Check::from_result("timestamp granularity", probe_timestamps(probe_dir)),
+    ];
+    LocationReport {
+        path: location.to_path_buf(),
+        checks,
+    }
+}
+
+fn probe_pair(src_dir: &Path, dst_dir: &Path) -> Vec<Check> {
+    let src_file = src_dir.join("pair-source");
+    if let Err(e) = fs::write(&src_file, vec![0x5a; 64 * 1024]) {
+        return vec![Check::from_result("probe file", Err(e))];
+    }
+
+    vec![
+        Check::from_result(
+            "copy_file_range",
+            probe_copy_file_range(&src_file, &dst_dir.join("pair-offload")),
+        ),
+        Check::from_result(
+            "reflink",
+            reflink_copy::reflink(&src_file, dst_dir.join("pair-reflink"))
+                .map(|()| "supported".to_string()),
+        ),
+        Check::from_result("same device", probe_same_device(src_dir, dst_dir)),
+    ]
+}
+
+/// Whether both ends are on one disk, which a copy makes read and write at
+/// once.
+fn probe_same_device(src_dir: &Path, dst_dir: &Path) -> io::Result<String> {
+    let (Some(source), Some(destination)) = (block_device(src_dir)?, block_device(dst_dir)?) else {
+        return Ok("no block device to compare".to_string());
+    };
+    if source.name != destination.name {
+        return Ok(format!(
+            "no ({} and {})",
+            source.describe(),
+            destination.describe()
+        ));
+    }
+    Ok(match source.rotational {
+        Some(true) => format!(
+            "yes, {}: copies default to one file at a time",
+            source.describe()
+        ),
+        _ => format!(
+            "yes, {}: reads and writes share its bandwidth",
+            source.describe()
+        ),
+    })
+}
+
+/// Copy the probe file the way `--engine=offload` copies every file, and
+/// say how it went.
+fn probe_copy_file_range(source: &Path, destination: &Path) -> io::Result<String> {
+    let options = CopyOptions {
+        engine: Engine::Offload,
+        ..CopyOptions::none()
+    };
+    let len = fs::metadata(source)?.len();
+    match fast_copy(source, destination, len, None, &options) {
+        Ok(Some((mechanism, copied))) => Ok(format!(
+            "copied {} of {} bytes by {}",
+            copied, len, mechanism
+        )),
+        Ok(None) => Err(io::Error::other("fell back to reading and writing")),
+        Err(CopyError::Io(e)) => Err(e),
+        Err(e) => Err(io::Error::other(e.to_string())),
+    }
+}
+
+fn probe_sparse(dir: &Path) -> io::Result<String> {
+    let path = dir.join("sparse");
+    let file = fs::File::create(&path)?;
+    let logical = 8 * 1024 * 1024;
+    file.set_len(logical)?;
+    file.sync_all()?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let allocated = file.metadata()?.blocks() * 512;
+        if allocated < logical {
+            Ok(format!(
+                "holes kept ({} bytes allocated for {} logical)",
+                allocated, logical
+            ))
+        } else {
+            Err(io::Error::other("holes were materialized"))
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        Ok("unknown on this platform".to_string())
+    }
+}
+
+fn probe_xattr(dir: &Path) -> io::Result<String> {
+    #[cfg(unix)]
+    {
+        if !xattr::SUPPORTED_PLATFORM {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+        let path = dir.join("xattr");
+        fs::write(&path, b"x")?;
+        xattr::set(&path, "user.cpx.doctor", b"1")?;
+        match xattr::get(&path, "user.cpx.doctor")? {
+            Some(value) if value == b"1" => Ok("user namespace writable".to_string()),
+            _ => Err(io::Error::other("attribute did not round-trip")),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+fn probe_symlink(dir: &Path) -> io::Result<String> {
+    let link = dir.join("symlink");
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("target", &link)?;
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_file("target", &link)?;
+    fs::read_link(&link)?;
+    Ok("supported".to_string())
+}
+
+fn probe_hardlink(dir: &Path) -> io::Result<String> {
+    let original = dir.join("hardlink-original");
+    fs::write(&original, b"x")?;
+    fs::hard_link(&original, dir.join("hardlink"))?;
+    Ok("supported".to_string())
+}
+
+fn probe_path_limits(dir: &Path) -> io::Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let c_path = std::ffi::CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let path_max = unsafe { libc::pathconf(c_path.as_ptr(), libc::_PC_PATH_MAX) };
+        let name_max = unsafe { libc::pathconf(c_path.as_ptr(), libc::_PC_NAME_MAX) };
+        if path_max < 0 || name_max < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(format!("path {}, name {}", path_max, name_max))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Ok("path 260, name 255".to_string())
+    }
+}
+
+fn probe_case_sensitivity(dir: &Path) -> io::Result<String> {
+    fs::write(dir.join("case-probe"), b"")?;
+    if dir.join("CASE-PROBE").exists() {
+        Ok("case-insensitive".to_string())
+    } else {
+        Ok("case-sensitive".to_string())
+    }
+}
+
+fn probe_timestamps(dir: &Path) -> io::Result<String> {
+    use filetime::{FileTime, set_file_mtime};
+
+    let path = dir.join("timestamps");
+    fs::write(&path, b"")?;
+    set_file_mtime(&path, FileTime::from_unix_time(1_600_000_001, 123_456_789))?;
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(&path)?);
+
+    let granularity = match mtime.nanoseconds() {
+        123_456_789 => "1ns",
+        123_456_700..=123_456_788 => "100ns",
+        123_456_000..=123_456_699 => "1us",
+        123_000_000..=123_455_999 => "1ms",
+        _ if mtime.unix_seconds() % 2 == 0 => "2s",
+        _ => "1s",
+    };
+    Ok(granularity.to_string())
+}
+
+#[cfg(unix)]
+fn kernel_version() -> String {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return "unknown".to_string();
+    }
+    let field = |raw: &[libc::c_char]| {
+        unsafe { std::ffi::CStr::from_ptr(raw.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    format!("{} {}", field(&uts.sysname), field(&uts.release))
+}
+
+#[cfg(not(unix))]
+fn kernel_version() -> String {
+    "unknown".to_string()
+}
+
+fn print_report(report: &DoctorReport) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    writeln!(out, "{}", "cpx doctor".bold().underline())?;
+    writeln!(out, "System: {} ({})", report.os, report.kernel)?;
+
+    for (label, location) in [
+        ("Source", &report.source),
+        ("Destination", &report.destination),
+    ] {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{} {}",
+            format!("{}:", label).bold(),
+            location.path.display().to_string().cyan()
+        )?;
+        for check in &location.checks {
+            print_check(&mut out, check)?;
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(out, "{}", "Source -> Destination:".bold())?;
+    for check in &report.pair {
+        print_check(&mut out, check)?;
+    }
+    Ok(())
+}
+
+fn print_check(out: &mut impl Write, check: &Check) -> io::Result<()> {
+    let status = if check.ok {
+        "[PASS]".green().bold()
+    } else {
+        "[FAIL]".red().bold()
+    };
+    writeln!(out, "  {} {:<22} {}", status, check.name, check.detail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_probe_location_reports_every_check() {
+        let temp_dir = TempDir::new().unwrap();
+        let probe = ProbeDir::create(temp_dir.path()).unwrap();
+
+        let report = probe_location(temp_dir.path(), &probe.path);
+        let names: Vec<_> = report.checks.iter().map(|c| c.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "filesystem",
+                "mount flags",
+                "sparse files",
+                "xattr",
+                "symlinks",
+                "hard links",
+                "max path length",
+                "case sensitivity",
+                "timestamp granularity",
+            ]
+        );
+        assert!(
+            report
+                .checks
+                .iter()
+                .find(|c| c.name == "case sensitivity")
+                .unwrap()
+                .ok
+        );
+    }
+
+    #[test]
+    fn test_probe_pair_compares_devices() {
+        let temp_dir = TempDir::new().unwrap();
+        let probe = ProbeDir::create(temp_dir.path()).unwrap();
+
+        let checks = probe_pair(&probe.path, &probe.path);
+        let same_device = checks.iter().find(|c| c.name == "same device").unwrap();
+        assert!(same_device.ok);
+        assert!(!same_device.detail.starts_with("no ("));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_copy_file_range_probe_goes_through_the_offload_engine() {
+        let temp_dir = TempDir::new().unwrap();
+        let probe = ProbeDir::create(temp_dir.path()).unwrap();
+
+        let checks = probe_pair(&probe.path, &probe.path);
+        let offload = checks.iter().find(|c| c.name == "copy_file_range").unwrap();
+        if offload.ok {
+            assert!(
+                offload
+                    .detail
+                    .starts_with("copied 65536 of 65536 bytes by copy_file_range ("),
+                "{}",
+                offload.detail
+            );
+            assert_eq!(
+                fs::read(probe.path.join("pair-offload")).unwrap().len(),
+                65536
+            );
+        } else {
+            assert!(
+                offload.detail.contains("offload engine"),
+                "{}",
+                offload.detail
+            );
+        }
+    }
+
+    #[test]
+    fn test_probe_dir_is_removed_on_drop() {
+        let temp_dir = TempDir::new().unwrap();
+        let probe = ProbeDir::create(temp_dir.path()).unwrap();
+        let path = probe.path.clone();
+        assert!(path.is_dir());
+        drop(probe);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_failed_check_carries_errno_text() {
+        let check = Check::from_result("reflink", Err(io::Error::from_raw_os_error(libc::EXDEV)));
+        assert!(!check.ok);
+        assert!(check.detail.starts_with("EXDEV"));
+    }
+}
@@ -1 +1,7 @@
 pub mod args;
+pub mod clean;
+pub mod doctor;
+pub mod mv;
+pub mod status;
+pub mod undo;
+pub mod verify;
@@ -0,0 +1,524 @@
+use crate::cli::args::{BackupMode, CopyOptions, FollowSymlink, ReflinkMode};
+use crate::core::copy::copy;
+use crate::error::{CopyError, CopyResult, CpxError, CpxResult};
+use crate::utility::backup::{self, back_up};
+use crate::utility::helper::{names_contents, prompt_overwrite, same_file, temp_path_for};
+use crate::utility::journal::{self, Journal, JournalEntry, MoveMethod, Operation};
+use crate::utility::output;
+use crate::utility::preserve::PreserveAttr;
+use clap::Args;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug, Clone)]
+pub struct MoveArgs {
+    #[arg(help = "Source file(s) or directory(ies)", required = true)]
+    pub sources: Vec<PathBuf>,
+
+    #[arg(help = "Destination file or directory", required = true)]
+    pub destination: PathBuf,
+
+    #[arg(
+        short = 't',
+        long = "target-directory",
+        value_name = "DIRECTORY",
+        help = "move all SOURCE arguments into DIRECTORY"
+    )]
+    pub target_directory: Option<PathBuf>,
+
+    #[arg(
+        short = 'f',
+        long = "force",
+        overrides_with_all = ["interactive", "no_clobber"],
+        help = "do not prompt before overwriting (the default)"
+    )]
+    pub force: bool,
+
+    #[arg(
+        short = 'i',
+        long = "interactive",
+        overrides_with_all = ["force", "no_clobber"],
+        help = "prompt before overwrite"
+    )]
+    pub interactive: bool,
+
+    #[arg(
+        short = 'n',
+        long = "no-clobber",
+        overrides_with_all = ["force", "interactive"],
+        help = "do not overwrite an existing file"
+    )]
+    pub no_clobber: bool,
+
+    #[arg(
+        short = 'u',
+        long,
+        help = "move a file only when the target is missing or older than it"
+    )]
+    pub update: bool,
+
+    #[arg(
+        long,
+        help = "move a source only when its target is already there, leaving the rest in place"
+    )]
+    pub existing: bool,
+
+    #[arg(
+        short = 'b',
+        long = "backup",
+        value_name = "CONTROL",
+        default_missing_value = "existing",
+        num_args = 0..=1,
+        require_equals = true,
+        help = "make a backup of each existing target before it is replaced (none, numbered, existing, simple); a simple backup already there is never replaced, so the move fails instead"
+    )]
+    pub backup: Option<BackupMode>,
+
+    #[arg(
+        short = 'S',
+        long = "suffix",
+        value_name = "SUFFIX",
+        help = "suffix of simple backups instead of ~ (or $SIMPLE_BACKUP_SUFFIX)"
+    )]
+    pub suffix: Option<String>,
+
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        help = "print each source and where it went to stdout as it is moved"
+    )]
+    pub verbose: bool,
+
+    #[arg(
+        short = 'j',
+        default_value = "4",
+        help = "number of parallel copies when moving across filesystems"
+    )]
+    pub parallel: usize,
+}
+
+/// What to do when the target of a move already exists. As with mv, the
+/// last of -f, -i and -n given wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    Force,
+    Interactive,
+    NoClobber,
+}
+
+impl MoveArgs {
+    fn overwrite(&self) -> Overwrite {
+        if self.interactive {
+            Overwrite::Interactive
+        } else if self.no_clobber {
+            Overwrite::NoClobber
+        } else {
+            Overwrite::Force
+        }
+    }
+
+    /// Moves every source, reporting failures as it goes like mv does, and
+    /// journals what was moved so `cpx undo` can put it back.
+    ///
+    /// Returns the number of sources that could not be moved.
+    pub fn execute(&self) -> CpxResult<usize> {
+        let overwrite = self.overwrite();
+        let (sources, destination) = match &self.target_directory {
+            Some(target) => {
+                let mut sources = self.sources.clone();
+                sources.push(self.destination.clone());
+                (sources, target.clone())
+            }
+            None => (self.sources.clone(), self.destination.clone()),
+        };
+
+        let into_directory = destination.is_dir();
+        if !into_directory && sources.len() > 1 {
+            return Err(CpxError::Validation(format!(
+                "target '{}' is not a directory",
+                destination.display()
+            )));
+        }
+
+        let mut options = move_copy_options(self.parallel);
+        options.backup = self.backup;
+        if let Some(suffix) = backup::suffix_from(self.suffix.as_deref()) {
+            options.backup_suffix = suffix;
+        }
+        if options.backup.is_some_and(|mode| mode != BackupMode::None) {
+            backup::validate_suffix(&options.backup_suffix).map_err(CpxError::Validation)?;
+        }
+        let mut failed = 0;
+        let mut moves = Vec::with_capacity(sources.len());
+        for source in &sources {
+            if names_contents(source) {
+                match contents_moves(source, &destination, !self.existing) {
+                    Ok(contents) => moves.extend(contents),
+                    Err(e) => {
+                        failed += 1;
+                        output::error(format!("cannot move '{}': {}", source.display(), e));
+                    }
+                }
+                continue;
+            }
+            let target = if into_directory {
+                match source.file_name() {
+                    Some(name) => destination.join(name),
+                    None => {
+                        failed += 1;
+                        output::error(format!("cannot move '{}'", source.display()));
+                        continue;
+                    }
+                }
+            } else {
+                destination.clone()
+            };
+            moves.push((source.clone(), target));
+        }
+
+        let mut journal = Journal::new(Operation::Move);
+        for (source, target) in &moves {
+            // The source stays where it is, as nothing was moved.
+            if self.update && up_to_date(source, target) {
+                continue;
+            }
+            if self.existing && fs::symlink_metadata(target).is_err() {
+                continue;
+            }
+
+            match move_path(source, target, overwrite, &options) {
+                Ok(Some((method, backup))) => {
+                    if self.verbose {
+                        if let Some(backup) = &backup {
+                            output::verbose(None, target, "~>", backup);
+                        }
+                        output::verbose(None, source, "->", target);
+                    }
+                    match journal::stamp(target) {
+                        Ok(stamp) => journal.entries.push(JournalEntry {
+                            original: absolute(source),
+                            moved_to: absolute(target),
+                            method,
+                            backup: backup.as_deref().map(absolute),
+                            stamp,
+                        }),
+                        Err(e) => output::warning(format!(
+                            "'{}' cannot be undone: {}",
+                            target.display(),
+                            e
+                        )),
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    failed += 1;
+                    let reason = match e {
+                        CopyError::CopyFailed { reason, .. } => reason,
+                        e => e.to_string(),
+                    };
+                    output::error(format!(
+                        "cannot move '{}' to '{}': {}",
+                        source.display(),
+                        target.display(),
+                        reason
+                    ));
+                }
+            }
+        }
+
+        if !journal.entries.is_empty()
+            && let Err(e) = journal.record()
+        {
+            output::warning(format!("could not write the undo journal: {}", e));
+        }
+        Ok(failed)
+    }
+}
+
+/// Copy options used when a move has to fall back to copying: everything
+/// is preserved and symlinks are moved as symlinks.
+pub fn move_copy_options(parallel: usize) -> CopyOptions {
+    CopyOptions {
+        recursive: true,
+        parallel,
+        force: true,
+        preserve: PreserveAttr::all(),
+        follow_symlink: FollowSymlink::NoDereference,
+        reflink: Some(ReflinkMode::Auto),
+        ..CopyOptions::none()
+    }
+}
+
+/// Move `source` to exactly `target`, renaming when both are on the same
+/// filesystem and copying then removing the source otherwise. An existing
+/// target is first backed up as `options.backup` asks.
+///
+/// Returns how the move was done and where the target was backed up to,
+/// or `None` when an existing target was kept and nothing moved.
+pub fn move_path(
+    source: &Path,
+    target: &Path,
+    overwrite: Overwrite,
+    options: &CopyOptions,
+) -> CopyResult<Option<(MoveMethod, Option<PathBuf>)>> {
+    let source_metadata = fs::symlink_metadata(source)
+        .map_err(|_e| CopyError::InvalidSource(source.to_path_buf()))?;
+    let source_is_dir = source_metadata.is_dir();
+
+    if source_is_dir
+        && let (Ok(source), Some(Ok(parent))) = (
+            fs::canonicalize(source),
+            target.parent().map(|p| {
+                fs::canonicalize(if p.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    p
+                })
+            }),
+        )
+        && parent.starts_with(&source)
+    {
+        return Err(failed(
+            source.as_path(),
+            target,
+            "cannot move a directory into itself",
+        ));
+    }
+
+    let mut backup = None;
+    if let Ok(target_metadata) = fs::symlink_metadata(target) {
+        if same_file(&source_metadata, &target_metadata) {
+            return Err(failed(
+                source,
+                target,
+                "source and target are the same file",
+            ));
+        }
+        match overwrite {
+            Overwrite::NoClobber => return Ok(None),
+            Overwrite::Interactive if !prompt_overwrite(target)? => return Ok(None),
+            _ => {}
+        }
+        if target_metadata.is_dir() && !source_is_dir {
+            return Err(failed(
+                source,
+                target,
+                "cannot overwrite directory with non-directory",
+            ));
+        }
+        if !target_metadata.is_dir() && source_is_dir {
+            return Err(failed(
+                source,
+                target,
+                "cannot overwrite non-directory with directory",
+            ));
+        }
+        if let Some(mode) = options.backup
+            && mode != BackupMode::None
+        {
+            backup = Some(back_up(target, mode, &options.backup_suffix)?);
+        }
+    }
+
+    let moved = match fs::rename(source, target) {
+        Ok(()) => Ok(MoveMethod::Rename),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_and_remove(source, target, source_is_dir, options).map(|()| MoveMethod::CopyDelete)
+        }
+        Err(error) => Err(CopyError::OperationFailed {
+            operation: "rename".to_string(),
+            path: source.to_path_buf(),
+            error,
+        }),
+    };
+    match moved {
+        Ok(method) => Ok(Some((method, backup))),
+        Err(e) => {
+            // Nothing replaced the target, so it goes back where it was.
+            if let Some(backup) = &backup
+                && fs::symlink_metadata(target).is_err()
+            {
+                let _ = fs::rename(backup, target);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Move across filesystems: copy with the regular engine, then remove the
+/// source once the copy has fully succeeded.
+fn copy_and_remove(
+    source: &Path,
+    target: &Path,
+    source_is_dir: bool,
+    options: &CopyOptions,
+) -> CopyResult<()> {
+    if source_is_dir {
+        // A copied directory keeps its name, so copy it into a scratch
+        // directory next to the target and rename it into place from there.
+        let staging = temp_path_for(target);
+        fs::create_dir(&staging).map_err(|error| CopyError::OperationFailed {
+            operation: "create".to_string(),
+            path: staging.clone(),
+            error,
+        })?;
+        let result = copy(source, &staging, options).and_then(|()| {
+            let copied = staging.join(source.file_name().unwrap_or_default());
+            if target.is_dir() {
+                // Only an empty directory can be replaced, as with rename.
+                fs::remove_dir(target)?;
+            }
+            fs::rename(copied, target).map_err(CopyError::from)
+        });
+        let _ = fs::remove_dir_all(&staging);
+        result?;
+        fs::remove_dir_all(source)?;
+    } else {
+        copy(source, target, options)?;
+        fs::remove_file(source)?;
+    }
+    Ok(())
+}
+
+/// The moves that take what is in the directory `source`, written
+/// `src/.`, into the directory `destination`, creating it if missing and
+/// `create` allows. `source` itself stays where it is, emptied.
+fn contents_moves(
+    source: &Path,
+    destination: &Path,
+    create: bool,
+) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    match fs::metadata(destination) {
+        Ok(metadata) if !metadata.is_dir() => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("target '{}' is not a directory", destination.display()),
+            ));
+        }
+        Ok(_) => {}
+        // Nothing in it would be there already.
+        Err(_) if !create => return Ok(Vec::new()),
+        Err(_) => fs::create_dir(destination)?,
+    }
+    let source: PathBuf = source.components().collect();
+    let mut names = fs::read_dir(&source)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    Ok(names
+        .into_iter()
+        .map(|name| (source.join(&name), destination.join(name)))
+        .collect())
+}
+
+/// Whether `target` is a file modified no earlier than the file `source`,
+/// for `--update`.
+fn up_to_date(source: &Path, target: &Path) -> bool {
+    let modified = |path: &Path| {
+        fs::symlink_metadata(path)
+            .ok()
+            .filter(|m| !m.is_dir())
+            .and_then(|m| m.modified().ok())
+    };
+    matches!((modified(source), modified(target)), (Some(s), Some(t)) if s <= t)
+}
+
+/// `path` made absolute, so the journal still makes sense from another
+/// working directory.
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn failed(source: &Path, target: &Path, reason: &str) -> CopyError {
+    CopyError::CopyFailed {
+        source: source.to_path_buf(),
+        destination: target.to_path_buf(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_copy_and_remove_moves_renamed_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("photos");
+        fs::create_dir_all(source.join("2019")).unwrap();
+        fs::write(source.join("2019").join("a.jpg"), "jpeg").unwrap();
+        let target = temp_dir.path().join("archive");
+
+        copy_and_remove(&source, &target, true, &move_copy_options(1)).unwrap();
+
+        assert!(!source.exists());
+        assert_eq!(
+            fs::read_to_string(target.join("2019").join("a.jpg")).unwrap(),
+            "jpeg"
+        );
+        let leftovers: Vec<_> = fs::read_dir(temp_dir.path()).unwrap().collect();
+        assert_eq!(leftovers.len(), 1);
+    }
+
+    #[test]
+    fn test_copy_and_remove_keeps_timestamps() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("a.txt");
+        fs::write(&source, "data").unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(&source, mtime).unwrap();
+        let target = temp_dir.path().join("b.txt");
+
+        copy_and_remove(&source, &target, false, &move_copy_options(1)).unwrap();
+
+        assert!(!source.exists());
+        let copied =
+            filetime::FileTime::from_last_modification_time(&fs::metadata(&target).unwrap());
+        assert_eq!(copied, mtime);
+    }
+
+    #[test]
+    fn test_last_overwrite_flag_wins() {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            args: MoveArgs,
+        }
+
+        let parse = |flags: &[&str]| {
+            let mut argv = vec!["mv"];
+            argv.extend_from_slice(flags);
+            argv.extend_from_slice(&["a", "b"]);
+            Cli::parse_from(argv).args.overwrite()
+        };
+        assert_eq!(parse(&[]), Overwrite::Force);
+        assert_eq!(parse(&["-n", "-i"]), Overwrite::Interactive);
+        assert_eq!(parse(&["-i", "-n"]), Overwrite::NoClobber);
+        assert_eq!(parse(&["-i", "-f"]), Overwrite::Force);
+    }
+
+    #[test]
+    fn test_update_moves_only_over_older_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("a.txt");
+        let target = temp_dir.path().join("b.txt");
+        fs::write(&source, "new").unwrap();
+        assert!(!up_to_date(&source, &target));
+
+        fs::write(&target, "old").unwrap();
+        let set = |path: &Path, secs| {
+            filetime::set_file_mtime(path, filetime::FileTime::from_unix_time(secs, 0)).unwrap()
+        };
+        set(&source, 2_000);
+        set(&target, 1_000);
+        assert!(!up_to_date(&source, &target));
+        set(&target, 2_000);
+        assert!(up_to_date(&source, &target));
+        set(&target, 3_000);
+        assert!(up_to_date(&source, &target));
+    }
+}
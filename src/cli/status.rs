@@ -0,0 +1,386 @@
+use crate::cli::args::CopyOptions;
+use crate::error::{CpxError, CpxResult};
+use crate::utility::checksum::file_checksum;
+use crate::utility::exclude::{
+    ExcludeRules, build_exclude_rules, parse_exclude_pattern_list, should_exclude,
+};
+use crate::utility::itemize::{Item, itemize_plan};
+use crate::utility::output;
+use crate::utility::preprocess::{CopyPlan, preprocess_directory_at};
+use clap::Args;
+use jwalk::WalkDir;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug, Clone)]
+pub struct StatusArgs {
+    #[arg(help = "Source file or directory")]
+    pub source: PathBuf,
+
+    #[arg(help = "Destination to compare it with")]
+    pub destination: PathBuf,
+
+    #[arg(
+        short = 'c',
+        long,
+        help = "compare the contents of files whose size matches, instead of trusting their times"
+    )]
+    pub checksum: bool,
+
+    #[arg(
+        short = 'i',
+        long = "itemize-changes",
+        help = "also list each difference, as --itemize-changes would for a copy"
+    )]
+    pub itemize: bool,
+
+    #[arg(long, help = "Print the result as JSON")]
+    pub json: bool,
+
+    #[arg(
+        short = 'e',
+        long = "exclude",
+        value_name = "PATTERN",
+        help = "Skip paths matching pattern (can be specified multiple times, supports comma-separated values)"
+    )]
+    pub exclude: Vec<String>,
+}
+
+/// How many entries fall in each category.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Differences {
+    pub new: usize,
+    pub newer: usize,
+    pub older: usize,
+    pub changed: usize,
+    pub extra: usize,
+    pub identical: usize,
+}
+
+impl Differences {
+    pub fn in_sync(&self) -> bool {
+        self.new + self.newer + self.older + self.changed + self.extra == 0
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    #[serde(flatten)]
+    differences: &'a Differences,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<Vec<String>>,
+}
+
+/// What comparing the two trees found.
+#[derive(Debug, Default)]
+pub struct Comparison {
+    pub differences: Differences,
+    /// One item per difference, in destination order.
+    pub items: Vec<Item>,
+    /// Source entries that couldn't be read.
+    pub unreadable: usize,
+}
+
+impl StatusArgs {
+    /// Prints the differences between source and destination.
+    ///
+    /// Returns whether the two are in sync; errors reading either side are
+    /// an error even after the counts are printed.
+    pub fn execute(&self) -> CpxResult<bool> {
+        let mut patterns = Vec::new();
+        for pattern in &self.exclude {
+            patterns.extend(parse_exclude_pattern_list(pattern).map_err(CpxError::Exclude)?);
+        }
+        let rules = build_exclude_rules(patterns).map_err(CpxError::Exclude)?;
+
+        let comparison = compare(&self.source, &self.destination, rules, self.checksum)?;
+        let changes = self
+            .itemize
+            .then(|| comparison.items.iter().map(Item::to_string).collect());
+        if self.json {
+            let report = Report {
+                differences: &comparison.differences,
+                changes,
+            };
+            let json = serde_json::to_string_pretty(&report).map_err(io::Error::other)?;
+            output::data(json);
+        } else {
+            changes.into_iter().flatten().for_each(output::data);
+            let d = &comparison.differences;
+            for (name, count) in [
+                ("new", d.new),
+                ("newer", d.newer),
+                ("older", d.older),
+                ("changed", d.changed),
+                ("extra", d.extra),
+                ("identical", d.identical),
+            ] {
+                output::data(format!("{} {}", name, count));
+            }
+        }
+
+        if comparison.unreadable > 0 {
+            return Err(CpxError::Validation(format!(
+                "{} source entries could not be read",
+                comparison.unreadable
+            )));
+        }
+        Ok(comparison.differences.in_sync())
+    }
+}
+
+/// Compare `source` with `destination`, which it would be copied onto, for
+/// `cpx status`.
+///
+/// The source is planned as a copy onto the destination would plan it, and
+/// each planned entry is sorted by how the destination compares: `new` when
+/// it is missing there, `newer` or `older` by modification time when the
+/// two differ, `changed` when the times agree but the size (or, with
+/// `--checksum`, the contents) doesn't, and `identical` otherwise. Entries
+/// only the destination has are `extra`, unless an exclude pattern covers
+/// them, as they would be for a copy that deletes.
+pub fn compare(
+    source: &Path,
+    destination: &Path,
+    rules: Option<ExcludeRules>,
+    checksum: bool,
+) -> CpxResult<Comparison> {
+    let mut options = CopyOptions::none();
+    options.recursive = true;
+    options.exclude_rules = rules;
+
+    let source_meta =
+        fs::symlink_metadata(source).map_err(|_| CpxError::InvalidPath(source.to_path_buf()))?;
+    let plan = if source_meta.is_dir() {
+        preprocess_directory_at(source, destination, &options)?
+    } else {
+        let mut plan = CopyPlan::new();
+        plan.add_file(
+            source.to_path_buf(),
+            destination.to_path_buf(),
+            source_meta.len(),
+        );
+        plan
+    };
+    for entry in &plan.unreadable {
+        output::error(format!(
+            "cannot read '{}': {}",
+            entry.path.display(),
+            entry.reason
+        ));
+    }
+
+    let mut comparison = Comparison {
+        unreadable: plan.unreadable.len(),
+        ..Comparison::default()
+    };
+    let d = &mut comparison.differences;
+    let mut identical = HashSet::new();
+    for task in &plan.files {
+        let category = compare_file(&task.source, &task.destination, checksum)?;
+        if category == Category::Identical {
+            identical.insert(task.destination.as_path());
+        }
+        category.count(d);
+    }
+    for task in &plan.symlinks {
+        let category = match fs::symlink_metadata(&task.destination) {
+            Err(_) => Category::New,
+            Ok(_) => match (
+                fs::read_link(&task.source),
+                fs::read_link(&task.destination),
+            ) {
+                (Ok(a), Ok(b)) if a == b => Category::Identical,
+                _ => Category::Changed,
+            },
+        };
+        if category == Category::Identical {
+            identical.insert(task.destination.as_path());
+        }
+        category.count(d);
+    }
+    for task in &plan.specials {
+        match fs::symlink_metadata(&task.destination) {
+            Err(_) => d.new += 1,
+            Ok(_) => {
+                d.identical += 1;
+                identical.insert(task.destination.as_path());
+            }
+        }
+    }
+    for task in &plan.directories {
+        match fs::symlink_metadata(&task.destination) {
+            Err(_) => d.new += 1,
+            Ok(meta) if !meta.is_dir() => d.changed += 1,
+            Ok(_) => {}
+        }
+    }
+
+    comparison.items = itemize_plan(&plan, &options)
+        .into_iter()
+        .filter(|item| !identical.contains(item.destination()))
+        .collect();
+    if source_meta.is_dir() && destination.is_dir() {
+        for (path, is_dir) in extra_entries(source, destination, options.exclude_rules.as_ref())? {
+            comparison.differences.extra += 1;
+            comparison
+                .items
+                .push(Item::message("*deleting", &path, is_dir));
+        }
+    }
+    comparison
+        .items
+        .sort_by(|a, b| a.destination().cmp(b.destination()));
+    Ok(comparison)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Category {
+    New,
+    Newer,
+    Older,
+    Changed,
+    Identical,
+}
+
+impl Category {
+    fn count(self, differences: &mut Differences) {
+        *match self {
+            Category::New => &mut differences.new,
+            Category::Newer => &mut differences.newer,
+            Category::Older => &mut differences.older,
+            Category::Changed => &mut differences.changed,
+            Category::Identical => &mut differences.identical,
+        } += 1;
+    }
+}
+
+fn compare_file(source: &Path, destination: &Path, checksum: bool) -> io::Result<Category> {
+    let Ok(dest_meta) = fs::symlink_metadata(destination) else {
+        return Ok(Category::New);
+    };
+    if !dest_meta.is_file() {
+        return Ok(Category::Changed);
+    }
+    let source_meta = fs::metadata(source)?;
+    let same_size = source_meta.len() == dest_meta.len();
+    if checksum && same_size && file_checksum(source)? == file_checksum(destination)? {
+        return Ok(Category::Identical);
+    }
+    Ok(match mtime_order(&source_meta, &dest_meta) {
+        std::cmp::Ordering::Greater => Category::Newer,
+        std::cmp::Ordering::Less => Category::Older,
+        std::cmp::Ordering::Equal if same_size && !checksum => Category::Identical,
+        std::cmp::Ordering::Equal => Category::Changed,
+    })
+}
+
+fn mtime_order(source: &Metadata, destination: &Metadata) -> std::cmp::Ordering {
+    match (source.modified(), destination.modified()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => std::cmp::Ordering::Equal,
+    }
+}
+
+/// Entries under `destination` with nothing at the same place under
+/// `source`, leaving out those the exclude patterns cover.
+fn extra_entries(
+    source: &Path,
+    destination: &Path,
+    rules: Option<&ExcludeRules>,
+) -> io::Result<Vec<(PathBuf, bool)>> {
+    let mut extra = Vec::new();
+    for entry in WalkDir::new(destination)
+        .skip_hidden(false)
+        .follow_links(false)
+        .min_depth(1)
+    {
+        let entry = entry.map_err(io::Error::other)?;
+        let path = entry.path();
+        let Ok(relative) = path.strip_prefix(destination) else {
+            continue;
+        };
+        if rules.is_some_and(|rules| should_exclude(&path, destination, rules)) {
+            continue;
+        }
+        if fs::symlink_metadata(source.join(relative)).is_err() {
+            extra.push((path, entry.file_type().is_dir()));
+        }
+    }
+    Ok(extra)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::{FileTime, set_file_mtime};
+    use tempfile::TempDir;
+
+    fn write(path: &Path, contents: &str, mtime: i64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+        set_file_mtime(path, FileTime::from_unix_time(mtime, 0)).unwrap();
+    }
+
+    #[test]
+    fn test_entries_are_sorted_into_categories() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dst");
+        write(&source.join("same.txt"), "same", 1_000);
+        write(&dest.join("same.txt"), "same", 1_000);
+        write(&source.join("new.txt"), "new", 1_000);
+        write(&source.join("sub/newer.txt"), "edited", 2_000);
+        write(&dest.join("sub/newer.txt"), "original", 1_000);
+        write(&source.join("older.txt"), "original", 1_000);
+        write(&dest.join("older.txt"), "edited at dest", 2_000);
+        write(&source.join("resized.txt"), "four", 1_000);
+        write(&dest.join("resized.txt"), "three", 1_000);
+        write(&dest.join("gone.txt"), "removed at source", 1_000);
+        write(&dest.join("build/out.o"), "object", 1_000);
+
+        let comparison = compare(&source, &dest, None, false).unwrap();
+        assert_eq!(
+            comparison.differences,
+            Differences {
+                new: 1,
+                newer: 1,
+                older: 1,
+                changed: 1,
+                extra: 3,
+                identical: 1,
+            }
+        );
+        let lines: Vec<String> = comparison.items.iter().map(Item::to_string).collect();
+        assert!(lines.contains(&format!("*deleting {}/", dest.join("build").display())));
+        assert!(lines.contains(&format!(">f+++++++ {}", dest.join("new.txt").display())));
+        assert!(!lines.iter().any(|line| line.ends_with("same.txt")));
+
+        // Excluded at the destination is not extra.
+        let rules = build_exclude_rules(parse_exclude_pattern_list("build").unwrap()).unwrap();
+        let comparison = compare(&source, &dest, rules, false).unwrap();
+        assert_eq!(comparison.differences.extra, 1);
+    }
+
+    #[test]
+    fn test_checksum_looks_past_times() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dst");
+        // Touched but unchanged, and changed without the time moving.
+        write(&source.join("touched.txt"), "same", 2_000);
+        write(&dest.join("touched.txt"), "same", 1_000);
+        write(&source.join("rotten.txt"), "original", 1_000);
+        write(&dest.join("rotten.txt"), "orig1nal", 1_000);
+
+        let by_time = compare(&source, &dest, None, false).unwrap().differences;
+        assert_eq!((by_time.newer, by_time.identical), (1, 1));
+
+        let by_contents = compare(&source, &dest, None, true).unwrap().differences;
+        assert_eq!((by_contents.changed, by_contents.identical), (1, 1));
+        assert!(!by_contents.in_sync());
+    }
+}
@@ -0,0 +1,374 @@
+use crate::cli::args::CopyOptions;
+use crate::cli::mv::{Overwrite, move_copy_options, move_path};
+use crate::error::{CpxError, CpxResult};
+use crate::utility::journal::{self, CreatedEntry, Journal, JournalEntry, Operation};
+use crate::utility::output;
+use clap::Args;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug, Clone)]
+pub struct UndoArgs {
+    #[arg(
+        value_name = "JOURNAL",
+        help = "Journal to undo (defaults to the last operation)"
+    )]
+    pub journal: Option<PathBuf>,
+
+    #[arg(long, help = "undo even if destinations were modified since")]
+    pub force: bool,
+
+    #[arg(short = 'y', long, help = "undo without asking")]
+    pub yes: bool,
+
+    #[arg(long = "dry-run", help = "only show what would be undone")]
+    pub dry_run: bool,
+}
+
+/// Why an entry of the journal can't simply be moved back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// The moved path is gone; it is skipped.
+    Missing,
+    /// Something is at the original path again; it is skipped.
+    OriginalTaken,
+    /// The moved path changed since the move; needs `--force`.
+    Modified,
+}
+
+impl UndoArgs {
+    /// Shows what undoing the journaled operation would do, then moves
+    /// everything back.
+    ///
+    /// Returns the number of paths that could not be put back.
+    pub fn execute(&self) -> CpxResult<usize> {
+        let path = match self.journal.clone().or_else(journal::default_path) {
+            Some(path) => path,
+            None => {
+                return Err(CpxError::Validation(
+                    "no journal location; pass the JOURNAL to undo".to_string(),
+                ));
+            }
+        };
+        let journal = match Journal::load(&path) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.journal.is_none() => {
+                output::status("Nothing to undo");
+                return Ok(0);
+            }
+            Err(e) => {
+                return Err(CpxError::Validation(format!(
+                    "cannot read journal '{}': {}",
+                    path.display(),
+                    e
+                )));
+            }
+        };
+        if journal.operation == Operation::Copy && !journal.created.is_empty() {
+            return self.undo_copy(&journal, &path);
+        }
+        if journal.operation != Operation::Move || journal.entries.is_empty() {
+            output::status("Nothing to undo: the last operation was a copy");
+            return Ok(0);
+        }
+
+        // Put things back in the reverse order they were moved.
+        let steps: Vec<(&JournalEntry, Option<Problem>)> = journal
+            .entries
+            .iter()
+            .rev()
+            .map(|entry| (entry, check(entry)))
+            .collect();
+        for (entry, problem) in &steps {
+            print_step(entry, *problem);
+        }
+
+        let modified = steps
+            .iter()
+            .filter(|(_, p)| *p == Some(Problem::Modified))
+            .count();
+        if modified > 0 && !self.force {
+            return Err(CpxError::Validation(format!(
+                "{} path(s) were modified since the move; use --force to undo anyway",
+                modified
+            )));
+        }
+        if self.dry_run {
+            output::status("Dry run, nothing moved");
+            return Ok(0);
+        }
+        if !self.yes && !output::confirm("Undo the move?")? {
+            output::status("Nothing undone");
+            return Ok(0);
+        }
+
+        let options = move_copy_options(4);
+        let mut remaining = Journal::new(Operation::Move);
+        for (entry, problem) in steps {
+            let result = match problem {
+                Some(Problem::Missing) => Err("it no longer exists".to_string()),
+                Some(Problem::OriginalTaken) => {
+                    Err(format!("'{}' already exists", entry.original.display()))
+                }
+                _ => move_back(entry, &options).map_err(|e| e.to_string()),
+            };
+            if let Err(reason) = result {
+                output::error(format!(
+                    "cannot move '{}' back: {}",
+                    entry.moved_to.display(),
+                    reason
+                ));
+                remaining.entries.insert(0, entry.clone());
+            }
+        }
+
+        // Whatever could not be put back stays in the journal for another try.
+        let saved = if remaining.entries.is_empty() {
+            fs::remove_file(&path)
+        } else {
+            remaining.save(&path)
+        };
+        if let Err(e) = saved {
+            output::warning(format!(
+                "could not update journal '{}': {}",
+                path.display(),
+                e
+            ));
+        }
+        output::status(format!(
+            "Undid {} of {} moves",
+            journal.entries.len() - remaining.entries.len(),
+            journal.entries.len()
+        ));
+        Ok(remaining.entries.len())
+    }
+
+    /// Remove the directories a copy created, with what it put in them.
+    /// Returns the number that are still there.
+    fn undo_copy(&self, journal: &Journal, path: &Path) -> CpxResult<usize> {
+        let steps: Vec<(&CreatedEntry, Option<Problem>)> = journal
+            .created
+            .iter()
+            .map(|entry| (entry, check_created(entry)))
+            .collect();
+        for (entry, problem) in &steps {
+            let note = match problem {
+                Some(Problem::Missing) => " (skipped, no longer exists)",
+                Some(Problem::Modified) => " (modified since the copy)",
+                _ => "",
+            };
+            output::status(format!("remove '{}'{}", entry.path.display(), note));
+        }
+
+        let modified = steps
+            .iter()
+            .filter(|(_, p)| *p == Some(Problem::Modified))
+            .count();
+        if modified > 0 && !self.force {
+            return Err(CpxError::Validation(format!(
+                "{} director(ies) were modified since the copy; use --force to undo anyway",
+                modified
+            )));
+        }
+        if self.dry_run {
+            output::status("Dry run, nothing removed");
+            return Ok(0);
+        }
+        if !self.yes && !output::confirm("Remove what the copy created?")? {
+            output::status("Nothing undone");
+            return Ok(0);
+        }
+
+        let mut remaining = Journal {
+            previous: journal.previous.clone(),
+            ..Journal::new(Operation::Copy)
+        };
+        for (entry, problem) in steps {
+            if problem == Some(Problem::Missing) {
+                continue;
+            }
+            if let Err(e) = fs::remove_dir_all(&entry.path) {
+                output::error(format!("cannot remove '{}': {}", entry.path.display(), e));
+                remaining.created.push(entry.clone());
+            }
+        }
+
+        // Once the copy is undone, the move before it is next.
+        let saved = if !remaining.created.is_empty() {
+            remaining.save(path)
+        } else if let Some(previous) = &journal.previous {
+            previous.save(path)
+        } else {
+            fs::remove_file(path)
+        };
+        if let Err(e) = saved {
+            output::warning(format!(
+                "could not update journal '{}': {}",
+                path.display(),
+                e
+            ));
+        }
+        output::status(format!(
+            "Removed {} of {} directories",
+            journal.created.len() - remaining.created.len(),
+            journal.created.len()
+        ));
+        Ok(remaining.created.len())
+    }
+}
+
+/// Whether a directory a copy created is gone or changed since.
+fn check_created(entry: &CreatedEntry) -> Option<Problem> {
+    match entry.changed_since() {
+        Err(_) => Some(Problem::Missing),
+        Ok(true) => Some(Problem::Modified),
+        Ok(false) => None,
+    }
+}
+
+/// What stands in the way of moving `entry` back, if anything.
+pub fn check(entry: &JournalEntry) -> Option<Problem> {
+    let stamp = match journal::stamp(&entry.moved_to) {
+        Ok(stamp) => stamp,
+        Err(_) => return Some(Problem::Missing),
+    };
+    if fs::symlink_metadata(&entry.original).is_ok() {
+        return Some(Problem::OriginalTaken);
+    }
+    if stamp != entry.stamp {
+        return Some(Problem::Modified);
+    }
+    None
+}
+
+fn print_step(entry: &JournalEntry, problem: Option<Problem>) {
+    let note = match problem {
+        None => "",
+        Some(Problem::Missing) => " (skipped, no longer exists)",
+        Some(Problem::OriginalTaken) => " (skipped, original path is taken)",
+        Some(Problem::Modified) => " (modified since the move)",
+    };
+    output::status(format!(
+        "move '{}' back to '{}'{}",
+        entry.moved_to.display(),
+        entry.original.display(),
+        note
+    ));
+    if let Some(backup) = &entry.backup
+        && matches!(problem, None | Some(Problem::Modified))
+    {
+        output::status(format!(
+            "restore '{}' to '{}'",
+            backup.display(),
+            entry.moved_to.display()
+        ));
+    }
+}
+
+/// Move `entry` back to where it came from, recreating its parent
+/// directories if they were removed since, and restore what it replaced.
+fn move_back(entry: &JournalEntry, options: &CopyOptions) -> io::Result<()> {
+    if let Some(parent) = entry.original.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    move_path(
+        &entry.moved_to,
+        &entry.original,
+        Overwrite::NoClobber,
+        options,
+    )
+    .map_err(io::Error::other)?;
+    if let Some(backup) = &entry.backup {
+        fs::rename(backup, &entry.moved_to)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::journal::MoveMethod;
+    use tempfile::TempDir;
+
+    fn entry_for(original: PathBuf, moved_to: PathBuf) -> JournalEntry {
+        JournalEntry {
+            stamp: journal::stamp(&moved_to).unwrap(),
+            original,
+            moved_to,
+            method: MoveMethod::Rename,
+            backup: None,
+        }
+    }
+
+    #[test]
+    fn test_check_spots_modified_and_taken_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let original = temp_dir.path().join("inbox/notes.txt");
+        let moved_to = temp_dir.path().join("notes.txt");
+        fs::write(&moved_to, "notes").unwrap();
+        let entry = entry_for(original.clone(), moved_to.clone());
+        assert_eq!(check(&entry), None);
+
+        fs::write(&moved_to, "more notes").unwrap();
+        assert_eq!(check(&entry), Some(Problem::Modified));
+
+        fs::create_dir_all(original.parent().unwrap()).unwrap();
+        fs::write(&original, "new").unwrap();
+        assert_eq!(check(&entry), Some(Problem::OriginalTaken));
+
+        fs::remove_file(&moved_to).unwrap();
+        assert_eq!(check(&entry), Some(Problem::Missing));
+    }
+
+    #[test]
+    fn test_undo_copy_removes_created_directories_only() {
+        let temp_dir = TempDir::new().unwrap();
+        let existing = temp_dir.path().join("backup");
+        let created = existing.join("2024");
+        fs::create_dir_all(&created).unwrap();
+        fs::write(existing.join("keep.txt"), "keep").unwrap();
+        fs::write(created.join("photo.jpg"), "jpeg").unwrap();
+        let path = temp_dir.path().join("journal.json");
+        Journal::for_copy(std::slice::from_ref(&created))
+            .save(&path)
+            .unwrap();
+
+        let undo = UndoArgs {
+            journal: Some(path.clone()),
+            force: false,
+            yes: true,
+            dry_run: false,
+        };
+        assert_eq!(undo.execute().unwrap(), 0);
+
+        assert!(!created.exists());
+        assert_eq!(
+            fs::read_to_string(existing.join("keep.txt")).unwrap(),
+            "keep"
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_move_back_recreates_removed_parent_and_restores_backup() {
+        let temp_dir = TempDir::new().unwrap();
+        let original = temp_dir.path().join("inbox/2024/notes.txt");
+        let moved_to = temp_dir.path().join("notes.txt");
+        let backup = temp_dir.path().join("notes.txt~");
+        fs::write(&moved_to, "moved").unwrap();
+        fs::write(&backup, "replaced").unwrap();
+        let entry = JournalEntry {
+            backup: Some(backup.clone()),
+            ..entry_for(original.clone(), moved_to.clone())
+        };
+
+        move_back(&entry, &move_copy_options(1)).unwrap();
+
+        assert_eq!(fs::read_to_string(&original).unwrap(), "moved");
+        assert_eq!(fs::read_to_string(&moved_to).unwrap(), "replaced");
+        assert!(!backup.exists());
+    }
+}
@@ -1,9 +1,15 @@
-use super::loader::{find_config_files, load_config};
+use super::loader::{
+    ConfigSource, config_candidates, config_file_keys, find_config_files, load_config,
+    load_config_file,
+};
 use super::schema::Config;
+use crate::utility::output;
 use clap::Subcommand;
 use colored::Colorize;
+use serde::Serialize;
 use std::fs;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Subcommand, Clone)]
 pub enum ConfigCommand {
@@ -14,8 +20,14 @@ pub enum ConfigCommand {
     },
     /// Show current config
     Show,
-    /// Show config file locations
-    Path,
+    /// Show every config file location and what was found there
+    Path {
+        #[arg(long, value_name = "PATH", help = "Also report this config file")]
+        config: Option<PathBuf>,
+
+        #[arg(long, help = "Print the candidates as JSON")]
+        json: bool,
+    },
 }
 
 impl ConfigCommand {
@@ -23,7 +35,7 @@ impl ConfigCommand {
         match self {
             ConfigCommand::Init { force } => init_config(*force),
             ConfigCommand::Show => show_config(),
-            ConfigCommand::Path => show_paths(),
+            ConfigCommand::Path { config, json } => show_paths(config.as_deref(), *json),
         }
     }
 }
@@ -71,10 +83,10 @@ fn init_config(force: bool) -> std::io::Result<()> {
     let mut file = fs::File::create(&config_path)?;
     file.write_all(commented_content.as_bytes())?;
 
-    println!(
+    output::status(format!(
         "Created config file at: {}",
         config_path.display().to_string().cyan()
-    );
+    ));
     Ok(())
 }
 
@@ -82,8 +94,8 @@ fn show_config() -> std::io::Result<()> {
     let config_files = find_config_files();
 
     if config_files.is_empty() {
-        println!("{} No config files found", "Info:".yellow().bold());
-        println!("\nCreate one with: {}", "cpx config init".green());
+        output::status(format!("{} No config files found", "Info:".yellow().bold()));
+        output::status(format!("\nCreate one with: {}", "cpx config init".green()));
         return Ok(());
     }
 
@@ -119,51 +131,93 @@ fn show_config() -> std::io::Result<()> {
     Ok(())
 }
 
-fn show_paths() -> std::io::Result<()> {
-    use std::path::PathBuf;
+#[derive(Debug, Serialize)]
+struct CandidateReport {
+    source: ConfigSource,
+    path: PathBuf,
+    exists: bool,
+    parsed: bool,
+    effective: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    keys: Vec<String>,
+}
 
-    println!("{}", "Effective Config File".bold().underline());
-    println!();
+fn inspect_candidates(cli_path: Option<&Path>) -> Vec<CandidateReport> {
+    let mut found_effective = false;
+    config_candidates(cli_path)
+        .into_iter()
+        .map(|candidate| {
+            let exists = candidate.path.exists();
+            let (parsed, error, keys) = if exists {
+                match load_config_file(&candidate.path)
+                    .and_then(|_| config_file_keys(&candidate.path))
+                {
+                    Ok(keys) => (true, None, keys),
+                    Err(e) => (false, Some(e.to_string()), Vec::new()),
+                }
+            } else {
+                (false, None, Vec::new())
+            };
+            let effective = parsed && !found_effective;
+            found_effective |= effective;
+            CandidateReport {
+                source: candidate.source,
+                path: candidate.path,
+                exists,
+                parsed,
+                effective,
+                error,
+                keys,
+            }
+        })
+        .collect()
+}
 
-    let mut effective: Option<PathBuf> = None;
+fn show_paths(cli_path: Option<&Path>, json: bool) -> std::io::Result<()> {
+    let candidates = inspect_candidates(cli_path);
 
-    //Project config
-    let project = PathBuf::from("./cpxconfig.toml");
-    if project.exists() {
-        effective = Some(project);
+    if json {
+        let output = serde_json::to_string_pretty(&candidates).map_err(std::io::Error::other)?;
+        println!("{}", output);
+        return Ok(());
     }
 
-    //User config
-    if effective.is_none()
-        && let Some(config_dir) = dirs::config_dir()
-    {
-        let user = config_dir.join("cpx").join("cpxconfig.toml");
-        if user.exists() {
-            effective = Some(user);
-        }
-    }
+    println!("{}", "Config Search Order".bold().underline());
+    println!();
 
-    //System config (Unix)
-    #[cfg(unix)]
-    if effective.is_none() {
-        let system = PathBuf::from("/etc/cpx/config.toml");
-        if system.exists() {
-            effective = Some(system);
+    for (i, candidate) in candidates.iter().enumerate() {
+        let status = if candidate.effective {
+            "effective".green().bold()
+        } else if candidate.parsed {
+            "shadowed".yellow()
+        } else if let Some(error) = &candidate.error {
+            format!("invalid: {}", error).red()
+        } else {
+            "not found".dimmed()
+        };
+        println!(
+            "  {}. {:<16} {}  {}",
+            i + 1,
+            candidate.source.label(),
+            candidate.path.display().to_string().cyan(),
+            status
+        );
+        if !candidate.keys.is_empty() {
+            println!("       keys: {}", candidate.keys.join(", ").dimmed());
         }
     }
 
-    match effective {
-        Some(path) => {
-            println!("{}", path.display().to_string().cyan());
-        }
-        None => {
-            println!("{}", "No config file found — using defaults".dimmed());
-        }
+    if !candidates.iter().any(|c| c.effective) {
+        println!();
+        println!("{}", "No config file found — using defaults".dimmed());
     }
 
     println!();
     println!("{}", "Priority Order:".bold());
-    println!("  CLI flags > Project config > User config > System config > Defaults");
+    println!(
+        "  CLI flags > --config > CPX_CONFIG > Project > User > XDG_CONFIG_DIRS > System > Defaults"
+    );
 
     Ok(())
 }
@@ -187,6 +241,7 @@ fn add_comments_to_config(toml: &str) -> String {
             }
             l if l.starts_with("[copy]") => {
                 result.push_str("\n# Copy operation settings\n");
+                result.push_str("# engine: \"auto\", \"offload\", \"buffered\", \"mmap\"\n");
             }
             l if l.starts_with("[preserve]") => {
                 result.push_str("\n# Preserve file attributes\n");
@@ -203,6 +258,7 @@ fn add_comments_to_config(toml: &str) -> String {
                 result.push_str(
                     "# mode: \"none\", \"simple\" (~), \"numbered\" (~1~, ~2~), \"existing\"\n",
                 );
+                result.push_str("# suffix: what simple backups append\n");
             }
             l if l.starts_with("[reflink]") => {
                 result.push_str("\n# Copy-on-Write (reflink) settings\n");
@@ -218,7 +274,7 @@ fn add_comments_to_config(toml: &str) -> String {
                 result.push_str("# Supported progress bar colors: black, red, green, yellow, blue, magenta, cyan, white\n");
             }
             l if l.starts_with("[progress.behavior]") => {
-                result.push_str("# Progress bar behavior\n");
+                result.push_str("# Progress bar behavior: copies below both min_bytes and min_files get no bar\n");
             }
             _ => {}
         }
@@ -229,3 +285,36 @@ fn add_comments_to_config(toml: &str) -> String {
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_inspect_candidates_marks_first_valid_file_effective() {
+        let temp_dir = TempDir::new().unwrap();
+        let cli = temp_dir.path().join("custom.toml");
+        fs::write(&cli, "[copy]\nparallel = 2\n").unwrap();
+
+        let candidates = inspect_candidates(Some(&cli));
+        let first = &candidates[0];
+        assert_eq!(first.source, ConfigSource::Cli);
+        assert!(first.exists && first.parsed && first.effective);
+        assert_eq!(first.keys, vec!["copy.parallel"]);
+        assert_eq!(candidates.iter().filter(|c| c.effective).count(), 1);
+    }
+
+    #[test]
+    fn test_inspect_candidates_reports_parse_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let cli = temp_dir.path().join("broken.toml");
+        fs::write(&cli, "[copy]\nparallel = \"many\"\n").unwrap();
+
+        let candidates = inspect_candidates(Some(&cli));
+        let first = &candidates[0];
+        assert!(first.exists);
+        assert!(!first.parsed && !first.effective);
+        assert!(first.error.is_some());
+    }
+}
@@ -1,28 +1,83 @@
 use super::schema::Config;
 use crate::error::{ConfigError, ConfigResult};
+use serde::Serialize;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-pub fn find_config_files() -> Vec<PathBuf> {
-    let mut paths = Vec::new();
-    let project_config = PathBuf::from("./cpxconfig.toml");
-    if project_config.exists() {
-        paths.push(project_config);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfigSource {
+    Cli,
+    Env,
+    Project,
+    User,
+    XdgDirs,
+    System,
+}
+
+impl ConfigSource {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConfigSource::Cli => "--config",
+            ConfigSource::Env => "CPX_CONFIG",
+            ConfigSource::Project => "project",
+            ConfigSource::User => "user",
+            ConfigSource::XdgDirs => "XDG_CONFIG_DIRS",
+            ConfigSource::System => "system",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigCandidate {
+    pub source: ConfigSource,
+    pub path: PathBuf,
+}
+
+/// Every location a config file may be read from, highest priority first.
+pub fn config_candidates(cli_path: Option<&Path>) -> Vec<ConfigCandidate> {
+    let mut candidates = Vec::new();
+    let mut push = |source, path| candidates.push(ConfigCandidate { source, path });
+
+    if let Some(path) = cli_path {
+        push(ConfigSource::Cli, path.to_path_buf());
+    }
+    if let Some(path) = std::env::var_os("CPX_CONFIG").filter(|p| !p.is_empty()) {
+        push(ConfigSource::Env, PathBuf::from(path));
     }
+    push(ConfigSource::Project, PathBuf::from("./cpxconfig.toml"));
     if let Some(config_dir) = dirs::config_dir() {
-        let user_config = config_dir.join("cpx").join("cpxconfig.toml");
-        if user_config.exists() {
-            paths.push(user_config);
-        }
+        push(
+            ConfigSource::User,
+            config_dir.join("cpx").join("cpxconfig.toml"),
+        );
     }
     #[cfg(unix)]
     {
-        let system_config = PathBuf::from("/etc/cpx/cpxconfig.toml");
-        if system_config.exists() {
-            paths.push(system_config);
+        let xdg_dirs = std::env::var("XDG_CONFIG_DIRS")
+            .ok()
+            .filter(|dirs| !dirs.is_empty())
+            .unwrap_or_else(|| "/etc/xdg".to_string());
+        for dir in xdg_dirs.split(':').filter(|d| !d.is_empty()) {
+            push(
+                ConfigSource::XdgDirs,
+                Path::new(dir).join("cpx").join("cpxconfig.toml"),
+            );
         }
+        push(
+            ConfigSource::System,
+            PathBuf::from("/etc/cpx/cpxconfig.toml"),
+        );
     }
-    paths
+    candidates
+}
+
+pub fn find_config_files() -> Vec<PathBuf> {
+    config_candidates(None)
+        .into_iter()
+        .map(|c| c.path)
+        .filter(|p| p.exists())
+        .collect()
 }
 
 pub fn load_config_file(path: &Path) -> ConfigResult<Config> {
@@ -31,33 +86,90 @@ pub fn load_config_file(path: &Path) -> ConfigResult<Config> {
     Ok(config)
 }
 
-/// Load and merge all config files (reverse priority: system < user < project)
-pub fn load_config() -> Config {
-    let project = PathBuf::from("./cpxconfig.toml");
-    if project.exists()
-        && let Ok(config) = load_config_file(&project)
-    {
-        return config;
-    }
+/// Lists the dotted keys (e.g. `copy.parallel`) a config file sets explicitly.
+pub fn config_file_keys(path: &Path) -> ConfigResult<Vec<String>> {
+    let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+    let table: toml::Table = toml::from_str(&contents).map_err(ConfigError::Parse)?;
+    let mut keys = Vec::new();
+    collect_keys("", &table, &mut keys);
+    Ok(keys)
+}
 
-    if let Some(config_dir) = dirs::config_dir() {
-        let user = config_dir.join("cpx").join("cpxconfig.toml");
-        if user.exists()
-            && let Ok(config) = load_config_file(&user)
-        {
-            return config;
+fn collect_keys(prefix: &str, table: &toml::Table, keys: &mut Vec<String>) {
+    for (name, value) in table {
+        let key = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        match value {
+            toml::Value::Table(inner) => collect_keys(&key, inner, keys),
+            _ => keys.push(key),
         }
     }
+}
 
-    #[cfg(unix)]
-    {
-        let system = PathBuf::from("/etc/cpx/cpxconfig.toml");
-        if system.exists()
-            && let Ok(config) = load_config_file(&system)
-        {
-            return config;
+/// Load the highest-priority config file that exists and parses
+pub fn load_config() -> Config {
+    config_candidates(None)
+        .iter()
+        .filter(|c| c.path.exists())
+        .find_map(|c| load_config_file(&c.path).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_config_candidates_cli_first_project_before_user() {
+        let cli = PathBuf::from("/tmp/custom.toml");
+        let candidates = config_candidates(Some(&cli));
+
+        assert_eq!(candidates[0].source, ConfigSource::Cli);
+        assert_eq!(candidates[0].path, cli);
+
+        let project = candidates
+            .iter()
+            .position(|c| c.source == ConfigSource::Project)
+            .unwrap();
+        let user = candidates
+            .iter()
+            .position(|c| c.source == ConfigSource::User);
+        if let Some(user) = user {
+            assert!(project < user);
         }
     }
 
-    Config::default()
+    #[test]
+    fn test_config_file_keys_lists_nested_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("cpxconfig.toml");
+        fs::write(
+            &path,
+            "[copy]\nparallel = 8\n\n[progress]\nstyle = \"detailed\"\n\n[progress.bar]\nfilled = \"=\"\n",
+        )
+        .unwrap();
+
+        let mut keys = config_file_keys(&path).unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec!["copy.parallel", "progress.bar.filled", "progress.style"]
+        );
+    }
+
+    #[test]
+    fn test_config_file_keys_reports_parse_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("broken.toml");
+        fs::write(&path, "[copy\nparallel = ").unwrap();
+
+        assert!(matches!(
+            config_file_keys(&path),
+            Err(ConfigError::Parse(_))
+        ));
+    }
 }
@@ -1,3 +1,4 @@
+use crate::utility::progress_bar::{DEFAULT_MIN_BYTES, DEFAULT_MIN_FILES};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,6 +19,7 @@ pub struct CopyConfig {
     pub resume: bool,
     pub attributes_only: bool,
     pub remove_destination: bool,
+    pub engine: String, // "auto", "offload", "buffered", "mmap"
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,7 +38,8 @@ pub struct SymlinkConfig {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct BackupConfig {
-    pub mode: String, // "none", "simple", "numbered", "existing"
+    pub mode: String,   // "none", "simple", "numbered", "existing"
+    pub suffix: String, // appended by simple backups
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,6 +54,7 @@ pub struct ProgressConfig {
     pub style: String, // "default", "detailed"
     pub bar: ProgressBarConfig,
     pub color: ProgressColorConfig,
+    pub behavior: ProgressBehaviorConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -68,6 +72,13 @@ pub struct ProgressColorConfig {
     pub message: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProgressBehaviorConfig {
+    pub min_bytes: u64,
+    pub min_files: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 #[derive(Default)]
@@ -92,6 +103,7 @@ impl Default for CopyConfig {
             resume: false,
             attributes_only: false,
             remove_destination: false,
+            engine: "auto".to_string(),
         }
     }
 }
@@ -117,6 +129,7 @@ impl Default for BackupConfig {
     fn default() -> Self {
         Self {
             mode: "none".to_string(),
+            suffix: "~".to_string(),
         }
     }
 }
@@ -135,6 +148,7 @@ impl Default for ProgressConfig {
             style: "default".to_string(),
             bar: ProgressBarConfig::default(),
             color: ProgressColorConfig::default(),
+            behavior: ProgressBehaviorConfig::default(),
         }
     }
 }
@@ -158,6 +172,15 @@ impl Default for ProgressColorConfig {
     }
 }
 
+impl Default for ProgressBehaviorConfig {
+    fn default() -> Self {
+        Self {
+            min_bytes: DEFAULT_MIN_BYTES,
+            min_files: DEFAULT_MIN_FILES,
+        }
+    }
+}
+
 impl Config {
     pub fn to_toml_string(&self) -> Result<String, toml::ser::Error> {
         toml::to_string_pretty(self)
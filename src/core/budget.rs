@@ -0,0 +1,105 @@
+use std::sync::Mutex;
+
+/// How much a run may copy, for `--max-bytes` and `--max-files`.
+///
+/// A migration too large for one maintenance window can be done in slices:
+/// each run hands out files until the next one would go over the budget,
+/// lets the files already started finish, and reports what it left. With
+/// `--resume` the next run skips what is done and carries on from there.
+#[derive(Debug)]
+pub struct Budget {
+    max_bytes: Option<u64>,
+    max_files: Option<usize>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    bytes: u64,
+    files: usize,
+    exhausted: bool,
+    files_left: usize,
+    bytes_left: u64,
+}
+
+impl Budget {
+    /// A budget, if either limit is set.
+    pub fn new(max_bytes: Option<u64>, max_files: Option<usize>) -> Option<Self> {
+        (max_bytes.is_some() || max_files.is_some()).then(|| Self {
+            max_bytes,
+            max_files,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Whether a file of `size` bytes may be started. A file turned away is
+    /// counted as left for the next run.
+    ///
+    /// Once a file is turned away every later one is too, even one small
+    /// enough to fit, so a run stops where it stops instead of picking
+    /// through the rest of the tree. The first file is always let through,
+    /// however large, so that every run makes progress.
+    pub fn admit(&self, size: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let first = state.files == 0;
+        let over = self
+            .max_bytes
+            .is_some_and(|max| state.bytes.saturating_add(size) > max)
+            || self.max_files.is_some_and(|max| state.files >= max);
+        if state.exhausted || (over && !first) {
+            state.exhausted = true;
+            state.files_left += 1;
+            state.bytes_left += size;
+            return false;
+        }
+        state.bytes += size;
+        state.files += 1;
+        true
+    }
+
+    /// Files and bytes turned away, if the budget ran out.
+    pub fn left(&self) -> Option<(usize, u64)> {
+        let state = self.state.lock().unwrap();
+        state
+            .exhausted
+            .then_some((state.files_left, state.bytes_left))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_limits_means_no_budget() {
+        assert!(Budget::new(None, None).is_none());
+    }
+
+    #[test]
+    fn test_bytes_run_out_and_stay_out() {
+        let budget = Budget::new(Some(100), None).unwrap();
+        assert!(budget.admit(60));
+        assert!(budget.admit(40));
+        assert_eq!(budget.left(), None);
+        assert!(!budget.admit(10));
+        // Would fit no better, but nothing is picked out after the stop.
+        assert!(!budget.admit(0));
+        assert_eq!(budget.left(), Some((2, 10)));
+    }
+
+    #[test]
+    fn test_first_file_always_goes() {
+        let budget = Budget::new(Some(100), None).unwrap();
+        assert!(budget.admit(1000));
+        assert!(!budget.admit(1));
+    }
+
+    #[test]
+    fn test_file_count_limit() {
+        let budget = Budget::new(None, Some(2)).unwrap();
+        assert!(budget.admit(5));
+        assert!(budget.admit(5));
+        assert!(!budget.admit(5));
+        assert_eq!(budget.left(), Some((1, 5)));
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Most bytes granted at once.
+pub const QUANTUM: u64 = 256 * 1024;
+
+/// Longest sleep between looks at the abort flag.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Where the limiter gets the time from; tests drive it by hand.
+pub trait Clock: Send + Sync {
+    /// Time since some fixed start.
+    fn now(&self) -> Duration;
+    fn sleep(&self, duration: Duration);
+}
+
+struct SystemClock(Instant);
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Bandwidth limit for `--bwlimit`, shared by every worker of a copy.
+///
+/// Reads are paid for in quanta of at most [`QUANTUM`] bytes. Each quantum
+/// books the next free slot on a single timeline, long enough to move it at
+/// the current rate, and the worker sleeps until its slot is over. A worker
+/// only books its next quantum once the last one is paid for, so one
+/// streaming a huge file queues behind everyone who asked in the meantime
+/// instead of starving the workers on small files. Time nobody used is not
+/// saved up, so an idle moment never turns into a burst.
+///
+/// On Unix, SIGUSR2 halves the rate mid-copy and the next SIGUSR2 restores
+/// it.
+pub struct BandwidthLimit {
+    /// Bytes per second as given on the command line.
+    configured: u64,
+    /// Bytes per second in effect now.
+    rate: AtomicU64,
+    /// End of the last slot booked.
+    next_free: Mutex<Duration>,
+    clock: Box<dyn Clock>,
+}
+
+impl fmt::Debug for BandwidthLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BandwidthLimit")
+            .field("configured", &self.configured)
+            .field("rate", &self.rate())
+            .finish()
+    }
+}
+
+impl BandwidthLimit {
+    /// A limit of `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        Self::with_clock(rate, Box::new(SystemClock(Instant::now())))
+    }
+
+    pub fn with_clock(rate: u64, clock: Box<dyn Clock>) -> Self {
+        Self {
+            configured: rate,
+            rate: AtomicU64::new(rate),
+            next_free: Mutex::new(clock.now()),
+            clock,
+        }
+    }
+
+    /// Bytes per second in effect now.
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Halve the limit, or restore it if it is halved already. Returns the
+    /// rate now in effect.
+    pub fn toggle_halved(&self) -> u64 {
+        let rate = if self.rate() == self.configured {
+            (self.configured / 2).max(1)
+        } else {
+            self.configured
+        };
+        self.rate.store(rate, Ordering::Relaxed);
+        rate
+    }
+
+    /// Wait until `bytes` may be moved, a quantum at a time, or until
+    /// `abort` is set.
+    pub fn throttle(&self, bytes: u64, abort: &AtomicBool) {
+        let mut left = bytes;
+        while left > 0 && !abort.load(Ordering::Relaxed) {
+            let quantum = left.min(QUANTUM);
+            let end = self.book(quantum);
+            self.wait_until(end, abort);
+            left -= quantum;
+        }
+    }
+
+    /// Book the next free slot for `bytes` and return when it ends.
+    fn book(&self, bytes: u64) -> Duration {
+        let rate = self.rate().max(1);
+        let mut next_free = self.next_free.lock().unwrap_or_else(|e| e.into_inner());
+        let start = (*next_free).max(self.clock.now());
+        *next_free = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        *next_free
+    }
+
+    fn wait_until(&self, end: Duration, abort: &AtomicBool) {
+        loop {
+            let now = self.clock.now();
+            if now >= end || abort.load(Ordering::Relaxed) {
+                return;
+            }
+            self.clock.sleep((end - now).min(TICK));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const MB: u64 = 1024 * 1024;
+
+    /// A clock that only moves when slept on or set.
+    #[derive(Default)]
+    struct ManualClock(Mutex<Duration>);
+
+    impl ManualClock {
+        fn set(&self, now: Duration) {
+            *self.0.lock().unwrap() = now;
+        }
+    }
+
+    impl Clock for Arc<ManualClock> {
+        fn now(&self) -> Duration {
+            *self.0.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    fn limit_with_clock(rate: u64) -> (BandwidthLimit, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::default());
+        (
+            BandwidthLimit::with_clock(rate, Box::new(clock.clone())),
+            clock,
+        )
+    }
+
+    #[test]
+    fn test_rate_is_kept_within_a_percent() {
+        let (limit, clock) = limit_with_clock(50 * MB);
+        let abort = AtomicBool::new(false);
+
+        // Chunks the size of the buffered copy's, for 500 MiB.
+        for _ in 0..250 {
+            limit.throttle(2 * MB, &abort);
+        }
+
+        let elapsed = clock.now().as_secs_f64();
+        assert!((elapsed - 10.0).abs() < 0.1, "took {}s", elapsed);
+    }
+
+    #[test]
+    fn test_small_files_are_not_starved_by_a_large_one() {
+        let (limit, clock) = limit_with_clock(MB);
+        // A worker streaming 64 MiB and one copying 8 files of 64 KiB ask
+        // at the same moment; each books its next quantum when the last
+        // one is paid for, as a waiting worker would.
+        let mut left = [64 * MB, 8 * 64 * 1024];
+        let chunk = [2 * MB, 64 * 1024];
+        let mut in_chunk = [0u64; 2];
+        let mut ready_at = [Duration::ZERO; 2];
+        let mut finished = [Duration::ZERO; 2];
+
+        while left.iter().any(|&l| l > 0) {
+            let worker = (0..2)
+                .filter(|&w| left[w] > 0)
+                .min_by_key(|&w| ready_at[w])
+                .unwrap();
+            clock.set(ready_at[worker]);
+            if in_chunk[worker] == 0 {
+                in_chunk[worker] = chunk[worker].min(left[worker]);
+            }
+            let quantum = in_chunk[worker].min(QUANTUM);
+            ready_at[worker] = limit.book(quantum);
+            in_chunk[worker] -= quantum;
+            left[worker] -= quantum;
+            finished[worker] = ready_at[worker];
+        }
+
+        // Each small file waits for at most one quantum of the large one:
+        // 8 rounds of 256 + 64 KiB at 1 MiB/s.
+        assert!(
+            finished[1] <= Duration::from_secs_f64(2.6),
+            "{:?}",
+            finished[1]
+        );
+        // And the two together still get exactly the configured rate.
+        let total = (64 * MB + 8 * 64 * 1024) as f64 / MB as f64;
+        assert!((finished[0].as_secs_f64() - total).abs() < total * 0.01);
+    }
+
+    #[test]
+    fn test_halving_applies_to_the_next_quantum_and_restores() {
+        let (limit, clock) = limit_with_clock(2 * MB);
+        let abort = AtomicBool::new(false);
+
+        assert_eq!(limit.toggle_halved(), MB);
+        limit.throttle(MB, &abort);
+        assert_eq!(clock.now(), Duration::from_secs(1));
+
+        assert_eq!(limit.toggle_halved(), 2 * MB);
+        limit.throttle(MB, &abort);
+        assert_eq!(clock.now(), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_idle_time_is_not_saved_up() {
+        let (limit, clock) = limit_with_clock(MB);
+        let abort = AtomicBool::new(false);
+        clock.set(Duration::from_secs(60));
+
+        limit.throttle(MB, &abort);
+
+        assert_eq!(clock.now(), Duration::from_secs(61));
+    }
+}
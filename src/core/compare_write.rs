@@ -0,0 +1,257 @@
+use crate::cli::args::CopyOptions;
+use crate::core::{full, stall};
+use crate::error::{CopyError, CopyResult};
+use crate::utility::confine;
+use crate::utility::helper::open_source;
+use indicatif::ProgressBar;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Bytes written to the destination and bytes that were already there.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompareStats {
+    pub written: u64,
+    pub identical: u64,
+}
+
+/// Update `destination` in place from `source`, writing only what differs,
+/// for `--compare-write`. Flash media see fewer writes and copy-on-write
+/// filesystems keep unchanged extents shared with their snapshots.
+///
+/// Since the destination is updated in place, this takes precedence over
+/// `--sparse`: a chunk that differs is written as data even when it is all
+/// zeroes, and no holes are punched.
+///
+/// Returns `None`, without touching anything, when the destination doesn't
+/// exist or its size differs from `file_size`; the caller copies normally.
+pub fn compare_write(
+    source: &Path,
+    destination: &Path,
+    file_size: u64,
+    overall_pb: Option<&ProgressBar>,
+    options: &CopyOptions,
+) -> CopyResult<Option<CompareStats>> {
+    // Confined, a symlink at the destination is not a file to update.
+    let confine = (options.confine.as_deref()).filter(|root| destination.starts_with(root));
+    let metadata = match confine {
+        Some(_) => std::fs::symlink_metadata(destination),
+        None => std::fs::metadata(destination),
+    };
+    match metadata {
+        Ok(meta) if meta.is_file() && meta.len() == file_size => {}
+        _ => return Ok(None),
+    }
+    let failed = |reason: String| CopyError::CopyFailed {
+        source: source.to_path_buf(),
+        destination: destination.to_path_buf(),
+        reason,
+    };
+    let mut src_file = open_source(source, options.noatime)
+        .map_err(|e| failed(format!("Failed to open source file: {}", e)))?;
+    let mut dest_file = match confine {
+        Some(root) => confine::update_beneath(root, destination),
+        None => OpenOptions::new().read(true).write(true).open(destination),
+    }
+    .map_err(|e| failed(format!("Failed to open destination: {}", e)))?;
+    // What was opened may not be what was looked at.
+    if !dest_file
+        .metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.len() == file_size)
+    {
+        return Ok(None);
+    }
+
+    let stats = compare_chunks(&mut src_file, &mut dest_file, CHUNK_SIZE, |bytes| {
+        full::hold(&options.abort);
+        if stall::cancelled() {
+            return Err(stall::stalled_error());
+        }
+        if options.abort.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Operation aborted by user",
+            ));
+        }
+        if let Some(limit) = &options.bwlimit {
+            limit.throttle(bytes, &options.abort);
+        }
+        stall::progress();
+        if let Some(pb) = overall_pb {
+            pb.inc(bytes);
+        }
+        Ok(())
+    })?;
+
+    // The source may have shrunk since it was planned.
+    let copied = stats.written + stats.identical;
+    if copied < file_size {
+        dest_file.set_len(copied)?;
+    }
+    Ok(Some(stats))
+}
+
+/// Make `dest` match `src`, reading both `chunk` bytes at a time and
+/// writing only the chunks that differ.
+///
+/// `on_chunk` is called with the size of every chunk handled and can stop
+/// the copy by returning an error. Bytes past the end of `src` are left for
+/// the caller to truncate.
+fn compare_chunks<S, D>(
+    src: &mut S,
+    dest: &mut D,
+    chunk: usize,
+    mut on_chunk: impl FnMut(u64) -> io::Result<()>,
+) -> io::Result<CompareStats>
+where
+    S: Read,
+    D: Read + Write + Seek,
+{
+    let mut src_buf = vec![0u8; chunk];
+    let mut dest_buf = vec![0u8; chunk];
+    let mut stats = CompareStats::default();
+
+    // Read until EOF rather than up to the planned size, as the regular
+    // copy does, so a source that grew comes out whole.
+    loop {
+        let read = read_full(src, &mut src_buf)?;
+        if read == 0 {
+            break;
+        }
+        let existing = read_full(dest, &mut dest_buf[..read])?;
+        if existing == read && src_buf[..read] == dest_buf[..read] {
+            stats.identical += read as u64;
+        } else {
+            dest.seek(SeekFrom::Current(-(existing as i64)))?;
+            dest.write_all(&src_buf[..read])?;
+            stats.written += read as u64;
+        }
+        on_chunk(read as u64)?;
+    }
+    Ok(stats)
+}
+
+/// Fill `buf` as far as the reader allows, stopping early only at EOF.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A destination that counts the bytes written to it.
+    struct CountingFile {
+        inner: Cursor<Vec<u8>>,
+        written: usize,
+    }
+
+    impl CountingFile {
+        fn new(data: &[u8]) -> Self {
+            Self {
+                inner: Cursor::new(data.to_vec()),
+                written: 0,
+            }
+        }
+    }
+
+    impl Read for CountingFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for CountingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = self.inner.write(buf)?;
+            self.written += n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for CountingFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_identical_files_cause_no_writes() {
+        let data = pattern(10_000);
+        let mut dest = CountingFile::new(&data);
+        let stats = compare_chunks(&mut data.as_slice(), &mut dest, 1024, |_| Ok(())).unwrap();
+
+        assert_eq!(dest.written, 0);
+        assert_eq!(
+            stats,
+            CompareStats {
+                written: 0,
+                identical: 10_000
+            }
+        );
+    }
+
+    #[test]
+    fn test_only_differing_chunks_are_written() {
+        let source = pattern(4096);
+        let mut existing = source.clone();
+        existing[1500] ^= 0xff;
+        existing[4095] ^= 0xff;
+        let mut dest = CountingFile::new(&existing);
+        let stats = compare_chunks(&mut source.as_slice(), &mut dest, 1024, |_| Ok(())).unwrap();
+
+        assert_eq!(dest.written, 2048);
+        assert_eq!(stats.written, 2048);
+        assert_eq!(stats.identical, 2048);
+        assert_eq!(dest.inner.into_inner(), source);
+    }
+
+    #[test]
+    fn test_grown_source_is_appended() {
+        let source = pattern(3000);
+        let mut dest = CountingFile::new(&source[..2000]);
+        let stats = compare_chunks(&mut source.as_slice(), &mut dest, 1024, |_| Ok(())).unwrap();
+
+        // The second chunk is only half there and the third not at all.
+        assert_eq!(stats.identical, 1024);
+        assert_eq!(stats.written, 3000 - 1024);
+        assert_eq!(dest.inner.into_inner(), source);
+    }
+
+    #[test]
+    fn test_callback_error_stops_the_copy() {
+        let source = pattern(4096);
+        let mut dest = CountingFile::new(&[0u8; 4096]);
+        let mut chunks = 0;
+        let err = compare_chunks(&mut source.as_slice(), &mut dest, 1024, |_| {
+            chunks += 1;
+            Err(io::Error::new(io::ErrorKind::Interrupted, "stop"))
+        })
+        .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(chunks, 1);
+        assert_eq!(dest.written, 1024);
+    }
+}
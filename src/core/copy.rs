@@ -1,134 +1,998 @@
-use crate::cli::args::{BackupMode, CopyOptions, FollowSymlink};
+use crate::cli::args::{BackupMode, CopyOptions, Engine, LinkFallback, Prompt, ReflinkMode};
+use crate::core::budget::Budget;
 #[cfg(target_os = "linux")]
+use crate::core::compare_write::compare_write;
+use crate::core::engine::{
+    self, Capabilities, FileContext, FileTiming, Mechanism, Method, MethodStats, SlowestFiles,
+};
 use crate::core::fast_copy::fast_copy;
+use crate::core::full::{self, DiskFull};
+#[cfg(unix)]
+use crate::core::mmap;
+#[cfg(windows)]
+use crate::core::odx;
+use crate::core::reflink::ReflinkCache;
+use crate::core::replicate::Replicas;
+use crate::core::sharing::SharedExtents;
+use crate::core::sparse;
+use crate::core::stall::{self, StallWatchdog};
+use crate::core::stream;
 use crate::error::{CopyError, CopyResult};
-use crate::utility::backup::{create_backup, generate_backup_path};
+use crate::utility::background;
+use crate::utility::backup::back_up;
+use crate::utility::checksum;
 use crate::utility::helper::{
-    create_directories, create_hardlink, create_symlink, prompt_overwrite,
+    LazyDirectories, clear_destination, create_hardlink, create_special, create_symlink,
+    link_in_place, link_symlink, names_contents, open_destination, open_source, prompt_overwrite,
+    read_denied, retry_eintr, same_file, temp_path_for,
 };
+use crate::utility::itemize::{self, ItemLog};
+use crate::utility::limits;
+use crate::utility::output::{self, Summary};
 use crate::utility::preprocess::{
-    CopyPlan, preprocess_directory, preprocess_file, preprocess_multiple,
+    AttrTask, CopyPlan, DirectoryTask, FileTask, UnreadableEntry, flatten, preprocess_directory,
+    preprocess_file, preprocess_multiple, skip_existing, source_error,
+};
+use crate::utility::preserve::{
+    self, AttrOverrides, HardLinkTracker, KeptAttrs, PreserveAttr, PreserveOutcome,
 };
-use crate::utility::preserve::{self, HardLinkTracker, PreserveAttr};
-use crate::utility::progress_bar::ProgressBarStyle;
-use indicatif::ProgressBar;
+use crate::utility::progress_bar::{ProgressBarStyle, ProgressMode, detailed_message};
+use crate::utility::staging::StagedFile;
+use indicatif::{HumanBytes, HumanCount, ProgressBar, ProgressDrawTarget};
 use rayon::prelude::*;
-use std::io::{self, Read, Write};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use std::{path::Path, path::PathBuf};
 
+/// Copy `source` to `destination` in a session of its own.
 pub fn copy(source: &Path, destination: &Path, options: &CopyOptions) -> CopyResult<()> {
-    let source_metadata = match options.follow_symlink {
-        FollowSymlink::Dereference | FollowSymlink::CommandLineSymlink => std::fs::metadata(source)
-            .map_err(|_e| CopyError::InvalidSource(source.to_path_buf()))?,
-        FollowSymlink::NoDereference => std::fs::symlink_metadata(source)
-            .map_err(|_e| CopyError::InvalidSource(source.to_path_buf()))?,
-    };
-    let source_root = source.parent().unwrap_or(source);
-    let destination_metadata = std::fs::metadata(destination).ok();
+    let mut session = CopySession::new();
+    let result = session.copy(source, destination, options);
+    session.finish(options);
+    result
+}
 
-    let plan = if source_metadata.is_dir() {
-        if !options.recursive {
-            return Err(CopyError::CopyFailed {
-                source: source.to_path_buf(),
-                destination: destination.to_path_buf(),
-                reason: "'src' is a directory (not copied, use -r to copy recursively)".to_string(),
-            });
+/// Copy several sources into `destination` in a session of its own.
+pub fn multiple_copy(
+    sources: Vec<PathBuf>,
+    destination: PathBuf,
+    options: &CopyOptions,
+) -> CopyResult<()> {
+    let mut session = CopySession::new();
+    let result = session.copy_many(sources, destination, options);
+    session.finish(options);
+    result
+}
+
+/// What copies made one after another share: the worker pool, the hard link
+/// tracker, one progress bar and the totals for the summary.
+///
+/// [`copy`] and [`multiple_copy`] each run a session of one call. A program
+/// copying many batches can keep a session instead, so files hard-linked
+/// across batches stay linked, the bar counts on from batch to batch and
+/// [`CopySession::finish`] reports the whole run.
+pub struct CopySession {
+    pool: Option<rayon::ThreadPool>,
+    hardlinks: Arc<Mutex<HardLinkTracker>>,
+    progress: Option<Arc<ProgressBar>>,
+    counters: FileCounters,
+    /// Directories that didn't exist until a call of this session made them.
+    created: Vec<PathBuf>,
+    /// Files planned by every call so far.
+    total_files: usize,
+    summary: Summary,
+    /// Plans actually carried out, as opposed to streams and dry runs.
+    runs: usize,
+    /// Whether a call got past planning, so that a failure may have left
+    /// part of the copy done.
+    started: bool,
+    /// Whether every call so far succeeded.
+    clean: bool,
+    /// `--max-bytes` and `--max-files`, counted across every call.
+    budget: Option<Budget>,
+}
+
+impl Default for CopySession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CopySession {
+    pub fn new() -> Self {
+        Self {
+            pool: None,
+            hardlinks: Arc::new(Mutex::new(HardLinkTracker::new())),
+            progress: None,
+            counters: FileCounters::default(),
+            created: Vec::new(),
+            total_files: 0,
+            summary: Summary::default(),
+            runs: 0,
+            started: false,
+            clean: true,
+            budget: None,
         }
+    }
 
-        if let Some(dest_meta) = destination_metadata
-            && dest_meta.is_file()
+    /// Totals of every call so far.
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// Whether any call got as far as copying, or with `--dry-run` as far
+    /// as listing what it would copy. A call that failed before then failed
+    /// over its arguments.
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    /// The directories this session created that no other one it created
+    /// contains. Everything in them is from this session, so removing them
+    /// undoes its copies into new directories without touching anything
+    /// that was there before.
+    pub fn created_roots(&self) -> Vec<PathBuf> {
+        self.created
+            .iter()
+            .filter(|dir| {
+                !self
+                    .created
+                    .iter()
+                    .any(|other| other != *dir && dir.starts_with(other))
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn copy(
+        &mut self,
+        source: &Path,
+        destination: &Path,
+        options: &CopyOptions,
+    ) -> CopyResult<()> {
+        let result = self.copy_one(source, destination, options);
+        self.clean &= result.is_ok();
+        result
+    }
+
+    pub fn copy_many(
+        &mut self,
+        sources: Vec<PathBuf>,
+        destination: PathBuf,
+        options: &CopyOptions,
+    ) -> CopyResult<()> {
+        let result = self.copy_several(sources, destination, options);
+        self.clean &= result.is_ok();
+        result
+    }
+
+    /// End the session: finish the progress bar, print the `--porcelain`
+    /// summary and hand back the totals.
+    pub fn finish(mut self, options: &CopyOptions) -> Summary {
+        let left = self.budget.as_ref().and_then(Budget::left);
+        if let Some((files, bytes)) = left {
+            self.summary.files_remaining = files;
+            self.summary.bytes_remaining = bytes;
+        }
+        if let Some(pb) = self.progress.filter(|pb| !pb.is_finished())
+            && self.clean
         {
-            return Err(CopyError::InvalidDestination(destination.to_path_buf()));
+            if left.is_some() {
+                pb.abandon_with_message("Stopped at the budget");
+            } else if matches!(options.progress_bar.style, ProgressBarStyle::Detailed) {
+                pb.finish_with_message(format!("Copied {} files successfully", self.total_files));
+            } else {
+                pb.finish_with_message("Done".to_string());
+            }
+        }
+        if options.porcelain && self.runs > 0 {
+            output::print_porcelain(&self.summary);
         }
+        self.summary
+    }
 
-        preprocess_directory(source, source_root, destination, options).map_err(|e| {
-            CopyError::CopyFailed {
+    /// The run's budget, set up by the first call that copies files.
+    fn ensure_budget(&mut self, options: &CopyOptions) {
+        if self.budget.is_none() {
+            self.budget = Budget::new(options.max_bytes, options.max_files);
+        }
+    }
+
+    /// The slowest files copied so far, slowest first.
+    pub fn slowest_files(&self) -> Vec<FileTiming> {
+        self.counters.slowest.slowest()
+    }
+
+    /// The worker pool, rebuilt when `-j` changed since the last call.
+    fn ensure_pool(&mut self, threads: usize) -> CopyResult<()> {
+        if self
+            .pool
+            .as_ref()
+            .is_some_and(|pool| pool.current_num_threads() == threads)
+        {
+            return Ok(());
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| CopyError::CopyFailed {
+                source: PathBuf::new(),
+                destination: PathBuf::new(),
+                reason: format!("Failed to create thread pool: {}", e),
+            })?;
+        self.pool = Some(pool);
+        Ok(())
+    }
+
+    /// The session's bar, grown by `size` bytes, or a new one if there is
+    /// none still running.
+    fn progress_for(&mut self, size: u64, options: &CopyOptions) -> Arc<ProgressBar> {
+        if let Some(pb) = &self.progress
+            && !pb.is_finished()
+        {
+            pb.inc_length(size);
+            return pb.clone();
+        }
+        let pb = ProgressBar::with_draw_target(
+            Some(size),
+            ProgressDrawTarget::stderr_with_hz(options.progress_bar.refresh_rate),
+        );
+        options.progress_bar.apply(&pb, self.total_files);
+        let pb = Arc::new(pb);
+        self.progress = Some(pb.clone());
+        pb
+    }
+
+    fn copy_one(
+        &mut self,
+        source: &Path,
+        destination: &Path,
+        options: &CopyOptions,
+    ) -> CopyResult<()> {
+        if stream::is_stream(source) {
+            reject_stream_replicas(source, options)?;
+            self.started = true;
+            return stream::copy_stream(source, destination, options);
+        }
+        let source_metadata = if options.follow_symlink.follows(true) {
+            std::fs::metadata(source)
+        } else {
+            std::fs::symlink_metadata(source)
+        }
+        .map_err(|e| source_error(source, e))?;
+        // With -T, or written `src/.`, the source's own name is not part of
+        // the copy.
+        let source_root = if options.no_target_directory || names_contents(source) {
+            source
+        } else {
+            source.parent().unwrap_or(source)
+        };
+        let destination_metadata = std::fs::metadata(destination).ok();
+
+        let flatten = options.flatten && source_metadata.is_dir();
+        let mut plan = if source_metadata.is_dir() {
+            if !options.recursive {
+                return Err(CopyError::CopyFailed {
+                    source: source.to_path_buf(),
+                    destination: destination.to_path_buf(),
+                    reason: "'src' is a directory (not copied, use -r to copy recursively)"
+                        .to_string(),
+                });
+            }
+
+            if let Some(dest_meta) = destination_metadata
+                && dest_meta.is_file()
+            {
+                return Err(CopyError::InvalidDestination(destination.to_path_buf()));
+            }
+
+            preprocess_directory(source, source_root, destination, options).map_err(|e| {
+                CopyError::CopyFailed {
+                    source: source.to_path_buf(),
+                    destination: destination.to_path_buf(),
+                    reason: e.to_string(),
+                }
+            })?
+        } else {
+            if options.no_target_directory
+                && destination_metadata.as_ref().is_some_and(|m| m.is_dir())
+            {
+                return Err(CopyError::CopyFailed {
+                    source: source.to_path_buf(),
+                    destination: destination.to_path_buf(),
+                    reason: "cannot overwrite directory with non-directory".to_string(),
+                });
+            }
+            preprocess_file(
+                source,
+                source_root,
+                destination,
+                options,
+                source_metadata,
+                destination_metadata,
+            )
+            .map_err(|e| CopyError::CopyFailed {
                 source: source.to_path_buf(),
                 destination: destination.to_path_buf(),
                 reason: e.to_string(),
-            }
-        })?
-    } else {
-        preprocess_file(
-            source,
-            source_root,
-            destination,
+            })?
+        };
+
+        if plan.skipped_files > 0 {
+            output::status(format!(
+                "Skipping {} files that already exist",
+                plan.skipped_files
+            ));
+        }
+        report_link_fallbacks(&plan, options);
+        report_other_filesystems(&plan);
+        if flatten {
+            flatten_plan(&mut plan, destination, options)?;
+        }
+
+        execute_copy(
+            self,
+            plan,
             options,
-            source_metadata,
-            destination_metadata,
+            &Replicas::new(destination, &options.also_to, options.confine.is_some()),
         )
-        .map_err(|e| CopyError::CopyFailed {
-            source: source.to_path_buf(),
-            destination: destination.to_path_buf(),
-            reason: e.to_string(),
-        })?
+    }
+
+    fn copy_several(
+        &mut self,
+        sources: Vec<PathBuf>,
+        destination: PathBuf,
+        options: &CopyOptions,
+    ) -> CopyResult<()> {
+        let (streams, sources): (Vec<_>, Vec<_>) =
+            sources.into_par_iter().partition(|s| stream::is_stream(s));
+        for source in &streams {
+            reject_stream_replicas(source, options)?;
+            self.started = true;
+            stream::copy_stream(source, &destination, options)?;
+        }
+        if sources.is_empty() {
+            return Ok(());
+        }
+
+        // Errors name the source they are about.
+        let mut plan = preprocess_multiple(&sources, &destination, options)?;
+        if plan.skipped_files > 0 {
+            output::status(format!(
+                "Skipping {} files that already exist",
+                plan.skipped_files
+            ));
+        }
+        report_link_fallbacks(&plan, options);
+        report_other_filesystems(&plan);
+        if options.flatten {
+            flatten_plan(&mut plan, &destination, options)?;
+        }
+        execute_copy(
+            self,
+            plan,
+            options,
+            &Replicas::new(&destination, &options.also_to, options.confine.is_some()),
+        )
+    }
+}
+
+/// Remove directories a failed copy created, with everything in them, for
+/// `--rollback-on-error`. Returns how many could not be removed.
+pub fn roll_back(directories: &[PathBuf]) -> usize {
+    let mut failed = 0;
+    for directory in directories {
+        // Symlinks inside are removed, never followed.
+        if let Err(e) = std::fs::remove_dir_all(directory) {
+            output::error(format!("cannot remove '{}': {}", directory.display(), e));
+            failed += 1;
+        }
+    }
+    failed
+}
+
+/// Streams are read as they are written, so they can't be copied on to
+/// `--also-to` targets afterwards.
+fn reject_stream_replicas(source: &Path, options: &CopyOptions) -> CopyResult<()> {
+    if options.also_to.is_empty() {
+        return Ok(());
+    }
+    Err(CopyError::CopyFailed {
+        source: source.to_path_buf(),
+        destination: PathBuf::new(),
+        reason: "--also-to cannot be used with a stream source".to_string(),
+    })
+}
+
+/// What `-x` left out, once for the whole plan.
+fn report_other_filesystems(plan: &CopyPlan) {
+    if plan.other_filesystems > 0 {
+        output::status(format!(
+            "Not copying into {} directories on other filesystems (-x); they are left empty",
+            plan.other_filesystems
+        ));
+    }
+}
+
+/// One banner per link kind the destination couldn't store, instead of an
+/// error for every link.
+fn report_link_fallbacks(plan: &CopyPlan, options: &CopyOptions) {
+    for (count, kind, policy) in [
+        (
+            plan.symlink_fallbacks,
+            "symbolic links",
+            options.symlink_fallback,
+        ),
+        (plan.hardlink_fallbacks, "hard links", options.link_fallback),
+    ] {
+        let action = match policy {
+            LinkFallback::CopyTarget => "copying link targets instead",
+            LinkFallback::Skip => "skipping them",
+            LinkFallback::Error => continue,
+        };
+        if count > 0 {
+            output::warning(format!(
+                "destination does not support {}; {} ({} affected)",
+                kind, action, count
+            ));
+        }
+    }
+}
+
+/// Refuse to start when files are larger than the destination filesystem can
+/// hold, or with `--skip-oversize` leave them out, instead of failing at the
+/// limit after writing gigabytes.
+fn check_file_sizes(plan: &mut CopyPlan, options: &CopyOptions) -> CopyResult<()> {
+    let Some(limit) = options.max_file_size() else {
+        return Ok(());
+    };
+    // Links and attributes don't write any data.
+    if options.attributes_only || options.hard_link || options.symbolic_link.is_some() {
+        return Ok(());
+    }
+    let oversized: Vec<&FileTask> = plan.files.iter().filter(|f| f.size > limit).collect();
+    let Some(first) = oversized.first() else {
+        return Ok(());
     };
 
-    if plan.skipped_files > 0 {
-        eprintln!("Skipping {} files that already exist", plan.skipped_files);
+    let filesystem = options.link_support.map_or("unknown", |s| s.kind.name());
+    let mut names: Vec<String> = oversized
+        .iter()
+        .take(3)
+        .map(|f| format!("'{}' ({})", f.source.display(), HumanBytes(f.size)))
+        .collect();
+    if oversized.len() > 3 {
+        names.push(format!("and {} more", oversized.len() - 3));
+    }
+    let problem = format!(
+        "{} larger than {}, the largest file a {} filesystem can hold: {}",
+        if oversized.len() == 1 {
+            "1 file is".to_string()
+        } else {
+            format!("{} files are", oversized.len())
+        },
+        HumanBytes(limit),
+        filesystem,
+        names.join(", ")
+    );
+    if !options.skip_oversize {
+        return Err(CopyError::CopyFailed {
+            source: first.source.clone(),
+            destination: first.destination.clone(),
+            reason: format!("{} (use --skip-oversize to copy the rest)", problem),
+        });
     }
+    output::warning(format!(
+        "{}; skipping {}",
+        problem,
+        if oversized.len() == 1 { "it" } else { "them" }
+    ));
+    plan.skip_larger_than(limit);
+    Ok(())
+}
 
-    execute_copy(plan, options)
+/// Answers given so far to `--interactive=dirs` questions.
+struct DirPrompts {
+    totals: Vec<(PathBuf, usize, u64)>,
+    order: HashMap<PathBuf, usize>,
+    current: Option<(PathBuf, bool)>,
+    all: bool,
+    quit: bool,
 }
 
-pub fn multiple_copy(
-    sources: Vec<PathBuf>,
-    destination: PathBuf,
+impl DirPrompts {
+    fn new(totals: Vec<(PathBuf, usize, u64)>) -> Self {
+        let order = totals
+            .iter()
+            .enumerate()
+            .map(|(i, (group, _, _))| (group.clone(), i))
+            .collect();
+        Self {
+            totals,
+            order,
+            current: None,
+            all: false,
+            quit: false,
+        }
+    }
+
+    fn position(&self, group: &Path) -> Option<usize> {
+        self.order.get(group).copied()
+    }
+
+    /// Whether files in `group` should be copied, asking the first time the
+    /// group comes up.
+    fn allows(&mut self, group: &Path) -> io::Result<bool> {
+        if self.quit {
+            return Ok(false);
+        }
+        if self.all {
+            return Ok(true);
+        }
+        if let Some((current, allowed)) = &self.current
+            && current == group
+        {
+            return Ok(*allowed);
+        }
+
+        let (files, bytes) = self
+            .position(group)
+            .map(|i| (self.totals[i].1, self.totals[i].2))
+            .unwrap_or_default();
+        let answer = output::ask(format!(
+            "descend into '{}' ({} files, {})? [y/N/a/q]",
+            group.display(),
+            HumanCount(files as u64),
+            HumanBytes(bytes)
+        ))?;
+        let allowed = match parse_dir_answer(&answer) {
+            DirAnswer::Yes => true,
+            DirAnswer::No => false,
+            DirAnswer::All => {
+                self.all = true;
+                true
+            }
+            DirAnswer::Quit => {
+                self.quit = true;
+                false
+            }
+        };
+        self.current = Some((group.to_path_buf(), allowed));
+        Ok(allowed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DirAnswer {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+/// Anything unrecognised, including an empty answer, means no.
+fn parse_dir_answer(answer: &str) -> DirAnswer {
+    match answer {
+        "y" | "yes" => DirAnswer::Yes,
+        "a" | "all" => DirAnswer::All,
+        "q" | "quit" => DirAnswer::Quit,
+        _ => DirAnswer::No,
+    }
+}
+
+fn execute_copy(
+    session: &mut CopySession,
+    mut plan: CopyPlan,
     options: &CopyOptions,
+    replicas: &Replicas,
 ) -> CopyResult<()> {
-    let plan = preprocess_multiple(&sources, &destination, options).map_err(|e| {
-        CopyError::CopyFailed {
-            source: sources[0].clone(),
-            destination: destination.clone(),
-            reason: e.to_string(),
+    check_file_sizes(&mut plan, options)?;
+    let unreadable = std::mem::take(&mut plan.unreadable);
+    if options.sudo.is_some()
+        && let Some(path) = first_denied(&plan, &unreadable)
+    {
+        return Err(CopyError::PermissionDenied(path));
+    }
+    let empty = plan.prune_directories();
+    session.started = true;
+    if options.dry_run {
+        itemize::itemize_plan(&plan, options)
+            .iter()
+            .for_each(itemize::print_item);
+        return report_unreadable(&unreadable);
+    }
+    // --interactive=once: one question, then no more.
+    let overwrites = plan.overwrites();
+    if options.interactive == Prompt::Once
+        && overwrites > 0
+        && !output::confirm(format!(
+            "the destination has {} files that would be overwritten, continue?",
+            overwrites
+        ))?
+    {
+        output::status("Nothing copied");
+        return Ok(());
+    }
+    // Itemized from the destination as it is before anything is copied.
+    let item_log = options
+        .itemize
+        .then(|| ItemLog::new(itemize::itemize_plan(&plan, options)));
+    if let Some(log) = &item_log {
+        log.print_excluded();
+    }
+    session.runs += 1;
+
+    let mut summary = Summary {
+        files_skipped: plan.skipped_files,
+        files_untouched: plan.kept_existing,
+        files_new_ignored: plan.ignored_new,
+        entries_unreadable: unreadable.len(),
+        ..Summary::default()
+    };
+    // --timestamp stamps the directories this run creates, the ones that
+    // don't exist yet; a reproducible copy normalizes every planned one.
+    let overrides = &options.overrides;
+    let directories: Vec<(DirectoryTask, bool)> = plan
+        .directories
+        .iter()
+        .map(|d| {
+            let stamp = overrides.stamps()
+                && (options.attributes_only
+                    || overrides.reproducible
+                    || std::fs::symlink_metadata(&d.destination).is_err());
+            (d.clone(), stamp)
+        })
+        .collect();
+    let refresh = std::mem::take(&mut plan.refresh);
+    // Attributes alone make nothing.
+    let made = if options.attributes_only {
+        LazyDirectories::default()
+    } else {
+        LazyDirectories::new(&plan.directories, empty)
+    };
+    let mut result = run_plan(
+        session,
+        plan,
+        options,
+        &mut summary,
+        item_log.as_ref(),
+        replicas,
+        &made,
+    );
+    session.created.extend(made.into_created());
+    if summary.files_untouched > 0 {
+        output::status(format!(
+            "{} existing files left untouched",
+            summary.files_untouched
+        ));
+    }
+    if summary.files_new_ignored > 0 {
+        output::status(format!(
+            "{} new files ignored (--existing)",
+            summary.files_new_ignored
+        ));
+    }
+    if result.is_ok() && !refresh.is_empty() {
+        let (refreshed, refused) = refresh_attributes(&refresh, options);
+        summary.attributes_refreshed = refreshed;
+        summary.attributes_failed += refused;
+        if refreshed > 0 {
+            output::status(format!("{} files: attributes refreshed", refreshed));
         }
-    })?;
-    if plan.skipped_files > 0 {
-        eprintln!("Skipping {} files that already exist", plan.skipped_files);
     }
-    execute_copy(plan, options)
+    // Last, since copying into a directory updates its mtime, and a mode
+    // without write bits would shut the rest of the copy out. A run that
+    // failed leaves them writable for the next try.
+    match finish_directories(&directories, options, result.is_ok()) {
+        Ok(refused) => summary.attributes_failed += refused,
+        Err(e) if result.is_ok() => result = Err(e),
+        Err(_) => {}
+    }
+    // Not after an interruption, which has its own report.
+    if result.is_ok() {
+        result = report_unreadable(&unreadable);
+    }
+    if !replicas.is_empty() {
+        let replicated = replicas.report();
+        if result.is_ok() {
+            result = replicated;
+        }
+    }
+
+    summary.interrupted = result.as_ref().is_err_and(CopyError::is_abort);
+    session.summary.add(&summary);
+    result
+}
+
+/// Set the preserved attributes again on the skipped files in `refresh`
+/// whose destinations no longer have them. Returns how many were refreshed
+/// and how many attributes the destination refused.
+fn refresh_attributes(refresh: &[AttrTask], options: &CopyOptions) -> (usize, usize) {
+    let (mut refreshed, mut refused) = (0, 0);
+    for task in refresh {
+        if !preserve::attributes_differ(
+            &task.metadata,
+            &task.destination,
+            options.preserve,
+            &options.overrides,
+        ) {
+            continue;
+        }
+        match preserve::apply_preserve_attrs_with_atime(
+            &task.source,
+            &task.destination,
+            options.preserve,
+            Some(&task.metadata),
+            None,
+            &options.overrides,
+        ) {
+            Ok(outcome) => {
+                refreshed += 1;
+                refused += report_refused(&outcome, &task.destination);
+            }
+            Err(e) => output::warning(format!(
+                "cannot refresh the attributes of '{}': {}",
+                task.destination.display(),
+                e
+            )),
+        }
+    }
+    (refreshed, refused)
+}
+
+/// Gather the planned files straight into `destination` for `--flatten`,
+/// counting those `--on-collision` leaves out as skipped.
+fn flatten_plan(plan: &mut CopyPlan, destination: &Path, options: &CopyOptions) -> CopyResult<()> {
+    let dropped = flatten(plan, destination, options.on_collision)?;
+    if dropped > 0 {
+        output::status(format!(
+            "Skipping {} files whose names were taken by earlier ones",
+            dropped
+        ));
+        plan.skipped_files += dropped;
+    }
+    skip_existing(plan, options);
+    Ok(())
+}
+
+/// The first planned source, or directory the walk couldn't read, that this
+/// user lacks permission to read. Checked before copying anything, for
+/// `--sudo` to start over with more privilege.
+fn first_denied(plan: &CopyPlan, unreadable: &[UnreadableEntry]) -> Option<PathBuf> {
+    unreadable
+        .iter()
+        .map(|entry| entry.path.as_path())
+        .chain(plan.files.iter().map(|task| task.source.as_path()))
+        .find(|path| read_denied(path))
+        .map(Path::to_path_buf)
 }
 
-fn execute_copy(plan: CopyPlan, options: &CopyOptions) -> CopyResult<()> {
+/// List what the directory walk couldn't read, failing the run if anything.
+fn report_unreadable(unreadable: &[UnreadableEntry]) -> CopyResult<()> {
+    if unreadable.is_empty() {
+        return Ok(());
+    }
+    for entry in unreadable {
+        output::error(format!(
+            "cannot read '{}': {}",
+            entry.path.display(),
+            entry.reason
+        ));
+    }
+    Err(CopyError::Io(io::Error::other(format!(
+        "{} path(s) could not be read",
+        unreadable.len()
+    ))))
+}
+
+/// Give the planned directories their preserved attributes, stamps and
+/// `--chmod-dir` modes, deepest first. Attributes and modes are only set
+/// when the copy `succeeded`; stamps always go on. Returns how many
+/// attributes the destination refused.
+fn finish_directories(
+    directories: &[(DirectoryTask, bool)],
+    options: &CopyOptions,
+    succeeded: bool,
+) -> CopyResult<usize> {
+    let mut directories: Vec<_> = directories.iter().collect();
+    directories.sort_by_key(|(d, _)| std::cmp::Reverse(d.destination.components().count()));
+    let unstamped = AttrOverrides {
+        mtime: None,
+        reproducible: false,
+        ..options.overrides.clone()
+    };
+    let preserve = succeeded && options.preserve != PreserveAttr::none();
+
+    let mut refused = 0;
+    for (directory, stamp) in directories {
+        let destination = &directory.destination;
+        if std::fs::symlink_metadata(destination).is_err() {
+            continue;
+        }
+        let overrides = if *stamp {
+            &options.overrides
+        } else {
+            &unstamped
+        };
+        match &directory.source {
+            Some(source) if preserve => {
+                let outcome = preserve::apply_preserve_attrs_with_atime(
+                    source,
+                    destination,
+                    options.preserve,
+                    directory.metadata.as_ref(),
+                    None,
+                    overrides,
+                )
+                .map_err(|e| CopyError::CopyFailed {
+                    source: source.clone(),
+                    destination: destination.clone(),
+                    reason: e.to_string(),
+                })?;
+                refused += report_refused(&outcome, destination);
+            }
+            source if *stamp => {
+                let source_mtime = source
+                    .as_ref()
+                    .and_then(|s| std::fs::metadata(s).and_then(|m| m.modified()).ok());
+                preserve::apply_overrides(destination, source_mtime, overrides)?;
+            }
+            _ => {}
+        }
+        // Set along with the preserved attributes above, if they were.
+        if succeeded && !(preserve && directory.source.is_some()) {
+            preserve::apply_chmod(destination, overrides)?;
+        }
+    }
+    Ok(refused)
+}
+
+/// Warn about each attribute the destination refused, returning how many.
+fn report_refused(outcome: &PreserveOutcome, destination: &Path) -> usize {
+    for (attribute, reason) in &outcome.failed {
+        output::warning(format!(
+            "cannot preserve {} on '{}': {}",
+            attribute,
+            destination.display(),
+            reason
+        ));
+    }
+    outcome.failed.len()
+}
+
+fn run_plan(
+    session: &mut CopySession,
+    mut plan: CopyPlan,
+    options: &CopyOptions,
+    summary: &mut Summary,
+    item_log: Option<&ItemLog>,
+    replicas: &Replicas,
+    directories: &LazyDirectories,
+) -> CopyResult<()> {
+    if let Some(max_memory) = options.max_memory {
+        let estimate = plan.estimated_memory();
+        if options.debug {
+            output::debug(format!(
+                "the copy plan takes about {}",
+                HumanBytes(estimate)
+            ));
+        }
+        if estimate > max_memory {
+            output::warning(format!(
+                "the copy plan alone takes about {}, more than --max-memory {}; \
+                 copy the tree in smaller parts to stay within it",
+                HumanBytes(estimate),
+                HumanBytes(max_memory)
+            ));
+        }
+    }
+    let performed = |destination: &Path| {
+        if let Some(log) = item_log {
+            log.performed(destination);
+        }
+    };
+    // A file --prefer-hardlink linked shows as the link it became.
+    let performed_file = |task: &FileTask| {
+        if let Some(log) = item_log
+            && options.prefer_hardlink
+            && let (Ok(source), Ok(destination)) = (
+                std::fs::symlink_metadata(&task.source),
+                std::fs::symlink_metadata(&task.destination),
+            )
+            && same_file(&source, &destination)
+        {
+            log.linked(&task.destination, &task.source);
+        } else {
+            performed(&task.destination);
+        }
+    };
+    // The -v line for what was made from `source`, before any bar is drawn.
+    let report = |source: &Path, destination: &Path| {
+        if options.verbose {
+            output::verbose(None, source, "->", destination);
+        }
+    };
+    // A link whose destination was made since planning is left alone
+    // under --no-clobber.
+    let keep = |destination: &Path, summary: &mut Summary| {
+        let kept = options.keeps_existing() && std::fs::symlink_metadata(destination).is_ok();
+        if kept {
+            summary.files_skipped += 1;
+            summary.files_untouched += 1;
+        }
+        kept
+    };
     if !options.attributes_only {
-        create_directories(&plan.directories)?;
-    } else {
-        for dir_task in &plan.directories {
-            if let Some(src) = &dir_task.source
-                && std::fs::symlink_metadata(&dir_task.destination).is_ok()
-            {
-                preserve::apply_preserve_attrs(src, &dir_task.destination, options.preserve)
-                    .map_err(|e| CopyError::CopyFailed {
-                        source: src.clone(),
-                        destination: dir_task.destination.clone(),
-                        reason: e.to_string(),
-                    })?;
+        // The rest are made as files are written into them.
+        directories.make_empty()?;
+        replicas.create_directories(&plan.directories);
+        summary.directories_created = plan.directories.len();
+        for directory in &plan.directories {
+            if let Some(source) = &directory.source {
+                report(source, &directory.destination);
             }
+            performed(&directory.destination);
         }
+    } else {
+        // Their attributes go on with finish_directories, after the files.
+        plan.directories
+            .iter()
+            .filter(|d| d.source.is_some() && std::fs::symlink_metadata(&d.destination).is_ok())
+            .for_each(|d| performed(&d.destination));
     }
 
     if options.hard_link {
         for hardlink_task in &plan.hardlinks {
+            if keep(&hardlink_task.destination, summary) {
+                continue;
+            }
+            directories.ensure_parent(&hardlink_task.destination)?;
             create_hardlink(hardlink_task, options)?;
+            summary.hardlinks_created += 1;
+            report(&hardlink_task.source, &hardlink_task.destination);
+            performed(&hardlink_task.destination);
         }
 
-        if plan.total_hardlinks > 0 {
-            println!("Created {} hard links", plan.total_hardlinks);
+        if summary.hardlinks_created > 0 {
+            output::status(format!("Created {} hard links", summary.hardlinks_created));
         }
         return Ok(());
     }
 
     if !plan.symlinks.is_empty() {
+        // Symlinks hard-linked to each other in the source stay that way.
+        let mut linked = HardLinkTracker::new();
         for symlink_task in &plan.symlinks {
-            create_symlink(symlink_task, options).map_err(|_e| CopyError::SymlinkFailed {
+            if keep(&symlink_task.destination, summary) {
+                continue;
+            }
+            directories.ensure_parent(&symlink_task.destination)?;
+            let first = symlink_task
+                .inode_group
+                .and_then(|inode| linked.first_for(inode, &symlink_task.destination));
+            match &first {
+                Some(first) => link_symlink(first, symlink_task, options),
+                None => create_symlink(symlink_task, options),
+            }
+            .map_err(|error| CopyError::SymlinkFailed {
                 source: symlink_task.source.clone(),
                 destination: symlink_task.destination.clone(),
+                error,
             })?;
+            summary.symlinks_created += 1;
+            let source = symlink_task.origin.as_ref().unwrap_or(&symlink_task.source);
+            report(source, &symlink_task.destination);
+            performed(&symlink_task.destination);
         }
-        if plan.total_symlinks > 0 {
-            println!("Created {} symbolic links", plan.total_symlinks);
+        replicas.create_symlinks(&plan.symlinks, options);
+        if summary.symlinks_created > 0 {
+            output::status(format!(
+                "Created {} symbolic links",
+                summary.symlinks_created
+            ));
         }
 
         if options.symbolic_link.is_some() {
@@ -136,91 +1000,290 @@ fn execute_copy(plan: CopyPlan, options: &CopyOptions) -> CopyResult<()> {
         }
     }
 
-    let overall_pb = if plan.total_files >= 1 && !options.interactive && !options.attributes_only {
-        let pb = ProgressBar::new(plan.total_size);
-        options.progress_bar.apply(&pb, plan.total_files);
-        Some(Arc::new(pb))
-    } else {
-        None
-    };
+    if !options.attributes_only {
+        for special in &plan.specials {
+            if keep(&special.destination, summary) {
+                continue;
+            }
+            directories.ensure_parent(&special.destination)?;
+            create_special(special, options).map_err(|error| CopyError::OperationFailed {
+                operation: "create special file".to_string(),
+                path: special.destination.clone(),
+                error,
+            })?;
+            report(&special.source, &special.destination);
+            performed(&special.destination);
+        }
+    }
 
-    let completed_files = Arc::new(AtomicUsize::new(0));
+    // --interactive=dirs asks as it reaches each directory, so it copies one
+    // file at a time. -i asks about every file it would replace up front
+    // instead, and the copy goes on in parallel like any other.
+    let interactive = options.interactive_dirs.is_some();
+    if options.interactive == Prompt::Always && !interactive {
+        let declined = ask_before_replacing(&mut plan)?;
+        summary.files_skipped += declined;
+    }
+    let wants_bar = plan.total_files >= 1 && !interactive && !options.attributes_only;
+    let draw_bar = options
+        .progress_bar
+        .should_draw(plan.total_size, plan.total_files);
+    if !interactive {
+        session.ensure_pool(options.parallel)?;
+    }
+    session.total_files += plan.total_files;
+    session.ensure_budget(options);
+    let overall_pb = (wants_bar && draw_bar)
+        .then(|| session.progress_for(plan.total_size * replicas.destinations(), options));
+    let session = &*session;
+
+    // Counted on from earlier calls in the session; this call's share of
+    // the summary is what they add up to from here.
+    let counters = &session.counters;
+    let completed_before = counters.completed.load(Ordering::Relaxed);
+    let identical_before = counters.identical.load(Ordering::Relaxed);
+    let refused_before = counters.attributes_failed.load(Ordering::Relaxed);
+    let linked_before = counters.linked.load(Ordering::Relaxed);
+    let untouched_before = counters.untouched.load(Ordering::Relaxed);
+    let (skipped_planned, untouched_planned) = (summary.files_skipped, summary.files_untouched);
+    // Files --prefer-hardlink linked complete like copied ones, but count
+    // as links.
+    let count_completed = |summary: &mut Summary| {
+        let untouched = counters.untouched.load(Ordering::Relaxed) - untouched_before;
+        summary.files_skipped = skipped_planned + untouched;
+        summary.files_untouched = untouched_planned + untouched;
+        let linked = counters.linked.load(Ordering::Relaxed) - linked_before;
+        summary.files_copied =
+            counters.completed.load(Ordering::Relaxed) - completed_before - linked;
+        if options.prefer_hardlink {
+            summary.hardlinks_created = linked;
+        }
+    };
+    let total_files = session.total_files;
+    counters
+        .skipped
+        .fetch_add(plan.skipped_files, Ordering::Relaxed);
+    // Files skipped while planning count from the start.
+    refresh_message(overall_pb.as_deref(), counters, total_files, options);
+
+    let watchdog = options.stall_timeout.map(|timeout| {
+        StallWatchdog::start(
+            timeout,
+            options.stall_action,
+            overall_pb.clone(),
+            options.progress_bar.style,
+        )
+    });
 
     // Initialize hard link tracker if preserve.links is enabled
-    let hardlink_tracker = if options.preserve.links {
-        Some(Arc::new(Mutex::new(HardLinkTracker::new())))
+    let hardlink_tracker = if options.preserve.links && options.hardlinks_supported() {
+        Some(&session.hardlinks)
     } else {
+        if options.preserve.links && plan.files.iter().any(|f| f.inode_group.is_some()) {
+            if options.link_fallback == LinkFallback::Error {
+                return Err(CopyError::Io(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "destination does not support hard links",
+                )));
+            }
+            output::warning(
+                "destination does not support hard links; linked files will be copied separately",
+            );
+        }
         None
     };
 
-    // For interactive mode, process sequentially
-    if options.interactive {
-        for file_task in plan.files {
-            copy_core(
-                &file_task.source,
-                &file_task.destination,
-                file_task.size,
+    let reflink_cache = match options.reflink {
+        Some(mode) if mode != ReflinkMode::Never => Some(ReflinkCache::new()),
+        _ => None,
+    };
+
+    let sharing = options.preserve_sharing.then(|| {
+        if cfg!(not(target_os = "linux")) {
+            output::warning(
+                "--preserve-sharing is only supported on Linux; copying files separately",
+            );
+        }
+        SharedExtents::find(&plan.files)
+    });
+
+    // Enter can end a pause, unless stdin answers prompts.
+    let full = DiskFull::new(
+        options.on_full,
+        plan.total_size,
+        !interactive && io::stdin().is_terminal(),
+        overall_pb.clone(),
+    );
+    // Whether the budget still lets a file start; the rest wait for the
+    // next run.
+    let admit = |file_task: &FileTask| {
+        session
+            .budget
+            .as_ref()
+            .is_none_or(|budget| budget.admit(file_task.size))
+    };
+    // Copy one file, again after a full destination was waited out.
+    // `None` when it was left alone instead.
+    let copy_file = |file_task: &FileTask| -> CopyResult<Option<u64>> {
+        if left_alone(file_task, overall_pb.as_deref(), counters, options)? {
+            return Ok(None);
+        }
+        directories.ensure_parent(&file_task.destination)?;
+        loop {
+            full.before_file(&file_task.destination, file_task.size, &options.abort)?;
+            let result = copy_core(
+                file_task,
                 overall_pb.as_deref(),
-                &completed_files,
-                plan.total_files,
+                counters,
+                total_files,
                 options,
-                hardlink_tracker.as_ref(),
-            )?;
+                hardlink_tracker,
+                reflink_cache.as_ref(),
+                sharing.as_ref(),
+                watchdog.as_ref(),
+                replicas,
+            );
+            match result {
+                Err(e) if full::is_disk_full(&e) => {
+                    full.on_full(&file_task.destination, file_task.size, e, &options.abort)?
+                }
+                result => {
+                    if result.is_ok() {
+                        full.completed(file_task.size);
+                        if options.drop_cache {
+                            // Only advice; the copy is done either way.
+                            let _ =
+                                background::drop_cache(&file_task.source, &file_task.destination);
+                        }
+                        if options.verbose {
+                            output::verbose(
+                                overall_pb.as_deref(),
+                                &file_task.source,
+                                "->",
+                                &file_task.destination,
+                            );
+                        }
+                    }
+                    return result.map(Some);
+                }
+            }
+        }
+    };
+
+    // For interactive mode, process sequentially
+    if interactive {
+        let mut prompts = DirPrompts::new(plan.dir_group_totals());
+        let mut files = plan.files;
+        // Ask about each directory once, in the order they were planned.
+        files.sort_by_key(|f| f.dir_group.as_ref().and_then(|g| prompts.position(g)));
+
+        let mut declined = 0;
+        for file_task in files {
+            let allowed = match &file_task.dir_group {
+                Some(group) => prompts.allows(group)?,
+                None => !prompts.quit,
+            };
+            if !allowed {
+                declined += 1;
+                counters.skipped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if !admit(&file_task) {
+                continue;
+            }
+            let result = copy_file(&file_task);
+            count_completed(summary);
+            match result {
+                Ok(Some(bytes)) => {
+                    summary.bytes_copied += bytes;
+                    performed_file(&file_task);
+                }
+                // Skipped, at the prompt or by -n.
+                Ok(None) => {}
+                Err(e) => {
+                    summary.files_failed += 1;
+                    return Err(e);
+                }
+            }
+        }
+        summary.bytes_deduplicated = sharing.as_ref().map_or(0, |s| s.deduplicated());
+        summary.bytes_identical = counters.identical.load(Ordering::Relaxed) - identical_before;
+        summary.attributes_failed =
+            counters.attributes_failed.load(Ordering::Relaxed) - refused_before;
+        if declined > 0 {
+            summary.files_skipped += declined;
+            output::status(format!(
+                "Skipped {} files in declined directories",
+                declined
+            ));
         }
     } else {
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(options.parallel)
-            .build()
-            .map_err(|e| CopyError::CopyFailed {
-                source: PathBuf::new(),
-                destination: PathBuf::new(),
-                reason: format!("Failed to create thread pool: {}", e),
-            })?;
+        let pool = session
+            .pool
+            .as_ref()
+            .expect("the pool is built before non-interactive copies");
 
-        let results: Vec<_> = pool.install(|| {
-            plan.files
-                .par_iter()
-                .map(|file_task| {
-                    let result = copy_core(
-                        &file_task.source,
-                        &file_task.destination,
-                        file_task.size,
-                        overall_pb.as_deref(),
-                        &completed_files,
-                        plan.total_files,
-                        options,
-                        hardlink_tracker.as_ref(),
-                    );
-
-                    match result {
-                        Ok(()) => Ok(()),
-                        Err(e) => Err((file_task.source.clone(), file_task.destination.clone(), e)),
-                    }
-                })
-                .collect()
-        });
+        let copy_task = |file_task: &FileTask| {
+            let result = copy_file(file_task);
+
+            if let Ok(Some(_)) = result {
+                performed_file(file_task);
+            }
+            result.map_err(|e| {
+                if !e.is_abort() {
+                    record_failure(overall_pb.as_deref(), counters, total_files, options);
+                }
+                (file_task.source.clone(), file_task.destination.clone(), e)
+            })
+        };
+        // Files sharing extents go one at a time, so a shared range is always
+        // written before anything clones it.
+        let (shared, independent): (Vec<&FileTask>, Vec<&FileTask>) = plan
+            .files
+            .iter()
+            .partition(|f| sharing.as_ref().is_some_and(|s| s.contains(&f.source)));
+        let mut results: Vec<_> = shared
+            .into_iter()
+            .filter(|f| admit(f))
+            .map(copy_task)
+            .collect();
+        results.extend(pool.install(|| {
+            // Bridging hands tasks out one at a time in plan order, so
+            // workers start files in the order chosen by --order, and the
+            // budget is checked as each is handed out.
+            independent
+                .into_iter()
+                .filter(|f| admit(f))
+                .par_bridge()
+                .map(copy_task)
+                .collect::<Vec<_>>()
+        }));
+        summary.bytes_deduplicated = sharing.as_ref().map_or(0, |s| s.deduplicated());
+        summary.bytes_identical = counters.identical.load(Ordering::Relaxed) - identical_before;
+        summary.attributes_failed =
+            counters.attributes_failed.load(Ordering::Relaxed) - refused_before;
 
         let mut interrupted = false;
         let mut errors: Vec<(PathBuf, PathBuf, CopyError)> = Vec::new();
 
         for result in results.into_iter() {
-            if let Err((source, dest, e)) = result {
-                match e {
-                    CopyError::Io(ref io_err) if io_err.kind() == io::ErrorKind::Interrupted => {
-                        interrupted = true;
-                    }
-                    _ => {
-                        errors.push((source, dest, e));
-                    }
-                }
+            match result {
+                Ok(size) => summary.bytes_copied += size.unwrap_or(0),
+                Err((_, _, e)) if e.is_abort() => interrupted = true,
+                Err(error) => errors.push(error),
             }
         }
+        count_completed(summary);
+        summary.files_failed = errors.len();
 
         if interrupted {
-            let completed = completed_files.load(Ordering::Relaxed);
+            let completed = summary.files_copied;
 
-            eprintln!("\nCompleted:  {} files", completed);
-            eprintln!("Remaining:  {} files", plan.total_files - completed);
+            output::status(format!("\nCompleted:  {} files", completed));
+            output::status(format!(
+                "Remaining:  {} files",
+                plan.total_files - completed
+            ));
 
             return Err(CopyError::Io(io::Error::new(
                 io::ErrorKind::Interrupted,
@@ -232,70 +1295,230 @@ fn execute_copy(plan: CopyPlan, options: &CopyOptions) -> CopyResult<()> {
             if let Some(pb) = overall_pb {
                 pb.abandon_with_message("Completed with errors");
             }
-            eprintln!("\nFailed to copy {} file(s):", errors.len());
-            for (source, _dest, err) in errors.iter().take(3) {
-                eprintln!("  {} - {}", source.display(), err);
+            // Files a full destination kept out share a single line.
+            if let Some(line) = full.summary() {
+                output::status(format!("\n{}", line));
+                errors.retain(|(_, _, e)| !full::is_disk_full(e));
             }
-            if errors.len() > 3 {
-                eprintln!("  ... and {} more", errors.len() - 5);
+            if !errors.is_empty() {
+                output::status(format!("\nFailed to copy {} file(s):", errors.len()));
+                for (source, _dest, err) in errors.iter().take(3) {
+                    output::status(format!("  {} - {}", source.display(), err));
+                }
+                if errors.len() > 3 {
+                    output::status(format!("  ... and {} more", errors.len() - 3));
+                }
+            }
+            if errors.iter().any(|(_, _, e)| limits::is_fd_exhaustion(e)) {
+                output::status(format!("Hint: {}", limits::FD_EXHAUSTION_HINT));
             }
             return Err(CopyError::Io(io::Error::other(format!(
                 "{} file(s) failed to copy",
-                errors.len()
+                summary.files_failed
             ))));
         }
     }
 
-    if let Some(pb) = overall_pb {
-        if matches!(options.progress_bar.style, ProgressBarStyle::Detailed)
-            && !options.attributes_only
-        {
-            pb.finish_with_message(format!("Copied {} files successfully", plan.total_files));
-        } else {
-            pb.finish_with_message("Done".to_string());
+    // The bar is finished with the session, so later calls count on.
+    if overall_pb.is_none() && wants_bar && options.progress_bar.mode == ProgressMode::Auto {
+        output::status(format!(
+            "Copied {} files ({})",
+            plan.total_files,
+            HumanBytes(summary.bytes_copied)
+        ));
+    }
+    if summary.bytes_deduplicated > 0 {
+        output::status(format!(
+            "Kept {} shared between copied files",
+            HumanBytes(summary.bytes_deduplicated)
+        ));
+    }
+    if options.prefer_hardlink {
+        output::status(format!(
+            "Linked {} files, copied {}",
+            summary.hardlinks_created, summary.files_copied
+        ));
+    }
+    if options.compare_write {
+        output::status(format!(
+            "Wrote {}, left {} already identical",
+            HumanBytes(summary.bytes_copied),
+            HumanBytes(summary.bytes_identical)
+        ));
+    }
+    if options.debug {
+        output::debug(format!(
+            "files copied by method: {}",
+            counters.methods.describe()
+        ));
+        let slowest = counters.slowest.describe();
+        if !slowest.is_empty() {
+            output::debug("slowest files:");
+            slowest
+                .into_iter()
+                .for_each(|line| output::debug(format!("  {}", line)));
         }
     }
 
     Ok(())
 }
 
+/// Whether the file is not to be written after all: its destination was
+/// made since the plan was, perhaps by another copy into the same tree,
+/// and is kept, or the user declined to overwrite it.
+fn left_alone(
+    file_task: &FileTask,
+    overall_pb: Option<&ProgressBar>,
+    counters: &FileCounters,
+    options: &CopyOptions,
+) -> CopyResult<bool> {
+    let destination = &file_task.destination;
+    if options.attributes_only {
+        return Ok(false);
+    }
+    if options.keeps_existing() && std::fs::symlink_metadata(destination).is_ok() {
+        counters.skipped.fetch_add(1, Ordering::Relaxed);
+        counters.untouched.fetch_add(1, Ordering::Relaxed);
+        if let Some(pb) = overall_pb {
+            pb.inc(file_task.size);
+        }
+        return Ok(true);
+    }
+    // Otherwise -i asked before copying began.
+    if options.interactive == Prompt::Always
+        && options.interactive_dirs.is_some()
+        && destination.try_exists().unwrap_or(false)
+        && !prompt_overwrite(destination)?
+    {
+        counters.skipped.fetch_add(1, Ordering::Relaxed);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Ask `-i`'s question about each planned file whose destination was there,
+/// in plan order, taking out the ones the answer is no for. Returns how
+/// many were taken out.
+fn ask_before_replacing(plan: &mut CopyPlan) -> CopyResult<usize> {
+    let mut declined = 0;
+    let mut kept = Vec::with_capacity(plan.files.len());
+    for file in std::mem::take(&mut plan.files) {
+        if file.existing && !prompt_overwrite(&file.destination)? {
+            plan.total_files -= 1;
+            plan.total_size -= file.size;
+            plan.mark_skipped(file.size);
+            declined += 1;
+        } else {
+            kept.push(file);
+        }
+    }
+    plan.files = kept;
+    Ok(declined)
+}
+
 #[allow(clippy::too_many_arguments)]
 fn copy_core(
-    source: &Path,
-    destination: &Path,
-    file_size: u64,
+    file_task: &FileTask,
     overall_pb: Option<&ProgressBar>,
-    completed_files: &AtomicUsize,
+    counters: &FileCounters,
     total_files: usize,
     options: &CopyOptions,
     hardlink_tracker: Option<&Arc<Mutex<HardLinkTracker>>>,
-) -> CopyResult<()> {
-    if options.attributes_only {
-        if std::fs::symlink_metadata(destination).is_err() {
-            return Ok(());
+    reflink_cache: Option<&ReflinkCache>,
+    sharing: Option<&SharedExtents>,
+    watchdog: Option<&StallWatchdog>,
+    replicas: &Replicas,
+) -> CopyResult<u64> {
+    let source = file_task.source.as_path();
+    let destination = file_task.destination.as_path();
+    let file_size = file_task.size;
+    let _stall_guard = watchdog.map(|w| w.begin(source));
+    let started = Instant::now();
+    // Files whose data was copied are timed; links and skips are not.
+    let timed = |mechanism: Mechanism, bytes: u64| {
+        counters
+            .slowest
+            .record(source, bytes, started.elapsed(), mechanism.name());
+        counters.methods.record(mechanism);
+        if options.debug {
+            output::debug_above(overall_pb, format!("{}: {}", source.display(), mechanism));
         }
-        preserve::apply_preserve_attrs(source, destination, options.preserve)?;
-        return Ok(());
-    }
+        bytes
+    };
+    // Taken before anything replaces the destination.
+    let kept = if options.keep_dest_attrs {
+        KeptAttrs::snapshot(destination)
+    } else {
+        None
+    };
+    let preserve_at = |destination: &Path| -> CopyResult<()> {
+        // Before the mode is preserved, which may leave the file read-only.
+        if options.store_checksum {
+            checksum::store_checksum(source, destination).map_err(|error| {
+                CopyError::OperationFailed {
+                    operation: "store checksum".to_string(),
+                    path: destination.to_path_buf(),
+                    error,
+                }
+            })?;
+        }
+        if options.preserve != PreserveAttr::none()
+            || options.overrides.stamps()
+            || options.overrides.sets_mode()
+        {
+            let outcome = preserve::apply_preserve_attrs_with_atime(
+                source,
+                destination,
+                options.preserve,
+                file_task.metadata.as_ref(),
+                None,
+                &options.overrides,
+            )?;
+            counters
+                .attributes_failed
+                .fetch_add(report_refused(&outcome, destination), Ordering::Relaxed);
+        }
+        if let Some(kept) = &kept {
+            kept.restore(destination)?;
+        }
+        Ok(())
+    };
+    let preserve_attrs = || preserve_at(destination);
 
-    if options.interactive
-        && destination.try_exists().unwrap_or(false)
-        && !prompt_overwrite(destination)?
-    {
-        return Ok(());
+    if options.attributes_only {
+        if std::fs::symlink_metadata(destination).is_err() {
+            return Ok(0);
+        }
+        preserve_attrs()?;
+        return Ok(0);
     }
 
-    if let Some(backup_mode) = options.backup
-        && backup_mode != BackupMode::None
-        && destination.try_exists().unwrap_or(false)
-    {
-        let backup_path = generate_backup_path(destination, backup_mode)?;
-        let _ = create_backup(destination, &backup_path);
-    }
+    prepare_destination(destination, overall_pb, options)?;
 
-    if options.remove_destination {
-        let _ = std::fs::remove_file(destination);
+    // The same file under each --also-to target. A target whose existing
+    // file can't be backed up is left out of this file only.
+    let mut extras = replicas.paths_for(destination);
+    if options.keeps_existing() {
+        extras.retain(|(_, path)| std::fs::symlink_metadata(path).is_err());
     }
+    extras.retain(
+        |(index, path)| match prepare_destination(path, overall_pb, options) {
+            Ok(()) => true,
+            Err(e) => {
+                replicas.failed(*index, path, e);
+                false
+            }
+        },
+    );
+    let replicate = || {
+        if replicas.is_empty() {
+            return;
+        }
+        replicas.copy_from_primary(destination, &extras, reflink_cache, preserve_at);
+        if let Some(pb) = overall_pb {
+            pb.inc(file_size * (replicas.destinations() - 1));
+        }
+    };
 
     // Handle hard link preservation
     if let Some(tracker) = hardlink_tracker {
@@ -303,39 +1526,121 @@ fn copy_core(
             CopyError::Io(io::Error::other("Failed to acquire hardlink tracker lock"))
         })?;
 
+        let first = tracker_guard.destination_for(source);
         if tracker_guard.track_and_create_link(source, destination)? {
             // Hard link was created, no need to copy file content
-            update_progress(overall_pb, completed_files, total_files, options);
-            if options.preserve != PreserveAttr::none() {
-                preserve::apply_preserve_attrs(source, destination, options.preserve)
-                    .map_err(CopyError::from)?;
+            if let Some(first) = first {
+                replicas.hard_link(&first, &extras);
             }
-            return Ok(());
+            update_progress(overall_pb, counters, total_files, options);
+            preserve_attrs()?;
+            return Ok(0);
         }
         // Continue with normal file copy if this is the first file in the inode group
     }
 
-    if let Some(reflink_mode) = options.reflink {
-        use crate::cli::args::ReflinkMode;
-        if reflink_mode != ReflinkMode::Never {
-            if destination.try_exists().unwrap_or(false) {
-                return Err(CopyError::ReflinkFailed {
-                    source: source.to_path_buf(),
-                    destination: destination.to_path_buf(),
-                });
+    // With --prefer-hardlink a file on the destination's filesystem is
+    // linked, unless that would write to the inode it shares with the
+    // source. Anything else about it is copied after all.
+    if options.prefer_hardlink && linking_keeps_source(options) {
+        match link_in_place(source, destination) {
+            Ok(()) => {
+                counters.linked.fetch_add(1, Ordering::Relaxed);
+                if let Some(pb) = overall_pb {
+                    pb.inc(file_size);
+                }
+                update_progress(overall_pb, counters, total_files, options);
+                replicate();
+                return Ok(0);
+            }
+            Err(e) if options.debug => output::debug_above(
+                overall_pb,
+                format!("cannot link '{}', copying it: {}", source.display(), e),
+            ),
+            Err(_) => {}
+        }
+    }
+
+    // With --copy-contents, pipes and devices found while walking are read
+    // like one named on the command line; they were planned with size 0.
+    // How much there is only shows once they end, so until then the bar
+    // spins, counting what was read.
+    if options.copy_contents && file_size == 0 && stream::is_stream(source) {
+        if let Some(pb) = overall_pb {
+            options.progress_bar.apply_spinner(pb);
+        }
+        let copied = stream::copy_contents(source, destination, options, |bytes| {
+            if let Some(pb) = overall_pb {
+                pb.inc_length(bytes);
+                pb.inc(bytes);
             }
+        });
+        if let Some(pb) = overall_pb {
+            let failed = counters.failed.load(Ordering::Relaxed) > 0;
+            pb.set_style(options.progress_bar.style_for(failed));
+        }
+        let copied = copied?;
+        update_progress(overall_pb, counters, total_files, options);
+        replicate();
+        return Ok(timed(Mechanism::Stream, copied));
+    }
+
+    // With --staging-dir the data is written to a staged file, which is
+    // moved into place before the attributes are preserved on it. If the
+    // copy fails the staged file is dropped and removed.
+    let landing = destination;
+    let staged = options.staging_dir.as_deref().map(StagedFile::new);
+    let destination = staged.as_ref().map_or(destination, StagedFile::path);
+    let preserve_attrs = || {
+        if let Some(staged) = &staged {
+            staged
+                .land(landing)
+                .map_err(|error| CopyError::OperationFailed {
+                    operation: "move staged file".to_string(),
+                    path: landing.to_path_buf(),
+                    error,
+                })?;
+        }
+        preserve_at(landing)
+    };
 
-            match reflink_copy::reflink(source, destination) {
+    if let Some(cache) = reflink_cache
+        && let Some(reflink_mode) = options.reflink
+    {
+        // A clone always creates a new file. When one is required, an
+        // existing destination the overwrite policy let through is replaced by
+        // cloning next to it and renaming; otherwise the regular copy below
+        // overwrites it in place.
+        let exists = destination.try_exists().unwrap_or(false);
+        if !exists || reflink_mode == ReflinkMode::Always {
+            let confine = (options.confine.as_deref()).filter(|root| destination.starts_with(root));
+            let result = if let Some(root) = confine {
+                cache.reflink_beneath(root, source, destination)
+            } else {
+                let target = if exists {
+                    temp_path_for(destination)
+                } else {
+                    destination.to_path_buf()
+                };
+                cache.reflink(source, &target).and_then(|()| {
+                    if exists {
+                        std::fs::rename(&target, destination).inspect_err(|_| {
+                            let _ = std::fs::remove_file(&target);
+                        })
+                    } else {
+                        Ok(())
+                    }
+                })
+            };
+            match result {
                 Ok(()) => {
                     if let Some(pb) = overall_pb {
                         pb.inc(file_size);
                     }
-                    update_progress(overall_pb, completed_files, total_files, options);
-                    if options.preserve != PreserveAttr::none() {
-                        preserve::apply_preserve_attrs(source, destination, options.preserve)
-                            .map_err(CopyError::from)?;
-                    }
-                    return Ok(());
+                    update_progress(overall_pb, counters, total_files, options);
+                    preserve_attrs()?;
+                    replicate();
+                    return Ok(timed(Mechanism::Reflink, file_size));
                 }
                 Err(_e) if reflink_mode == ReflinkMode::Always => {
                     return Err(CopyError::ReflinkFailed {
@@ -348,35 +1653,116 @@ fn copy_core(
         }
     }
 
-    #[cfg(target_os = "linux")]
+    if options.compare_write
+        && let Some(stats) = compare_write(source, destination, file_size, overall_pb, options)?
     {
-        if options.abort.load(Ordering::Relaxed) {
-            return Err(CopyError::Io(io::Error::new(
-                io::ErrorKind::Interrupted,
-                "Operation aborted by user",
-            )));
+        counters
+            .identical
+            .fetch_add(stats.identical, Ordering::Relaxed);
+        update_progress(overall_pb, counters, total_files, options);
+        preserve_attrs()?;
+        replicate();
+        return Ok(timed(Mechanism::CompareWrite, stats.written));
+    }
+
+    if let Some(sharing) = sharing
+        && let Some(copied) = sharing
+            .copy_file(source, destination, file_size, options)
+            .map_err(|error| CopyError::OperationFailed {
+                operation: "copy".to_string(),
+                path: source.to_path_buf(),
+                error,
+            })?
+    {
+        if let Some(pb) = overall_pb {
+            pb.inc(copied);
         }
-        if let Ok(true) = fast_copy(source, destination, file_size, overall_pb, options) {
-            update_progress(overall_pb, completed_files, total_files, options);
-            if options.preserve != PreserveAttr::none() {
-                preserve::apply_preserve_attrs(source, destination, options.preserve)
-                    .map_err(CopyError::from)?;
+        update_progress(overall_pb, counters, total_files, options);
+        preserve_attrs()?;
+        replicate();
+        return Ok(timed(Mechanism::Shared, copied));
+    }
+
+    // With --also-to the buffered copy below writes every target from a
+    // single read of the source, and with --bwlimit it is the one that
+    // can be throttled.
+    let file = FileContext {
+        size: file_size,
+        replicated: !replicas.is_empty(),
+        throttled: options.bwlimit.is_some(),
+    };
+    let methods =
+        engine::methods(options.engine, Capabilities::probe(), file).map_err(|reason| {
+            CopyError::CopyFailed {
+                source: source.to_path_buf(),
+                destination: destination.to_path_buf(),
+                reason,
             }
-            return Ok(());
+        })?;
+    for &method in &methods {
+        // How the copy was done and the bytes it copied.
+        let copied = match method {
+            #[cfg(windows)]
+            Method::Odx => odx::server_copy(source, destination, file_size, overall_pb, options)?
+                .map(|used| (Mechanism::Method(used), file_size)),
+            #[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+            Method::Sparse => {
+                match sparse::sparse_copy(source, destination, file_size, overall_pb, options) {
+                    Ok(true) => Some((Mechanism::Method(method), file_size)),
+                    _ => None,
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Method::Offload => {
+                if options.abort.load(Ordering::Relaxed) {
+                    return Err(CopyError::Io(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        "Operation aborted by user",
+                    )));
+                }
+                match fast_copy(source, destination, file_size, overall_pb, options) {
+                    Ok(copied) => copied,
+                    Err(e) if options.engine == Engine::Offload => return Err(e),
+                    Err(_) => None,
+                }
+            }
+            #[cfg(unix)]
+            Method::Mmap => Some((
+                Mechanism::Method(method),
+                mmap::mmap_copy(source, destination, overall_pb, options)?,
+            )),
+            // The buffered copy below.
+            _ => None,
+        };
+        if let Some((used, copied)) = copied {
+            update_progress(overall_pb, counters, total_files, options);
+            preserve_attrs()?;
+            return Ok(timed(used, copied));
         }
     }
+    if !methods.contains(&Method::Buffered) {
+        return Err(CopyError::CopyFailed {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            reason: format!("the {} engine could not copy the file", methods[0].name()),
+        });
+    }
 
-    let mut src_file = std::fs::File::open(source)?;
-    let dest_file = match std::fs::File::create(destination) {
-        Ok(file) => file,
-        Err(_e) if options.force => {
-            let _ = std::fs::remove_file(destination);
-            std::fs::File::create(destination)?
-        }
-        Err(e) => return Err(CopyError::Io(e)),
-    };
+    let mut src_file = open_source(source, options.noatime)?;
+    let dest_file = open_destination(source, destination, options)?;
+
+    // Zeros written as holes, where the sparse copy couldn't be used.
+    let make_holes = src_file
+        .metadata()
+        .is_ok_and(|metadata| sparse::makes_holes(options.sparse, &metadata));
+    if options.preallocate && file_size >= 1024 * 1024 && !make_holes {
+        let _ = sparse::preallocate(&dest_file, file_size);
+    }
 
-    let buffer_size: usize = if file_size < 1024 * 1024 {
+    let buffer_size: usize = if let Some(size) = options.buffer_size {
+        // No point holding more than the whole file.
+        size.min((file_size as usize).max(64 * 1024))
+    } else if file_size < 1024 * 1024 {
         64 * 1024
     } else if file_size < 8 * 1024 * 1024 {
         256 * 1024
@@ -391,6 +1777,18 @@ fn copy_core(
     let mut dest_file = std::io::BufWriter::with_capacity(buffer_size, dest_file);
     let mut buffer = vec![0u8; buffer_size];
 
+    let mut replica_files = Vec::with_capacity(extras.len());
+    for (index, path) in &extras {
+        match replicas.create(*index, path) {
+            Ok(file) => replica_files.push((
+                *index,
+                path.as_path(),
+                std::io::BufWriter::with_capacity(buffer_size, file),
+            )),
+            Err(e) => replicas.failed(*index, path, e),
+        }
+    }
+
     const MAX_UPDATES: u64 = 128;
     let update_threshold = if file_size > MAX_UPDATES * buffer_size as u64 {
         file_size / MAX_UPDATES
@@ -399,34 +1797,76 @@ fn copy_core(
     };
 
     let mut accumulated_bytes = 0u64;
+    let mut copied = 0u64;
 
+    // Read until EOF rather than up to the planned size, so files that
+    // report a wrong size (procfs, sysfs) or grew since planning come out
+    // whole.
     loop {
-        if options.abort.load(Ordering::Relaxed) {
+        full::hold(&options.abort);
+        let stalled = stall::cancelled();
+        if stalled || options.abort.load(Ordering::Relaxed) {
             dest_file.flush()?;
             drop(dest_file);
+            for (_, path, writer) in replica_files {
+                drop(writer);
+                let _ = std::fs::remove_file(path);
+            }
             if let Err(e) = std::fs::remove_file(destination) {
-                eprintln!(
+                output::status(format!(
                     "Could not remove incomplete file {}: {}",
                     destination.display(),
                     e
-                );
+                ));
             } else {
-                eprintln!("Cleaned up incomplete file: {}", destination.display());
+                output::status(format!(
+                    "Cleaned up incomplete file: {}",
+                    destination.display()
+                ));
             }
 
+            if stalled {
+                return Err(CopyError::Io(stall::stalled_error()));
+            }
             return Err(CopyError::Io(io::Error::new(
                 io::ErrorKind::Interrupted,
                 "Operation aborted by user",
             )));
         }
 
-        let bytes_read = src_file.read(&mut buffer)?;
+        let bytes_read = retry_eintr(|| src_file.read(&mut buffer))?;
         if bytes_read == 0 {
             break;
         }
-        dest_file.write_all(&buffer[..bytes_read])?;
+        if let Some(limit) = &options.bwlimit {
+            limit.throttle(bytes_read as u64, &options.abort);
+        }
+        let chunk = &buffer[..bytes_read];
+        let hole = make_holes && sparse::is_zeros(chunk);
+        if let Err(e) = sparse::write_chunk(&mut dest_file, chunk, hole) {
+            return Err(discard_if_full(destination, e));
+        }
+        replica_files.retain_mut(|(index, path, writer)| {
+            match sparse::write_chunk(writer, chunk, hole) {
+                Ok(()) => true,
+                Err(e) => {
+                    replicas.failed(*index, path, e);
+                    let _ = std::fs::remove_file(*path);
+                    false
+                }
+            }
+        });
+        stall::progress();
 
-        accumulated_bytes += bytes_read as u64;
+        // Progress counts every destination, dropped targets included.
+        let fan_out = replicas.destinations();
+        copied += bytes_read as u64;
+        if copied > file_size
+            && let Some(pb) = overall_pb
+        {
+            pb.inc_length((copied - file_size.max(copied - bytes_read as u64)) * fan_out);
+        }
+        accumulated_bytes += bytes_read as u64 * fan_out;
         if accumulated_bytes >= update_threshold {
             if let Some(pb) = overall_pb {
                 pb.inc(accumulated_bytes);
@@ -441,35 +1881,156 @@ fn copy_core(
         pb.inc(accumulated_bytes);
     }
 
-    dest_file.flush()?;
+    // A hole at the end is only there once the length says so.
+    let finish = |writer: &mut io::BufWriter<std::fs::File>| {
+        writer.flush()?;
+        if make_holes {
+            writer.get_ref().set_len(copied)?;
+        }
+        Ok(())
+    };
+    if let Err(e) = finish(&mut dest_file) {
+        return Err(discard_if_full(destination, e));
+    }
+
+    update_progress(overall_pb, counters, total_files, options);
 
-    update_progress(overall_pb, completed_files, total_files, options);
+    preserve_attrs()?;
 
-    if options.preserve != PreserveAttr::none() {
-        preserve::apply_preserve_attrs(source, destination, options.preserve)
-            .map_err(CopyError::from)?;
+    for (index, path, mut writer) in replica_files {
+        match finish(&mut writer)
+            .map_err(CopyError::from)
+            .and_then(|()| preserve_at(path))
+        {
+            Ok(()) => replicas.completed(index, copied),
+            Err(e) => replicas.failed(index, path, e),
+        }
     }
 
-    Ok(())
+    Ok(timed(
+        Mechanism::Buffered {
+            buffer: buffer_size,
+        },
+        copied,
+    ))
+}
+
+/// A file cut short by a full destination is removed rather than left
+/// behind half-written.
+fn discard_if_full(destination: &Path, error: io::Error) -> CopyError {
+    if full::is_disk_full(&error)
+        && std::fs::symlink_metadata(destination).is_ok_and(|m| m.is_file())
+    {
+        let _ = std::fs::remove_file(destination);
+    }
+    CopyError::Io(error)
+}
+
+/// Back up or remove an existing `destination` as the options ask before it
+/// is written.
+fn prepare_destination(
+    destination: &Path,
+    overall_pb: Option<&ProgressBar>,
+    options: &CopyOptions,
+) -> CopyResult<()> {
+    if let Some(backup_mode) = options.backup
+        && backup_mode != BackupMode::None
+        && std::fs::symlink_metadata(destination).is_ok()
+    {
+        let backup_path = back_up(destination, backup_mode, &options.backup_suffix)?;
+        if options.verbose {
+            output::verbose(overall_pb, destination, "~>", &backup_path);
+        }
+    }
+
+    clear_destination(destination, options)
+}
+
+/// Outcomes so far, shared by the workers and shown in the Detailed bar.
+#[derive(Debug, Default)]
+struct FileCounters {
+    completed: AtomicUsize,
+    skipped: AtomicUsize,
+    failed: AtomicUsize,
+    /// Bytes `--compare-write` found already in place.
+    identical: AtomicU64,
+    /// Preserved attributes the destination refused.
+    attributes_failed: AtomicUsize,
+    /// Files `--prefer-hardlink` linked rather than copied.
+    linked: AtomicUsize,
+    /// Destinations that appeared after planning, left alone by
+    /// `--no-clobber`.
+    untouched: AtomicUsize,
+    /// Files copied by each method, for --debug.
+    methods: MethodStats,
+    slowest: SlowestFiles,
+}
+
+/// Whether a file linked by `--prefer-hardlink` is left as its copy would
+/// be. Preserving the source's attributes onto its own inode changes
+/// nothing, but stamping times, setting modes, mapping owners, storing a checksum or
+/// giving back the attributes of the file it replaced would change the
+/// source too, so those files are copied.
+fn linking_keeps_source(options: &CopyOptions) -> bool {
+    let maps_owners = options.preserve.ownership && !options.overrides.owners.is_empty();
+    !(options.overrides.stamps()
+        || options.overrides.sets_mode()
+        || maps_owners
+        || options.store_checksum
+        || options.keep_dest_attrs)
 }
 
 fn update_progress(
     overall_pb: Option<&ProgressBar>,
-    completed_files: &AtomicUsize,
+    counters: &FileCounters,
+    total_files: usize,
+    options: &CopyOptions,
+) {
+    counters.completed.fetch_add(1, Ordering::Relaxed);
+    refresh_message(overall_pb, counters, total_files, options);
+}
+
+/// Count a failed file, switching the bar to its error style on the first.
+fn record_failure(
+    overall_pb: Option<&ProgressBar>,
+    counters: &FileCounters,
+    total_files: usize,
+    options: &CopyOptions,
+) {
+    let first = counters.failed.fetch_add(1, Ordering::Relaxed) == 0;
+    if first && let Some(pb) = overall_pb {
+        pb.set_style(options.progress_bar.style_for(true));
+    }
+    refresh_message(overall_pb, counters, total_files, options);
+}
+
+// The message is rebuilt when a file finishes, never on a plain redraw.
+fn refresh_message(
+    overall_pb: Option<&ProgressBar>,
+    counters: &FileCounters,
     total_files: usize,
     options: &CopyOptions,
 ) {
-    let completed = completed_files.fetch_add(1, Ordering::Relaxed) + 1;
     if let Some(pb) = overall_pb
         && matches!(options.progress_bar.style, ProgressBarStyle::Detailed)
     {
-        pb.set_message(format!("Copying: {}/{} files", completed, total_files));
+        pb.set_message(detailed_message(
+            counters.completed.load(Ordering::Relaxed),
+            total_files,
+            counters.skipped.load(Ordering::Relaxed),
+            counters.failed.load(Ordering::Relaxed),
+        ));
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cli::args::{
+        CollisionPolicy, CopyOrder, FollowSymlink, NotifyOn, OnFull, SparseMode, StallAction,
+        UpdateMode,
+    };
+    use crate::utility::filesystem::{FsKind, LinkSupport};
     use crate::utility::progress_bar::ProgressOptions;
     use std::fs;
     use std::sync::atomic::AtomicBool;
@@ -479,12 +2040,15 @@ mod tests {
             recursive: false,
             resume: false,
             force: false,
-            interactive: false,
+            interactive: Prompt::Never,
             preserve: PreserveAttr::none(),
             backup: None,
             symbolic_link: None,
             hard_link: false,
             follow_symlink: FollowSymlink::NoDereference,
+            symlink_fallback: LinkFallback::CopyTarget,
+            link_fallback: LinkFallback::CopyTarget,
+            link_support: None,
             attributes_only: false,
             remove_destination: false,
             reflink: None,
@@ -493,6 +2057,60 @@ mod tests {
             exclude_rules: None,
             progress_bar: ProgressOptions::default(),
             abort: Arc::new(AtomicBool::new(false)),
+            existing: false,
+            no_dereference_destination: false,
+            backup_suffix: "~".to_string(),
+            one_file_system: false,
+            verbose: false,
+            update: UpdateMode::All,
+            no_clobber: false,
+            keep_dest_attrs: false,
+            prefer_hardlink: false,
+            staging_dir: None,
+            notify_on: NotifyOn::Always,
+            notify_desktop: false,
+            notify_cmd: None,
+            sudo: None,
+            strict_preserve: false,
+            flatten: false,
+            fix_attrs: false,
+            max_memory: None,
+            max_files: None,
+            assume_yes: false,
+            rollback_on_error: false,
+            confine: None,
+            buffer_size: None,
+            engine: Engine::Auto,
+            debug: false,
+            offload_chunk_size: None,
+            bwlimit: None,
+            drop_cache: false,
+            background: false,
+            max_bytes: None,
+            copy_contents: false,
+            on_full: OnFull::Abort,
+            store_checksum: false,
+            also_to: Vec::new(),
+            files_from_root: None,
+            into_directory: false,
+            mkdir: false,
+            no_target_directory: false,
+            compare_write: false,
+            dry_run: false,
+            itemize: false,
+            preserve_sharing: false,
+            overrides: Default::default(),
+            interactive_dirs: None,
+            order: CopyOrder::Size,
+            on_collision: CollisionPolicy::Error,
+            skip_oversize: false,
+            stall_action: StallAction::Warn,
+            stall_timeout: None,
+            watch: false,
+            preallocate: true,
+            sparse: SparseMode::Auto,
+            noatime: false,
+            porcelain: false,
         }
     }
 
@@ -566,6 +2184,40 @@ mod tests {
         assert_eq!(content, "new content");
     }
 
+    #[test]
+    fn test_reflink_auto_overwrites_existing_destination() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+
+        fs::write(&source, b"new content").unwrap();
+        fs::write(&dest, b"old content that is longer").unwrap();
+
+        let mut options = default_copy_options();
+        options.reflink = Some(ReflinkMode::Auto);
+
+        copy(&source, &dest, &options).unwrap();
+
+        let content = fs::read_to_string(&dest).unwrap();
+        assert_eq!(content, "new content");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_copy_proc_file_reads_until_eof() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = Path::new("/proc/self/status");
+        let dest = temp_dir.path().join("status");
+        assert_eq!(fs::metadata(source).unwrap().len(), 0);
+
+        let options = default_copy_options();
+        copy(source, &dest, &options).unwrap();
+
+        let content = fs::read_to_string(&dest).unwrap();
+        assert!(content.starts_with("Name:"));
+        assert!(content.contains("\nPid:"));
+    }
+
     #[test]
     fn test_copy_preserves_timestamps() {
         let temp_dir = TempDir::new().unwrap();
@@ -591,6 +2243,306 @@ mod tests {
         assert!(diff.as_secs() < 1);
     }
 
+    #[test]
+    fn test_session_totals_span_calls() {
+        let temp_dir = TempDir::new().unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+        fs::create_dir(&dest_dir).unwrap();
+        let batch = |name: &str, files: &[(&str, &[u8])]| {
+            let dir = temp_dir.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            for (file, contents) in files {
+                fs::write(dir.join(file), contents).unwrap();
+            }
+            dir
+        };
+        let first = batch("first", &[("a", b"12345"), ("b", b"123")]);
+        let second = batch("second", &[("c", b"1234567")]);
+
+        let mut options = default_copy_options();
+        options.recursive = true;
+        let mut session = CopySession::new();
+        session.copy(&first, &dest_dir, &options).unwrap();
+        session
+            .copy_many(vec![second.join("c")], dest_dir.clone(), &options)
+            .unwrap();
+        let summary = session.finish(&options);
+
+        assert_eq!(summary.files_copied, 3);
+        assert_eq!(summary.bytes_copied, 15);
+        assert_eq!(summary.directories_created, 1);
+        assert!(dest_dir.join("first/b").exists() && dest_dir.join("c").exists());
+    }
+
+    #[test]
+    fn test_budget_spans_calls_and_reports_what_is_left() {
+        let temp_dir = TempDir::new().unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+        fs::create_dir(&dest_dir).unwrap();
+        let sources: Vec<PathBuf> = (0..4)
+            .map(|i| {
+                let path = temp_dir.path().join(format!("{}.txt", i));
+                fs::write(&path, "12345").unwrap();
+                path
+            })
+            .collect();
+
+        let mut options = default_copy_options();
+        options.max_bytes = Some(12);
+        let mut session = CopySession::new();
+        session
+            .copy_many(sources[..2].to_vec(), dest_dir.clone(), &options)
+            .unwrap();
+        session
+            .copy_many(sources[2..].to_vec(), dest_dir.clone(), &options)
+            .unwrap();
+        let summary = session.finish(&options);
+
+        assert_eq!(summary.files_copied, 2);
+        assert_eq!(summary.files_remaining, 2);
+        assert_eq!(summary.bytes_remaining, 10);
+        assert!(!dest_dir.join("2.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_session_keeps_files_linked_across_calls() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_a = temp_dir.path().join("a.txt");
+        let source_b = temp_dir.path().join("b.txt");
+        let dest_dir = temp_dir.path().join("dest");
+        fs::write(&source_a, b"linked").unwrap();
+        fs::hard_link(&source_a, &source_b).unwrap();
+        fs::create_dir(&dest_dir).unwrap();
+
+        let mut options = default_copy_options();
+        options.preserve.links = true;
+        let mut session = CopySession::new();
+        session.copy(&source_a, &dest_dir, &options).unwrap();
+        session.copy(&source_b, &dest_dir, &options).unwrap();
+
+        let inode = |name: &str| fs::metadata(dest_dir.join(name)).unwrap().ino();
+        assert_eq!(inode("a.txt"), inode("b.txt"));
+        assert_eq!(session.summary().files_copied, 2);
+    }
+
+    /// Options under which every file fails once the directories exist: the
+    /// forced offload engine can't keep to a bandwidth limit.
+    fn failing_copy_options() -> CopyOptions {
+        let mut options = default_copy_options();
+        options.recursive = true;
+        options.engine = crate::cli::args::Engine::Offload;
+        options.bwlimit = Some(Arc::new(crate::core::bwlimit::BandwidthLimit::new(1 << 20)));
+        options
+    }
+
+    #[test]
+    fn test_failed_copy_rolls_back_the_directories_it_created() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("project");
+        let dest_dir = temp_dir.path().join("dest");
+        fs::create_dir_all(source_dir.join("src/bin")).unwrap();
+        fs::write(source_dir.join("src/bin/main.rs"), b"fn main() {}").unwrap();
+        fs::create_dir(&dest_dir).unwrap();
+        fs::write(dest_dir.join("keep.txt"), b"keep").unwrap();
+
+        let mut session = CopySession::new();
+        assert!(
+            session
+                .copy(&source_dir, &dest_dir, &failing_copy_options())
+                .is_err()
+        );
+        let created = session.created_roots();
+        assert_eq!(created, vec![dest_dir.join("project")]);
+
+        assert_eq!(roll_back(&created), 0);
+        assert!(!dest_dir.join("project").exists());
+        assert_eq!(fs::read(dest_dir.join("keep.txt")).unwrap(), b"keep");
+    }
+
+    #[test]
+    fn test_rollback_keeps_directories_that_already_existed() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("project");
+        let dest_dir = temp_dir.path().join("dest");
+        fs::create_dir_all(source_dir.join("docs")).unwrap();
+        fs::write(source_dir.join("docs/guide.md"), b"guide").unwrap();
+        fs::write(source_dir.join("README"), b"readme").unwrap();
+        // An earlier copy left the top directory and a file in it.
+        fs::create_dir_all(dest_dir.join("project")).unwrap();
+        fs::write(dest_dir.join("project/old.txt"), b"old").unwrap();
+
+        let mut session = CopySession::new();
+        assert!(
+            session
+                .copy(&source_dir, &dest_dir, &failing_copy_options())
+                .is_err()
+        );
+        let created = session.created_roots();
+        assert_eq!(created, vec![dest_dir.join("project/docs")]);
+
+        roll_back(&created);
+        assert!(!dest_dir.join("project/docs").exists());
+        assert_eq!(fs::read(dest_dir.join("project/old.txt")).unwrap(), b"old");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_confined_copy_does_not_follow_a_swapped_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("tree");
+        let dest_dir = temp_dir.path().join("dest");
+        let outside = temp_dir.path().join("etc");
+        fs::create_dir_all(source_dir.join("conf")).unwrap();
+        fs::write(source_dir.join("conf/passwd"), b"attacker's choice").unwrap();
+        fs::write(source_dir.join("top.txt"), b"fine").unwrap();
+        fs::create_dir_all(dest_dir.join("tree")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        // What the destination looks like once someone has swapped
+        // `tree/conf` for a symlink after the directories were created:
+        // creating directories takes it for the one it made.
+        std::os::unix::fs::symlink(&outside, dest_dir.join("tree/conf")).unwrap();
+
+        let mut options = default_copy_options();
+        options.recursive = true;
+        options.confine = Some(dest_dir.clone());
+        let result = copy(&source_dir, &dest_dir, &options);
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        assert_eq!(fs::read(dest_dir.join("tree/top.txt")).unwrap(), b"fine");
+    }
+
+    /// A source tree, and a destination whose `tree/conf` someone has
+    /// swapped for a symlink to the returned directory, holding `passwd`.
+    #[cfg(unix)]
+    fn swapped_destination(temp_dir: &Path, dest_dir: &Path) -> (PathBuf, PathBuf) {
+        let source_dir = temp_dir.join("tree");
+        let outside = temp_dir.join("etc");
+        fs::create_dir_all(source_dir.join("conf")).unwrap();
+        fs::write(source_dir.join("conf/passwd"), b"attacker's choice").unwrap();
+        fs::create_dir_all(dest_dir.join("tree")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("passwd"), b"root:x:0:0:root:/").unwrap();
+        std::os::unix::fs::symlink(&outside, dest_dir.join("tree/conf")).unwrap();
+        (source_dir, outside)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_confined_clones_and_updates_do_not_follow_a_swapped_directory() {
+        for (reflink, compare_write) in [(Some(ReflinkMode::Always), false), (None, true)] {
+            let temp_dir = TempDir::new().unwrap();
+            let dest_dir = temp_dir.path().join("dest");
+            let (source_dir, outside) = swapped_destination(temp_dir.path(), &dest_dir);
+
+            let mut options = default_copy_options();
+            options.recursive = true;
+            options.reflink = reflink;
+            options.compare_write = compare_write;
+            options.confine = Some(dest_dir.clone());
+            let result = copy(&source_dir, &dest_dir, &options);
+
+            assert!(result.is_err());
+            assert_eq!(fs::read_dir(&outside).unwrap().count(), 1);
+            assert_eq!(
+                fs::read(outside.join("passwd")).unwrap(),
+                b"root:x:0:0:root:/"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_confined_also_to_does_not_follow_a_swapped_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+        let mirror = temp_dir.path().join("mirror");
+        let (source_dir, outside) = swapped_destination(temp_dir.path(), &mirror);
+        fs::create_dir(&dest_dir).unwrap();
+
+        let mut options = default_copy_options();
+        options.recursive = true;
+        options.also_to = vec![mirror.clone()];
+        options.confine = Some(dest_dir.clone());
+        let result = copy(&source_dir, &dest_dir, &options);
+
+        assert!(result.is_err());
+        assert_eq!(
+            fs::read(dest_dir.join("tree/conf/passwd")).unwrap(),
+            b"attacker's choice"
+        );
+        assert_eq!(
+            fs::read(outside.join("passwd")).unwrap(),
+            b"root:x:0:0:root:/"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_directories_get_their_mode_after_their_contents() {
+        use filetime::FileTime;
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("release");
+        let nested = source_dir.join("docs");
+        let dest_dir = temp_dir.path().join("dest");
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir(&dest_dir).unwrap();
+        fs::write(source_dir.join("VERSION"), b"1.0").unwrap();
+        fs::write(nested.join("README"), b"read me").unwrap();
+        let old = FileTime::from_unix_time(1_500_000_000, 0);
+        for path in [
+            source_dir.join("VERSION"),
+            nested.join("README"),
+            nested.clone(),
+            source_dir.clone(),
+        ] {
+            let mode = if path.is_dir() { 0o555 } else { 0o444 };
+            filetime::set_file_mtime(&path, old).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+
+        let mut options = default_copy_options();
+        options.recursive = true;
+        options.preserve = PreserveAttr::default();
+        let result = copy(&source_dir, &dest_dir, &options);
+
+        let copied = dest_dir.join("release");
+        let mode_and_mtime = |path: &Path| {
+            let metadata = fs::symlink_metadata(path).unwrap();
+            (
+                metadata.permissions().mode() & 0o777,
+                FileTime::from_last_modification_time(&metadata),
+            )
+        };
+        let found: Vec<_> = [
+            copied.clone(),
+            copied.join("docs"),
+            copied.join("VERSION"),
+            copied.join("docs/README"),
+        ]
+        .iter()
+        .map(|p| mode_and_mtime(p))
+        .collect();
+        for dir in [&source_dir, &nested, &copied, &copied.join("docs")] {
+            let _ = fs::set_permissions(dir, fs::Permissions::from_mode(0o755));
+        }
+
+        result.unwrap();
+        assert_eq!(
+            found,
+            vec![(0o555, old), (0o555, old), (0o444, old), (0o444, old)]
+        );
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("release/docs/README")).unwrap(),
+            "read me"
+        );
+    }
+
     #[test]
     fn test_multiple_copy() {
         let temp_dir = TempDir::new().unwrap();
@@ -611,6 +2563,16 @@ mod tests {
         assert!(dest_dir.join("source2.txt").exists());
     }
 
+    #[test]
+    fn test_parse_dir_answer_defaults_to_no() {
+        assert_eq!(parse_dir_answer("y"), DirAnswer::Yes);
+        assert_eq!(parse_dir_answer("yes"), DirAnswer::Yes);
+        assert_eq!(parse_dir_answer("a"), DirAnswer::All);
+        assert_eq!(parse_dir_answer("q"), DirAnswer::Quit);
+        assert_eq!(parse_dir_answer(""), DirAnswer::No);
+        assert_eq!(parse_dir_answer("maybe"), DirAnswer::No);
+    }
+
     #[test]
     fn test_copy_empty_file() {
         let temp_dir = TempDir::new().unwrap();
@@ -643,4 +2605,66 @@ mod tests {
         assert!(dest.exists());
         assert_eq!(fs::metadata(&dest).unwrap().len(), 70 * 1024 * 1024);
     }
+
+    fn oversize_plan(sizes: &[u64]) -> CopyPlan {
+        let mut plan = CopyPlan::new();
+        for (i, size) in sizes.iter().enumerate() {
+            plan.add_file(
+                PathBuf::from(format!("/src/video{}.mkv", i)),
+                PathBuf::from(format!("/sd/video{}.mkv", i)),
+                *size,
+            );
+        }
+        plan
+    }
+
+    fn options_for(kind: FsKind) -> CopyOptions {
+        CopyOptions {
+            link_support: Some(LinkSupport::for_kind(kind)),
+            ..default_copy_options()
+        }
+    }
+
+    #[test]
+    fn test_oversized_files_refuse_to_start_on_fat() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        let mut plan = oversize_plan(&[12 * GIB, GIB, 4 * GIB]);
+        let err = check_file_sizes(&mut plan, &options_for(FsKind::Fat))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("2 files are larger than 4.00 GiB"), "{}", err);
+        assert!(err.contains("'/src/video0.mkv' (12.00 GiB)"), "{}", err);
+        assert!(err.contains("'/src/video2.mkv'"), "{}", err);
+        assert!(!err.contains("video1"), "{}", err);
+        assert_eq!(plan.total_files, 3);
+
+        // Exactly 4 GiB - 1 byte still fits.
+        let mut plan = oversize_plan(&[4 * GIB - 1]);
+        check_file_sizes(&mut plan, &options_for(FsKind::Fat)).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_files_are_fine_elsewhere() {
+        let mut plan = oversize_plan(&[64 * 1024 * 1024 * 1024]);
+        for kind in [FsKind::Exfat, FsKind::Ntfs, FsKind::Ext4, FsKind::Unknown] {
+            check_file_sizes(&mut plan, &options_for(kind)).unwrap();
+        }
+        // Nothing probed, nothing assumed.
+        check_file_sizes(&mut plan, &default_copy_options()).unwrap();
+    }
+
+    #[test]
+    fn test_skip_oversize_leaves_out_only_large_files() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        let mut plan = oversize_plan(&[12 * GIB, GIB]);
+        let options = CopyOptions {
+            skip_oversize: true,
+            ..options_for(FsKind::Fat)
+        };
+        check_file_sizes(&mut plan, &options).unwrap();
+        assert_eq!(plan.files.len(), 1);
+        assert_eq!(plan.files[0].source, Path::new("/src/video1.mkv"));
+        assert_eq!((plan.total_files, plan.total_size), (1, GIB));
+        assert_eq!((plan.skipped_files, plan.skipped_size), (1, 12 * GIB));
+    }
 }
@@ -0,0 +1,448 @@
+use crate::cli::args::Engine;
+use indicatif::HumanBytes;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Files listed by the `--debug` report of the slowest ones.
+pub const SLOWEST_REPORTED: usize = 10;
+
+/// The methods this build can use on this platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// ODX and CopyFileEx within one SMB share.
+    pub server_copy: bool,
+    /// copy_file_range.
+    pub offload: bool,
+    /// Hole-preserving copies done separately from offload.
+    pub sparse: bool,
+    pub mmap: bool,
+}
+
+impl Capabilities {
+    pub fn probe() -> Self {
+        Self {
+            server_copy: cfg!(windows),
+            offload: cfg!(target_os = "linux"),
+            sparse: cfg!(any(windows, target_os = "linux", target_os = "macos")),
+            mmap: cfg!(unix),
+        }
+    }
+}
+
+/// One way of copying a file's data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Odx,
+    /// What a file is copied with on a share that won't do ODX; it never
+    /// comes up on its own.
+    CopyFileEx,
+    Sparse,
+    Offload,
+    Mmap,
+    Buffered,
+}
+
+impl Method {
+    const ALL: [Method; 6] = [
+        Method::Odx,
+        Method::CopyFileEx,
+        Method::Sparse,
+        Method::Offload,
+        Method::Mmap,
+        Method::Buffered,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Odx => "odx",
+            Method::CopyFileEx => "copyfileex",
+            Method::Sparse => "sparse",
+            Method::Offload => "offload",
+            Method::Mmap => "mmap",
+            Method::Buffered => "buffered",
+        }
+    }
+}
+
+/// What about one file rules methods out.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileContext {
+    pub size: u64,
+    /// Also written to `--also-to` targets, which only the buffered copy
+    /// does from a single read.
+    pub replicated: bool,
+    /// Under `--bwlimit`, which only the buffered copy can keep to.
+    pub throttled: bool,
+}
+
+/// The methods to try for a file, in order, or why the forced engine
+/// can't copy it.
+///
+/// With `auto` a file goes down the cascade of methods this system offers,
+/// falling back until one works. A forced engine is the only method tried,
+/// and a file it can't copy fails with the reason instead of quietly going
+/// another way, so benchmarks measure what they say they measure. Reflinks,
+/// `--compare-write` and `--preserve-sharing` are asked for on their own and
+/// still come first.
+pub fn methods(
+    engine: Engine,
+    capabilities: Capabilities,
+    file: FileContext,
+) -> Result<Vec<Method>, String> {
+    let single_pass = !file.replicated && !file.throttled;
+    match engine {
+        Engine::Auto => {
+            let mut methods = Vec::new();
+            // Only taken when both sides are on the same share.
+            if capabilities.server_copy && single_pass && file.size > 0 {
+                methods.push(Method::Odx);
+            }
+            if capabilities.sparse && single_pass && file.size > 0 {
+                methods.push(Method::Sparse);
+            }
+            // A size of zero may be a procfs or sysfs file whose contents
+            // only show up when read, which copy_file_range can't see.
+            if capabilities.offload && single_pass && file.size > 0 {
+                methods.push(Method::Offload);
+            }
+            methods.push(Method::Buffered);
+            Ok(methods)
+        }
+        Engine::Buffered => Ok(vec![Method::Buffered]),
+        Engine::Offload | Engine::Mmap => {
+            let (method, available) = match engine {
+                Engine::Offload => (Method::Offload, capabilities.offload),
+                _ => (Method::Mmap, capabilities.mmap),
+            };
+            if !available {
+                return Err(format!(
+                    "the {} engine is not available on this platform",
+                    method.name()
+                ));
+            }
+            if !single_pass {
+                return Err(format!(
+                    "the {} engine can't be used with --also-to or --bwlimit",
+                    method.name()
+                ));
+            }
+            Ok(vec![method])
+        }
+    }
+}
+
+/// How one file's data got to the destination, as `--debug` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mechanism {
+    /// Cloned, without copying.
+    Reflink,
+    /// Extents shared with a file copied before, with `--preserve-sharing`.
+    Shared,
+    /// Only what differed written, with `--compare-write`.
+    CompareWrite,
+    /// Read to its end, with `--copy-contents`.
+    Stream,
+    /// An engine method with nothing more to tell.
+    Method(Method),
+    /// copy_file_range, in this many calls.
+    Offload { calls: u64 },
+    /// Reads and writes through a buffer of this size.
+    Buffered { buffer: usize },
+}
+
+impl Mechanism {
+    /// Those that never reach the engine, in the order the tally lists
+    /// them, before the engine's methods.
+    const OUTSIDE_ENGINE: [Mechanism; 4] = [
+        Mechanism::Reflink,
+        Mechanism::Shared,
+        Mechanism::CompareWrite,
+        Mechanism::Stream,
+    ];
+
+    /// Where the tally counts it.
+    fn slot(self) -> usize {
+        let method = match self {
+            Mechanism::Method(method) => method,
+            Mechanism::Offload { .. } => Method::Offload,
+            Mechanism::Buffered { .. } => Method::Buffered,
+            other => {
+                return Self::OUTSIDE_ENGINE
+                    .iter()
+                    .position(|&m| m == other)
+                    .unwrap();
+            }
+        };
+        Self::OUTSIDE_ENGINE.len() + method as usize
+    }
+
+    /// The name of the path the file took, its details aside.
+    pub fn name(self) -> &'static str {
+        match self {
+            Mechanism::Reflink => "reflink",
+            Mechanism::Shared => "shared",
+            Mechanism::CompareWrite => "compare-write",
+            Mechanism::Stream => "stream",
+            Mechanism::Method(method) => method.name(),
+            Mechanism::Offload { .. } => Method::Offload.name(),
+            Mechanism::Buffered { .. } => Method::Buffered.name(),
+        }
+    }
+}
+
+/// `reflink`, `copy_file_range (3 calls)`, `read/write (512 KiB buffer)`.
+impl std::fmt::Display for Mechanism {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Mechanism::Offload { calls } => write!(
+                f,
+                "copy_file_range ({} call{})",
+                calls,
+                if calls == 1 { "" } else { "s" }
+            ),
+            // Buffer sizes are round, and read better without decimals.
+            Mechanism::Buffered { buffer } if buffer % (1 << 20) == 0 => {
+                write!(f, "read/write ({} MiB buffer)", buffer >> 20)
+            }
+            Mechanism::Buffered { buffer } if buffer % 1024 == 0 => {
+                write!(f, "read/write ({} KiB buffer)", buffer >> 10)
+            }
+            Mechanism::Buffered { buffer } => write!(f, "read/write ({} B buffer)", buffer),
+            other => f.write_str(other.name()),
+        }
+    }
+}
+
+/// How many files went each way, for `--debug`.
+#[derive(Debug, Default)]
+pub struct MethodStats([AtomicUsize; Mechanism::OUTSIDE_ENGINE.len() + Method::ALL.len()]);
+
+impl MethodStats {
+    pub fn record(&self, mechanism: Mechanism) {
+        self.0[mechanism.slot()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// "reflink 4, offload 12, buffered 3", leaving out ways no file went.
+    pub fn describe(&self) -> String {
+        let names = (Mechanism::OUTSIDE_ENGINE.iter().map(|m| m.name()))
+            .chain(Method::ALL.iter().map(|m| m.name()));
+        let used: Vec<String> = names
+            .zip(&self.0)
+            .filter_map(|(name, count)| {
+                let count = count.load(Ordering::Relaxed);
+                (count > 0).then(|| format!("{} {}", name, count))
+            })
+            .collect();
+        if used.is_empty() {
+            "none".to_string()
+        } else {
+            used.join(", ")
+        }
+    }
+}
+
+/// How long one file took to copy and how.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileTiming {
+    pub source: PathBuf,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// A [`Mechanism`] name.
+    pub method: &'static str,
+}
+
+impl FileTiming {
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+/// The slowest files copied so far, which point at a failing disk or a slow
+/// mount.
+#[derive(Debug)]
+pub struct SlowestFiles {
+    keep: usize,
+    files: Mutex<Vec<FileTiming>>,
+}
+
+impl Default for SlowestFiles {
+    fn default() -> Self {
+        Self::new(SLOWEST_REPORTED)
+    }
+}
+
+impl SlowestFiles {
+    pub fn new(keep: usize) -> Self {
+        Self {
+            keep,
+            files: Mutex::new(Vec::with_capacity(keep + 1)),
+        }
+    }
+
+    /// Count in a copied file; the path is only kept if it is among the
+    /// slowest.
+    pub fn record(&self, source: &Path, bytes: u64, elapsed: Duration, method: &'static str) {
+        let mut files = self.files.lock().unwrap();
+        if files.len() == self.keep && files.last().is_none_or(|f| f.elapsed >= elapsed) {
+            return;
+        }
+        let at = files.partition_point(|f| f.elapsed >= elapsed);
+        files.insert(
+            at,
+            FileTiming {
+                source: source.to_path_buf(),
+                bytes,
+                elapsed,
+                method,
+            },
+        );
+        files.truncate(self.keep);
+    }
+
+    /// Slowest first.
+    pub fn slowest(&self) -> Vec<FileTiming> {
+        self.files.lock().unwrap().clone()
+    }
+
+    /// One line per file, e.g. `2.41s  3.20 MiB/s  7.71 MiB  offload  a/b.bin`.
+    pub fn describe(&self) -> Vec<String> {
+        self.slowest()
+            .iter()
+            .map(|f| {
+                format!(
+                    "{:.2}s  {}/s  {}  {}  {}",
+                    f.elapsed.as_secs_f64(),
+                    HumanBytes(f.bytes_per_second() as u64),
+                    HumanBytes(f.bytes),
+                    f.method,
+                    f.source.display()
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINUX: Capabilities = Capabilities {
+        server_copy: false,
+        offload: true,
+        sparse: true,
+        mmap: true,
+    };
+    const WINDOWS: Capabilities = Capabilities {
+        server_copy: true,
+        offload: false,
+        sparse: true,
+        mmap: false,
+    };
+
+    fn file(size: u64) -> FileContext {
+        FileContext {
+            size,
+            ..FileContext::default()
+        }
+    }
+
+    #[test]
+    fn test_auto_cascades_to_buffered() {
+        assert_eq!(
+            methods(Engine::Auto, LINUX, file(10)),
+            Ok(vec![Method::Sparse, Method::Offload, Method::Buffered])
+        );
+        assert_eq!(
+            methods(Engine::Auto, WINDOWS, file(10)),
+            Ok(vec![Method::Odx, Method::Sparse, Method::Buffered])
+        );
+        // procfs files and throttled copies are read.
+        assert_eq!(
+            methods(Engine::Auto, LINUX, file(0)),
+            Ok(vec![Method::Buffered])
+        );
+        let throttled = FileContext {
+            throttled: true,
+            ..file(10)
+        };
+        assert_eq!(
+            methods(Engine::Auto, LINUX, throttled),
+            Ok(vec![Method::Buffered])
+        );
+    }
+
+    #[test]
+    fn test_forced_engine_is_the_only_method() {
+        assert_eq!(
+            methods(Engine::Offload, LINUX, file(0)),
+            Ok(vec![Method::Offload])
+        );
+        assert_eq!(
+            methods(Engine::Mmap, LINUX, file(10)),
+            Ok(vec![Method::Mmap])
+        );
+        assert_eq!(
+            methods(Engine::Buffered, WINDOWS, file(10)),
+            Ok(vec![Method::Buffered])
+        );
+    }
+
+    #[test]
+    fn test_forced_engine_errors_instead_of_falling_back() {
+        assert_eq!(
+            methods(Engine::Offload, WINDOWS, file(10)),
+            Err("the offload engine is not available on this platform".to_string())
+        );
+        let replicated = FileContext {
+            replicated: true,
+            ..file(10)
+        };
+        assert!(methods(Engine::Mmap, LINUX, replicated).is_err());
+    }
+
+    #[test]
+    fn test_stats_describe_the_methods_used() {
+        let stats = MethodStats::default();
+        assert_eq!(stats.describe(), "none");
+        stats.record(Mechanism::Buffered { buffer: 65536 });
+        stats.record(Mechanism::Offload { calls: 3 });
+        stats.record(Mechanism::Method(Method::Offload));
+        stats.record(Mechanism::Reflink);
+        assert_eq!(stats.describe(), "reflink 1, offload 2, buffered 1");
+    }
+
+    #[test]
+    fn test_mechanisms_say_how_the_data_was_copied() {
+        assert_eq!(Mechanism::Reflink.to_string(), "reflink");
+        assert_eq!(
+            Mechanism::Offload { calls: 3 }.to_string(),
+            "copy_file_range (3 calls)"
+        );
+        assert_eq!(
+            Mechanism::Buffered { buffer: 512 * 1024 }.to_string(),
+            "read/write (512 KiB buffer)"
+        );
+        assert_eq!(Mechanism::Method(Method::Mmap).to_string(), "mmap");
+    }
+
+    #[test]
+    fn test_only_the_slowest_files_are_kept() {
+        let slowest = SlowestFiles::new(2);
+        let ms = Duration::from_millis;
+        slowest.record(Path::new("a"), 100, ms(30), "offload");
+        slowest.record(Path::new("b"), 100, ms(10), "offload");
+        slowest.record(Path::new("c"), 2000, ms(1000), "buffered");
+        slowest.record(Path::new("d"), 100, ms(5), "mmap");
+
+        let kept = slowest.slowest();
+        let names: Vec<_> = kept.iter().map(|f| f.source.to_str().unwrap()).collect();
+        assert_eq!(names, ["c", "a"]);
+        assert_eq!(kept[0].bytes_per_second(), 2000.0);
+        assert_eq!(
+            slowest.describe()[0],
+            "1.00s  1.95 KiB/s  1.95 KiB  buffered  c"
+        );
+    }
+}
@@ -1,19 +1,109 @@
-use crate::cli::args::CopyOptions;
+use crate::cli::args::{CopyOptions, Engine, SparseMode};
+use crate::core::engine::Mechanism;
+use crate::core::{full, stall};
 use crate::error::{CopyError, CopyResult};
-use indicatif::ProgressBar;
+use crate::utility::helper::{open_destination, open_source};
+use crate::utility::output;
+use indicatif::{HumanBytes, ProgressBar};
+use nix::errno::Errno;
 use nix::fcntl::copy_file_range;
 use std::io;
 use std::path::Path;
 use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
+/// Smallest chunk asked of copy_file_range.
+const MIN_CHUNK: u64 = 64 * 1024;
+/// Chunk a large file starts with.
+const START_CHUNK: u64 = 4 * 1024 * 1024;
+/// Largest chunk the adaptation grows to; high-latency network
+/// filesystems need chunks this big to keep round trips down.
+const MAX_CHUNK: u64 = 256 * 1024 * 1024;
+/// Progress updates aimed for while a file is small enough that the
+/// starting chunk would cover it in a few steps.
+const TARGET_UPDATES: u64 = 128;
+/// A full chunk copied faster than this makes the next one twice as big.
+const GROW_BELOW: Duration = Duration::from_millis(200);
+
+/// Size of the chunks asked of copy_file_range for one file.
+///
+/// A fixed `--offload-chunk-size` is used as given. Otherwise the chunk
+/// starts small enough to give the progress bar steps to show, doubles
+/// while whole chunks come back quickly, and halves when a call returns
+/// less than asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkSizer {
+    size: u64,
+    adaptive: bool,
+}
+
+impl ChunkSizer {
+    pub fn new(file_size: u64, fixed: Option<u64>) -> Self {
+        match fixed {
+            Some(size) => Self {
+                size: size.max(1),
+                adaptive: false,
+            },
+            None => Self {
+                size: (file_size / TARGET_UPDATES).clamp(MIN_CHUNK, START_CHUNK),
+                adaptive: true,
+            },
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Adjust after a call asked for `size()` bytes and got `copied` in
+    /// `elapsed`. Returns whether the size changed.
+    pub fn record(&mut self, copied: u64, elapsed: Duration) -> bool {
+        if !self.adaptive {
+            return false;
+        }
+        let before = self.size;
+        if copied < self.size {
+            self.size = (self.size / 2).max(MIN_CHUNK);
+        } else if elapsed < GROW_BELOW {
+            self.size = (self.size * 2).min(MAX_CHUNK);
+        }
+        self.size != before
+    }
+}
+
+/// Copy with copy_file_range until EOF.
+///
+/// Returns the calls it took and the bytes copied, or `None` when
+/// copy_file_range can't be used and the caller should fall back to reading
+/// and writing. With
+/// `--engine=offload` there is no falling back, and that is an error.
+///
+/// It is tried whatever devices the two files are on. Between NFS 4.2
+/// mounts the server does the copy, even across exports, and other
+/// cross-filesystem pairs just fail the first call with EXDEV.
 pub fn fast_copy(
     source: &Path,
     destination: &Path,
     file_size: u64,
     overall_pb: Option<&ProgressBar>,
     options: &CopyOptions,
-) -> CopyResult<bool> {
-    let src_file = std::fs::File::open(source).map_err(|e| CopyError::CopyFailed {
+) -> CopyResult<Option<(Mechanism, u64)>> {
+    let unusable = |reason: String| -> CopyResult<Option<(Mechanism, u64)>> {
+        if options.engine != Engine::Offload {
+            return Ok(None);
+        }
+        Err(CopyError::CopyFailed {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            reason: format!("offload engine: {}", reason),
+        })
+    };
+    // Zeros can't be turned into holes without reading them. Checked before
+    // the destination is touched, which the fallback may still need.
+    if options.sparse == SparseMode::Always {
+        return unusable("--sparse=always needs the data read to find zeros".to_string());
+    }
+    let src_file = open_source(source, options.noatime).map_err(|e| CopyError::CopyFailed {
         source: source.to_path_buf(),
         destination: destination.to_path_buf(),
         reason: format!("Failed to open source file: {}", e),
@@ -29,37 +119,39 @@ pub fn fast_copy(
             })?;
         }
     }
-    let dest_file = match std::fs::File::create(destination) {
-        Ok(file) => file,
-        Err(_e) if options.force => {
-            let _ = std::fs::remove_file(destination).map_err(|e| CopyError::CopyFailed {
-                source: source.to_path_buf(),
-                destination: destination.to_path_buf(),
-                reason: format!("Failed to remove destination: {}", e),
-            });
-            std::fs::File::create(destination).map_err(|e| CopyError::CopyFailed {
-                source: source.to_path_buf(),
-                destination: destination.to_path_buf(),
-                reason: format!("Failed to create destination: {}", e),
-            })?
-        }
-        Err(e) => return Err(CopyError::from(e)),
-    };
-    const TARGET_UPDATES: u64 = 128;
-    const MIN_CHUNK: usize = 4 * 1024 * 1024;
-    let chunk_size = std::cmp::max(MIN_CHUNK, (file_size / TARGET_UPDATES) as usize);
+    let dest_file = open_destination(source, destination, options)?;
+    let mut chunk = ChunkSizer::new(file_size, options.offload_chunk_size);
+    if options.debug {
+        output::debug_above(
+            overall_pb,
+            format!(
+                "copy_file_range '{}': {} chunks to start",
+                source.display(),
+                HumanBytes(chunk.size())
+            ),
+        );
+    }
     let mut total_copied = 0u64;
+    let mut calls = 0u64;
     loop {
+        full::hold(&options.abort);
+        if stall::cancelled() {
+            // The buffered path cleans up and reports the skip.
+            return Err(CopyError::Io(stall::stalled_error()));
+        }
         if options.abort.load(Ordering::Relaxed) {
             drop(dest_file); // Close file
             if let Err(e) = std::fs::remove_file(destination) {
-                eprintln!(
+                output::status(format!(
                     "Could not remove incomplete file {}: {}",
                     destination.display(),
                     e
-                );
+                ));
             } else {
-                eprintln!("Cleaned up incomplete file: {}", destination.display());
+                output::status(format!(
+                    "Cleaned up incomplete file: {}",
+                    destination.display()
+                ));
             }
             return Err(CopyError::Io(io::Error::new(
                 io::ErrorKind::Interrupted,
@@ -67,22 +159,82 @@ pub fn fast_copy(
             )));
         }
 
-        let to_copy = std::cmp::min(chunk_size, (file_size - total_copied) as usize);
-        if to_copy == 0 {
-            break;
-        }
-        match copy_file_range(&src_file, None, &dest_file, None, to_copy) {
+        // Copy until EOF rather than up to the planned size, in case the
+        // file grew since planning.
+        let started = Instant::now();
+        let requested = chunk.size() as usize;
+        calls += 1;
+        match copy_file_range(&src_file, None, &dest_file, None, requested) {
+            // Some virtual filesystems report nothing to copy_file_range even
+            // though a read would return data.
+            Ok(0) if total_copied == 0 && file_size > 0 => {
+                return unusable("copy_file_range sees no data in the source".to_string());
+            }
             Ok(0) => break,
             Ok(copied) => {
+                // The last, short chunk of a file says nothing about the
+                // filesystem.
+                let at_end = total_copied + copied as u64 >= file_size;
+                if !at_end && chunk.record(copied as u64, started.elapsed()) && options.debug {
+                    output::debug_above(
+                        overall_pb,
+                        format!(
+                            "copy_file_range '{}': {} chunks after {} in {:?}",
+                            source.display(),
+                            HumanBytes(chunk.size()),
+                            HumanBytes(copied as u64),
+                            started.elapsed()
+                        ),
+                    );
+                }
                 total_copied += copied as u64;
+                stall::progress();
                 if let Some(pb) = overall_pb {
+                    if total_copied > file_size {
+                        pb.inc_length(total_copied - file_size.max(total_copied - copied as u64));
+                    }
                     pb.inc(copied as u64);
                 }
             }
-            Err(_) => {
-                return Ok(false);
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                return unusable(format!("copy_file_range failed: {}", e));
             }
         }
     }
-    Ok(true)
+    Ok(Some((Mechanism::Offload { calls }, total_copied)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_small_files_start_with_small_chunks() {
+        assert_eq!(ChunkSizer::new(MB, None).size(), MIN_CHUNK);
+        assert_eq!(ChunkSizer::new(64 * MB, None).size(), 512 * 1024);
+        assert_eq!(ChunkSizer::new(10 * 1024 * MB, None).size(), START_CHUNK);
+    }
+
+    #[test]
+    fn test_chunks_grow_while_fast_and_shrink_on_short_returns() {
+        let mut chunk = ChunkSizer::new(10 * 1024 * MB, None);
+        let fast = Duration::from_millis(5);
+        while chunk.record(chunk.size(), fast) {}
+        assert_eq!(chunk.size(), MAX_CHUNK);
+
+        // A slow full chunk keeps the size; a short one halves it.
+        assert!(!chunk.record(chunk.size(), Duration::from_secs(2)));
+        assert!(chunk.record(MB, fast));
+        assert_eq!(chunk.size(), MAX_CHUNK / 2);
+    }
+
+    #[test]
+    fn test_fixed_chunk_size_is_kept() {
+        let mut chunk = ChunkSizer::new(MB, Some(128 * MB));
+        assert!(!chunk.record(MB, Duration::ZERO));
+        assert_eq!(chunk.size(), 128 * MB);
+    }
 }
@@ -0,0 +1,273 @@
+use crate::cli::args::OnFull;
+use crate::error::{CopyError, CopyResult};
+use crate::utility::filesystem::available_space;
+use crate::utility::output;
+use indicatif::{HumanBytes, ProgressBar};
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+
+/// How often a paused copy looks at the free space.
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+const TICK: Duration = Duration::from_millis(100);
+
+/// Set while a full destination has every worker waiting.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// Set when Enter is pressed during a pause.
+static RESUME: AtomicBool = AtomicBool::new(false);
+
+/// How the workers of one copy handle a destination that fills up, for
+/// `--on-full`. A worker whose write fails for lack of space removes its
+/// partial file and hands the error to [`DiskFull::on_full`]:
+///
+/// - `abort` stops workers from starting further files; the copy ends with
+///   one line saying what was left out.
+/// - `skip` fails that file and, from then on, every file larger than the
+///   space left, while the ones that still fit keep going.
+/// - `pause` stops all writes: the copy loops wait in [`hold`] while the
+///   first worker to hit the full disk polls the free space, and everyone
+///   resumes once the failed file fits again or Enter is pressed. The failed
+///   file is then copied again.
+pub struct DiskFull {
+    action: OnFull,
+    /// Planned bytes not copied yet.
+    remaining: AtomicU64,
+    /// A full destination was hit; `abort` and `skip` act on it.
+    hit: AtomicBool,
+    left_out: AtomicUsize,
+    listen: bool,
+    progress_bar: Option<Arc<ProgressBar>>,
+}
+
+impl DiskFull {
+    /// `listen` lets Enter end a pause; leave it off when stdin is taken.
+    pub fn new(
+        action: OnFull,
+        total_size: u64,
+        listen: bool,
+        progress_bar: Option<Arc<ProgressBar>>,
+    ) -> Self {
+        Self {
+            action,
+            remaining: AtomicU64::new(total_size),
+            hit: AtomicBool::new(false),
+            left_out: AtomicUsize::new(0),
+            listen,
+            progress_bar,
+        }
+    }
+
+    /// Count a planned file as copied.
+    pub fn completed(&self, size: u64) {
+        let _ = self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| {
+                Some(r.saturating_sub(size))
+            });
+    }
+
+    /// Check before starting a file of `size` bytes at `destination`.
+    pub fn before_file(&self, destination: &Path, size: u64, abort: &AtomicBool) -> CopyResult<()> {
+        match self.action {
+            OnFull::Pause => hold(abort),
+            _ if !self.hit.load(Ordering::Relaxed) => {}
+            OnFull::Abort => {
+                self.left_out.fetch_add(1, Ordering::Relaxed);
+                return Err(full_error("not started, the destination is full"));
+            }
+            OnFull::Skip => {
+                let dir = destination.parent().unwrap_or(Path::new("."));
+                if available_space(dir).is_ok_and(|free| free < size) {
+                    self.left_out.fetch_add(1, Ordering::Relaxed);
+                    return Err(full_error("does not fit in the space left"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deal with `error`, the destination filling up while `destination`
+    /// was written. The partial file is already gone.
+    ///
+    /// `Ok` means the file should be copied again.
+    pub fn on_full(
+        &self,
+        destination: &Path,
+        size: u64,
+        error: CopyError,
+        abort: &AtomicBool,
+    ) -> CopyResult<()> {
+        if self.action != OnFull::Pause {
+            self.hit.store(true, Ordering::Relaxed);
+            self.left_out.fetch_add(1, Ordering::Relaxed);
+            return Err(error);
+        }
+        if PAUSED
+            .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            // Another worker is already waiting for space.
+            hold(abort);
+            return check_abort(abort);
+        }
+        let result = self.wait_for_space(destination, size, abort);
+        PAUSED.store(false, Ordering::Relaxed);
+        result
+    }
+
+    fn wait_for_space(&self, destination: &Path, size: u64, abort: &AtomicBool) -> CopyResult<()> {
+        let dir = destination.parent().unwrap_or(Path::new("."));
+        let free =
+            available_space(dir).map_or("unknown".to_string(), |f| HumanBytes(f).to_string());
+        let mut message = format!(
+            "destination is full; {} still to copy, {} free. Paused until space is freed",
+            HumanBytes(self.remaining.load(Ordering::Relaxed)),
+            free
+        );
+        if self.listen {
+            listen_for_enter();
+            message.push_str(" (or press Enter to retry now)");
+        }
+        self.say(&message);
+
+        RESUME.store(false, Ordering::Relaxed);
+        let mut last_poll = Instant::now();
+        loop {
+            check_abort(abort)?;
+            if RESUME.swap(false, Ordering::Relaxed) {
+                break;
+            }
+            if last_poll.elapsed() >= POLL_INTERVAL {
+                last_poll = Instant::now();
+                if available_space(dir).is_ok_and(|free| free >= size.max(1)) {
+                    break;
+                }
+            }
+            std::thread::sleep(TICK);
+        }
+        self.say("resuming the copy");
+        Ok(())
+    }
+
+    fn say(&self, message: &str) {
+        match &self.progress_bar {
+            Some(pb) => pb.println(format!("Warning: {}", message)),
+            None => output::warning(message),
+        }
+    }
+
+    /// The single line summing up the files a full destination kept out,
+    /// if there were any.
+    pub fn summary(&self) -> Option<String> {
+        let left_out = self.left_out.load(Ordering::Relaxed);
+        if left_out == 0 {
+            return None;
+        }
+        let remaining = HumanBytes(self.remaining.load(Ordering::Relaxed));
+        Some(match self.action {
+            OnFull::Skip => format!(
+                "Destination full: skipped {} file(s) that did not fit, {} not copied",
+                left_out, remaining
+            ),
+            _ => format!(
+                "Destination full: stopped with {} file(s) not copied, {} still to copy",
+                left_out, remaining
+            ),
+        })
+    }
+}
+
+/// Wait while a full destination has the copy paused, or until `abort` is
+/// set. Copy loops call this before each write.
+pub fn hold(abort: &AtomicBool) {
+    while PAUSED.load(Ordering::Relaxed) && !abort.load(Ordering::Relaxed) {
+        std::thread::sleep(TICK);
+    }
+}
+
+/// Whether `err` or anything it wraps says the destination is out of space.
+pub fn is_disk_full(err: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if let Some(io_err) = e.downcast_ref::<io::Error>()
+            && matches!(
+                io_err.kind(),
+                io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+            )
+        {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+fn full_error(reason: &str) -> CopyError {
+    CopyError::Io(io::Error::new(io::ErrorKind::StorageFull, reason))
+}
+
+fn check_abort(abort: &AtomicBool) -> CopyResult<()> {
+    if abort.load(Ordering::Relaxed) {
+        return Err(CopyError::Io(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Operation aborted by user",
+        )));
+    }
+    Ok(())
+}
+
+/// Read lines from stdin for the rest of the run; each one ends a pause.
+fn listen_for_enter() {
+    static LISTENER: Once = Once::new();
+    LISTENER.call_once(|| {
+        std::thread::spawn(|| {
+            let mut line = String::new();
+            while io::stdin().read_line(&mut line).is_ok_and(|n| n > 0) {
+                RESUME.store(true, Ordering::Relaxed);
+                line.clear();
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_full_is_found_through_wrapping() {
+        let err = CopyError::OperationFailed {
+            operation: "copy".to_string(),
+            path: "/dest/file".into(),
+            error: io::Error::from(io::ErrorKind::StorageFull),
+        };
+        assert!(is_disk_full(&err));
+        assert!(!is_disk_full(&CopyError::Io(io::Error::from(
+            io::ErrorKind::PermissionDenied
+        ))));
+    }
+
+    #[test]
+    fn test_abort_stops_starting_files_after_the_first_full_error() {
+        let full = DiskFull::new(OnFull::Abort, 300, false, None);
+        let abort = AtomicBool::new(false);
+        let dest = Path::new("/dest/a");
+
+        assert!(full.before_file(dest, 100, &abort).is_ok());
+        full.completed(100);
+        let err = full
+            .on_full(dest, 100, full_error("No space left on device"), &abort)
+            .unwrap_err();
+        assert!(is_disk_full(&err));
+        let err = full.before_file(dest, 100, &abort).unwrap_err();
+        assert!(is_disk_full(&err));
+
+        assert_eq!(
+            full.summary().unwrap(),
+            "Destination full: stopped with 2 file(s) not copied, 200 B still to copy"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use crate::cli::args::CopyOptions;
+use crate::core::{full, stall};
+use crate::error::{CopyError, CopyResult};
+use crate::utility::helper::{open_destination, open_source};
+use indicatif::ProgressBar;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+/// Bytes written between progress updates and looks at the abort flag.
+const CHUNK: usize = 8 * 1024 * 1024;
+
+/// A read-only mapping, unmapped on drop.
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(file: &std::fs::File, len: usize) -> io::Result<Self> {
+        // SAFETY: a fresh private read-only mapping of an open descriptor;
+        // the kernel picks the address.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: advice on the mapping just made; failure is harmless.
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Self { ptr, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` readable bytes until dropped.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: unmaps exactly what `new` mapped.
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Copy `source` to `destination` by writing straight from a map of the
+/// source, as long as it was when opened, for `--engine=mmap`. Returns the
+/// bytes copied.
+///
+/// Like any mmap reader, this dies with SIGBUS if another process
+/// truncates the source mid-copy, which is why it is never chosen by
+/// `auto`.
+pub fn mmap_copy(
+    source: &Path,
+    destination: &Path,
+    overall_pb: Option<&ProgressBar>,
+    options: &CopyOptions,
+) -> CopyResult<u64> {
+    let src_file = open_source(source, options.noatime)?;
+    let len = src_file.metadata()?.len() as usize;
+    let mut dest_file = open_destination(source, destination, options)?;
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let mapping = Mapping::new(&src_file, len).map_err(|e| CopyError::CopyFailed {
+        source: source.to_path_buf(),
+        destination: destination.to_path_buf(),
+        reason: format!("mmap engine: cannot map the source: {}", e),
+    })?;
+    for chunk in mapping.bytes().chunks(CHUNK) {
+        full::hold(&options.abort);
+        if stall::cancelled() {
+            return Err(CopyError::Io(stall::stalled_error()));
+        }
+        if options.abort.load(Ordering::Relaxed) {
+            return Err(CopyError::Io(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Operation aborted by user",
+            )));
+        }
+        dest_file.write_all(chunk)?;
+        stall::progress();
+        if let Some(pb) = overall_pb {
+            pb.inc(chunk.len() as u64);
+        }
+    }
+    Ok(len as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_mmap_copy_copies_every_byte() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("data.bin");
+        let destination = temp_dir.path().join("copy.bin");
+        let data: Vec<u8> = (0..CHUNK + 4321).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+
+        let copied = mmap_copy(&source, &destination, None, &CopyOptions::none()).unwrap();
+
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+    }
+}
@@ -1,2 +1,18 @@
+pub mod budget;
+pub mod bwlimit;
+pub mod compare_write;
 pub mod copy;
+pub mod engine;
 pub mod fast_copy;
+pub mod full;
+#[cfg(unix)]
+pub mod mmap;
+#[cfg(windows)]
+pub mod odx;
+pub mod reflink;
+pub mod replicate;
+pub mod sharing;
+pub mod sparse;
+pub mod stall;
+pub mod stream;
+pub mod watch;
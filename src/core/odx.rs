@@ -0,0 +1,275 @@
+use crate::cli::args::CopyOptions;
+use crate::core::engine::Method;
+use crate::error::{CopyError, CopyResult};
+use crate::utility::helper::{create_destination, open_source};
+use crate::utility::output;
+use indicatif::ProgressBar;
+use std::fs::File;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use windows_sys::Win32::Storage::FileSystem::{CopyFileExW, GetDriveTypeW, GetVolumePathNameW};
+use windows_sys::Win32::System::IO::DeviceIoControl;
+
+const DRIVE_REMOTE: u32 = 4;
+const FSCTL_OFFLOAD_READ: u32 = 0x0009_4264;
+const FSCTL_OFFLOAD_WRITE: u32 = 0x0009_8268;
+const OFFLOAD_READ_FLAG_ALL_ZERO_BEYOND_CURRENT_RANGE: u32 = 1;
+const TOKEN_SIZE: usize = 512;
+
+/// Offsets and lengths must be whole logical sectors; 4 KiB is a multiple
+/// of every sector size in use.
+const ALIGNMENT: u64 = 4096;
+/// Most asked of one token, so an interrupt is noticed between them.
+const CHUNK: u64 = 256 * 1024 * 1024;
+
+// As laid out in winioctl.h.
+#[repr(C)]
+struct OffloadReadInput {
+    size: u32,
+    flags: u32,
+    token_time_to_live: u32,
+    reserved: u32,
+    file_offset: u64,
+    copy_length: u64,
+}
+
+#[repr(C)]
+struct OffloadReadOutput {
+    size: u32,
+    flags: u32,
+    transfer_length: u64,
+    token: [u8; TOKEN_SIZE],
+}
+
+#[repr(C)]
+struct OffloadWriteInput {
+    size: u32,
+    flags: u32,
+    file_offset: u64,
+    copy_length: u64,
+    transfer_offset: u64,
+    token: [u8; TOKEN_SIZE],
+}
+
+#[repr(C)]
+struct OffloadWriteOutput {
+    size: u32,
+    flags: u32,
+    length_written: u64,
+}
+
+/// Have the server copy `source` to `destination` if both are on the same
+/// share, so the data needn't cross the network at all.
+///
+/// Offloaded data transfer (ODX) asks the server for a token standing for a
+/// range of the source (`FSCTL_OFFLOAD_READ`) and hands it back to write
+/// that range of the destination (`FSCTL_OFFLOAD_WRITE`), so a copy bound by
+/// 10GbE becomes a storage operation on the server. Servers that don't offer
+/// ODX fail the first request, and the file goes to CopyFileEx instead,
+/// which on SMB still has the server copy chunks itself. Linux needs nothing
+/// like this: copy_file_range already lets NFS 4.2 copy on the server, see
+/// [`fast_copy`](crate::core::fast_copy).
+///
+/// Returns the method that did it, [`Method::Odx`] or
+/// [`Method::CopyFileEx`], or `None` when neither could and the caller
+/// should copy the data itself. Only an interrupt is an error.
+pub fn server_copy(
+    source: &Path,
+    destination: &Path,
+    file_size: u64,
+    overall_pb: Option<&ProgressBar>,
+    options: &CopyOptions,
+) -> CopyResult<Option<Method>> {
+    if !same_remote_volume(source, destination) {
+        return Ok(None);
+    }
+    let method = match offload(source, destination, file_size, options) {
+        Ok(()) => Method::Odx,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+            let _ = std::fs::remove_file(destination);
+            return Err(CopyError::Io(e));
+        }
+        Err(e) => {
+            if options.debug {
+                output::debug_above(
+                    overall_pb,
+                    format!("ODX '{}': {}, trying CopyFileEx", source.display(), e),
+                );
+            }
+            match copy_file_ex(source, destination) {
+                Ok(()) => Method::CopyFileEx,
+                Err(_) => return Ok(None),
+            }
+        }
+    };
+    if let Some(pb) = overall_pb {
+        pb.inc(file_size);
+    }
+    Ok(Some(method))
+}
+
+/// Copy through offload tokens. The destination is sized to whole sectors
+/// while the ranges are written and cut back to `file_size` after.
+fn offload(
+    source: &Path,
+    destination: &Path,
+    file_size: u64,
+    options: &CopyOptions,
+) -> io::Result<()> {
+    let src_file = open_source(source, options.noatime)?;
+    let dest_file = create_destination(destination, options)?;
+    let aligned = file_size.div_ceil(ALIGNMENT) * ALIGNMENT;
+    dest_file.set_len(aligned)?;
+
+    let mut offset = 0;
+    while offset < aligned {
+        if options.abort.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Operation aborted by user",
+            ));
+        }
+        let (length, rest_is_zero) = offload_range(&src_file, &dest_file, offset, aligned)?;
+        offset += length;
+        if rest_is_zero {
+            // Left as the zeros set_len filled it with.
+            break;
+        }
+    }
+    dest_file.set_len(file_size)?;
+    Ok(())
+}
+
+/// Offload one token's worth from `offset`, returning how much it covered
+/// and whether the source is all zeros past it.
+fn offload_range(
+    src_file: &File,
+    dest_file: &File,
+    offset: u64,
+    aligned: u64,
+) -> io::Result<(u64, bool)> {
+    let read = OffloadReadInput {
+        size: std::mem::size_of::<OffloadReadInput>() as u32,
+        flags: 0,
+        token_time_to_live: 0,
+        reserved: 0,
+        file_offset: offset,
+        copy_length: (aligned - offset).min(CHUNK),
+    };
+    let mut token = OffloadReadOutput {
+        size: std::mem::size_of::<OffloadReadOutput>() as u32,
+        flags: 0,
+        transfer_length: 0,
+        token: [0; TOKEN_SIZE],
+    };
+    control(src_file, FSCTL_OFFLOAD_READ, &read, &mut token)?;
+    let rest_is_zero = token.flags & OFFLOAD_READ_FLAG_ALL_ZERO_BEYOND_CURRENT_RANGE != 0;
+    if token.transfer_length == 0 && !rest_is_zero {
+        return Err(io::Error::other("the server offered an empty token"));
+    }
+
+    // The write may take less than the token covers; the rest is written
+    // from the same token at a later transfer offset.
+    let mut written = 0;
+    while written < token.transfer_length {
+        let write = OffloadWriteInput {
+            size: std::mem::size_of::<OffloadWriteInput>() as u32,
+            flags: 0,
+            file_offset: offset + written,
+            copy_length: token.transfer_length - written,
+            transfer_offset: written,
+            token: token.token,
+        };
+        let mut done = OffloadWriteOutput {
+            size: std::mem::size_of::<OffloadWriteOutput>() as u32,
+            flags: 0,
+            length_written: 0,
+        };
+        control(dest_file, FSCTL_OFFLOAD_WRITE, &write, &mut done)?;
+        if done.length_written == 0 {
+            return Err(io::Error::other("the server wrote nothing from the token"));
+        }
+        written += done.length_written;
+    }
+    Ok((token.transfer_length, rest_is_zero))
+}
+
+fn control<I, O>(file: &File, code: u32, input: &I, output: &mut O) -> io::Result<()> {
+    let mut returned = 0u32;
+    // SAFETY: both buffers are the structures the control code expects,
+    // sized as passed.
+    let ok = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle(),
+            code,
+            input as *const I as *const _,
+            std::mem::size_of::<I>() as u32,
+            output as *mut O as *mut _,
+            std::mem::size_of::<O>() as u32,
+            &mut returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn copy_file_ex(source: &Path, destination: &Path) -> io::Result<()> {
+    let source = wide(source);
+    let destination = wide(destination);
+    // SAFETY: both paths are NUL-terminated; no progress routine or cancel
+    // flag is passed.
+    let ok = unsafe {
+        CopyFileExW(
+            source.as_ptr(),
+            destination.as_ptr(),
+            None,
+            std::ptr::null(),
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `source` and the directory `destination` goes in are on the same
+/// network share.
+fn same_remote_volume(source: &Path, destination: &Path) -> bool {
+    let parent = match destination.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    match (volume_root(source), volume_root(parent)) {
+        (Some(a), Some(b)) => {
+            // SAFETY: `a` is NUL-terminated.
+            let remote = unsafe { GetDriveTypeW(a.as_ptr()) } == DRIVE_REMOTE;
+            remote
+                && String::from_utf16_lossy(&a).eq_ignore_ascii_case(&String::from_utf16_lossy(&b))
+        }
+        _ => false,
+    }
+}
+
+/// The mount point holding `path`, NUL-terminated, such as `\\server\share\`.
+fn volume_root(path: &Path) -> Option<Vec<u16>> {
+    let path = wide(path);
+    let mut root = [0u16; 261];
+    // SAFETY: `path` is NUL-terminated and `root` sized as passed.
+    if unsafe { GetVolumePathNameW(path.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        return None;
+    }
+    let len = root.iter().position(|&c| c == 0)?;
+    Some(root[..=len].to_vec())
+}
+
+fn wide(path: &Path) -> Vec<u16> {
+    path.as_os_str().encode_wide().chain(Some(0)).collect()
+}
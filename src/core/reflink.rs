@@ -0,0 +1,171 @@
+use crate::utility::confine;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Reflink support learned so far, keyed by device pair.
+///
+/// Whether a clone can work depends on both filesystems, so the first
+/// attempt on a (source device, destination device) pair is remembered and
+/// later files on a pair that can't clone go straight to the regular copy
+/// instead of failing the ioctl once per file.
+#[derive(Debug, Default)]
+pub struct ReflinkCache {
+    pairs: Mutex<HashMap<(u64, u64), bool>>,
+}
+
+impl ReflinkCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether cloning between these devices is known to work, if tried yet.
+    pub fn supported(&self, pair: (u64, u64)) -> Option<bool> {
+        self.pairs.lock().ok()?.get(&pair).copied()
+    }
+
+    fn record(&self, pair: (u64, u64), supported: bool) {
+        if let Ok(mut pairs) = self.pairs.lock() {
+            pairs.insert(pair, supported);
+        }
+    }
+
+    /// Clone `source` to `destination`, which must not exist yet.
+    ///
+    /// Pairs already known not to support clones fail with
+    /// [`io::ErrorKind::Unsupported`] without trying again.
+    pub fn reflink(&self, source: &Path, destination: &Path) -> io::Result<()> {
+        self.attempt(source, destination, || {
+            reflink_copy::reflink(source, destination)
+        })
+    }
+
+    /// Clone `source` to `destination` without following a symlink at or
+    /// below `root`, replacing what is there (see
+    /// [`clone_beneath`](crate::utility::confine::clone_beneath)).
+    pub fn reflink_beneath(
+        &self,
+        root: &Path,
+        source: &Path,
+        destination: &Path,
+    ) -> io::Result<()> {
+        self.attempt(source, destination, || {
+            confine::clone_beneath(root, source, destination)
+        })
+    }
+
+    fn attempt(
+        &self,
+        source: &Path,
+        destination: &Path,
+        clone: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let pair = device_pair(source, destination);
+        if let Some(pair) = pair
+            && self.supported(pair) == Some(false)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "reflink not supported between these filesystems",
+            ));
+        }
+
+        let result = clone();
+        if let Some(pair) = pair {
+            match &result {
+                Ok(()) => self.record(pair, true),
+                Err(e) if is_unsupported(e) => self.record(pair, false),
+                // Permissions, space and the like say nothing about the pair.
+                Err(_) => {}
+            }
+        }
+        result
+    }
+}
+
+/// Devices holding `source` and the directory `destination` is created in.
+#[cfg(unix)]
+fn device_pair(source: &Path, destination: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let parent = match destination.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let source_dev = std::fs::metadata(source).ok()?.dev();
+    let dest_dev = std::fs::metadata(parent).ok()?.dev();
+    Some((source_dev, dest_dev))
+}
+
+#[cfg(not(unix))]
+fn device_pair(_source: &Path, _destination: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Whether a failed clone means the filesystems can't clone at all.
+pub(crate) fn is_unsupported(err: &io::Error) -> bool {
+    if err.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+    #[cfg(unix)]
+    if let Some(code) = err.raw_os_error() {
+        // EOPNOTSUPP and ENOTSUP are the same value on Linux but not on macOS.
+        return [
+            libc::EOPNOTSUPP,
+            libc::ENOTSUP,
+            libc::EXDEV,
+            libc::EINVAL,
+            libc::ENOTTY,
+            libc::ENOSYS,
+        ]
+        .contains(&code);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_unsupported_pair_is_not_retried() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("a.txt");
+        fs::write(&source, "clone me").unwrap();
+
+        let cache = ReflinkCache::new();
+        let first = cache.reflink(&source, &temp_dir.path().join("b.txt"));
+        let Some(pair) = device_pair(&source, &temp_dir.path().join("b.txt")) else {
+            return;
+        };
+
+        match first {
+            Ok(()) => assert_eq!(cache.supported(pair), Some(true)),
+            Err(e) if is_unsupported(&e) => {
+                assert_eq!(cache.supported(pair), Some(false));
+                // The second attempt is answered from the cache, so it fails
+                // the same way without creating anything.
+                let second = cache
+                    .reflink(&source, &temp_dir.path().join("c.txt"))
+                    .unwrap_err();
+                assert_eq!(second.kind(), io::ErrorKind::Unsupported);
+                assert!(!temp_dir.path().join("c.txt").exists());
+            }
+            Err(e) => panic!("unexpected reflink error: {}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cross_device_errors_count_as_unsupported() {
+        assert!(is_unsupported(&io::Error::from_raw_os_error(libc::EXDEV)));
+        assert!(is_unsupported(&io::Error::from_raw_os_error(
+            libc::EOPNOTSUPP
+        )));
+        assert!(!is_unsupported(&io::Error::from_raw_os_error(libc::EACCES)));
+        assert!(!is_unsupported(&io::Error::from_raw_os_error(libc::ENOSPC)));
+    }
+}
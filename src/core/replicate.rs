@@ -0,0 +1,308 @@
+use crate::cli::args::CopyOptions;
+use crate::core::reflink::ReflinkCache;
+use crate::error::{CopyError, CopyResult};
+use crate::utility::confine::{self, confine_root};
+use crate::utility::helper::{create_directories, create_symlink};
+use crate::utility::output;
+use crate::utility::preprocess::{DirectoryTask, SymlinkTask};
+use indicatif::HumanBytes;
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// The `--also-to` targets of one copy and what has been written to each.
+///
+/// Every path planned under DESTINATION is also written to the same place
+/// under each target, and what gets copied at all is decided by DESTINATION
+/// alone. Sources are read once: the buffered copy writes each chunk to every
+/// target, and files the primary got some other way are copied on from the
+/// freshly written primary. A target that fails doesn't stop the others; its
+/// failures are reported once the copy is done.
+#[derive(Debug, Default)]
+pub struct Replicas {
+    primary: PathBuf,
+    targets: Vec<Target>,
+}
+
+#[derive(Debug)]
+struct Target {
+    root: PathBuf,
+    /// The directory writes into the target are confined to, as the
+    /// primary's are under `options.confine`.
+    confine: Option<PathBuf>,
+    files: AtomicUsize,
+    bytes: AtomicU64,
+    failures: Mutex<Vec<(PathBuf, String)>>,
+}
+
+impl Replicas {
+    /// Targets standing in for `primary`, the DESTINATION of the copy,
+    /// each `confined` as the primary is.
+    pub fn new(primary: &Path, roots: &[PathBuf], confined: bool) -> Self {
+        Self {
+            primary: primary.to_path_buf(),
+            targets: roots
+                .iter()
+                .map(|root| Target {
+                    root: root.clone(),
+                    confine: confined.then(|| confine_root(root)).flatten(),
+                    files: AtomicUsize::new(0),
+                    bytes: AtomicU64::new(0),
+                    failures: Mutex::new(Vec::new()),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Number of destinations written, the primary included.
+    pub fn destinations(&self) -> u64 {
+        self.targets.len() as u64 + 1
+    }
+
+    /// Where `path`, planned under the primary destination, goes in each
+    /// target, with the target's index.
+    pub fn paths_for(&self, path: &Path) -> Vec<(usize, PathBuf)> {
+        self.targets
+            .iter()
+            .enumerate()
+            .map(|(i, target)| (i, self.rebase(path, &target.root)))
+            .collect()
+    }
+
+    fn rebase(&self, path: &Path, root: &Path) -> PathBuf {
+        match path.strip_prefix(&self.primary) {
+            // A file copied to a file path goes into a target directory
+            // under that name.
+            Ok(relative) if relative.as_os_str().is_empty() => match self.primary.file_name() {
+                Some(name) if root.is_dir() => root.join(name),
+                _ => root.to_path_buf(),
+            },
+            Ok(relative) => root.join(relative),
+            Err(_) => root.join(path.file_name().unwrap_or_default()),
+        }
+    }
+
+    /// Create or truncate `path` in target `index` for writing.
+    pub fn create(&self, index: usize, path: &Path) -> io::Result<File> {
+        match &self.targets[index].confine {
+            Some(root) if path.starts_with(root) => confine::create_beneath(root, path),
+            _ => File::create(path),
+        }
+    }
+
+    /// Count a file written to target `index`.
+    pub fn completed(&self, index: usize, bytes: u64) {
+        let target = &self.targets[index];
+        target.files.fetch_add(1, Ordering::Relaxed);
+        target.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Record that `path` in target `index` could not be written.
+    pub fn failed(&self, index: usize, path: &Path, error: impl Display) {
+        if let Ok(mut failures) = self.targets[index].failures.lock() {
+            failures.push((path.to_path_buf(), error.to_string()));
+        }
+    }
+
+    /// Create the planned directories in every target.
+    pub fn create_directories(&self, directories: &[DirectoryTask]) {
+        for (index, target) in self.targets.iter().enumerate() {
+            let rebased: Vec<DirectoryTask> = directories
+                .iter()
+                .map(|d| DirectoryTask {
+                    source: d.source.clone(),
+                    destination: self.rebase(&d.destination, &target.root),
+                    metadata: None,
+                })
+                .collect();
+            if let Err(e) = create_directories(&rebased, &mut Vec::new()) {
+                self.failed(index, &target.root, e);
+            }
+        }
+    }
+
+    /// Create the planned symlinks in every target, leaving existing
+    /// entries alone under `--no-clobber` or `--update=none`.
+    pub fn create_symlinks(&self, symlinks: &[SymlinkTask], options: &CopyOptions) {
+        for task in symlinks {
+            for (index, destination) in self.paths_for(&task.destination) {
+                if options.keeps_existing() && std::fs::symlink_metadata(&destination).is_ok() {
+                    continue;
+                }
+                let rebased = SymlinkTask {
+                    destination,
+                    ..task.clone()
+                };
+                if let Err(e) = create_symlink(&rebased, options) {
+                    self.failed(index, &rebased.destination, e);
+                }
+            }
+        }
+    }
+
+    /// Copy the finished `primary` file to each of `destinations`, then run
+    /// `finish` on every copy that succeeded.
+    pub fn copy_from_primary(
+        &self,
+        primary: &Path,
+        destinations: &[(usize, PathBuf)],
+        reflink_cache: Option<&ReflinkCache>,
+        finish: impl Fn(&Path) -> CopyResult<()>,
+    ) {
+        for (index, destination) in destinations {
+            let confine = (self.targets[*index].confine.as_deref())
+                .filter(|root| destination.starts_with(root));
+            let result = clone_or_copy(primary, destination, reflink_cache, confine)
+                .map_err(CopyError::from)
+                .and_then(|bytes| finish(destination).map(|()| bytes));
+            match result {
+                Ok(bytes) => self.completed(*index, bytes),
+                Err(e) => self.failed(*index, destination, e),
+            }
+        }
+    }
+
+    /// Hard-link each of `destinations` to the copy of `first` in the same
+    /// target, as the primary was linked to `first`.
+    pub fn hard_link(&self, first: &Path, destinations: &[(usize, PathBuf)]) {
+        for (index, destination) in destinations {
+            let original = self.rebase(first, &self.targets[*index].root);
+            match std::fs::hard_link(&original, destination) {
+                Ok(()) => self.completed(*index, 0),
+                Err(e) => self.failed(*index, destination, e),
+            }
+        }
+    }
+
+    /// Print what each target received and fail if any target had errors.
+    pub fn report(&self) -> CopyResult<()> {
+        let mut failed = 0;
+        for target in &self.targets {
+            output::status(format!(
+                "Also copied to {}: {} files ({})",
+                target.root.display(),
+                target.files.load(Ordering::Relaxed),
+                HumanBytes(target.bytes.load(Ordering::Relaxed))
+            ));
+            let failures = target
+                .failures
+                .lock()
+                .map(|f| f.clone())
+                .unwrap_or_default();
+            for (path, error) in &failures {
+                output::error(format!("could not write '{}': {}", path.display(), error));
+            }
+            failed += failures.len();
+        }
+        if failed > 0 {
+            return Err(CopyError::Io(io::Error::other(format!(
+                "{} file(s) failed to copy to --also-to targets",
+                failed
+            ))));
+        }
+        Ok(())
+    }
+}
+
+/// Clone `primary` to `destination` when it doesn't exist yet and the
+/// filesystems allow, copy it otherwise. Returns the bytes in the file.
+/// Under `confine`, no symlink below that directory is followed.
+fn clone_or_copy(
+    primary: &Path,
+    destination: &Path,
+    reflink_cache: Option<&ReflinkCache>,
+    confine: Option<&Path>,
+) -> io::Result<u64> {
+    let Some(root) = confine else {
+        if let Some(cache) = reflink_cache
+            && !destination.try_exists().unwrap_or(false)
+            && cache.reflink(primary, destination).is_ok()
+        {
+            return Ok(std::fs::metadata(destination)?.len());
+        }
+        return std::fs::copy(primary, destination);
+    };
+    if let Some(cache) = reflink_cache
+        && std::fs::symlink_metadata(destination).is_err()
+        && cache.reflink_beneath(root, primary, destination).is_ok()
+    {
+        return Ok(std::fs::metadata(primary)?.len());
+    }
+    let mut source = File::open(primary)?;
+    let mut file = confine::create_beneath(root, destination)?;
+    let copied = io::copy(&mut source, &mut file)?;
+    file.set_permissions(source.metadata()?.permissions())?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_paths_are_rebased_onto_each_target() {
+        let replicas = Replicas::new(
+            Path::new("/archive"),
+            &[PathBuf::from("/mnt/share"), PathBuf::from("/backup")],
+            false,
+        );
+        assert_eq!(
+            replicas.paths_for(Path::new("/archive/release/app.tar")),
+            vec![
+                (0, PathBuf::from("/mnt/share/release/app.tar")),
+                (1, PathBuf::from("/backup/release/app.tar")),
+            ]
+        );
+        assert_eq!(replicas.destinations(), 3);
+    }
+
+    #[test]
+    fn test_file_destination_lands_in_target_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("share");
+        std::fs::create_dir(&target).unwrap();
+        let missing = temp_dir.path().join("new.bin");
+        let replicas = Replicas::new(
+            &temp_dir.path().join("out/app.bin"),
+            &[target.clone(), missing.clone()],
+            false,
+        );
+
+        // An existing directory gets the file by name; anything else is
+        // taken as the file path itself.
+        assert_eq!(
+            replicas.paths_for(&temp_dir.path().join("out/app.bin")),
+            vec![(0, target.join("app.bin")), (1, missing)]
+        );
+    }
+
+    #[test]
+    fn test_failures_are_kept_per_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let primary = temp_dir.path().join("primary.txt");
+        std::fs::write(&primary, "data").unwrap();
+        let good = temp_dir.path().join("good.txt");
+        let bad = temp_dir.path().join("missing/dir/bad.txt");
+        let replicas = Replicas::new(
+            temp_dir.path(),
+            &[temp_dir.path().join("a"), temp_dir.path().join("b")],
+            false,
+        );
+
+        replicas.copy_from_primary(&primary, &[(0, good.clone()), (1, bad)], None, |_| Ok(()));
+
+        assert_eq!(std::fs::read_to_string(&good).unwrap(), "data");
+        assert_eq!(replicas.targets[0].files.load(Ordering::Relaxed), 1);
+        assert_eq!(replicas.targets[0].bytes.load(Ordering::Relaxed), 4);
+        assert_eq!(replicas.targets[1].failures.lock().unwrap().len(), 1);
+        assert!(replicas.report().is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use crate::cli::args::CopyOptions;
+use crate::core::reflink::is_unsupported;
+use crate::utility::helper::{create_destination, open_source};
+use crate::utility::preprocess::FileTask;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Clones only cover whole blocks; shorter tails are written instead.
+const CLONE_ALIGNMENT: u64 = 4096;
+
+/// A mapped range of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+}
+
+impl Extent {
+    fn key(&self) -> (u64, u64) {
+        (self.physical, self.length)
+    }
+}
+
+/// Shared extents of the files in a plan, and where each one has been
+/// written at the destination so far, for `--preserve-sharing`.
+///
+/// Files cloned from one another or from a common snapshot point at the same
+/// physical extents, and a plain copy writes each of them out in full. The
+/// first file to reach a shared extent writes it; every later one clones the
+/// range from that copy with FICLONERANGE and only writes the rest of its
+/// data. Files taking part are copied one at a time so that a range is always
+/// written before anything clones it, and whenever cloning isn't possible the
+/// range is simply written.
+#[derive(Debug, Default)]
+pub struct SharedExtents {
+    files: HashMap<PathBuf, Vec<Extent>>,
+    placed: Mutex<HashMap<(u64, u64), (PathBuf, u64)>>,
+    unsupported: AtomicBool,
+    deduplicated: AtomicU64,
+}
+
+impl SharedExtents {
+    /// Map the shared extents of every file in `files`.
+    ///
+    /// Files that can't be mapped are copied normally.
+    pub fn find(files: &[FileTask]) -> Self {
+        let per_file = files
+            .iter()
+            .filter(|f| f.size > 0)
+            .filter_map(|f| {
+                let extents = shared_extents(&f.source).ok()?;
+                (!extents.is_empty()).then(|| (f.source.clone(), extents))
+            })
+            .collect();
+        Self::from_extents(per_file)
+    }
+
+    /// Keep the extents that more than one place in the plan uses; sharing
+    /// with files outside the copy can't be reproduced.
+    fn from_extents(per_file: Vec<(PathBuf, Vec<Extent>)>) -> Self {
+        let mut users: HashMap<(u64, u64), usize> = HashMap::new();
+        for (_, extents) in &per_file {
+            for extent in extents {
+                *users.entry(extent.key()).or_default() += 1;
+            }
+        }
+
+        let files = per_file
+            .into_iter()
+            .filter_map(|(path, extents)| {
+                let mut shared: Vec<_> = extents
+                    .into_iter()
+                    .filter(|e| users[&e.key()] > 1)
+                    .collect();
+                shared.sort_by_key(|e| e.logical);
+                (!shared.is_empty()).then_some((path, shared))
+            })
+            .collect();
+        SharedExtents {
+            files,
+            ..SharedExtents::default()
+        }
+    }
+
+    /// Whether `source` has extents shared with another file in the plan.
+    pub fn contains(&self, source: &Path) -> bool {
+        self.files.contains_key(source)
+    }
+
+    /// Bytes cloned instead of written so far.
+    pub fn deduplicated(&self) -> u64 {
+        self.deduplicated.load(Ordering::Relaxed)
+    }
+
+    /// Copy `source` to `destination`, cloning the shared ranges already
+    /// written for another file.
+    ///
+    /// Returns the bytes copied, or `None` if `source` shares nothing and
+    /// should be copied the usual way.
+    pub fn copy_file(
+        &self,
+        source: &Path,
+        destination: &Path,
+        size: u64,
+        options: &CopyOptions,
+    ) -> io::Result<Option<u64>> {
+        let Some(extents) = self.files.get(source) else {
+            return Ok(None);
+        };
+        let mut src = open_source(source, options.noatime)?;
+        let mut dest = create_destination(destination, options)?;
+
+        let mut position = 0;
+        for extent in extents {
+            if extent.logical < position || extent.logical >= size {
+                continue;
+            }
+            copy_range(&mut src, &mut dest, position, extent.logical - position)?;
+            let length = extent.length.min(size - extent.logical);
+            if !self.clone_placed(extent, length, &dest) {
+                copy_range(&mut src, &mut dest, extent.logical, length)?;
+                if let Ok(mut placed) = self.placed.lock() {
+                    placed
+                        .entry(extent.key())
+                        .or_insert_with(|| (destination.to_path_buf(), extent.logical));
+                }
+            }
+            position = extent.logical + length;
+        }
+
+        // Everything after the last shared extent, up to EOF.
+        src.seek(SeekFrom::Start(position))?;
+        dest.seek(SeekFrom::Start(position))?;
+        let tail = io::copy(&mut src, &mut dest)?;
+        Ok(Some(position + tail))
+    }
+
+    /// Clone `extent` from wherever it was written before, if anywhere.
+    fn clone_placed(&self, extent: &Extent, length: u64, dest: &File) -> bool {
+        if self.unsupported.load(Ordering::Relaxed) || !length.is_multiple_of(CLONE_ALIGNMENT) {
+            return false;
+        }
+        let Some((path, offset)) = self
+            .placed
+            .lock()
+            .ok()
+            .and_then(|placed| placed.get(&extent.key()).cloned())
+        else {
+            return false;
+        };
+        let Ok(from) = File::open(&path) else {
+            return false;
+        };
+        match clone_range(&from, offset, length, dest, extent.logical) {
+            Ok(()) => {
+                self.deduplicated.fetch_add(length, Ordering::Relaxed);
+                true
+            }
+            Err(e) => {
+                if is_unsupported(&e) {
+                    self.unsupported.store(true, Ordering::Relaxed);
+                }
+                false
+            }
+        }
+    }
+}
+
+fn copy_range(src: &mut File, dest: &mut File, offset: u64, length: u64) -> io::Result<()> {
+    if length == 0 {
+        return Ok(());
+    }
+    src.seek(SeekFrom::Start(offset))?;
+    dest.seek(SeekFrom::Start(offset))?;
+    io::copy(&mut src.take(length), dest)?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod fiemap {
+    use super::Extent;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+    const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+    const FIEMAP_EXTENT_NOT_ALIGNED: u32 = 0x100;
+    const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+    const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+    /// Extents whose physical address can't identify them.
+    const FIEMAP_EXTENT_UNUSABLE: u32 = FIEMAP_EXTENT_UNKNOWN
+        | FIEMAP_EXTENT_DELALLOC
+        | FIEMAP_EXTENT_NOT_ALIGNED
+        | FIEMAP_EXTENT_DATA_INLINE;
+    const EXTENTS_PER_CALL: usize = 128;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct FiemapExtent {
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_reserved64: [u64; 2],
+        fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+
+    /// `struct fiemap` followed by room for its extents.
+    #[repr(C)]
+    struct Fiemap {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fm_extents: [FiemapExtent; EXTENTS_PER_CALL],
+    }
+
+    /// Size of `struct fiemap` without the extents, which the request code
+    /// is built from.
+    const FIEMAP_HEADER_SIZE: usize = 32;
+
+    /// The extents of `path` flagged as shared.
+    pub fn shared_extents(path: &Path) -> io::Result<Vec<Extent>> {
+        let file = File::open(path)?;
+        let request = nix::request_code_readwrite!(b'f', 11, FIEMAP_HEADER_SIZE);
+        let mut shared = Vec::new();
+        let mut start = 0u64;
+        loop {
+            let mut map = Box::new(Fiemap {
+                fm_start: start,
+                fm_length: u64::MAX - start,
+                fm_flags: FIEMAP_FLAG_SYNC,
+                fm_mapped_extents: 0,
+                fm_extent_count: EXTENTS_PER_CALL as u32,
+                fm_reserved: 0,
+                fm_extents: [FiemapExtent::default(); EXTENTS_PER_CALL],
+            });
+            if unsafe { libc::ioctl(file.as_raw_fd(), request as _, &mut *map) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mapped = (map.fm_mapped_extents as usize).min(EXTENTS_PER_CALL);
+            if mapped == 0 {
+                return Ok(shared);
+            }
+            for extent in &map.fm_extents[..mapped] {
+                start = extent.fe_logical + extent.fe_length;
+                if extent.fe_flags & FIEMAP_EXTENT_SHARED != 0
+                    && extent.fe_flags & FIEMAP_EXTENT_UNUSABLE == 0
+                {
+                    shared.push(Extent {
+                        logical: extent.fe_logical,
+                        physical: extent.fe_physical,
+                        length: extent.fe_length,
+                    });
+                }
+                if extent.fe_flags & FIEMAP_EXTENT_LAST != 0 {
+                    return Ok(shared);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+use fiemap::shared_extents;
+
+#[cfg(not(target_os = "linux"))]
+fn shared_extents(_path: &Path) -> io::Result<Vec<Extent>> {
+    Ok(Vec::new())
+}
+
+/// Clone `length` bytes at `offset` in `from` to `dest_offset` in `dest`.
+#[cfg(target_os = "linux")]
+fn clone_range(
+    from: &File,
+    offset: u64,
+    length: u64,
+    dest: &File,
+    dest_offset: u64,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let range = libc::file_clone_range {
+        src_fd: from.as_raw_fd() as i64,
+        src_offset: offset,
+        src_length: length,
+        dest_offset,
+    };
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONERANGE as _, &range) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_range(
+    _from: &File,
+    _offset: u64,
+    _length: u64,
+    _dest: &File,
+    _dest_offset: u64,
+) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn extent(logical: u64, physical: u64, length: u64) -> Extent {
+        Extent {
+            logical,
+            physical,
+            length,
+        }
+    }
+
+    #[test]
+    fn test_only_extents_used_twice_in_the_plan_are_kept() {
+        let shared = SharedExtents::from_extents(vec![
+            (
+                PathBuf::from("a"),
+                vec![extent(0, 1 << 20, 8192), extent(8192, 9 << 20, 4096)],
+            ),
+            (PathBuf::from("b"), vec![extent(4096, 1 << 20, 8192)]),
+            (PathBuf::from("c"), vec![extent(0, 5 << 20, 4096)]),
+        ]);
+
+        assert_eq!(shared.files[Path::new("a")], vec![extent(0, 1 << 20, 8192)]);
+        assert_eq!(
+            shared.files[Path::new("b")],
+            vec![extent(4096, 1 << 20, 8192)]
+        );
+        // Shared only with something outside the copy.
+        assert!(!shared.contains(Path::new("c")));
+    }
+
+    #[test]
+    fn test_copy_without_clone_support_writes_everything() {
+        let temp_dir = TempDir::new().unwrap();
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+        fs::write(&a, &data).unwrap();
+        fs::write(&b, &data).unwrap();
+
+        // Pretend both files share their first two blocks.
+        let shared = SharedExtents::from_extents(vec![
+            (a.clone(), vec![extent(0, 1 << 20, 8192)]),
+            (b.clone(), vec![extent(0, 1 << 20, 8192)]),
+        ]);
+        let a_copy = temp_dir.path().join("a.copy");
+        let b_copy = temp_dir.path().join("b.copy");
+        let size = data.len() as u64;
+        assert_eq!(
+            shared
+                .copy_file(&a, &a_copy, size, &CopyOptions::none())
+                .unwrap(),
+            Some(size)
+        );
+        assert_eq!(
+            shared
+                .copy_file(&b, &b_copy, size, &CopyOptions::none())
+                .unwrap(),
+            Some(size)
+        );
+
+        assert_eq!(fs::read(&a_copy).unwrap(), data);
+        assert_eq!(fs::read(&b_copy).unwrap(), data);
+        // Not sharing anything, so not handled here at all.
+        assert_eq!(
+            shared
+                .copy_file(
+                    &temp_dir.path().join("c"),
+                    &b_copy,
+                    size,
+                    &CopyOptions::none()
+                )
+                .unwrap(),
+            None
+        );
+    }
+}
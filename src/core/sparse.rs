@@ -0,0 +1,526 @@
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+use crate::cli::args::CopyOptions;
+use crate::cli::args::SparseMode;
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+use crate::error::CopyResult;
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+use crate::utility::helper::{create_destination, retry_eintr};
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+use indicatif::ProgressBar;
+use std::fs::{File, Metadata};
+use std::io::{self, Seek, SeekFrom, Write};
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+use std::path::Path;
+
+/// Copy `source` to `destination` preserving holes, on NTFS/ReFS.
+///
+/// Returns `Ok(false)` without touching the destination when the source has
+/// no holes or the destination volume can't hold sparse files (FAT, exFAT),
+/// so the caller falls back to a dense copy.
+#[cfg(windows)]
+pub fn sparse_copy(
+    source: &Path,
+    destination: &Path,
+    file_size: u64,
+    overall_pb: Option<&ProgressBar>,
+    options: &CopyOptions,
+) -> CopyResult<bool> {
+    if options.sparse == SparseMode::Never || file_size == 0 {
+        return Ok(false);
+    }
+
+    let mut src_file = crate::utility::helper::open_source(source, options.noatime)?;
+    let ranges = windows::allocated_ranges(&src_file, file_size)?;
+    let allocated: u64 = ranges.iter().map(|(_, len)| len).sum();
+    if allocated >= file_size {
+        return Ok(false);
+    }
+
+    if !windows::volume_supports_sparse(destination)? {
+        return Ok(false);
+    }
+
+    let mut dest_file = create_destination(destination, options)?;
+    windows::set_sparse(&dest_file)?;
+    dest_file.set_len(file_size)?;
+
+    copy_extents(&mut src_file, &mut dest_file, &ranges, destination, options)?;
+    dest_file.flush()?;
+
+    if let Some(pb) = overall_pb {
+        pb.inc(file_size);
+    }
+    Ok(true)
+}
+
+/// Copy `source` to `destination` preserving holes, using SEEK_DATA/SEEK_HOLE:
+/// only the allocated extents are read and written, so holes stay holes.
+/// With `--sparse=always` runs of zeros in the data become holes too.
+///
+/// Returns `Ok(false)` without touching the destination when the source has
+/// no holes, and zeros aren't being looked for, or its filesystem can't
+/// report them, so the caller falls back to a dense copy.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn sparse_copy(
+    source: &Path,
+    destination: &Path,
+    file_size: u64,
+    overall_pb: Option<&ProgressBar>,
+    options: &CopyOptions,
+) -> CopyResult<bool> {
+    if options.sparse == SparseMode::Never || file_size == 0 {
+        return Ok(false);
+    }
+
+    let mut src_file = crate::utility::helper::open_source(source, options.noatime)?;
+    let Some(extents) = data_extents(&src_file, file_size)? else {
+        return Ok(false);
+    };
+    let allocated: u64 = extents.iter().map(|(_, len)| len).sum();
+    if allocated >= file_size && options.sparse != SparseMode::Always {
+        return Ok(false);
+    }
+
+    let mut dest_file = create_destination(destination, options)?;
+    dest_file.set_len(file_size)?;
+    copy_extents(
+        &mut src_file,
+        &mut dest_file,
+        &extents,
+        destination,
+        options,
+    )?;
+    dest_file.flush()?;
+
+    if let Some(pb) = overall_pb {
+        pb.inc(file_size);
+    }
+    Ok(true)
+}
+
+/// Data `(offset, length)` extents of `file`, in order, or `None` when the
+/// filesystem does not support SEEK_DATA (detected at runtime).
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn data_extents(file: &File, file_size: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let end = file_size as libc::off_t;
+    let mut extents = Vec::new();
+    let mut offset: libc::off_t = 0;
+
+    while offset < end {
+        let data = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // No data past `offset`: the rest of the file is a hole
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) if offset == 0 => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let hole = hole.min(end);
+        if hole > data {
+            extents.push((data as u64, (hole - data) as u64));
+        }
+        offset = hole;
+    }
+    Ok(Some(extents))
+}
+
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+fn copy_extents(
+    src_file: &mut File,
+    dest_file: &mut File,
+    extents: &[(u64, u64)],
+    destination: &Path,
+    options: &CopyOptions,
+) -> CopyResult<()> {
+    use crate::core::{full, stall};
+    use std::io::Read;
+    use std::sync::atomic::Ordering;
+
+    let mut buffer = vec![0u8; 1024 * 1024];
+    for &(offset, len) in extents {
+        src_file.seek(SeekFrom::Start(offset))?;
+        dest_file.seek(SeekFrom::Start(offset))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            full::hold(&options.abort);
+            if stall::cancelled() {
+                let _ = std::fs::remove_file(destination);
+                return Err(stall::stalled_error().into());
+            }
+            if options.abort.load(Ordering::Relaxed) {
+                let _ = std::fs::remove_file(destination);
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "Operation aborted by user",
+                )
+                .into());
+            }
+            let want = remaining.min(buffer.len() as u64) as usize;
+            let read = retry_eintr(|| src_file.read(&mut buffer[..want]))?;
+            if read == 0 {
+                break;
+            }
+            let zeros = options.sparse == SparseMode::Always && is_zeros(&buffer[..read]);
+            write_chunk(dest_file, &buffer[..read], zeros)?;
+            stall::progress();
+            remaining -= read as u64;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a copy that writes every byte should leave blocks of zeros as
+/// holes instead: always with `--sparse=always`, and with `auto` when the
+/// source has holes of its own, as far as its size on disk tells.
+pub fn makes_holes(mode: SparseMode, source: &Metadata) -> bool {
+    match mode {
+        SparseMode::Always => true,
+        SparseMode::Never => false,
+        #[cfg(unix)]
+        SparseMode::Auto => {
+            use std::os::unix::fs::MetadataExt;
+            source.blocks() * 512 < source.len()
+        }
+        #[cfg(not(unix))]
+        SparseMode::Auto => false,
+    }
+}
+
+/// Whether `chunk` is all zeros.
+pub fn is_zeros(chunk: &[u8]) -> bool {
+    chunk.iter().all(|&byte| byte == 0)
+}
+
+/// Write `chunk` at the current position, or with `hole` skip past it,
+/// leaving a hole. A hole at the end only counts once the file's length is
+/// set past it.
+pub fn write_chunk<W: Write + Seek>(writer: &mut W, chunk: &[u8], hole: bool) -> io::Result<()> {
+    if hole {
+        writer
+            .seek(SeekFrom::Current(chunk.len() as i64))
+            .map(|_| ())
+    } else {
+        writer.write_all(chunk)
+    }
+}
+
+/// Reserve space for `len` bytes without changing the file size, so large
+/// writes don't fragment. Callers treat failure as non-fatal.
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                0,
+                len as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let mut store = libc::fstore_t {
+            fst_flags: libc::F_ALLOCATECONTIG,
+            fst_posmode: libc::F_PEOFPOSMODE,
+            fst_offset: 0,
+            fst_length: len as libc::off_t,
+            fst_bytesalloc: 0,
+        };
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) } == 0 {
+            return Ok(());
+        }
+        // No contiguous run that large; take whatever blocks are free
+        store.fst_flags = libc::F_ALLOCATEALL;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = (file, len);
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use std::path::Path;
+    use windows_sys::Win32::Foundation::ERROR_MORE_DATA;
+    use windows_sys::Win32::Storage::FileSystem::GetVolumeInformationByHandleW;
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+    use windows_sys::Win32::System::Ioctl::{
+        FILE_ALLOCATED_RANGE_BUFFER, FSCTL_QUERY_ALLOCATED_RANGES, FSCTL_SET_SPARSE,
+    };
+
+    const FILE_SUPPORTS_SPARSE_FILES: u32 = 0x40;
+
+    /// Allocated `(offset, length)` ranges of `file`, in order.
+    pub fn allocated_ranges(file: &File, file_size: u64) -> io::Result<Vec<(u64, u64)>> {
+        let mut ranges = Vec::new();
+        let mut query = FILE_ALLOCATED_RANGE_BUFFER {
+            FileOffset: 0,
+            Length: file_size as i64,
+        };
+        let mut out = [FILE_ALLOCATED_RANGE_BUFFER::default(); 64];
+
+        loop {
+            let mut returned = 0u32;
+            let ok = unsafe {
+                DeviceIoControl(
+                    file.as_raw_handle(),
+                    FSCTL_QUERY_ALLOCATED_RANGES,
+                    &query as *const _ as *const _,
+                    std::mem::size_of::<FILE_ALLOCATED_RANGE_BUFFER>() as u32,
+                    out.as_mut_ptr() as *mut _,
+                    std::mem::size_of_val(&out) as u32,
+                    &mut returned,
+                    std::ptr::null_mut(),
+                )
+            };
+            let more = if ok != 0 {
+                false
+            } else {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(ERROR_MORE_DATA as i32) {
+                    return Err(err);
+                }
+                true
+            };
+
+            let count = returned as usize / std::mem::size_of::<FILE_ALLOCATED_RANGE_BUFFER>();
+            for range in &out[..count] {
+                ranges.push((range.FileOffset as u64, range.Length as u64));
+            }
+            match ranges.last() {
+                Some(&(offset, len)) if more => {
+                    let next = offset + len;
+                    query.FileOffset = next as i64;
+                    query.Length = file_size.saturating_sub(next) as i64;
+                }
+                _ => return Ok(ranges),
+            }
+        }
+    }
+
+    pub fn set_sparse(file: &File) -> io::Result<()> {
+        let mut returned = 0u32;
+        let ok = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle(),
+                FSCTL_SET_SPARSE,
+                std::ptr::null(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Whether the volume holding `path` (or its parent, if `path` does not
+    /// exist yet) supports sparse files.
+    pub fn volume_supports_sparse(path: &Path) -> io::Result<bool> {
+        let probe = if path.exists() {
+            path
+        } else {
+            path.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+        };
+        let handle = open_for_query(probe)?;
+        let mut flags = 0u32;
+        let ok = unsafe {
+            GetVolumeInformationByHandleW(
+                handle.as_raw_handle(),
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &mut flags,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(flags & FILE_SUPPORTS_SPARSE_FILES != 0)
+    }
+
+    fn open_for_query(path: &Path) -> io::Result<File> {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+        std::fs::OpenOptions::new()
+            .access_mode(0)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+            .open(path)
+    }
+
+    /// On-disk size as reported by GetCompressedFileSizeW.
+    #[cfg(test)]
+    pub fn allocated_size(path: &Path) -> io::Result<u64> {
+        use std::os::windows::ffi::OsStrExt;
+        use windows_sys::Win32::Storage::FileSystem::GetCompressedFileSizeW;
+
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        let mut high = 0u32;
+        let low = unsafe { GetCompressedFileSizeW(wide.as_ptr(), &mut high) };
+        if low == u32::MAX {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(0) {
+                return Err(err);
+            }
+        }
+        Ok(((high as u64) << 32) | low as u64)
+    }
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+    use crate::cli::args::CopyOptions;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sparse_copy_keeps_holes_on_ntfs() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("sparse.img");
+        let dest = temp_dir.path().join("copy.img");
+        let logical = 64 * 1024 * 1024;
+
+        let mut file = std::fs::File::create(&source).unwrap();
+        if windows::set_sparse(&file).is_err() {
+            return; // not NTFS/ReFS
+        }
+        file.write_all(b"header").unwrap();
+        file.set_len(logical).unwrap();
+        drop(file);
+
+        let options = CopyOptions::none();
+        assert!(sparse_copy(&source, &dest, logical, None, &options).unwrap());
+
+        assert_eq!(std::fs::metadata(&dest).unwrap().len(), logical);
+        assert!(windows::allocated_size(&dest).unwrap() < logical);
+        assert_eq!(
+            windows::allocated_size(&dest).unwrap(),
+            windows::allocated_size(&source).unwrap()
+        );
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+mod unix_tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::TempDir;
+
+    fn make_sparse(path: &Path, logical: u64) {
+        let mut file = File::create(path).unwrap();
+        file.write_all(&[0xab; 4096]).unwrap();
+        file.seek(SeekFrom::Start(logical / 2)).unwrap();
+        file.write_all(&[0xcd; 4096]).unwrap();
+        file.set_len(logical).unwrap();
+        file.sync_all().unwrap();
+    }
+
+    #[test]
+    fn test_sparse_copy_keeps_holes() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("sparse.img");
+        let dest = temp_dir.path().join("copy.img");
+        let logical = 32 * 1024 * 1024;
+        make_sparse(&source, logical);
+
+        let src_blocks = std::fs::metadata(&source).unwrap().blocks() * 512;
+        if src_blocks >= logical {
+            return; // filesystem does not keep holes
+        }
+
+        let options = CopyOptions::none();
+        assert!(sparse_copy(&source, &dest, logical, None, &options).unwrap());
+
+        let dest_meta = std::fs::metadata(&dest).unwrap();
+        assert_eq!(dest_meta.len(), logical);
+        assert!(dest_meta.blocks() * 512 < logical / 4);
+        assert_eq!(
+            std::fs::read(&source).unwrap(),
+            std::fs::read(&dest).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sparse_copy_declines_when_never() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("sparse.img");
+        let dest = temp_dir.path().join("copy.img");
+        make_sparse(&source, 8 * 1024 * 1024);
+
+        let mut options = CopyOptions::none();
+        options.sparse = SparseMode::Never;
+        assert!(!sparse_copy(&source, &dest, 8 * 1024 * 1024, None, &options).unwrap());
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn test_sparse_copy_turns_zeros_into_holes_when_always() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("zeros.img");
+        let dest = temp_dir.path().join("copy.img");
+        let logical = 8 * 1024 * 1024;
+        let mut data = vec![0u8; logical as usize];
+        data[..4096].fill(0xab);
+        std::fs::write(&source, &data).unwrap();
+
+        let mut options = CopyOptions::none();
+        assert!(!sparse_copy(&source, &dest, logical, None, &options).unwrap());
+        options.sparse = SparseMode::Always;
+        if !sparse_copy(&source, &dest, logical, None, &options).unwrap() {
+            return; // filesystem can't report holes
+        }
+
+        let dest_meta = std::fs::metadata(&dest).unwrap();
+        assert_eq!(dest_meta.len(), logical);
+        assert!(dest_meta.blocks() * 512 < logical / 4);
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+    }
+
+    #[test]
+    fn test_preallocate_keeps_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("prealloc");
+        let file = File::create(&path).unwrap();
+
+        if preallocate(&file, 4 * 1024 * 1024).is_ok() {
+            let meta = file.metadata().unwrap();
+            assert_eq!(meta.len(), 0);
+            assert!(meta.blocks() * 512 >= 4 * 1024 * 1024);
+        }
+    }
+}
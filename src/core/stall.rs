@@ -0,0 +1,266 @@
+use crate::cli::args::StallAction;
+use crate::utility::output;
+use crate::utility::progress_bar::ProgressBarStyle;
+use indicatif::ProgressBar;
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<FileState>>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug)]
+struct FileState {
+    path: PathBuf,
+    started: Instant,
+    last_progress_ms: AtomicU64,
+    reported: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+impl FileState {
+    fn idle(&self, now: Instant) -> Duration {
+        let since_start = now.saturating_duration_since(self.started);
+        since_start.saturating_sub(Duration::from_millis(
+            self.last_progress_ms.load(Ordering::Relaxed),
+        ))
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    files: Mutex<Vec<Arc<FileState>>>,
+    stop: AtomicBool,
+}
+
+/// Background thread that flags files whose copy has stopped moving, e.g.
+/// on a hung NFS or SMB mount.
+///
+/// Each worker registers the file it is copying with [`StallWatchdog::begin`]
+/// and reports progress through [`progress`]. Files that made no progress for
+/// `--stall-timeout` are warned about and, with `--stall-action=skip`, given
+/// up on; the copy loops check [`cancelled`] next to the abort flag.
+pub struct StallWatchdog {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Registration of the file the current thread is copying; dropping it
+/// unregisters the file.
+pub struct FileGuard<'a> {
+    watchdog: &'a StallWatchdog,
+    state: Arc<FileState>,
+}
+
+impl StallWatchdog {
+    pub fn start(
+        timeout: Duration,
+        action: StallAction,
+        progress_bar: Option<Arc<ProgressBar>>,
+        style: ProgressBarStyle,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            files: Mutex::new(Vec::new()),
+            stop: AtomicBool::new(false),
+        });
+        let tick = (timeout / 4).clamp(Duration::from_millis(10), Duration::from_millis(500));
+
+        let handle = std::thread::spawn({
+            let shared = shared.clone();
+            move || {
+                while !shared.stop.load(Ordering::Relaxed) {
+                    std::thread::sleep(tick);
+                    let now = Instant::now();
+                    let files = match shared.files.lock() {
+                        Ok(files) => files.clone(),
+                        Err(_) => return,
+                    };
+                    for file in files {
+                        let idle = file.idle(now);
+                        if idle < timeout {
+                            file.reported.store(false, Ordering::Relaxed);
+                            continue;
+                        }
+                        if file.reported.swap(true, Ordering::Relaxed) {
+                            continue;
+                        }
+                        if action == StallAction::Skip {
+                            file.cancelled.store(true, Ordering::Relaxed);
+                        }
+                        report(&file, idle, action, progress_bar.as_deref(), style);
+                    }
+                }
+            }
+        });
+
+        Self {
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    /// Start watching `path` on the calling thread.
+    pub fn begin(&self, path: &Path) -> FileGuard<'_> {
+        let state = Arc::new(FileState {
+            path: path.to_path_buf(),
+            started: Instant::now(),
+            last_progress_ms: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        });
+        if let Ok(mut files) = self.shared.files.lock() {
+            files.push(state.clone());
+        }
+        CURRENT.with(|current| *current.borrow_mut() = Some(state.clone()));
+        FileGuard {
+            watchdog: self,
+            state,
+        }
+    }
+}
+
+impl Drop for FileGuard<'_> {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        if let Ok(mut files) = self.watchdog.shared.files.lock() {
+            files.retain(|f| !Arc::ptr_eq(f, &self.state));
+        }
+    }
+}
+
+impl Drop for StallWatchdog {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Record that the file being copied on this thread moved forward.
+pub fn progress() {
+    CURRENT.with(|current| {
+        if let Some(state) = current.borrow().as_ref() {
+            let elapsed = state.started.elapsed().as_millis() as u64;
+            state.last_progress_ms.store(elapsed, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Whether the watchdog gave up on the file being copied on this thread.
+pub fn cancelled() -> bool {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .is_some_and(|state| state.cancelled.load(Ordering::Relaxed))
+    })
+}
+
+/// Error a copy loop returns once its file was skipped for stalling.
+pub fn stalled_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no progress within --stall-timeout, skipped",
+    )
+}
+
+fn report(
+    file: &FileState,
+    idle: Duration,
+    action: StallAction,
+    progress_bar: Option<&ProgressBar>,
+    style: ProgressBarStyle,
+) {
+    let mut message = format!(
+        "no progress on '{}' for {}s",
+        file.path.display(),
+        idle.as_secs()
+    );
+    if action == StallAction::Skip {
+        message.push_str(", skipping it");
+    }
+
+    match progress_bar {
+        Some(pb) => {
+            if matches!(style, ProgressBarStyle::Detailed) {
+                pb.set_message(format!("Stalled: {}", file.path.display()));
+            }
+            pb.println(format!("Warning: {}", message));
+        }
+        None => output::warning(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_skip_cancels_stalled_file() {
+        let watchdog = StallWatchdog::start(
+            Duration::from_millis(50),
+            StallAction::Skip,
+            None,
+            ProgressBarStyle::Default,
+        );
+        let guard = watchdog.begin(Path::new("/mnt/nfs/stuck.bin"));
+
+        assert!(wait_for(cancelled));
+        drop(guard);
+        assert!(!cancelled());
+    }
+
+    #[test]
+    fn test_warn_never_cancels() {
+        let watchdog = StallWatchdog::start(
+            Duration::from_millis(20),
+            StallAction::Warn,
+            None,
+            ProgressBarStyle::Default,
+        );
+        let guard = watchdog.begin(Path::new("/mnt/nfs/slow.bin"));
+
+        assert!(wait_for(|| guard.state.reported.load(Ordering::Relaxed)));
+        assert!(!cancelled());
+    }
+
+    #[test]
+    fn test_progress_keeps_file_alive() {
+        let watchdog = StallWatchdog::start(
+            Duration::from_millis(200),
+            StallAction::Skip,
+            None,
+            ProgressBarStyle::Default,
+        );
+        let _guard = watchdog.begin(Path::new("/data/moving.bin"));
+
+        for _ in 0..20 {
+            std::thread::sleep(Duration::from_millis(20));
+            progress();
+        }
+        assert!(!cancelled());
+    }
+
+    #[test]
+    fn test_progress_without_watchdog_is_noop() {
+        progress();
+        assert!(!cancelled());
+    }
+}
@@ -0,0 +1,413 @@
+use crate::cli::args::{BackupMode, CopyOptions, Prompt, ReflinkMode};
+use crate::error::{CopyError, CopyResult};
+use crate::utility::backup::back_up;
+use crate::utility::helper::{
+    clear_destination, open_destination, open_source, prompt_overwrite, with_parents,
+};
+use crate::utility::itemize::{self, Item};
+use crate::utility::output::{self, Summary};
+use crate::utility::progress_bar::ProgressMode;
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Whether `path`, following symlinks, is a pipe or character device, such
+/// as `/dev/stdin` or a process substitution like `<(generate-data)`.
+///
+/// Special files found while walking a directory are recreated as nodes
+/// instead, unless `--copy-contents` has them read like a stream.
+#[cfg(unix)]
+pub fn is_stream(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path).is_ok_and(|m| {
+        let file_type = m.file_type();
+        file_type.is_fifo() || file_type.is_char_device()
+    })
+}
+
+#[cfg(not(unix))]
+pub fn is_stream(_path: &Path) -> bool {
+    false
+}
+
+/// Where a stream named `source` is written when copied to `destination`.
+pub fn stream_destination(source: &Path, destination: &Path, options: &CopyOptions) -> PathBuf {
+    if options.parents {
+        with_parents(destination, source)
+    } else if destination.is_dir()
+        && let Some(name) = source.file_name()
+    {
+        destination.join(name)
+    } else {
+        destination.to_path_buf()
+    }
+}
+
+/// Copy the stream `source` to `destination`, reading until EOF.
+///
+/// A stream has no meaningful size and can't be seeked, cloned or handed to
+/// copy_file_range, so it goes through the buffered loop and is shown with a
+/// spinner instead of a progress bar. Some never reach EOF (`/dev/zero`,
+/// `/dev/urandom`); `--max-bytes` stops reading after a set amount.
+///
+/// Attributes are never preserved: the mode and timestamps of a pipe say
+/// nothing about the data that went through it.
+pub fn copy_stream(source: &Path, destination: &Path, options: &CopyOptions) -> CopyResult<()> {
+    let refuse = |reason: &str| CopyError::CopyFailed {
+        source: source.to_path_buf(),
+        destination: destination.to_path_buf(),
+        reason: reason.to_string(),
+    };
+    if options.resume {
+        return Err(refuse("--resume can't be used with a pipe or device"));
+    }
+    if options.reflink == Some(ReflinkMode::Always) {
+        return Err(refuse(
+            "--reflink=always can't be used with a pipe or device",
+        ));
+    }
+    if options.symbolic_link.is_some() || options.hard_link {
+        return Err(refuse("can't link to a pipe or device"));
+    }
+
+    let destination = stream_destination(source, destination, options);
+    if options.dry_run {
+        let exists = destination.try_exists().unwrap_or(false);
+        itemize::print_item(&Item::stream(&destination, exists));
+        return Ok(());
+    }
+    let mut summary = Summary::default();
+    let result = write_stream(source, &destination, options, &mut summary);
+
+    if options.porcelain {
+        summary.files_failed = usize::from(result.is_err());
+        summary.interrupted = result.as_ref().is_err_and(CopyError::is_abort);
+        output::print_porcelain(&summary);
+    }
+    result
+}
+
+fn write_stream(
+    source: &Path,
+    destination: &Path,
+    options: &CopyOptions,
+    summary: &mut Summary,
+) -> CopyResult<()> {
+    if options.attributes_only {
+        return Ok(());
+    }
+
+    let exists = destination.try_exists().unwrap_or(false);
+    if options.interactive != Prompt::Never && exists && !prompt_overwrite(destination)? {
+        return Ok(());
+    }
+    if let Some(backup_mode) = options.backup
+        && backup_mode != BackupMode::None
+        && exists
+    {
+        let backup_path = back_up(destination, backup_mode, &options.backup_suffix)?;
+        if options.verbose {
+            output::verbose(None, destination, "~>", &backup_path);
+        }
+    }
+    clear_destination(destination, options)?;
+
+    let spinner = (options.progress_bar.mode != ProgressMode::Never).then(|| {
+        let pb = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+        options.progress_bar.apply_spinner(&pb);
+        pb
+    });
+
+    let result = copy_contents(source, destination, options, |bytes| {
+        summary.bytes_copied += bytes;
+        if let Some(pb) = &spinner {
+            pb.inc(bytes);
+        }
+    });
+    match spinner {
+        Some(pb) if result.is_err() => pb.abandon(),
+        Some(pb) => pb.finish_and_clear(),
+        None => {}
+    }
+    result?;
+
+    summary.files_copied = 1;
+    if options.verbose {
+        output::verbose(None, source, "->", destination);
+    }
+    if options.itemize {
+        itemize::print_done(&Item::stream(destination, exists));
+    }
+    Ok(())
+}
+
+/// Read the pipe or device `source` into a regular file at `destination`
+/// until EOF, or until `--max-bytes` have been read. `on_read` is told the
+/// size of every chunk.
+///
+/// An interrupted copy removes the partial file.
+pub fn copy_contents(
+    source: &Path,
+    destination: &Path,
+    options: &CopyOptions,
+    mut on_read: impl FnMut(u64),
+) -> CopyResult<u64> {
+    let mut src_file = open_stream(source, options).map_err(|error| {
+        if error.kind() == io::ErrorKind::Interrupted {
+            return CopyError::Io(error);
+        }
+        CopyError::OperationFailed {
+            operation: "open".to_string(),
+            path: source.to_path_buf(),
+            error,
+        }
+    })?;
+    let dest_file = open_destination(source, destination, options)?;
+    let mut dest_file = io::BufWriter::with_capacity(BUFFER_SIZE, dest_file);
+
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut copied = 0u64;
+    loop {
+        if options.abort.load(Ordering::Relaxed) {
+            drop(dest_file);
+            if std::fs::remove_file(destination).is_ok() {
+                output::status(format!(
+                    "Cleaned up incomplete file: {}",
+                    destination.display()
+                ));
+            }
+            return Err(CopyError::Io(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Operation aborted by user",
+            )));
+        }
+        let want = match options.max_bytes {
+            Some(limit) if copied >= limit => {
+                output::warning(format!(
+                    "stopped reading '{}' after {} (--max-bytes)",
+                    source.display(),
+                    HumanBytes(limit)
+                ));
+                break;
+            }
+            Some(limit) => buffer.len().min((limit - copied) as usize),
+            None => buffer.len(),
+        };
+
+        wait_readable(&src_file, &options.abort)?;
+        let bytes_read = match src_file.read(&mut buffer[..want]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(CopyError::Io(e)),
+        };
+        dest_file.write_all(&buffer[..bytes_read])?;
+        copied += bytes_read as u64;
+        on_read(bytes_read as u64);
+    }
+    dest_file.flush()?;
+    Ok(copied)
+}
+
+/// Open `source` for reading.
+///
+/// Opening a FIFO blocks until a writer shows up, so it happens on a helper
+/// thread while this one watches for Ctrl-C.
+fn open_stream(source: &Path, options: &CopyOptions) -> io::Result<File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+        use std::sync::mpsc::{RecvTimeoutError, channel};
+
+        if std::fs::metadata(source)?.file_type().is_fifo() {
+            let (tx, rx) = channel();
+            std::thread::spawn({
+                let source = source.to_path_buf();
+                move || {
+                    let _ = tx.send(File::open(source));
+                }
+            });
+            loop {
+                match rx.recv_timeout(std::time::Duration::from_millis(200)) {
+                    Ok(result) => return result,
+                    Err(RecvTimeoutError::Timeout) if options.abort.load(Ordering::Relaxed) => {
+                        // Release the waiting open by briefly being its writer.
+                        let _ = std::fs::OpenOptions::new()
+                            .write(true)
+                            .custom_flags(libc::O_NONBLOCK)
+                            .open(source);
+                        return Err(io::Error::new(
+                            io::ErrorKind::Interrupted,
+                            "Operation aborted by user",
+                        ));
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Err(io::Error::other("opening the pipe failed"));
+                    }
+                }
+            }
+        }
+    }
+    open_source(source, options.noatime)
+}
+
+/// Wait until `file` has data or hit EOF, checking `abort` in between.
+#[cfg(target_os = "linux")]
+fn wait_readable(file: &File, abort: &AtomicBool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut pollfd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    while !abort.load(Ordering::Relaxed) {
+        match unsafe { libc::poll(&mut pollfd, 1, 200) } {
+            0 => continue,
+            n if n > 0 => break,
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wait_readable(_file: &File, _abort: &AtomicBool) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn make_fifo(path: &Path) {
+        nix::unistd::mkfifo(path, nix::sys::stat::Mode::S_IRWXU).unwrap();
+    }
+
+    #[test]
+    fn test_fifo_and_char_devices_are_streams() {
+        let temp_dir = TempDir::new().unwrap();
+        let fifo = temp_dir.path().join("pipe");
+        make_fifo(&fifo);
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "data").unwrap();
+
+        assert!(is_stream(&fifo));
+        assert!(is_stream(Path::new("/dev/null")));
+        assert!(!is_stream(&file));
+        assert!(!is_stream(temp_dir.path()));
+    }
+
+    #[test]
+    fn test_copy_stream_reads_fifo_until_eof() {
+        let temp_dir = TempDir::new().unwrap();
+        let fifo = temp_dir.path().join("pipe");
+        make_fifo(&fifo);
+        let dest = temp_dir.path().join("out.bin");
+
+        let writer = std::thread::spawn({
+            let fifo = fifo.clone();
+            move || {
+                let mut pipe = fs::OpenOptions::new().write(true).open(&fifo).unwrap();
+                for _ in 0..100 {
+                    pipe.write_all(&[7u8; 1000]).unwrap();
+                }
+            }
+        });
+
+        let options = CopyOptions {
+            progress_bar: crate::utility::progress_bar::ProgressOptions {
+                mode: ProgressMode::Never,
+                ..Default::default()
+            },
+            ..CopyOptions::none()
+        };
+        copy_stream(&fifo, &dest, &options).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), vec![7u8; 100_000]);
+    }
+
+    #[test]
+    fn test_max_bytes_stops_an_endless_device() {
+        let temp_dir = TempDir::new().unwrap();
+        let dest = temp_dir.path().join("zeros.bin");
+        let options = CopyOptions {
+            max_bytes: Some(100_000),
+            ..CopyOptions::none()
+        };
+
+        let copied = copy_contents(Path::new("/dev/zero"), &dest, &options, |_| {}).unwrap();
+        assert_eq!(copied, 100_000);
+        assert_eq!(fs::read(&dest).unwrap(), vec![0u8; 100_000]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_abort_while_waiting_for_a_writer_removes_the_partial() {
+        let temp_dir = TempDir::new().unwrap();
+        let fifo = temp_dir.path().join("pipe");
+        make_fifo(&fifo);
+        let dest = temp_dir.path().join("out.bin");
+        let options = CopyOptions::none();
+
+        // Nobody ever writes to the pipe; only the abort ends the copy.
+        let abort = options.abort.clone();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            abort.store(true, Ordering::Relaxed);
+        });
+        let err = copy_contents(&fifo, &dest, &options, |_| {}).unwrap_err();
+        stopper.join().unwrap();
+
+        assert!(matches!(err, CopyError::Io(e) if e.kind() == io::ErrorKind::Interrupted));
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn test_copy_stream_refuses_resume() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = CopyOptions {
+            resume: true,
+            ..CopyOptions::none()
+        };
+        let err =
+            copy_stream(Path::new("/dev/null"), &temp_dir.path().join("x"), &options).unwrap_err();
+        assert!(err.to_string().contains("--resume"));
+    }
+
+    #[test]
+    fn test_stream_lands_inside_directory_destination() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = CopyOptions::none();
+        assert_eq!(
+            stream_destination(Path::new("/dev/stdin"), temp_dir.path(), &options),
+            temp_dir.path().join("stdin")
+        );
+        let file = temp_dir.path().join("out.bin");
+        assert_eq!(
+            stream_destination(Path::new("/dev/stdin"), &file, &options),
+            file
+        );
+    }
+}
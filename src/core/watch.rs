@@ -0,0 +1,356 @@
+use crate::cli::args::CopyOptions;
+use crate::core::copy::copy;
+use crate::error::{CopyError, CopyResult};
+use crate::utility::exclude::should_exclude;
+use crate::utility::helper::with_parents;
+use crate::utility::output;
+use notify::event::{MetadataKind, ModifyKind};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+/// How long the sources must stay quiet before a burst of events is applied.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// How often the abort flag is checked while no events arrive.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A watched source and the destination path it was copied to.
+#[derive(Debug, Clone)]
+pub struct WatchRoot {
+    source: PathBuf,
+    source_root: PathBuf,
+    destination: PathBuf,
+    is_dir: bool,
+}
+
+impl WatchRoot {
+    /// Destination for a changed path, or `None` if the path is outside this
+    /// root or excluded.
+    fn map(&self, path: &Path, options: &CopyOptions) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.source).ok()?;
+        if !self.is_dir && !relative.as_os_str().is_empty() {
+            return None;
+        }
+        if let Some(rules) = &options.exclude_rules
+            && path
+                .ancestors()
+                .take_while(|p| p.starts_with(&self.source))
+                .any(|p| should_exclude(p, &self.source_root, rules))
+        {
+            return None;
+        }
+        Some(self.destination.join(relative))
+    }
+}
+
+/// Totals reported when the watch stops.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WatchSummary {
+    pub updates_applied: usize,
+    pub updates_failed: usize,
+}
+
+/// Work out where each source lands, using the same rules as the initial copy.
+///
+/// Must be called before the initial copy, since whether the destination
+/// already exists decides where a single source ends up.
+pub fn watch_roots(
+    sources: &[PathBuf],
+    destination: &Path,
+    options: &CopyOptions,
+) -> CopyResult<Vec<WatchRoot>> {
+    let destination_is_dir = destination.is_dir();
+    let multiple = sources.len() > 1;
+
+    sources
+        .iter()
+        .map(|source| {
+            let source_metadata = std::fs::metadata(source)
+                .map_err(|_e| CopyError::InvalidSource(source.to_path_buf()))?;
+            let is_dir = source_metadata.is_dir();
+
+            let target = if options.parents {
+                with_parents(destination, source)
+            } else if is_dir || multiple || destination_is_dir {
+                let name = source
+                    .file_name()
+                    .ok_or_else(|| CopyError::InvalidSource(source.to_path_buf()))?;
+                destination.join(name)
+            } else {
+                destination.to_path_buf()
+            };
+
+            let canonical =
+                std::fs::canonicalize(source).map_err(|error| CopyError::OperationFailed {
+                    operation: "watch".to_string(),
+                    path: source.to_path_buf(),
+                    error,
+                })?;
+            // Excludes are matched relative to the copied directory, as in
+            // the initial copy.
+            let source_root = if is_dir {
+                canonical.clone()
+            } else {
+                canonical.parent().unwrap_or(&canonical).to_path_buf()
+            };
+
+            Ok(WatchRoot {
+                source: canonical,
+                source_root,
+                destination: target,
+                is_dir,
+            })
+        })
+        .collect()
+}
+
+/// Copy changes under `roots` until `options.abort` is set, keeping the
+/// destination in step with the sources after the initial copy. Each
+/// changed path is copied on its own rather than the whole tree planned
+/// again.
+///
+/// Failures are reported and counted but do not stop the watch. Deleted
+/// source paths are left alone at the destination.
+pub fn watch(roots: &[WatchRoot], options: &CopyOptions) -> CopyResult<WatchSummary> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(|e| watch_error(e, Path::new("")))?;
+
+    for root in roots {
+        // A single file is watched through its directory so editors that
+        // replace the file by renaming over it keep being noticed.
+        let (path, mode) = if root.is_dir {
+            (root.source.as_path(), RecursiveMode::Recursive)
+        } else {
+            (root.source_root.as_path(), RecursiveMode::NonRecursive)
+        };
+        watcher
+            .watch(path, mode)
+            .map_err(|e| watch_error(e, path))?;
+    }
+
+    // Never react to our own writes when a destination lives inside a source.
+    let destinations: Vec<PathBuf> = roots
+        .iter()
+        .filter_map(|root| std::fs::canonicalize(&root.destination).ok())
+        .collect();
+
+    let event_options = CopyOptions {
+        parents: false,
+        porcelain: false,
+        ..options.clone()
+    };
+
+    output::status("Watching for changes (press Ctrl-C to stop)");
+    let mut summary = WatchSummary::default();
+    let mut pending = BTreeSet::new();
+
+    while !options.abort.load(Ordering::Relaxed) {
+        let timeout = if pending.is_empty() {
+            POLL_INTERVAL
+        } else {
+            DEBOUNCE
+        };
+        match rx.recv_timeout(timeout) {
+            Ok(Ok(event)) => {
+                if is_relevant(&event) {
+                    pending.extend(
+                        event
+                            .paths
+                            .into_iter()
+                            .filter(|p| !destinations.iter().any(|d| p.starts_with(d))),
+                    );
+                }
+            }
+            Ok(Err(e)) => output::warning(format!("watch error: {}", e)),
+            Err(RecvTimeoutError::Timeout) => {
+                if !pending.is_empty() {
+                    apply_changes(
+                        std::mem::take(&mut pending),
+                        roots,
+                        &event_options,
+                        &mut summary,
+                    );
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    output::status(format!(
+        "\nWatch stopped: {} updates applied, {} failed",
+        summary.updates_applied, summary.updates_failed
+    ));
+    Ok(summary)
+}
+
+/// Whether an event may have changed file contents or the tree's shape.
+fn is_relevant(event: &Event) -> bool {
+    match event.kind {
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::AccessTime)) => false,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => true,
+        EventKind::Access(_) | EventKind::Any | EventKind::Other => false,
+    }
+}
+
+fn apply_changes(
+    paths: BTreeSet<PathBuf>,
+    roots: &[WatchRoot],
+    options: &CopyOptions,
+    summary: &mut WatchSummary,
+) {
+    // Paths sort parents first, so a new directory is copied whole and its
+    // children in the same burst are skipped.
+    let mut copied_dirs: Vec<PathBuf> = Vec::new();
+
+    for path in paths {
+        if options.abort.load(Ordering::Relaxed) {
+            return;
+        }
+        if copied_dirs.iter().any(|dir| path.starts_with(dir)) {
+            continue;
+        }
+        let Some(dest) = roots.iter().find_map(|root| root.map(&path, options)) else {
+            continue;
+        };
+        let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+            // Removed or renamed away; deletions are not mirrored.
+            continue;
+        };
+
+        let result = if metadata.is_dir() {
+            if dest.is_dir() {
+                continue;
+            }
+            copied_dirs.push(path.clone());
+            match dest.parent() {
+                Some(parent) => copy(&path, parent, options),
+                None => continue,
+            }
+        } else {
+            copy(&path, &dest, options)
+        };
+
+        match result {
+            Ok(()) => {
+                summary.updates_applied += 1;
+                output::status(format!("Updated {}", dest.display()));
+            }
+            Err(e) if e.is_abort() => return,
+            Err(e) => {
+                summary.updates_failed += 1;
+                output::error(format!("failed to update '{}': {}", dest.display(), e));
+            }
+        }
+    }
+}
+
+fn watch_error(error: notify::Error, path: &Path) -> CopyError {
+    let error = match error.kind {
+        notify::ErrorKind::Io(e) => e,
+        kind => io::Error::other(format!("{:?}", kind)),
+    };
+    CopyError::OperationFailed {
+        operation: "watch".to_string(),
+        path: path.to_path_buf(),
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::exclude::{build_exclude_rules, parse_exclude_pattern_list};
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    fn recursive_options() -> CopyOptions {
+        CopyOptions {
+            recursive: true,
+            ..CopyOptions::none()
+        }
+    }
+
+    #[test]
+    fn test_watch_root_maps_paths_under_directory_source() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("src");
+        fs::create_dir(&source).unwrap();
+        let dest = temp_dir.path().join("dst");
+        fs::create_dir(&dest).unwrap();
+
+        let mut options = recursive_options();
+        options.exclude_rules =
+            build_exclude_rules(parse_exclude_pattern_list("target").unwrap()).unwrap();
+
+        let roots = watch_roots(std::slice::from_ref(&source), &dest, &options).unwrap();
+        let root = &roots[0];
+        let canonical = fs::canonicalize(&source).unwrap();
+
+        assert_eq!(
+            root.map(&canonical.join("a/b.txt"), &options),
+            Some(dest.join("src/a/b.txt"))
+        );
+        assert_eq!(root.map(&canonical.join("target/out.o"), &options), None);
+        assert_eq!(root.map(&temp_dir.path().join("elsewhere"), &options), None);
+    }
+
+    #[test]
+    fn test_watch_root_single_file_maps_only_itself() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("notes.txt");
+        fs::write(&source, "v1").unwrap();
+        let dest = temp_dir.path().join("copy.txt");
+
+        let options = CopyOptions::none();
+        let roots = watch_roots(std::slice::from_ref(&source), &dest, &options).unwrap();
+        let canonical = fs::canonicalize(&source).unwrap();
+
+        assert_eq!(roots[0].map(&canonical, &options), Some(dest));
+        assert_eq!(
+            roots[0].map(&canonical.parent().unwrap().join("other.txt"), &options),
+            None
+        );
+    }
+
+    #[test]
+    fn test_watch_copies_changed_files_until_aborted() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("src");
+        fs::create_dir(&source).unwrap();
+        let dest = temp_dir.path().join("dst");
+        fs::create_dir(&dest).unwrap();
+        fs::create_dir(dest.join("src")).unwrap();
+
+        let abort = Arc::new(AtomicBool::new(false));
+        let options = CopyOptions {
+            abort: abort.clone(),
+            ..recursive_options()
+        };
+        let roots = watch_roots(std::slice::from_ref(&source), &dest, &options).unwrap();
+        let handle = std::thread::spawn(move || watch(&roots, &options));
+
+        // Give the watcher time to register before changing anything.
+        std::thread::sleep(Duration::from_millis(300));
+        fs::write(source.join("new.txt"), "hello").unwrap();
+
+        let copied = dest.join("src/new.txt");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while fs::read_to_string(&copied).ok().as_deref() != Some("hello") {
+            assert!(Instant::now() < deadline, "change was not mirrored");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        abort.store(true, Ordering::Relaxed);
+        let summary = handle.join().unwrap().unwrap();
+        assert!(summary.updates_applied >= 1);
+        assert_eq!(summary.updates_failed, 0);
+    }
+}
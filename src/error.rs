@@ -1,3 +1,4 @@
+use crate::utility::helper::describe_io_error;
 use std::fmt;
 use std::io;
 use std::path::PathBuf;
@@ -40,10 +41,17 @@ pub enum CopyError {
     HardlinkFailed {
         source: PathBuf,
         destination: PathBuf,
+        error: io::Error,
     },
     SymlinkFailed {
         source: PathBuf,
         destination: PathBuf,
+        error: io::Error,
+    },
+    OperationFailed {
+        operation: String,
+        path: PathBuf,
+        error: io::Error,
     },
     PreserveFailed(PreserveError),
 }
@@ -58,7 +66,11 @@ pub enum ExcludeError {
 pub enum PreserveError {
     Io(io::Error),
     UnsupportedAttribute(String),
-    FailedToPreserve { path: PathBuf, attribute: String },
+    FailedToPreserve {
+        path: PathBuf,
+        attribute: String,
+        error: io::Error,
+    },
 }
 
 impl fmt::Display for CpxError {
@@ -123,23 +135,40 @@ impl fmt::Display for CopyError {
             CopyError::HardlinkFailed {
                 source,
                 destination,
+                error,
             } => {
                 write!(
                     f,
-                    "Hardlink failed from '{}' to '{}'",
+                    "operation 'hardlink' on '{}' (source '{}'): {}",
+                    destination.display(),
                     source.display(),
-                    destination.display()
+                    describe_io_error(error)
                 )
             }
             CopyError::SymlinkFailed {
                 source,
                 destination,
+                error,
             } => {
                 write!(
                     f,
-                    "Symlink failed from '{}' to '{}'",
+                    "operation 'symlink' on '{}' (target '{}'): {}",
+                    destination.display(),
                     source.display(),
-                    destination.display()
+                    describe_io_error(error)
+                )
+            }
+            CopyError::OperationFailed {
+                operation,
+                path,
+                error,
+            } => {
+                write!(
+                    f,
+                    "operation '{}' on '{}': {}",
+                    operation,
+                    path.display(),
+                    describe_io_error(error)
                 )
             }
             CopyError::PreserveFailed(e) => write!(f, "Preserve failed: {}", e),
@@ -165,12 +194,17 @@ impl fmt::Display for PreserveError {
             PreserveError::UnsupportedAttribute(attr) => {
                 write!(f, "Unsupported preserve attribute: {}", attr)
             }
-            PreserveError::FailedToPreserve { path, attribute } => {
+            PreserveError::FailedToPreserve {
+                path,
+                attribute,
+                error,
+            } => {
                 write!(
                     f,
-                    "Failed to preserve '{}' for '{}'",
+                    "operation 'preserve {}' on '{}': {}",
                     attribute,
-                    path.display()
+                    path.display(),
+                    describe_io_error(error)
                 )
             }
         }
@@ -204,6 +238,10 @@ impl std::error::Error for CopyError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             CopyError::Io(e) => Some(e),
+            CopyError::HardlinkFailed { error, .. }
+            | CopyError::SymlinkFailed { error, .. }
+            | CopyError::OperationFailed { error, .. } => Some(error),
+            CopyError::PreserveFailed(e) => Some(e),
             _ => None,
         }
     }
@@ -222,6 +260,7 @@ impl std::error::Error for PreserveError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             PreserveError::Io(e) => Some(e),
+            PreserveError::FailedToPreserve { error, .. } => Some(error),
             _ => None,
         }
     }
@@ -274,11 +313,20 @@ impl CopyError {
             CopyError::InvalidDestination(_) => io::ErrorKind::NotFound,
             CopyError::CopyFailed { .. } => io::ErrorKind::Other,
             CopyError::ReflinkFailed { .. } => io::ErrorKind::Unsupported,
-            CopyError::HardlinkFailed { .. } => io::ErrorKind::Other,
-            CopyError::SymlinkFailed { .. } => io::ErrorKind::Other,
+            CopyError::HardlinkFailed { error, .. }
+            | CopyError::SymlinkFailed { error, .. }
+            | CopyError::OperationFailed { error, .. } => error.kind(),
             CopyError::PreserveFailed(_) => io::ErrorKind::Other,
         }
     }
+
+    /// Whether the copy stopped because it was told to. A signal that
+    /// merely interrupted a system call (EINTR) comes with an OS error
+    /// code; a deliberate stop is made up by cpx and has none.
+    pub fn is_abort(&self) -> bool {
+        matches!(self, CopyError::Io(e)
+            if e.kind() == io::ErrorKind::Interrupted && e.raw_os_error().is_none())
+    }
 }
 
 impl From<io::Error> for CopyError {
@@ -317,3 +365,19 @@ pub type ConfigResult<T> = Result<T, ConfigError>;
 pub type CopyResult<T> = Result<T, CopyError>;
 pub type ExcludeResult<T> = Result<T, ExcludeError>;
 pub type PreserveResult<T> = Result<T, PreserveError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_a_made_up_interrupt_is_an_abort() {
+        let aborted = CopyError::Io(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Operation aborted by user",
+        ));
+        assert!(aborted.is_abort());
+        let signalled = CopyError::Io(io::Error::from_raw_os_error(libc::EINTR));
+        assert!(!signalled.is_abort());
+    }
+}
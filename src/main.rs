@@ -1,12 +1,42 @@
-use cpx::cli::args::CLIArgs;
-use cpx::core::copy::{copy, multiple_copy};
+use cpx::cli::args::{CLIArgs, CopyOptions, NotifyOn};
+use cpx::core::copy::{CopySession, roll_back};
+use cpx::core::watch::{watch, watch_roots};
 use cpx::error::CpxError;
+use cpx::utility::journal::Journal;
+use cpx::utility::notify::{self, Outcome, Status};
+use cpx::utility::output::Summary;
+use cpx::utility::{background, limits, output};
+use indicatif::HumanBytes;
 use signal_hook::consts::signal::*;
 use signal_hook::iterator::Signals;
+use std::path::PathBuf;
 use std::process;
 
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// Exit statuses of a copy, as the README lists them. Everything copied is 0.
+
+/// Some files failed, or the copy failed after it had started.
+const FAILED: i32 = 1;
+/// The arguments were wrong, or named sources that could not be copied at
+/// all, so nothing was.
+const USAGE: i32 = 2;
+/// A run that stopped at `--max-bytes` or `--max-files`.
+const BUDGET_EXHAUSTED: i32 = 3;
+/// A run stopped by SIGINT or SIGTERM, left for `--resume`.
+const INTERRUPTED: i32 = 130;
+
+/// Environment handed to the command `--sudo` runs again, besides `LC_*`.
+#[cfg(unix)]
+const SUDO_ENVIRONMENT: &[&str] = &[
+    "CPX_CONFIG",
+    "CPX_JOURNAL",
+    "SOURCE_DATE_EPOCH",
+    "LANG",
+    "LANGUAGE",
+];
 
 fn main() {
     // custom parser
@@ -15,56 +45,299 @@ fn main() {
     let (sources, destination, mut options) = match args.validate() {
         Ok(validated) => validated,
         Err(e) => {
-            eprintln!("Error: {}", e);
-            process::exit(1);
+            output::error(e);
+            process::exit(USAGE);
         }
     };
 
+    let started = Instant::now();
+    let parallel = limits::fit_parallelism(options.parallel);
+    if parallel < options.parallel {
+        output::warning(format!(
+            "open file limit only allows {} parallel copies, using -j {} instead of -j {}",
+            parallel, parallel, options.parallel
+        ));
+        options.parallel = parallel;
+    }
+
+    // A dry run only prints, so it ends like any other printer when its
+    // reader goes away.
+    if options.dry_run {
+        output::restore_sigpipe();
+    }
+
+    // Before any thread starts, so that every worker inherits it.
+    if options.background {
+        if let Err(e) = background::lower_priority() {
+            output::warning(format!("could not lower the priority of the copy: {}", e));
+        }
+        output::status(format!(
+            "Note: running in the background (low CPU and I/O priority, -j {}), so the copy may be slow",
+            options.parallel
+        ));
+    }
+
     let abort = Arc::new(AtomicBool::new(false));
     options.abort = abort.clone();
 
-    let mut signals = Signals::new([SIGINT, SIGTERM])
+    // SIGUSR2 keeps its default meaning unless there is a limit to step.
+    let mut handled = vec![SIGINT, SIGTERM];
+    if options.bwlimit.is_some() {
+        handled.push(SIGUSR2);
+    }
+    let mut signals = Signals::new(handled)
         .map_err(CpxError::Io)
         .unwrap_or_else(|e| {
-            eprintln!("Failed to setup signal handler: {}", e);
-            process::exit(1);
+            output::error(format!("Failed to setup signal handler: {}", e));
+            process::exit(FAILED);
         });
 
     std::thread::spawn({
         let abort = abort.clone();
+        let bwlimit = options.bwlimit.clone();
         move || {
             for sig in signals.forever() {
                 match sig {
                     SIGINT | SIGTERM => {
                         abort.store(true, Ordering::Relaxed);
                     }
+                    SIGUSR2 => {
+                        if let Some(limit) = &bwlimit {
+                            let rate = limit.toggle_halved();
+                            output::status(format!("Bandwidth limit now {}/s", HumanBytes(rate)));
+                        }
+                    }
                     _ => unreachable!(),
                 }
             }
         }
     });
 
-    let result = if sources.len() == 1 {
-        copy(&sources[0], &destination, &options)
+    // Resolved before the initial copy, which may create the destination.
+    let roots = if options.watch {
+        match watch_roots(&sources, &destination, &options) {
+            Ok(roots) => Some(roots),
+            Err(e) => {
+                output::error(e);
+                process::exit(USAGE);
+            }
+        }
+    } else {
+        None
+    };
+
+    let notified_destination = destination.clone();
+    // Kept to find the one that was denied, should --sudo need to.
+    let given_sources = if options.sudo.is_some() {
+        sources.clone()
     } else {
-        multiple_copy(sources, destination, &options)
+        Vec::new()
     };
 
+    // Listed sources keep their relative paths, which only the multi-source
+    // path knows how to do, and it is the one that copies into -t DIR.
+    let mut session = CopySession::new();
+    let result =
+        if sources.len() == 1 && options.files_from_root.is_none() && !options.into_directory {
+            session.copy(&sources[0], &destination, &options)
+        } else {
+            session.copy_many(sources, destination, &options)
+        };
+    let created = session.created_roots();
+    let failed = if session.started() { FAILED } else { USAGE };
+    let summary = session.finish(&options);
+
+    // A copy that created directories is the last operation now; undo can
+    // remove them. One that created nothing leaves the journal to the move
+    // before it. An interrupted copy is left for --resume.
+    let rolled_back = result.is_err()
+        && options.rollback_on_error
+        && !abort.load(Ordering::Relaxed)
+        && offer_rollback(&created, &options);
+    let journal = Journal::for_copy(&created);
+    if !options.dry_run && !rolled_back && !journal.created.is_empty() {
+        let _ = journal.record();
+    }
+    notify(
+        &options,
+        &summary,
+        result.is_err(),
+        started.elapsed(),
+        &notified_destination,
+    );
+
     match result {
         Ok(_) => {
-            // normal
+            // Told apart from a failure, so a scheduler knows to run it again.
+            if summary.files_remaining > 0 {
+                output::status(format!(
+                    "Budget exhausted, {} files / {} remaining; run again with --resume to continue",
+                    summary.files_remaining,
+                    HumanBytes(summary.bytes_remaining)
+                ));
+                process::exit(BUDGET_EXHAUSTED);
+            }
+            if let Some(roots) = roots
+                && let Err(e) = watch(&roots, &options)
+            {
+                output::error(e);
+                process::exit(FAILED);
+            }
         }
         Err(e) => {
+            #[cfg(unix)]
+            if let Some(program) = &options.sudo
+                && let Some(path) = denied_source(&e, &given_sources)
+            {
+                offer_sudo(program, &path);
+            }
+            #[cfg(not(unix))]
+            let _ = given_sources;
+            if rolled_back {
+                output::status(format!("Error copying file: {}", e));
+                process::exit(FAILED);
+            }
             // interrupt check
             if abort.load(Ordering::Relaxed) {
-                eprintln!("\nOperation interrupted");
-                eprintln!("Resume with: cpx --resume [original command]");
-                eprintln!("Completed files will be skipped automatically");
-                process::exit(130); // SIGINT
+                output::status("\nOperation interrupted");
+                output::status("Resume with: cpx --resume [original command]");
+                output::status("Completed files will be skipped automatically");
+                process::exit(INTERRUPTED);
             } else {
-                eprintln!("Error copying file: {}", e);
-                process::exit(1);
+                output::status(format!("Error copying file: {}", e));
+                if limits::is_fd_exhaustion(&e) {
+                    output::status(format!("Hint: {}", limits::FD_EXHAUSTION_HINT));
+                }
+                process::exit(failed);
             }
         }
     }
 }
+
+/// The source the copy failed for lack of permission to read, if it did.
+#[cfg(unix)]
+fn denied_source(error: &cpx::error::CopyError, sources: &[PathBuf]) -> Option<PathBuf> {
+    if let cpx::error::CopyError::PermissionDenied(path) = error {
+        return Some(path.clone());
+    }
+    // A source that can't even be stat'ed fails before planning.
+    sources
+        .iter()
+        .find(|source| {
+            std::fs::symlink_metadata(source)
+                .is_err_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+        })
+        .cloned()
+}
+
+/// Offer to run the same command again with `program` after `path` could
+/// not be read. Never without asking on a terminal, whatever -y says; if
+/// the user agrees this doesn't return.
+#[cfg(unix)]
+fn offer_sudo(program: &str, path: &std::path::Path) {
+    use std::io::IsTerminal;
+    use std::os::unix::process::CommandExt;
+
+    output::error(format!("permission denied reading '{}'", path.display()));
+    if !std::io::stdin().is_terminal() {
+        output::status(format!(
+            "Not offering to run it again with {}: no terminal to ask on",
+            program
+        ));
+        return;
+    }
+    if !output::confirm(format!("Run the same command again with {}?", program)).unwrap_or(false) {
+        return;
+    }
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            output::error(format!("cannot find the cpx executable: {}", e));
+            return;
+        }
+    };
+    // sudo resets the environment, and doas has no way to keep parts of
+    // it, so what cpx reads is handed over through env(1).
+    let environment = std::env::vars_os().filter_map(|(name, value)| {
+        let keep = name
+            .to_str()
+            .is_some_and(|name| SUDO_ENVIRONMENT.contains(&name) || name.starts_with("LC_"));
+        keep.then(|| {
+            let mut pair = name;
+            pair.push("=");
+            pair.push(value);
+            pair
+        })
+    });
+    // The implicit `copy` is spelled out, so the owner can go before the
+    // original arguments, which may end in `--`.
+    let mut arguments = std::env::args_os().skip(1).peekable();
+    if arguments.peek().is_some_and(|first| first == "copy") {
+        arguments.next();
+    }
+    // SAFETY: getuid and getgid have no preconditions and cannot fail.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let error = process::Command::new(program)
+        .arg("env")
+        .args(environment)
+        .arg(exe)
+        .arg("copy")
+        .arg(format!("--sudo-for={}:{}", uid, gid))
+        .args(arguments)
+        .exec();
+    output::error(format!("cannot run {}: {}", program, error));
+}
+
+/// Run `--notify-cmd` and `--notify-desktop` for a run that ended as
+/// `summary` says, unless `--notify-on` rules it out.
+fn notify(
+    options: &CopyOptions,
+    summary: &Summary,
+    failed: bool,
+    elapsed: Duration,
+    destination: &std::path::Path,
+) {
+    if options.notify_cmd.is_none() && !options.notify_desktop {
+        return;
+    }
+    let status = Status::of(summary, failed);
+    if options.notify_on == NotifyOn::Error && status == Status::Success {
+        return;
+    }
+    let outcome = Outcome {
+        status,
+        summary,
+        elapsed,
+        destination,
+    };
+    if let Some(command) = &options.notify_cmd {
+        notify::run_hook(command, &outcome);
+    }
+    if options.notify_desktop {
+        notify::notify_desktop(&outcome);
+    }
+}
+
+/// Ask, unless told not to, before removing what a failed copy created.
+/// Returns whether it was removed.
+fn offer_rollback(created: &[std::path::PathBuf], options: &CopyOptions) -> bool {
+    if created.is_empty() {
+        output::status("Nothing to roll back: the copy created no directories");
+        return false;
+    }
+    for directory in created {
+        output::status(format!("remove '{}'", directory.display()));
+    }
+    let proceed = options.assume_yes
+        || output::confirm("The copy failed; remove what it created?").unwrap_or(false);
+    if !proceed {
+        return false;
+    }
+    let failed = roll_back(created);
+    output::status(format!(
+        "Rolled back {} of {} directories",
+        created.len() - failed,
+        created.len()
+    ));
+    failed == 0
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::path::Path;
+
+/// Workers used with `--background` unless `-j` says otherwise.
+pub const PARALLEL: usize = 2;
+
+/// Progress redraws per second with `--background`.
+pub const REFRESH_RATE: u8 = 2;
+
+/// Lower the CPU and I/O priority of the process and the threads it starts
+/// from now on, for `--background`.
+///
+/// This runs before any worker thread starts, so every worker inherits the
+/// lowest niceness and idle I/O priority: on Linux through `setpriority` and
+/// `ioprio_set`, on macOS with `setiopolicy_np`, and on Windows with
+/// `PROCESS_MODE_BACKGROUND_BEGIN`, which covers both at once.
+#[cfg(target_os = "linux")]
+pub fn lower_priority() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // SAFETY: both calls only change scheduling attributes of this thread,
+    // which threads created afterwards inherit.
+    unsafe {
+        if libc::setpriority(libc::PRIO_PROCESS, 0, 19) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub fn lower_priority() -> io::Result<()> {
+    const IOPOL_TYPE_DISK: libc::c_int = 0;
+    const IOPOL_SCOPE_PROCESS: libc::c_int = 0;
+    const IOPOL_THROTTLE: libc::c_int = 3;
+
+    unsafe extern "C" {
+        fn setiopolicy_np(
+            iotype: libc::c_int,
+            scope: libc::c_int,
+            policy: libc::c_int,
+        ) -> libc::c_int;
+    }
+
+    // SAFETY: plain calls changing the scheduling of this process.
+    unsafe {
+        if libc::setpriority(libc::PRIO_PROCESS, 0, 19) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if setiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_PROCESS, IOPOL_THROTTLE) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn lower_priority() -> io::Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, PROCESS_MODE_BACKGROUND_BEGIN, SetPriorityClass,
+    };
+
+    // SAFETY: GetCurrentProcess returns a pseudo handle that needs no closing.
+    if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn lower_priority() -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Tell the kernel the copied data at `source` and `destination` won't be
+/// read again soon, so a large backup doesn't push everything else out of
+/// memory. The destination is flushed first, since dirty pages can't be
+/// dropped.
+#[cfg(target_os = "linux")]
+pub fn drop_cache(source: &Path, destination: &Path) -> io::Result<()> {
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+
+    let advise = |file: &File| {
+        // SAFETY: the descriptor is open for the duration of the call.
+        match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    };
+    let written = File::open(destination)?;
+    written.sync_data()?;
+    advise(&written)?;
+    advise(&File::open(source)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_cache(_source: &Path, _destination: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_drop_cache_keeps_contents() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("a.bin");
+        let destination = temp_dir.path().join("b.bin");
+        std::fs::write(&source, vec![7u8; 64 * 1024]).unwrap();
+        std::fs::copy(&source, &destination).unwrap();
+
+        drop_cache(&source, &destination).unwrap();
+
+        assert_eq!(
+            std::fs::read(&destination).unwrap(),
+            std::fs::read(&source).unwrap()
+        );
+    }
+}
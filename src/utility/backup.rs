@@ -2,40 +2,98 @@ use crate::cli::args::BackupMode;
 use crate::error::{CopyError, CopyResult};
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-const DEFAULT_SUFFIX: &str = "~";
+pub const DEFAULT_SUFFIX: &str = "~";
 
-pub fn generate_backup_path(destination: &Path, mode: BackupMode) -> CopyResult<PathBuf> {
+/// Held from choosing a backup's name until the rename takes it, so that
+/// workers backing up the same name never pick the same number.
+static NAMING: Mutex<()> = Mutex::new(());
+
+/// The suffix of simple backups: `-S`, else `SIMPLE_BACKUP_SUFFIX` as cp
+/// reads it, else none. Like cp, an empty or unusable variable is ignored.
+pub fn suffix_from(cli: Option<&str>) -> Option<String> {
+    cli.map(str::to_string).or_else(|| {
+        std::env::var("SIMPLE_BACKUP_SUFFIX")
+            .ok()
+            .filter(|suffix| validate_suffix(suffix).is_ok())
+    })
+}
+
+/// A suffix must make a different name in the same directory.
+pub fn validate_suffix(suffix: &str) -> Result<(), String> {
+    if suffix.is_empty() {
+        return Err("the backup suffix can't be empty".to_string());
+    }
+    if suffix.contains('/') {
+        return Err(format!("the backup suffix '{}' can't contain '/'", suffix));
+    }
+    Ok(())
+}
+
+/// Where to move `destination` before it is replaced. Numbered backups
+/// ignore `suffix`.
+pub fn generate_backup_path(
+    destination: &Path,
+    mode: BackupMode,
+    suffix: &str,
+) -> CopyResult<PathBuf> {
     match mode {
         BackupMode::None => Ok(destination.to_path_buf()),
-        BackupMode::Simple => Ok(add_suffix(destination)),
+        BackupMode::Simple => simple_backup(destination, suffix),
         BackupMode::Numbered => {
-            let max_number =
-                find_max_backup_number(destination).map_err(|e| CopyError::CopyFailed {
-                    source: PathBuf::new(),
-                    destination: destination.to_path_buf(),
-                    reason: format!("Failed to find backup number: {}", e),
-                })?;
+            let max_number = find_max_backup_number(destination).map_err(|error| {
+                CopyError::OperationFailed {
+                    operation: "find backup number".to_string(),
+                    path: destination.to_path_buf(),
+                    error,
+                }
+            })?;
             Ok(format_numbered_backup(destination, max_number + 1))
         }
         BackupMode::Existing => {
-            let max_number =
-                find_max_backup_number(destination).map_err(|e| CopyError::CopyFailed {
-                    source: PathBuf::new(),
-                    destination: destination.to_path_buf(),
-                    reason: format!("Failed to find backup number: {}", e),
-                })?;
+            let max_number = find_max_backup_number(destination).map_err(|error| {
+                CopyError::OperationFailed {
+                    operation: "find backup number".to_string(),
+                    path: destination.to_path_buf(),
+                    error,
+                }
+            })?;
             if max_number > 0 {
                 Ok(format_numbered_backup(destination, max_number + 1))
             } else {
-                Ok(add_suffix(destination))
+                simple_backup(destination, suffix)
             }
         }
     }
 }
 
+/// `destination` with `suffix`, unless something already has that name:
+/// it may be an earlier backup, and a rename would replace it unasked.
+fn simple_backup(destination: &Path, suffix: &str) -> CopyResult<PathBuf> {
+    let backup = add_suffix(destination, suffix);
+    if std::fs::symlink_metadata(&backup).is_ok() {
+        return Err(CopyError::OperationFailed {
+            operation: "backup".to_string(),
+            path: destination.to_path_buf(),
+            error: io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "backup '{}' already exists; move it away or use --backup=numbered",
+                    backup.display()
+                ),
+            ),
+        });
+    }
+    Ok(backup)
+}
+
 fn find_max_backup_number(path: &Path) -> io::Result<u32> {
-    let parent = path.parent().unwrap_or_else(|| Path::new("."));
+    // A bare name's parent is "", which read_dir can't open.
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
     let file_name = path
         .file_name()
         .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?
@@ -60,9 +118,9 @@ fn find_max_backup_number(path: &Path) -> io::Result<u32> {
     Ok(max_number)
 }
 
-fn add_suffix(path: &Path) -> PathBuf {
+fn add_suffix(path: &Path, suffix: &str) -> PathBuf {
     let mut path_str = path.as_os_str().to_string_lossy().to_string();
-    path_str.push_str(DEFAULT_SUFFIX);
+    path_str.push_str(suffix);
     PathBuf::from(path_str)
 }
 
@@ -73,12 +131,21 @@ fn format_numbered_backup(path: &Path, number: u32) -> PathBuf {
 }
 
 pub fn create_backup(destination: &Path, backup_path: &PathBuf) -> CopyResult<()> {
-    std::fs::rename(destination, backup_path).map_err(|e| CopyError::CopyFailed {
-        source: destination.to_path_buf(),
-        destination: backup_path.clone(),
-        reason: format!("Failed to create backup: {}", e),
+    std::fs::rename(destination, backup_path).map_err(|error| CopyError::OperationFailed {
+        operation: format!("backup to {}", backup_path.display()),
+        path: destination.to_path_buf(),
+        error,
     })
 }
+
+/// Move `destination` out of the way as `mode` names it, returning where
+/// it went.
+pub fn back_up(destination: &Path, mode: BackupMode, suffix: &str) -> CopyResult<PathBuf> {
+    let _naming = NAMING.lock().unwrap_or_else(|e| e.into_inner());
+    let backup_path = generate_backup_path(destination, mode, suffix)?;
+    create_backup(destination, &backup_path)?;
+    Ok(backup_path)
+}
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -88,8 +155,10 @@ mod tests {
     #[test]
     fn test_add_suffix() {
         let path = Path::new("/tmp/file.txt");
-        let result = add_suffix(path);
+        let result = add_suffix(path, DEFAULT_SUFFIX);
         assert_eq!(result, PathBuf::from("/tmp/file.txt~"));
+        let result = add_suffix(path, ".orig");
+        assert_eq!(result, PathBuf::from("/tmp/file.txt.orig"));
     }
 
     #[test]
@@ -128,8 +197,8 @@ mod tests {
         let temp_dir = TempDir::new().unwrap();
         let file = temp_dir.path().join("test.txt");
 
-        let backup = generate_backup_path(&file, BackupMode::Simple).unwrap();
-        assert_eq!(backup, add_suffix(&file));
+        let backup = generate_backup_path(&file, BackupMode::Simple, "~").unwrap();
+        assert_eq!(backup, add_suffix(&file, "~"));
     }
 
     #[test]
@@ -137,12 +206,12 @@ mod tests {
         let temp_dir = TempDir::new().unwrap();
         let file = temp_dir.path().join("test.txt");
 
-        let backup1 = generate_backup_path(&file, BackupMode::Numbered).unwrap();
+        let backup1 = generate_backup_path(&file, BackupMode::Numbered, ".orig").unwrap();
         assert!(backup1.to_string_lossy().contains(".~1~"));
 
         fs::write(&backup1, "backup1").unwrap();
 
-        let backup2 = generate_backup_path(&file, BackupMode::Numbered).unwrap();
+        let backup2 = generate_backup_path(&file, BackupMode::Numbered, ".orig").unwrap();
         assert!(backup2.to_string_lossy().contains(".~2~"));
     }
 
@@ -152,8 +221,8 @@ mod tests {
         let file = temp_dir.path().join("test.txt");
         fs::write(&file, "content").unwrap();
 
-        let backup = generate_backup_path(&file, BackupMode::Existing).unwrap();
-        assert_eq!(backup, add_suffix(&file));
+        let backup = generate_backup_path(&file, BackupMode::Existing, "~").unwrap();
+        assert_eq!(backup, add_suffix(&file, "~"));
     }
 
     #[test]
@@ -165,7 +234,52 @@ mod tests {
         let backup1 = temp_dir.path().join("test.txt.~1~");
         fs::write(&backup1, "backup1").unwrap();
 
-        let backup = generate_backup_path(&file, BackupMode::Existing).unwrap();
+        let backup = generate_backup_path(&file, BackupMode::Existing, "~").unwrap();
         assert!(backup.to_string_lossy().contains(".~2~"));
     }
+
+    #[test]
+    fn test_simple_backup_keeps_an_earlier_one() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("test.txt");
+        fs::write(&file, "content").unwrap();
+        fs::write(temp_dir.path().join("test.txt.orig"), "earlier").unwrap();
+
+        let error = generate_backup_path(&file, BackupMode::Simple, ".orig").unwrap_err();
+        assert!(
+            error.to_string().starts_with(&format!(
+                "operation 'backup' on '{}': backup '",
+                file.display()
+            )),
+            "{}",
+            error
+        );
+        assert!(generate_backup_path(&file, BackupMode::Existing, ".orig").is_err());
+        let backup = generate_backup_path(&file, BackupMode::Simple, ".bak").unwrap();
+        assert_eq!(backup, temp_dir.path().join("test.txt.bak"));
+    }
+
+    #[test]
+    fn test_validate_suffix() {
+        assert!(validate_suffix(".orig").is_ok());
+        assert!(validate_suffix("").is_err());
+        assert!(validate_suffix("/bak").is_err());
+    }
+
+    #[test]
+    fn test_back_up_numbers_each_backup_of_a_name() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("test.txt");
+        for content in ["one", "two"] {
+            fs::write(&file, content).unwrap();
+            back_up(&file, BackupMode::Numbered, "~").unwrap();
+        }
+
+        assert!(!file.exists());
+        let backup = |n| fs::read_to_string(format_numbered_backup(&file, n)).unwrap();
+        assert_eq!(
+            (backup(1), backup(2)),
+            ("one".to_string(), "two".to_string())
+        );
+    }
 }
@@ -0,0 +1,152 @@
+use super::helper::retry_eintr;
+use super::output;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::UNIX_EPOCH;
+use xxhash_rust::xxh3::Xxh3;
+
+pub const CHECKSUM_ATTR: &str = "user.cpx.checksum";
+pub const SRC_MTIME_ATTR: &str = "user.cpx.src_mtime";
+
+const ALGORITHM: &str = "xxh3";
+
+static WARNED_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// XXH3 hash of everything in the file at `path`.
+pub fn xxh3_file(path: &Path) -> io::Result<u64> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0u8; 128 * 1024];
+
+    loop {
+        let bytes_read = retry_eintr(|| file.read(&mut buffer))?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+
+    Ok(hasher.digest())
+}
+
+/// The checksum of `path` in the stored format, e.g. `xxh3:00ff...`.
+pub fn file_checksum(path: &Path) -> io::Result<String> {
+    Ok(format!("{}:{:016x}", ALGORITHM, xxh3_file(path)?))
+}
+
+/// Hash `destination` and store the result, with the mtime of `source`, in
+/// its extended attributes, for `--store-checksum`. `cpx verify --stored`
+/// later rehashes the files and compares, so bit rot shows up without the
+/// original source at hand.
+///
+/// The hash is taken from the destination as written, whichever fast path
+/// wrote it.
+///
+/// Filesystems without extended attributes are warned about once and
+/// otherwise left alone.
+pub fn store_checksum(source: &Path, destination: &Path) -> io::Result<()> {
+    let checksum = file_checksum(destination)?;
+    let src_mtime = std::fs::metadata(source)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| format!("{}.{:09}", d.as_secs(), d.subsec_nanos()))
+        .unwrap_or_default();
+
+    let result = set_attr(destination, CHECKSUM_ATTR, &checksum)
+        .and_then(|()| set_attr(destination, SRC_MTIME_ATTR, &src_mtime));
+    match result {
+        Err(e) if is_unsupported(&e) => {
+            if !WARNED_UNSUPPORTED.swap(true, Ordering::Relaxed) {
+                output::warning(format!(
+                    "'{}' does not support extended attributes; checksums are not stored",
+                    destination.display()
+                ));
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// The checksum stored on `path`, if it has one.
+pub fn stored_checksum(path: &Path) -> io::Result<Option<String>> {
+    get_attr(path, CHECKSUM_ATTR)
+}
+
+fn is_unsupported(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::ENOTSUP) || e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        return true;
+    }
+    false
+}
+
+#[cfg(unix)]
+fn set_attr(path: &Path, name: &str, value: &str) -> io::Result<()> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Err(io::Error::from(io::ErrorKind::Unsupported));
+    }
+    xattr::set(path, name, value.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn set_attr(_path: &Path, _name: &str, _value: &str) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(unix)]
+fn get_attr(path: &Path, name: &str) -> io::Result<Option<String>> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(None);
+    }
+    match xattr::get(path, name) {
+        Ok(value) => Ok(value.map(|v| String::from_utf8_lossy(&v).into_owned())),
+        Err(e) if is_unsupported(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn get_attr(_path: &Path, _name: &str) -> io::Result<Option<String>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_checksum_is_prefixed_with_the_algorithm() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data.bin");
+        std::fs::write(&path, b"hello").unwrap();
+
+        let checksum = file_checksum(&path).unwrap();
+        assert_eq!(
+            checksum,
+            format!("xxh3:{:016x}", xxhash_rust::xxh3::xxh3_64(b"hello"))
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_stored_checksum_round_trips() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let destination = temp_dir.path().join("destination.txt");
+        std::fs::write(&source, "content").unwrap();
+        std::fs::write(&destination, "content").unwrap();
+
+        store_checksum(&source, &destination).unwrap();
+        if let Some(stored) = stored_checksum(&destination).unwrap() {
+            assert_eq!(stored, file_checksum(&destination).unwrap());
+            let mtime = xattr::get(&destination, SRC_MTIME_ATTR).unwrap().unwrap();
+            assert!(String::from_utf8(mtime).unwrap().contains('.'));
+        }
+    }
+}
@@ -0,0 +1,167 @@
+const USER: u32 = 0o4700;
+const GROUP: u32 = 0o2070;
+const OTHER: u32 = 0o1007;
+const ALL: u32 = 0o7777;
+
+/// A mode to give copies, whatever the source's was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModeSpec {
+    Octal(u32),
+    Symbolic(Vec<Clause>),
+}
+
+/// One clause of a symbolic mode, as `go-w`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clause {
+    /// Bits of the classes named, setuid, setgid and sticky among them.
+    who: u32,
+    actions: Vec<Action>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Action {
+    op: char,
+    /// Bits named, for every class.
+    bits: u32,
+    /// `X`: execute, if the file is a directory or already executable.
+    conditional_execute: bool,
+}
+
+impl ModeSpec {
+    /// Parse a `--chmod` or `--chmod-dir` mode: octal, as `644`, or symbolic
+    /// as chmod(1) takes it, as `u+rwX,go-w`.
+    ///
+    /// A symbolic mode is comma-separated clauses of who (`u`, `g`, `o`,
+    /// `a`), one or more operators (`+`, `-`, `=`) and the permissions each
+    /// one changes (`r`, `w`, `x`, `X`, `s`, `t`). A clause without who
+    /// changes everyone's; unlike chmod(1), the umask plays no part. `X` is
+    /// execute for directories and for files someone can already execute.
+    /// Copying one class's permissions to another, as `g=u`, is not
+    /// supported.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = |why: &str| {
+            format!(
+                "invalid mode '{}': {}; expected octal such as 644, or symbolic such as u+rwX,go-w",
+                spec, why
+            )
+        };
+        if !spec.is_empty() && spec.bytes().all(|b| b.is_ascii_digit()) {
+            return match u32::from_str_radix(spec, 8) {
+                Ok(mode) if mode <= ALL => Ok(Self::Octal(mode)),
+                _ => Err(invalid("not an octal mode up to 7777")),
+            };
+        }
+
+        let mut clauses = Vec::new();
+        for text in spec.split(',') {
+            let mut chars = text.chars().peekable();
+            let mut who = 0;
+            while let Some(&c) = chars.peek() {
+                who |= match c {
+                    'u' => USER,
+                    'g' => GROUP,
+                    'o' => OTHER,
+                    'a' => ALL,
+                    _ => break,
+                };
+                chars.next();
+            }
+            let mut actions: Vec<Action> = Vec::new();
+            for c in chars {
+                match (c, actions.last_mut()) {
+                    ('+' | '-' | '=', _) => actions.push(Action {
+                        op: c,
+                        bits: 0,
+                        conditional_execute: false,
+                    }),
+                    ('u' | 'g' | 'o', Some(_)) => {
+                        return Err(invalid(
+                            "copying permissions between classes is not supported",
+                        ));
+                    }
+                    (_, None) => return Err(invalid("each clause needs +, - or =")),
+                    (c, Some(action)) => match c {
+                        'r' => action.bits |= 0o444,
+                        'w' => action.bits |= 0o222,
+                        'x' => action.bits |= 0o111,
+                        'X' => action.conditional_execute = true,
+                        's' => action.bits |= 0o6000,
+                        't' => action.bits |= 0o1000,
+                        _ => return Err(invalid(&format!("unknown permission '{}'", c))),
+                    },
+                }
+            }
+            if actions.is_empty() {
+                return Err(invalid("each clause needs +, - or ="));
+            }
+            clauses.push(Clause {
+                who: if who == 0 { ALL } else { who },
+                actions,
+            });
+        }
+        Ok(Self::Symbolic(clauses))
+    }
+
+    /// The mode a file that has `mode` ends up with.
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        let clauses = match self {
+            Self::Octal(octal) => return (mode & !ALL) | octal,
+            Self::Symbolic(clauses) => clauses,
+        };
+        let executable = is_dir || mode & 0o111 != 0;
+        let mut mode = mode;
+        for clause in clauses {
+            for action in &clause.actions {
+                let mut bits = action.bits;
+                if action.conditional_execute && executable {
+                    bits |= 0o111;
+                }
+                let bits = bits & clause.who;
+                mode = match action.op {
+                    '+' => mode | bits,
+                    '-' => mode & !bits,
+                    _ => (mode & !clause.who) | bits,
+                };
+            }
+        }
+        mode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(spec: &str, mode: u32, is_dir: bool) -> u32 {
+        ModeSpec::parse(spec).unwrap().apply(mode, is_dir)
+    }
+
+    #[test]
+    fn test_octal_modes_replace_the_permissions() {
+        assert_eq!(mode("644", 0o100777, false), 0o100644);
+        assert_eq!(mode("0750", 0o40777, true), 0o40750);
+        assert_eq!(mode("4755", 0o644, false), 0o4755);
+        assert!(ModeSpec::parse("888").is_err());
+        assert!(ModeSpec::parse("17777").is_err());
+    }
+
+    #[test]
+    fn test_symbolic_modes_change_the_permissions() {
+        assert_eq!(mode("go-w", 0o777, false), 0o755);
+        assert_eq!(mode("u+rwX,go=rX", 0o600, false), 0o644);
+        assert_eq!(mode("u+rwX,go=rX", 0o700, false), 0o755);
+        assert_eq!(mode("u+rwX,go=rX", 0o600, true), 0o755);
+        assert_eq!(mode("a-x", 0o777, true), 0o666);
+        assert_eq!(mode("=r", 0o777, false), 0o444);
+        assert_eq!(mode("o+t", 0o777, true), 0o1777);
+        assert_eq!(mode("u+s,g-w+s", 0o775, false), 0o6755);
+        assert_eq!(mode("o=", 0o777, false), 0o770);
+    }
+
+    #[test]
+    fn test_unsupported_modes_are_refused() {
+        for spec in ["", "u", "u+q", "rw", "g=u", "u+r,", "+r,,go-w"] {
+            assert!(ModeSpec::parse(spec).is_err(), "{}", spec);
+        }
+    }
+}
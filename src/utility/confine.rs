@@ -0,0 +1,327 @@
+#[cfg(target_os = "linux")]
+use crate::utility::helper::temp_path_for;
+#[cfg(unix)]
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+#[cfg(unix)]
+use std::path::Component;
+use std::path::{Path, PathBuf};
+
+/// Create or truncate `path` for writing without following a symlink at or
+/// below `root`. `path` must lie under `root`.
+///
+/// Destination directories are created before the files in them, and anyone
+/// who can write to part of the destination could swap one of them for a
+/// symlink to `/etc` in between. So for a copy run as root, this walks down
+/// from `root` one directory at a time with `O_NOFOLLOW | O_DIRECTORY` and
+/// opens the file itself with `O_NOFOLLOW` relative to its parent; a
+/// component swapped for a symlink fails with `ELOOP` or `ENOTDIR` instead of
+/// being followed. `root` and everything above it are resolved normally,
+/// since they are the path the user gave.
+#[cfg(unix)]
+pub fn create_beneath(root: &Path, path: &Path) -> io::Result<File> {
+    open_beneath(root, path, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)
+}
+
+/// Open the existing `path` to be read and rewritten in place, without
+/// following a symlink at or below `root`.
+#[cfg(unix)]
+pub fn update_beneath(root: &Path, path: &Path) -> io::Result<File> {
+    open_beneath(root, path, libc::O_RDWR)
+}
+
+/// Clone `source` to `path` without following a symlink at or below
+/// `root`. An existing `path` is replaced by cloning next to it and
+/// renaming over it within the directory already opened.
+#[cfg(target_os = "linux")]
+pub fn clone_beneath(root: &Path, source: &Path, path: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (parent, name) = parent_beneath(root, path)?;
+    let source = File::open(source)?;
+    let exists = unsafe {
+        let mut stat: libc::stat = std::mem::zeroed();
+        libc::fstatat(
+            parent.as_raw_fd(),
+            name.as_ptr(),
+            &mut stat,
+            libc::AT_SYMLINK_NOFOLLOW,
+        ) == 0
+    };
+    let target = if exists {
+        CString::new(
+            temp_path_for(path)
+                .file_name()
+                .unwrap_or_default()
+                .as_bytes(),
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+    } else {
+        name.clone()
+    };
+    let file = openat(
+        &parent,
+        &target,
+        libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW,
+    )
+    .map_err(|e| swapped(path, e))?;
+    // SAFETY: both descriptors are open for the length of the call.
+    let cloned = unsafe { libc::ioctl(file.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    let result = if cloned < 0 {
+        Err(io::Error::last_os_error())
+    } else if exists {
+        // SAFETY: both names are NUL-terminated and relative to `parent`.
+        let renamed = unsafe {
+            libc::renameat(
+                parent.as_raw_fd(),
+                target.as_ptr(),
+                parent.as_raw_fd(),
+                name.as_ptr(),
+            )
+        };
+        match renamed {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    } else {
+        Ok(())
+    };
+    if result.is_err() {
+        // SAFETY: `target` is NUL-terminated and relative to `parent`.
+        unsafe { libc::unlinkat(parent.as_raw_fd(), target.as_ptr(), 0) };
+    }
+    result
+}
+
+/// No clone can be made from a directory descriptor here, so confined
+/// copies are never cloned.
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn clone_beneath(_root: &Path, _source: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "clones can't be confined to the destination on this system",
+    ))
+}
+
+/// The first directory that exists from `destination` up, which a copy
+/// into `destination` is confined to.
+pub fn confine_root(destination: &Path) -> Option<PathBuf> {
+    destination
+        .ancestors()
+        .find(|p| p.as_os_str().is_empty() || p.is_dir())
+        .map(PathBuf::from)
+}
+
+#[cfg(unix)]
+fn open_beneath(root: &Path, path: &Path, flags: libc::c_int) -> io::Result<File> {
+    let (parent, name) = parent_beneath(root, path)?;
+    let file = openat(&parent, &name, flags | libc::O_NOFOLLOW).map_err(|e| swapped(path, e))?;
+    Ok(File::from(file))
+}
+
+#[cfg(unix)]
+fn openat(dir: &OwnedFd, name: &CString, flags: libc::c_int) -> io::Result<OwnedFd> {
+    openat_raw(std::os::fd::AsRawFd::as_raw_fd(dir), name, flags)
+}
+
+#[cfg(unix)]
+fn openat_raw(dir: libc::c_int, name: &CString, flags: libc::c_int) -> io::Result<OwnedFd> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: `name` is NUL-terminated and `dir` an open directory or
+    // AT_FDCWD; a returned descriptor is owned by nobody else.
+    let fd = unsafe { libc::openat(dir, name.as_ptr(), flags | libc::O_CLOEXEC, 0o666) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// The directory `path` is in, opened by walking down from `root` one
+/// directory at a time without following symlinks, and the name of `path`
+/// in it.
+#[cfg(unix)]
+fn parent_beneath(root: &Path, path: &Path) -> io::Result<(OwnedFd, CString)> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::ffi::OsStrExt;
+
+    let relative = path.strip_prefix(root).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not under '{}'", path.display(), root.display()),
+        )
+    })?;
+    let mut names = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => names.push(
+                CString::new(name.as_bytes())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            ),
+            Component::CurDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' leaves '{}'", path.display(), root.display()),
+                ));
+            }
+        }
+    }
+    let Some(file_name) = names.pop() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' names no file", path.display()),
+        ));
+    };
+
+    let directory = libc::O_RDONLY | libc::O_DIRECTORY;
+    // A relative destination with no existing parent is confined to the
+    // current directory.
+    let root_name = if root.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        root
+    };
+    let root_name = CString::new(root_name.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut parent = openat_raw(libc::AT_FDCWD, &root_name, directory)?;
+    let mut walked = root.to_path_buf();
+    for name in &names {
+        walked.push(std::ffi::OsStr::from_bytes(name.as_bytes()));
+        parent = openat_raw(parent.as_raw_fd(), name, directory | libc::O_NOFOLLOW)
+            .map_err(|e| swapped(&walked, e))?;
+    }
+    Ok((parent, file_name))
+}
+
+#[cfg(not(unix))]
+pub fn create_beneath(_root: &Path, path: &Path) -> io::Result<File> {
+    File::create(path)
+}
+
+#[cfg(not(unix))]
+pub fn update_beneath(_root: &Path, path: &Path) -> io::Result<File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+pub fn clone_beneath(_root: &Path, source: &Path, path: &Path) -> io::Result<()> {
+    reflink_copy::reflink(source, path)
+}
+
+/// Name the component that turned out to be a symlink, which the bare
+/// errno doesn't.
+#[cfg(unix)]
+fn swapped(path: &Path, error: io::Error) -> io::Error {
+    match error.raw_os_error() {
+        Some(libc::ELOOP) | Some(libc::ENOTDIR) => io::Error::new(
+            error.kind(),
+            format!(
+                "refusing to follow '{}', which is no longer a plain directory or file ({})",
+                path.display(),
+                error
+            ),
+        ),
+        _ => error,
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_creates_files_in_real_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("dest");
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        let path = root.join("a/b/file.txt");
+        std::fs::write(&path, "old contents").unwrap();
+
+        create_beneath(&root, &path)
+            .unwrap()
+            .write_all(b"new")
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_swapped_directory_is_not_followed() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("dest");
+        let outside = temp_dir.path().join("outside");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::remove_dir(root.join("sub")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("sub")).unwrap();
+
+        let error = create_beneath(&root, &root.join("sub/passwd")).unwrap_err();
+
+        assert_eq!(error.raw_os_error(), None);
+        assert!(
+            error.to_string().contains("refusing to follow"),
+            "{}",
+            error
+        );
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_symlinked_file_is_not_followed() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("dest");
+        let target = temp_dir.path().join("shadow");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(&target, "keep").unwrap();
+        std::os::unix::fs::symlink(&target, root.join("file")).unwrap();
+
+        assert!(create_beneath(&root, &root.join("file")).is_err());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "keep");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_clones_and_updates_do_not_follow_a_swapped_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("dest");
+        let outside = temp_dir.path().join("outside");
+        let source = temp_dir.path().join("source");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(&source, "clone me").unwrap();
+        std::fs::write(outside.join("passwd"), "keep").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("sub")).unwrap();
+
+        for path in [root.join("sub/passwd"), root.join("sub/new")] {
+            let error = clone_beneath(&root, &source, &path).unwrap_err();
+            assert!(
+                error.to_string().contains("refusing to follow"),
+                "{}",
+                error
+            );
+        }
+        assert!(update_beneath(&root, &root.join("sub/passwd")).is_err());
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 1);
+        assert_eq!(
+            std::fs::read_to_string(outside.join("passwd")).unwrap(),
+            "keep"
+        );
+    }
+
+    #[test]
+    fn test_paths_leaving_the_root_are_refused() {
+        let temp_dir = TempDir::new().unwrap();
+        let error = create_beneath(temp_dir.path(), &temp_dir.path().join("../x")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,8 +1,7 @@
 use crate::error::{ExcludeError, ExcludeResult};
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use std::path::Component;
 use std::{
-    borrow::Cow,
     collections::HashSet,
     path::{Path, PathBuf},
 };
@@ -11,7 +10,60 @@ use std::{
 pub struct ExcludeRules {
     pub absolute_paths: Vec<PathBuf>,
     pub basenames: HashSet<String>,
-    pub glob_set: Option<GlobSet>,
+    /// Patterns that apply to files and directories alike.
+    pub any: PatternSet,
+    /// Patterns written with a trailing slash.
+    pub dirs_only: PatternSet,
+}
+
+/// Compiled globs, split by what they are matched against.
+#[derive(Debug, Clone, Default)]
+pub struct PatternSet {
+    /// Matched against a single file or directory name.
+    pub names: Option<GlobSet>,
+    /// Matched against a path relative to the source root.
+    pub paths: Option<GlobSet>,
+}
+
+impl PatternSet {
+    fn matches(&self, name: &str, relative: &str) -> bool {
+        self.names.as_ref().is_some_and(|set| set.is_match(name))
+            || self
+                .paths
+                .as_ref()
+                .is_some_and(|set| set.is_match(relative))
+    }
+}
+
+#[derive(Default)]
+struct PatternSetBuilder {
+    names: Option<GlobSetBuilder>,
+    paths: Option<GlobSetBuilder>,
+}
+
+impl PatternSetBuilder {
+    fn add(&mut self, pattern: &str, anchored: bool) -> ExcludeResult<()> {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| {
+                ExcludeError::InvalidPattern(format!("Invalid glob '{}': {}", pattern, e))
+            })?;
+        let builder = if anchored {
+            &mut self.paths
+        } else {
+            &mut self.names
+        };
+        builder.get_or_insert_with(GlobSetBuilder::new).add(glob);
+        Ok(())
+    }
+
+    fn build(self) -> ExcludeResult<PatternSet> {
+        Ok(PatternSet {
+            names: self.names.map(|b| b.build()).transpose()?,
+            paths: self.paths.map(|b| b.build()).transpose()?,
+        })
+    }
 }
 
 pub enum ExcludePattern {
@@ -39,6 +91,51 @@ impl ExcludePattern {
     }
 }
 
+/// A glob pattern with its slashes interpreted, following gitignore-like
+/// rules:
+///
+/// - a pattern without a slash (`*.tmp`, `build`) matches a file or
+///   directory name at any depth;
+/// - a pattern containing a slash (`docs/internal`) matches the path
+///   relative to the source root, and a leading slash (`/build`) anchors a
+///   pattern to the root without otherwise changing it;
+/// - a trailing slash (`build/`) matches directories only.
+///
+/// A match on a directory excludes everything below it. `*` and `?` never
+/// match `/` in anchored patterns; use `**` to cross directories.
+#[derive(Debug, PartialEq, Eq)]
+struct GlobRule {
+    glob: String,
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl GlobRule {
+    fn parse(raw: &str) -> ExcludeResult<Self> {
+        #[cfg(windows)]
+        let pattern = raw.replace('\\', "/");
+        #[cfg(not(windows))]
+        let pattern = raw;
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let (glob, anchored) = match pattern.strip_prefix('/') {
+            Some(rest) => (rest, true),
+            None => (pattern, pattern.contains('/')),
+        };
+        if glob.is_empty() {
+            return Err(ExcludeError::InvalidPattern(format!(
+                "pattern '{}' does not match anything",
+                raw
+            )));
+        }
+        Ok(GlobRule {
+            glob: glob.to_string(),
+            anchored,
+            dir_only,
+        })
+    }
+}
+
 pub fn parse_exclude_pattern_list(input: &str) -> ExcludeResult<Vec<ExcludePattern>> {
     let mut patterns = Vec::new();
 
@@ -70,11 +167,24 @@ pub fn build_exclude_rules(patterns: Vec<ExcludePattern>) -> ExcludeResult<Optio
     }
     let mut absolute_paths = Vec::new();
     let mut basenames = HashSet::new();
-    let mut glob_builder = GlobSetBuilder::new();
-    let mut has_globs = false;
+    let mut any = PatternSetBuilder::default();
+    let mut dirs_only = PatternSetBuilder::default();
     for pattern in patterns {
         match pattern {
             ExcludePattern::AbsolutePath(path) => {
+                // `/build` can't be told apart from an absolute path, so it
+                // is also tried as a pattern anchored to the source root.
+                if let Some(text) = path.to_str()
+                    && text.starts_with('/')
+                    && let Ok(rule) = GlobRule::parse(text)
+                {
+                    let set = if rule.dir_only {
+                        &mut dirs_only
+                    } else {
+                        &mut any
+                    };
+                    let _ = set.add(&rule.glob, true);
+                }
                 let canonical = path.canonicalize().unwrap_or(path);
                 absolute_paths.push(canonical);
             }
@@ -82,47 +192,28 @@ pub fn build_exclude_rules(patterns: Vec<ExcludePattern>) -> ExcludeResult<Optio
                 basenames.insert(name);
             }
             ExcludePattern::GlobPattern(pattern) => {
-                let glob = Glob::new(&pattern).map_err(|e| {
-                    ExcludeError::InvalidPattern(format!("Invalid glob '{}': {}", pattern, e))
-                })?;
-                glob_builder.add(glob);
-                has_globs = true;
+                let rule = GlobRule::parse(&pattern)?;
+                let set = if rule.dir_only {
+                    &mut dirs_only
+                } else {
+                    &mut any
+                };
+                set.add(&rule.glob, rule.anchored)?;
             }
         }
     }
     absolute_paths.sort_unstable_by_key(|b| std::cmp::Reverse(b.as_os_str().len()));
-    let glob_set = if has_globs {
-        Some(glob_builder.build()?)
-    } else {
-        None
-    };
     Ok(Some(ExcludeRules {
         absolute_paths,
         basenames,
-        glob_set,
+        any: any.build()?,
+        dirs_only: dirs_only.build()?,
     }))
 }
 
+/// Whether `path`, or any directory between `source_root` and it, is
+/// excluded by `rules`.
 pub fn should_exclude(path: &Path, source_root: &Path, rules: &ExcludeRules) -> bool {
-    // Check basename of the path itself
-    if let Some(name) = path.file_name().and_then(|n| n.to_str())
-        && rules.basenames.contains(name)
-    {
-        return true;
-    }
-
-    // Check if any parent directory (between source_root and path) has an excluded basename
-    // This ensures that files inside excluded directories are also excluded
-    let relative = path.strip_prefix(source_root).unwrap_or(path);
-    for component in relative.components() {
-        if let std::path::Component::Normal(os_str) = component
-            && let Some(name) = os_str.to_str()
-            && rules.basenames.contains(name)
-        {
-            return true;
-        }
-    }
-
     // Check absolute paths
     if !rules.absolute_paths.is_empty() {
         let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
@@ -136,23 +227,29 @@ pub fn should_exclude(path: &Path, source_root: &Path, rules: &ExcludeRules) ->
         }
     }
 
-    // Check glob patterns
-    if let Some(glob_set) = &rules.glob_set {
-        let relative = path.strip_prefix(source_root).unwrap_or(path);
-        let mut rel_str: Cow<str> = relative.to_string_lossy();
-        if rel_str.contains('\\') {
-            rel_str = Cow::Owned(rel_str.replace('\\', "/"));
+    // Walk down from the source root, so that a match on any directory on
+    // the way also excludes everything inside it.
+    let relative = path.strip_prefix(source_root).unwrap_or(path);
+    let names: Vec<_> = relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    let mut prefix = String::new();
+    for (i, name) in names.iter().enumerate() {
+        if !prefix.is_empty() {
+            prefix.push('/');
         }
-        if glob_set.is_match(&*rel_str) {
+        prefix.push_str(name);
+
+        if rules.basenames.contains(name.as_ref()) || rules.any.matches(name, &prefix) {
             return true;
         }
-        if path.is_dir() {
-            let mut with_slash = String::with_capacity(rel_str.len() + 1);
-            with_slash.push_str(&rel_str);
-            with_slash.push('/');
-            if glob_set.is_match(&with_slash) {
-                return true;
-            }
+        // Everything above `path` itself is a directory.
+        if rules.dirs_only.matches(name, &prefix) && (i + 1 < names.len() || path.is_dir()) {
+            return true;
         }
     }
 
@@ -305,4 +402,109 @@ mod exclude_tests {
 
         assert!(should_exclude(&dir_path, temp_dir.path(), &rules));
     }
+
+    fn rules_for(input: &str) -> ExcludeRules {
+        build_exclude_rules(parse_exclude_pattern_list(input).unwrap())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_name_without_slash_matches_at_any_depth() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_file(&root.join("build").join("a.o"), b"");
+        create_file(&root.join("src").join("build").join("b.o"), b"");
+        create_file(&root.join("src").join("main.rs"), b"");
+
+        let rules = rules_for("build");
+        assert!(should_exclude(&root.join("build"), root, &rules));
+        assert!(should_exclude(&root.join("build/a.o"), root, &rules));
+        assert!(should_exclude(&root.join("src/build/b.o"), root, &rules));
+        assert!(!should_exclude(&root.join("src/main.rs"), root, &rules));
+    }
+
+    #[test]
+    fn test_glob_without_slash_matches_names_at_any_depth() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let rules = rules_for("*.log");
+        assert!(should_exclude(&root.join("a.log"), root, &rules));
+        assert!(should_exclude(&root.join("x/y/z.log"), root, &rules));
+        assert!(!should_exclude(&root.join("x/log.txt"), root, &rules));
+    }
+
+    #[test]
+    fn test_pattern_with_slash_matches_relative_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_file(&root.join("docs/internal/notes.md"), b"");
+        create_file(&root.join("docs/public/index.md"), b"");
+        create_file(&root.join("vendor/docs/internal/x.md"), b"");
+
+        let rules = rules_for("docs/internal");
+        assert!(should_exclude(&root.join("docs/internal"), root, &rules));
+        assert!(should_exclude(
+            &root.join("docs/internal/notes.md"),
+            root,
+            &rules
+        ));
+        assert!(!should_exclude(
+            &root.join("docs/public/index.md"),
+            root,
+            &rules
+        ));
+        assert!(!should_exclude(
+            &root.join("vendor/docs/internal/x.md"),
+            root,
+            &rules
+        ));
+    }
+
+    #[test]
+    fn test_star_does_not_cross_directories_in_path_patterns() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let rules = rules_for("src/*.rs");
+        assert!(should_exclude(&root.join("src/main.rs"), root, &rules));
+        assert!(!should_exclude(&root.join("src/cli/args.rs"), root, &rules));
+
+        let rules = rules_for("src/**/*.rs");
+        assert!(should_exclude(&root.join("src/cli/args.rs"), root, &rules));
+    }
+
+    #[test]
+    fn test_leading_slash_anchors_to_root() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_file(&root.join("build/a.o"), b"");
+        create_file(&root.join("src/build/b.o"), b"");
+
+        let rules = rules_for("/build");
+        assert!(should_exclude(&root.join("build"), root, &rules));
+        assert!(should_exclude(&root.join("build/a.o"), root, &rules));
+        assert!(!should_exclude(&root.join("src/build"), root, &rules));
+        assert!(!should_exclude(&root.join("src/build/b.o"), root, &rules));
+    }
+
+    #[test]
+    fn test_trailing_slash_matches_directories_only() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_file(&root.join("cache/data.bin"), b"");
+        create_file(&root.join("src/cache"), b"a file named cache");
+
+        let rules = rules_for("cache/");
+        assert!(should_exclude(&root.join("cache"), root, &rules));
+        assert!(should_exclude(&root.join("cache/data.bin"), root, &rules));
+        assert!(!should_exclude(&root.join("src/cache"), root, &rules));
+
+        let rules = rules_for("/cache/");
+        assert!(should_exclude(&root.join("cache/data.bin"), root, &rules));
+    }
+
+    #[test]
+    fn test_root_itself_is_not_a_pattern() {
+        assert!(build_exclude_rules(vec![ExcludePattern::GlobPattern("//".to_string())]).is_err());
+    }
 }
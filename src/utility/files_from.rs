@@ -0,0 +1,135 @@
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Read the list at `list` (`-` for stdin) and resolve it against `root`.
+pub fn read_files_from(list: &Path, root: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut content = String::new();
+    let result = if list == Path::new("-") {
+        io::stdin().read_to_string(&mut content)
+    } else {
+        std::fs::File::open(list).and_then(|mut f| f.read_to_string(&mut content))
+    };
+    result.map_err(|e| format!("cannot read --files-from '{}': {}", list.display(), e))?;
+    parse_files_from(&content, root)
+}
+
+/// The sources named by `content`, each joined onto `root`.
+///
+/// The list has one path per line; empty lines and lines starting with `#`
+/// or `;` are ignored, as with rsync. Each path is recreated under the
+/// destination with the same relative path, so `photos/2021/a.jpg` lands in
+/// `DEST/photos/2021/a.jpg`.
+pub fn parse_files_from(content: &str, root: &Path) -> Result<Vec<PathBuf>, String> {
+    let absolute_root = std::path::absolute(root)
+        .map_err(|e| format!("invalid --files-from-root '{}': {}", root.display(), e))?;
+
+    let mut sources = Vec::new();
+    for line in content.lines() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        let relative = relative_to_root(Path::new(line), &absolute_root)?;
+        if relative.as_os_str().is_empty() {
+            return Err(format!("'{}' names --files-from-root itself", line));
+        }
+        sources.push(if root == Path::new(".") {
+            relative
+        } else {
+            root.join(relative)
+        });
+    }
+    if sources.is_empty() {
+        return Err("--files-from list names no files".to_string());
+    }
+    Ok(sources)
+}
+
+/// `path` as a plain relative path under `root`, which must be absolute.
+///
+/// An absolute path is accepted when it lies under the root, which is then
+/// stripped from it; any other absolute path is rejected, as is a path that
+/// climbs out of the root with `..`.
+fn relative_to_root(path: &Path, root: &Path) -> Result<PathBuf, String> {
+    let path = if path.is_absolute() {
+        normalize(path)
+            .strip_prefix(normalize(root))
+            .map(Path::to_path_buf)
+            .map_err(|_| {
+                format!(
+                    "'{}' is not under --files-from-root '{}'",
+                    path.display(),
+                    root.display()
+                )
+            })?
+    } else {
+        path.to_path_buf()
+    };
+
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            _ => {
+                return Err(format!(
+                    "'{}' leaves --files-from-root; '..' is not allowed",
+                    path.display()
+                ));
+            }
+        }
+    }
+    Ok(relative)
+}
+
+/// Drop `.` components, without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_are_resolved_against_the_root() {
+        let sources = parse_files_from(
+            "photos/2021/a.jpg\n# comment\n\n./photos/b.jpg\r\n",
+            "/data".as_ref(),
+        )
+        .unwrap();
+        assert_eq!(
+            sources,
+            vec![
+                PathBuf::from("/data/photos/2021/a.jpg"),
+                PathBuf::from("/data/photos/b.jpg"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_current_directory_root_keeps_paths_relative() {
+        let sources = parse_files_from("photos/a.jpg\n", ".".as_ref()).unwrap();
+        assert_eq!(sources, vec![PathBuf::from("photos/a.jpg")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_absolute_entries_must_be_under_the_root() {
+        let sources = parse_files_from("/data/photos/a.jpg\n", "/data/".as_ref()).unwrap();
+        assert_eq!(sources, vec![PathBuf::from("/data/photos/a.jpg")]);
+
+        let err = parse_files_from("/etc/passwd\n", "/data".as_ref()).unwrap_err();
+        assert_eq!(err, "'/etc/passwd' is not under --files-from-root '/data'");
+    }
+
+    #[test]
+    fn test_entries_cannot_leave_the_root() {
+        let err = parse_files_from("photos/../../secret\n", "/data".as_ref()).unwrap_err();
+        assert!(err.contains("'..' is not allowed"));
+        let err = parse_files_from("# nothing\n", "/data".as_ref()).unwrap_err();
+        assert_eq!(err, "--files-from list names no files");
+    }
+}
@@ -0,0 +1,607 @@
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsKind {
+    Ext4,
+    Btrfs,
+    Xfs,
+    Zfs,
+    F2fs,
+    Bcachefs,
+    Tmpfs,
+    Overlay,
+    Nfs,
+    Cifs,
+    Smb2,
+    Sshfs,
+    Fuse,
+    Fat,
+    Exfat,
+    Ntfs,
+    Apfs,
+    Hfs,
+    Proc,
+    Sysfs,
+    Unknown,
+}
+
+impl FsKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FsKind::Ext4 => "ext2/3/4",
+            FsKind::Btrfs => "btrfs",
+            FsKind::Xfs => "xfs",
+            FsKind::Zfs => "zfs",
+            FsKind::F2fs => "f2fs",
+            FsKind::Bcachefs => "bcachefs",
+            FsKind::Tmpfs => "tmpfs",
+            FsKind::Overlay => "overlayfs",
+            FsKind::Nfs => "nfs",
+            FsKind::Cifs => "cifs",
+            FsKind::Smb2 => "smb2",
+            FsKind::Sshfs => "sshfs",
+            FsKind::Fuse => "fuse",
+            FsKind::Fat => "fat",
+            FsKind::Exfat => "exfat",
+            FsKind::Ntfs => "ntfs",
+            FsKind::Apfs => "apfs",
+            FsKind::Hfs => "hfs",
+            FsKind::Proc => "proc",
+            FsKind::Sysfs => "sysfs",
+            FsKind::Unknown => "unknown",
+        }
+    }
+
+    /// Whether files live on another machine, where every metadata call is
+    /// a round trip and the server serializes much of the work.
+    pub fn is_network(&self) -> bool {
+        matches!(
+            self,
+            FsKind::Nfs | FsKind::Cifs | FsKind::Smb2 | FsKind::Sshfs
+        )
+    }
+
+    /// Largest file the filesystem can hold, when that is small enough to
+    /// matter. FAT keeps sizes in 32 bits; exFAT, NTFS and the native
+    /// filesystems allow far more than anything we'd copy.
+    pub fn max_file_size(&self) -> Option<u64> {
+        match self {
+            FsKind::Fat => Some(u64::from(u32::MAX)),
+            _ => None,
+        }
+    }
+}
+
+/// Which kinds of links a destination filesystem can store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LinkSupport {
+    pub kind: FsKind,
+    pub symlinks: bool,
+    pub hardlinks: bool,
+}
+
+impl LinkSupport {
+    pub fn for_kind(kind: FsKind) -> Self {
+        // FAT and exFAT have no way to store either kind of link. Anything
+        // else, including filesystems we can't identify, is given the benefit
+        // of the doubt.
+        let links = !matches!(kind, FsKind::Fat | FsKind::Exfat);
+        Self {
+            kind,
+            symlinks: links,
+            hardlinks: links,
+        }
+    }
+}
+
+/// Link support of the filesystem `path` lives on, checked on its nearest
+/// existing ancestor so a destination that is about to be created works too.
+pub fn link_support(path: &Path) -> LinkSupport {
+    LinkSupport::for_kind(filesystem_family(path))
+}
+
+/// Kind of the filesystem `path` lives on, checked on its nearest existing
+/// ancestor; `Unknown` when it can't be told.
+pub fn filesystem_family(path: &Path) -> FsKind {
+    nearest_existing(path)
+        .and_then(|p| filesystem_kind(p).ok())
+        .unwrap_or(FsKind::Unknown)
+}
+
+/// `path` or its closest ancestor that exists.
+fn nearest_existing(path: &Path) -> Option<&Path> {
+    path.ancestors()
+        .map(|p| {
+            if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p
+            }
+        })
+        .find(|p| p.exists())
+}
+
+/// The disk a filesystem is stored on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockDevice {
+    /// Kernel name of the whole disk, e.g. `sda` or `nvme0n1`, so that two
+    /// partitions of one disk count as the same device.
+    pub name: String,
+    /// Whether it is a spinning disk; `None` when the kernel doesn't say.
+    pub rotational: Option<bool>,
+}
+
+impl BlockDevice {
+    /// e.g. `sda (rotational)`.
+    pub fn describe(&self) -> String {
+        match self.rotational {
+            Some(true) => format!("{} (rotational)", self.name),
+            Some(false) => format!("{} (SSD)", self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// The disk behind the filesystem `path` (or its nearest existing ancestor)
+/// lives on, if it is backed by one.
+///
+/// The device number of the file is looked up in `/sys/dev/block`.
+/// Filesystems such as btrfs give their files an anonymous device number,
+/// so for those the mount table is walked for the device the filesystem
+/// was mounted from instead.
+#[cfg(target_os = "linux")]
+pub fn block_device(path: &Path) -> io::Result<Option<BlockDevice>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let Some(existing) = nearest_existing(path) else {
+        return Ok(None);
+    };
+    let dev = std::fs::metadata(existing)?.dev();
+    let sys_dir = match sysfs_block_dir(dev) {
+        Some(dir) => dir,
+        None => {
+            let canonical = std::fs::canonicalize(existing)?;
+            let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+            let Some(source) = mount_source(&mountinfo, &canonical) else {
+                return Ok(None);
+            };
+            match std::fs::metadata(source)
+                .ok()
+                .and_then(|m| sysfs_block_dir(m.rdev()))
+            {
+                Some(dir) => dir,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    // A partition's directory sits inside the one of its disk.
+    let disk = if sys_dir.join("partition").exists() {
+        sys_dir.parent().map(Path::to_path_buf).unwrap_or(sys_dir)
+    } else {
+        sys_dir
+    };
+    let Some(name) = disk.file_name() else {
+        return Ok(None);
+    };
+    let rotational = std::fs::read_to_string(disk.join("queue/rotational"))
+        .ok()
+        .map(|flag| flag.trim() == "1");
+    Ok(Some(BlockDevice {
+        name: name.to_string_lossy().into_owned(),
+        rotational,
+    }))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn block_device(_path: &Path) -> io::Result<Option<BlockDevice>> {
+    Ok(None)
+}
+
+/// The disk both `source` and `destination` are on, if they share one.
+pub fn shared_device(source: &Path, destination: &Path) -> Option<BlockDevice> {
+    let source = block_device(source).ok()??;
+    let destination = block_device(destination).ok()??;
+    (source.name == destination.name).then_some(source)
+}
+
+#[cfg(target_os = "linux")]
+fn sysfs_block_dir(dev: u64) -> Option<PathBuf> {
+    let link = format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev));
+    std::fs::canonicalize(link).ok()
+}
+
+/// The device the filesystem holding `path` was mounted from, found in the
+/// contents of `/proc/self/mountinfo`. `path` must be canonical.
+#[cfg(any(target_os = "linux", test))]
+fn mount_source(mountinfo: &str, path: &Path) -> Option<PathBuf> {
+    mount_entry(mountinfo, path)
+        .map(|entry| entry.source)
+        .filter(|source| source.starts_with("/dev/"))
+        .map(PathBuf::from)
+}
+
+/// One line of the mount table.
+#[cfg(any(target_os = "linux", test))]
+struct MountEntry {
+    fs_type: String,
+    source: String,
+}
+
+/// The mount holding `path` in the contents of `/proc/self/mountinfo`.
+/// `path` must be canonical.
+#[cfg(any(target_os = "linux", test))]
+fn mount_entry(mountinfo: &str, path: &Path) -> Option<MountEntry> {
+    let mut best: Option<(PathBuf, &str, &str)> = None;
+    for line in mountinfo.lines() {
+        // ID PARENT MAJ:MIN ROOT MOUNT_POINT OPTIONS [OPTIONAL...] - TYPE SOURCE SUPER_OPTIONS
+        let fields: Vec<&str> = line.split(' ').collect();
+        let Some(separator) = fields.iter().position(|f| *f == "-") else {
+            continue;
+        };
+        let (Some(mount_point), Some(fs_type), Some(source)) = (
+            fields.get(4),
+            fields.get(separator + 1),
+            fields.get(separator + 2),
+        ) else {
+            continue;
+        };
+        let mount_point = PathBuf::from(unescape_mount_path(mount_point));
+        // Later mounts hide earlier ones on the same point.
+        if path.starts_with(&mount_point)
+            && best
+                .as_ref()
+                .is_none_or(|(p, _, _)| mount_point.as_os_str().len() >= p.as_os_str().len())
+        {
+            best = Some((mount_point, fs_type, source));
+        }
+    }
+    best.map(|(_, fs_type, source)| MountEntry {
+        fs_type: unescape_mount_path(fs_type),
+        source: unescape_mount_path(source),
+    })
+}
+
+/// Undo the octal escapes (`\040` for a space) the mount table uses.
+#[cfg(any(target_os = "linux", test))]
+fn unescape_mount_path(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(code) = field
+                .get(i + 1..i + 4)
+                .and_then(|octal| u8::from_str_radix(octal, 8).ok())
+        {
+            out.push(code);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Maps a Linux `statfs.f_type` magic number to a filesystem family.
+pub fn fs_kind_from_magic(magic: u32) -> FsKind {
+    match magic {
+        0xEF53 => FsKind::Ext4,
+        0x9123_683E => FsKind::Btrfs,
+        0x5846_5342 => FsKind::Xfs,
+        0x2FC1_2FC1 => FsKind::Zfs,
+        0xF2F5_2010 => FsKind::F2fs,
+        0xCA45_1A4E => FsKind::Bcachefs,
+        0x0102_1994 => FsKind::Tmpfs,
+        0x794C_7630 => FsKind::Overlay,
+        0x6969 => FsKind::Nfs,
+        0xFF53_4D42 | 0x517B => FsKind::Cifs,
+        0xFE53_4D42 => FsKind::Smb2,
+        0x6573_5546 => FsKind::Fuse,
+        0x4D44 => FsKind::Fat,
+        0x2011_BAB0 => FsKind::Exfat,
+        0x5346_544E => FsKind::Ntfs,
+        0x482B => FsKind::Hfs,
+        0x9FA0 => FsKind::Proc,
+        0x6265_6572 => FsKind::Sysfs,
+        _ => FsKind::Unknown,
+    }
+}
+
+/// Maps a BSD/macOS `statfs.f_fstypename` to a filesystem family.
+pub fn fs_kind_from_name(name: &str) -> FsKind {
+    match name {
+        "apfs" => FsKind::Apfs,
+        "hfs" => FsKind::Hfs,
+        "msdos" => FsKind::Fat,
+        "exfat" => FsKind::Exfat,
+        "ntfs" => FsKind::Ntfs,
+        "nfs" => FsKind::Nfs,
+        "smbfs" => FsKind::Smb2,
+        "sshfs" | "fuse.sshfs" => FsKind::Sshfs,
+        "zfs" => FsKind::Zfs,
+        "tmpfs" => FsKind::Tmpfs,
+        "ufs" | "ext4" => FsKind::Ext4,
+        n if n.starts_with("fuse") || n == "macfuse" => FsKind::Fuse,
+        _ => FsKind::Unknown,
+    }
+}
+
+#[cfg(unix)]
+fn path_cstring(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(target_os = "linux")]
+pub fn filesystem_kind(path: &Path) -> io::Result<FsKind> {
+    let c_path = path_cstring(path)?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let kind = fs_kind_from_magic(buf.f_type as u32);
+    if kind != FsKind::Fuse {
+        return Ok(kind);
+    }
+    // Every FUSE filesystem shares one magic; the mount table has the
+    // subtype, e.g. `fuse.sshfs`.
+    let subtype = std::fs::canonicalize(path).ok().and_then(|canonical| {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+        mount_entry(&mountinfo, &canonical)
+    });
+    Ok(
+        subtype.map_or(kind, |entry| match fs_kind_from_name(&entry.fs_type) {
+            FsKind::Unknown => kind,
+            refined => refined,
+        }),
+    )
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub fn filesystem_kind(path: &Path) -> io::Result<FsKind> {
+    let c_path = path_cstring(path)?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(buf.f_fstypename.as_ptr()) };
+    Ok(fs_kind_from_name(&name.to_string_lossy()))
+}
+
+/// Windows reports the filesystem name of the volume, and separately
+/// whether it is a mapped network share, whose name is the server's.
+#[cfg(windows)]
+pub fn filesystem_kind(path: &Path) -> io::Result<FsKind> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetDriveTypeW, GetVolumeInformationW, GetVolumePathNameW,
+    };
+    const DRIVE_REMOTE: u32 = 4;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut root = [0u16; 261];
+    // SAFETY: both buffers are NUL-terminated or sized as passed.
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { GetDriveTypeW(root.as_ptr()) } == DRIVE_REMOTE {
+        return Ok(FsKind::Smb2);
+    }
+    let mut name = [0u16; 64];
+    // SAFETY: as above; the outputs not wanted are null.
+    let ok = unsafe {
+        GetVolumeInformationW(
+            root.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            name.as_mut_ptr(),
+            name.len() as u32,
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    let name = String::from_utf16_lossy(&name[..len]).to_ascii_lowercase();
+    Ok(match name.as_str() {
+        "fat" | "fat32" => FsKind::Fat,
+        other => fs_kind_from_name(other),
+    })
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    windows
+)))]
+pub fn filesystem_kind(_path: &Path) -> io::Result<FsKind> {
+    Ok(FsKind::Unknown)
+}
+
+#[cfg(unix)]
+pub fn mount_flags(path: &Path) -> io::Result<Vec<&'static str>> {
+    let c_path = path_cstring(path)?;
+    let mut buf: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    #[cfg(target_os = "linux")]
+    let known = [
+        (libc::ST_RDONLY, "ro"),
+        (libc::ST_NOSUID, "nosuid"),
+        (libc::ST_NODEV, "nodev"),
+        (libc::ST_NOEXEC, "noexec"),
+        (libc::ST_SYNCHRONOUS, "sync"),
+        (libc::ST_NOATIME, "noatime"),
+        (libc::ST_NODIRATIME, "nodiratime"),
+        (libc::ST_RELATIME, "relatime"),
+    ];
+    #[cfg(not(target_os = "linux"))]
+    let known = [(libc::ST_RDONLY, "ro"), (libc::ST_NOSUID, "nosuid")];
+
+    let mut flags: Vec<&'static str> = known
+        .iter()
+        .filter(|(bit, _)| buf.f_flag & *bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if flags.first() != Some(&"ro") {
+        flags.insert(0, "rw");
+    }
+    Ok(flags)
+}
+
+#[cfg(not(unix))]
+pub fn mount_flags(_path: &Path) -> io::Result<Vec<&'static str>> {
+    Ok(Vec::new())
+}
+
+/// Bytes an unprivileged user can still write to the filesystem at `path`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let c_path = path_cstring(path)?;
+    let mut buf: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(buf.f_bavail as u64 * buf.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fs_kind_from_magic_table() {
+        let table = [
+            (0xEF53, FsKind::Ext4),
+            (0x9123_683E, FsKind::Btrfs),
+            (0x5846_5342, FsKind::Xfs),
+            (0x0102_1994, FsKind::Tmpfs),
+            (0x6969, FsKind::Nfs),
+            (0xFF53_4D42, FsKind::Cifs),
+            (0xFE53_4D42, FsKind::Smb2),
+            (0x6573_5546, FsKind::Fuse),
+            (0x4D44, FsKind::Fat),
+            (0x2011_BAB0, FsKind::Exfat),
+            (0x9FA0, FsKind::Proc),
+            (0x1234, FsKind::Unknown),
+        ];
+        for (magic, kind) in table {
+            assert_eq!(fs_kind_from_magic(magic), kind, "magic {:#x}", magic);
+        }
+    }
+
+    #[test]
+    fn test_network_filesystems_by_magic() {
+        let table = [
+            (0x6969, true),
+            (0xFF53_4D42, true),
+            (0x517B, true),
+            (0xFE53_4D42, true),
+            // A bare FUSE mount could be anything until its subtype is known.
+            (0x6573_5546, false),
+            (0xEF53, false),
+            (0x0102_1994, false),
+            (0x1234, false),
+        ];
+        for (magic, network) in table {
+            assert_eq!(
+                fs_kind_from_magic(magic).is_network(),
+                network,
+                "magic {:#x}",
+                magic
+            );
+        }
+        assert!(fs_kind_from_name("fuse.sshfs").is_network());
+    }
+
+    #[test]
+    fn test_fs_kind_from_name() {
+        assert_eq!(fs_kind_from_name("apfs"), FsKind::Apfs);
+        assert_eq!(fs_kind_from_name("msdos"), FsKind::Fat);
+        assert_eq!(fs_kind_from_name("smbfs"), FsKind::Smb2);
+        assert_eq!(fs_kind_from_name("fuse.sshfs"), FsKind::Sshfs);
+        assert_eq!(fs_kind_from_name("fuse.rclone"), FsKind::Fuse);
+        assert_eq!(fs_kind_from_name("weird"), FsKind::Unknown);
+    }
+
+    #[test]
+    fn test_link_support_for_fat_family() {
+        for kind in [FsKind::Fat, FsKind::Exfat] {
+            let support = LinkSupport::for_kind(kind);
+            assert!(!support.symlinks && !support.hardlinks, "{:?}", kind);
+        }
+        let ext4 = LinkSupport::for_kind(FsKind::Ext4);
+        assert!(ext4.symlinks && ext4.hardlinks);
+        assert!(LinkSupport::for_kind(FsKind::Unknown).symlinks);
+    }
+
+    #[test]
+    fn test_max_file_size_only_limits_fat() {
+        assert_eq!(
+            FsKind::Fat.max_file_size(),
+            Some(4 * 1024 * 1024 * 1024 - 1)
+        );
+        for kind in [FsKind::Exfat, FsKind::Ntfs, FsKind::Ext4, FsKind::Unknown] {
+            assert_eq!(kind.max_file_size(), None, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_link_support_uses_existing_ancestor() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let missing = temp_dir.path().join("not/yet/created");
+        assert_eq!(link_support(&missing), link_support(temp_dir.path()));
+    }
+
+    #[test]
+    fn test_mount_source_picks_the_innermost_mount() {
+        let mountinfo = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+40 22 0:35 / /data rw,relatime shared:20 - btrfs /dev/sdb1 rw,space_cache
+41 40 0:36 / /data/my\\040photos rw - btrfs /dev/sdc rw
+42 22 0:37 / /tmp rw - tmpfs tmpfs rw
+";
+        let source = |path: &str| mount_source(mountinfo, Path::new(path));
+        assert_eq!(source("/data/a"), Some(PathBuf::from("/dev/sdb1")));
+        assert_eq!(source("/data/my photos/x"), Some(PathBuf::from("/dev/sdc")));
+        assert_eq!(source("/home/me"), Some(PathBuf::from("/dev/sda2")));
+        assert_eq!(source("/tmp/x"), None);
+    }
+
+    #[test]
+    fn test_mount_entry_has_the_fuse_subtype() {
+        let mountinfo = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+50 22 0:50 / /mnt/remote rw,nosuid - fuse.sshfs me@host:/srv rw,user_id=0
+";
+        let entry = mount_entry(mountinfo, Path::new("/mnt/remote/a")).unwrap();
+        assert_eq!(entry.fs_type, "fuse.sshfs");
+        assert_eq!(entry.source, "me@host:/srv");
+        assert_eq!(fs_kind_from_name(&entry.fs_type), FsKind::Sshfs);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mount_flags_reports_rw_or_ro() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let flags = mount_flags(temp_dir.path()).unwrap();
+        assert!(flags[0] == "rw" || flags[0] == "ro");
+    }
+}
@@ -1,44 +1,185 @@
-use super::preprocess::{SymlinkKind, SymlinkTask};
-use super::progress_bar::{ProgressBarStyle, ProgressOptions};
-use crate::cli::args::{BackupMode, CopyOptions, FollowSymlink, ReflinkMode, SymlinkMode};
+use super::output;
+use super::preprocess::{SpecialTask, SymlinkKind, SymlinkTask};
+use super::progress_bar::{DEFAULT_REFRESH_RATE, ProgressBarStyle, ProgressMode, ProgressOptions};
+use crate::cli::args::{
+    BackupMode, CopyOptions, Engine, FollowSymlink, Prompt, ReflinkMode, SymlinkMode,
+};
 use crate::config::schema::Config;
 use crate::error::{CopyError, CopyResult};
+use crate::utility::confine;
 use crate::utility::preprocess::HardlinkTask;
+use crate::utility::preserve;
+use std::collections::HashSet;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Suffix of a destination file that is still being written.
+pub const PARTIAL_SUFFIX: &str = ".cpx-partial";
+/// Prefix of a temporary file that is renamed over the destination once complete.
+pub const TEMP_PREFIX: &str = ".cpx-tmp-";
+/// Prefix of the scratch directory `cpx doctor` probes with.
+pub const DOCTOR_PREFIX: &str = ".cpx-doctor-";
+
+/// Whether a file name matches one of the names cpx gives to files it has not finished with.
+pub fn is_leftover_name(name: &str) -> bool {
+    name.ends_with(PARTIAL_SUFFIX)
+        || name.starts_with(TEMP_PREFIX)
+        || name.starts_with(DOCTOR_PREFIX)
+}
+
+/// Temporary path next to `destination` for a file that will be renamed over it.
+pub fn temp_path_for(destination: &Path) -> PathBuf {
+    let name = destination
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    destination.with_file_name(format!("{}{}-{}", TEMP_PREFIX, std::process::id(), name))
+}
 
-pub fn create_directories(dirs: &[crate::utility::preprocess::DirectoryTask]) -> io::Result<()> {
+/// Run `op` again for as long as a signal interrupts it with EINTR, which
+/// says nothing about the file. Stopping on purpose goes through the abort
+/// flag, never through EINTR.
+pub fn retry_eintr<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match op() {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Create the planned directories, parents first. The ones that didn't
+/// exist before, missing parents included, are added to `created` in the
+/// order made, also when a later one fails.
+pub fn create_directories(
+    dirs: &[crate::utility::preprocess::DirectoryTask],
+    created: &mut Vec<PathBuf>,
+) -> CopyResult<()> {
     let mut dirs: Vec<_> = dirs.iter().collect();
     dirs.sort_unstable_by_key(|d| d.destination.components().count());
     dirs.dedup_by_key(|d| &d.destination);
 
     for dir in &dirs {
-        match std::fs::create_dir(&dir.destination) {
-            Ok(()) => {}
-            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                std::fs::create_dir_all(&dir.destination)?;
-            }
-            Err(e) => return Err(e),
-        }
+        create_directory(&dir.destination, created)?;
     }
     Ok(())
 }
 
-pub fn create_symlink(task: &SymlinkTask, options: &CopyOptions) -> io::Result<()> {
-    if task.destination.is_symlink() || task.destination.try_exists().unwrap_or(false) {
-        if options.interactive && !prompt_overwrite(&task.destination).map_err(io::Error::other)? {
+/// Create `path`, and its parents if they are missing, adding the ones made
+/// to `created`.
+fn create_directory(path: &Path, created: &mut Vec<PathBuf>) -> CopyResult<()> {
+    let result = match std::fs::create_dir(path) {
+        Ok(()) => {
+            created.push(path.to_path_buf());
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut missing: Vec<PathBuf> = path
+                .ancestors()
+                .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+                .map(Path::to_path_buf)
+                .collect();
+            missing.reverse();
+            std::fs::create_dir_all(path).inspect(|()| created.extend(missing))
+        }
+        Err(e) => Err(e),
+    };
+    result.map_err(|error| CopyError::OperationFailed {
+        operation: "create directory".to_string(),
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+/// Planned directories made only once something is about to be written
+/// into them, by whichever worker gets there first, so that the copy
+/// doesn't start with a pass over every directory.
+#[derive(Debug, Default)]
+pub struct LazyDirectories {
+    /// Planned and not made yet.
+    pending: Mutex<HashSet<PathBuf>>,
+    /// Planned directories nothing is written into, made up front.
+    empty: Vec<crate::utility::preprocess::DirectoryTask>,
+    /// The ones that didn't exist before, in the order made.
+    created: Mutex<Vec<PathBuf>>,
+}
+
+impl LazyDirectories {
+    /// Make `directories` as needed, and `empty`, planned directories
+    /// nothing is written into, when [`make_empty`](Self::make_empty) is
+    /// called.
+    pub fn new(
+        directories: &[crate::utility::preprocess::DirectoryTask],
+        empty: Vec<crate::utility::preprocess::DirectoryTask>,
+    ) -> Self {
+        Self {
+            pending: Mutex::new(directories.iter().map(|d| d.destination.clone()).collect()),
+            empty,
+            created: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Make the planned directories nothing is written into.
+    pub fn make_empty(&self) -> CopyResult<()> {
+        create_directories(&self.empty, &mut self.created.lock().unwrap())
+    }
+
+    /// The directories made that didn't exist before.
+    pub fn into_created(self) -> Vec<PathBuf> {
+        self.created.into_inner().unwrap()
+    }
+
+    /// Make the planned directories `path` is to be written into, outermost
+    /// first. A parent that wasn't planned is left to exist or not, as it
+    /// was before the copy.
+    pub fn ensure_parent(&self, path: &Path) -> CopyResult<()> {
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+        let mut pending = self.pending.lock().unwrap();
+        if !pending.contains(parent) {
             return Ok(());
         }
+        let chain: Vec<&Path> = parent
+            .ancestors()
+            .take_while(|dir| pending.contains(*dir))
+            .collect();
+        let mut created = self.created.lock().unwrap();
+        for dir in chain.into_iter().rev() {
+            create_directory(dir, &mut created)?;
+            pending.remove(dir);
+        }
+        Ok(())
+    }
+}
+
+/// Make way for a new node at `destination`. Returns false when the user
+/// declined to overwrite what is there.
+fn clear_for_node(destination: &Path, options: &CopyOptions) -> io::Result<bool> {
+    if destination.is_symlink() || destination.try_exists().unwrap_or(false) {
+        if options.interactive == Prompt::Always
+            && !prompt_overwrite(destination).map_err(io::Error::other)?
+        {
+            return Ok(false);
+        }
         if options.force || options.remove_destination || options.resume {
-            std::fs::remove_file(&task.destination)?;
+            std::fs::remove_file(destination)?;
         } else {
             return Err(io::Error::new(
                 io::ErrorKind::AlreadyExists,
-                format!("destination already exists: {:?}", task.destination),
+                format!("destination already exists: {:?}", destination),
             ));
         }
     }
+    Ok(true)
+}
+
+pub fn create_symlink(task: &SymlinkTask, options: &CopyOptions) -> io::Result<()> {
+    if !clear_for_node(&task.destination, options)? {
+        return Ok(());
+    }
     let target = match task.kind {
         SymlinkKind::PreserveExact => task.source.clone(),
         SymlinkKind::AbsoluteToSource => task.source.canonicalize()?,
@@ -70,20 +211,79 @@ pub fn create_symlink(task: &SymlinkTask, options: &CopyOptions) -> io::Result<(
         }
     }
 
+    // A link copied as a link keeps its own times, and owner if asked for.
+    if let Some(metadata) = &task.metadata {
+        preserve::preserve_symlink_attrs(
+            &task.destination,
+            metadata,
+            options.preserve,
+            &options.overrides,
+        )?;
+    }
+    Ok(())
+}
+
+/// Recreate `task` as a hard link to `first`, the link already created for
+/// another name of the same source symlink. Falls back to a separate
+/// symlink where the destination can't hard-link symlinks.
+pub fn link_symlink(first: &Path, task: &SymlinkTask, options: &CopyOptions) -> io::Result<()> {
+    if !clear_for_node(&task.destination, options)? {
+        return Ok(());
+    }
+    // Without AT_SYMLINK_FOLLOW, so the link itself gets the new name.
+    match std::fs::hard_link(first, &task.destination) {
+        Ok(()) => Ok(()),
+        Err(_) => create_symlink(task, options),
+    }
+}
+
+/// Recreate the pipe, device or socket `task.source` at `task.destination`
+/// with the same mode. Device nodes usually need root.
+#[cfg(unix)]
+pub fn create_special(task: &SpecialTask, options: &CopyOptions) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    if !clear_for_node(&task.destination, options)? {
+        return Ok(());
+    }
+    let metadata = std::fs::symlink_metadata(&task.source)?;
+    let path = std::ffi::CString::new(task.destination.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let result = if metadata.file_type().is_fifo() {
+        unsafe { libc::mkfifo(path.as_ptr(), (metadata.mode() & 0o7777) as libc::mode_t) }
+    } else {
+        unsafe {
+            libc::mknod(
+                path.as_ptr(),
+                metadata.mode() as libc::mode_t,
+                metadata.rdev() as libc::dev_t,
+            )
+        }
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
     Ok(())
 }
 
+#[cfg(not(unix))]
+pub fn create_special(_task: &SpecialTask, _options: &CopyOptions) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
 pub fn create_hardlink(task: &HardlinkTask, options: &CopyOptions) -> CopyResult<()> {
     if task.destination.try_exists()? {
-        if options.interactive && !prompt_overwrite(&task.destination)? {
+        if options.interactive == Prompt::Always && !prompt_overwrite(&task.destination)? {
             return Ok(());
         }
 
         if options.force || options.remove_destination || options.resume {
-            if let Err(_e) = std::fs::remove_file(&task.destination) {
+            if let Err(error) = std::fs::remove_file(&task.destination) {
                 return Err(CopyError::HardlinkFailed {
                     source: task.source.clone(),
                     destination: task.destination.clone(),
+                    error,
                 });
             }
         } else {
@@ -91,26 +291,236 @@ pub fn create_hardlink(task: &HardlinkTask, options: &CopyOptions) -> CopyResult
         }
     }
 
-    std::fs::hard_link(&task.source, &task.destination).map_err(|_e| {
+    std::fs::hard_link(&task.source, &task.destination).map_err(|error| {
         CopyError::HardlinkFailed {
             source: task.source.clone(),
             destination: task.destination.clone(),
+            error,
         }
     })?;
 
     Ok(())
 }
 
-pub fn prompt_overwrite(path: &Path) -> io::Result<bool> {
-    use std::io::{Write, stdin, stdout};
+/// Hard link `destination` to `source` for `--prefer-hardlink`, replacing
+/// whatever the overwrite policy let through by linking next to it and
+/// renaming. Fails with `CrossesDevices` when the two aren't on the same
+/// filesystem, so the caller copies instead.
+pub fn link_in_place(source: &Path, destination: &Path) -> io::Result<()> {
+    let source_metadata = std::fs::symlink_metadata(source)?;
+    // A symlink followed to its target would be linked itself.
+    if !source_metadata.is_file() {
+        return Err(io::Error::other("not a regular file"));
+    }
+    let parent = match destination.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    if !same_device(&source_metadata, &std::fs::metadata(parent)?) {
+        return Err(io::ErrorKind::CrossesDevices.into());
+    }
+    if !destination.try_exists()? {
+        return std::fs::hard_link(source, destination);
+    }
+    let temp = temp_path_for(destination);
+    std::fs::hard_link(source, &temp)?;
+    let result = std::fs::rename(&temp, destination);
+    // Still there after a failed rename, or after renaming over a link to
+    // the same file, which does nothing.
+    let _ = std::fs::remove_file(&temp);
+    result
+}
 
-    print!("overwrite '{}'? (y/n): ", path.display());
-    stdout().flush()?;
+#[cfg(unix)]
+fn same_device(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev()
+}
 
-    let mut input = String::new();
-    stdin().read_line(&mut input)?;
+/// Left to the link itself to refuse.
+#[cfg(not(unix))]
+fn same_device(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> bool {
+    true
+}
 
-    Ok(input.trim().eq_ignore_ascii_case("y"))
+/// The filesystem `metadata` is on, as `-x` tells them apart.
+#[cfg(unix)]
+pub fn device_of(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+/// Not known, so `-x` keeps to nothing.
+#[cfg(not(unix))]
+pub fn device_of(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// What tells a file apart whatever name or link it is reached through.
+#[cfg(unix)]
+pub type FileId = (u64, u64);
+#[cfg(not(unix))]
+pub type FileId = PathBuf;
+
+/// The device and inode of the file at `path`, following links.
+#[cfg(unix)]
+pub fn file_id(path: &Path) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Where the file at `path` really is, without inodes to go by.
+#[cfg(not(unix))]
+pub fn file_id(path: &Path) -> Option<FileId> {
+    std::fs::canonicalize(path).ok()
+}
+
+/// Whether both are the same file, such as two hard links to it.
+#[cfg(unix)]
+pub fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+pub fn same_file(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Open a source file for reading. With `noatime`, ask the kernel not to
+/// update its access time, falling back to a plain open when not permitted
+/// (O_NOATIME requires owning the file or CAP_FOWNER).
+pub fn open_source(path: &Path, noatime: bool) -> io::Result<std::fs::File> {
+    #[cfg(target_os = "linux")]
+    if noatime {
+        use std::os::unix::fs::OpenOptionsExt;
+        match std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOATIME)
+            .open(path)
+        {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            result => return result,
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = noatime;
+    std::fs::File::open(path)
+}
+
+/// Whether this user is refused permission to read `path`, a file or a
+/// directory to list. Checked without opening it, so a FIFO doesn't block
+/// and no access time changes.
+#[cfg(unix)]
+pub fn read_denied(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(name) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `name` is NUL-terminated and outlives the call.
+    let result =
+        unsafe { libc::faccessat(libc::AT_FDCWD, name.as_ptr(), libc::R_OK, libc::AT_EACCESS) };
+    result != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EACCES)
+}
+
+#[cfg(not(unix))]
+pub fn read_denied(_path: &Path) -> bool {
+    false
+}
+
+/// Create or truncate a destination file for writing. Under
+/// `options.confine`, no symlink below that directory is followed on the way
+/// (see [`confine`](crate::utility::confine)).
+pub fn create_destination(path: &Path, options: &CopyOptions) -> io::Result<std::fs::File> {
+    match &options.confine {
+        Some(root) if path.starts_with(root) => confine::create_beneath(root, path),
+        _ => std::fs::File::create(path),
+    }
+}
+
+/// Open `destination` for writing with [`create_destination`]. Under `-f`,
+/// a destination that can't be opened because of its permissions, or
+/// because it is a program that is running, is unlinked (a symlink rather
+/// than what it leads to) and created once more, as cp does.
+pub fn open_destination(
+    source: &Path,
+    destination: &Path,
+    options: &CopyOptions,
+) -> CopyResult<std::fs::File> {
+    let error = match create_destination(destination, options) {
+        Ok(file) => return Ok(file),
+        Err(error) => error,
+    };
+    if !matches!(
+        error.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ExecutableFileBusy
+    ) {
+        return Err(CopyError::Io(error));
+    }
+    if options.force
+        && std::fs::remove_file(destination).is_ok()
+        && let Ok(file) = create_destination(destination, options)
+    {
+        return Ok(file);
+    }
+    Err(CopyError::CopyFailed {
+        source: source.to_path_buf(),
+        destination: destination.to_path_buf(),
+        reason: format!(
+            "cannot open '{}' for writing: {}",
+            destination.display(),
+            describe_io_error(&error)
+        ),
+    })
+}
+
+/// Make way for a new file at `destination`: with `--remove-destination`
+/// whatever file or link is there is unlinked, and with an explicit `-P` or
+/// `-f` a symlink is. A dangling symlink always is: creating the file
+/// through it would put the copy wherever the link happens to point. Any
+/// other symlink is refused rather than written through, whoever runs the
+/// copy, since a root copy never follows links below its destination. A
+/// directory is never removed.
+pub fn clear_destination(destination: &Path, options: &CopyOptions) -> CopyResult<()> {
+    let Ok(metadata) = std::fs::symlink_metadata(destination) else {
+        return Ok(());
+    };
+    let is_link = metadata.file_type().is_symlink();
+    let replaces_link = is_link
+        && (options.no_dereference_destination
+            || options.force
+            || std::fs::metadata(destination).is_err());
+    let failed = |error| CopyError::OperationFailed {
+        operation: "remove destination".to_string(),
+        path: destination.to_path_buf(),
+        error,
+    };
+    if !options.remove_destination && !replaces_link {
+        if is_link {
+            return Err(CopyError::OperationFailed {
+                operation: "write destination".to_string(),
+                path: destination.to_path_buf(),
+                error: io::Error::other(
+                    "it is a symlink, which is not written through; \
+                     replace it with -P, -f or --remove-destination",
+                ),
+            });
+        }
+        return Ok(());
+    }
+    if metadata.is_dir() {
+        return Err(failed(io::Error::new(
+            io::ErrorKind::IsADirectory,
+            "it is a directory, which a file can't replace",
+        )));
+    }
+    std::fs::remove_file(destination).map_err(failed)
+}
+
+pub fn prompt_overwrite(path: &Path) -> io::Result<bool> {
+    output::confirm(format!("overwrite '{}'?", path.display()))
 }
 
 pub fn with_parents(dest: &Path, source: &Path) -> PathBuf {
@@ -125,6 +535,44 @@ pub fn with_parents(dest: &Path, source: &Path) -> PathBuf {
     dest.join(relative)
 }
 
+/// Length of `path` without the separators it ends with, keeping a root
+/// that is nothing but separators.
+fn trimmed_len(path: &Path) -> usize {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    let kept = bytes
+        .iter()
+        .rposition(|&b| !std::path::is_separator(b as char))
+        .map_or(0, |last| last + 1);
+    kept.max(bytes.len().min(1))
+}
+
+/// Whether `path` is written with a trailing slash, which makes it name
+/// the directory it is or leads to.
+pub fn has_trailing_slash(path: &Path) -> bool {
+    trimmed_len(path) < path.as_os_str().len()
+}
+
+/// Whether `path` ends in a `.` component, as `src/.` and `src/./` do,
+/// which makes it name the contents of the directory rather than the
+/// directory: they are copied straight into the destination.
+pub fn names_contents(path: &Path) -> bool {
+    let bytes = &path.as_os_str().as_encoded_bytes()[..trimmed_len(path)];
+    match bytes {
+        [b'.'] => true,
+        [.., separator, b'.'] => std::path::is_separator(*separator as char),
+        _ => false,
+    }
+}
+
+/// `path` without its trailing slashes, for `--strip-trailing-slashes`.
+pub fn strip_trailing_slashes(path: &Path) -> PathBuf {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    // Safe: the bytes end just before an ASCII separator.
+    let stripped =
+        unsafe { std::ffi::OsStr::from_encoded_bytes_unchecked(&bytes[..trimmed_len(path)]) };
+    PathBuf::from(stripped)
+}
+
 pub fn truncate_filename(filename: &str, max_len: usize) -> String {
     if filename.len() <= max_len {
         filename.to_string()
@@ -134,6 +582,16 @@ pub fn truncate_filename(filename: &str, max_len: usize) -> String {
     }
 }
 
+/// Formats an I/O error as `EPERM (Operation not permitted)` when it carries an errno.
+pub fn describe_io_error(err: &io::Error) -> String {
+    #[cfg(unix)]
+    if let Some(code) = err.raw_os_error() {
+        let errno = nix::errno::Errno::from_raw(code);
+        return format!("{:?} ({})", errno, errno.desc());
+    }
+    err.to_string()
+}
+
 pub fn parse_symlink_mode(s: &str) -> Option<SymlinkMode> {
     match s {
         "auto" => Some(SymlinkMode::Auto),
@@ -167,6 +625,10 @@ pub fn parse_progress_bar(cfg: &Config) -> ProgressOptions {
         head: cfg.progress.bar.head.clone(),
         bar_color: cfg.progress.color.bar.clone(),
         message_color: cfg.progress.color.message.clone(),
+        mode: ProgressMode::Auto,
+        min_bytes: cfg.progress.behavior.min_bytes,
+        min_files: cfg.progress.behavior.min_files,
+        refresh_rate: DEFAULT_REFRESH_RATE,
     }
 }
 
@@ -189,6 +651,16 @@ pub fn parse_reflink_mode(s: &str) -> Option<ReflinkMode> {
     }
 }
 
+pub fn parse_engine(s: &str) -> Option<Engine> {
+    match s {
+        "auto" => Some(Engine::Auto),
+        "offload" => Some(Engine::Offload),
+        "buffered" => Some(Engine::Buffered),
+        "mmap" => Some(Engine::Mmap),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -196,6 +668,46 @@ mod tests {
     use std::path::PathBuf;
     use tempfile::TempDir;
 
+    #[test]
+    fn test_linking_again_leaves_nothing_behind() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("a.txt");
+        let destination = temp_dir.path().join("b.txt");
+        fs::write(&source, "a").unwrap();
+
+        link_in_place(&source, &destination).unwrap();
+        // Renaming over a link to the same file leaves the temporary link.
+        link_in_place(&source, &destination).unwrap();
+
+        let names: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+        let same = same_file(
+            &fs::metadata(&source).unwrap(),
+            &fs::metadata(&destination).unwrap(),
+        );
+        assert_eq!(same, cfg!(unix));
+    }
+
+    #[test]
+    fn test_eintr_is_retried_and_other_errors_are_not() {
+        let mut calls = 0;
+        let result = retry_eintr(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(io::Error::from(io::ErrorKind::Interrupted))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let result: io::Result<()> = retry_eintr(|| Err(io::Error::other("disk on fire")));
+        assert_eq!(result.unwrap_err().to_string(), "disk on fire");
+    }
+
     #[test]
     fn test_with_parents_relative_path() {
         let dest = Path::new("/dest");
@@ -205,6 +717,30 @@ mod tests {
         assert_eq!(result, PathBuf::from("/dest/a/b/file.txt"));
     }
 
+    #[test]
+    fn test_trailing_slashes_are_stripped_down_to_the_root() {
+        assert!(has_trailing_slash(Path::new("dir/")));
+        assert!(!has_trailing_slash(Path::new("dir")));
+        assert!(!has_trailing_slash(Path::new("/")));
+        assert_eq!(
+            strip_trailing_slashes(Path::new("a/dir//")),
+            Path::new("a/dir")
+        );
+        assert_eq!(strip_trailing_slashes(Path::new("dir")), Path::new("dir"));
+        assert_eq!(strip_trailing_slashes(Path::new("//")), Path::new("/"));
+    }
+
+    #[test]
+    fn test_a_final_dot_names_the_contents() {
+        assert!(names_contents(Path::new("src/.")));
+        assert!(names_contents(Path::new("src/./")));
+        assert!(names_contents(Path::new(".")));
+        assert!(!names_contents(Path::new("src")));
+        assert!(!names_contents(Path::new("src/..")));
+        assert!(!names_contents(Path::new("src/.hidden")));
+        assert!(!names_contents(Path::new("./src")));
+    }
+
     #[test]
     fn test_with_parents_absolute_path_unix() {
         #[cfg(unix)]
@@ -303,6 +839,116 @@ mod tests {
         assert_eq!(result, "...");
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_describe_io_error_with_errno() {
+        let err = io::Error::from_raw_os_error(libc::EPERM);
+        assert_eq!(describe_io_error(&err), "EPERM (Operation not permitted)");
+
+        let err = io::Error::other("custom failure");
+        assert_eq!(describe_io_error(&err), "custom failure");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_create_hardlink_error_includes_os_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let task = HardlinkTask {
+            source: temp_dir.path().join("missing.txt"),
+            destination: temp_dir.path().join("link.txt"),
+        };
+
+        let err = create_hardlink(&task, &CopyOptions::none()).unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("operation 'hardlink' on '"));
+        assert!(message.ends_with(": ENOENT (No such file or directory)"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_create_directories_error_includes_os_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("file");
+        fs::write(&file, b"x").unwrap();
+        let dirs = vec![crate::utility::preprocess::DirectoryTask {
+            source: None,
+            destination: file.join("sub"),
+            metadata: None,
+        }];
+
+        let err = create_directories(&dirs, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "operation 'create directory' on '{}': ENOTDIR (Not a directory)",
+                file.join("sub").display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_create_directories_reports_only_new_ones() {
+        let temp_dir = TempDir::new().unwrap();
+        let existing = temp_dir.path().join("existing");
+        fs::create_dir(&existing).unwrap();
+        let task = |destination: PathBuf| crate::utility::preprocess::DirectoryTask {
+            source: None,
+            destination,
+            metadata: None,
+        };
+        let dirs = vec![
+            task(existing.clone()),
+            task(existing.join("new")),
+            task(temp_dir.path().join("a/b/c")),
+        ];
+
+        let mut created = Vec::new();
+        create_directories(&dirs, &mut created).unwrap();
+
+        assert_eq!(
+            created,
+            vec![
+                existing.join("new"),
+                temp_dir.path().join("a"),
+                temp_dir.path().join("a/b"),
+                temp_dir.path().join("a/b/c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lazy_directories_make_only_planned_parents() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("dest");
+        let task = |destination: PathBuf| crate::utility::preprocess::DirectoryTask {
+            source: None,
+            destination,
+            metadata: None,
+        };
+        let planned = [
+            task(root.clone()),
+            task(root.join("a")),
+            task(root.join("a/b")),
+            task(root.join("unused")),
+        ];
+        let directories = LazyDirectories::new(&planned, Vec::new());
+
+        directories.ensure_parent(&root.join("a/b/file")).unwrap();
+        directories.ensure_parent(&root.join("a/other")).unwrap();
+        // Parents that weren't planned are not made up.
+        directories
+            .ensure_parent(&temp_dir.path().join("elsewhere/file"))
+            .unwrap();
+
+        assert!(root.join("a/b").is_dir());
+        assert!(!root.join("unused").exists());
+        assert!(!temp_dir.path().join("elsewhere").exists());
+        assert_eq!(
+            directories.into_created(),
+            [root.clone(), root.join("a"), root.join("a/b")]
+        );
+    }
+
     #[test]
     #[cfg(unix)]
     fn test_create_symlink_absolute() {
@@ -317,6 +963,9 @@ mod tests {
             source: source.clone(),
             destination: dest.clone(),
             kind: SymlinkKind::AbsoluteToSource,
+            inode_group: None,
+            metadata: None,
+            origin: None,
         };
 
         create_symlink(&task, &options).unwrap();
@@ -344,6 +993,9 @@ mod tests {
             source: source.clone(),
             destination: dest.clone(),
             kind: SymlinkKind::RelativeToSource,
+            inode_group: None,
+            metadata: None,
+            origin: None,
         };
 
         create_symlink(&task, &options).unwrap();
@@ -371,6 +1023,9 @@ mod tests {
             source: source_dir.clone(),
             destination: dest_link.clone(),
             kind: SymlinkKind::AbsoluteToSource,
+            inode_group: None,
+            metadata: None,
+            origin: None,
         };
 
         create_symlink(&task, &options).unwrap();
@@ -396,6 +1051,9 @@ mod tests {
             source: source.clone(),
             destination: dest.clone(),
             kind: SymlinkKind::RelativeToSource,
+            inode_group: None,
+            metadata: None,
+            origin: None,
         };
 
         create_symlink(&task, &options).unwrap();
@@ -418,6 +1076,9 @@ mod tests {
             source: source.clone(),
             destination: dest.clone(),
             kind: SymlinkKind::AbsoluteToSource,
+            inode_group: None,
+            metadata: None,
+            origin: None,
         };
 
         let result = create_symlink(&task, &options);
@@ -436,6 +1097,9 @@ mod tests {
             source: source.clone(),
             destination: dest.clone(),
             kind: SymlinkKind::RelativeToSource,
+            inode_group: None,
+            metadata: None,
+            origin: None,
         };
 
         create_symlink(&task, &options).unwrap();
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+/// Translation of one kind of id, user or group, for `--usermap`,
+/// `--groupmap`, `--owner` and `--group`.
+///
+/// A map is a comma-separated list of `FROM:TO` pairs, as with rsync. Either
+/// side may be a number or a name, and FROM may be `*` to catch every id not
+/// listed on its own; `--owner` and `--group` are a map from `*`. Names are
+/// looked up once, when the map is parsed, and ids the map doesn't mention
+/// are preserved as they are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    ids: HashMap<u32, u32>,
+    wildcard: Option<u32>,
+}
+
+impl IdMap {
+    /// Parse a `--usermap` value, resolving names as user names.
+    pub fn parse_users(spec: &str) -> Result<Self, String> {
+        Self::parse(spec, "user", user_id)
+    }
+
+    /// Parse a `--groupmap` value, resolving names as group names.
+    pub fn parse_groups(spec: &str) -> Result<Self, String> {
+        Self::parse(spec, "group", group_id)
+    }
+
+    /// Parse an `--owner` value, a user name or uid.
+    pub fn parse_user(name: &str) -> Result<u32, String> {
+        resolve(name.trim(), "user", user_id)
+    }
+
+    /// Parse a `--group` value, a group name or gid.
+    pub fn parse_group(name: &str) -> Result<u32, String> {
+        resolve(name.trim(), "group", group_id)
+    }
+
+    fn parse(
+        spec: &str,
+        kind: &str,
+        lookup: impl Fn(&str) -> Result<Option<u32>, String>,
+    ) -> Result<Self, String> {
+        let resolve = |name: &str| resolve(name, kind, &lookup);
+
+        let mut map = Self::default();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((from, to)) = entry.split_once(':') else {
+                return Err(format!("invalid entry '{}': expected FROM:TO", entry));
+            };
+            let (from, to) = (from.trim(), to.trim());
+            if from.is_empty() || to.is_empty() {
+                return Err(format!("invalid entry '{}': expected FROM:TO", entry));
+            }
+            if to == "*" {
+                return Err(format!("invalid entry '{}': '*' can only be FROM", entry));
+            }
+            let to = resolve(to)?;
+            // As with rsync, the first entry for an id wins.
+            if from == "*" {
+                map.wildcard.get_or_insert(to);
+            } else {
+                map.ids.entry(resolve(from)?).or_insert(to);
+            }
+        }
+        Ok(map)
+    }
+
+    /// A map that gives every id as `id`.
+    pub fn everyone(id: u32) -> Self {
+        Self {
+            ids: HashMap::new(),
+            wildcard: Some(id),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty() && self.wildcard.is_none()
+    }
+
+    /// The id `id` becomes at the destination.
+    pub fn map(&self, id: u32) -> u32 {
+        self.ids.get(&id).copied().or(self.wildcard).unwrap_or(id)
+    }
+}
+
+/// The id `name` stands for: itself if it is a number, else what `lookup`
+/// finds for it.
+fn resolve(
+    name: &str,
+    kind: &str,
+    lookup: impl Fn(&str) -> Result<Option<u32>, String>,
+) -> Result<u32, String> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    lookup(name)?.ok_or_else(|| format!("unknown {} '{}'", kind, name))
+}
+
+/// Owner and group translation applied when ownership is preserved.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OwnerMap {
+    pub users: IdMap,
+    pub groups: IdMap,
+}
+
+impl OwnerMap {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty()
+    }
+
+    /// The owner and group a file owned by `uid`:`gid` gets at the destination.
+    pub fn map(&self, uid: u32, gid: u32) -> (u32, u32) {
+        (self.users.map(uid), self.groups.map(gid))
+    }
+}
+
+#[cfg(unix)]
+fn user_id(name: &str) -> Result<Option<u32>, String> {
+    nix::unistd::User::from_name(name)
+        .map(|user| user.map(|u| u.uid.as_raw()))
+        .map_err(|e| format!("cannot look up user '{}': {}", name, e))
+}
+
+#[cfg(unix)]
+fn group_id(name: &str) -> Result<Option<u32>, String> {
+    nix::unistd::Group::from_name(name)
+        .map(|group| group.map(|g| g.gid.as_raw()))
+        .map_err(|e| format!("cannot look up group '{}': {}", name, e))
+}
+
+#[cfg(not(unix))]
+fn user_id(_name: &str) -> Result<Option<u32>, String> {
+    Ok(None)
+}
+
+#[cfg(not(unix))]
+fn group_id(_name: &str) -> Result<Option<u32>, String> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Result<Option<u32>, String> {
+        Ok(match name {
+            "alice" => Some(1001),
+            "staff" => Some(50),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_parse_numeric_and_named_entries() {
+        let map = IdMap::parse("1001:2001, 1002:2002,alice:staff", "user", lookup).unwrap();
+        assert_eq!(map.map(1001), 2001);
+        assert_eq!(map.map(1002), 2002);
+        // Unmapped ids are preserved.
+        assert_eq!(map.map(0), 0);
+        assert_eq!(map.map(1003), 1003);
+    }
+
+    #[test]
+    fn test_wildcard_squashes_unlisted_ids() {
+        let map = IdMap::parse("0:0,*:1000", "user", lookup).unwrap();
+        assert_eq!(map.map(0), 0);
+        assert_eq!(map.map(1001), 1000);
+        assert_eq!(map.map(65534), 1000);
+    }
+
+    #[test]
+    fn test_first_entry_for_an_id_wins() {
+        let map = IdMap::parse("alice:1,1001:2,*:3,*:4", "user", lookup).unwrap();
+        assert_eq!(map.map(1001), 1);
+        assert_eq!(map.map(7), 3);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_entries() {
+        let parse = |spec| IdMap::parse(spec, "group", lookup).unwrap_err();
+        assert!(parse("1001").contains("expected FROM:TO"));
+        assert!(parse("1001:").contains("expected FROM:TO"));
+        assert!(parse("1001:*").contains("'*' can only be FROM"));
+        assert_eq!(parse("nobody-here:1"), "unknown group 'nobody-here'");
+        assert!(IdMap::parse("", "user", lookup).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_owner_map_maps_users_and_groups_separately() {
+        let owners = OwnerMap {
+            users: IdMap::parse("1001:2001", "user", lookup).unwrap(),
+            groups: IdMap::parse("*:staff", "group", lookup).unwrap(),
+        };
+        assert_eq!(owners.map(1001, 100), (2001, 50));
+        assert_eq!(owners.map(1002, 1001), (1002, 50));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_root_resolves_by_name() {
+        assert_eq!(IdMap::parse_users("root:root").unwrap().map(0), 0);
+        assert_eq!(IdMap::parse_user("root"), Ok(0));
+        assert_eq!(IdMap::parse_group("33"), Ok(33));
+        assert!(IdMap::parse_group("nobody-here").is_err());
+    }
+}
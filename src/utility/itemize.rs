@@ -0,0 +1,547 @@
+use crate::cli::args::CopyOptions;
+use crate::utility::output;
+use crate::utility::preprocess::CopyPlan;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+/// Number of attribute columns after the two-character prefix.
+const ATTRIBUTE_COLUMNS: usize = 7;
+
+/// One line of itemized output for `--itemize-changes` and `--dry-run`, in
+/// the style of `rsync -i`: a nine-character code, a space and the
+/// destination path.
+///
+/// ```text
+/// YXcstpogx path
+/// ```
+///
+/// `Y` is what happens to the item: `>` its data is copied, `c` it is
+/// created (directories and symlinks), `h` it is made a hard link, `.` only
+/// its attributes change. `*` starts a message instead of a code, such as
+/// `*excluded` for an item an exclude pattern leaves out.
+///
+/// `X` is the kind of item: `f` file, `d` directory, `L` symlink.
+///
+/// The seven attribute columns are all `+` for an item that doesn't exist
+/// yet. Otherwise each is `.` or its letter when that attribute differs:
+///
+/// - `c` the source was modified after the destination
+/// - `s` the sizes differ
+/// - `t` the modification times differ
+/// - `p` the permissions differ and are preserved
+/// - `o` the owner differs and is preserved
+/// - `g` the group differs and is preserved
+/// - `x` the extended attributes differ and are preserved
+///
+/// Directories end with `/`, symlinks are followed by `-> SOURCE` and hard
+/// links by `=> SOURCE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    code: String,
+    destination: PathBuf,
+    suffix: String,
+}
+
+impl Item {
+    /// A pipe or device read into `destination`. It has no attributes worth
+    /// comparing, so only whether `destination` existed shows.
+    pub fn stream(destination: &Path, existed: bool) -> Self {
+        Self {
+            code: format!(">f{}", existence_columns(existed)),
+            destination: destination.to_path_buf(),
+            suffix: String::new(),
+        }
+    }
+
+    /// A directory made before planning, as `--mkdir` makes the
+    /// destination.
+    pub fn created_directory(destination: &Path) -> Self {
+        Self {
+            code: format!("cd{}", existence_columns(false)),
+            destination: destination.to_path_buf(),
+            suffix: "/".to_string(),
+        }
+    }
+
+    /// A message such as `*excluded` in place of a code.
+    pub fn message(message: &str, destination: &Path, is_dir: bool) -> Self {
+        Self {
+            code: message.to_string(),
+            destination: destination.to_path_buf(),
+            suffix: if is_dir { "/" } else { "" }.to_string(),
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let destination = self.destination.display().to_string();
+        // A directory named with a trailing slash, as `--mkdir dir/` names
+        // it, gets only the one.
+        let destination = if self.suffix.starts_with('/') {
+            destination.trim_end_matches(std::path::MAIN_SEPARATOR)
+        } else {
+            &destination
+        };
+        write!(f, "{} {}{}", self.code, destination, self.suffix)
+    }
+}
+
+/// Items for everything `plan` would do, in destination order. Reads the
+/// destination as it is now, so it has to run before the copy.
+///
+/// Directories that already exist and don't change are left out, as are
+/// attribute-only items with nothing to change.
+pub fn itemize_plan(plan: &CopyPlan, options: &CopyOptions) -> Vec<Item> {
+    let mut items = Vec::new();
+
+    let mut seen_directories = std::collections::HashSet::new();
+    for task in &plan.directories {
+        if !seen_directories.insert(&task.destination) {
+            continue;
+        }
+        let existing = std::fs::symlink_metadata(&task.destination).ok();
+        let columns = match (&task.source, &existing) {
+            (_, None) if options.attributes_only => continue,
+            (_, None) => "+".repeat(ATTRIBUTE_COLUMNS),
+            (Some(source), Some(_)) => match std::fs::metadata(source) {
+                Ok(source_meta) => attribute_columns(
+                    source,
+                    &source_meta,
+                    &task.destination,
+                    existing.as_ref(),
+                    options,
+                ),
+                Err(_) => continue,
+            },
+            (None, Some(_)) => continue,
+        };
+        let update = if existing.is_some() { '.' } else { 'c' };
+        if update == '.' && is_unchanged(&columns) {
+            continue;
+        }
+        items.push(Item {
+            code: format!("{}d{}", update, columns),
+            destination: task.destination.clone(),
+            suffix: "/".to_string(),
+        });
+    }
+
+    for task in &plan.symlinks {
+        items.push(Item {
+            code: format!(
+                "cL{}",
+                existence_columns(std::fs::symlink_metadata(&task.destination).is_ok())
+            ),
+            destination: task.destination.clone(),
+            suffix: format!(" -> {}", task.source.display()),
+        });
+    }
+    for task in &plan.hardlinks {
+        items.push(Item {
+            code: format!(
+                "hf{}",
+                existence_columns(std::fs::symlink_metadata(&task.destination).is_ok())
+            ),
+            destination: task.destination.clone(),
+            suffix: format!(" => {}", task.source.display()),
+        });
+    }
+
+    for task in &plan.files {
+        let existing = std::fs::symlink_metadata(&task.destination).ok();
+        if options.attributes_only && existing.is_none() {
+            continue;
+        }
+        let columns = match std::fs::metadata(&task.source) {
+            Ok(source_meta) => attribute_columns(
+                &task.source,
+                &source_meta,
+                &task.destination,
+                existing.as_ref(),
+                options,
+            ),
+            Err(_) => continue,
+        };
+        let update = if options.attributes_only { '.' } else { '>' };
+        if update == '.' && is_unchanged(&columns) {
+            continue;
+        }
+        items.push(Item {
+            code: format!("{}f{}", update, columns),
+            destination: task.destination.clone(),
+            suffix: String::new(),
+        });
+    }
+
+    for excluded in &plan.excluded {
+        items.push(Item::message(
+            "*excluded",
+            &excluded.destination,
+            excluded.is_dir,
+        ));
+    }
+
+    items.sort_by(|a, b| a.destination.cmp(&b.destination));
+    items
+}
+
+/// Print `item` to stdout, as the whole output of a dry run.
+pub fn print_item(item: &Item) {
+    output::data(item);
+}
+
+/// Print `item` to stdout alongside the copy making it.
+pub fn print_done(item: &Item) {
+    output::side_data(item);
+}
+
+/// Items of a live copy, printed one by one as their tasks complete.
+#[derive(Debug, Default)]
+pub struct ItemLog {
+    pending: HashMap<PathBuf, Item>,
+}
+
+impl ItemLog {
+    pub fn new(items: Vec<Item>) -> Self {
+        Self {
+            pending: items
+                .into_iter()
+                .map(|item| (item.destination.clone(), item))
+                .collect(),
+        }
+    }
+
+    /// Print the item for `destination`, if there is one, now that it has
+    /// been done.
+    pub fn performed(&self, destination: &Path) {
+        if let Some(item) = self.pending.get(destination) {
+            print_done(item);
+        }
+    }
+
+    /// Print the item for `destination` as the hard link to `source` that
+    /// `--prefer-hardlink` made of it instead of a copy.
+    pub fn linked(&self, destination: &Path, source: &Path) {
+        if let Some(item) = self.pending.get(destination) {
+            print_done(&Item {
+                code: format!("h{}", &item.code[1..]),
+                destination: item.destination.clone(),
+                suffix: format!(" => {}", source.display()),
+            });
+        }
+    }
+
+    /// Print the items of excluded entries, which happen by not copying.
+    pub fn print_excluded(&self) {
+        let mut excluded: Vec<&Item> = self
+            .pending
+            .values()
+            .filter(|item| item.code.starts_with('*'))
+            .collect();
+        excluded.sort_by(|a, b| a.destination.cmp(&b.destination));
+        excluded.into_iter().for_each(print_done);
+    }
+}
+
+fn is_unchanged(columns: &str) -> bool {
+    columns.chars().all(|c| c == '.')
+}
+
+/// Columns for items whose attributes aren't compared: all `.` when
+/// replacing something, all `+` otherwise.
+fn existence_columns(existed: bool) -> String {
+    if existed {
+        ".".repeat(ATTRIBUTE_COLUMNS)
+    } else {
+        "+".repeat(ATTRIBUTE_COLUMNS)
+    }
+}
+
+/// The attribute columns for copying `source` over `destination`, which
+/// doesn't exist if `existing` is `None`.
+fn attribute_columns(
+    source: &Path,
+    source_meta: &Metadata,
+    destination: &Path,
+    existing: Option<&Metadata>,
+    options: &CopyOptions,
+) -> String {
+    let Some(dest_meta) = existing else {
+        return "+".repeat(ATTRIBUTE_COLUMNS);
+    };
+    let mut columns = ['.'; ATTRIBUTE_COLUMNS];
+    let is_file = source_meta.is_file();
+
+    if let (Ok(source_mtime), Ok(dest_mtime)) = (source_meta.modified(), dest_meta.modified()) {
+        if is_file && source_mtime > dest_mtime {
+            columns[0] = 'c';
+        }
+        // Copying into a directory always moves its mtime, so it only
+        // counts when it is put back.
+        if source_mtime != dest_mtime && (is_file || options.preserve.timestamps) {
+            columns[2] = 't';
+        }
+    }
+    if is_file && source_meta.len() != dest_meta.len() {
+        columns[1] = 's';
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        if options.preserve.mode && source_meta.mode() & 0o7777 != dest_meta.mode() & 0o7777 {
+            columns[3] = 'p';
+        }
+        if options.preserve.ownership {
+            let (uid, gid) = options
+                .overrides
+                .owners
+                .map(source_meta.uid(), source_meta.gid());
+            if uid != dest_meta.uid() {
+                columns[4] = 'o';
+            }
+            if gid != dest_meta.gid() {
+                columns[5] = 'g';
+            }
+        }
+        if options.preserve.xattr && xattrs_differ(source, destination) {
+            columns[6] = 'x';
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (source, destination);
+
+    columns.iter().collect()
+}
+
+#[cfg(unix)]
+fn xattrs_differ(source: &Path, destination: &Path) -> bool {
+    if !xattr::SUPPORTED_PLATFORM {
+        return false;
+    }
+    let read = |path: &Path| -> Vec<_> {
+        let mut attrs: Vec<_> = xattr::list(path)
+            .into_iter()
+            .flatten()
+            .map(|name| {
+                let value = xattr::get(path, &name).ok().flatten();
+                (name, value)
+            })
+            .collect();
+        attrs.sort();
+        attrs
+    };
+    read(source) != read(destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::preprocess::ExcludedEntry;
+    use filetime::{FileTime, set_file_mtime};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn codes(plan: &CopyPlan, options: &CopyOptions) -> Vec<String> {
+        itemize_plan(plan, options)
+            .iter()
+            .map(|item| item.code().to_string())
+            .collect()
+    }
+
+    fn file_plan(temp_dir: &TempDir, source: &[u8], dest: Option<&[u8]>) -> CopyPlan {
+        let source_path = temp_dir.path().join("src.txt");
+        let dest_path = temp_dir.path().join("dest.txt");
+        fs::write(&source_path, source).unwrap();
+        set_file_mtime(&source_path, FileTime::from_unix_time(2_000_000_000, 0)).unwrap();
+        if let Some(content) = dest {
+            fs::write(&dest_path, content).unwrap();
+            set_file_mtime(&dest_path, FileTime::from_unix_time(2_000_000_000, 0)).unwrap();
+        }
+        let mut plan = CopyPlan::new();
+        plan.add_file(source_path, dest_path, source.len() as u64);
+        plan
+    }
+
+    #[test]
+    fn test_new_file_is_all_plus() {
+        let temp_dir = TempDir::new().unwrap();
+        let plan = file_plan(&temp_dir, b"new", None);
+        let items = itemize_plan(&plan, &CopyOptions::none());
+        assert_eq!(items[0].code(), ">f+++++++");
+        assert_eq!(
+            items[0].to_string(),
+            format!(">f+++++++ {}", temp_dir.path().join("dest.txt").display())
+        );
+        assert_eq!(Item::stream(Path::new("x"), false).code(), ">f+++++++");
+        assert_eq!(Item::stream(Path::new("x"), true).code(), ">f.......");
+    }
+
+    #[test]
+    fn test_overwrite_with_nothing_different_is_all_dots() {
+        let temp_dir = TempDir::new().unwrap();
+        let plan = file_plan(&temp_dir, b"same", Some(b"same"));
+        assert_eq!(codes(&plan, &CopyOptions::none()), [">f......."]);
+    }
+
+    #[test]
+    fn test_size_and_time_differences() {
+        let temp_dir = TempDir::new().unwrap();
+        let plan = file_plan(&temp_dir, b"longer", Some(b"short"));
+        assert_eq!(codes(&plan, &CopyOptions::none()), [">f.s....."]);
+
+        // Destination older: the source's content is newer.
+        set_file_mtime(
+            temp_dir.path().join("dest.txt"),
+            FileTime::from_unix_time(1_000_000_000, 0),
+        )
+        .unwrap();
+        assert_eq!(codes(&plan, &CopyOptions::none()), [">fcst...."]);
+
+        // Destination newer: only the time differs.
+        fs::write(temp_dir.path().join("dest.txt"), b"longer").unwrap();
+        set_file_mtime(
+            temp_dir.path().join("dest.txt"),
+            FileTime::from_unix_time(2_100_000_000, 0),
+        )
+        .unwrap();
+        assert_eq!(codes(&plan, &CopyOptions::none()), [">f..t...."]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserved_permission_difference() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let plan = file_plan(&temp_dir, b"same", Some(b"same"));
+        fs::set_permissions(
+            temp_dir.path().join("src.txt"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        fs::set_permissions(
+            temp_dir.path().join("dest.txt"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+
+        // Not preserved, so not a change.
+        assert_eq!(codes(&plan, &CopyOptions::none()), [">f......."]);
+        let options = CopyOptions {
+            preserve: crate::utility::preserve::PreserveAttr::from_string("mode").unwrap(),
+            ..CopyOptions::none()
+        };
+        assert_eq!(codes(&plan, &options), [">f...p..."]);
+
+        // With --attributes-only the data stays and only the mode changes.
+        let options = CopyOptions {
+            attributes_only: true,
+            ..options
+        };
+        assert_eq!(codes(&plan, &options), [".f...p..."]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserved_owner_and_group_difference() {
+        use crate::utility::idmap::{IdMap, OwnerMap};
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let plan = file_plan(&temp_dir, b"same", Some(b"same"));
+        let meta = fs::metadata(temp_dir.path().join("src.txt")).unwrap();
+        // Map the source's owner elsewhere instead of needing root to chown.
+        let mut options = CopyOptions {
+            preserve: crate::utility::preserve::PreserveAttr::from_string("ownership").unwrap(),
+            ..CopyOptions::none()
+        };
+        assert_eq!(codes(&plan, &options), [">f......."]);
+        options.overrides.owners = OwnerMap {
+            users: IdMap::parse_users(&format!("{}:{}", meta.uid(), meta.uid() + 1)).unwrap(),
+            groups: IdMap::parse_groups(&format!("{}:{}", meta.gid(), meta.gid() + 1)).unwrap(),
+        };
+        assert_eq!(codes(&plan, &options), [">f....og."]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_preserved_xattr_difference() {
+        let temp_dir = TempDir::new().unwrap();
+        let plan = file_plan(&temp_dir, b"same", Some(b"same"));
+        if xattr::set(temp_dir.path().join("src.txt"), "user.cpx", b"1").is_err() {
+            return; // filesystem without user xattrs
+        }
+        let options = CopyOptions {
+            preserve: crate::utility::preserve::PreserveAttr::from_string("xattr").unwrap(),
+            ..CopyOptions::none()
+        };
+        assert_eq!(codes(&plan, &options), [">f......x"]);
+    }
+
+    #[test]
+    fn test_directories_links_and_exclusions() {
+        let temp_dir = TempDir::new().unwrap();
+        let existing = temp_dir.path().join("existing");
+        fs::create_dir(&existing).unwrap();
+        let mut plan = CopyPlan::new();
+        plan.add_directory(None, temp_dir.path().join("new"), None);
+        plan.add_directory(Some(existing.clone()), existing.clone(), None);
+        plan.add_symlink(
+            existing.clone(),
+            temp_dir.path().join("link"),
+            crate::utility::preprocess::SymlinkKind::PreserveExact,
+        );
+        plan.add_hardlink(existing.clone(), temp_dir.path().join("hard"));
+        plan.excluded.push(ExcludedEntry {
+            destination: temp_dir.path().join("node_modules"),
+            is_dir: true,
+        });
+
+        let lines: Vec<String> = itemize_plan(&plan, &CopyOptions::none())
+            .iter()
+            .map(|item| {
+                item.to_string()
+                    .replace(&*temp_dir.path().to_string_lossy(), "")
+            })
+            .collect();
+        // The existing directory has nothing to change, so it is left out.
+        assert_eq!(
+            lines,
+            [
+                "hf+++++++ /hard => /existing",
+                "cL+++++++ /link -> /existing",
+                "cd+++++++ /new/",
+                "*excluded /node_modules/",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directory_named_with_a_slash_ends_with_one() {
+        assert_eq!(
+            Item::created_directory(Path::new("new/")).to_string(),
+            "cd+++++++ new/"
+        );
+        assert_eq!(
+            Item::message("*excluded", Path::new("skipped//"), true).to_string(),
+            "*excluded skipped/"
+        );
+        assert_eq!(
+            Item::created_directory(Path::new("/")).to_string(),
+            "cd+++++++ /"
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use filetime::FileTime;
+use jwalk::WalkDir;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const FILE_NAME: &str = "last-operation.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    Copy,
+    Move,
+}
+
+/// How a path was moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MoveMethod {
+    Rename,
+    /// Copied across filesystems, then the source was removed.
+    CopyDelete,
+}
+
+/// Size and newest mtime of a path; for a directory, over everything in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    pub size: u64,
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub original: PathBuf,
+    pub moved_to: PathBuf,
+    pub method: MoveMethod,
+    /// Where the file the move replaced was kept, if it was.
+    #[serde(default)]
+    pub backup: Option<PathBuf>,
+    pub stamp: Stamp,
+}
+
+/// A directory a copy created, with everything in it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreatedEntry {
+    pub path: PathBuf,
+    /// When the copy was journaled. Copies may carry old mtimes, so what
+    /// changed since is told by the inode change time instead.
+    pub recorded_secs: i64,
+    pub recorded_nanos: u32,
+}
+
+impl CreatedEntry {
+    /// Whether anything in the directory changed after it was journaled.
+    pub fn changed_since(&self) -> io::Result<bool> {
+        let recorded = FileTime::from_unix_time(self.recorded_secs, self.recorded_nanos);
+        for entry in WalkDir::new(&self.path)
+            .skip_hidden(false)
+            .follow_links(false)
+        {
+            let entry = entry.map_err(io::Error::other)?;
+            if change_time(&fs::symlink_metadata(entry.path())?) > recorded {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(unix)]
+fn change_time(metadata: &fs::Metadata) -> FileTime {
+    use std::os::unix::fs::MetadataExt;
+    FileTime::from_unix_time(metadata.ctime(), metadata.ctime_nsec() as u32)
+}
+
+#[cfg(not(unix))]
+fn change_time(metadata: &fs::Metadata) -> FileTime {
+    FileTime::from_last_modification_time(metadata)
+}
+
+/// The journal of the last operation, for `cpx undo`; every copy and move
+/// overwrites it.
+///
+/// A move records each path it moved, with the backup of anything it
+/// replaced and the stamp it ended up with, so undo can tell when a
+/// destination was touched since. A copy records the directories it
+/// created so undo can remove them again; files it wrote into directories
+/// that already existed are left alone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Journal {
+    pub operation: Operation,
+    #[serde(default)]
+    pub entries: Vec<JournalEntry>,
+    /// Topmost directories a copy created.
+    #[serde(default)]
+    pub created: Vec<CreatedEntry>,
+    /// The move journaled before this copy, which undo turns to once the
+    /// copy is undone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<Box<Journal>>,
+}
+
+impl Journal {
+    pub fn new(operation: Operation) -> Self {
+        Self {
+            operation,
+            entries: Vec::new(),
+            created: Vec::new(),
+            previous: None,
+        }
+    }
+
+    /// A copy that created `directories`, as of now. Only their own
+    /// metadata is read, so nothing in them gets a new access time.
+    pub fn for_copy(directories: &[PathBuf]) -> Self {
+        let now = FileTime::now();
+        let created = directories
+            .iter()
+            .filter(|path| path.symlink_metadata().is_ok_and(|m| m.is_dir()))
+            .map(|path| CreatedEntry {
+                path: path.clone(),
+                recorded_secs: now.unix_seconds(),
+                recorded_nanos: now.nanoseconds(),
+            })
+            .collect();
+        Self {
+            created,
+            ..Self::new(Operation::Copy)
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read_to_string(path)?;
+        serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(path, data)
+    }
+
+    /// Save as the last operation, if there is anywhere to keep it. A copy
+    /// keeps the last move it replaces, so that undo gets back to it.
+    pub fn record(&self) -> io::Result<()> {
+        let Some(path) = default_path() else {
+            return Ok(());
+        };
+        if self.operation != Operation::Copy {
+            return self.save(&path);
+        }
+        let previous = Journal::load(&path).ok().and_then(Journal::last_move);
+        Journal {
+            previous: previous.map(Box::new),
+            ..self.clone()
+        }
+        .save(&path)
+    }
+
+    /// The last move this journal knows of: itself, or the one a copy kept.
+    fn last_move(self) -> Option<Journal> {
+        match self.operation {
+            Operation::Move => Some(self),
+            Operation::Copy => self.previous.map(|previous| *previous),
+        }
+    }
+}
+
+/// Where the last operation is journaled: `$CPX_JOURNAL` when set, and
+/// otherwise `cpx/last-operation.json` in the user's local data directory.
+pub fn default_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("CPX_JOURNAL").filter(|p| !p.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+    dirs::data_local_dir().map(|dir| dir.join("cpx").join(FILE_NAME))
+}
+
+/// Stamp `path` as it is now. Symlinks are not followed.
+pub fn stamp(path: &Path) -> io::Result<Stamp> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut newest = FileTime::from_last_modification_time(&metadata);
+    if !metadata.is_dir() {
+        return Ok(make_stamp(metadata.len(), newest));
+    }
+
+    let mut size = 0;
+    for entry in WalkDir::new(path).skip_hidden(false).follow_links(false) {
+        let entry = entry.map_err(io::Error::other)?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+        newest = newest.max(FileTime::from_last_modification_time(&metadata));
+    }
+    Ok(make_stamp(size, newest))
+}
+
+fn make_stamp(size: u64, mtime: FileTime) -> Stamp {
+    Stamp {
+        size,
+        mtime_secs: mtime.unix_seconds(),
+        mtime_nanos: mtime.nanoseconds(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_journal_round_trips() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("report.pdf");
+        fs::write(&file, "pdf").unwrap();
+        let mut journal = Journal::new(Operation::Move);
+        journal.entries.push(JournalEntry {
+            original: temp_dir.path().join("inbox/report.pdf"),
+            moved_to: file.clone(),
+            method: MoveMethod::CopyDelete,
+            backup: None,
+            stamp: stamp(&file).unwrap(),
+        });
+        let path = temp_dir.path().join("state/journal.json");
+
+        journal.save(&path).unwrap();
+
+        assert_eq!(Journal::load(&path).unwrap(), journal);
+    }
+
+    #[test]
+    fn test_copy_journal_keeps_created_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let created = temp_dir.path().join("backup");
+        fs::create_dir(&created).unwrap();
+        fs::write(created.join("notes.txt"), "notes").unwrap();
+        let path = temp_dir.path().join("journal.json");
+
+        let journal = Journal::for_copy(&[created.clone(), temp_dir.path().join("gone")]);
+        journal.save(&path).unwrap();
+
+        let loaded = Journal::load(&path).unwrap();
+        assert_eq!(loaded.operation, Operation::Copy);
+        assert_eq!(loaded.created.len(), 1);
+        assert_eq!(loaded.created[0].path, created);
+        assert!(!loaded.created[0].changed_since().unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(created.join("notes.txt"), "edited").unwrap();
+        assert!(loaded.created[0].changed_since().unwrap());
+        // Journals written before directories were recorded still load.
+        fs::write(&path, r#"{"operation":"copy"}"#).unwrap();
+        assert!(Journal::load(&path).unwrap().created.is_empty());
+    }
+
+    #[test]
+    fn test_stamp_changes_when_a_file_in_a_directory_does() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("photos");
+        fs::create_dir(&dir).unwrap();
+        let photo = dir.join("a.jpg");
+        fs::write(&photo, "jpeg").unwrap();
+        let old = FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(&photo, old).unwrap();
+        filetime::set_file_mtime(&dir, old).unwrap();
+        let before = stamp(&dir).unwrap();
+        assert_eq!(before.size, 4);
+
+        fs::write(&photo, "jpeg").unwrap();
+        filetime::set_file_mtime(&dir, old).unwrap();
+
+        assert_ne!(stamp(&dir).unwrap(), before);
+    }
+}
@@ -0,0 +1,146 @@
+use std::error::Error;
+use std::io;
+
+/// Descriptors a single copy worker may hold at once (source, destination,
+/// and one spare for backups or directory handles).
+pub const FDS_PER_WORKER: u64 = 3;
+
+/// Descriptors kept aside for stdio, the directory walker, signal handling
+/// and anything else outside the workers.
+pub const FD_RESERVE: u64 = 64;
+
+/// Hint shown when a copy fails because descriptors ran out.
+pub const FD_EXHAUSTION_HINT: &str =
+    "too many open files; try a lower -j or raise the limit with `ulimit -n`";
+
+/// Descriptors needed to run `parallel` workers.
+pub fn fds_needed(parallel: usize) -> u64 {
+    FD_RESERVE + parallel as u64 * FDS_PER_WORKER
+}
+
+/// Largest number of workers, at most `parallel`, that fits in `limit`
+/// descriptors. Never less than one.
+pub fn parallelism_for_limit(limit: u64, parallel: usize) -> usize {
+    let fitting = limit.saturating_sub(FD_RESERVE) / FDS_PER_WORKER;
+    (fitting.min(parallel as u64) as usize).max(1)
+}
+
+/// Raise the soft descriptor limit to at least `wanted` if the hard limit
+/// allows it, and return the soft limit now in effect.
+#[cfg(unix)]
+pub fn raise_fd_limit(wanted: u64) -> io::Result<u64> {
+    use nix::sys::resource::{Resource, getrlimit, setrlimit};
+
+    let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE).map_err(io::Error::from)?;
+    if soft >= wanted {
+        return Ok(soft);
+    }
+
+    // macOS rejects soft limits above OPEN_MAX even when the hard limit is
+    // unlimited.
+    #[cfg(target_os = "macos")]
+    let hard = hard.min(libc::OPEN_MAX as u64);
+
+    let new_soft = wanted.min(hard);
+    if new_soft <= soft {
+        return Ok(soft);
+    }
+    setrlimit(Resource::RLIMIT_NOFILE, new_soft, hard).map_err(io::Error::from)?;
+    Ok(new_soft)
+}
+
+#[cfg(not(unix))]
+pub fn raise_fd_limit(wanted: u64) -> io::Result<u64> {
+    Ok(wanted)
+}
+
+/// Make sure `parallel` workers fit in the descriptor limit, raising it if
+/// needed, and return the parallelism to actually use.
+///
+/// With a high `-j` the usual soft limit of 1024 can run out mid-copy, so the
+/// soft limit is raised toward the hard limit at startup and parallelism is
+/// capped to whatever still fits.
+pub fn fit_parallelism(parallel: usize) -> usize {
+    let limit = match raise_fd_limit(fds_needed(parallel)) {
+        Ok(limit) => limit,
+        Err(_) => return parallel,
+    };
+    parallelism_for_limit(limit, parallel)
+}
+
+/// Whether `err`, or any error it wraps, is EMFILE/ENFILE.
+pub fn is_fd_exhaustion(err: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if let Some(io_err) = e.downcast_ref::<io::Error>()
+            && matches!(io_err.raw_os_error(), Some(code) if is_fd_errno(code))
+        {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+#[cfg(unix)]
+fn is_fd_errno(code: i32) -> bool {
+    code == libc::EMFILE || code == libc::ENFILE
+}
+
+#[cfg(not(unix))]
+fn is_fd_errno(_code: i32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CopyError;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_fds_needed_scales_with_parallelism() {
+        assert_eq!(fds_needed(1), FD_RESERVE + FDS_PER_WORKER);
+        assert_eq!(fds_needed(64), FD_RESERVE + 64 * FDS_PER_WORKER);
+    }
+
+    #[test]
+    fn test_parallelism_kept_when_limit_is_enough() {
+        assert_eq!(parallelism_for_limit(1024, 64), 64);
+        assert_eq!(parallelism_for_limit(fds_needed(16), 16), 16);
+    }
+
+    #[test]
+    fn test_parallelism_capped_to_limit() {
+        // 256 - 64 reserved leaves room for 64 workers at 3 each.
+        assert_eq!(parallelism_for_limit(256, 512), 64);
+        assert_eq!(parallelism_for_limit(fds_needed(16) - 1, 16), 15);
+    }
+
+    #[test]
+    fn test_parallelism_never_below_one() {
+        assert_eq!(parallelism_for_limit(0, 8), 1);
+        assert_eq!(parallelism_for_limit(FD_RESERVE, 8), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_raise_fd_limit_reports_current_when_enough() {
+        let current = raise_fd_limit(1).unwrap();
+        assert!(current >= 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_fd_exhaustion_sees_wrapped_emfile() {
+        let err = CopyError::OperationFailed {
+            operation: "open".to_string(),
+            path: PathBuf::from("/src/a"),
+            error: io::Error::from_raw_os_error(libc::EMFILE),
+        };
+        assert!(is_fd_exhaustion(&err));
+
+        let other = CopyError::Io(io::Error::from_raw_os_error(libc::ENOENT));
+        assert!(!is_fd_exhaustion(&other));
+    }
+}
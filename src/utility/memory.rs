@@ -0,0 +1,134 @@
+/// Largest buffer the buffered copy picks on its own, for files of 512 MiB
+/// and up.
+pub const LARGEST_BUFFER: usize = 2 * 1024 * 1024;
+
+/// Buffers are not shrunk below this; workers go instead.
+pub const SMALLEST_BUFFER: usize = 64 * 1024;
+
+/// A planned entry without its paths: the task, its metadata and the
+/// allocations' own bookkeeping.
+pub const PLAN_ENTRY_OVERHEAD: u64 = 256;
+
+/// Workers and buffer size that fit a memory limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLimits {
+    pub parallel: usize,
+    /// `None` when the buffers already fit as they are.
+    pub buffer_size: Option<usize>,
+}
+
+impl MemoryLimits {
+    /// Buffers held by all workers at once, at most.
+    pub fn buffers(&self, writers: usize) -> u64 {
+        let buffer = self.buffer_size.unwrap_or(LARGEST_BUFFER);
+        (buffer * (1 + writers) * self.parallel) as u64
+    }
+}
+
+/// Fit `parallel` workers, each with a read buffer of `buffer_size` (or the
+/// adaptive default) and `writers` write buffers, within `max_memory`.
+///
+/// These buffers are most of what a copy allocates, so under a limit they
+/// shrink first, down to [`SMALLEST_BUFFER`], and only then are workers
+/// taken away, down to one. copy_file_range, reflinks and mmap go through
+/// the page cache, which the kernel trims by itself.
+pub fn fit_buffers(
+    max_memory: u64,
+    parallel: usize,
+    buffer_size: Option<usize>,
+    writers: usize,
+) -> MemoryLimits {
+    let per_buffer = (1 + writers) as u64;
+    let largest = buffer_size.unwrap_or(LARGEST_BUFFER) as u64;
+    if largest * per_buffer * parallel as u64 <= max_memory {
+        return MemoryLimits {
+            parallel,
+            buffer_size,
+        };
+    }
+    // Whole pages, so reads stay aligned.
+    let buffer = (max_memory / (per_buffer * parallel as u64)) & !4095;
+    let smallest = buffer_size.map_or(SMALLEST_BUFFER, |size| size.min(SMALLEST_BUFFER)) as u64;
+    if buffer >= smallest {
+        return MemoryLimits {
+            parallel,
+            buffer_size: Some(buffer as usize),
+        };
+    }
+    MemoryLimits {
+        parallel: ((max_memory / (smallest * per_buffer)) as usize).clamp(1, parallel),
+        buffer_size: Some(smallest as usize),
+    }
+}
+
+/// Rough size of a plan holding `entries` entries whose source and
+/// destination paths come to `path_bytes`.
+///
+/// The plan holds every entry until the copy ends, so one over the limit
+/// gets a warning; it is too late by then to plan less, but the copy can
+/// still be split up.
+pub fn estimate_plan(entries: usize, path_bytes: u64) -> u64 {
+    entries as u64 * PLAN_ENTRY_OVERHEAD + path_bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_buffers_that_fit_are_left_alone() {
+        assert_eq!(
+            fit_buffers(512 * MIB, 8, None, 1),
+            MemoryLimits {
+                parallel: 8,
+                buffer_size: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_buffers_shrink_before_workers_go() {
+        // 8 workers with a read and a write buffer each: 1 MiB apiece.
+        let limits = fit_buffers(16 * MIB, 8, None, 1);
+        assert_eq!(limits.parallel, 8);
+        assert_eq!(limits.buffer_size, Some(MIB as usize));
+        assert!(limits.buffers(1) <= 16 * MIB);
+
+        // An --also-to target is one more buffer per worker.
+        let limits = fit_buffers(32 * MIB, 8, Some(4 * MIB as usize), 2);
+        assert_eq!(limits.buffer_size, Some(1_396_736));
+        assert!(limits.buffers(2) <= 32 * MIB);
+    }
+
+    #[test]
+    fn test_workers_go_once_buffers_are_smallest() {
+        let limits = fit_buffers(MIB, 32, None, 1);
+        assert_eq!(
+            limits,
+            MemoryLimits {
+                parallel: 8,
+                buffer_size: Some(SMALLEST_BUFFER)
+            }
+        );
+        // Never below one worker.
+        assert_eq!(fit_buffers(1, 4, None, 1).parallel, 1);
+        // A buffer chosen smaller than the floor isn't raised to it.
+        assert_eq!(
+            fit_buffers(512 * 1024, 128, Some(4096), 1),
+            MemoryLimits {
+                parallel: 64,
+                buffer_size: Some(4096)
+            }
+        );
+    }
+
+    #[test]
+    fn test_plan_estimate_counts_entries_and_paths() {
+        assert_eq!(
+            estimate_plan(1000, 50_000),
+            1000 * PLAN_ENTRY_OVERHEAD + 50_000
+        );
+    }
+}
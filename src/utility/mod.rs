@@ -1,6 +1,21 @@
+pub mod background;
 pub mod backup;
+pub mod checksum;
+pub mod chmod;
+pub mod confine;
 pub mod exclude;
+pub mod files_from;
+pub mod filesystem;
 pub mod helper;
+pub mod idmap;
+pub mod itemize;
+pub mod journal;
+pub mod limits;
+pub mod memory;
+pub mod network;
+pub mod notify;
+pub mod output;
 pub mod preprocess;
 pub mod preserve;
 pub mod progress_bar;
+pub mod staging;
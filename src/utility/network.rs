@@ -0,0 +1,54 @@
+use crate::utility::filesystem::{FsKind, filesystem_family};
+use std::path::{Path, PathBuf};
+
+/// Most workers used on a network filesystem unless `-j` says otherwise.
+pub const PARALLEL: usize = 4;
+
+/// Buffered copy size unless `--buffer-size` says otherwise.
+pub const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// copy_file_range chunk size unless `--offload-chunk-size` says otherwise.
+pub const OFFLOAD_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Where a network filesystem is involved in a copy.
+///
+/// NFS, SMB and SSHFS turn every open, stat and close into a round trip to
+/// the server, and a server fielding dozens of workers mostly spends its
+/// time on locks. Copies there get fewer workers and larger reads and
+/// writes, and skip preallocation, which such filesystems either emulate by
+/// writing zeros over the wire or don't do at all. Each of these yields to
+/// its own flag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkPath {
+    pub kind: FsKind,
+    pub destination: bool,
+}
+
+impl NetworkPath {
+    /// e.g. `destination is on nfs`.
+    pub fn describe(&self) -> String {
+        let side = if self.destination {
+            "destination"
+        } else {
+            "source"
+        };
+        format!("{} is on {}", side, self.kind.name())
+    }
+}
+
+/// The first network filesystem among `destination` and `sources`, the
+/// destination first since that is where the writes and metadata go.
+pub fn find_network_path(sources: &[PathBuf], destination: &Path) -> Option<NetworkPath> {
+    let candidates = std::iter::once((destination, true))
+        .chain(sources.iter().map(|source| (source.as_path(), false)));
+    for (path, is_destination) in candidates {
+        let kind = filesystem_family(path);
+        if kind.is_network() {
+            return Some(NetworkPath {
+                kind,
+                destination: is_destination,
+            });
+        }
+    }
+    None
+}
@@ -0,0 +1,232 @@
+use crate::utility::output::{self, Summary};
+use indicatif::HumanBytes;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Longest a notification command may run before it is killed.
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How a run ended, as `CPX_STATUS` tells it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Everything planned was copied.
+    Success,
+    /// Some of it was: files failed, the budget ran out or the run stopped
+    /// after copying something.
+    Partial,
+    /// The run failed before copying anything.
+    Failed,
+}
+
+impl Status {
+    /// The status of a run that copied what `summary` says and ended with
+    /// `failed` set if it returned an error.
+    pub fn of(summary: &Summary, failed: bool) -> Self {
+        match (failed, summary.files_copied) {
+            (false, _) if summary.files_failed == 0 && summary.files_remaining == 0 => {
+                Status::Success
+            }
+            (true, 0) => Status::Failed,
+            _ => Status::Partial,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Success => "success",
+            Status::Partial => "partial",
+            Status::Failed => "failed",
+        })
+    }
+}
+
+/// The outcome of one run, as handed to the hook.
+#[derive(Debug, Clone)]
+pub struct Outcome<'a> {
+    pub status: Status,
+    pub summary: &'a Summary,
+    pub elapsed: Duration,
+    pub destination: &'a Path,
+}
+
+impl Outcome<'_> {
+    /// Errors to report: failed files and unreadable entries, or the one
+    /// error a run that failed outright stopped at.
+    pub fn errors(&self) -> usize {
+        let errors = self.summary.files_failed + self.summary.entries_unreadable;
+        if self.status == Status::Failed {
+            errors.max(1)
+        } else {
+            errors
+        }
+    }
+
+    /// The `CPX_*` variables the hook gets.
+    pub fn environment(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("CPX_STATUS", self.status.to_string()),
+            ("CPX_FILES_COPIED", self.summary.files_copied.to_string()),
+            ("CPX_BYTES", self.summary.bytes_copied.to_string()),
+            ("CPX_ERRORS", self.errors().to_string()),
+            (
+                "CPX_ELAPSED_SECS",
+                format!("{:.3}", self.elapsed.as_secs_f64()),
+            ),
+            ("CPX_DEST", self.destination.display().to_string()),
+        ]
+    }
+
+    /// One line for a desktop notification.
+    pub fn describe(&self) -> String {
+        match self.status {
+            Status::Success => format!(
+                "Copied {} files ({}) to {}",
+                self.summary.files_copied,
+                HumanBytes(self.summary.bytes_copied),
+                self.destination.display()
+            ),
+            Status::Partial => format!(
+                "Copied {} files ({}) to {}, with {} errors",
+                self.summary.files_copied,
+                HumanBytes(self.summary.bytes_copied),
+                self.destination.display(),
+                self.errors()
+            ),
+            Status::Failed => format!("Copy to {} failed", self.destination.display()),
+        }
+    }
+}
+
+/// Run `command` through `sh -c` with the outcome in its environment, for
+/// `--notify-cmd`, so an orchestrator doesn't have to poll.
+///
+/// What it prints goes to stderr, out of the way of `--porcelain`. A hook
+/// that doesn't finish within [`HOOK_TIMEOUT`] is killed; a hook that fails
+/// is reported but never changes how cpx exits.
+pub fn run_hook(command: &str, outcome: &Outcome) {
+    let mut hook = Command::new("sh");
+    hook.arg("-c").arg(command).envs(outcome.environment());
+    if let Err(e) = run_with_timeout(hook, HOOK_TIMEOUT) {
+        output::warning(format!("--notify-cmd '{}' failed: {}", command, e));
+    }
+}
+
+/// Show the outcome as a desktop notification: notify-send on Linux and
+/// the BSDs, Notification Center on macOS.
+pub fn notify_desktop(outcome: &Outcome) {
+    let message = outcome.describe();
+    let notifier = if cfg!(target_os = "macos") {
+        let mut osascript = Command::new("osascript");
+        osascript.arg("-e").arg(format!(
+            "display notification {:?} with title \"cpx\"",
+            message
+        ));
+        osascript
+    } else if cfg!(unix) {
+        let mut notify_send = Command::new("notify-send");
+        if outcome.status != Status::Success {
+            notify_send.arg("--urgency=critical");
+        }
+        notify_send.arg("cpx").arg(&message);
+        notify_send
+    } else {
+        output::warning("--notify-desktop is not supported on this platform");
+        return;
+    };
+    if let Err(e) = run_with_timeout(notifier, HOOK_TIMEOUT) {
+        output::warning(format!("cannot show a desktop notification: {}", e));
+    }
+}
+
+/// Run `command` to completion, killing it after `timeout`. Its output goes
+/// to stderr.
+fn run_with_timeout(mut command: Command, timeout: Duration) -> io::Result<()> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(io::stderr()))
+        .spawn()?;
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(io::Error::other(format!("exited with {}", status)));
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("still running after {}s, killed", timeout.as_secs()),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_tells_success_partial_and_failure_apart() {
+        let copied = Summary {
+            files_copied: 3,
+            ..Summary::default()
+        };
+        assert_eq!(Status::of(&copied, false), Status::Success);
+        assert_eq!(Status::of(&copied, true), Status::Partial);
+        assert_eq!(Status::of(&Summary::default(), true), Status::Failed);
+        // Nothing to copy is still a success.
+        assert_eq!(Status::of(&Summary::default(), false), Status::Success);
+        let budgeted = Summary {
+            files_remaining: 2,
+            ..copied.clone()
+        };
+        assert_eq!(Status::of(&budgeted, false), Status::Partial);
+    }
+
+    #[test]
+    fn test_environment_describes_the_outcome() {
+        let summary = Summary {
+            files_copied: 2,
+            bytes_copied: 2048,
+            files_failed: 1,
+            ..Summary::default()
+        };
+        let outcome = Outcome {
+            status: Status::of(&summary, true),
+            summary: &summary,
+            elapsed: Duration::from_millis(1500),
+            destination: Path::new("/backup"),
+        };
+        assert_eq!(
+            outcome.environment(),
+            [
+                ("CPX_STATUS", "partial".to_string()),
+                ("CPX_FILES_COPIED", "2".to_string()),
+                ("CPX_BYTES", "2048".to_string()),
+                ("CPX_ERRORS", "1".to_string()),
+                ("CPX_ELAPSED_SECS", "1.500".to_string()),
+                ("CPX_DEST", "/backup".to_string()),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hung_command_is_killed() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("sleep 10");
+        let started = Instant::now();
+        let error = run_with_timeout(command, Duration::from_millis(200)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
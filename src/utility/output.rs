@@ -0,0 +1,288 @@
+use indicatif::ProgressBar;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Version of the `--porcelain` format, printed as the first line.
+pub const PORCELAIN_VERSION: u32 = 1;
+
+/// What a shell reports for a process killed by SIGPIPE: 128 + 13.
+pub const SIGPIPE_STATUS: i32 = 141;
+
+/// Set once a line printed alongside a copy found stdout closed.
+static STDOUT_CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// Print an informational message for the user. Everything meant for a
+/// human, progress, warnings and prompts included, goes to stderr, so that
+/// stdout carries only data and can be piped safely.
+pub fn status(message: impl Display) {
+    eprintln!("{}", message);
+}
+
+/// Print a line of data the user asked for, such as `config show` or the
+/// `--porcelain` summary, to stdout. A reader that goes away early, as
+/// `head` does, makes the command exit quietly with [`SIGPIPE_STATUS`],
+/// like any other tool in a pipeline.
+pub fn data(line: impl Display) {
+    if let Err(e) = writeln!(io::stdout().lock(), "{}", line) {
+        if e.kind() == io::ErrorKind::BrokenPipe {
+            std::process::exit(SIGPIPE_STATUS);
+        }
+        error(format!("failed to write to stdout: {}", e));
+        std::process::exit(1);
+    }
+}
+
+/// Print a line to stdout that comes with a copy, such as an itemized
+/// change. Once no one reads stdout these are dropped, and the copy goes
+/// on without them.
+pub fn side_data(line: impl Display) {
+    write_side(&mut io::stdout().lock(), &STDOUT_CLOSED, line);
+}
+
+/// Print a `-v` line, `'from' -> 'to'`, with `arrow` between them, to
+/// stdout. A progress bar is cleared while it is written and drawn again
+/// after, so the two don't tear each other.
+pub fn verbose(progress: Option<&ProgressBar>, from: &Path, arrow: &str, to: &Path) {
+    let line = format!("'{}' {} '{}'", from.display(), arrow, to.display());
+    match progress {
+        Some(pb) => pb.suspend(|| side_data(line)),
+        None => side_data(line),
+    }
+}
+
+/// Print a `--debug` line while `progress` may be drawn, clearing the bar
+/// for it as [`verbose`] does.
+pub fn debug_above(progress: Option<&ProgressBar>, message: impl Display) {
+    match progress {
+        Some(pb) => pb.suspend(|| debug(message)),
+        None => debug(message),
+    }
+}
+
+fn write_side(out: &mut impl Write, closed: &AtomicBool, line: impl Display) {
+    if closed.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Err(e) = writeln!(out, "{}", line) {
+        closed.store(true, Ordering::Relaxed);
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            warning(format!("stopped writing to stdout: {}", e));
+        }
+    }
+}
+
+/// Let SIGPIPE end the process, as it does other tools. For commands that
+/// only print to stdout, including what they print with `println!`.
+pub fn restore_sigpipe() {
+    #[cfg(unix)]
+    // SAFETY: resetting a signal to its default disposition, before any
+    // thread that could be writing to a pipe is started.
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+}
+
+/// Print a non-fatal problem the user should know about.
+pub fn warning(message: impl Display) {
+    eprintln!("Warning: {}", message);
+}
+
+/// Print an error message for the user.
+pub fn error(message: impl Display) {
+    eprintln!("Error: {}", message);
+}
+
+/// Print a `--debug` trace line.
+pub fn debug(message: impl Display) {
+    eprintln!("debug: {}", message);
+}
+
+/// Ask a yes/no question on stderr and read the answer from stdin.
+pub fn confirm(question: impl Display) -> io::Result<bool> {
+    let mut stderr = io::stderr().lock();
+    write!(stderr, "{} (y/n): ", question)?;
+    stderr.flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Ask a question on stderr and return the trimmed, lowercased answer.
+pub fn ask(question: impl Display) -> io::Result<String> {
+    let mut stderr = io::stderr().lock();
+    write!(stderr, "{} ", question)?;
+    stderr.flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_ascii_lowercase())
+}
+
+/// Totals for a single cpx invocation.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+    pub files_skipped: usize,
+    pub files_failed: usize,
+    pub directories_created: usize,
+    pub symlinks_created: usize,
+    pub hardlinks_created: usize,
+    pub interrupted: bool,
+    pub bytes_deduplicated: u64,
+    pub bytes_identical: u64,
+    pub entries_unreadable: usize,
+    /// Attributes that were asked for but couldn't be set.
+    pub attributes_failed: usize,
+    /// Files `--max-bytes` or `--max-files` left for the next run.
+    pub files_remaining: usize,
+    pub bytes_remaining: u64,
+    /// Files skipped as complete whose attributes `--fix-attrs` set again.
+    pub attributes_refreshed: usize,
+    /// Of the skipped files, existing destinations `--no-clobber` left
+    /// alone.
+    pub files_untouched: usize,
+    /// Files `--existing` left out because they weren't at the destination.
+    pub files_new_ignored: usize,
+}
+
+impl Summary {
+    /// Count `other` in as well, e.g. another call of the same session.
+    pub fn add(&mut self, other: &Summary) {
+        self.files_copied += other.files_copied;
+        self.bytes_copied += other.bytes_copied;
+        self.files_skipped += other.files_skipped;
+        self.files_failed += other.files_failed;
+        self.directories_created += other.directories_created;
+        self.symlinks_created += other.symlinks_created;
+        self.hardlinks_created += other.hardlinks_created;
+        self.interrupted |= other.interrupted;
+        self.bytes_deduplicated += other.bytes_deduplicated;
+        self.bytes_identical += other.bytes_identical;
+        self.entries_unreadable += other.entries_unreadable;
+        self.attributes_failed += other.attributes_failed;
+        self.files_remaining += other.files_remaining;
+        self.bytes_remaining += other.bytes_remaining;
+        self.attributes_refreshed += other.attributes_refreshed;
+        self.files_untouched += other.files_untouched;
+        self.files_new_ignored += other.files_new_ignored;
+    }
+
+    /// Write the summary as `key value` lines.
+    ///
+    /// The format is stable: keys are never renamed or removed and new keys
+    /// are only appended. Scripts should ignore keys they do not recognise.
+    pub fn write_porcelain(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "version {}", PORCELAIN_VERSION)?;
+        writeln!(out, "files_copied {}", self.files_copied)?;
+        writeln!(out, "bytes_copied {}", self.bytes_copied)?;
+        writeln!(out, "files_skipped {}", self.files_skipped)?;
+        writeln!(out, "files_failed {}", self.files_failed)?;
+        writeln!(out, "directories_created {}", self.directories_created)?;
+        writeln!(out, "symlinks_created {}", self.symlinks_created)?;
+        writeln!(out, "hardlinks_created {}", self.hardlinks_created)?;
+        writeln!(out, "interrupted {}", self.interrupted)?;
+        writeln!(out, "bytes_deduplicated {}", self.bytes_deduplicated)?;
+        writeln!(out, "bytes_identical {}", self.bytes_identical)?;
+        writeln!(out, "entries_unreadable {}", self.entries_unreadable)?;
+        writeln!(out, "attributes_failed {}", self.attributes_failed)?;
+        writeln!(out, "files_remaining {}", self.files_remaining)?;
+        writeln!(out, "bytes_remaining {}", self.bytes_remaining)?;
+        writeln!(out, "attributes_refreshed {}", self.attributes_refreshed)?;
+        writeln!(out, "files_untouched {}", self.files_untouched)?;
+        writeln!(out, "files_new_ignored {}", self.files_new_ignored)?;
+        Ok(())
+    }
+}
+
+/// Print the `--porcelain` summary to stdout.
+pub fn print_porcelain(summary: &Summary) {
+    let mut stdout = io::stdout().lock();
+    if let Err(e) = summary.write_porcelain(&mut stdout)
+        && e.kind() != io::ErrorKind::BrokenPipe
+    {
+        error(format!("failed to write summary: {}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_porcelain_format_is_stable() {
+        let summary = Summary {
+            files_copied: 3,
+            bytes_copied: 4096,
+            files_skipped: 1,
+            directories_created: 2,
+            ..Summary::default()
+        };
+        let mut out = Vec::new();
+        summary.write_porcelain(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "version 1\n\
+             files_copied 3\n\
+             bytes_copied 4096\n\
+             files_skipped 1\n\
+             files_failed 0\n\
+             directories_created 2\n\
+             symlinks_created 0\n\
+             hardlinks_created 0\n\
+             interrupted false\n\
+             bytes_deduplicated 0\n\
+             bytes_identical 0\n\
+             entries_unreadable 0\n\
+             attributes_failed 0\n\
+             files_remaining 0\n\
+             bytes_remaining 0\n\
+             attributes_refreshed 0\n\
+             files_untouched 0\n\
+             files_new_ignored 0\n"
+        );
+    }
+
+    /// Accepts `room` writes, then behaves like a pipe whose reader left.
+    struct ClosingPipe {
+        room: usize,
+        written: Vec<u8>,
+    }
+
+    impl Write for ClosingPipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.room -= 1;
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_side_lines_stop_once_the_reader_leaves() {
+        let mut pipe = ClosingPipe {
+            room: 2,
+            written: Vec::new(),
+        };
+        let closed = AtomicBool::new(false);
+        write_side(&mut pipe, &closed, "first");
+        assert!(!closed.load(Ordering::Relaxed));
+        write_side(&mut pipe, &closed, "second");
+        assert!(closed.load(Ordering::Relaxed));
+
+        // Nothing more is attempted, even were there room again.
+        pipe.room = 10;
+        write_side(&mut pipe, &closed, "third");
+        assert_eq!(pipe.written, b"first\n");
+    }
+}
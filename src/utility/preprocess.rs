@@ -1,13 +1,22 @@
+use super::checksum::xxh3_file;
 use super::exclude::should_exclude;
-use super::helper::with_parents;
-use crate::cli::args::{CopyOptions, FollowSymlink, SymlinkMode};
+use super::helper::{
+    FileId, device_of, file_id, has_trailing_slash, names_contents, strip_trailing_slashes,
+    with_parents,
+};
+use super::memory;
+use super::output;
+use crate::cli::args::{
+    CollisionPolicy, CopyOptions, CopyOrder, LinkFallback, Prompt, SymlinkMode, UpdateMode,
+};
 use crate::error::{CopyError, CopyResult};
 use jwalk::WalkDir;
-use std::collections::HashMap;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::fs::Metadata;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io;
-use std::path::{Path, PathBuf};
-use xxhash_rust::xxh3::Xxh3;
+use std::path::{Component, Path, PathBuf};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SymlinkKind {
@@ -21,13 +30,17 @@ pub struct FileTask {
     pub source: PathBuf,
     pub destination: PathBuf,
     pub size: u64,
-    pub inode_group: Option<u64>, // For tracking hard link groups
+    pub inode_group: Option<u64>,   // For tracking hard link groups
+    pub metadata: Option<Metadata>, // Source as stat'ed while planning, before any read
+    pub dir_group: Option<PathBuf>, // Directory asked about by --interactive=dirs
+    pub existing: bool,             // Destination there when planned, for -i to ask about
 }
 
 #[derive(Debug, Clone)]
 pub struct DirectoryTask {
     pub source: Option<PathBuf>,
     pub destination: PathBuf,
+    pub metadata: Option<Metadata>, // Source as stat'ed while planning
 }
 
 #[derive(Debug, Clone)]
@@ -35,6 +48,9 @@ pub struct SymlinkTask {
     pub source: PathBuf,
     pub destination: PathBuf,
     pub kind: SymlinkKind,
+    pub inode_group: Option<u64>,   // For tracking hard-linked symlinks
+    pub metadata: Option<Metadata>, // The source link as lstat'ed, when copied as a link
+    pub origin: Option<PathBuf>,    // The source link, when `source` is what it points to
 }
 
 #[derive(Debug, Clone)]
@@ -43,18 +59,69 @@ pub struct HardlinkTask {
     pub destination: PathBuf,
 }
 
+/// A pipe, device or socket found while walking a directory, recreated as a
+/// node of the same kind unless `--copy-contents` asks for its data.
+#[derive(Debug, Clone)]
+pub struct SpecialTask {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+}
+
+/// A file `--resume` skipped as complete whose attributes `--fix-attrs`
+/// checks again.
+#[derive(Debug, Clone)]
+pub struct AttrTask {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub metadata: Metadata,
+}
+
+/// An entry left out by an exclude pattern, recorded for itemized output.
+#[derive(Debug, Clone)]
+pub struct ExcludedEntry {
+    pub destination: PathBuf,
+    pub is_dir: bool,
+}
+
+/// A path the directory walk couldn't read. Whatever it holds is left out
+/// of the plan, and the copy reports it once the rest is done.
+#[derive(Debug, Clone)]
+pub struct UnreadableEntry {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
 #[derive(Debug)]
 pub struct CopyPlan {
     pub files: Vec<FileTask>,
     pub directories: Vec<DirectoryTask>,
     pub symlinks: Vec<SymlinkTask>,
     pub hardlinks: Vec<HardlinkTask>,
+    pub specials: Vec<SpecialTask>,
+    pub refresh: Vec<AttrTask>,
+    /// Only filled in when changes are itemized.
+    pub excluded: Vec<ExcludedEntry>,
+    pub unreadable: Vec<UnreadableEntry>,
     pub total_size: u64,
     pub total_files: usize,
     pub total_symlinks: usize,
     pub total_hardlinks: usize,
     pub skipped_files: usize,
     pub skipped_size: u64,
+    /// Of the skipped files, those `--no-clobber` left in place.
+    pub kept_existing: usize,
+    /// Files, links and special files `--existing` left out because
+    /// nothing was at their destination.
+    pub ignored_new: usize,
+    pub symlink_fallbacks: usize,
+    pub hardlink_fallbacks: usize,
+    /// Directories on another filesystem that `-x` made empty rather than
+    /// walked into.
+    pub other_filesystems: usize,
+    /// Hashes of every destination a task was added for, so that adding
+    /// one only searches the plan for a task it replaces when there may be
+    /// one.
+    destinations: HashSet<u64>,
 }
 
 impl Default for CopyPlan {
@@ -70,23 +137,42 @@ impl CopyPlan {
             directories: Vec::new(),
             symlinks: Vec::new(),
             hardlinks: Vec::new(),
+            specials: Vec::new(),
+            refresh: Vec::new(),
+            excluded: Vec::new(),
+            unreadable: Vec::new(),
             total_size: 0,
             total_files: 0,
             total_symlinks: 0,
             total_hardlinks: 0,
             skipped_files: 0,
             skipped_size: 0,
+            kept_existing: 0,
+            ignored_new: 0,
+            symlink_fallbacks: 0,
+            hardlink_fallbacks: 0,
+            other_filesystems: 0,
+            destinations: HashSet::new(),
         }
     }
 
     pub fn add_file(&mut self, source: PathBuf, destination: PathBuf, size: u64) {
-        self.add_file_with_inode(source, destination, size, None);
+        self.add_file_with_inode(source, destination, size, None, None);
     }
 
     // last source wins, if multiple sources collide prevents symlink write-through
     fn remove_existing_task(&mut self, dest: &Path) {
+        let mut hasher = DefaultHasher::new();
+        dest.hash(&mut hasher);
+        if self.destinations.insert(hasher.finish()) {
+            return;
+        }
+        let (symlinks, hardlinks) = (self.symlinks.len(), self.hardlinks.len());
         self.symlinks.retain(|t| t.destination != dest);
         self.hardlinks.retain(|t| t.destination != dest);
+        self.specials.retain(|t| t.destination != dest);
+        self.total_symlinks -= symlinks - self.symlinks.len();
+        self.total_hardlinks -= hardlinks - self.hardlinks.len();
 
         if let Some(pos) = self.files.iter().position(|t| t.destination == dest) {
             let removed = self.files.remove(pos);
@@ -100,6 +186,7 @@ impl CopyPlan {
         destination: PathBuf,
         size: u64,
         inode_group: Option<u64>,
+        metadata: Option<Metadata>,
     ) {
         self.remove_existing_task(&destination);
         self.files.push(FileTask {
@@ -107,24 +194,48 @@ impl CopyPlan {
             destination,
             size,
             inode_group,
+            metadata,
+            dir_group: None,
+            existing: false,
         });
         self.total_size += size;
         self.total_files += 1;
     }
 
-    pub fn add_directory(&mut self, source: Option<PathBuf>, destination: PathBuf) {
+    pub fn add_directory(
+        &mut self,
+        source: Option<PathBuf>,
+        destination: PathBuf,
+        metadata: Option<Metadata>,
+    ) {
         self.directories.push(DirectoryTask {
             source,
             destination,
+            metadata,
         });
     }
 
     pub fn add_symlink(&mut self, source: PathBuf, destination: PathBuf, kind: SymlinkKind) {
+        self.add_symlink_with_inode(source, destination, kind, None, None, None);
+    }
+
+    pub fn add_symlink_with_inode(
+        &mut self,
+        source: PathBuf,
+        destination: PathBuf,
+        kind: SymlinkKind,
+        inode_group: Option<u64>,
+        metadata: Option<Metadata>,
+        origin: Option<PathBuf>,
+    ) {
         self.remove_existing_task(&destination);
         self.symlinks.push(SymlinkTask {
             source,
             destination,
             kind,
+            inode_group,
+            metadata,
+            origin,
         });
         self.total_symlinks += 1;
     }
@@ -138,13 +249,441 @@ impl CopyPlan {
         self.total_hardlinks += 1;
     }
 
+    pub fn add_special(&mut self, source: PathBuf, destination: PathBuf) {
+        self.remove_existing_task(&destination);
+        self.specials.push(SpecialTask {
+            source,
+            destination,
+        });
+    }
+
+    /// Record that `destination` was left out by an exclude pattern, unless
+    /// a directory above it already was.
+    pub fn add_excluded(&mut self, destination: PathBuf, is_dir: bool) {
+        if self
+            .excluded
+            .iter()
+            .any(|e| destination.starts_with(&e.destination))
+        {
+            return;
+        }
+        self.excluded.push(ExcludedEntry {
+            destination,
+            is_dir,
+        });
+    }
+
+    /// Where the plan writes something other than a directory: files,
+    /// links and special files.
+    fn destinations_written(&self) -> impl Iterator<Item = &Path> {
+        (self.files.iter().map(|t| t.destination.as_path()))
+            .chain(self.symlinks.iter().map(|t| t.destination.as_path()))
+            .chain(self.hardlinks.iter().map(|t| t.destination.as_path()))
+            .chain(self.specials.iter().map(|t| t.destination.as_path()))
+    }
+
+    /// Where the plan writes something other than a directory.
+    #[cfg(feature = "plan-introspection")]
+    pub fn written(&self) -> impl Iterator<Item = &Path> {
+        self.destinations_written()
+    }
+
+    /// What is wrong with the plan as a copy into `root`, one line each:
+    /// tasks that write outside it, destinations written twice or both
+    /// written and made a directory, and totals that don't add up. Empty
+    /// for a sound plan.
+    #[cfg(any(test, feature = "plan-introspection"))]
+    pub fn violations(&self, root: &Path) -> Vec<String> {
+        let mut violations = Vec::new();
+        let directories: HashSet<&Path> = self
+            .directories
+            .iter()
+            .map(|d| d.destination.as_path())
+            .collect();
+        for destination in self
+            .destinations_written()
+            .chain(directories.iter().copied())
+        {
+            if !destination.starts_with(root) {
+                violations.push(format!(
+                    "'{}' is outside '{}'",
+                    destination.display(),
+                    root.display()
+                ));
+            }
+        }
+        let mut seen = HashSet::new();
+        for destination in self.destinations_written() {
+            if !seen.insert(destination) {
+                violations.push(format!("'{}' is written twice", destination.display()));
+            }
+            if directories.contains(destination) {
+                violations.push(format!(
+                    "'{}' is written and made a directory",
+                    destination.display()
+                ));
+            }
+        }
+
+        let size: u64 = self.files.iter().map(|f| f.size).sum();
+        let counts = [
+            ("total_size", self.total_size, size),
+            (
+                "total_files",
+                self.total_files as u64,
+                self.files.len() as u64,
+            ),
+            (
+                "total_symlinks",
+                self.total_symlinks as u64,
+                self.symlinks.len() as u64,
+            ),
+            (
+                "total_hardlinks",
+                self.total_hardlinks as u64,
+                self.hardlinks.len() as u64,
+            ),
+        ];
+        for (name, total, planned) in counts {
+            if total != planned {
+                violations.push(format!(
+                    "{} is {} but the tasks add up to {}",
+                    name, total, planned
+                ));
+            }
+        }
+        violations
+    }
+
+    /// Roughly what the plan takes in memory, for `--max-memory`.
+    pub fn estimated_memory(&self) -> u64 {
+        let len = |path: &Path| path.as_os_str().len() as u64;
+        let pair = |source: &Path, destination: &Path| len(source) + len(destination);
+        let path_bytes = self
+            .files
+            .iter()
+            .map(|f| pair(&f.source, &f.destination))
+            .chain(
+                self.directories
+                    .iter()
+                    .map(|d| d.source.as_deref().map_or(0, len) + len(&d.destination)),
+            )
+            .chain(
+                self.symlinks
+                    .iter()
+                    .map(|l| pair(&l.source, &l.destination)),
+            )
+            .chain(
+                self.hardlinks
+                    .iter()
+                    .map(|l| pair(&l.source, &l.destination)),
+            )
+            .chain(
+                self.specials
+                    .iter()
+                    .map(|s| pair(&s.source, &s.destination)),
+            )
+            .sum();
+        let entries = self.files.len()
+            + self.directories.len()
+            + self.symlinks.len()
+            + self.hardlinks.len()
+            + self.specials.len();
+        memory::estimate_plan(entries, path_bytes)
+    }
+
+    /// Take the files larger than `limit` bytes out of the plan, counting
+    /// them as skipped.
+    pub fn skip_larger_than(&mut self, limit: u64) -> Vec<FileTask> {
+        let (oversized, files) = std::mem::take(&mut self.files)
+            .into_iter()
+            .partition(|f| f.size > limit);
+        self.files = files;
+        for file in &oversized {
+            self.total_size -= file.size;
+            self.total_files -= 1;
+            self.mark_skipped(file.size);
+        }
+        oversized
+    }
+
+    /// Take out the files, links and special files whose destination
+    /// already exists, for `--no-clobber` and `--update=none`, counting them as skipped.
+    /// Directories stay, so that what is new inside them is still copied.
+    pub fn keep_existing(&mut self) {
+        let exists = |path: &Path| std::fs::symlink_metadata(path).is_ok();
+        let (files, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.files)
+            .into_iter()
+            .partition(|f| !exists(&f.destination));
+        self.files = files;
+        for file in &kept {
+            self.total_size -= file.size;
+            self.total_files -= 1;
+            self.mark_skipped(file.size);
+        }
+        let (symlinks, hardlinks, specials) = (
+            self.symlinks.len(),
+            self.hardlinks.len(),
+            self.specials.len(),
+        );
+        self.symlinks.retain(|t| !exists(&t.destination));
+        self.hardlinks.retain(|t| !exists(&t.destination));
+        self.specials.retain(|t| !exists(&t.destination));
+        self.total_symlinks -= symlinks - self.symlinks.len();
+        self.total_hardlinks -= hardlinks - self.hardlinks.len();
+        let links = (symlinks - self.symlinks.len())
+            + (hardlinks - self.hardlinks.len())
+            + (specials - self.specials.len());
+        for _ in 0..links {
+            self.mark_skipped(0);
+        }
+        self.kept_existing += kept.len() + links;
+    }
+
+    /// Take out everything, directories included, whose destination isn't
+    /// there, for `--existing`.
+    pub fn ignore_new(&mut self) {
+        let exists = |path: &Path| std::fs::symlink_metadata(path).is_ok();
+        let (files, new): (Vec<_>, Vec<_>) = std::mem::take(&mut self.files)
+            .into_iter()
+            .partition(|f| exists(&f.destination));
+        self.files = files;
+        for file in &new {
+            self.total_size -= file.size;
+            self.total_files -= 1;
+        }
+        let (symlinks, hardlinks, specials) = (
+            self.symlinks.len(),
+            self.hardlinks.len(),
+            self.specials.len(),
+        );
+        self.symlinks.retain(|t| exists(&t.destination));
+        self.hardlinks.retain(|t| exists(&t.destination));
+        self.specials.retain(|t| exists(&t.destination));
+        self.total_symlinks -= symlinks - self.symlinks.len();
+        self.total_hardlinks -= hardlinks - self.hardlinks.len();
+        self.ignored_new += new.len()
+            + (symlinks - self.symlinks.len())
+            + (hardlinks - self.hardlinks.len())
+            + (specials - self.specials.len());
+        // Nothing goes into a directory that isn't there either.
+        self.directories.retain(|d| exists(&d.destination));
+    }
+
+    /// Take out the files whose destination was modified no earlier than
+    /// the source, for `--update=older`, counting them as skipped. A missing
+    /// destination, or one that isn't a file, is copied.
+    pub fn skip_up_to_date(&mut self) {
+        let up_to_date = |file: &FileTask| {
+            let dest_modified = match std::fs::metadata(&file.destination) {
+                Ok(metadata) if !metadata.is_dir() => metadata.modified(),
+                _ => return false,
+            };
+            let source_modified = match &file.metadata {
+                Some(metadata) => metadata.modified(),
+                None => std::fs::metadata(&file.source).and_then(|m| m.modified()),
+            };
+            matches!((source_modified, dest_modified), (Ok(s), Ok(d)) if s <= d)
+        };
+        let (skipped, files): (Vec<_>, Vec<_>) = std::mem::take(&mut self.files)
+            .into_iter()
+            .partition(up_to_date);
+        self.files = files;
+        for file in &skipped {
+            self.total_size -= file.size;
+            self.total_files -= 1;
+            self.mark_skipped(file.size);
+        }
+    }
+
+    /// Mark the files whose destination is already there, which `-i` asks
+    /// about before anything is copied.
+    pub fn mark_existing(&mut self) {
+        self.files.par_iter_mut().for_each(|file| {
+            file.existing = std::fs::symlink_metadata(&file.destination).is_ok();
+        });
+    }
+
+    /// How many planned files would replace one already there, as marked
+    /// by [`CopyPlan::mark_existing`].
+    pub fn overwrites(&self) -> usize {
+        self.files.iter().filter(|file| file.existing).count()
+    }
+
+    pub fn add_unreadable(&mut self, path: PathBuf, reason: impl std::fmt::Display) {
+        self.unreadable.push(UnreadableEntry {
+            path,
+            reason: reason.to_string(),
+        });
+    }
+
     pub fn mark_skipped(&mut self, size: u64) {
         self.skipped_files += 1;
         self.skipped_size += size;
     }
 
-    pub fn sort_files_descending(&mut self) {
-        self.files.sort_by(|a, b| b.size.cmp(&a.size));
+    /// Drop the directories nothing planned goes into that aren't empty in
+    /// the source either, such as ones whose every entry was excluded, so
+    /// that they aren't left empty at the destination. The directories the
+    /// copy is of, and ones that already exist, whose attributes may still
+    /// need setting, are kept. Returns the kept directories nothing planned
+    /// goes into, which have to be made on their own.
+    pub fn prune_directories(&mut self) -> Vec<DirectoryTask> {
+        fn fill(filled: &mut HashSet<PathBuf>, path: &Path) {
+            for ancestor in path.ancestors().skip(1) {
+                if !filled.insert(ancestor.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+        let mut filled = HashSet::new();
+        for destination in self.destinations_written() {
+            fill(&mut filled, destination);
+        }
+
+        let planned: HashSet<&Path> = self
+            .directories
+            .iter()
+            .map(|d| d.destination.as_path())
+            .collect();
+        let leaves: Vec<DirectoryTask> = self
+            .directories
+            .iter()
+            .filter(|d| !filled.contains(&d.destination))
+            .filter(|d| {
+                let root = d.destination.parent().is_none_or(|p| !planned.contains(p));
+                let empty = |source: &Path| {
+                    std::fs::read_dir(source).map_or(true, |mut entries| entries.next().is_none())
+                };
+                root || d.source.as_deref().is_none_or(empty)
+                    || std::fs::symlink_metadata(&d.destination).is_ok()
+            })
+            .cloned()
+            .collect();
+        for leaf in &leaves {
+            fill(&mut filled, &leaf.destination);
+        }
+        let kept: HashSet<&Path> = leaves.iter().map(|d| d.destination.as_path()).collect();
+        self.directories
+            .retain(|d| filled.contains(&d.destination) || kept.contains(d.destination.as_path()));
+        leaves
+    }
+
+    /// Arrange file tasks in the order workers should pick them up.
+    pub fn sort_files(&mut self, order: CopyOrder) {
+        match order {
+            CopyOrder::Size => self.files.sort_by_key(|f| std::cmp::Reverse(f.size)),
+            CopyOrder::Name => self
+                .files
+                .sort_by(|a, b| a.destination.components().cmp(b.destination.components())),
+            CopyOrder::None => {}
+        }
+    }
+
+    /// Put every kind of task in destination order, so that nothing about
+    /// the run depends on the order the directory walk found things in.
+    pub fn sort_deterministic(&mut self) {
+        self.sort_files(CopyOrder::Name);
+        self.directories
+            .sort_by(|a, b| a.destination.cmp(&b.destination));
+        self.symlinks
+            .sort_by(|a, b| a.destination.cmp(&b.destination));
+        self.hardlinks
+            .sort_by(|a, b| a.destination.cmp(&b.destination));
+    }
+
+    /// Assign each file under `walk_root` to the directory `depth` levels
+    /// below it (or to the shallower directory it lives in), named as under
+    /// `source`.
+    pub fn group_files(&mut self, source: &Path, walk_root: &Path, depth: usize) {
+        for file in &mut self.files {
+            let Some(parent) = file
+                .source
+                .strip_prefix(walk_root)
+                .ok()
+                .and_then(|relative| relative.parent())
+            else {
+                continue;
+            };
+            let group: PathBuf = parent.components().take(depth).collect();
+            file.dir_group = Some(if group.as_os_str().is_empty() {
+                source.to_path_buf()
+            } else {
+                source.join(group)
+            });
+        }
+    }
+
+    /// File count and size of each directory group, in the order groups are
+    /// first seen.
+    pub fn dir_group_totals(&self) -> Vec<(PathBuf, usize, u64)> {
+        let mut totals: Vec<(PathBuf, usize, u64)> = Vec::new();
+        let mut index: HashMap<&Path, usize> = HashMap::new();
+        for file in &self.files {
+            let Some(group) = &file.dir_group else {
+                continue;
+            };
+            match index.get(group.as_path()) {
+                Some(&i) => {
+                    totals[i].1 += 1;
+                    totals[i].2 += file.size;
+                }
+                None => {
+                    index.insert(group, totals.len());
+                    totals.push((group.clone(), 1, file.size));
+                }
+            }
+        }
+        totals
+    }
+
+    /// Like [`merge`](Self::merge), but tasks in `other` replace the ones
+    /// already planned for the same destinations, so the later plan wins.
+    /// A file, link or special file in `other` also replaces a directory
+    /// planned where it goes, along with everything planned inside it.
+    pub fn merge_replacing(&mut self, other: CopyPlan) {
+        let overwritten: HashSet<&Path> = other.destinations_written().collect();
+        let replaced: HashSet<&Path> = other
+            .directories
+            .iter()
+            .map(|t| t.destination.as_path())
+            .chain(overwritten.iter().copied())
+            .collect();
+        let gone = |destination: &Path| {
+            replaced.contains(destination)
+                || destination
+                    .ancestors()
+                    .skip(1)
+                    .any(|a| overwritten.contains(a))
+        };
+        let (mut size, mut files, mut symlinks, mut hardlinks) = (0, 0, 0, 0);
+        self.files.retain(|t| {
+            let keep = !gone(&t.destination);
+            if !keep {
+                size += t.size;
+                files += 1;
+            }
+            keep
+        });
+        self.symlinks.retain(|t| {
+            let keep = !gone(&t.destination);
+            symlinks += usize::from(!keep);
+            keep
+        });
+        self.hardlinks.retain(|t| {
+            let keep = !gone(&t.destination);
+            hardlinks += usize::from(!keep);
+            keep
+        });
+        self.specials.retain(|t| !gone(&t.destination));
+        self.refresh.retain(|t| !gone(&t.destination));
+        self.directories
+            .retain(|t| !t.destination.ancestors().any(|a| overwritten.contains(a)));
+        self.total_size -= size;
+        self.total_files -= files;
+        self.total_symlinks -= symlinks;
+        self.total_hardlinks -= hardlinks;
+        self.merge(other);
     }
 
     pub fn merge(&mut self, other: CopyPlan) {
@@ -152,12 +691,39 @@ impl CopyPlan {
         self.directories.extend(other.directories);
         self.symlinks.extend(other.symlinks);
         self.hardlinks.extend(other.hardlinks);
+        self.specials.extend(other.specials);
+        self.refresh.extend(other.refresh);
+        self.excluded.extend(other.excluded);
+        self.unreadable.extend(other.unreadable);
         self.total_size += other.total_size;
         self.total_files += other.total_files;
         self.total_symlinks += other.total_symlinks;
         self.total_hardlinks += other.total_hardlinks;
         self.skipped_files += other.skipped_files;
         self.skipped_size += other.skipped_size;
+        self.kept_existing += other.kept_existing;
+        self.ignored_new += other.ignored_new;
+        self.symlink_fallbacks += other.symlink_fallbacks;
+        self.hardlink_fallbacks += other.hardlink_fallbacks;
+        self.other_filesystems += other.other_filesystems;
+        self.destinations.extend(other.destinations);
+    }
+}
+
+/// Take out of the plan what `--existing` leaves out and what
+/// `--no-clobber` and `--update` leave as it is at the destination, and
+/// mark what `-i` has to ask about.
+pub fn skip_existing(plan: &mut CopyPlan, options: &CopyOptions) {
+    if options.existing {
+        plan.ignore_new();
+    }
+    if options.keeps_existing() {
+        plan.keep_existing();
+    } else if options.update == UpdateMode::Older {
+        plan.skip_up_to_date();
+    }
+    if options.interactive != Prompt::Never {
+        plan.mark_existing();
     }
 }
 
@@ -175,23 +741,6 @@ fn symlink_kind_from_mode(source: &Path, mode: SymlinkMode) -> SymlinkKind {
     }
 }
 
-fn calculate_checksum(path: &Path) -> io::Result<u64> {
-    use std::io::Read;
-    let mut file = std::fs::File::open(path)?;
-    let mut hasher = Xxh3::new();
-    let mut buffer = vec![0u8; 128 * 1024];
-
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-        hasher.update(&buffer[..bytes_read]);
-    }
-
-    Ok(hasher.digest())
-}
-
 pub fn should_skip_file(source: &Path, destination: &Path) -> io::Result<bool> {
     let dest_metadata = match std::fs::metadata(destination) {
         Ok(meta) => meta,
@@ -211,8 +760,8 @@ pub fn should_skip_file(source: &Path, destination: &Path) -> io::Result<bool> {
         return Ok(true);
     }
 
-    let src_checksum = calculate_checksum(source)?;
-    let dest_checksum = calculate_checksum(destination)?;
+    let src_checksum = xxh3_file(source)?;
+    let dest_checksum = xxh3_file(destination)?;
 
     Ok(src_checksum == dest_checksum)
 }
@@ -229,6 +778,9 @@ fn process_entry(
     if let Some(exclude_rules) = &options.exclude_rules
         && should_exclude(source, source_root, exclude_rules)
     {
+        if options.itemize {
+            plan.add_excluded(dest_path, metadata.is_dir());
+        }
         return Ok(());
     }
 
@@ -266,28 +818,154 @@ fn process_entry(
     };
 
     if metadata.file_type().is_symlink() {
-        if !matches!(options.follow_symlink, FollowSymlink::Dereference) {
-            if let Some(mode) = options.symbolic_link {
+        if !options.follow_symlink.follows(false) {
+            if !options.symlinks_supported() {
+                add_link_fallback(plan, source, dest_path, options.symlink_fallback, true)?;
+            } else if let Some(mode) = options.symbolic_link {
                 let kind = symlink_kind_from_mode(source, mode);
                 plan.add_symlink(source.to_path_buf(), dest_path, kind);
             } else {
                 let original_target = std::fs::read_link(source)?;
-                plan.add_symlink(original_target, dest_path, SymlinkKind::PreserveExact);
+                plan.add_symlink_with_inode(
+                    original_target,
+                    dest_path,
+                    SymlinkKind::PreserveExact,
+                    inode_group,
+                    Some(metadata.clone()),
+                    Some(source.to_path_buf()),
+                );
             }
         }
     } else if options.hard_link {
-        plan.add_hardlink(source.to_path_buf(), dest_path);
+        if options.hardlinks_supported() {
+            plan.add_hardlink(source.to_path_buf(), dest_path);
+        } else {
+            add_link_fallback(plan, source, dest_path, options.link_fallback, false)?;
+        }
     } else if let Some(mode) = options.symbolic_link {
-        let kind = symlink_kind_from_mode(source, mode);
-        plan.add_symlink(source.to_path_buf(), dest_path, kind);
+        if options.symlinks_supported() {
+            let kind = symlink_kind_from_mode(source, mode);
+            plan.add_symlink(source.to_path_buf(), dest_path, kind);
+        } else {
+            add_link_fallback(plan, source, dest_path, options.symlink_fallback, true)?;
+        }
+    } else if is_special(metadata) && !options.copy_contents {
+        plan.add_special(source.to_path_buf(), dest_path);
     } else if options.resume && should_skip_file(source, &dest_path)? {
         plan.mark_skipped(metadata.len());
+        if options.fix_attrs {
+            plan.refresh.push(AttrTask {
+                source: source.to_path_buf(),
+                destination: dest_path,
+                metadata: metadata.clone(),
+            });
+        }
+    } else {
+        plan.add_file_with_inode(
+            source.to_path_buf(),
+            dest_path,
+            metadata.len(),
+            inode_group,
+            Some(metadata.clone()),
+        );
+    }
+    Ok(())
+}
+
+/// Whether `metadata` is a pipe, device or socket rather than a file.
+#[cfg(unix)]
+fn is_special(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    file_type.is_fifo()
+        || file_type.is_char_device()
+        || file_type.is_block_device()
+        || file_type.is_socket()
+}
+
+#[cfg(not(unix))]
+fn is_special(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Plan a link the destination filesystem can't store, following the
+/// `--symlink-fallback`/`--link-fallback` policy.
+fn add_link_fallback(
+    plan: &mut CopyPlan,
+    source: &Path,
+    dest_path: PathBuf,
+    policy: LinkFallback,
+    symlink: bool,
+) -> io::Result<()> {
+    match policy {
+        LinkFallback::Error => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "destination does not support {}",
+                    if symlink {
+                        "symbolic links"
+                    } else {
+                        "hard links"
+                    }
+                ),
+            ));
+        }
+        LinkFallback::CopyTarget => {
+            // Dangling links and links to directories have no file to copy.
+            if let Ok(metadata) = std::fs::metadata(source)
+                && metadata.is_file()
+            {
+                plan.add_file_with_inode(
+                    source.to_path_buf(),
+                    dest_path,
+                    metadata.len(),
+                    None,
+                    Some(metadata),
+                );
+            }
+        }
+        LinkFallback::Skip => {}
+    }
+    if symlink {
+        plan.symlink_fallbacks += 1;
     } else {
-        plan.add_file_with_inode(source.to_path_buf(), dest_path, metadata.len(), inode_group);
+        plan.hardlink_fallbacks += 1;
     }
     Ok(())
 }
 
+/// With `--parents`, the directories the parents of `source` become under
+/// `destination`, outermost first, each with the source directory it stands
+/// for so that its attributes are preserved like any other's. The
+/// components follow [`with_parents`].
+fn parent_directories(source: &Path, destination: &Path) -> Vec<DirectoryTask> {
+    let Some(parent) = source.parent() else {
+        return Vec::new();
+    };
+    let mut from = PathBuf::new();
+    let mut to = destination.to_path_buf();
+    let mut directories = Vec::new();
+    for component in parent.components() {
+        from.push(component);
+        match component {
+            Component::Normal(name) => to.push(name),
+            Component::CurDir | Component::ParentDir => {
+                to.push(component);
+                continue;
+            }
+            Component::RootDir | Component::Prefix(_) => continue,
+        }
+        directories.push(DirectoryTask {
+            metadata: std::fs::metadata(&from).ok(),
+            source: Some(from.clone()),
+            destination: to.clone(),
+        });
+    }
+    directories
+}
+
 pub fn preprocess_file(
     source: &Path,
     source_root: &Path,
@@ -343,12 +1021,14 @@ pub fn preprocess_file(
     if let Some(exclude_rules) = &options.exclude_rules
         && should_exclude(source, source_root, exclude_rules)
     {
+        if options.itemize {
+            plan.add_excluded(dest_path, false);
+        }
         return Ok(plan);
     }
-    if options.parents
-        && let Some(parent) = dest_path.parent()
-    {
-        plan.add_directory(None, parent.to_path_buf());
+    if options.parents {
+        plan.directories
+            .extend(parent_directories(source, destination));
     }
 
     let mut inode_groups = None;
@@ -366,6 +1046,7 @@ pub fn preprocess_file(
         destination: dest_path,
         reason: e.to_string(),
     })?;
+    skip_existing(&mut plan, options);
     Ok(plan)
 }
 
@@ -375,60 +1056,100 @@ pub fn preprocess_directory(
     destination: &Path,
     options: &CopyOptions,
 ) -> CopyResult<CopyPlan> {
-    let mut plan = CopyPlan::new();
     if source != source_root
         && let Some(exclude_rules) = &options.exclude_rules
         && should_exclude(source, source_root, exclude_rules)
     {
-        return Ok(plan);
+        return Ok(CopyPlan::new());
     }
 
+    // With -T, or a source written `src/.`, the source's contents go
+    // straight into the destination.
+    let contents = names_contents(source);
+    let normalized: PathBuf;
+    let source = if contents {
+        normalized = source.components().collect();
+        normalized.as_path()
+    } else {
+        source
+    };
     let root_destination =
         if options.parents {
             with_parents(destination, source)
+        } else if options.no_target_directory || contents {
+            destination.to_path_buf()
         } else {
             destination.join(source.file_name().ok_or_else(|| {
                 io::Error::new(io::ErrorKind::InvalidInput, "Invalid source path")
             })?)
         };
+    if !options.parents {
+        return preprocess_directory_at(source, &root_destination, options);
+    }
+    let mut plan = CopyPlan::new();
+    plan.directories = parent_directories(source, destination);
+    plan.merge(preprocess_directory_at(source, &root_destination, options)?);
+    Ok(plan)
+}
 
-    plan.add_directory(Some(source.into()), root_destination.clone());
+/// Plan copying the directory `source` to exactly `root_destination`.
+pub fn preprocess_directory_at(
+    source: &Path,
+    root_destination: &Path,
+    options: &CopyOptions,
+) -> CopyResult<CopyPlan> {
+    let mut plan = CopyPlan::new();
+    plan.add_directory(Some(source.into()), root_destination.to_path_buf(), None);
 
     let num_threads = num_cpus::get().min(8);
-    let follow_symlink = match options.follow_symlink {
-        FollowSymlink::NoDereference | FollowSymlink::CommandLineSymlink => false,
-        FollowSymlink::Dereference => true,
-    };
-
-    let walk_root = match options.follow_symlink {
-        FollowSymlink::CommandLineSymlink => {
-            let meta = std::fs::symlink_metadata(source)?;
-            if meta.file_type().is_symlink() {
-                std::fs::canonicalize(source).map_err(|e| CopyError::CopyFailed {
-                    source: source.to_path_buf(),
-                    destination: destination.to_path_buf(),
-                    reason: format!("Failed to canonicalize symlink: {}", e),
-                })?
-            } else {
-                source.to_path_buf()
-            }
-        }
-        _ => source.to_path_buf(),
+    // Whether each symlink is followed is decided as it is met: `source`
+    // is named on the command line, and everything below it is not. With
+    // -H the walk starts where `source` leads and keeps every link below
+    // it a link.
+    let follow_symlink = options.follow_symlink.follows(false);
+    let follow_source = options.follow_symlink.follows(true)
+        && std::fs::symlink_metadata(source)?.file_type().is_symlink();
+    let walk_root = if follow_source && !follow_symlink {
+        std::fs::canonicalize(source).map_err(|e| CopyError::CopyFailed {
+            source: source.to_path_buf(),
+            destination: root_destination.to_path_buf(),
+            reason: format!("Failed to canonicalize symlink: {}", e),
+        })?
+    } else {
+        source.to_path_buf()
     };
 
     let mut inode_groups = None;
+    // With -x, directories anywhere else are made but not walked into.
+    let root_device = if options.one_file_system {
+        device_of(&std::fs::metadata(&walk_root)?)
+    } else {
+        None
+    };
+    let elsewhere = |metadata: &Metadata| {
+        metadata.is_dir() && root_device.is_some_and(|root| device_of(metadata) != Some(root))
+    };
 
-    for entry in WalkDir::new(&walk_root)
+    let walk = WalkDir::new(&walk_root)
         .skip_hidden(false)
         .parallelism(jwalk::Parallelism::RayonNewPool(num_threads))
-        .follow_links(follow_symlink)
-    {
-        let entry = entry.map_err(|e| CopyError::CopyFailed {
-            source: source.to_path_buf(),
-            destination: destination.to_path_buf(),
-            reason: format!("Failed to read directory entry: {}", e),
-        })?;
+        .follow_links(follow_symlink);
+    for entry in prune(walk, source, &walk_root, root_device, options) {
+        // Like cp, copy everything that can be read and report the rest at
+        // the end: unreadable directories, symlink loops and entries that
+        // vanished mid-walk don't stop their siblings from being planned.
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().unwrap_or(source).to_path_buf();
+                plan.add_unreadable(path, walk_error_reason(&e));
+                continue;
+            }
+        };
         let src_path = entry.path();
+        if let Some(e) = &entry.read_children_error {
+            plan.add_unreadable(src_path.clone(), walk_error_reason(e));
+        }
         if src_path == walk_root {
             continue;
         }
@@ -437,7 +1158,7 @@ pub fn preprocess_directory(
             .strip_prefix(&walk_root)
             .map_err(|_| CopyError::CopyFailed {
                 source: source.to_path_buf(),
-                destination: destination.to_path_buf(),
+                destination: root_destination.to_path_buf(),
                 reason: "Failed to calculate relative path".to_string(),
             })?;
 
@@ -450,18 +1171,35 @@ pub fn preprocess_directory(
         if let Some(exclude_rules) = &options.exclude_rules
             && should_exclude(&full_source_path, source, exclude_rules)
         {
+            if options.itemize {
+                plan.add_excluded(root_destination.join(relative), entry.file_type().is_dir());
+            }
             continue;
         }
 
         let dest_path = root_destination.join(relative);
-        let metadata = entry.metadata().map_err(|e| CopyError::CopyFailed {
-            source: src_path.to_path_buf(),
-            destination: destination.to_path_buf(),
-            reason: format!("Failed to get metadata: {}", e),
-        })?;
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                plan.add_unreadable(src_path.clone(), walk_error_reason(&e));
+                continue;
+            }
+        };
+
+        if elsewhere(&metadata) {
+            // Left unopened by `prune`, and made empty, as cp -x does.
+            plan.other_filesystems += 1;
+        } else if entry.path_is_symlink()
+            && entry.file_type().is_dir()
+            && entry.read_children_path.is_none()
+        {
+            // Left unopened by `prune`.
+            plan.add_unreadable(src_path.clone(), SYMLINK_CYCLE);
+            continue;
+        }
 
         if metadata.is_dir() {
-            plan.add_directory(Some(src_path.to_path_buf()), dest_path);
+            plan.add_directory(Some(src_path.to_path_buf()), dest_path, Some(metadata));
         } else {
             process_entry(
                 &mut plan,
@@ -475,73 +1213,278 @@ pub fn preprocess_directory(
         }
     }
 
-    plan.sort_files_descending();
+    if let Some(depth) = options.interactive_dirs {
+        plan.group_files(source, &walk_root, depth);
+    }
+    // With --flatten the files are only checked once they are moved.
+    if !options.flatten {
+        skip_existing(&mut plan, options);
+    }
+    plan.sort_files(options.order);
+    if options.overrides.reproducible {
+        plan.sort_deterministic();
+    }
     Ok(plan)
 }
 
-pub fn preprocess_multiple(
-    sources: &[PathBuf],
-    destination: &Path,
+/// Have the walk yield excluded directories without opening them. A match
+/// on a directory excludes everything below it, so reading one would only
+/// stat files to throw them away; the directory itself is still yielded,
+/// and left out as a single entry.
+///
+/// With `-L`, symlinks leading back to a directory the walk is already in
+/// are yielded unopened too, told by device and inode. jwalk only catches a
+/// loop whose target is written as the very path of an ancestor, so `..` or
+/// a link through another link would otherwise be followed until memory
+/// runs out. A directory reached twice through links that don't loop is
+/// not a cycle, and is copied both times, as cp does.
+///
+/// With `-x`, so are directories on another device than `root_device`.
+fn prune(
+    walk: WalkDir,
+    source: &Path,
+    walk_root: &Path,
+    root_device: Option<u64>,
     options: &CopyOptions,
-) -> CopyResult<CopyPlan> {
-    let dest_metadata = std::fs::metadata(destination)
-        .map_err(|_e| CopyError::InvalidDestination(destination.to_path_buf()))?;
-    if !dest_metadata.is_dir() {
-        return Err(CopyError::CopyFailed {
-            source: PathBuf::new(),
-            destination: destination.to_path_buf(),
-            reason: format!("Destination '{}' is not a directory", destination.display()),
-        });
+) -> WalkDir {
+    let rules = options.exclude_rules.clone();
+    let follow = options.follow_symlink.follows(false);
+    if rules.is_none() && !follow && root_device.is_none() {
+        return walk;
     }
-
-    let mut plan = CopyPlan::new();
-
-    for source in sources {
-        let metadata = match options.follow_symlink {
-            FollowSymlink::Dereference | FollowSymlink::CommandLineSymlink => {
-                std::fs::metadata(source)
-                    .map_err(|_e| CopyError::InvalidSource(source.to_path_buf()))?
+    let source = source.to_path_buf();
+    let walk_root = walk_root.to_path_buf();
+    walk.process_read_dir(move |depth, path, _state, children| {
+        // The directories being read from, found once per directory and
+        // only if it holds a symlink to follow.
+        let mut ancestors: Option<Vec<FileId>> = None;
+        for entry in children.iter_mut().flatten() {
+            if entry.read_children_path.is_none() {
+                continue;
             }
-            FollowSymlink::NoDereference => std::fs::symlink_metadata(source)
-                .map_err(|_e| CopyError::InvalidSource(source.to_path_buf()))?,
-        };
-
-        if metadata.is_dir() {
-            let dir_plan =
-                preprocess_directory(source, source, destination, options).map_err(|e| {
-                    CopyError::CopyFailed {
-                        source: source.to_path_buf(),
-                        destination: destination.to_path_buf(),
-                        reason: e.to_string(),
-                    }
-                })?;
-            plan.merge(dir_plan);
-        } else {
-            let _source_root = source.parent().unwrap_or_else(|| Path::new("."));
-
-            let dest_path = if options.parents {
-                with_parents(destination, source)
-            } else {
-                destination.join(source.file_name().ok_or_else(|| CopyError::CopyFailed {
-                    source: source.to_path_buf(),
-                    destination: destination.to_path_buf(),
-                    reason: "Invalid source path".to_string(),
-                })?)
-            };
-
-            if options.parents
-                && let Some(parent) = dest_path.parent()
+            let child = entry.path();
+            let relative = child.strip_prefix(&walk_root).unwrap_or(&child);
+            if let Some(rules) = &rules
+                && should_exclude(&source.join(relative), &source, rules)
+            {
+                entry.read_children_path = None;
+                continue;
+            }
+            if let Some(root) = root_device
+                && entry
+                    .metadata()
+                    .is_ok_and(|metadata| device_of(&metadata) != Some(root))
             {
-                plan.add_directory(None, parent.to_path_buf());
+                entry.read_children_path = None;
+                continue;
             }
+            if depth.is_none() || !entry.path_is_symlink() {
+                continue;
+            }
+            let ancestors = ancestors.get_or_insert_with(|| {
+                path.ancestors()
+                    .take_while(|a| a.starts_with(&walk_root))
+                    .filter_map(file_id)
+                    .collect()
+            });
+            if file_id(&child).is_some_and(|target| ancestors.contains(&target)) {
+                entry.read_children_path = None;
+            }
+        }
+    })
+}
 
-            let mut inode_groups = None;
-            process_entry(
-                &mut plan,
+/// Why a symlink leading back to a directory being copied is left out.
+const SYMLINK_CYCLE: &str = "won't follow symlink cycle";
+
+/// Why the walk couldn't read an entry, without the path jwalk prefixes.
+fn walk_error_reason(error: &jwalk::Error) -> String {
+    if error.loop_ancestor().is_some() {
+        return SYMLINK_CYCLE.to_string();
+    }
+    match error.io_error() {
+        Some(e) => io_error_reason(e),
+        None => error.to_string(),
+    }
+}
+
+/// `e` as the reason an entry couldn't be read. ELOOP is what the kernel
+/// gives for a chain of symlinks that loops back on itself, or is just too
+/// long to resolve, and its own message doesn't say which link.
+fn io_error_reason(e: &io::Error) -> String {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::ELOOP) {
+        return "won't follow symlink chain that loops or is too long to resolve".to_string();
+    }
+    e.to_string()
+}
+
+/// A command-line source copied into a directory, and where it lands.
+struct SourceEntry {
+    /// Position among the entries first gathered, to tell apart entries
+    /// with the same source once some are dropped.
+    index: usize,
+    source: PathBuf,
+    metadata: Metadata,
+    destination: PathBuf,
+}
+
+/// The error for a source that can't be stat'ed. One written with a
+/// trailing slash has to be a directory, so why it isn't is what is worth
+/// telling, most often that it is a dangling symlink.
+pub fn source_error(source: &Path, error: io::Error) -> CopyError {
+    if !has_trailing_slash(source) {
+        return CopyError::InvalidSource(source.to_path_buf());
+    }
+    let dangling = error.kind() == io::ErrorKind::NotFound
+        && std::fs::symlink_metadata(strip_trailing_slashes(source))
+            .is_ok_and(|metadata| metadata.file_type().is_symlink());
+    let error = if dangling {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "a dangling symbolic link; without the trailing slash the link itself is copied",
+        )
+    } else {
+        error
+    };
+    CopyError::OperationFailed {
+        operation: "stat".to_string(),
+        path: source.to_path_buf(),
+        error,
+    }
+}
+
+pub fn preprocess_multiple(
+    sources: &[PathBuf],
+    destination: &Path,
+    options: &CopyOptions,
+) -> CopyResult<CopyPlan> {
+    // A dry run plans into the directory --mkdir would have made.
+    let dest_is_dir = match std::fs::metadata(destination) {
+        Ok(metadata) => metadata.is_dir(),
+        Err(_) if options.mkdir && options.dry_run => true,
+        Err(_) => return Err(CopyError::InvalidDestination(destination.to_path_buf())),
+    };
+    if !dest_is_dir {
+        return Err(CopyError::Io(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            format!("destination '{}' is not a directory", destination.display()),
+        )));
+    }
+
+    // A shell glob can name tens of thousands of sources; stat them all at
+    // once, then report the first one, in command-line order, that failed.
+    let all_metadata: Vec<io::Result<Metadata>> = sources
+        .par_iter()
+        .map(|source| {
+            if options.follow_symlink.follows(true) {
+                std::fs::metadata(source)
+            } else {
+                std::fs::symlink_metadata(source)
+            }
+        })
+        .collect();
+    let mut plan = CopyPlan::new();
+    let mut entries = Vec::with_capacity(sources.len());
+    for (source, metadata) in sources.iter().zip(all_metadata) {
+        let metadata = metadata.map_err(|e| source_error(source, e))?;
+        let contents = names_contents(source) && metadata.is_dir();
+        let source = &if contents {
+            source.components().collect()
+        } else {
+            source.clone()
+        };
+        let dest_path = if let Some(root) = &options.files_from_root {
+            // Listed paths keep their place relative to the root.
+            destination.join(source.strip_prefix(root).unwrap_or(source))
+        } else if options.parents {
+            with_parents(destination, source)
+        } else if contents {
+            destination.to_path_buf()
+        } else {
+            destination.join(source.file_name().ok_or_else(|| CopyError::CopyFailed {
+                source: source.to_path_buf(),
+                destination: destination.to_path_buf(),
+                reason: "Invalid source path".to_string(),
+            })?)
+        };
+        // Patterns apply to the sources themselves as they do to a single
+        // one, matched from the directory each is in.
+        if let Some(exclude_rules) = &options.exclude_rules {
+            let root = match &options.files_from_root {
+                Some(root) => root.as_path(),
+                None => source.parent().unwrap_or(source),
+            };
+            if should_exclude(source, root, exclude_rules) {
+                if options.itemize {
+                    plan.add_excluded(dest_path, metadata.is_dir());
+                }
+                continue;
+            }
+        }
+        entries.push(SourceEntry {
+            index: entries.len(),
+            source: source.clone(),
+            metadata,
+            destination: dest_path,
+        });
+    }
+    drop_repeated_sources(&mut entries);
+    // Flattened, the files inside are what collide, once planned.
+    if !options.flatten {
+        resolve_collisions(&mut entries, options.on_collision)?;
+    }
+
+    // Sources from one directory share its parents; plan each once.
+    let mut created_parents = HashSet::new();
+
+    for SourceEntry {
+        source,
+        metadata,
+        destination: dest_path,
+        ..
+    } in &entries
+    {
+        if options.files_from_root.is_some() {
+            let parents: Vec<&Path> = dest_path
+                .ancestors()
+                .skip(1)
+                .take_while(|a| *a != destination)
+                .collect();
+            for parent in parents.into_iter().rev() {
+                if created_parents.insert(parent.to_path_buf()) {
+                    plan.add_directory(None, parent.to_path_buf(), None);
+                }
+            }
+        }
+        if options.parents {
+            plan.directories.extend(
+                parent_directories(source, destination)
+                    .into_iter()
+                    .filter(|d| created_parents.insert(d.destination.clone())),
+            );
+        }
+        if metadata.is_dir() {
+            let dir_plan = preprocess_directory_at(source, dest_path, options).map_err(|e| {
+                CopyError::CopyFailed {
+                    source: source.to_path_buf(),
+                    destination: destination.to_path_buf(),
+                    reason: e.to_string(),
+                }
+            })?;
+            if options.on_collision == CollisionPolicy::LastWins {
+                plan.merge_replacing(dir_plan);
+            } else {
+                plan.merge(dir_plan);
+            }
+        } else {
+            let mut inode_groups = None;
+            process_entry(
+                &mut plan,
                 source,
                 source,
                 dest_path.clone(),
-                &metadata,
+                metadata,
                 options,
                 &mut inode_groups,
             )
@@ -553,14 +1496,258 @@ pub fn preprocess_multiple(
         }
     }
 
-    plan.sort_files_descending();
+    // With --flatten the files are only checked once they are moved.
+    if !options.flatten {
+        skip_existing(&mut plan, options);
+    }
+    plan.sort_files(options.order);
+    if options.overrides.reproducible {
+        plan.sort_deterministic();
+    }
     Ok(plan)
 }
 
+/// Move every planned file, link and special file straight into
+/// `destination`, for `--flatten`. No directories are created but
+/// `destination` itself, and names that clash are settled by `policy` as
+/// for sources copied to the same path. Returns how many entries the policy
+/// left out.
+pub fn flatten(
+    plan: &mut CopyPlan,
+    destination: &Path,
+    policy: CollisionPolicy,
+) -> CopyResult<usize> {
+    let flat = |path: &Path| destination.join(path.file_name().unwrap_or_default());
+    // Symlinks planned as links carry their target as the source, so they
+    // are known by where they were going instead.
+    let mut entries = Vec::new();
+    let mut gather = |source: &Path, metadata: Metadata, destination: &Path| {
+        entries.push(SourceEntry {
+            index: entries.len(),
+            source: source.to_path_buf(),
+            metadata,
+            destination: flat(destination),
+        });
+    };
+    for file in &plan.files {
+        let metadata = match &file.metadata {
+            Some(metadata) => metadata.clone(),
+            None => std::fs::symlink_metadata(&file.source)?,
+        };
+        gather(&file.source, metadata, &file.destination);
+    }
+    for link in &plan.hardlinks {
+        let metadata = std::fs::symlink_metadata(&link.source)?;
+        gather(&link.source, metadata, &link.destination);
+    }
+    for link in &plan.symlinks {
+        // Links made to sources with `-s` have no link metadata; they
+        // stand for the file they point to.
+        let metadata = match &link.metadata {
+            Some(metadata) => metadata.clone(),
+            None => std::fs::symlink_metadata(&link.source)?,
+        };
+        gather(&link.destination, metadata, &link.destination);
+    }
+    for special in &plan.specials {
+        let metadata = std::fs::symlink_metadata(&special.source)?;
+        gather(&special.source, metadata, &special.destination);
+    }
+    resolve_collisions(&mut entries, policy)?;
+
+    // Entries were gathered in the order of the task lists.
+    let placed: HashMap<usize, PathBuf> = entries
+        .into_iter()
+        .map(|entry| (entry.index, entry.destination))
+        .collect();
+    let mut index = 0;
+    let mut place = |destination: &mut PathBuf| {
+        index += 1;
+        match placed.get(&(index - 1)) {
+            Some(flat) => {
+                *destination = flat.clone();
+                true
+            }
+            None => false,
+        }
+    };
+    let (mut size, mut files) = (0, 0);
+    plan.files.retain_mut(|file| {
+        let kept = place(&mut file.destination);
+        if !kept {
+            size += file.size;
+            files += 1;
+        }
+        kept
+    });
+    let hardlinks = plan.hardlinks.len();
+    plan.hardlinks
+        .retain_mut(|link| place(&mut link.destination));
+    let symlinks = plan.symlinks.len();
+    plan.symlinks
+        .retain_mut(|link| place(&mut link.destination));
+    plan.specials
+        .retain_mut(|special| place(&mut special.destination));
+    plan.total_size -= size;
+    plan.total_files -= files;
+    let dropped = files + hardlinks - plan.hardlinks.len() + symlinks - plan.symlinks.len();
+    plan.total_hardlinks = plan.hardlinks.len();
+    plan.total_symlinks = plan.symlinks.len();
+    plan.directories.clear();
+    if std::fs::symlink_metadata(destination).is_err() {
+        plan.add_directory(None, destination.to_path_buf(), None);
+    }
+    Ok(dropped)
+}
+
+/// Copy a source named more than once only once.
+fn drop_repeated_sources(entries: &mut Vec<SourceEntry>) {
+    // A source is where it is named from, so a symlink and the directory
+    // it leads to, or `link/` and `dir/`, are two sources.
+    let identity = |source: &Path| {
+        let canonical = match (source.parent(), source.file_name()) {
+            (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+                std::fs::canonicalize(parent).map(|parent| parent.join(name))
+            }
+            (Some(_), Some(name)) => std::env::current_dir().map(|cwd| cwd.join(name)),
+            _ => std::fs::canonicalize(source),
+        };
+        canonical.unwrap_or_else(|_| source.to_path_buf())
+    };
+    let identities: Vec<PathBuf> = entries
+        .par_iter()
+        .map(|entry| identity(&entry.source))
+        .collect();
+    let mut seen = HashSet::with_capacity(identities.len());
+    let mut identities = identities.into_iter();
+    entries.retain(|entry| {
+        let identity = identities.next().expect("one identity per entry");
+        let first = seen.insert(identity);
+        if !first {
+            output::warning(format!(
+                "source '{}' specified more than once",
+                entry.source.display()
+            ));
+        }
+        first
+    });
+}
+
+/// Apply `policy` to entries that would be copied to the same path.
+fn resolve_collisions(entries: &mut Vec<SourceEntry>, policy: CollisionPolicy) -> CopyResult<()> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut index: HashMap<&Path, usize> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        match index.get(entry.destination.as_path()) {
+            Some(&group) => groups[group].push(i),
+            None => {
+                index.insert(&entry.destination, groups.len());
+                groups.push(vec![i]);
+            }
+        }
+    }
+    groups.retain(|group| group.len() > 1);
+    let Some(first) = groups.first() else {
+        return Ok(());
+    };
+
+    match policy {
+        CollisionPolicy::Error => {
+            let colliding = &entries[first[0]];
+            let names: Vec<String> = first
+                .iter()
+                .map(|&i| format!("'{}'", entries[i].source.display()))
+                .collect();
+            Err(CopyError::CopyFailed {
+                source: colliding.source.clone(),
+                destination: colliding.destination.clone(),
+                reason: format!(
+                    "{} would all be copied to '{}' (use --on-collision=number, \
+                     --on-collision=skip or --on-collision=last-wins)",
+                    names.join(", "),
+                    colliding.destination.display()
+                ),
+            })
+        }
+        CollisionPolicy::Number => {
+            let mut taken: HashSet<PathBuf> =
+                entries.iter().map(|e| e.destination.clone()).collect();
+            for group in &groups {
+                for &i in &group[1..] {
+                    let entry = &mut entries[i];
+                    let numbered = (1..)
+                        .map(|n| numbered_path(&entry.destination, n, entry.metadata.is_dir()))
+                        .find(|p| !taken.contains(p) && std::fs::symlink_metadata(p).is_err())
+                        .expect("unbounded range always finds a free name");
+                    taken.insert(numbered.clone());
+                    entry.destination = numbered;
+                }
+            }
+            Ok(())
+        }
+        CollisionPolicy::Skip => {
+            let dropped: HashSet<usize> = groups
+                .iter()
+                .flat_map(|group| group[1..].iter().copied())
+                .collect();
+            let mut i = 0;
+            entries.retain(|_| {
+                i += 1;
+                !dropped.contains(&(i - 1))
+            });
+            Ok(())
+        }
+        CollisionPolicy::LastWins => {
+            let mut dropped = HashSet::new();
+            for group in &groups {
+                let (&last, earlier) = group.split_last().expect("collision groups are not empty");
+                if !entries[last].metadata.is_dir() {
+                    dropped.extend(earlier.iter().copied());
+                    continue;
+                }
+                // Directories merge, later ones winning for the paths they
+                // share; a file among them can't be merged into anything.
+                if let Some(&file) = earlier.iter().find(|&&i| !entries[i].metadata.is_dir()) {
+                    return Err(CopyError::CopyFailed {
+                        source: entries[last].source.clone(),
+                        destination: entries[last].destination.clone(),
+                        reason: format!(
+                            "cannot overwrite non-directory '{}' with directory '{}'",
+                            entries[file].source.display(),
+                            entries[last].source.display()
+                        ),
+                    });
+                }
+            }
+            let mut i = 0;
+            entries.retain(|_| {
+                i += 1;
+                !dropped.contains(&(i - 1))
+            });
+            Ok(())
+        }
+    }
+}
+
+/// `path` with `-n` added to its name: before the extension for files
+/// (report-1.pdf), at the end for directories.
+fn numbered_path(path: &Path, n: usize, is_dir: bool) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let numbered = match path.extension() {
+        Some(ext) if !is_dir => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            format!("{}-{}.{}", stem, n, ext.to_string_lossy())
+        }
+        _ => format!("{}-{}", name, n),
+    };
+    path.with_file_name(numbered)
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::cli::args::FollowSymlink;
     use std::fs as std_fs;
     use tempfile::TempDir;
 
@@ -581,12 +1768,100 @@ mod tests {
         create_test_file(&file1, content).unwrap();
         create_test_file(&file2, content).unwrap();
 
-        let hash1 = calculate_checksum(&file1).unwrap();
-        let hash2 = calculate_checksum(&file2).unwrap();
+        let hash1 = xxh3_file(&file1).unwrap();
+        let hash2 = xxh3_file(&file2).unwrap();
 
         assert_eq!(hash1, hash2);
     }
 
+    fn ordered_plan(order: CopyOrder) -> Vec<&'static str> {
+        let mut plan = CopyPlan::new();
+        for (name, size) in [
+            ("b/z.txt", 10),
+            ("a.txt", 5),
+            ("c.txt", 30),
+            ("b/a.txt", 20),
+        ] {
+            plan.add_file(PathBuf::from(name), Path::new("/dest").join(name), size);
+        }
+        plan.sort_files(order);
+        plan.files
+            .iter()
+            .map(|f| match f.size {
+                5 => "a.txt",
+                10 => "b/z.txt",
+                20 => "b/a.txt",
+                _ => "c.txt",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sort_files_by_size_largest_first() {
+        assert_eq!(
+            ordered_plan(CopyOrder::Size),
+            vec!["c.txt", "b/a.txt", "b/z.txt", "a.txt"]
+        );
+    }
+
+    #[test]
+    fn test_sort_files_by_name() {
+        assert_eq!(
+            ordered_plan(CopyOrder::Name),
+            vec!["a.txt", "b/a.txt", "b/z.txt", "c.txt"]
+        );
+    }
+
+    #[test]
+    fn test_sort_files_none_keeps_traversal_order() {
+        assert_eq!(
+            ordered_plan(CopyOrder::None),
+            vec!["b/z.txt", "a.txt", "c.txt", "b/a.txt"]
+        );
+    }
+
+    #[cfg(unix)]
+    fn plan_symlink_onto_exfat(policy: LinkFallback) -> CopyResult<CopyPlan> {
+        use crate::utility::filesystem::{FsKind, LinkSupport};
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("target.txt"), b"content").unwrap();
+        std::os::unix::fs::symlink("target.txt", source_dir.join("link")).unwrap();
+
+        let options = CopyOptions {
+            symlink_fallback: policy,
+            link_support: Some(LinkSupport::for_kind(FsKind::Exfat)),
+            ..CopyOptions::none()
+        };
+        preprocess_directory(
+            &source_dir,
+            &source_dir,
+            &temp_dir.path().join("dest"),
+            &options,
+        )
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_fallback_copies_target() {
+        let plan = plan_symlink_onto_exfat(LinkFallback::CopyTarget).unwrap();
+        assert!(plan.symlinks.is_empty());
+        assert_eq!(plan.total_files, 2);
+        assert_eq!(plan.symlink_fallbacks, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_fallback_skip_and_error() {
+        let plan = plan_symlink_onto_exfat(LinkFallback::Skip).unwrap();
+        assert!(plan.symlinks.is_empty());
+        assert_eq!(plan.total_files, 1);
+        assert_eq!(plan.symlink_fallbacks, 1);
+
+        assert!(plan_symlink_onto_exfat(LinkFallback::Error).is_err());
+    }
+
     #[test]
     fn test_preprocess_directory() {
         let temp_dir = TempDir::new().unwrap();
@@ -607,6 +1882,72 @@ mod tests {
         assert!(!plan.directories.is_empty());
     }
 
+    #[test]
+    fn test_trailing_dot_plans_the_contents_into_the_destination() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let dest_dir = temp_dir.path().join("dest");
+        std_fs::create_dir_all(source_dir.join("subdir")).unwrap();
+        create_test_file(&source_dir.join("subdir/file.txt"), b"content").unwrap();
+
+        let dotted = source_dir.join(".");
+        let plan = preprocess_directory(&dotted, &dotted, &dest_dir, &CopyOptions::none()).unwrap();
+
+        assert_eq!(plan.files[0].source, source_dir.join("subdir/file.txt"));
+        assert_eq!(plan.files[0].destination, dest_dir.join("subdir/file.txt"));
+    }
+
+    #[test]
+    fn test_interactive_plan_marks_existing_destinations() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let dest_dir = temp_dir.path().join("dest");
+        std_fs::create_dir_all(&source_dir).unwrap();
+        create_test_file(&source_dir.join("old.txt"), b"new").unwrap();
+        create_test_file(&source_dir.join("new.txt"), b"new").unwrap();
+        std_fs::create_dir_all(dest_dir.join("source")).unwrap();
+        create_test_file(&dest_dir.join("source/old.txt"), b"old").unwrap();
+
+        let mut options = CopyOptions::none();
+        options.interactive = Prompt::Always;
+        let plan = preprocess_directory(&source_dir, temp_dir.path(), &dest_dir, &options).unwrap();
+
+        let existing: Vec<_> = plan
+            .files
+            .iter()
+            .filter(|f| f.existing)
+            .map(|f| f.source.clone())
+            .collect();
+        assert_eq!(existing, vec![source_dir.join("old.txt")]);
+        assert_eq!(plan.overwrites(), 1);
+    }
+
+    #[test]
+    fn test_plan_carries_source_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let subdir = source_dir.join("subdir");
+        std_fs::create_dir_all(&subdir).unwrap();
+        create_test_file(&subdir.join("file.txt"), b"content").unwrap();
+
+        let plan = preprocess_directory(
+            &source_dir,
+            &source_dir,
+            &temp_dir.path().join("dest"),
+            &CopyOptions::none(),
+        )
+        .unwrap();
+
+        let file = plan.files[0].metadata.as_ref().unwrap();
+        assert_eq!(file.len(), 7);
+        let planned_subdir = plan
+            .directories
+            .iter()
+            .find(|d| d.source.as_deref() == Some(subdir.as_path()))
+            .unwrap();
+        assert!(planned_subdir.metadata.as_ref().unwrap().is_dir());
+    }
+
     #[test]
     fn test_preprocess_file_with_symlink_auto() {
         let temp_dir = TempDir::new().unwrap();
@@ -746,6 +2087,352 @@ mod tests {
         assert_eq!(plan.symlinks.len(), 2);
     }
 
+    /// `a/report.pdf`, `b/report.pdf`, ... under a temp dir, plus an empty
+    /// `dest` to copy them into.
+    fn colliding_sources(dirs: &[&str]) -> (TempDir, Vec<PathBuf>, PathBuf) {
+        let temp_dir = TempDir::new().unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+        std_fs::create_dir(&dest_dir).unwrap();
+        let sources = dirs
+            .iter()
+            .map(|dir| {
+                let source = temp_dir.path().join(dir).join("report.pdf");
+                std_fs::create_dir_all(source.parent().unwrap()).unwrap();
+                create_test_file(&source, dir.as_bytes()).unwrap();
+                source
+            })
+            .collect();
+        (temp_dir, sources, dest_dir)
+    }
+
+    fn planned_files(plan: &CopyPlan, dest_dir: &Path) -> Vec<(String, String)> {
+        let mut files: Vec<_> = plan
+            .files
+            .iter()
+            .map(|t| {
+                (
+                    t.destination
+                        .strip_prefix(dest_dir)
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned(),
+                    std_fs::read_to_string(&t.source).unwrap(),
+                )
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn with_policy(policy: CollisionPolicy) -> CopyOptions {
+        CopyOptions {
+            on_collision: policy,
+            ..CopyOptions::none()
+        }
+    }
+
+    #[test]
+    fn test_collisions_are_refused_by_default() {
+        let (_temp_dir, sources, dest_dir) = colliding_sources(&["a", "b", "c"]);
+        let err = preprocess_multiple(&sources, &dest_dir, &CopyOptions::none()).unwrap_err();
+        let message = err.to_string();
+        for source in &sources {
+            assert!(message.contains(&*source.to_string_lossy()), "{}", message);
+        }
+        assert!(message.contains("--on-collision"));
+    }
+
+    #[test]
+    fn test_collisions_are_numbered() {
+        let (_temp_dir, sources, dest_dir) = colliding_sources(&["a", "b", "c"]);
+        // An existing report-1.pdf is never overwritten by the renaming.
+        create_test_file(&dest_dir.join("report-1.pdf"), b"keep").unwrap();
+
+        let plan = preprocess_multiple(&sources, &dest_dir, &with_policy(CollisionPolicy::Number))
+            .unwrap();
+        assert_eq!(
+            planned_files(&plan, &dest_dir),
+            [
+                ("report-2.pdf".to_string(), "b".to_string()),
+                ("report-3.pdf".to_string(), "c".to_string()),
+                ("report.pdf".to_string(), "a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_last_wins_keeps_only_the_last_file() {
+        let (_temp_dir, sources, dest_dir) = colliding_sources(&["a", "b"]);
+        let plan =
+            preprocess_multiple(&sources, &dest_dir, &with_policy(CollisionPolicy::LastWins))
+                .unwrap();
+        assert_eq!(
+            planned_files(&plan, &dest_dir),
+            [("report.pdf".to_string(), "b".to_string())]
+        );
+        assert_eq!(plan.total_files, 1);
+    }
+
+    #[test]
+    fn test_colliding_directories_are_numbered_or_merged() {
+        let (temp_dir, _, dest_dir) = colliding_sources(&["x/docs", "y/docs"]);
+        create_test_file(&temp_dir.path().join("x/docs/only-x.txt"), b"x").unwrap();
+        let sources = vec![
+            temp_dir.path().join("x/docs"),
+            temp_dir.path().join("y/docs"),
+        ];
+
+        let err = preprocess_multiple(&sources, &dest_dir, &CopyOptions::none()).unwrap_err();
+        assert!(err.to_string().contains("docs"));
+
+        let plan = preprocess_multiple(&sources, &dest_dir, &with_policy(CollisionPolicy::Number))
+            .unwrap();
+        assert_eq!(
+            planned_files(&plan, &dest_dir),
+            [
+                ("docs-1/report.pdf".to_string(), "y/docs".to_string()),
+                ("docs/only-x.txt".to_string(), "x".to_string()),
+                ("docs/report.pdf".to_string(), "x/docs".to_string()),
+            ]
+        );
+
+        let plan =
+            preprocess_multiple(&sources, &dest_dir, &with_policy(CollisionPolicy::LastWins))
+                .unwrap();
+        assert_eq!(
+            planned_files(&plan, &dest_dir),
+            [
+                ("docs/only-x.txt".to_string(), "x".to_string()),
+                ("docs/report.pdf".to_string(), "y/docs".to_string()),
+            ]
+        );
+        assert_eq!(plan.total_files, 2);
+    }
+
+    #[test]
+    fn test_many_sources_are_planned_quickly() {
+        let temp_dir = TempDir::new().unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+        std_fs::create_dir(&dest_dir).unwrap();
+        let sources: Vec<PathBuf> = (0..20_000)
+            .map(|i| {
+                let source = temp_dir.path().join(format!("shard-{}.bin", i));
+                std_fs::write(&source, b"x").unwrap();
+                source
+            })
+            .collect();
+
+        let started = std::time::Instant::now();
+        let plan = preprocess_multiple(&sources, &dest_dir, &CopyOptions::none()).unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(plan.total_files, 20_000);
+        assert_eq!(plan.files[19_999].source, sources[19_999]);
+        assert!(elapsed.as_secs_f64() < 1.0, "planning took {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_missing_source_is_the_one_blamed() {
+        let (_temp_dir, mut sources, dest_dir) = colliding_sources(&["a", "b"]);
+        let missing = sources[1].with_file_name("missing");
+        sources.insert(1, missing.clone());
+        let error = preprocess_multiple(&sources, &dest_dir, &CopyOptions::none()).unwrap_err();
+        assert!(
+            matches!(&error, CopyError::InvalidSource(path) if *path == missing),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_repeated_source_is_not_a_collision() {
+        let (_temp_dir, sources, dest_dir) = colliding_sources(&["a"]);
+        let repeated = vec![sources[0].clone(), sources[0].clone()];
+        let plan = preprocess_multiple(&repeated, &dest_dir, &CopyOptions::none()).unwrap();
+        assert_eq!(plan.total_files, 1);
+    }
+
+    #[test]
+    fn test_flatten_puts_every_file_in_the_destination() {
+        let (temp_dir, _, dest_dir) = colliding_sources(&["src/a", "src/a/b", "src/c"]);
+        let source_dir = temp_dir.path().join("src");
+        create_test_file(&source_dir.join("notes.txt"), b"src").unwrap();
+        let flat_dir = dest_dir.join("flat");
+        let plan_for = |policy| {
+            let mut plan =
+                preprocess_directory(&source_dir, &source_dir, &flat_dir, &CopyOptions::none())
+                    .unwrap();
+            flatten(&mut plan, &flat_dir, policy).map(|dropped| (plan, dropped))
+        };
+
+        let err = plan_for(CollisionPolicy::Error).unwrap_err();
+        assert!(err.to_string().contains("report.pdf"), "{}", err);
+
+        let (plan, dropped) = plan_for(CollisionPolicy::Number).unwrap();
+        assert_eq!(dropped, 0);
+        let mut contents: Vec<_> = planned_files(&plan, &flat_dir)
+            .into_iter()
+            .map(|(_, content)| content)
+            .collect();
+        contents.sort();
+        assert_eq!(contents, ["src", "src/a", "src/a/b", "src/c"]);
+        let mut names: Vec<_> = planned_files(&plan, &flat_dir)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["notes.txt", "report-1.pdf", "report-2.pdf", "report.pdf"]
+        );
+        assert_eq!(plan.directories.len(), 1);
+
+        let (plan, dropped) = plan_for(CollisionPolicy::Skip).unwrap();
+        assert_eq!(dropped, 2);
+        assert_eq!(plan.total_files, 2);
+        assert_eq!(plan.files.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_flatten_moves_links_too() {
+        let (temp_dir, _, dest_dir) = colliding_sources(&["src/a", "src/b"]);
+        let source_dir = temp_dir.path().join("src");
+        for (hard_link, symbolic_link) in [(true, None), (false, Some(SymlinkMode::Absolute))] {
+            let options = CopyOptions {
+                hard_link,
+                symbolic_link,
+                ..CopyOptions::none()
+            };
+            let mut plan =
+                preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+            let dropped = flatten(&mut plan, &dest_dir, CollisionPolicy::Skip).unwrap();
+            assert_eq!(dropped, 1);
+            let links: Vec<&Path> = plan.destinations_written().collect();
+            assert_eq!(links, [dest_dir.join("report.pdf")]);
+            assert!(plan.violations(&dest_dir).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_excluded_sources_are_left_out() {
+        use crate::utility::exclude::{build_exclude_rules, parse_exclude_pattern_list};
+
+        let (temp_dir, _, dest_dir) = colliding_sources(&["keep", "drop.tmp"]);
+        let sources = [
+            temp_dir.path().join("keep"),
+            temp_dir.path().join("drop.tmp"),
+        ];
+        let options = CopyOptions {
+            exclude_rules: build_exclude_rules(parse_exclude_pattern_list("*.tmp").unwrap())
+                .unwrap(),
+            ..CopyOptions::none()
+        };
+        let plan = preprocess_multiple(&sources, &dest_dir, &options).unwrap();
+        assert_eq!(
+            planned_files(&plan, &dest_dir),
+            [("keep/report.pdf".to_string(), "keep".to_string())]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_last_wins_file_replaces_a_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+        std_fs::create_dir(&dest_dir).unwrap();
+        create_test_file(&temp_dir.path().join("x/docs/notes/a.txt"), b"x").unwrap();
+        create_test_file(&temp_dir.path().join("y/docs/notes"), b"y").unwrap();
+        std::os::unix::fs::symlink("notes", temp_dir.path().join("x/docs/link")).unwrap();
+        std::os::unix::fs::symlink("notes", temp_dir.path().join("y/docs/link")).unwrap();
+        let sources = [
+            temp_dir.path().join("x/docs"),
+            temp_dir.path().join("y/docs"),
+        ];
+
+        let plan =
+            preprocess_multiple(&sources, &dest_dir, &with_policy(CollisionPolicy::LastWins))
+                .unwrap();
+        assert_eq!(
+            planned_files(&plan, &dest_dir),
+            [("docs/notes".to_string(), "y".to_string())]
+        );
+        assert!(
+            !plan
+                .directories
+                .iter()
+                .any(|d| d.destination == dest_dir.join("docs/notes"))
+        );
+        assert!(plan.violations(&dest_dir).is_empty());
+    }
+
+    #[test]
+    fn test_files_from_keeps_relative_structure() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("data");
+        for (name, content) in [
+            ("photos/2021/a.jpg", "a"),
+            ("photos/2021/c.jpg", "c"),
+            ("photos/2022/b.jpg", "b"),
+        ] {
+            create_test_file(&root.join(name), content.as_bytes()).unwrap();
+        }
+        let dest_dir = temp_dir.path().join("dest");
+        std_fs::create_dir(&dest_dir).unwrap();
+        let sources: Vec<PathBuf> = [
+            "photos/2021/a.jpg",
+            "photos/2022/b.jpg",
+            "photos/2021/c.jpg",
+        ]
+        .iter()
+        .map(|p| root.join(p))
+        .collect();
+        let options = CopyOptions {
+            files_from_root: Some(root.clone()),
+            ..CopyOptions::none()
+        };
+
+        let plan = preprocess_multiple(&sources, &dest_dir, &options).unwrap();
+        assert_eq!(
+            planned_files(&plan, &dest_dir),
+            vec![
+                ("photos/2021/a.jpg".to_string(), "a".to_string()),
+                ("photos/2021/c.jpg".to_string(), "c".to_string()),
+                ("photos/2022/b.jpg".to_string(), "b".to_string()),
+            ]
+        );
+        // Shared parents are planned once, outermost first.
+        let directories: Vec<_> = plan
+            .directories
+            .iter()
+            .map(|d| d.destination.strip_prefix(&dest_dir).unwrap())
+            .collect();
+        assert_eq!(
+            directories,
+            vec![
+                Path::new("photos"),
+                Path::new("photos/2021"),
+                Path::new("photos/2022")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_numbered_path_keeps_extension_of_files_only() {
+        assert_eq!(
+            numbered_path(Path::new("/a/report.pdf"), 1, false),
+            Path::new("/a/report-1.pdf")
+        );
+        assert_eq!(
+            numbered_path(Path::new("/a/v1.2"), 2, true),
+            Path::new("/a/v1.2-2")
+        );
+        assert_eq!(
+            numbered_path(Path::new("/a/.bashrc"), 1, false),
+            Path::new("/a/.bashrc-1")
+        );
+    }
+
     #[test]
     fn test_preprocess_file_normal_copy_mode() {
         let temp_dir = TempDir::new().unwrap();
@@ -788,4 +2475,401 @@ mod tests {
         assert_eq!(plan.symlinks[0].source, source);
         assert_eq!(plan.symlinks[0].destination, dest);
     }
+
+    #[test]
+    fn test_group_files_by_depth() {
+        let mut plan = CopyPlan::new();
+        for (name, size) in [
+            ("photos/2019/jan/a.jpg", 10),
+            ("photos/2019/b.jpg", 20),
+            ("photos/2020/c.jpg", 5),
+            ("top.txt", 1),
+        ] {
+            plan.add_file(
+                Path::new("/walk").join(name),
+                Path::new("/dest").join(name),
+                size,
+            );
+        }
+
+        plan.group_files(Path::new("src"), Path::new("/walk"), 2);
+        let groups: Vec<_> = plan
+            .files
+            .iter()
+            .map(|f| f.dir_group.clone().unwrap())
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                PathBuf::from("src/photos/2019"),
+                PathBuf::from("src/photos/2019"),
+                PathBuf::from("src/photos/2020"),
+                PathBuf::from("src"),
+            ]
+        );
+        assert_eq!(
+            plan.dir_group_totals(),
+            vec![
+                (PathBuf::from("src/photos/2019"), 2, 30),
+                (PathBuf::from("src/photos/2020"), 1, 5),
+                (PathBuf::from("src"), 1, 1),
+            ]
+        );
+
+        plan.group_files(Path::new("src"), Path::new("/walk"), 1);
+        assert_eq!(
+            plan.dir_group_totals()[0],
+            (PathBuf::from("src/photos"), 3, 35)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_entries_are_recorded_and_siblings_planned() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("ok.txt"), b"a").unwrap();
+        create_test_file(&source_dir.join("sub/file.txt"), b"b").unwrap();
+        // Followed, a link to itself can't be resolved (ELOOP).
+        symlink("self", source_dir.join("sub/self")).unwrap();
+
+        let options = CopyOptions {
+            follow_symlink: FollowSymlink::Dereference,
+            ..CopyOptions::none()
+        };
+        let dest_dir = temp_dir.path().join("dest");
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+
+        let mut planned: Vec<_> = plan
+            .files
+            .iter()
+            .map(|f| f.source.strip_prefix(&source_dir).unwrap().to_path_buf())
+            .collect();
+        planned.sort();
+        assert_eq!(
+            planned,
+            vec![PathBuf::from("ok.txt"), PathBuf::from("sub/file.txt")]
+        );
+        assert_eq!(plan.unreadable.len(), 1);
+        assert_eq!(plan.unreadable[0].path, source_dir.join("sub/self"));
+        assert!(plan.unreadable[0].reason.contains("loops"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_excluded_directories_are_not_read() {
+        use crate::utility::exclude::{build_exclude_rules, parse_exclude_pattern_list};
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("keep.txt"), b"a").unwrap();
+        create_test_file(&source_dir.join("node_modules/pkg/index.js"), b"b").unwrap();
+        // Reading this directory would report the loop as unreadable.
+        symlink("self", source_dir.join("node_modules/self")).unwrap();
+
+        let options = CopyOptions {
+            follow_symlink: FollowSymlink::Dereference,
+            exclude_rules: build_exclude_rules(parse_exclude_pattern_list("node_modules").unwrap())
+                .unwrap(),
+            itemize: true,
+            ..CopyOptions::none()
+        };
+        let dest_dir = temp_dir.path().join("dest");
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+
+        assert_eq!(plan.files.len(), 1);
+        assert!(plan.unreadable.is_empty(), "{:?}", plan.unreadable);
+        assert_eq!(plan.excluded.len(), 1);
+        assert_eq!(
+            plan.excluded[0].destination,
+            dest_dir.join("source/node_modules")
+        );
+        assert!(plan.excluded[0].is_dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_relative_symlink_loops_are_not_followed() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("sub/file.txt"), b"a").unwrap();
+        symlink("..", source_dir.join("sub/up")).unwrap();
+        symlink(".", source_dir.join("here")).unwrap();
+
+        let options = CopyOptions {
+            follow_symlink: FollowSymlink::Dereference,
+            ..CopyOptions::none()
+        };
+        let dest_dir = temp_dir.path().join("dest");
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+
+        assert_eq!(plan.files.len(), 1);
+        let mut loops: Vec<_> = plan.unreadable.iter().map(|u| u.path.clone()).collect();
+        loops.sort();
+        assert_eq!(loops, [source_dir.join("here"), source_dir.join("sub/up")]);
+        assert!(plan.unreadable.iter().all(|u| u.reason == SYMLINK_CYCLE));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_looping_through_each_other_are_not_followed() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("a/one.txt"), b"a").unwrap();
+        create_test_file(&source_dir.join("b/two.txt"), b"b").unwrap();
+        symlink("../b", source_dir.join("a/to_b")).unwrap();
+        symlink("../a", source_dir.join("b/to_a")).unwrap();
+
+        let options = CopyOptions {
+            follow_symlink: FollowSymlink::Dereference,
+            ..CopyOptions::none()
+        };
+        let dest_dir = temp_dir.path().join("dest");
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+
+        // Each directory is copied once more through the link to it, and
+        // the link back from there is left out.
+        let mut planned: Vec<_> = plan
+            .files
+            .iter()
+            .map(|f| f.destination.strip_prefix(&dest_dir).unwrap().to_path_buf())
+            .collect();
+        planned.sort();
+        assert_eq!(
+            planned,
+            [
+                "source/a/one.txt",
+                "source/a/to_b/two.txt",
+                "source/b/to_a/one.txt",
+                "source/b/two.txt"
+            ]
+            .map(PathBuf::from)
+        );
+        let mut cycles: Vec<_> = plan.unreadable.iter().map(|u| u.path.clone()).collect();
+        cycles.sort();
+        assert_eq!(
+            cycles,
+            [
+                source_dir.join("a/to_b/to_a"),
+                source_dir.join("b/to_a/to_b")
+            ]
+        );
+        assert!(plan.unreadable.iter().all(|u| u.reason == SYMLINK_CYCLE));
+    }
+
+    #[test]
+    fn test_fully_excluded_directories_are_pruned() {
+        use crate::utility::exclude::{build_exclude_rules, parse_exclude_pattern_list};
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("docs/readme.txt"), b"a").unwrap();
+        create_test_file(&source_dir.join("logs/run.log"), b"b").unwrap();
+        create_test_file(&source_dir.join("logs/old/run.log"), b"c").unwrap();
+        std_fs::create_dir(source_dir.join("empty")).unwrap();
+
+        let options = CopyOptions {
+            exclude_rules: build_exclude_rules(parse_exclude_pattern_list("*.log").unwrap())
+                .unwrap(),
+            ..CopyOptions::none()
+        };
+        let dest_dir = temp_dir.path().join("dest");
+        let mut plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+        let empty = plan.prune_directories();
+
+        let mut directories: Vec<_> = plan
+            .directories
+            .iter()
+            .map(|d| d.destination.strip_prefix(&dest_dir).unwrap().to_path_buf())
+            .collect();
+        directories.sort();
+        assert_eq!(
+            directories,
+            [
+                PathBuf::from("source"),
+                PathBuf::from("source/docs"),
+                PathBuf::from("source/empty"),
+            ]
+        );
+        let empty: Vec<_> = empty.iter().map(|d| d.destination.clone()).collect();
+        assert_eq!(empty, [dest_dir.join("source/empty")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fifos_are_recreated_unless_contents_are_copied() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("file.txt"), b"a").unwrap();
+        nix::unistd::mkfifo(&source_dir.join("pipe"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &CopyOptions::none())
+            .unwrap();
+        assert_eq!(plan.files.len(), 1);
+        assert_eq!(plan.specials.len(), 1);
+        assert_eq!(plan.specials[0].destination, dest_dir.join("source/pipe"));
+
+        let options = CopyOptions {
+            copy_contents: true,
+            ..CopyOptions::none()
+        };
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+        assert_eq!(plan.files.len(), 2);
+        assert!(plan.specials.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_no_clobber_counts_existing_destinations_as_skipped() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("new.txt"), b"new").unwrap();
+        create_test_file(&source_dir.join("old.txt"), b"source").unwrap();
+        create_test_file(&source_dir.join("sub/old.txt"), b"source").unwrap();
+        std::os::unix::fs::symlink("new.txt", source_dir.join("link")).unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+        create_test_file(&dest_dir.join("source/old.txt"), b"dest").unwrap();
+        create_test_file(&dest_dir.join("source/sub/old.txt"), b"dest").unwrap();
+        create_test_file(&dest_dir.join("source/link"), b"dest").unwrap();
+
+        let options = CopyOptions {
+            no_clobber: true,
+            ..CopyOptions::none()
+        };
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+        let files: Vec<_> = plan.files.iter().map(|f| f.destination.clone()).collect();
+        assert_eq!(files, [dest_dir.join("source/new.txt")]);
+        assert!(plan.symlinks.is_empty());
+        assert_eq!((plan.total_files, plan.total_size), (1, 3));
+        assert_eq!((plan.skipped_files, plan.kept_existing), (3, 3));
+        assert_eq!(plan.skipped_size, 12);
+        // The existing directories are still planned, for what is new in them.
+        assert_eq!(plan.directories.len(), 2);
+        assert!(plan.violations(&dest_dir).is_empty());
+    }
+
+    #[test]
+    fn test_existing_ignores_new_files_and_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("new.txt"), b"new").unwrap();
+        create_test_file(&source_dir.join("old.txt"), b"source").unwrap();
+        create_test_file(&source_dir.join("sub/new.txt"), b"new").unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+        create_test_file(&dest_dir.join("source/old.txt"), b"dest").unwrap();
+
+        let options = CopyOptions {
+            existing: true,
+            ..CopyOptions::none()
+        };
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+        let files: Vec<_> = plan.files.iter().map(|f| f.destination.clone()).collect();
+        assert_eq!(files, [dest_dir.join("source/old.txt")]);
+        assert_eq!((plan.total_files, plan.total_size), (1, 6));
+        assert_eq!((plan.ignored_new, plan.skipped_files), (2, 0));
+        let directories: Vec<_> = plan.directories.iter().map(|d| &d.destination).collect();
+        assert_eq!(directories, [&dest_dir.join("source")]);
+    }
+
+    #[test]
+    fn test_update_skips_files_no_newer_than_their_destination() {
+        use filetime::{FileTime, set_file_mtime};
+
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let dest_dir = temp_dir.path().join("dest");
+        let (older, newer) = (
+            FileTime::from_unix_time(1_000_000_000, 0),
+            FileTime::from_unix_time(1_500_000_000, 0),
+        );
+        // Source mtime, then destination mtime, or no destination.
+        let cases = [
+            ("newer.txt", newer, Some(older)),
+            ("equal.txt", newer, Some(newer)),
+            ("older.txt", older, Some(newer)),
+            ("sub/missing.txt", older, None),
+        ];
+        for (name, source_mtime, dest_mtime) in cases {
+            let source = source_dir.join(name);
+            create_test_file(&source, b"source").unwrap();
+            set_file_mtime(&source, source_mtime).unwrap();
+            if let Some(mtime) = dest_mtime {
+                let dest = dest_dir.join("source").join(name);
+                create_test_file(&dest, b"dest!!!").unwrap();
+                set_file_mtime(&dest, mtime).unwrap();
+            }
+        }
+
+        let options = CopyOptions {
+            update: UpdateMode::Older,
+            ..CopyOptions::none()
+        };
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+        let mut files: Vec<_> = plan.files.iter().map(|f| f.destination.clone()).collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                dest_dir.join("source/newer.txt"),
+                dest_dir.join("source/sub/missing.txt")
+            ]
+        );
+        assert_eq!((plan.total_files, plan.total_size), (2, 12));
+        assert_eq!((plan.skipped_files, plan.skipped_size), (2, 12));
+        assert_eq!(plan.directories.len(), 2);
+    }
+
+    #[test]
+    fn test_no_target_directory_copies_contents_into_the_destination() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("sub/a.txt"), b"a").unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+        std_fs::create_dir(&dest_dir).unwrap();
+
+        let options = CopyOptions {
+            no_target_directory: true,
+            ..CopyOptions::none()
+        };
+        let plan = preprocess_directory(&source_dir, &source_dir, &dest_dir, &options).unwrap();
+        assert_eq!(plan.files[0].destination, dest_dir.join("sub/a.txt"));
+        assert_eq!(plan.directories[0].destination, dest_dir);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_one_file_system_makes_other_filesystems_empty() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        create_test_file(&source_dir.join("sub/a.txt"), b"a").unwrap();
+        // Followed with -L, the link leads onto procfs.
+        std::os::unix::fs::symlink("/proc", source_dir.join("proc")).unwrap();
+        let dest_dir = temp_dir.path().join("dest");
+
+        let options = CopyOptions {
+            one_file_system: true,
+            follow_symlink: FollowSymlink::Dereference,
+            ..CopyOptions::none()
+        };
+        let plan = preprocess_directory(&source_dir, temp_dir.path(), &dest_dir, &options).unwrap();
+        assert_eq!(plan.other_filesystems, 1);
+        assert_eq!(plan.total_files, 1);
+        assert!(plan.unreadable.is_empty());
+        let proc = dest_dir.join("source/proc");
+        assert!(plan.directories.iter().any(|d| d.destination == proc));
+        assert!(
+            !plan
+                .directories
+                .iter()
+                .any(|d| d.destination.starts_with(proc.join("self")))
+        );
+    }
 }
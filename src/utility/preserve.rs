@@ -1,7 +1,13 @@
 use crate::error::{PreserveError, PreserveResult};
+use crate::utility::chmod::ModeSpec;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+use crate::utility::helper::describe_io_error;
+use crate::utility::idmap::OwnerMap;
 use std::collections::HashMap;
+use std::fs::Metadata;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
@@ -11,9 +17,136 @@ pub struct PreserveAttr {
     pub mode: bool,
     pub ownership: bool,
     pub timestamps: bool,
+    pub atimes: bool,
     pub links: bool,
     pub context: bool,
+    /// `-Z`: give each copy the SELinux context policy picks for where it
+    /// lands, instead of the source's.
+    pub default_context: bool,
     pub xattr: bool,
+    pub fsflags: bool,
+}
+
+/// Difference in mtime small enough to be the destination filesystem's
+/// rounding rather than drift; FAT keeps times to two seconds.
+const MTIME_WINDOW_SECS: i64 = 2;
+
+/// Whether `destination` lacks the mode, owner or mtime in `attrs` that
+/// copying from a source with `source_metadata` would give it. Those are the
+/// cheap ones to compare; the rest are set again along with them. Stamped
+/// times and owners are always taken to differ, and owners the user can't
+/// set never are.
+pub fn attributes_differ(
+    source_metadata: &Metadata,
+    destination: &Path,
+    attrs: PreserveAttr,
+    overrides: &AttrOverrides,
+) -> bool {
+    let Ok(dest_metadata) = std::fs::symlink_metadata(destination) else {
+        return true;
+    };
+    if overrides.stamps() || overrides.sets_mode() {
+        return true;
+    }
+    if attrs.timestamps {
+        let seconds = |metadata: &Metadata| {
+            metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64)
+        };
+        match (seconds(source_metadata), seconds(&dest_metadata)) {
+            (Some(source), Some(dest)) if (source - dest).abs() < MTIME_WINDOW_SECS => {}
+            _ => return true,
+        }
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if attrs.mode && source_metadata.mode() & 0o7777 != dest_metadata.mode() & 0o7777 {
+            return true;
+        }
+        if attrs.ownership
+            && !overrides.best_effort_owner
+            && overrides
+                .owners
+                .map(source_metadata.uid(), source_metadata.gid())
+                != (dest_metadata.uid(), dest_metadata.gid())
+        {
+            return true;
+        }
+    }
+    #[cfg(not(unix))]
+    if attrs.mode
+        && source_metadata.permissions().readonly() != dest_metadata.permissions().readonly()
+    {
+        return true;
+    }
+    false
+}
+
+/// Destination metadata chosen by the caller rather than taken from the
+/// source, for `--timestamp`, `--reproducible`, `--usermap`, `--groupmap`,
+/// `--chmod` and `--chmod-dir`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AttrOverrides {
+    /// Modification time given with `--timestamp`.
+    pub mtime: Option<SystemTime>,
+    /// Normalize times and ownership so identical sources give identical
+    /// copies: both times become the source's mtime, no later than `epoch`,
+    /// and the owner becomes 0:0 as root or the current user otherwise.
+    pub reproducible: bool,
+    /// `SOURCE_DATE_EPOCH`: the latest time a reproducible copy may carry.
+    pub epoch: Option<SystemTime>,
+    /// Translation applied to the source's owner and group when ownership
+    /// is preserved.
+    pub owners: OwnerMap,
+    /// Set by a user without the privilege to give files away: an owner
+    /// the system refuses is skipped, even one asked for with a map.
+    pub best_effort_owner: bool,
+    /// Mode given with `--chmod`, applied after whatever was preserved.
+    pub chmod: Option<ModeSpec>,
+    /// Mode given with `--chmod-dir`, for directories instead of `chmod`.
+    pub chmod_dir: Option<ModeSpec>,
+}
+
+impl AttrOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether destination times or ownership are set regardless of what is
+    /// preserved, rather than only translated.
+    pub fn stamps(&self) -> bool {
+        self.mtime.is_some() || self.reproducible
+    }
+
+    /// Whether destination modes are set regardless of what is preserved.
+    pub fn sets_mode(&self) -> bool {
+        self.chmod.is_some() || self.chmod_dir.is_some()
+    }
+
+    /// Mode to give a destination that is or isn't a directory, if any.
+    pub fn chmod_for(&self, is_dir: bool) -> Option<&ModeSpec> {
+        if is_dir {
+            self.chmod_dir.as_ref().or(self.chmod.as_ref())
+        } else {
+            self.chmod.as_ref()
+        }
+    }
+
+    /// Time to give a destination whose source was last modified at
+    /// `source_mtime`, or that has no source, if any.
+    pub fn time_for(&self, source_mtime: Option<SystemTime>) -> Option<SystemTime> {
+        if self.mtime.is_some() || !self.reproducible {
+            return self.mtime;
+        }
+        match (source_mtime, self.epoch) {
+            (Some(mtime), Some(epoch)) => Some(mtime.min(epoch)),
+            (mtime, epoch) => mtime.or(epoch),
+        }
+    }
 }
 
 impl Default for PreserveAttr {
@@ -22,9 +155,12 @@ impl Default for PreserveAttr {
             mode: true,
             ownership: true,
             timestamps: true,
+            atimes: true,
             links: false,
             context: false,
+            default_context: false,
             xattr: false,
+            fsflags: false,
         }
     }
 }
@@ -35,20 +171,27 @@ impl PreserveAttr {
             mode: false,
             ownership: false,
             timestamps: false,
+            atimes: false,
             links: false,
             context: false,
+            default_context: false,
             xattr: false,
+            fsflags: false,
         }
     }
 
+    /// Everything except `fsflags`, which must be requested explicitly.
     pub fn all() -> Self {
         Self {
             mode: true,
             ownership: true,
             timestamps: true,
+            atimes: true,
             links: true,
             context: true,
+            default_context: false,
             xattr: true,
+            fsflags: false,
         }
     }
 
@@ -68,11 +211,21 @@ impl PreserveAttr {
                 "" => continue,
                 "mode" => attr.mode = true,
                 "ownership" => attr.ownership = true,
-                "timestamps" => attr.timestamps = true,
+                "timestamps" => {
+                    attr.timestamps = true;
+                    attr.atimes = true;
+                }
+                "atimes" => attr.atimes = true,
                 "xattr" => attr.xattr = true,
                 "context" => attr.context = true,
                 "links" => attr.links = true,
-                "all" => return Ok(Self::all()),
+                "fsflags" => attr.fsflags = true,
+                "all" => {
+                    attr = Self {
+                        fsflags: attr.fsflags,
+                        ..Self::all()
+                    }
+                }
                 other => {
                     return Err(PreserveError::UnsupportedAttribute(format!(
                         "Unknown attribute: {}",
@@ -84,76 +237,473 @@ impl PreserveAttr {
 
         Ok(attr)
     }
+
+    /// The attributes in either set.
+    pub fn with(self, other: Self) -> Self {
+        Self {
+            mode: self.mode || other.mode,
+            ownership: self.ownership || other.ownership,
+            timestamps: self.timestamps || other.timestamps,
+            atimes: self.atimes || other.atimes,
+            links: self.links || other.links,
+            context: self.context || other.context,
+            default_context: self.default_context || other.default_context,
+            xattr: self.xattr || other.xattr,
+            fsflags: self.fsflags || other.fsflags,
+        }
+    }
+
+    /// The attributes in this set that aren't in `other`.
+    pub fn without(self, other: Self) -> Self {
+        Self {
+            mode: self.mode && !other.mode,
+            ownership: self.ownership && !other.ownership,
+            timestamps: self.timestamps && !other.timestamps,
+            atimes: self.atimes && !other.atimes,
+            links: self.links && !other.links,
+            context: self.context && !other.context,
+            default_context: self.default_context && !other.default_context,
+            xattr: self.xattr && !other.xattr,
+            fsflags: self.fsflags && !other.fsflags,
+        }
+    }
+}
+
+/// An attribute [`apply_preserve_attrs`] can carry over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attribute {
+    Timestamps,
+    Atimes,
+    Mode,
+    Ownership,
+    Xattr,
+    Context,
+    Fsflags,
+}
+
+impl Attribute {
+    /// The name `--preserve` knows it by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Attribute::Timestamps => "timestamps",
+            Attribute::Atimes => "atimes",
+            Attribute::Mode => "mode",
+            Attribute::Ownership => "ownership",
+            Attribute::Xattr => "xattr",
+            Attribute::Context => "context",
+            Attribute::Fsflags => "fsflags",
+        }
+    }
+}
+
+impl std::fmt::Display for Attribute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
+/// What became of each attribute asked for. Failures that stop the copy
+/// are returned as errors instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreserveOutcome {
+    pub applied: Vec<Attribute>,
+    /// Not supported here, or refused as it is to anyone but root, such as
+    /// ownership or xattrs on a filesystem without them.
+    pub skipped: Vec<Attribute>,
+    /// Could not be set, with why; the copy went on without them.
+    pub failed: Vec<(Attribute, String)>,
+}
+
+/// How setting one attribute went, short of an error.
+enum Step {
+    Applied,
+    Skipped,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Failed(String),
+}
+
+impl PreserveOutcome {
+    fn record(&mut self, attribute: Attribute, step: Step) {
+        match step {
+            Step::Applied => self.applied.push(attribute),
+            Step::Skipped => self.skipped.push(attribute),
+            Step::Failed(reason) => self.failed.push((attribute, reason)),
+        }
+    }
+}
+
+/// Carry `attrs` over from `source` to `destination`. `source_metadata` is
+/// the source as stat'ed while planning, if it was; otherwise it is stat'ed
+/// now.
 pub fn apply_preserve_attrs(
     source: &Path,
     destination: &Path,
     attrs: PreserveAttr,
-) -> PreserveResult<()> {
-    let src_metadata = std::fs::metadata(source).map_err(|_e| PreserveError::FailedToPreserve {
-        path: source.to_path_buf(),
-        attribute: "metadata".to_string(),
-    })?;
+    source_metadata: Option<&Metadata>,
+) -> PreserveResult<PreserveOutcome> {
+    apply_preserve_attrs_with_atime(
+        source,
+        destination,
+        attrs,
+        source_metadata,
+        None,
+        &AttrOverrides::default(),
+    )
+}
+
+/// Like [`apply_preserve_attrs`], but uses `source_atime`, when given,
+/// instead of the source's access time, and applies `overrides` on top of
+/// what was preserved.
+pub fn apply_preserve_attrs_with_atime(
+    source: &Path,
+    destination: &Path,
+    attrs: PreserveAttr,
+    source_metadata: Option<&Metadata>,
+    source_atime: Option<SystemTime>,
+    overrides: &AttrOverrides,
+) -> PreserveResult<PreserveOutcome> {
+    let stat;
+    let src_metadata = match source_metadata {
+        Some(metadata) => metadata,
+        None => {
+            stat = std::fs::metadata(source).map_err(|error| PreserveError::FailedToPreserve {
+                path: source.to_path_buf(),
+                attribute: "metadata".to_string(),
+                error,
+            })?;
+            &stat
+        }
+    };
+    let failed = |attribute: Attribute| {
+        move |error| PreserveError::FailedToPreserve {
+            path: destination.to_path_buf(),
+            attribute: attribute.name().to_string(),
+            error,
+        }
+    };
+    let mut outcome = PreserveOutcome::default();
+
     if attrs.timestamps {
-        preserve_timestamps(destination, &src_metadata).map_err(|_e| {
-            PreserveError::FailedToPreserve {
-                path: destination.to_path_buf(),
-                attribute: "timestamps".to_string(),
-            }
-        })?;
+        preserve_timestamps(destination, src_metadata).map_err(failed(Attribute::Timestamps))?;
+        preserve_birth_time(destination, src_metadata).map_err(failed(Attribute::Timestamps))?;
+        outcome.record(Attribute::Timestamps, Step::Applied);
     }
-    #[cfg(unix)]
+    if attrs.atimes {
+        let accessed = source_atime.or_else(|| src_metadata.accessed().ok());
+        preserve_atime(destination, accessed).map_err(failed(Attribute::Atimes))?;
+        outcome.record(Attribute::Atimes, Step::Applied);
+    }
+    // Before mode and flags, which may leave the file read-only or immutable.
+    if overrides.stamps() {
+        apply_overrides(destination, src_metadata.modified().ok(), overrides)?;
+    }
+
+    if attrs.ownership {
+        #[cfg(unix)]
+        let step = preserve_ownership(destination, src_metadata, overrides)
+            .map_err(failed(Attribute::Ownership))?;
+        #[cfg(not(unix))]
+        let step = Step::Skipped;
+        outcome.record(Attribute::Ownership, step);
+    }
+
+    if attrs.xattr {
+        #[cfg(unix)]
+        let step = preserve_xattr(source, destination).map_err(failed(Attribute::Xattr))?;
+        #[cfg(not(unix))]
+        let step = Step::Skipped;
+        outcome.record(Attribute::Xattr, step);
+    }
+
+    // A filesystem without labels, or a policy that won't allow the label,
+    // leaves the copy with whatever it got and a warning.
+    if attrs.context || attrs.default_context {
+        let result = if attrs.default_context {
+            set_default_context(destination)
+        } else {
+            preserve_context(source, destination)
+        };
+        let step = match result {
+            Ok(true) => Step::Applied,
+            Ok(false) => Step::Skipped,
+            Err(e) => Step::Failed(e.to_string()),
+        };
+        outcome.record(Attribute::Context, step);
+    }
+
+    // After everything else that changes the file: a mode without write
+    // bits refuses user xattrs to anyone but root, and chown clears setuid.
     if attrs.mode {
-        preserve_mode(destination, &src_metadata).map_err(|_e| {
-            PreserveError::FailedToPreserve {
-                path: destination.to_path_buf(),
-                attribute: "mode".to_string(),
-            }
-        })?;
+        #[cfg(unix)]
+        let step = {
+            preserve_mode(destination, src_metadata).map_err(failed(Attribute::Mode))?;
+            Step::Applied
+        };
+        #[cfg(not(unix))]
+        let step = Step::Skipped;
+        outcome.record(Attribute::Mode, step);
+    }
+    // An explicit mode wins over a preserved one.
+    apply_chmod(destination, overrides)?;
+
+    // Last: immutable/append-only flags block every later change to the file.
+    if attrs.fsflags {
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+        let step = match preserve_fsflags(source, destination) {
+            Ok(()) => Step::Applied,
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => Step::Failed(describe_io_error(&e)),
+            Err(error) => return Err(failed(Attribute::Fsflags)(error)),
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+        let step = Step::Skipped;
+        outcome.record(Attribute::Fsflags, step);
+    }
+
+    Ok(outcome)
+}
+
+/// Give the symlink `destination` the times of the source link described
+/// by `src_metadata` and, when ownership is preserved, its owner. The link
+/// itself is changed, never what it points to.
+pub fn preserve_symlink_attrs(
+    destination: &Path,
+    src_metadata: &Metadata,
+    attrs: PreserveAttr,
+    overrides: &AttrOverrides,
+) -> io::Result<()> {
+    use filetime::FileTime;
+
+    if attrs.timestamps || attrs.atimes {
+        let dest_metadata = std::fs::symlink_metadata(destination)?;
+        let mtime = FileTime::from_last_modification_time(if attrs.timestamps {
+            src_metadata
+        } else {
+            &dest_metadata
+        });
+        let atime = FileTime::from_last_access_time(if attrs.atimes {
+            src_metadata
+        } else {
+            &dest_metadata
+        });
+        // utimensat with AT_SYMLINK_NOFOLLOW, or lutimes on macOS.
+        filetime::set_symlink_file_times(destination, atime, mtime)?;
     }
 
     #[cfg(unix)]
     if attrs.ownership {
-        preserve_ownership(destination, &src_metadata).map_err(|_e| {
-            PreserveError::FailedToPreserve {
-                path: destination.to_path_buf(),
-                attribute: "ownership".to_string(),
-            }
-        })?;
+        use std::os::unix::fs::MetadataExt;
+
+        let owners = &overrides.owners;
+        let (uid, gid) = owners.map(src_metadata.uid(), src_metadata.gid());
+        match std::os::unix::fs::lchown(destination, Some(uid), Some(gid)) {
+            // Refused to anyone but root, as for files.
+            Err(e)
+                if (owners.is_empty() || overrides.best_effort_owner)
+                    && matches!(e.raw_os_error(), Some(libc::EPERM | libc::EACCES)) => {}
+            result => result?,
+        }
     }
+    #[cfg(not(unix))]
+    let _ = overrides;
+    Ok(())
+}
 
+/// Give `destination` the mode `--chmod` or `--chmod-dir` asks for, if
+/// either does. Symlinks have no mode of their own and are left alone.
+pub fn apply_chmod(destination: &Path, overrides: &AttrOverrides) -> PreserveResult<()> {
+    if !overrides.sets_mode() {
+        return Ok(());
+    }
+    let failed = |error| PreserveError::FailedToPreserve {
+        path: destination.to_path_buf(),
+        attribute: "mode".to_string(),
+        error,
+    };
+    let metadata = std::fs::symlink_metadata(destination).map_err(failed)?;
+    let Some(spec) = overrides.chmod_for(metadata.is_dir()) else {
+        return Ok(());
+    };
     #[cfg(unix)]
-    if attrs.xattr {
-        preserve_xattr(source, destination).map_err(|_e| PreserveError::FailedToPreserve {
+    if !metadata.file_type().is_symlink() {
+        let mode = metadata.permissions().mode();
+        let wanted = spec.apply(mode, metadata.is_dir());
+        if wanted != mode {
+            std::fs::set_permissions(
+                destination,
+                std::fs::Permissions::from_mode(wanted & 0o7777),
+            )
+            .map_err(failed)?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = spec;
+    Ok(())
+}
+
+/// Apply `overrides` to `destination`, whose source was last modified at
+/// `source_mtime` (`None` for directories made up by the copy).
+pub fn apply_overrides(
+    destination: &Path,
+    source_mtime: Option<SystemTime>,
+    overrides: &AttrOverrides,
+) -> PreserveResult<()> {
+    use filetime::{FileTime, set_file_mtime, set_file_times};
+
+    if let Some(time) = overrides.time_for(source_mtime) {
+        let time = FileTime::from_system_time(time);
+        // Reading the source moves its access time, so a reproducible copy
+        // pins that too.
+        let result = if overrides.reproducible {
+            set_file_times(destination, time, time)
+        } else {
+            set_file_mtime(destination, time)
+        };
+        result.map_err(|error| PreserveError::FailedToPreserve {
             path: destination.to_path_buf(),
-            attribute: "xattr".to_string(),
+            attribute: "timestamp".to_string(),
+            error,
         })?;
     }
 
     #[cfg(unix)]
-    if attrs.context {
-        preserve_context(source, destination).map_err(|_e| PreserveError::FailedToPreserve {
+    if overrides.reproducible {
+        normalize_ownership(destination).map_err(|error| PreserveError::FailedToPreserve {
             path: destination.to_path_buf(),
-            attribute: "context".to_string(),
+            attribute: "ownership".to_string(),
+            error,
         })?;
     }
+    Ok(())
+}
+
+/// The owner, group and mode a destination had before it was overwritten,
+/// for `--keep-dest-attrs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeptAttrs {
+    permissions: std::fs::Permissions,
+    #[cfg(unix)]
+    owner: (u32, u32),
+}
+
+impl KeptAttrs {
+    /// Those of the file at `destination`, if there is one.
+    pub fn snapshot(destination: &Path) -> Option<Self> {
+        let metadata = std::fs::symlink_metadata(destination).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+        Some(Self {
+            permissions: metadata.permissions(),
+            #[cfg(unix)]
+            owner: (metadata.uid(), metadata.gid()),
+        })
+    }
+
+    /// Give them back to the file now at `destination`, which may be a new
+    /// one renamed over the old. The owner goes first, as changing it
+    /// clears setuid and setgid bits.
+    pub fn restore(&self, destination: &Path) -> PreserveResult<()> {
+        let failed = |attribute: &str, error| PreserveError::FailedToPreserve {
+            path: destination.to_path_buf(),
+            attribute: attribute.to_string(),
+            error,
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = std::fs::metadata(destination).map_err(|e| failed("ownership", e))?;
+            // Overwritten in place, the file is still the old one's.
+            if (metadata.uid(), metadata.gid()) != self.owner {
+                let (uid, gid) = self.owner;
+                std::os::unix::fs::chown(destination, Some(uid), Some(gid))
+                    .map_err(|e| failed("ownership", e))?;
+            }
+        }
+        std::fs::set_permissions(destination, self.permissions.clone())
+            .map_err(|e| failed("mode", e))
+    }
+}
+
+/// Give `destination` a fixed owner: 0:0 when running as root, the current
+/// user and group otherwise (a setgid parent may have picked another group).
+#[cfg(unix)]
+fn normalize_ownership(destination: &Path) -> io::Result<()> {
+    let (uid, gid) = unsafe {
+        if libc::geteuid() == 0 {
+            (0, 0)
+        } else {
+            (libc::geteuid(), libc::getegid())
+        }
+    };
+    let dest_cstring = std::ffi::CString::new(destination.to_string_lossy().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
+    if unsafe { libc::chown(dest_cstring.as_ptr(), uid, gid) } != 0 {
+        let err = io::Error::last_os_error();
+        // Filesystems without ownership (FAT, some network mounts) refuse.
+        if err.raw_os_error() != Some(libc::EPERM) {
+            return Err(err);
+        }
+    }
     Ok(())
 }
 
 fn preserve_timestamps(destination: &Path, src_metadata: &std::fs::Metadata) -> io::Result<()> {
     use filetime::{FileTime, set_file_mtime};
 
-    let modified_time = src_metadata.modified().map_err(io::Error::other)?;
+    let modified_time = src_metadata.modified()?;
 
     let system_modified_time = FileTime::from_system_time(modified_time);
 
-    set_file_mtime(destination, system_modified_time).map_err(io::Error::other)?;
+    set_file_mtime(destination, system_modified_time)?;
+
+    Ok(())
+}
+
+/// Set the destination's creation time on platforms where it is writable.
+#[cfg(any(target_os = "macos", windows))]
+fn preserve_birth_time(destination: &Path, src_metadata: &std::fs::Metadata) -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::{FileTimesExt, OpenOptionsExt};
+
+    let Ok(created) = src_metadata.created() else {
+        return Ok(());
+    };
+
+    #[cfg(target_os = "macos")]
+    let file = std::fs::File::open(destination)?;
+    #[cfg(windows)]
+    let file = {
+        const FILE_WRITE_ATTRIBUTES: u32 = 0x0100;
+        const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+        std::fs::OpenOptions::new()
+            .access_mode(FILE_WRITE_ATTRIBUTES)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+            .open(destination)?
+    };
+
+    file.set_times(std::fs::FileTimes::new().set_created(created))
+}
 
+/// Linux exposes birth time through statx but offers no way to set it.
+#[cfg(not(any(target_os = "macos", windows)))]
+fn preserve_birth_time(_destination: &Path, _src_metadata: &std::fs::Metadata) -> io::Result<()> {
     Ok(())
 }
 
+fn preserve_atime(destination: &Path, accessed: Option<SystemTime>) -> io::Result<()> {
+    use filetime::{FileTime, set_file_atime};
+
+    let Some(accessed) = accessed else {
+        return Ok(());
+    };
+    set_file_atime(destination, FileTime::from_system_time(accessed))
+}
+
 #[cfg(unix)]
 fn preserve_mode(destination: &Path, src_metadata: &std::fs::Metadata) -> io::Result<()> {
     use std::fs::Permissions;
@@ -167,11 +717,14 @@ fn preserve_mode(destination: &Path, src_metadata: &std::fs::Metadata) -> io::Re
 }
 
 #[cfg(unix)]
-fn preserve_ownership(destination: &Path, src_metadata: &std::fs::Metadata) -> io::Result<()> {
+fn preserve_ownership(
+    destination: &Path,
+    src_metadata: &std::fs::Metadata,
+    overrides: &AttrOverrides,
+) -> io::Result<Step> {
     use std::os::unix::fs::MetadataExt;
 
-    let uid = src_metadata.uid();
-    let gid = src_metadata.gid();
+    let (uid, gid) = overrides.owners.map(src_metadata.uid(), src_metadata.gid());
 
     // Note: This requires elevated privileges (root) to work in most cases
     // We'll attempt it but won't fail if it doesn't work
@@ -183,62 +736,188 @@ fn preserve_ownership(destination: &Path, src_metadata: &std::fs::Metadata) -> i
         if result != 0 {
             let err = io::Error::last_os_error();
             // Only return error if it's not a permission issue
-            // (EPERM = 1, EACCES = 13)
-            if err.raw_os_error() != Some(1) && err.raw_os_error() != Some(13) {
+            // (EPERM = 1, EACCES = 13), unless the owner was asked for
+            // explicitly with a map by someone who may give files away
+            let refused = err.raw_os_error() == Some(1) || err.raw_os_error() == Some(13);
+            if !refused || (!overrides.owners.is_empty() && !overrides.best_effort_owner) {
                 return Err(err);
             }
+            return Ok(Step::Skipped);
         }
     }
 
-    Ok(())
+    Ok(Step::Applied)
 }
 
 #[cfg(unix)]
-fn preserve_xattr(source: &Path, destination: &Path) -> io::Result<()> {
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// Copy every xattr of `source` but its SELinux label; the ones the
+/// destination refuses are reported rather than failing the copy.
+#[cfg(unix)]
+fn preserve_xattr(source: &Path, destination: &Path) -> io::Result<Step> {
     if !xattr::SUPPORTED_PLATFORM {
-        return Ok(());
+        return Ok(Step::Skipped);
     }
 
     let xattrs = match xattr::list(source) {
         Ok(attrs) => attrs,
         Err(e) => {
             if e.kind() == io::ErrorKind::Unsupported {
-                return Ok(());
+                return Ok(Step::Skipped);
             }
             return Err(e);
         }
     };
-    for attr_name in xattrs {
-        if let Some(value) = xattr::get(source, &attr_name)? {
-            let _ = xattr::set(destination, &attr_name, &value);
+    let mut refused = Vec::new();
+    // The SELinux label is the context, preserved or not on its own.
+    for attr_name in xattrs.filter(|name| name != SELINUX_XATTR) {
+        if let Some(value) = xattr::get(source, &attr_name)?
+            && let Err(e) = xattr::set(destination, &attr_name, &value)
+        {
+            refused.push(format!("{}: {}", attr_name.to_string_lossy(), e));
         }
     }
+    if refused.is_empty() {
+        Ok(Step::Applied)
+    } else {
+        Ok(Step::Failed(refused.join(", ")))
+    }
+}
+
+/// Flags that are safe to carry over: append-only, immutable, nodump, noatime.
+#[cfg(target_os = "linux")]
+const FS_FLAGS_SAFE: libc::c_int = 0x20 | 0x10 | 0x40 | 0x80;
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+#[cfg(target_os = "linux")]
+fn get_fsflags(path: &Path) -> io::Result<libc::c_int> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    let request = nix::request_code_read!(b'f', 1, std::mem::size_of::<libc::c_long>());
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags)
+}
+
+#[cfg(target_os = "linux")]
+fn set_fsflags(path: &Path, flags: libc::c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open(path)?;
+    let request = nix::request_code_write!(b'f', 2, std::mem::size_of::<libc::c_long>());
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copy chattr flags, setting immutable in a second step since nothing can
+/// be changed once it is on. Setting immutable or append-only needs
+/// CAP_LINUX_IMMUTABLE, which surfaces as EPERM.
+#[cfg(target_os = "linux")]
+fn preserve_fsflags(source: &Path, destination: &Path) -> io::Result<()> {
+    let wanted = get_fsflags(source)? & FS_FLAGS_SAFE;
+    let current = get_fsflags(destination)?;
+    let base = current & !FS_FLAGS_SAFE;
+
+    let without_immutable = base | (wanted & !FS_IMMUTABLE_FL);
+    if without_immutable != current {
+        set_fsflags(destination, without_immutable)?;
+    }
+    if wanted & FS_IMMUTABLE_FL != 0 {
+        set_fsflags(destination, without_immutable | FS_IMMUTABLE_FL)?;
+    }
     Ok(())
 }
 
-#[cfg(all(unix, feature = "selinux-support"))]
-pub fn preserve_context(source: &Path, destination: &Path) -> io::Result<()> {
-    use selinux;
-    if selinux::kernel_support() == selinux::KernelSupport::Unsupported {
+/// Copy the user-settable chflags: uchg, uappnd and nodump.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn preserve_fsflags(source: &Path, destination: &Path) -> io::Result<()> {
+    #[cfg(target_os = "freebsd")]
+    use std::os::freebsd::fs::MetadataExt;
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::MetadataExt;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mask = (libc::UF_IMMUTABLE | libc::UF_APPEND | libc::UF_NODUMP) as u32;
+    let wanted = std::fs::metadata(source)?.st_flags() & mask;
+    let current = std::fs::metadata(destination)?.st_flags();
+    let flags = (current & !mask) | wanted;
+    if flags == current {
         return Ok(());
     }
 
+    let c_path = std::ffi::CString::new(destination.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::chflags(c_path.as_ptr(), flags as _) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether the running kernel enforces or logs SELinux policy, so that
+/// contexts mean something. Always false without the `selinux-support`
+/// feature.
+#[cfg(all(target_os = "linux", feature = "selinux-support"))]
+pub fn selinux_enabled() -> bool {
+    selinux::kernel_support() != selinux::KernelSupport::Unsupported
+}
+
+#[cfg(not(all(target_os = "linux", feature = "selinux-support")))]
+pub fn selinux_enabled() -> bool {
+    false
+}
+
+/// Copy the SELinux context of `source`. Returns whether there was one to
+/// copy.
+#[cfg(all(target_os = "linux", feature = "selinux-support"))]
+pub fn preserve_context(source: &Path, destination: &Path) -> io::Result<bool> {
+    if !selinux_enabled() {
+        return Ok(false);
+    }
+
     let context = selinux::SecurityContext::of_path(source, false, false)
         .map_err(|e| std::io::Error::other(format!("Failed to get SELinux context: {}", e)))?;
 
     let Some(context) = context else {
-        return Ok(());
+        return Ok(false);
     };
 
     context
         .set_for_path(destination, false, false)
         .map_err(|e| std::io::Error::other(format!("Failed to set SELinux context: {}", e)))?;
 
-    Ok(())
+    Ok(true)
 }
-#[cfg(not(all(unix, feature = "selinux-support")))]
-pub fn preserve_context(_source: &Path, _destination: &Path) -> io::Result<()> {
-    Ok(()) // No-op when SELinux support is disabled
+
+/// Give `destination` the context policy has for its path, as restorecon
+/// would. Returns whether there was a policy to ask.
+#[cfg(all(target_os = "linux", feature = "selinux-support"))]
+pub fn set_default_context(destination: &Path) -> io::Result<bool> {
+    if !selinux_enabled() {
+        return Ok(false);
+    }
+
+    selinux::SecurityContext::set_default_for_path(destination).map_err(|e| {
+        std::io::Error::other(format!("Failed to set default SELinux context: {}", e))
+    })?;
+
+    Ok(true)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "selinux-support")))]
+pub fn preserve_context(_source: &Path, _destination: &Path) -> io::Result<bool> {
+    Ok(false) // No-op when SELinux support is disabled
+}
+
+#[cfg(not(all(target_os = "linux", feature = "selinux-support")))]
+pub fn set_default_context(_destination: &Path) -> io::Result<bool> {
+    Ok(false)
 }
 
 #[cfg(unix)]
@@ -278,6 +957,28 @@ impl HardLinkTracker {
             Ok(false) // Need to copy the file normally
         }
     }
+
+    /// The destination first written for `source`'s inode, if any.
+    pub fn destination_for(&self, source: &Path) -> Option<PathBuf> {
+        use std::os::unix::fs::MetadataExt;
+
+        let inode = std::fs::metadata(source).ok()?.ino();
+        self.inode_to_destination.get(&inode).cloned()
+    }
+
+    /// The destination first recorded for `inode`, if any; otherwise
+    /// `destination` is recorded as that. For inodes known without a stat,
+    /// such as a symlink's.
+    pub fn first_for(&mut self, inode: u64, destination: &Path) -> Option<PathBuf> {
+        match self.inode_to_destination.get(&inode) {
+            Some(first) => Some(first.clone()),
+            None => {
+                self.inode_to_destination
+                    .insert(inode, destination.to_path_buf());
+                None
+            }
+        }
+    }
 }
 
 #[cfg(not(unix))]
@@ -296,6 +997,14 @@ impl HardLinkTracker {
     ) -> io::Result<bool> {
         Ok(false) // No hard link support on non-Unix systems
     }
+
+    pub fn destination_for(&self, _source: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    pub fn first_for(&mut self, _inode: u64, _destination: &Path) -> Option<PathBuf> {
+        None
+    }
 }
 #[cfg(test)]
 mod tests {
@@ -305,6 +1014,86 @@ mod tests {
     use std::time::Duration;
     use tempfile::TempDir;
 
+    #[cfg(unix)]
+    #[test]
+    fn test_attributes_differ_only_on_drift() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, "data").unwrap();
+        fs::write(&dest, "data").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o640)).unwrap();
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o640)).unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_mtime(&source, mtime).unwrap();
+        filetime::set_file_mtime(&dest, mtime).unwrap();
+        let attrs = PreserveAttr::from_string("mode,timestamps").unwrap();
+        let overrides = AttrOverrides::default();
+        let source_metadata = || fs::metadata(&source).unwrap();
+
+        assert!(!attributes_differ(
+            &source_metadata(),
+            &dest,
+            attrs,
+            &overrides
+        ));
+
+        // Rounding by the destination filesystem isn't drift.
+        filetime::set_file_mtime(&dest, filetime::FileTime::from_unix_time(1_600_000_001, 0))
+            .unwrap();
+        assert!(!attributes_differ(
+            &source_metadata(),
+            &dest,
+            attrs,
+            &overrides
+        ));
+
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(attributes_differ(
+            &source_metadata(),
+            &dest,
+            attrs,
+            &overrides
+        ));
+        // Unless the mode isn't preserved.
+        let timestamps = PreserveAttr::from_string("timestamps").unwrap();
+        assert!(!attributes_differ(
+            &source_metadata(),
+            &dest,
+            timestamps,
+            &overrides
+        ));
+
+        filetime::set_file_mtime(&dest, filetime::FileTime::from_unix_time(1_700_000_000, 0))
+            .unwrap();
+        assert!(attributes_differ(
+            &source_metadata(),
+            &dest,
+            timestamps,
+            &overrides
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_kept_attrs_survive_replacing_the_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let destination = temp_dir.path().join("deployed.conf");
+        assert_eq!(KeptAttrs::snapshot(&destination), None);
+
+        fs::write(&destination, "old").unwrap();
+        fs::set_permissions(&destination, fs::Permissions::from_mode(0o640)).unwrap();
+        let kept = KeptAttrs::snapshot(&destination).unwrap();
+
+        fs::remove_file(&destination).unwrap();
+        fs::write(&destination, "new").unwrap();
+        fs::set_permissions(&destination, fs::Permissions::from_mode(0o755)).unwrap();
+        kept.restore(&destination).unwrap();
+
+        let mode = fs::metadata(&destination).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o640);
+    }
+
     #[test]
     fn test_preserve_attr_from_string() {
         let attr = PreserveAttr::from_string("mode,timestamps").unwrap();
@@ -325,6 +1114,16 @@ mod tests {
         assert!(attr.xattr);
     }
 
+    #[test]
+    fn test_preserve_attr_with_and_without() {
+        let attr = PreserveAttr::all()
+            .with(PreserveAttr::from_string("fsflags").unwrap())
+            .without(PreserveAttr::from_string("ownership,timestamps").unwrap());
+        assert!(attr.mode && attr.links && attr.xattr && attr.fsflags);
+        assert!(!attr.ownership);
+        assert!(!attr.timestamps && !attr.atimes);
+    }
+
     #[test]
     fn test_preserve_attr_default() {
         let attr = PreserveAttr::from_string("").unwrap();
@@ -455,7 +1254,7 @@ mod tests {
         let mut attrs = PreserveAttr::none();
         attrs.timestamps = true;
 
-        apply_preserve_attrs(&source, &dest, attrs).unwrap();
+        apply_preserve_attrs(&source, &dest, attrs, None).unwrap();
 
         let src_mtime = fs::metadata(&source).unwrap().modified().unwrap();
         let dest_mtime = fs::metadata(&dest).unwrap().modified().unwrap();
@@ -485,7 +1284,7 @@ mod tests {
         fs::set_permissions(&source, perms).unwrap();
 
         let attrs = PreserveAttr::all();
-        apply_preserve_attrs(&source, &dest, attrs).unwrap();
+        apply_preserve_attrs(&source, &dest, attrs, None).unwrap();
 
         let dest_mode = fs::metadata(&dest).unwrap().permissions().mode() & 0o777;
         assert_eq!(dest_mode, 0o600);
@@ -525,4 +1324,284 @@ mod tests {
         let dest2_inode = fs::metadata(&dest2).unwrap().ino();
         assert_eq!(dest1_inode, dest2_inode);
     }
+
+    #[test]
+    fn test_preserve_attr_atimes() {
+        let attr = PreserveAttr::from_string("timestamps").unwrap();
+        assert!(attr.timestamps);
+        assert!(attr.atimes);
+
+        let attr = PreserveAttr::from_string("atimes").unwrap();
+        assert!(attr.atimes);
+        assert!(!attr.timestamps);
+    }
+
+    #[test]
+    fn test_preserve_uses_captured_atime() {
+        use filetime::{FileTime, set_file_times};
+
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"test").unwrap();
+        fs::write(&dest, b"test").unwrap();
+
+        let mtime = FileTime::from_unix_time(1_500_000_000, 0);
+        set_file_times(&source, FileTime::from_unix_time(1_400_000_000, 0), mtime).unwrap();
+        let captured = FileTime::from_unix_time(1_300_000_000, 0);
+
+        let attrs = PreserveAttr::from_string("timestamps").unwrap();
+        apply_preserve_attrs_with_atime(
+            &source,
+            &dest,
+            attrs,
+            None,
+            Some(std::time::UNIX_EPOCH + Duration::from_secs(1_300_000_000)),
+            &AttrOverrides::default(),
+        )
+        .unwrap();
+
+        let dest_meta = fs::metadata(&dest).unwrap();
+        assert_eq!(FileTime::from_last_access_time(&dest_meta), captured);
+        assert_eq!(FileTime::from_last_modification_time(&dest_meta), mtime);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_explicit_timestamp_lands_before_read_only_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"test").unwrap();
+        fs::write(&dest, b"test").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let time = std::time::UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+        let attrs = PreserveAttr::from_string("mode").unwrap();
+        let overrides = AttrOverrides {
+            mtime: Some(time),
+            ..AttrOverrides::default()
+        };
+        apply_preserve_attrs_with_atime(&source, &dest, attrs, None, None, &overrides).unwrap();
+
+        let dest_meta = fs::metadata(&dest).unwrap();
+        assert_eq!(dest_meta.modified().unwrap(), time);
+        assert_eq!(dest_meta.permissions().mode() & 0o777, 0o444);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_mode_goes_on_after_timestamps() {
+        use filetime::FileTime;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"test").unwrap();
+        fs::write(&dest, b"test").unwrap();
+        let mtime = FileTime::from_unix_time(1_500_000_000, 0);
+        filetime::set_file_mtime(&source, mtime).unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let attrs = PreserveAttr::from_string("mode,timestamps,xattr").unwrap();
+        apply_preserve_attrs(&source, &dest, attrs, None).unwrap();
+
+        let dest_meta = fs::metadata(&dest).unwrap();
+        assert_eq!(dest_meta.permissions().mode() & 0o777, 0o444);
+        assert_eq!(FileTime::from_last_modification_time(&dest_meta), mtime);
+    }
+
+    #[test]
+    fn test_cached_metadata_is_used_instead_of_a_fresh_stat() {
+        use filetime::FileTime;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"test").unwrap();
+        fs::write(&dest, b"test").unwrap();
+        let planned = FileTime::from_unix_time(1_500_000_000, 0);
+        filetime::set_file_mtime(&source, planned).unwrap();
+        let metadata = fs::metadata(&source).unwrap();
+        filetime::set_file_mtime(&source, FileTime::from_unix_time(1_600_000_000, 0)).unwrap();
+
+        let attrs = PreserveAttr::from_string("timestamps").unwrap();
+        apply_preserve_attrs(&source, &dest, attrs, Some(&metadata)).unwrap();
+
+        let dest_meta = fs::metadata(&dest).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&dest_meta), planned);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_outcome_lists_applied_attributes() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"test").unwrap();
+        fs::write(&dest, b"test").unwrap();
+
+        let attrs = PreserveAttr::from_string("mode,timestamps").unwrap();
+        let outcome = apply_preserve_attrs(&source, &dest, attrs, None).unwrap();
+
+        assert_eq!(
+            outcome,
+            PreserveOutcome {
+                applied: vec![Attribute::Timestamps, Attribute::Atimes, Attribute::Mode],
+                ..PreserveOutcome::default()
+            }
+        );
+    }
+
+    #[cfg(any(target_os = "macos", windows))]
+    #[test]
+    fn test_preserve_birth_time() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+
+        fs::write(&source, b"test").unwrap();
+        thread::sleep(Duration::from_millis(1100));
+        fs::write(&dest, b"test").unwrap();
+
+        apply_preserve_attrs(&source, &dest, PreserveAttr::default(), None).unwrap();
+
+        let src_created = fs::metadata(&source).unwrap().created().unwrap();
+        let dest_created = fs::metadata(&dest).unwrap().created().unwrap();
+        assert_eq!(src_created, dest_created);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_birth_time_not_settable_is_not_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"test").unwrap();
+        fs::write(&dest, b"test").unwrap();
+
+        let src_metadata = fs::metadata(&source).unwrap();
+        assert!(preserve_birth_time(&dest, &src_metadata).is_ok());
+    }
+
+    #[test]
+    fn test_preserve_attr_fsflags_is_opt_in() {
+        assert!(!PreserveAttr::all().fsflags);
+        assert!(!PreserveAttr::from_string("all").unwrap().fsflags);
+
+        let attr = PreserveAttr::from_string("all,fsflags").unwrap();
+        assert!(attr.fsflags);
+        assert!(attr.mode && attr.xattr);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_preserve_fsflags_immutable_roundtrip() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"test").unwrap();
+        fs::write(&dest, b"test").unwrap();
+
+        let Ok(flags) = get_fsflags(&source) else {
+            return; // filesystem has no chattr support
+        };
+        if set_fsflags(&source, flags | FS_IMMUTABLE_FL | 0x40).is_err() {
+            return;
+        }
+
+        let result = preserve_fsflags(&source, &dest);
+        let dest_flags = get_fsflags(&dest).unwrap();
+
+        set_fsflags(&source, flags).unwrap();
+        let _ = set_fsflags(&dest, dest_flags & !FS_IMMUTABLE_FL);
+
+        result.unwrap();
+        assert_ne!(dest_flags & FS_IMMUTABLE_FL, 0);
+        assert_ne!(dest_flags & 0x40, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_error_includes_os_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("missing").join("dest.txt");
+        fs::write(&source, b"test").unwrap();
+
+        let attrs = PreserveAttr::from_string("timestamps").unwrap();
+        let err = apply_preserve_attrs(&source, &dest, attrs, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "operation 'preserve timestamps' on '{}': ENOENT (No such file or directory)",
+                dest.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_reproducible_time_is_clamped_to_epoch() {
+        let epoch = std::time::UNIX_EPOCH + Duration::from_secs(1_000);
+        let older = std::time::UNIX_EPOCH + Duration::from_secs(500);
+        let newer = std::time::UNIX_EPOCH + Duration::from_secs(2_000);
+        let overrides = AttrOverrides {
+            reproducible: true,
+            epoch: Some(epoch),
+            ..AttrOverrides::default()
+        };
+        assert_eq!(overrides.time_for(Some(older)), Some(older));
+        assert_eq!(overrides.time_for(Some(newer)), Some(epoch));
+        assert_eq!(overrides.time_for(None), Some(epoch));
+
+        let unclamped = AttrOverrides {
+            reproducible: true,
+            ..AttrOverrides::default()
+        };
+        assert_eq!(unclamped.time_for(Some(newer)), Some(newer));
+        assert_eq!(unclamped.time_for(None), None);
+
+        let fixed = AttrOverrides {
+            mtime: Some(older),
+            ..overrides
+        };
+        assert_eq!(fixed.time_for(Some(newer)), Some(older));
+        assert_eq!(AttrOverrides::default().time_for(Some(newer)), None);
+    }
+
+    #[test]
+    fn test_reproducible_pins_access_time() {
+        use filetime::FileTime;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"test").unwrap();
+        fs::write(&dest, b"test").unwrap();
+        let mtime = FileTime::from_unix_time(1_500_000_000, 0);
+        filetime::set_file_times(&source, FileTime::from_unix_time(1_600_000_000, 0), mtime)
+            .unwrap();
+
+        let overrides = AttrOverrides {
+            reproducible: true,
+            epoch: Some(std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            ..AttrOverrides::default()
+        };
+        apply_preserve_attrs_with_atime(
+            &source,
+            &dest,
+            PreserveAttr::none(),
+            None,
+            None,
+            &overrides,
+        )
+        .unwrap();
+
+        let dest_meta = fs::metadata(&dest).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&dest_meta), mtime);
+        assert_eq!(FileTime::from_last_access_time(&dest_meta), mtime);
+    }
 }
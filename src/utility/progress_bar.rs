@@ -1,5 +1,12 @@
 use clap::ValueEnum;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanCount, ProgressBar, ProgressStyle};
+
+/// Copies smaller than this (and with fewer than `DEFAULT_MIN_FILES` files)
+/// get no progress bar unless `--progress=always` is given.
+pub const DEFAULT_MIN_BYTES: u64 = 8 * 1024 * 1024;
+pub const DEFAULT_MIN_FILES: usize = 10;
+/// Progress redraws per second.
+pub const DEFAULT_REFRESH_RATE: u8 = 20;
 
 fn colorize(token: &str, color: &str) -> String {
     match color {
@@ -10,6 +17,24 @@ fn colorize(token: &str, color: &str) -> String {
     }
 }
 
+/// Message of the Detailed bar, e.g. "Copying: 1,204/40,000 files • 3
+/// skipped • 1 error". Skips and errors are left out until there are some.
+pub fn detailed_message(completed: usize, total: usize, skipped: usize, failed: usize) -> String {
+    let mut message = format!(
+        "Copying: {}/{} files",
+        HumanCount(completed as u64),
+        HumanCount(total as u64)
+    );
+    if skipped > 0 {
+        message.push_str(&format!(" • {} skipped", HumanCount(skipped as u64)));
+    }
+    if failed > 0 {
+        let noun = if failed == 1 { "error" } else { "errors" };
+        message.push_str(&format!(" • {} {}", HumanCount(failed as u64), noun));
+    }
+    message
+}
+
 #[derive(Debug, Clone)]
 pub struct ProgressOptions {
     pub style: ProgressBarStyle,
@@ -18,11 +43,43 @@ pub struct ProgressOptions {
     pub head: String,
     pub bar_color: String,
     pub message_color: String,
+    pub mode: ProgressMode,
+    pub min_bytes: u64,
+    pub min_files: usize,
+    pub refresh_rate: u8,
 }
 impl ProgressOptions {
+    /// Whether a copy of this size gets a progress bar. In `auto` mode small
+    /// copies (below both `min_bytes` and `min_files`) finish before a bar
+    /// would be useful, so none is drawn.
+    pub fn should_draw(&self, total_bytes: u64, total_files: usize) -> bool {
+        match self.mode {
+            ProgressMode::Always => true,
+            ProgressMode::Never => false,
+            ProgressMode::Auto => total_bytes >= self.min_bytes || total_files >= self.min_files,
+        }
+    }
+
     pub fn apply(&self, pb: &ProgressBar, total_files: usize) {
-        let bar = colorize("wide_bar", &self.bar_color);
-        let msg = colorize("msg", &self.message_color);
+        pb.set_style(self.style_for(false));
+        pb.set_message(match self.style {
+            ProgressBarStyle::Detailed => detailed_message(0, total_files, 0, 0),
+            _ => "Copying".to_string(),
+        });
+    }
+
+    /// The bar style, turned red once some file has failed. With color
+    /// turned off the bar stays as it is; the message still counts errors.
+    pub fn style_for(&self, failed: bool) -> ProgressStyle {
+        let color = |configured: &str| {
+            if failed && colorize("", configured) != "{}" {
+                "red".to_string()
+            } else {
+                configured.to_string()
+            }
+        };
+        let bar = colorize("wide_bar", &color(&self.bar_color));
+        let msg = colorize("msg", &color(&self.message_color));
 
         let template = match self.style {
             ProgressBarStyle::Default => {
@@ -37,20 +94,37 @@ impl ProgressOptions {
 
         let chars = format!("{}{}{}", self.filled, self.head, self.empty);
 
-        let style = ProgressStyle::default_bar()
+        ProgressStyle::default_bar()
             .template(&template)
             .unwrap()
-            .progress_chars(&chars);
-
-        pb.set_style(style);
+            .progress_chars(&chars)
+    }
 
-        pb.set_message(match self.style {
-            ProgressBarStyle::Detailed => format!("Copying: 0/{} files", total_files),
-            _ => "Copying".to_string(),
-        });
+    /// Style `pb` as a spinner with a byte counter, for copies whose size
+    /// isn't known up front.
+    pub fn apply_spinner(&self, pb: &ProgressBar) {
+        let msg = colorize("msg", &self.message_color);
+        let template = format!(
+            "{{spinner}} {} {{binary_bytes}} • {{binary_bytes_per_sec}} • Elapsed: {{elapsed_precise}}",
+            msg
+        );
+        pb.set_style(
+            ProgressStyle::default_spinner()
+                .template(&template)
+                .unwrap(),
+        );
+        pb.set_message("Copying");
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ProgressMode {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
 #[derive(Debug, Clone, Copy, Default, ValueEnum)]
 pub enum ProgressBarStyle {
     #[default]
@@ -67,6 +141,51 @@ impl Default for ProgressOptions {
             head: String::from("░"),
             bar_color: String::from("white"),
             message_color: String::from("white"),
+            mode: ProgressMode::Auto,
+            min_bytes: DEFAULT_MIN_BYTES,
+            min_files: DEFAULT_MIN_FILES,
+            refresh_rate: DEFAULT_REFRESH_RATE,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_copies_skip_the_bar_in_auto_mode() {
+        let options = ProgressOptions::default();
+        assert!(!options.should_draw(3 * 1024, 1));
+        assert!(options.should_draw(DEFAULT_MIN_BYTES, 1));
+        assert!(options.should_draw(0, DEFAULT_MIN_FILES));
+    }
+
+    #[test]
+    fn test_mode_overrides_thresholds() {
+        let always = ProgressOptions {
+            mode: ProgressMode::Always,
+            ..ProgressOptions::default()
+        };
+        assert!(always.should_draw(1, 1));
+
+        let never = ProgressOptions {
+            mode: ProgressMode::Never,
+            ..ProgressOptions::default()
+        };
+        assert!(!never.should_draw(u64::MAX, usize::MAX));
+    }
+
+    #[test]
+    fn test_detailed_message_counts_skips_and_errors() {
+        assert_eq!(detailed_message(0, 12, 0, 0), "Copying: 0/12 files");
+        assert_eq!(
+            detailed_message(1204, 40000, 3, 1),
+            "Copying: 1,204/40,000 files • 3 skipped • 1 error"
+        );
+        assert_eq!(
+            detailed_message(5, 10, 0, 2),
+            "Copying: 5/10 files • 2 errors"
+        );
+    }
+}
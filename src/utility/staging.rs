@@ -0,0 +1,111 @@
+use crate::utility::helper::{TEMP_PREFIX, temp_path_for};
+use crate::utility::output;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Numbers the staged files of this run.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+static COPIED_TWICE: Once = Once::new();
+
+/// A file being written in the staging directory, for `--staging-dir`.
+///
+/// Each file is written whole under the staging directory and only then
+/// moved to its destination, so nothing reading the destination ever sees
+/// part of a file, not even under a temporary name. A staged file that never
+/// lands is removed when it is dropped; one left by a run that was killed
+/// has the same name as the temporary files `cpx clean` finds.
+#[derive(Debug)]
+pub struct StagedFile {
+    path: PathBuf,
+}
+
+impl StagedFile {
+    /// A path under `dir` that no other file of this run or any other uses.
+    pub fn new(dir: &Path) -> Self {
+        let name = format!(
+            "{}{}-{}",
+            TEMP_PREFIX,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        Self {
+            path: dir.join(name),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the written file to `destination`, replacing what is there.
+    ///
+    /// On the same filesystem this is a rename. Across filesystems the file
+    /// is copied once more, next to the destination, and renamed over it
+    /// from there; that reads it twice, so the first such move gets a
+    /// warning.
+    pub fn land(&self, destination: &Path) -> io::Result<()> {
+        match fs::rename(&self.path, destination) {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+            result => return result,
+        }
+        COPIED_TWICE.call_once(|| {
+            output::warning(
+                "--staging-dir is on another filesystem than the destination, \
+                 so every file is written twice",
+            )
+        });
+        let temp = temp_path_for(destination);
+        fs::copy(&self.path, &temp)
+            .and_then(|_| fs::rename(&temp, destination))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&temp);
+            })?;
+        let _ = fs::remove_file(&self.path);
+        Ok(())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        // Gone already once landed.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_staged_file_lands_over_the_destination() {
+        let temp_dir = TempDir::new().unwrap();
+        let staging = temp_dir.path().join("staging");
+        fs::create_dir(&staging).unwrap();
+        let destination = temp_dir.path().join("file.txt");
+        fs::write(&destination, "old").unwrap();
+
+        let staged = StagedFile::new(&staging);
+        fs::write(staged.path(), "new").unwrap();
+        staged.land(&destination).unwrap();
+
+        assert_eq!(fs::read_to_string(&destination).unwrap(), "new");
+        assert_eq!(fs::read_dir(&staging).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_unlanded_file_is_removed() {
+        let temp_dir = TempDir::new().unwrap();
+        let first = StagedFile::new(temp_dir.path());
+        let second = StagedFile::new(temp_dir.path());
+        assert_ne!(first.path(), second.path());
+
+        fs::write(first.path(), "partial").unwrap();
+        drop(first);
+
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+}
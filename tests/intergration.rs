@@ -3,6 +3,7 @@ use assert_cmd::prelude::*;
 use assert_fs::prelude::*;
 use predicates::prelude::*;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 #[cfg(unix)]
@@ -484,6 +485,165 @@ fn test_backup_simple() {
     temp.child("dest.txt~").assert("old content");
 }
 
+#[test]
+fn test_backup_suffix() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    let dest = temp.child("dest.txt");
+    source.write_str("new").unwrap();
+    dest.write_str("old").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env_remove("SIMPLE_BACKUP_SUFFIX")
+        .args(["--backup=simple", "-S", ".orig"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    temp.child("dest.txt.orig").assert("old");
+
+    // A second run would replace that backup, so the file fails instead.
+    source.write_str("newer").unwrap();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env_remove("SIMPLE_BACKUP_SUFFIX")
+        .args(["--backup=simple", "-S", ".orig"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already exists"));
+    temp.child("dest.txt.orig").assert("old");
+    dest.assert("new");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--backup=simple", "-S", "a/b"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("can't contain '/'"));
+}
+
+#[test]
+fn test_backup_suffix_from_environment_with_configured_backup() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let config = temp.child("cpx.toml");
+    config
+        .write_str("[backup]\nmode = \"simple\"\nsuffix = \".cfg\"\n")
+        .unwrap();
+    let source = temp.child("source.txt");
+    let dest = temp.child("dest.txt");
+    source.write_str("new").unwrap();
+    dest.write_str("old").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("SIMPLE_BACKUP_SUFFIX", ".env")
+        .arg("--config")
+        .arg(config.path())
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    temp.child("dest.txt.env").assert("old");
+
+    // Without the variable the configured suffix is used; numbered
+    // backups ignore it.
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env_remove("SIMPLE_BACKUP_SUFFIX")
+        .arg("--config")
+        .arg(config.path())
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    temp.child("dest.txt.cfg").assert("new");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--backup=numbered", "-S", ".orig"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    temp.child("dest.txt.~1~").assert("new");
+
+    // -S still wins over both.
+    source.write_str("newest").unwrap();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("SIMPLE_BACKUP_SUFFIX", ".env")
+        .arg("--config")
+        .arg(config.path())
+        .args(["-S", ".cli"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    temp.child("dest.txt.cli").assert("new");
+    dest.assert("newest");
+}
+
+#[test]
+fn test_unusable_backup_suffix_only_matters_with_backups() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    let dest = temp.child("dest.txt");
+    source.write_str("new").unwrap();
+
+    // An unusable variable is ignored, as cp does, with or without -b.
+    for value in ["", "a/b"] {
+        dest.write_str("old").unwrap();
+        Command::new(cargo::cargo_bin!("cpx"))
+            .env("SIMPLE_BACKUP_SUFFIX", value)
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+            .success();
+        Command::new(cargo::cargo_bin!("cpx"))
+            .env("SIMPLE_BACKUP_SUFFIX", value)
+            .args(["--backup=simple"])
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+            .success();
+        temp.child("dest.txt~").assert("new");
+        fs::remove_file(temp.child("dest.txt~").path()).unwrap();
+
+        Command::new(cargo::cargo_bin!("cpx"))
+            .env("SIMPLE_BACKUP_SUFFIX", value)
+            .env("CPX_JOURNAL", temp.child("journal.json").path())
+            .args(["move", "--backup=simple"])
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+            .success();
+        temp.child("dest.txt~").assert("new");
+        fs::remove_file(temp.child("dest.txt~").path()).unwrap();
+        source.write_str("new").unwrap();
+    }
+
+    // A bad -S or configured suffix is only an error once backups are made.
+    let config = temp.child("cpx.toml");
+    config.write_str("[backup]\nsuffix = \"\"\n").unwrap();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env_remove("SIMPLE_BACKUP_SUFFIX")
+        .arg("--config")
+        .arg(config.path())
+        .args(["-S", "a/b"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env_remove("SIMPLE_BACKUP_SUFFIX")
+        .arg("--config")
+        .arg(config.path())
+        .arg("--backup=simple")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("the backup suffix can't be empty"));
+}
+
 #[test]
 fn test_backup_numbered() {
     let temp = assert_fs::TempDir::new().unwrap();
@@ -629,6 +789,94 @@ fn test_attributes_only() {
     dest.assert("dest content");
 }
 
+#[test]
+#[cfg(not(feature = "selinux-support"))]
+fn test_context_without_selinux_is_a_warning() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    let dest = temp.child("dest.txt");
+    source.write_str("content").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-Z")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("ignoring SELinux contexts"));
+    dest.assert("content");
+
+    // -a asks for contexts only in passing.
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-a")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("SELinux").not());
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--preserve=context", "--strict-preserve"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot set SELinux contexts"));
+}
+
+#[test]
+fn test_attributes_only_without_some_attributes() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    let dest = temp.child("dest.txt");
+    source.write_str("source content").unwrap();
+    dest.write_str("dest content").unwrap();
+    fs::set_permissions(source.path(), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::set_permissions(dest.path(), fs::Permissions::from_mode(0o644)).unwrap();
+    let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(source.path(), old).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--attributes-only", "--no-preserve=mode"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    let metadata = fs::metadata(dest.path()).unwrap();
+    assert_eq!(metadata.mtime(), 1_000_000_000);
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
+    dest.assert("dest content");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--attributes-only", "--no-preserve=all"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("nothing to copy"));
+}
+
+#[test]
+fn test_no_preserve_all_leaves_attributes_alone() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    let dest = temp.child("dest.txt");
+    source.write_str("content").unwrap();
+    let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(source.path(), old).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-a", "--no-preserve=all"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.assert("content");
+    assert_ne!(fs::metadata(dest.path()).unwrap().mtime(), 1_000_000_000);
+}
+
 #[test]
 fn test_exclude_basename() {
     let temp = assert_fs::TempDir::new().unwrap();
@@ -741,101 +989,448 @@ fn test_exclude_relative_path() {
 }
 
 #[test]
-fn test_parents_flag() {
+fn test_exclude_anchored_and_nested_paths() {
     let temp = assert_fs::TempDir::new().unwrap();
-    let source_dir = temp.child("a/b/c");
+    let source_dir = temp.child("source");
     let dest_dir = temp.child("dest");
 
-    source_dir.create_dir_all().unwrap();
-    let source_file = source_dir.child("file.txt");
-    source_file.write_str("content").unwrap();
-    dest_dir.create_dir_all().unwrap();
-
-    let original_dir = std::env::current_dir().unwrap();
-    std::env::set_current_dir(temp.path()).unwrap();
+    source_dir.child("build/a.o").write_str("top").unwrap();
+    source_dir
+        .child("src/build/b.o")
+        .write_str("nested")
+        .unwrap();
+    source_dir
+        .child("docs/internal/notes.md")
+        .write_str("secret")
+        .unwrap();
+    source_dir
+        .child("docs/index.md")
+        .write_str("public")
+        .unwrap();
 
     Command::new(cargo::cargo_bin!("cpx"))
-        .arg("--parents")
-        .arg("a/b/c/file.txt")
-        .arg("dest")
+        .arg("-r")
+        .arg("-e")
+        .arg("/build,docs/internal")
+        .arg(source_dir.path())
+        .arg(dest_dir.path())
         .assert()
         .success();
 
-    temp.child("dest/a/b/c/file.txt").assert("content");
-
-    std::env::set_current_dir(original_dir).unwrap();
+    assert!(!dest_dir.child("source/build").path().exists());
+    dest_dir.child("source/src/build/b.o").assert("nested");
+    assert!(!dest_dir.child("source/docs/internal").path().exists());
+    dest_dir.child("source/docs/index.md").assert("public");
 }
 
 #[test]
-fn test_parents_multiple_files_absolute() {
+fn test_timestamp_sets_exact_mtimes() {
     let temp = assert_fs::TempDir::new().unwrap();
+    let source_dir = temp.child("source");
     let dest_dir = temp.child("dest");
-    dest_dir.create_dir_all().unwrap();
-
-    let file1_dir = temp.child("dir1/sub1");
-    file1_dir.create_dir_all().unwrap();
-    let file1 = file1_dir.child("file1.txt");
-    file1.write_str("content1").unwrap();
-
-    let file2_dir = temp.child("dir2/sub2");
-    file2_dir.create_dir_all().unwrap();
-    let file2 = file2_dir.child("file2.txt");
-    file2.write_str("content2").unwrap();
+    source_dir.child("a.txt").write_str("a").unwrap();
+    source_dir.child("sub/b.txt").write_str("b").unwrap();
 
     Command::new(cargo::cargo_bin!("cpx"))
-        .arg("--parents")
-        .arg(file1.path())
-        .arg(file2.path())
+        .arg("-r")
+        .arg("--timestamp=2024-01-01T00:00:00Z")
+        .arg(source_dir.path())
         .arg(dest_dir.path())
         .assert()
         .success();
-    let file1_rel = file1.path().strip_prefix("/").unwrap();
-    let file2_rel = file2.path().strip_prefix("/").unwrap();
 
-    dest_dir.child(file1_rel).assert("content1");
-    dest_dir.child(file2_rel).assert("content2");
+    let expected = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_704_067_200);
+    for path in ["source", "source/a.txt", "source/sub", "source/sub/b.txt"] {
+        let modified = fs::metadata(dest_dir.child(path).path())
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(modified, expected, "{}", path);
+    }
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--timestamp=@0")
+        .arg(source_dir.child("a.txt").path())
+        .arg(temp.child("epoch.txt").path())
+        .assert()
+        .success();
+    let modified = fs::metadata(temp.child("epoch.txt").path())
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(modified, std::time::UNIX_EPOCH);
 }
 
 #[test]
-#[cfg(unix)]
-fn test_dereference_command_line() {
-    use std::os::unix::fs::symlink;
-
+fn test_timestamp_conflicts_with_preserve_timestamps() {
     let temp = assert_fs::TempDir::new().unwrap();
-    let actual_dir = temp.child("actual");
-    actual_dir.create_dir_all().unwrap();
-    actual_dir.child("file.txt").write_str("content").unwrap();
-
-    let symlink_dir = temp.child("link");
-    symlink(actual_dir.path(), symlink_dir.path()).unwrap();
-
-    let dest_dir = temp.child("dest");
-    dest_dir.create_dir_all().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
 
     Command::new(cargo::cargo_bin!("cpx"))
-        .arg("-r")
-        .arg("-H")
-        .arg(symlink_dir.path())
-        .arg(dest_dir.path())
+        .arg("--no-config")
+        .arg("--preserve=timestamps")
+        .arg("--timestamp=@0")
+        .arg(source.path())
+        .arg(temp.child("b.txt").path())
         .assert()
-        .success();
+        .failure()
+        .stderr(predicate::str::contains(
+            "--timestamp and --preserve=timestamps cannot be used together",
+        ));
+}
 
-    // The symlink is dereferenced, so contents are copied
-    dest_dir.child("link/file.txt").assert("content");
+/// Path, contents, mode, uid, gid, mtime and atime.
+#[cfg(unix)]
+type EntrySnapshot = (PathBuf, Vec<u8>, u32, u32, u32, i64, i64);
+
+/// Everything a reproducible copy promises to keep stable, per path.
+#[cfg(unix)]
+fn tree_snapshot(root: &Path) -> Vec<EntrySnapshot> {
+    let mut entries: Vec<_> = walkdir(root)
+        .into_iter()
+        .map(|path| {
+            let meta = fs::symlink_metadata(&path).unwrap();
+            let data = if meta.is_file() {
+                fs::read(&path).unwrap()
+            } else {
+                Vec::new()
+            };
+            (
+                path.strip_prefix(root).unwrap().to_path_buf(),
+                data,
+                meta.mode(),
+                meta.uid(),
+                meta.gid(),
+                meta.mtime(),
+                meta.atime(),
+            )
+        })
+        .collect();
+    entries.sort();
+    entries
 }
 
-#[test]
 #[cfg(unix)]
-fn test_dereference_always() {
-    let temp = assert_fs::TempDir::new().unwrap();
-    let actual = temp.child("actual.txt");
-    actual.write_str("content").unwrap();
+fn walkdir(root: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![root.to_path_buf()];
+    let mut i = 0;
+    while i < paths.len() {
+        if paths[i].is_dir() {
+            let children: Vec<_> = fs::read_dir(&paths[i])
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect();
+            paths.extend(children);
+        }
+        i += 1;
+    }
+    paths
+}
 
+#[cfg(unix)]
+#[test]
+fn test_reproducible_copies_are_identical() {
+    let temp = assert_fs::TempDir::new().unwrap();
     let source_dir = temp.child("source");
-    source_dir.create_dir_all().unwrap();
+    source_dir.child("old.txt").write_str("old").unwrap();
+    source_dir.child("sub/new.txt").write_str("new").unwrap();
+    source_dir
+        .child("sub/deeper/more.txt")
+        .write_str("more")
+        .unwrap();
+    let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(source_dir.child("old.txt").path(), old).unwrap();
 
-    let link = source_dir.child("link.txt");
-    symlink(actual.path(), link.path()).unwrap();
+    let epoch = 1_500_000_000;
+    for dest in ["first", "second"] {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .env("SOURCE_DATE_EPOCH", epoch.to_string())
+            .arg("-r")
+            .arg("-j4")
+            .arg("--preserve=mode,timestamps")
+            .arg(source_dir.path())
+            .arg(temp.child(dest).path())
+            .assert()
+            .success();
+        // Let the clock move between the runs.
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+    }
+
+    // Checked before anything reads the copies, which may move atimes.
+    let meta = |path: &str| fs::metadata(temp.child("first/source").child(path).path()).unwrap();
+    assert_eq!(meta("sub/new.txt").atime(), epoch);
+
+    let first = tree_snapshot(temp.child("first").child("source").path());
+    let second = tree_snapshot(temp.child("second").child("source").path());
+    assert_eq!(first, second);
+
+    assert_eq!(meta("old.txt").mtime(), 1_000_000_000);
+    assert_eq!(meta("sub/new.txt").mtime(), epoch);
+    assert_eq!(meta("sub").mtime(), epoch);
+    assert_eq!(meta("sub/new.txt").uid(), unsafe { libc::geteuid() });
+}
+
+#[test]
+fn test_reproducible_conflicts_with_preserve_ownership() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--reproducible")
+        .arg("--preserve=mode,ownership")
+        .arg(source.path())
+        .arg(temp.child("b.txt").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--reproducible and --preserve=ownership cannot be used together",
+        ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_usermap_and_groupmap_translate_owners() {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("home");
+    source.child("mapped.txt").write_str("a").unwrap();
+    source.child("squashed.txt").write_str("b").unwrap();
+    let chown = |name: &str, uid: u32, gid: u32| {
+        std::os::unix::fs::chown(source.child(name).path(), Some(uid), Some(gid)).unwrap();
+    };
+    chown("mapped.txt", 1001, 100);
+    chown("squashed.txt", 1002, 100);
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("--usermap=1001:2001,*:3000")
+        .arg("--groupmap=200:300")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    let meta = |name: &str| fs::metadata(dest.child("home").child(name).path()).unwrap();
+    assert_eq!(
+        (meta("mapped.txt").uid(), meta("mapped.txt").gid()),
+        (2001, 100)
+    );
+    assert_eq!(meta("squashed.txt").uid(), 3000);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_owner_and_group_are_given_to_every_copy() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("site");
+    source.child("css/site.css").write_str("body {}").unwrap();
+    symlink("css/site.css", source.child("style.css").path()).unwrap();
+    // Root can give files to anyone; anyone else to themselves.
+    let (uid, gid) = match unsafe { libc::geteuid() } {
+        0 => (33, 33),
+        uid => (uid, unsafe { libc::getegid() }),
+    };
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg(format!("--owner={}", uid))
+        .arg(format!("--group={}", gid))
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("warning").not());
+
+    for name in ["", "css", "css/site.css", "style.css"] {
+        let metadata = fs::symlink_metadata(dest.child("site").child(name).path()).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (uid, gid), "{}", name);
+    }
+}
+
+#[test]
+fn test_owner_refuses_unknown_and_unsettable_owners() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+    let cpx = |flag: &str| {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .arg(flag)
+            .arg(source.path())
+            .arg(temp.child("b.txt").path())
+            .assert()
+            .failure()
+    };
+
+    cpx("--owner=no-such-user-here").stderr(predicate::str::contains("unknown user"));
+    cpx("--group=no-such-group-here").stderr(predicate::str::contains("unknown group"));
+    #[cfg(unix)]
+    if unsafe { libc::geteuid() } != 0 {
+        cpx("--owner=0").stderr(predicate::str::contains("--owner=0 needs root"));
+        temp.child("b.txt").assert(predicate::path::missing());
+    }
+}
+
+#[test]
+fn test_usermap_rejects_unknown_names() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--usermap=no-such-user-here:0")
+        .arg(source.path())
+        .arg(temp.child("b.txt").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unknown user 'no-such-user-here'"));
+}
+
+#[test]
+fn test_parents_flag() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source_dir = temp.child("a/b/c");
+    let dest_dir = temp.child("dest");
+
+    source_dir.create_dir_all().unwrap();
+    let source_file = source_dir.child("file.txt");
+    source_file.write_str("content").unwrap();
+    dest_dir.create_dir_all().unwrap();
+
+    let original_dir = std::env::current_dir().unwrap();
+    std::env::set_current_dir(temp.path()).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--parents")
+        .arg("a/b/c/file.txt")
+        .arg("dest")
+        .assert()
+        .success();
+
+    temp.child("dest/a/b/c/file.txt").assert("content");
+
+    std::env::set_current_dir(original_dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_parents_preserve_recreated_directories() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    temp.child("a/b/file.txt").write_str("content").unwrap();
+    let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    for (dir, mode) in [("a", 0o750), ("a/b", 0o700)] {
+        let path = temp.child(dir);
+        fs::set_permissions(path.path(), fs::Permissions::from_mode(mode)).unwrap();
+        filetime::set_file_mtime(path.path(), old).unwrap();
+    }
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .current_dir(temp.path())
+        .args(["--parents", "--preserve=mode,timestamps", "a/b/file.txt"])
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.child("a/b/file.txt").assert("content");
+    for (dir, mode) in [("a", 0o750), ("a/b", 0o700)] {
+        let metadata = fs::metadata(dest.child(dir).path()).unwrap();
+        assert_eq!(metadata.mode() & 0o777, mode, "{}", dir);
+        assert_eq!(metadata.mtime(), 1_000_000_000, "{}", dir);
+    }
+
+    // With nothing preserved a directory already there is left as it is.
+    let other = temp.child("other");
+    other.child("a").create_dir_all().unwrap();
+    fs::set_permissions(other.child("a").path(), fs::Permissions::from_mode(0o777)).unwrap();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .current_dir(temp.path())
+        .args(["--parents", "--no-preserve=all", "a/b/file.txt"])
+        .arg(other.path())
+        .assert()
+        .success();
+
+    other.child("a/b/file.txt").assert("content");
+    let metadata = fs::metadata(other.child("a").path()).unwrap();
+    assert_eq!(metadata.mode() & 0o777, 0o777);
+}
+
+#[test]
+fn test_parents_multiple_files_absolute() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let dest_dir = temp.child("dest");
+    dest_dir.create_dir_all().unwrap();
+
+    let file1_dir = temp.child("dir1/sub1");
+    file1_dir.create_dir_all().unwrap();
+    let file1 = file1_dir.child("file1.txt");
+    file1.write_str("content1").unwrap();
+
+    let file2_dir = temp.child("dir2/sub2");
+    file2_dir.create_dir_all().unwrap();
+    let file2 = file2_dir.child("file2.txt");
+    file2.write_str("content2").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--parents")
+        .arg(file1.path())
+        .arg(file2.path())
+        .arg(dest_dir.path())
+        .assert()
+        .success();
+    let file1_rel = file1.path().strip_prefix("/").unwrap();
+    let file2_rel = file2.path().strip_prefix("/").unwrap();
+
+    dest_dir.child(file1_rel).assert("content1");
+    dest_dir.child(file2_rel).assert("content2");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_dereference_command_line() {
+    use std::os::unix::fs::symlink;
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let actual_dir = temp.child("actual");
+    actual_dir.create_dir_all().unwrap();
+    actual_dir.child("file.txt").write_str("content").unwrap();
+
+    let symlink_dir = temp.child("link");
+    symlink(actual_dir.path(), symlink_dir.path()).unwrap();
+
+    let dest_dir = temp.child("dest");
+    dest_dir.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("-H")
+        .arg(symlink_dir.path())
+        .arg(dest_dir.path())
+        .assert()
+        .success();
+
+    // The symlink is dereferenced, so contents are copied
+    dest_dir.child("link/file.txt").assert("content");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_dereference_always() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let actual = temp.child("actual.txt");
+    actual.write_str("content").unwrap();
+
+    let source_dir = temp.child("source");
+    source_dir.create_dir_all().unwrap();
+
+    let link = source_dir.child("link.txt");
+    symlink(actual.path(), link.path()).unwrap();
 
     let dest_dir = temp.child("dest");
 
@@ -1019,77 +1614,261 @@ fn test_remove_destination_flag() {
     dest.assert("new");
 }
 
+#[cfg(unix)]
 #[test]
-fn test_copy_very_long_filename() {
+fn test_remove_destination_unlinks_symlinks_but_not_directories() {
     let temp = assert_fs::TempDir::new().unwrap();
-    let long_name = "a".repeat(200) + ".txt";
-    let source = temp.child(&long_name);
-    let dest_dir = temp.child("dest");
-
-    source.write_str("content").unwrap();
-    dest_dir.create_dir_all().unwrap();
+    let source = temp.child("source.txt");
+    source.write_str("new").unwrap();
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+    let missing = temp.child("missing");
+    symlink(missing.path(), dest.child("source.txt").path()).unwrap();
 
     Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--remove-destination")
         .arg(source.path())
-        .arg(dest_dir.path())
+        .arg(dest.path())
         .assert()
         .success();
 
-    dest_dir.child(&long_name).assert("content");
+    let copy = dest.child("source.txt");
+    assert!(fs::symlink_metadata(copy.path()).unwrap().is_file());
+    copy.assert("new");
+    assert!(!missing.path().exists());
+
+    let occupied = temp.child("occupied");
+    occupied.child("source.txt").create_dir_all().unwrap();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--remove-destination")
+        .arg(source.path())
+        .arg(occupied.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is a directory"));
+    assert!(occupied.child("source.txt").path().is_dir());
 }
 
+#[cfg(unix)]
 #[test]
-fn test_config_init() {
+fn test_no_dereference_replaces_destination_symlink() {
     let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    source.write_str("new").unwrap();
+    let snapshot = temp.child("snapshot.txt");
+    snapshot.write_str("kept").unwrap();
+    fs::set_permissions(snapshot.path(), fs::Permissions::from_mode(0o444)).unwrap();
+    let dest = temp.child("dest.txt");
+    symlink(snapshot.path(), dest.path()).unwrap();
 
     Command::new(cargo::cargo_bin!("cpx"))
-        .arg("config")
-        .arg("init")
-        .env("HOME", temp.path())
-        .env("XDG_CONFIG_HOME", temp.path().join(".config"))
+        .arg("-P")
+        .arg(source.path())
+        .arg(dest.path())
         .assert()
         .success();
 
-    let config_path = temp.path().join(".config/cpx/cpxconfig.toml");
-    assert!(config_path.exists());
-
-    let contents = fs::read_to_string(&config_path).unwrap();
-    assert!(contents.contains("[exclude]"));
-    assert!(contents.contains("[copy]"));
-    assert!(contents.contains("[preserve]"));
+    assert!(fs::symlink_metadata(dest.path()).unwrap().is_file());
+    dest.assert("new");
+    snapshot.assert("kept");
 }
 
+#[cfg(unix)]
 #[test]
-fn test_config_init_force_overwrite() {
+fn test_dangling_destination_symlink_is_replaced() {
     let temp = assert_fs::TempDir::new().unwrap();
-    let config_dir = temp.path().join(".config/cpx");
-    fs::create_dir_all(&config_dir).unwrap();
-
-    let config_path = config_dir.join("cpxconfig.toml");
-    fs::write(&config_path, "old config").unwrap();
+    let source = temp.child("source.txt");
+    source.write_str("new").unwrap();
+    let is_link = |path: &Path| fs::symlink_metadata(path).unwrap().file_type().is_symlink();
+
+    // Each way of writing the data creates the file itself.
+    for flags in [
+        &["--engine=buffered"][..],
+        &["--engine=offload"],
+        &["--engine=mmap"],
+        &["--reflink=auto"],
+    ] {
+        let dest = temp.child("dest.txt");
+        symlink("missing_dir/target.txt", dest.path()).unwrap();
+        Command::new(cargo::cargo_bin!("cpx"))
+            .args(flags)
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+            .success();
+        assert!(!is_link(dest.path()), "{:?}", flags);
+        dest.assert("new");
+        temp.child("missing_dir").assert(predicate::path::missing());
+        fs::remove_file(dest.path()).unwrap();
+    }
 
+    // A backup keeps the link as it was.
+    let dest = temp.child("dest.txt");
+    symlink("target.txt", dest.path()).unwrap();
     Command::new(cargo::cargo_bin!("cpx"))
-        .arg("config")
-        .arg("init")
-        .arg("--force")
-        .env("HOME", temp.path())
-        .env("XDG_CONFIG_HOME", temp.path().join(".config"))
+        .arg("--backup=simple")
+        .arg(source.path())
+        .arg(dest.path())
         .assert()
         .success();
-
-    let contents = fs::read_to_string(&config_path).unwrap();
-    assert_ne!(contents, "old config");
+    dest.assert("new");
+    let backup = temp.child("dest.txt~");
+    assert!(is_link(backup.path()));
+    assert_eq!(
+        fs::read_link(backup.path()).unwrap(),
+        Path::new("target.txt")
+    );
+    temp.child("target.txt").assert(predicate::path::missing());
 }
 
+#[cfg(unix)]
 #[test]
-fn test_config_show() {
-    Command::new(cargo::cargo_bin!("cpx"))
-        .arg("config")
-        .arg("show")
+fn test_existing_destination_symlink_is_refused_or_replaced() {
+    // Root and other users get the same answer, though only root has its
+    // copies confined to the destination tree.
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    source.write_str("new").unwrap();
+    let target = temp.child("target.txt");
+    target.write_str("kept").unwrap();
+    let link = temp.child("link");
+    symlink(target.path(), link.path()).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg(source.path())
+        .arg(link.path())
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains(
+            "it is a symlink, which is not written through",
+        ))
+        .stderr(predicate::str::contains("no longer a plain").not());
+    target.assert("kept");
+
+    // The same inside a tree being copied into.
+    let tree = temp.child("tree");
+    tree.child("source.txt").write_str("new").unwrap();
+    let dest = temp.child("dest");
+    dest.child("tree").create_dir_all().unwrap();
+    symlink(target.path(), dest.child("tree/source.txt").path()).unwrap();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg(tree.path())
+        .arg(dest.path())
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("it is a symlink"));
+    target.assert("kept");
+
+    for flag in ["-f", "-P", "--remove-destination"] {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .arg(flag)
+            .arg(source.path())
+            .arg(link.path())
+            .assert()
+            .success();
+        assert!(
+            fs::symlink_metadata(link.path()).unwrap().is_file(),
+            "{flag}"
+        );
+        link.assert("new");
+        target.assert("kept");
+        fs::remove_file(link.path()).unwrap();
+        symlink(target.path(), link.path()).unwrap();
+    }
+}
+
+#[test]
+fn test_copy_very_long_filename() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let long_name = "a".repeat(200) + ".txt";
+    let source = temp.child(&long_name);
+    let dest_dir = temp.child("dest");
+
+    source.write_str("content").unwrap();
+    dest_dir.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg(source.path())
+        .arg(dest_dir.path())
+        .assert()
+        .success();
+
+    dest_dir.child(&long_name).assert("content");
+}
+
+#[test]
+fn test_config_init() {
+    let temp = assert_fs::TempDir::new().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("config")
+        .arg("init")
+        .env("HOME", temp.path())
+        .env("XDG_CONFIG_HOME", temp.path().join(".config"))
+        .assert()
+        .success();
+
+    let config_path = temp.path().join(".config/cpx/cpxconfig.toml");
+    assert!(config_path.exists());
+
+    let contents = fs::read_to_string(&config_path).unwrap();
+    assert!(contents.contains("[exclude]"));
+    assert!(contents.contains("[copy]"));
+    assert!(contents.contains("[preserve]"));
+}
+
+#[test]
+fn test_config_init_force_overwrite() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let config_dir = temp.path().join(".config/cpx");
+    fs::create_dir_all(&config_dir).unwrap();
+
+    let config_path = config_dir.join("cpxconfig.toml");
+    fs::write(&config_path, "old config").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("config")
+        .arg("init")
+        .arg("--force")
+        .env("HOME", temp.path())
+        .env("XDG_CONFIG_HOME", temp.path().join(".config"))
+        .assert()
+        .success();
+
+    let contents = fs::read_to_string(&config_path).unwrap();
+    assert_ne!(contents, "old config");
+}
+
+#[test]
+fn test_config_show() {
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("config")
+        .arg("show")
         .assert()
         .success();
 }
 
+#[cfg(unix)]
+#[test]
+fn test_config_show_without_config_files_says_so_on_stderr() {
+    if Path::new("/etc/cpx/cpxconfig.toml").exists() {
+        return; // a system config is always found
+    }
+    let temp = assert_fs::TempDir::new().unwrap();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .current_dir(temp.path())
+        .env("HOME", temp.path())
+        .env("XDG_CONFIG_HOME", temp.child("config").path())
+        .env("XDG_CONFIG_DIRS", temp.child("xdg").path())
+        .env_remove("CPX_CONFIG")
+        .args(["config", "show"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr(predicate::str::contains("No config files found"));
+}
+
 #[test]
 fn test_config_path() {
     Command::new(cargo::cargo_bin!("cpx"))
@@ -1099,6 +1878,236 @@ fn test_config_path() {
         .success();
 }
 
+#[test]
+fn test_config_path_lists_env_candidate_as_json() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let config_path = temp.path().join("env.toml");
+    fs::write(&config_path, "[copy]\nparallel = 3\n").unwrap();
+
+    let output = Command::new(cargo::cargo_bin!("cpx"))
+        .current_dir(temp.path())
+        .env("CPX_CONFIG", &config_path)
+        .args(["config", "path", "--json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let candidates: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let env = candidates
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|c| c["source"] == "env")
+        .unwrap();
+    assert_eq!(env["exists"], true);
+    assert_eq!(env["parsed"], true);
+    assert_eq!(env["effective"], true);
+    assert_eq!(env["keys"][0], "copy.parallel");
+}
+
+#[test]
+fn test_porcelain_summary_on_stdout() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.create_dir_all().unwrap();
+    source.child("a.txt").write_str("hello").unwrap();
+    source.child("sub/b.txt").write_str("world!").unwrap();
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("--porcelain")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "version 1\nfiles_copied 2\nbytes_copied 11\nfiles_skipped 0\nfiles_failed 0\n",
+        ))
+        .stdout(predicate::str::contains("interrupted false\n"));
+}
+
+#[test]
+fn test_status_messages_go_to_stderr() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    source.write_str("content").unwrap();
+    let dest = temp.child("dest.txt");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-l")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("Created 1 hard links"));
+}
+
+#[test]
+fn test_preserve_atime_with_noatime() {
+    use filetime::{FileTime, set_file_times};
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    source.write_str(&"x".repeat(256 * 1024)).unwrap();
+    let dest = temp.child("dest.txt");
+
+    // atime older than mtime, so a plain read would update it under relatime
+    let atime = FileTime::from_unix_time(1_000_000_000, 0);
+    let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+    set_file_times(source.path(), atime, mtime).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--preserve=timestamps")
+        .arg("--noatime")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    let dest_meta = fs::metadata(dest.path()).unwrap();
+    assert_eq!(FileTime::from_last_access_time(&dest_meta), atime);
+    assert_eq!(FileTime::from_last_modification_time(&dest_meta), mtime);
+
+    let src_meta = fs::metadata(source.path()).unwrap();
+    assert_eq!(FileTime::from_last_access_time(&src_meta), atime);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_timestamps_are_preserved() {
+    use filetime::{FileTime, set_symlink_file_times};
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.child("target.txt").write_str("target").unwrap();
+    let link = source.child("link.txt");
+    symlink("target.txt", link.path()).unwrap();
+    let atime = FileTime::from_unix_time(1_000_000_000, 0);
+    let mtime = FileTime::from_unix_time(1_100_000_000, 0);
+    set_symlink_file_times(link.path(), atime, mtime).unwrap();
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("--preserve=timestamps,ownership")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    let copied = fs::symlink_metadata(dest.path().join("src/link.txt")).unwrap();
+    assert!(copied.file_type().is_symlink());
+    assert_eq!(FileTime::from_last_modification_time(&copied), mtime);
+    assert_eq!(FileTime::from_last_access_time(&copied), atime);
+    let original = fs::symlink_metadata(link.path()).unwrap();
+    assert_eq!(
+        (copied.uid(), copied.gid()),
+        (original.uid(), original.gid())
+    );
+    // The target kept its own times.
+    let target = fs::metadata(dest.path().join("src/target.txt")).unwrap();
+    assert_ne!(FileTime::from_last_modification_time(&target), mtime);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_hard_linked_symlinks_stay_hard_linked() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.create_dir_all().unwrap();
+    let link = source.child("current");
+    symlink("releases/1.0", link.path()).unwrap();
+    // link(2) names the symlink itself on Linux.
+    fs::hard_link(link.path(), source.child("latest").path()).unwrap();
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("--preserve=links,timestamps")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    let current = fs::symlink_metadata(dest.path().join("src/current")).unwrap();
+    let latest = fs::symlink_metadata(dest.path().join("src/latest")).unwrap();
+    assert!(current.file_type().is_symlink());
+    assert_eq!(current.ino(), latest.ino());
+    assert_eq!(current.nlink(), 2);
+    assert_eq!(
+        fs::read_link(dest.path().join("src/latest")).unwrap(),
+        Path::new("releases/1.0")
+    );
+}
+
+#[test]
+fn test_debug_says_how_each_file_was_copied() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.child("a.bin").write_binary(&[1u8; 1000]).unwrap();
+    source.child("b.bin").write_binary(&[2u8; 1000]).unwrap();
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--debug", "--engine=buffered", "--reflink=never"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "debug: {}: read/write (64 KiB buffer)",
+            source.child("a.bin").path().display()
+        )))
+        .stderr(predicate::str::contains(format!(
+            "debug: {}: read/write (64 KiB buffer)",
+            source.child("b.bin").path().display()
+        )))
+        .stderr(predicate::str::contains(
+            "files copied by method: buffered 2",
+        ));
+}
+
+#[test]
+fn test_engine_forces_the_copy_method() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.bin");
+    source.write_binary(&vec![42u8; 300 * 1024]).unwrap();
+
+    for engine in [
+        "buffered",
+        #[cfg(unix)]
+        "mmap",
+    ] {
+        let dest = temp.child(format!("{}.bin", engine));
+        Command::new(cargo::cargo_bin!("cpx"))
+            .arg(format!("--engine={}", engine))
+            .arg("--debug")
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+            .success()
+            .stderr(predicate::str::contains(format!(
+                "files copied by method: {} 1",
+                engine
+            )));
+        assert_eq!(fs::read(dest.path()).unwrap(), vec![42u8; 300 * 1024]);
+    }
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--engine=offload")
+        .arg("--bwlimit=1M")
+        .arg(source.path())
+        .arg(temp.child("throttled.bin").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--engine=offload cannot be used with --also-to or --bwlimit",
+        ));
+}
+
 #[test]
 fn test_no_config_flag() {
     let temp = assert_fs::TempDir::new().unwrap();
@@ -1498,3 +2507,2732 @@ fn test_copy_empty_directory() {
     assert!(dest_dir.child("empty_source").path().exists());
     assert!(dest_dir.child("empty_source").path().is_dir());
 }
+
+#[test]
+fn test_clean_dry_run_lists_leftovers_and_keeps_them() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let partial = temp.child("dest/video.mkv.cpx-partial");
+    partial.write_str("half").unwrap();
+    temp.child("dest/video.txt").write_str("done").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["clean", "--dry-run"])
+        .arg(temp.path().join("dest"))
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("video.mkv.cpx-partial"))
+        .stdout(predicate::str::contains("video.txt").not());
+    partial.assert(predicate::path::exists());
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["clean", "--yes"])
+        .arg(temp.path().join("dest"))
+        .assert()
+        .code(1);
+    partial.assert(predicate::path::missing());
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["clean"])
+        .arg(temp.path().join("dest"))
+        .assert()
+        .code(0);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_default_reflink_overwrites_existing_destination() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    let dest = temp.child("dest.txt");
+
+    source.write_str("fresh").unwrap();
+    dest.write_str("stale and longer").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--no-config")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.assert("fresh");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_copy_zero_size_proc_file() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let dest = temp.child("status");
+
+    let output = Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--porcelain")
+        .arg("/proc/self/status")
+        .arg(dest.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let copied = std::fs::read_to_string(dest.path()).unwrap();
+    assert!(copied.starts_with("Name:"));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(&format!("bytes_copied {}\n", copied.len())));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_copy_from_dev_stdin() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let dest = temp.child("piped.txt");
+
+    assert_cmd::Command::new(cargo::cargo_bin!("cpx"))
+        .arg("/dev/stdin")
+        .arg(dest.path())
+        .write_stdin("streamed through a pipe\n")
+        .assert()
+        .success();
+
+    dest.assert("streamed through a pipe\n");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_copy_from_dev_stdin_refuses_resume() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let dest = temp.child("piped.txt");
+
+    assert_cmd::Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--resume")
+        .arg("/dev/stdin")
+        .arg(dest.path())
+        .write_stdin("data")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--resume can't be used with a pipe",
+        ));
+}
+
+#[test]
+fn test_move_renames_file() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("moved").unwrap();
+    let dest = temp.child("b.txt");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    source.assert(predicate::path::missing());
+    dest.assert("moved");
+}
+
+#[test]
+fn test_move_directory_without_recursive_flag() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("dir");
+    source.child("sub/file.txt").write_str("inside").unwrap();
+    let dest = temp.child("renamed");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    source.assert(predicate::path::missing());
+    dest.child("sub/file.txt").assert("inside");
+}
+
+#[test]
+fn test_move_multiple_sources_into_directory() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let a = temp.child("a.txt");
+    let b = temp.child("b.txt");
+    a.write_str("a").unwrap();
+    b.write_str("b").unwrap();
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg("-t")
+        .arg(dest.path())
+        .arg(a.path())
+        .arg(b.path())
+        .assert()
+        .success();
+
+    dest.child("a.txt").assert("a");
+    dest.child("b.txt").assert("b");
+    a.assert(predicate::path::missing());
+}
+
+#[test]
+fn test_move_multiple_sources_needs_directory_target() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let a = temp.child("a.txt");
+    let b = temp.child("b.txt");
+    a.write_str("a").unwrap();
+    b.write_str("b").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg(a.path())
+        .arg(b.path())
+        .arg(temp.child("missing").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a directory"));
+
+    a.assert("a");
+}
+
+#[test]
+fn test_move_overwrites_by_default() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("new.txt");
+    source.write_str("new").unwrap();
+    let dest = temp.child("old.txt");
+    dest.write_str("old").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.assert("new");
+}
+
+#[test]
+fn test_move_no_clobber_keeps_target_and_source() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("new.txt");
+    source.write_str("new").unwrap();
+    let dest = temp.child("old.txt");
+    dest.write_str("old").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg("-n")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.assert("old");
+    source.assert("new");
+}
+
+#[test]
+fn test_move_interactive_declined() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("new.txt");
+    source.write_str("new").unwrap();
+    let dest = temp.child("old.txt");
+    dest.write_str("old").unwrap();
+
+    assert_cmd::Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg("-i")
+        .arg(source.path())
+        .arg(dest.path())
+        .write_stdin("n\n")
+        .assert()
+        .success();
+
+    dest.assert("old");
+    source.assert("new");
+}
+
+#[test]
+fn test_move_directory_into_itself_fails() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("dir");
+    source.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg(source.path())
+        .arg(source.child("inner").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("into itself"));
+
+    source.assert(predicate::path::is_dir());
+}
+
+#[test]
+fn test_move_file_over_directory_fails() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+    let dest = temp.child("dir");
+    dest.child("a.txt").create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "cannot overwrite directory with non-directory",
+        ));
+
+    source.assert("a");
+}
+
+#[test]
+fn test_move_same_file_fails() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg(source.path())
+        .arg(temp.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("same file"));
+
+    source.assert("a");
+}
+
+#[test]
+fn test_undo_moves_files_back_unless_modified() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let journal = temp.child("journal.json");
+    let inbox = temp.child("inbox");
+    inbox.child("notes.txt").write_str("notes").unwrap();
+    inbox.child("todo.txt").write_str("todo").unwrap();
+    let archive = temp.child("archive");
+    archive.create_dir_all().unwrap();
+    let cpx = |args: &[&std::ffi::OsStr]| {
+        let mut cmd = Command::new(cargo::cargo_bin!("cpx"));
+        cmd.env("CPX_JOURNAL", journal.path()).args(args);
+        cmd
+    };
+
+    cpx(&[
+        "move".as_ref(),
+        inbox.child("notes.txt").path().as_ref(),
+        inbox.child("todo.txt").path().as_ref(),
+        archive.path().as_ref(),
+    ])
+    .assert()
+    .success();
+    archive.child("todo.txt").write_str("todo, edited").unwrap();
+
+    cpx(&["undo".as_ref(), "-y".as_ref()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("modified since the move"));
+    inbox.child("notes.txt").assert(predicate::path::missing());
+
+    cpx(&["undo".as_ref(), "-y".as_ref(), "--force".as_ref()])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("back to"));
+    inbox.child("notes.txt").assert("notes");
+    inbox.child("todo.txt").assert("todo, edited");
+    archive
+        .child("notes.txt")
+        .assert(predicate::path::missing());
+
+    cpx(&["undo".as_ref()])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Nothing to undo"));
+}
+
+#[test]
+fn test_undo_after_copy_has_nothing_to_undo() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let journal = temp.child("journal.json");
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", journal.path())
+        .arg(source.path())
+        .arg(temp.child("b.txt").path())
+        .assert()
+        .success();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", journal.path())
+        .args(["undo", "-y"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Nothing to undo"));
+    source.assert("a");
+
+    // Nor does it stand in the way of undoing the move before it.
+    let moved = temp.child("moved.txt");
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", journal.path())
+        .arg("move")
+        .arg(source.path())
+        .arg(moved.path())
+        .assert()
+        .success();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", journal.path())
+        .arg(moved.path())
+        .arg(temp.child("c.txt").path())
+        .assert()
+        .success();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", journal.path())
+        .args(["undo", "-y"])
+        .assert()
+        .success();
+    source.assert("a");
+    moved.assert(predicate::path::missing());
+    temp.child("c.txt").assert("a");
+}
+
+#[test]
+fn test_undo_takes_a_move_and_a_copy_in_turn() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let journal = temp.child("journal.json");
+    let cpx = |args: &[&std::ffi::OsStr]| {
+        let mut cmd = Command::new(cargo::cargo_bin!("cpx"));
+        cmd.env("CPX_JOURNAL", journal.path()).args(args);
+        cmd
+    };
+    let undo = || cpx(&["undo".as_ref(), "-y".as_ref()]).assert().success();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+    let moved = temp.child("moved.txt");
+    let tree = temp.child("tree");
+    tree.child("b.txt").write_str("b").unwrap();
+    let copy = temp.child("copy");
+
+    // A move, then a copy: undo removes the copy, then puts the move back.
+    cpx(&[
+        "move".as_ref(),
+        source.path().as_ref(),
+        moved.path().as_ref(),
+    ])
+    .assert()
+    .success();
+    cpx(&["-r".as_ref(), tree.path().as_ref(), copy.path().as_ref()])
+        .assert()
+        .success();
+    undo();
+    copy.assert(predicate::path::missing());
+    moved.assert("a");
+    undo();
+    source.assert("a");
+    moved.assert(predicate::path::missing());
+    undo().stderr(predicate::str::contains("Nothing to undo"));
+
+    // A copy, then a move: undo puts the move back and leaves the copy.
+    cpx(&["-r".as_ref(), tree.path().as_ref(), copy.path().as_ref()])
+        .assert()
+        .success();
+    cpx(&[
+        "move".as_ref(),
+        source.path().as_ref(),
+        moved.path().as_ref(),
+    ])
+    .assert()
+    .success();
+    undo();
+    source.assert("a");
+    copy.child("tree/b.txt").assert("b");
+    undo().stderr(predicate::str::contains("Nothing to undo"));
+    copy.child("tree/b.txt").assert("b");
+}
+
+#[test]
+fn test_interactive_dirs_skips_declined_directory() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.child("a/1.txt").write_str("one").unwrap();
+    source.child("a/2.txt").write_str("two").unwrap();
+    source.child("b/3.txt").write_str("three").unwrap();
+    source.child("top.txt").write_str("top").unwrap();
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+
+    // Groups come up in name order: src/a, src/b, then src itself.
+    assert_cmd::Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("--interactive=dirs")
+        .arg("--order=name")
+        .arg(source.path())
+        .arg(dest.path())
+        .write_stdin("y\nn\ny\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("(2 files, 6 B)? [y/N/a/q]"))
+        .stderr(predicate::str::contains(
+            "Skipped 1 files in declined directories",
+        ));
+
+    dest.child("src/a/1.txt").assert("one");
+    dest.child("src/a/2.txt").assert("two");
+    dest.child("src/b/3.txt").assert(predicate::path::missing());
+    dest.child("src/top.txt").assert("top");
+}
+
+#[test]
+fn test_interactive_flag_does_not_take_source_as_value() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+    let dest = temp.child("b.txt");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-i")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.assert("a");
+}
+
+#[test]
+fn test_interactive_asks_only_about_existing_files_before_copying() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    for name in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+        source.child(name).write_str("new").unwrap();
+    }
+    let dest = temp.child("dest");
+    dest.child("src/a.txt").write_str("old").unwrap();
+    dest.child("src/c.txt").write_str("old").unwrap();
+
+    assert_cmd::Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "-i", "-j", "4", "--order=name"])
+        .arg(source.path())
+        .arg(dest.path())
+        .write_stdin("y\nn\n")
+        .assert()
+        .success()
+        .stderr(
+            predicate::str::contains("a.txt'? (y/n): overwrite")
+                .and(predicate::str::contains("c.txt'? (y/n): "))
+                .and(predicate::str::contains("b.txt'?").not()),
+        );
+
+    dest.child("src/a.txt").assert("new");
+    dest.child("src/b.txt").assert("new");
+    dest.child("src/c.txt").assert("old");
+    dest.child("src/d.txt").assert("new");
+}
+
+#[test]
+fn test_interactive_once_asks_a_single_question() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        source.child(name).write_str("new").unwrap();
+    }
+    let dest = temp.child("dest");
+    dest.child("src/a.txt").write_str("old").unwrap();
+    dest.child("src/c.txt").write_str("old").unwrap();
+    let cpx = |answer: &str| {
+        assert_cmd::Command::new(cargo::cargo_bin!("cpx"))
+            .args(["-r", "--interactive=once"])
+            .arg(source.path())
+            .arg(dest.path())
+            .write_stdin(answer)
+            .assert()
+            .success()
+            .stderr(
+                predicate::str::contains("has 2 files that would be overwritten, continue?")
+                    .and(predicate::str::contains("overwrite '").not()),
+            )
+    };
+
+    cpx("n\n").stderr(predicate::str::contains("Nothing copied"));
+    dest.child("src/a.txt").assert("old");
+    dest.child("src/b.txt").assert(predicate::path::missing());
+
+    cpx("y\n");
+    dest.child("src/a.txt").assert("new");
+    dest.child("src/b.txt").assert("new");
+    dest.child("src/c.txt").assert("new");
+}
+
+#[test]
+fn test_interactive_never_overrides_the_config() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let config = temp.child("cpx.toml");
+    config.write_str("[copy]\ninteractive = true\n").unwrap();
+    let source = temp.child("source.txt");
+    source.write_str("new").unwrap();
+    let dest = temp.child("dest.txt");
+    dest.write_str("old").unwrap();
+
+    assert_cmd::Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--config")
+        .arg(config.path())
+        .arg("--interactive=never")
+        .arg(source.path())
+        .arg(dest.path())
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("overwrite").not());
+    dest.assert("new");
+}
+
+#[test]
+fn test_basename_collisions_across_sources() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    temp.child("a/report.pdf").write_str("a").unwrap();
+    temp.child("b/report.pdf").write_str("b").unwrap();
+    let archive = temp.child("archive");
+    archive.create_dir_all().unwrap();
+    let cpx = |policy: Option<&str>| {
+        let mut cmd = Command::new(cargo::cargo_bin!("cpx"));
+        if let Some(policy) = policy {
+            cmd.arg(format!("--on-collision={}", policy));
+        }
+        cmd.arg(temp.child("a/report.pdf").path())
+            .arg(temp.child("b/report.pdf").path())
+            .arg(archive.path());
+        cmd.assert()
+    };
+
+    cpx(None)
+        .failure()
+        .stderr(predicate::str::contains("a/report.pdf"))
+        .stderr(predicate::str::contains("b/report.pdf"));
+    assert!(!archive.child("report.pdf").exists());
+
+    cpx(Some("number")).success();
+    archive.child("report.pdf").assert("a");
+    archive.child("report-1.pdf").assert("b");
+
+    fs::remove_file(archive.child("report-1.pdf").path()).unwrap();
+    cpx(Some("last-wins")).success();
+    archive.child("report.pdf").assert("b");
+    assert!(!archive.child("report-1.pdf").exists());
+}
+
+#[test]
+fn test_dry_run_itemizes_without_copying() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.child("new.txt").write_str("new").unwrap();
+    source
+        .child("changed.txt")
+        .write_str("longer content")
+        .unwrap();
+    source.child("skip.log").write_str("log").unwrap();
+    source.child("sub/deep.txt").write_str("deep").unwrap();
+    let dest = temp.child("dest");
+    dest.child("src/changed.txt").write_str("short").unwrap();
+    let newer = filetime::FileTime::from_unix_time(2_100_000_000, 0);
+    filetime::set_file_mtime(dest.child("src/changed.txt").path(), newer).unwrap();
+    // Leave the existing directory unchanged whatever is preserved.
+    filetime::set_file_mtime(dest.child("src").path(), newer).unwrap();
+    filetime::set_file_mtime(source.path(), newer).unwrap();
+
+    let output = Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("--dry-run")
+        .arg("-e")
+        .arg("*.log")
+        .arg(source.path())
+        .arg(dest.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let root = dest.path().join("src");
+    let expected = [
+        format!(">f.st.... {}", root.join("changed.txt").display()),
+        format!(">f+++++++ {}", root.join("new.txt").display()),
+        format!("*excluded {}", root.join("skip.log").display()),
+        format!("cd+++++++ {}/", root.join("sub").display()),
+        format!(">f+++++++ {}", root.join("sub/deep.txt").display()),
+    ];
+    assert_eq!(
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .collect::<Vec<_>>(),
+        expected
+    );
+    assert!(!dest.child("src/new.txt").exists());
+    dest.child("src/changed.txt").assert("short");
+}
+
+#[test]
+fn test_itemize_changes_reports_performed_copies() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("data").unwrap();
+    let dest = temp.child("b.txt");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--itemize-changes")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(format!(">f+++++++ {}\n", dest.path().display()));
+    dest.assert("data");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_compare_write_leaves_identical_files_untouched() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let mut changed = data.clone();
+    changed[299_999] ^= 0xff;
+    let source = temp.child("src");
+    source.child("same.bin").write_binary(&data).unwrap();
+    source.child("changed.bin").write_binary(&changed).unwrap();
+    source.child("resized.bin").write_str("new length").unwrap();
+    let dest = temp.child("dest");
+    dest.child("src/same.bin").write_binary(&data).unwrap();
+    dest.child("src/changed.bin").write_binary(&data).unwrap();
+    dest.child("src/resized.bin").write_str("old").unwrap();
+    let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(dest.child("src/same.bin").path(), old).unwrap();
+    let before = fs::metadata(dest.child("src/same.bin").path()).unwrap();
+
+    let output = Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("--compare-write")
+        // Keep times as they are, so a write would show in the mtime.
+        .arg("--preserve=mode")
+        .arg("--porcelain")
+        .arg(source.path())
+        .arg(dest.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Nothing was written to the identical file.
+    let after = fs::metadata(dest.child("src/same.bin").path()).unwrap();
+    assert_eq!(after.mtime(), before.mtime());
+    assert_eq!(after.mtime_nsec(), before.mtime_nsec());
+    assert_eq!(
+        fs::read(dest.child("src/changed.bin").path()).unwrap(),
+        changed
+    );
+    dest.child("src/resized.bin").assert("new length");
+
+    // Only the last 256 KiB chunk of changed.bin differed.
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let identical = 300_000 + 256 * 1024;
+    let written = 300_000 - 256 * 1024 + "new length".len();
+    assert!(stdout.contains(&format!("\nbytes_copied {}\n", written)));
+    assert!(stdout.contains(&format!("\nbytes_identical {}\n", identical)));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_force_replaces_a_destination_that_cannot_be_opened() {
+    // Root can open the file anyway.
+    if unsafe { libc::geteuid() } == 0 {
+        return;
+    }
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("new").unwrap();
+    let dest = temp.child("b.txt");
+    dest.write_str("old").unwrap();
+    fs::set_permissions(dest.path(), fs::Permissions::from_mode(0o000)).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("EACCES (Permission denied)"));
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-f")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.assert("new");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unreadable_subdirectory_does_not_stop_the_copy() {
+    // Root can read the directory anyway.
+    if unsafe { libc::geteuid() } == 0 {
+        return;
+    }
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("logs");
+    source.child("a.log").write_str("a").unwrap();
+    source.child("private/secret.log").write_str("s").unwrap();
+    source.child("zz/b.log").write_str("b").unwrap();
+    let private = source.child("private");
+    fs::set_permissions(private.path(), fs::Permissions::from_mode(0o000)).unwrap();
+    let dest = temp.child("backup");
+
+    let output = Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg(source.path())
+        .arg(dest.path())
+        .output()
+        .unwrap();
+    fs::set_permissions(private.path(), fs::Permissions::from_mode(0o755)).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(&format!("cannot read '{}'", private.path().display())));
+    dest.child("logs/a.log").assert("a");
+    dest.child("logs/zz/b.log").assert("b");
+    assert!(!dest.child("logs/private/secret.log").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_loop_is_reported_after_copying_the_rest() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.child("a.txt").write_str("a").unwrap();
+    source.child("sub/b.txt").write_str("b").unwrap();
+    symlink("self", source.child("sub/self").path()).unwrap();
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("-L")
+        .arg("--porcelain")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("\nentries_unreadable 1\n"))
+        .stderr(predicate::str::contains(format!(
+            "cannot read '{}'",
+            source.child("sub/self").path().display()
+        )));
+    dest.child("src/a.txt").assert("a");
+    dest.child("src/sub/b.txt").assert("b");
+}
+
+#[test]
+fn test_files_from_recreates_listed_paths() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let root = temp.child("data");
+    root.child("photos/2021/a.jpg").write_str("a").unwrap();
+    root.child("photos/2021/skipped.jpg")
+        .write_str("s")
+        .unwrap();
+    root.child("photos/2022/b.jpg").write_str("b").unwrap();
+    root.child("docs/notes/n.txt").write_str("n").unwrap();
+    let list = temp.child("list.txt");
+    list.write_str("# wanted\nphotos/2021/a.jpg\nphotos/2022/b.jpg\ndocs\n")
+        .unwrap();
+    let dest = temp.child("backup");
+    dest.create_dir_all().unwrap();
+
+    // Paths are relative to the current directory by default.
+    Command::new(cargo::cargo_bin!("cpx"))
+        .current_dir(root.path())
+        .arg("-r")
+        .arg("--files-from")
+        .arg(list.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.child("photos/2021/a.jpg").assert("a");
+    dest.child("photos/2022/b.jpg").assert("b");
+    dest.child("docs/notes/n.txt").assert("n");
+    assert!(!dest.child("photos/2021/skipped.jpg").exists());
+    assert!(!dest.child("a.jpg").exists());
+}
+
+#[test]
+fn test_files_from_root_rejects_paths_outside_it() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let root = temp.child("data");
+    root.child("a.txt").write_str("a").unwrap();
+    let list = temp.child("list.txt");
+    list.write_str("../list.txt\n").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--files-from")
+        .arg(list.path())
+        .arg("--files-from-root")
+        .arg(root.path())
+        .arg(temp.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("'..' is not allowed"));
+}
+
+#[test]
+fn test_also_to_writes_every_target_in_one_pass() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let src = temp.child("release");
+    src.child("app.bin").write_str("binary").unwrap();
+    src.child("docs/readme.txt").write_str("readme").unwrap();
+    let dest = temp.child("archive");
+    let share = temp.child("share");
+    let backup = temp.child("backup");
+    for dir in [&dest, &share, &backup] {
+        dir.create_dir_all().unwrap();
+    }
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg(src.path())
+        .arg(dest.path())
+        .arg("--also-to")
+        .arg(share.path())
+        .arg("--also-to")
+        .arg(backup.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "Also copied to {}: 2 files",
+            share.path().display()
+        )))
+        .stderr(predicate::str::contains(format!(
+            "Also copied to {}: 2 files",
+            backup.path().display()
+        )));
+
+    for dir in [&dest, &share, &backup] {
+        dir.child("release/app.bin").assert("binary");
+        dir.child("release/docs/readme.txt").assert("readme");
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_stored_checksums_catch_changed_files() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let src = temp.child("photos");
+    src.child("a.jpg").write_str("aaaa").unwrap();
+    src.child("b.jpg").write_str("bbbb").unwrap();
+    let dest = temp.child("archive");
+    dest.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("--store-checksum")
+        .arg(src.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("verify")
+        .arg("--stored")
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Verified 2 files: 0 mismatched"));
+
+    // Flip a byte in place, as bit rot would, keeping the attributes.
+    let mut rotten = fs::OpenOptions::new()
+        .write(true)
+        .open(dest.child("photos/b.jpg").path())
+        .unwrap();
+    std::io::Write::write_all(&mut rotten, b"x").unwrap();
+    drop(rotten);
+    dest.child("photos/c.jpg").write_str("new").unwrap();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("verify")
+        .arg("--stored")
+        .arg(dest.path())
+        .assert()
+        .code(1)
+        .stderr(
+            predicate::str::contains("checksum mismatch").and(predicate::str::contains("b.jpg")),
+        )
+        .stderr(
+            predicate::str::contains("no stored checksum").and(predicate::str::contains("c.jpg")),
+        );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_full_destination_ends_with_a_single_summary_line() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let src = temp.child("big.bin");
+    src.write_binary(&vec![7u8; 256 * 1024]).unwrap();
+
+    // Every write to /dev/full fails with ENOSPC.
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg(src.path())
+        .arg("/dev/full")
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains(
+            "Destination full: stopped with 1 file(s) not copied",
+        ))
+        .stderr(predicate::str::contains("Failed to copy").not());
+    assert!(Path::new("/dev/full").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fifo_in_directory_is_recreated_or_read_with_copy_contents() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let src = temp.child("spool");
+    src.child("note.txt").write_str("note").unwrap();
+    let fifo = src.child("pipe");
+    Command::new("mkfifo").arg(fifo.path()).assert().success();
+
+    let nodes = temp.child("nodes");
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg(src.path())
+        .arg(nodes.path())
+        .assert()
+        .success();
+    let copied = fs::symlink_metadata(nodes.child("spool/pipe").path()).unwrap();
+    assert!(copied.file_type().is_fifo());
+
+    let contents = temp.child("contents");
+    let writer = std::thread::spawn({
+        let fifo = fifo.path().to_path_buf();
+        move || {
+            let mut pipe = fs::OpenOptions::new().write(true).open(fifo).unwrap();
+            std::io::Write::write_all(&mut pipe, b"streamed").unwrap();
+        }
+    });
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg("--copy-contents")
+        .arg(src.path())
+        .arg(contents.path())
+        .assert()
+        .success();
+    writer.join().unwrap();
+
+    contents.child("spool/pipe").assert("streamed");
+    contents.child("spool/note.txt").assert("note");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_copy_contents_reads_devices_under_a_progress_bar() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let src = temp.child("src");
+    src.child("a.txt").write_str("a").unwrap();
+    symlink("/dev/zero", src.child("zero").path()).unwrap();
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args([
+            "-rL",
+            "--copy-contents",
+            "--max-bytes=64K",
+            "--progress=always",
+        ])
+        .arg(src.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    let zero = fs::read(dest.child("src/zero").path()).unwrap();
+    assert_eq!(zero.len(), 64 * 1024);
+    assert!(zero.iter().all(|&b| b == 0));
+    dest.child("src/a.txt").assert("a");
+}
+
+#[test]
+fn test_bwlimit_throttles_the_copy() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("data.bin");
+    source.write_binary(&vec![9u8; 1024 * 1024]).unwrap();
+    let dest = temp.child("copy.bin");
+
+    let started = std::time::Instant::now();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--bwlimit", "4M"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    // 1 MiB at 4 MiB/s takes a quarter of a second.
+    assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+    assert_eq!(fs::read(dest.path()).unwrap(), vec![9u8; 1024 * 1024]);
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--bwlimit", "0"])
+        .arg(source.path())
+        .arg(temp.child("other.bin").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--bwlimit must be more than 0"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_debug_traces_offload_chunk_sizes() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("data.bin");
+    source.write_binary(&vec![3u8; 4 * 1024 * 1024]).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--debug", "--reflink=never", "--progress=never"])
+        .arg(source.path())
+        .arg(temp.child("adaptive.bin").path())
+        .assert()
+        .success()
+        .stderr(
+            predicate::str::contains("debug: copy_file_range")
+                .and(predicate::str::contains("64.00 KiB chunks to start")),
+        );
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--debug", "--reflink=never", "--progress=never"])
+        .args(["--offload-chunk-size", "1M"])
+        .arg(source.path())
+        .arg(temp.child("fixed.bin").path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("1.00 MiB chunks to start"));
+    temp.child("fixed.bin")
+        .assert(predicate::path::eq_file(source.path()));
+}
+
+#[test]
+fn test_file_budget_stops_the_run_and_resume_continues() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("share");
+    for name in ["a", "b", "c", "d", "e"] {
+        source.child(name).write_str("contents").unwrap();
+    }
+    let dest = temp.child("dest");
+    let run = || {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .args(["-r", "--resume", "--max-files=2"])
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+    };
+    let copied = || fs::read_dir(dest.child("share").path()).unwrap().count();
+
+    run()
+        .code(3)
+        .stderr(predicate::str::contains("Budget exhausted, 3 files"));
+    assert_eq!(copied(), 2);
+    run()
+        .code(3)
+        .stderr(predicate::str::contains("Budget exhausted, 1 files"));
+    assert_eq!(copied(), 4);
+    run().success();
+    assert_eq!(copied(), 5);
+}
+
+#[test]
+fn test_debug_reports_the_slowest_files() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("data.bin");
+    source.write_binary(&vec![5u8; 64 * 1024]).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--debug", "--engine=buffered", "--progress=never"])
+        .arg(source.path())
+        .arg(temp.child("copy.bin").path())
+        .assert()
+        .success()
+        .stderr(
+            predicate::str::contains("slowest files:")
+                .and(predicate::str::contains("64.00 KiB  buffered")),
+        );
+}
+
+#[test]
+fn test_max_memory_caps_workers_and_reports_limits() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("a").unwrap();
+    source.child("b.txt").write_str("b").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "-j8", "--max-memory=256K", "--debug"])
+        .arg(source.path())
+        .arg(temp.child("dest").path())
+        .assert()
+        .success()
+        .stderr(
+            predicate::str::contains("using -j 2")
+                .and(predicate::str::contains("buffers of up to 64.00 KiB"))
+                .and(predicate::str::contains("the copy plan takes about")),
+        );
+    temp.child("dest/source/b.txt").assert("b");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fix_attrs_refreshes_drifted_files_skipped_by_resume() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("drifted.txt").write_str("a").unwrap();
+    source.child("intact.txt").write_str("b").unwrap();
+    for name in ["drifted.txt", "intact.txt"] {
+        fs::set_permissions(source.child(name).path(), fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let dest = temp.child("dest");
+    let copy = |extra: &[&str]| {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .args(["-r", "--preserve=mode,timestamps"])
+            .args(extra)
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+            .success()
+    };
+    copy(&[]);
+    let drifted = dest.child("source/drifted.txt");
+    fs::set_permissions(drifted.path(), fs::Permissions::from_mode(0o666)).unwrap();
+
+    copy(&["--resume", "--fix-attrs", "--porcelain"])
+        .stdout(predicate::str::contains("files_copied 0"))
+        .stdout(predicate::str::contains("attributes_refreshed 1"))
+        .stderr(predicate::str::contains("1 files: attributes refreshed"));
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(drifted.path()), 0o600);
+
+    copy(&["--resume", "--fix-attrs"]).stderr(predicate::str::contains("refreshed").not());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_chmod_sets_modes_after_preserving() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("sub/data.txt").write_str("data").unwrap();
+    source.child("run.sh").write_str("#!/bin/sh").unwrap();
+    symlink("run.sh", source.child("link").path()).unwrap();
+    for path in [source.path(), source.child("sub").path()] {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o777)).unwrap();
+    }
+    fs::set_permissions(
+        source.child("sub/data.txt").path(),
+        fs::Permissions::from_mode(0o666),
+    )
+    .unwrap();
+    fs::set_permissions(
+        source.child("run.sh").path(),
+        fs::Permissions::from_mode(0o777),
+    )
+    .unwrap();
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+    let copy = |flags: &[&str], dest: &Path| {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .args(["-r", "--preserve=mode"])
+            .args(flags)
+            .arg(source.path())
+            .arg(dest)
+            .assert()
+    };
+
+    let symbolic = temp.child("symbolic");
+    copy(&["--chmod=u+rwX,go=rX"], symbolic.path()).success();
+    assert_eq!(mode(symbolic.child("source").path()), 0o755);
+    assert_eq!(mode(symbolic.child("source/sub").path()), 0o755);
+    assert_eq!(mode(symbolic.child("source/sub/data.txt").path()), 0o644);
+    assert_eq!(mode(symbolic.child("source/run.sh").path()), 0o755);
+    assert!(symbolic.child("source/link").path().is_symlink());
+
+    let octal = temp.child("octal");
+    copy(&["--chmod=640", "--chmod-dir=750"], octal.path()).success();
+    assert_eq!(mode(octal.child("source/sub").path()), 0o750);
+    assert_eq!(mode(octal.child("source/sub/data.txt").path()), 0o640);
+    assert_eq!(mode(octal.child("source/run.sh").path()), 0o640);
+
+    copy(&["--chmod=g=u"], temp.child("refused").path())
+        .failure()
+        .stderr(predicate::str::contains(
+            "copying permissions between classes is not supported",
+        ));
+}
+
+#[test]
+fn test_flatten_gathers_nested_files_into_the_destination() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("2023/report.pdf").write_str("old").unwrap();
+    source.child("2024/q1/report.pdf").write_str("new").unwrap();
+    source
+        .child("2024/q1/notes.txt")
+        .write_str("notes")
+        .unwrap();
+    let dest = temp.child("dest");
+    let copy = |extra: &[&str]| {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .args(["-r", "--flatten"])
+            .args(extra)
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+    };
+
+    copy(&[])
+        .failure()
+        .stderr(predicate::str::contains("--on-collision"));
+    dest.assert(predicate::path::missing());
+
+    copy(&["--on-collision=number"]).success();
+    let mut names: Vec<_> = fs::read_dir(dest.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["notes.txt", "report-1.pdf", "report.pdf"]);
+    let mut contents = vec![
+        fs::read_to_string(dest.child("report.pdf").path()).unwrap(),
+        fs::read_to_string(dest.child("report-1.pdf").path()).unwrap(),
+    ];
+    contents.sort();
+    assert_eq!(contents, ["new", "old"]);
+}
+
+#[test]
+fn test_excluded_subtrees_leave_no_empty_directories() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source
+        .child("src/main.rs")
+        .write_str("fn main() {}")
+        .unwrap();
+    source.child("build/out/app.o").write_str("obj").unwrap();
+    source.child("build/lib.o").write_str("obj").unwrap();
+    source.child("assets").create_dir_all().unwrap();
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "-e", "*.o"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.child("source/src/main.rs")
+        .assert(predicate::path::is_file());
+    // Empty in the source, so still copied.
+    dest.child("source/assets")
+        .assert(predicate::path::is_dir());
+    dest.child("source/build")
+        .assert(predicate::path::missing());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_notify_cmd_hears_how_the_copy_ended() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("hello").unwrap();
+    source.child("b.txt").write_str("world").unwrap();
+    let dest = temp.child("dest");
+    let heard = temp.child("heard");
+    let hook = format!("env | grep ^CPX_ | sort > '{}'", heard.path().display());
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--notify-on=error", "--notify-cmd", &hook])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    heard.assert(predicate::path::missing());
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--notify-cmd", &hook])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    let heard = fs::read_to_string(heard.path()).unwrap();
+    assert!(heard.contains("CPX_STATUS=success\n"), "{}", heard);
+    assert!(heard.contains("CPX_FILES_COPIED=2\n"), "{}", heard);
+    assert!(heard.contains("CPX_BYTES=10\n"), "{}", heard);
+    assert!(heard.contains("CPX_ERRORS=0\n"), "{}", heard);
+    assert!(
+        heard.contains(&format!("CPX_DEST={}\n", dest.path().display())),
+        "{}",
+        heard
+    );
+}
+
+/// Server-side copy needs an NFS 4.2 mount; point CPX_TEST_NFS_DIR at a
+/// writable directory on one to run this.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_copy_within_nfs_share_is_offloaded() {
+    let Some(share) = std::env::var_os("CPX_TEST_NFS_DIR") else {
+        return;
+    };
+    let temp = assert_fs::TempDir::new_in(share).unwrap();
+    let source = temp.child("source.bin");
+    source.write_binary(&vec![7u8; 8 * 1024 * 1024]).unwrap();
+    let dest = temp.child("dest.bin");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--engine=offload", "--debug"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "files copied by method: offload 1",
+        ));
+    assert_eq!(
+        fs::read(dest.path()).unwrap(),
+        fs::read(source.path()).unwrap()
+    );
+}
+
+/// Point CPX_TEST_SMB_DIR at a writable directory on an SMB share whose
+/// server offers ODX to run this.
+#[cfg(windows)]
+#[test]
+fn test_copy_within_smb_share_uses_odx() {
+    let Some(share) = std::env::var_os("CPX_TEST_SMB_DIR") else {
+        return;
+    };
+    let temp = assert_fs::TempDir::new_in(share).unwrap();
+    let source = temp.child("source.bin");
+    let contents: Vec<u8> = (0..5 * 1024 * 1024 + 123).map(|i| i as u8).collect();
+    source.write_binary(&contents).unwrap();
+    let dest = temp.child("dest.bin");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("--debug")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("files copied by method: odx 1"));
+    assert_eq!(fs::read(dest.path()).unwrap(), contents);
+}
+
+#[test]
+fn test_staging_dir_lands_files_and_leaves_nothing_behind() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("alpha").unwrap();
+    source.child("sub/b.txt").write_str("beta").unwrap();
+    let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(source.child("a.txt").path(), old).unwrap();
+    let staging = temp.child("staging");
+    staging.create_dir_all().unwrap();
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--preserve=timestamps", "--staging-dir"])
+        .arg(staging.path())
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.child("source/a.txt").assert("alpha");
+    dest.child("source/sub/b.txt").assert("beta");
+    // Preserved once in place, not on the staged file.
+    let copied = fs::metadata(dest.child("source/a.txt").path()).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&copied),
+        old
+    );
+    assert_eq!(fs::read_dir(staging.path()).unwrap().count(), 0);
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--staging-dir"])
+        .arg(temp.child("missing").path())
+        .arg(source.child("a.txt").path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a directory"));
+}
+
+#[test]
+fn test_status_reports_differences_and_exits_by_them() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("alpha").unwrap();
+    source.child("sub/b.txt").write_str("beta").unwrap();
+    let backup = temp.child("backup");
+    let dest = backup.child("source");
+
+    let status = |extra: &[&str]| {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .arg("status")
+            .args(extra)
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+    };
+
+    status(&[])
+        .code(1)
+        .stdout(predicate::str::contains("new 4\n"));
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--preserve=timestamps"])
+        .arg(source.path())
+        .arg(backup.path())
+        .assert()
+        .success();
+    status(&[])
+        .success()
+        .stdout(predicate::str::contains("identical 2\n"));
+
+    dest.child("stray.log").write_str("left over").unwrap();
+    let output = status(&["--json", "-i", "-e", "*.tmp"]).code(1);
+    let report: serde_json::Value = serde_json::from_slice(&output.get_output().stdout).unwrap();
+    assert_eq!(report["extra"], 1);
+    assert_eq!(report["identical"], 2);
+    assert_eq!(
+        report["changes"][0],
+        format!("*deleting {}", dest.child("stray.log").path().display())
+    );
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("status")
+        .arg(temp.child("missing").path())
+        .arg(dest.path())
+        .assert()
+        .code(2);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_signal_storm_does_not_interrupt_a_copy() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    for i in 0..8 {
+        source
+            .child(format!("file{}.bin", i))
+            .write_binary(&vec![i as u8; 16 * 1024 * 1024])
+            .unwrap();
+    }
+    let dest = temp.child("dest");
+
+    let child = Command::new(cargo::cargo_bin!("cpx"))
+        .args([
+            "-r",
+            "--engine=buffered",
+            "--buffer-size=4K",
+            "--progress=never",
+        ])
+        // A limit makes cpx handle SIGUSR2 rather than die of it.
+        .arg("--bwlimit=100G")
+        .arg(source.path())
+        .arg(dest.path())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id() as libc::pid_t;
+    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let storm = std::thread::spawn({
+        let done = done.clone();
+        let started = dest.path().to_path_buf();
+        move || {
+            // The handler is in place once files start to appear.
+            while !started.exists() {
+                std::thread::sleep(std::time::Duration::from_micros(100));
+            }
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                unsafe {
+                    libc::kill(pid, libc::SIGUSR2);
+                    libc::kill(pid, libc::SIGCHLD);
+                }
+                std::thread::sleep(std::time::Duration::from_micros(100));
+            }
+        }
+    });
+    let output = child.wait_with_output().unwrap();
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    storm.join().unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(!stderr.contains("nterrupted"), "{}", stderr);
+    for i in 0..8 {
+        let copied = fs::read(dest.child(format!("source/file{}.bin", i)).path()).unwrap();
+        assert_eq!(copied, vec![i as u8; 16 * 1024 * 1024]);
+    }
+}
+
+#[test]
+fn test_exit_status_tells_how_the_copy_ended() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.child("a.txt").write_str("a").unwrap();
+    source.child("b.txt").write_str("b").unwrap();
+    let dest = temp.child("dest");
+    let cpx = |args: &[&str]| {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .current_dir(temp.path())
+            .args(args)
+            .assert()
+    };
+
+    cpx(&["-r", "src", "dest"]).code(0);
+
+    // A directory in the way of one file fails just that one.
+    fs::remove_file(dest.child("src/b.txt").path()).unwrap();
+    dest.child("src/b.txt").create_dir_all().unwrap();
+    cpx(&["-r", "src", "dest"]).code(1);
+
+    // Nothing is copied when the arguments are wrong.
+    cpx(&["--no-such-flag", "src", "dest"]).code(2);
+    cpx(&["missing", "dest"]).code(2);
+    cpx(&["src", "elsewhere"]).code(2);
+    temp.child("elsewhere").assert(predicate::path::missing());
+}
+
+#[test]
+fn test_move_exit_status_tells_how_the_move_ended() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    temp.child("a.txt").write_str("a").unwrap();
+    temp.child("b.txt").write_str("b").unwrap();
+    let cpx = |args: &[&str]| {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .current_dir(temp.path())
+            .env("CPX_JOURNAL", temp.child("journal.json").path())
+            .arg("move")
+            .args(args)
+            .assert()
+    };
+
+    // Nothing is moved when the arguments are wrong.
+    cpx(&["--no-such-flag", "a.txt", "c.txt"]).code(2);
+    cpx(&["a.txt", "b.txt", "not-a-directory"]).code(2);
+    cpx(&["-b", "-S", "", "a.txt", "b.txt"]).code(2);
+    temp.child("a.txt").assert("a");
+    temp.child("b.txt").assert("b");
+
+    cpx(&["missing.txt", "a.txt", "dir"]).code(2);
+    temp.child("dir").create_dir_all().unwrap();
+    cpx(&["missing.txt", "a.txt", "dir"]).code(1);
+    temp.child("dir/a.txt").assert("a");
+    cpx(&["b.txt", "c.txt"]).code(0);
+    temp.child("c.txt").assert("b");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_interrupted_copy_exits_with_130() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("big.bin");
+    source.write_binary(&vec![1u8; 8 * 1024 * 1024]).unwrap();
+    let dest = temp.child("copy.bin");
+
+    let child = Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--engine=buffered", "--bwlimit=1M", "--progress=never"])
+        .arg(source.path())
+        .arg(dest.path())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The handler is in place once the copy has started.
+    while !dest.path().exists() {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Operation interrupted"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_prefer_hardlink_links_files_on_the_same_filesystem() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("a").unwrap();
+    source.child("sub/b.txt").write_str("b").unwrap();
+    let dest = temp.child("dest");
+    // One already there is replaced by a link too.
+    dest.child("source/a.txt").write_str("old").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--prefer-hardlink", "--porcelain"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files_copied 0\n"))
+        .stdout(predicate::str::contains("hardlinks_created 2\n"));
+
+    for name in ["a.txt", "sub/b.txt"] {
+        let original = fs::metadata(source.child(name).path()).unwrap();
+        let linked = fs::metadata(dest.child("source").child(name).path()).unwrap();
+        assert_eq!(original.ino(), linked.ino());
+    }
+    assert_eq!(link_count(&source.child("a.txt")), 2);
+}
+
+#[cfg(unix)]
+fn link_count(path: &Path) -> u64 {
+    fs::metadata(path).unwrap().nlink()
+}
+
+#[cfg(unix)]
+#[test]
+fn test_prefer_hardlink_itemizes_links() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+    let dest = temp.child("b.txt");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--prefer-hardlink", "--itemize-changes"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("hf+++++++ "))
+        .stdout(predicate::str::contains(format!(
+            " => {}",
+            source.path().display()
+        )));
+}
+
+/// Needs /dev/shm on another filesystem than the temporary directory.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_prefer_hardlink_copies_across_filesystems() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let Ok(other) = tempfile::TempDir::new_in("/dev/shm") else {
+        return;
+    };
+    if fs::metadata(temp.path()).unwrap().dev() == fs::metadata(other.path()).unwrap().dev() {
+        return;
+    }
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+    let dest = other.path().join("a.txt");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--prefer-hardlink", "--porcelain"])
+        .arg(source.path())
+        .arg(&dest)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files_copied 1\n"))
+        .stdout(predicate::str::contains("hardlinks_created 0\n"));
+    assert_eq!(fs::read_to_string(&dest).unwrap(), "a");
+    assert_eq!(link_count(source.path()), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_prefer_hardlink_copies_when_the_link_would_change_the_source() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+    let mtime = fs::metadata(source.path()).unwrap().modified().unwrap();
+    let dest = temp.child("b.txt");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--prefer-hardlink", "--timestamp=@0", "--porcelain"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files_copied 1\n"));
+
+    assert_eq!(link_count(source.path()), 1);
+    assert_eq!(
+        fs::metadata(source.path()).unwrap().modified().unwrap(),
+        mtime
+    );
+    assert_eq!(
+        fs::metadata(dest.path()).unwrap().modified().unwrap(),
+        std::time::UNIX_EPOCH
+    );
+}
+
+/// A pipe whose reader is already gone.
+#[cfg(unix)]
+fn closed_pipe() -> std::io::PipeWriter {
+    let (reader, writer) = std::io::pipe().unwrap();
+    drop(reader);
+    writer
+}
+
+#[cfg(unix)]
+#[test]
+fn test_itemized_copy_finishes_when_stdout_is_closed() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    for i in 0..20 {
+        source
+            .child(format!("file{}.txt", i))
+            .write_str("data")
+            .unwrap();
+    }
+    let dest = temp.child("dest");
+
+    let output = Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--itemize-changes", "--porcelain"])
+        .arg(source.path())
+        .arg(dest.path())
+        .stdout(closed_pipe())
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert_eq!(
+        fs::read_dir(dest.child("source").path()).unwrap().count(),
+        20
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_printing_commands_end_quietly_when_stdout_is_closed() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("a").unwrap();
+    let dest = temp.child("dest");
+
+    let runs: [Vec<std::ffi::OsString>; 2] = [
+        vec![
+            "--dry-run".into(),
+            "-r".into(),
+            source.path().into(),
+            dest.path().into(),
+        ],
+        vec!["status".into(), source.path().into(), dest.path().into()],
+    ];
+    for args in runs {
+        let output = Command::new(cargo::cargo_bin!("cpx"))
+            .args(&args)
+            .stdout(closed_pipe())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let status = output.status;
+        assert!(
+            status.signal() == Some(libc::SIGPIPE) || status.code() == Some(141),
+            "{:?}: {} {}",
+            args,
+            status,
+            stderr
+        );
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+    }
+    assert!(!dest.path().exists());
+}
+
+#[test]
+fn test_error_names_the_source_that_failed() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let first = temp.child("first.txt");
+    first.write_str("first").unwrap();
+    let missing = temp.child("missing.txt");
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg(first.path())
+        .arg(missing.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("missing.txt"))
+        .stderr(predicate::str::contains("first.txt").not());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_keep_dest_attrs_keeps_the_mode_of_overwritten_files() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("app.conf");
+    source.write_str("new contents").unwrap();
+    fs::set_permissions(source.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    let staging = temp.child("staging");
+    staging.create_dir_all().unwrap();
+
+    // Overwritten in place, replaced by a new file, and moved into place.
+    let runs: [&[&str]; 4] = [
+        &["--engine=buffered"],
+        &["--engine=offload"],
+        &["--remove-destination"],
+        &["--staging-dir", staging.path().to_str().unwrap()],
+    ];
+    for (i, flags) in runs.into_iter().enumerate() {
+        let dest = temp.child(format!("deployed{}.conf", i));
+        dest.write_str("old").unwrap();
+        fs::set_permissions(dest.path(), fs::Permissions::from_mode(0o640)).unwrap();
+
+        Command::new(cargo::cargo_bin!("cpx"))
+            .arg("--keep-dest-attrs")
+            .args(flags)
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+            .success();
+
+        assert_eq!(fs::read_to_string(dest.path()).unwrap(), "new contents");
+        let mode = fs::metadata(dest.path()).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode, 0o640, "{:?}", flags);
+    }
+
+    // A new file still gets the source's.
+    let fresh = temp.child("fresh.conf");
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--keep-dest-attrs", "--remove-destination"])
+        .arg(source.path())
+        .arg(fresh.path())
+        .assert()
+        .success();
+    assert_ne!(
+        fs::metadata(fresh.path()).unwrap().permissions().mode() & 0o7777,
+        0o640
+    );
+}
+
+#[test]
+fn test_keep_dest_attrs_conflicts_with_preserving_mode() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("a").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--keep-dest-attrs", "--preserve=mode"])
+        .arg(source.path())
+        .arg(temp.child("b.txt").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--keep-dest-attrs"));
+}
+
+#[test]
+fn test_no_clobber_leaves_existing_files_alone() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("new.txt").write_str("new").unwrap();
+    source.child("old.txt").write_str("source").unwrap();
+    let dest = temp.child("dest");
+    dest.child("source/old.txt").write_str("dest").unwrap();
+
+    // -n wins over -f and --remove-destination, whatever the order.
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "-n", "-f", "--remove-destination", "--porcelain"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files_copied 1"))
+        .stdout(predicate::str::contains("files_skipped 1"))
+        .stdout(predicate::str::contains("files_untouched 1"))
+        .stderr(predicate::str::contains("1 existing files left untouched"));
+    dest.child("source/old.txt").assert("dest");
+    dest.child("source/new.txt").assert("new");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_no_clobber_leaves_existing_links_alone() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("a").unwrap();
+    source.child("b.txt").write_str("b").unwrap();
+    let dest = temp.child("dest");
+    dest.child("a.txt").write_str("dest").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-n", "-s=absolute"])
+        .arg(source.child("a.txt").path())
+        .arg(source.child("b.txt").path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("1 existing files left untouched"));
+    assert!(!dest.child("a.txt").path().is_symlink());
+    dest.child("a.txt").assert("dest");
+    assert!(dest.child("b.txt").path().is_symlink());
+}
+
+#[test]
+fn test_no_clobber_rejects_backup() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    temp.child("a.txt").write_str("a").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-n", "--backup=numbered"])
+        .arg(temp.child("a.txt").path())
+        .arg(temp.child("b.txt").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--no-clobber and --backup"));
+}
+
+#[test]
+fn test_update_copies_only_newer_files() {
+    use filetime::{FileTime, set_file_mtime};
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("changed.txt").write_str("changed").unwrap();
+    source.child("same.txt").write_str("source").unwrap();
+    let dest = temp.child("dest");
+    dest.child("source/changed.txt").write_str("stale").unwrap();
+    dest.child("source/same.txt").write_str("dest").unwrap();
+    let at = |secs| FileTime::from_unix_time(secs, 0);
+    set_file_mtime(source.child("changed.txt").path(), at(2_000)).unwrap();
+    set_file_mtime(dest.child("source/changed.txt").path(), at(1_000)).unwrap();
+    set_file_mtime(source.child("same.txt").path(), at(1_000)).unwrap();
+    set_file_mtime(dest.child("source/same.txt").path(), at(1_000)).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "-u", "--porcelain"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files_copied 1"))
+        .stdout(predicate::str::contains("files_skipped 1"));
+    dest.child("source/changed.txt").assert("changed");
+    dest.child("source/same.txt").assert("dest");
+}
+
+#[test]
+fn test_existing_only_replaces_files_already_there() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("old.txt").write_str("source").unwrap();
+    source.child("new.txt").write_str("new").unwrap();
+    source.child("sub/deep.txt").write_str("deep").unwrap();
+    let dest = temp.child("dest");
+    dest.child("source/old.txt").write_str("dest").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--existing", "--porcelain"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files_copied 1"))
+        .stdout(predicate::str::contains("files_new_ignored 2"))
+        .stderr(predicate::str::contains("2 new files ignored (--existing)"));
+    dest.child("source/old.txt").assert("source");
+    dest.child("source/new.txt")
+        .assert(predicate::path::missing());
+    dest.child("source/sub").assert(predicate::path::missing());
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--existing", "-n"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("copies nothing"));
+}
+
+#[test]
+fn test_move_existing_keeps_sources_without_a_target() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let (old, new) = (temp.child("old.txt"), temp.child("new.txt"));
+    old.write_str("old").unwrap();
+    new.write_str("new").unwrap();
+    let dest = temp.child("dest");
+    dest.child("old.txt").write_str("replaced").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .args(["move", "--existing"])
+        .arg(old.path())
+        .arg(new.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    old.assert(predicate::path::missing());
+    dest.child("old.txt").assert("old");
+    new.assert("new");
+    dest.child("new.txt").assert(predicate::path::missing());
+}
+
+#[test]
+fn test_move_backs_up_the_target_it_replaces() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let journal = temp.child("journal");
+    let target = temp.child("notes.txt");
+    let cpx_move = |flags: &[&str], content: &str| {
+        let source = temp.child("source.txt");
+        source.write_str(content).unwrap();
+        Command::new(cargo::cargo_bin!("cpx"))
+            .env("CPX_JOURNAL", journal.path())
+            .arg("move")
+            .args(flags)
+            .arg(source.path())
+            .arg(target.path())
+            .assert()
+            .success();
+        source.assert(predicate::path::missing());
+        target.assert(content);
+    };
+    target.write_str("first").unwrap();
+
+    cpx_move(&["--backup=simple"], "second");
+    temp.child("notes.txt~").assert("first");
+    cpx_move(&["--backup=simple", "-S", ".orig"], "third");
+    temp.child("notes.txt.orig").assert("second");
+    cpx_move(&["--backup=numbered"], "fourth");
+    temp.child("notes.txt.~1~").assert("third");
+    // Numbered backups exist now, so -b continues them.
+    cpx_move(&["-b"], "fifth");
+    temp.child("notes.txt.~2~").assert("fourth");
+    cpx_move(&["--backup=none"], "sixth");
+    temp.child("notes.txt.~3~")
+        .assert(predicate::path::missing());
+
+    // Undo puts the backup back along with the moved file.
+    cpx_move(&["--backup=numbered"], "seventh");
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", journal.path())
+        .args(["undo", "-y"])
+        .assert()
+        .success();
+    target.assert("sixth");
+    temp.child("source.txt").assert("seventh");
+}
+
+#[test]
+fn test_move_backs_up_a_target_named_without_a_directory() {
+    // Each mode, with the target named relative to the current directory.
+    for (mode, backup) in [
+        ("simple", "notes.txt~"),
+        ("numbered", "notes.txt.~1~"),
+        ("existing", "notes.txt~"),
+    ] {
+        let temp = assert_fs::TempDir::new().unwrap();
+        temp.child("source.txt").write_str("new").unwrap();
+        temp.child("notes.txt").write_str("old").unwrap();
+        Command::new(cargo::cargo_bin!("cpx"))
+            .current_dir(temp.path())
+            .env("CPX_JOURNAL", temp.child("journal.json").path())
+            .args([
+                "move",
+                &format!("--backup={mode}"),
+                "source.txt",
+                "notes.txt",
+            ])
+            .assert()
+            .success();
+        temp.child("notes.txt").assert("new");
+        temp.child(backup).assert("old");
+        temp.child("source.txt").assert(predicate::path::missing());
+    }
+
+    // And the same for a copy.
+    for (mode, backup) in [("numbered", "notes.txt.~1~"), ("existing", "notes.txt~")] {
+        let temp = assert_fs::TempDir::new().unwrap();
+        temp.child("source.txt").write_str("new").unwrap();
+        temp.child("notes.txt").write_str("old").unwrap();
+        Command::new(cargo::cargo_bin!("cpx"))
+            .current_dir(temp.path())
+            .env("CPX_JOURNAL", temp.child("journal.json").path())
+            .args([&format!("--backup={mode}"), "source.txt", "notes.txt"])
+            .assert()
+            .success();
+        temp.child("notes.txt").assert("new");
+        temp.child(backup).assert("old");
+    }
+}
+
+#[test]
+fn test_move_update_keeps_source_of_up_to_date_files() {
+    use filetime::{FileTime, set_file_mtime};
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let (old, new) = (temp.child("old.txt"), temp.child("new.txt"));
+    old.write_str("old").unwrap();
+    new.write_str("new").unwrap();
+    let dest = temp.child("dest");
+    dest.child("old.txt").write_str("kept").unwrap();
+    dest.child("new.txt").write_str("replaced").unwrap();
+    let at = |secs| FileTime::from_unix_time(secs, 0);
+    set_file_mtime(old.path(), at(1_000)).unwrap();
+    set_file_mtime(dest.child("old.txt").path(), at(2_000)).unwrap();
+    set_file_mtime(new.path(), at(2_000)).unwrap();
+    set_file_mtime(dest.child("new.txt").path(), at(1_000)).unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .args(["move", "-u"])
+        .arg(old.path())
+        .arg(new.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    old.assert("old");
+    dest.child("old.txt").assert("kept");
+    new.assert(predicate::path::missing());
+    dest.child("new.txt").assert("new");
+}
+
+#[test]
+fn test_update_none_keeps_existing_files_without_backups() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("new.txt").write_str("new").unwrap();
+    source.child("old.txt").write_str("source").unwrap();
+    let dest = temp.child("dest");
+    dest.child("source/old.txt").write_str("dest").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--update=none", "--backup=numbered", "--porcelain"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files_copied 1"))
+        .stdout(predicate::str::contains("files_skipped 1"));
+    dest.child("source/old.txt").assert("dest");
+    dest.child("source/new.txt").assert("new");
+    dest.child("source/old.txt.~1~")
+        .assert(predicate::path::missing());
+
+    // all is the default: every existing file is replaced.
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "--update=all"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("source/old.txt").assert("source");
+}
+
+#[test]
+fn test_target_directory_must_be_a_directory() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source.txt");
+    source.write_str("content").unwrap();
+    let file = temp.child("file.txt");
+    file.write_str("not a directory").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-t")
+        .arg(file.path())
+        .arg(source.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid destination path"));
+    file.assert("not a directory");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_target_directory_takes_one_source_and_links() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("a").unwrap();
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "-t"])
+        .arg(dest.path())
+        .arg(source.path())
+        .assert()
+        .success();
+    dest.child("source/a.txt").assert("a");
+
+    let links = temp.child("links");
+    links.create_dir_all().unwrap();
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-l", "-t"])
+        .arg(links.path())
+        .arg(source.child("a.txt").path())
+        .assert()
+        .success();
+    let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+    assert_eq!(
+        inode(links.child("a.txt").path()),
+        inode(source.child("a.txt").path())
+    );
+}
+
+#[test]
+fn test_trailing_dot_copies_the_contents() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("a").unwrap();
+    source.child("sub/b.txt").write_str("b").unwrap();
+    let existing = temp.child("existing");
+    existing.child("kept.txt").write_str("kept").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg(source.path().join("."))
+        .arg(existing.path())
+        .assert()
+        .success();
+    existing.child("a.txt").assert("a");
+    existing.child("sub/b.txt").assert("b");
+    existing.child("kept.txt").assert("kept");
+    existing.child("source").assert(predicate::path::missing());
+
+    let missing = temp.child("missing");
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-r")
+        .arg(format!("{}/./", source.path().display()))
+        .arg(missing.path())
+        .assert()
+        .success();
+    missing.child("a.txt").assert("a");
+    missing.child("sub/b.txt").assert("b");
+    missing.child("source").assert(predicate::path::missing());
+}
+
+#[test]
+fn test_move_trailing_dot_leaves_the_source_directory() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("a").unwrap();
+    source.child("sub/b.txt").write_str("b").unwrap();
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .arg("move")
+        .arg(source.path().join("."))
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.child("a.txt").assert("a");
+    dest.child("sub/b.txt").assert("b");
+    dest.child("source").assert(predicate::path::missing());
+    source.assert(predicate::path::is_dir());
+    assert_eq!(fs::read_dir(source.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_no_target_directory_copies_onto_the_destination() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("source");
+    source.child("a.txt").write_str("a").unwrap();
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "-T"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("a.txt").assert("a");
+    dest.child("source").assert(predicate::path::missing());
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-T")
+        .arg(source.child("a.txt").path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot overwrite directory"));
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-r", "-T"])
+        .arg(source.path())
+        .arg(source.child("a.txt").path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("-T takes exactly one SOURCE"));
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-T", "-t"])
+        .arg(dest.path())
+        .arg(source.child("a.txt").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_verbose_prints_each_directory_file_and_link() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.child("a.txt").write_str("data").unwrap();
+    symlink("a.txt", source.child("link").path()).unwrap();
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+    let copy = dest.path().join("src");
+
+    let output = Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-rv"])
+        .arg(source.path())
+        .arg(dest.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let line = |from: &Path, to: &Path| format!("'{}' -> '{}'", from.display(), to.display());
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", stdout);
+    assert!(lines.contains(&line(source.path(), &copy).as_str()));
+    for name in ["a.txt", "link"] {
+        let expected = line(&source.path().join(name), &copy.join(name));
+        assert!(lines.contains(&expected.as_str()), "{}", stdout);
+    }
+}
+
+#[test]
+fn test_verbose_prints_backups() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("new").unwrap();
+    let dest = temp.child("b.txt");
+    dest.write_str("old").unwrap();
+    let backup = temp.child("b.txt.~1~");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-v", "--backup=numbered"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(format!(
+            "'{}' ~> '{}'\n'{}' -> '{}'\n",
+            dest.path().display(),
+            backup.path().display(),
+            source.path().display(),
+            dest.path().display()
+        ));
+    backup.assert("old");
+}
+
+#[test]
+fn test_move_verbose_prints_each_move() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("a.txt");
+    source.write_str("data").unwrap();
+    let dest = temp.child("b.txt");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .env("CPX_JOURNAL", temp.child("journal.json").path())
+        .args(["move", "-v"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(format!(
+            "'{}' -> '{}'\n",
+            source.path().display(),
+            dest.path().display()
+        ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_archive_copies_links_as_links() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.child("a.txt").write_str("data").unwrap();
+    fs::hard_link(source.child("a.txt").path(), source.child("b.txt").path()).unwrap();
+    symlink("a.txt", source.child("link").path()).unwrap();
+    fs::set_permissions(
+        source.child("a.txt").path(),
+        fs::Permissions::from_mode(0o640),
+    )
+    .unwrap();
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-a", "--no-preserve=ownership"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    let copy = dest.child("src");
+    let link = fs::symlink_metadata(copy.child("link").path()).unwrap();
+    assert!(link.file_type().is_symlink());
+    assert_eq!(
+        fs::read_link(copy.child("link").path()).unwrap(),
+        PathBuf::from("a.txt")
+    );
+    let a = fs::metadata(copy.child("a.txt").path()).unwrap();
+    let b = fs::metadata(copy.child("b.txt").path()).unwrap();
+    assert_eq!(a.ino(), b.ino());
+    assert_eq!(a.mode() & 0o777, 0o640);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_d_keeps_symlinks_and_hard_links() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("src");
+    source.child("sub/a.txt").write_str("data").unwrap();
+    fs::hard_link(
+        source.child("sub/a.txt").path(),
+        source.child("b.txt").path(),
+    )
+    .unwrap();
+    symlink("sub", source.child("dir-link").path()).unwrap();
+    symlink("b.txt", source.child("file-link").path()).unwrap();
+    let dest = temp.child("dest");
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["-rd"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    let copy = dest.child("src");
+    for (link, target) in [("dir-link", "sub"), ("file-link", "b.txt")] {
+        let metadata = fs::symlink_metadata(copy.child(link).path()).unwrap();
+        assert!(metadata.file_type().is_symlink());
+        assert_eq!(
+            fs::read_link(copy.child(link).path()).unwrap(),
+            PathBuf::from(target)
+        );
+    }
+    let a = fs::metadata(copy.child("sub/a.txt").path()).unwrap();
+    let b = fs::metadata(copy.child("b.txt").path()).unwrap();
+    assert_eq!(a.ino(), b.ino());
+    assert_eq!(a.nlink(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sparse_auto_keeps_holes_of_a_truncated_file() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let source = temp.child("disk.img");
+    let logical = 64 * 1024 * 1024;
+    {
+        let file = fs::File::create(source.path()).unwrap();
+        file.set_len(logical).unwrap();
+    }
+    if fs::metadata(source.path()).unwrap().blocks() * 512 >= logical {
+        return; // filesystem does not keep holes
+    }
+
+    for (engine, name) in [("auto", "auto.img"), ("buffered", "buffered.img")] {
+        let dest = temp.child(name);
+        Command::new(cargo::cargo_bin!("cpx"))
+            .arg(format!("--engine={}", engine))
+            .args(["--sparse=auto", "--reflink=never"])
+            .arg(source.path())
+            .arg(dest.path())
+            .assert()
+            .success();
+
+        let copy = fs::metadata(dest.path()).unwrap();
+        assert_eq!(copy.len(), logical);
+        assert!(copy.blocks() * 512 < logical / 16, "{} engine", engine);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_offload_refusing_sparse_always_leaves_destination_alone() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    temp.child("new.txt").write_str("new").unwrap();
+    let dest = temp.child("old.txt");
+    dest.write_str("old").unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .args(["--engine=offload", "--sparse=always"])
+        .arg(temp.child("new.txt").path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--sparse=always needs the data read to find zeros",
+        ));
+    dest.assert("old");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_trailing_slash_names_the_directory() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    temp.child("dir/a.txt").write_str("a").unwrap();
+    symlink("dir", temp.child("link").path()).unwrap();
+    let (plain, slashed) = (temp.child("plain"), temp.child("slashed"));
+    plain.create_dir_all().unwrap();
+    slashed.create_dir_all().unwrap();
+
+    let cpx = |args: &[&str], dest: &Path| {
+        Command::new(cargo::cargo_bin!("cpx"))
+            .current_dir(temp.path())
+            .args(args)
+            .arg(dest)
+            .assert()
+    };
+    cpx(&["-r", "dir"], plain.path()).success();
+    // With a slash a link leads to its directory, even under -P, and is
+    // not taken for the directory named after it.
+    cpx(&["-rP", "dir/", "link/"], slashed.path()).success();
+
+    plain.child("dir/a.txt").assert("a");
+    slashed.child("dir/a.txt").assert("a");
+    let link = fs::symlink_metadata(slashed.child("link").path()).unwrap();
+    assert!(link.is_dir());
+    slashed.child("link/a.txt").assert("a");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_dangling_symlink_with_trailing_slash_is_explained() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    symlink("missing", temp.child("dangling").path()).unwrap();
+    let dest = temp.child("dest");
+    dest.create_dir_all().unwrap();
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .current_dir(temp.path())
+        .args(["-r", "dangling/"])
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'dangling/': a dangling symbolic link",
+        ));
+
+    Command::new(cargo::cargo_bin!("cpx"))
+        .current_dir(temp.path())
+        .args(["-r", "--strip-trailing-slashes", "dangling/"])
+        .arg(dest.path())
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_link(dest.child("dangling").path()).unwrap(),
+        PathBuf::from("missing")
+    );
+}
+
+#[test]
+fn test_mkdir_creates_the_destination_directory() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    temp.child("a.txt").write_str("a").unwrap();
+    temp.child("b.txt").write_str("b").unwrap();
+    let cpx = |flags: &[&str], dest: &Path| {
+        assert_cmd::Command::new(cargo::cargo_bin!("cpx"))
+            .args(flags)
+            .arg(temp.child("a.txt").path())
+            .arg(temp.child("b.txt").path())
+            .arg(dest)
+            .assert()
+    };
+
+    let dest = temp.child("backups/2024-06-01");
+    cpx(&["--mkdir", "--dry-run"], dest.path())
+        .success()
+        .stdout(predicate::str::contains("cd+++++++ ").and(predicate::str::contains("b.txt")));
+    temp.child("backups").assert(predicate::path::missing());
+
+    cpx(&["--mkdir", "--chmod-dir=700"], dest.path()).success();
+    dest.child("a.txt").assert("a");
+    dest.child("b.txt").assert("b");
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode(dest.path()), 0o700);
+    assert_eq!(mode(temp.child("backups").path()), 0o700);
+
+    // -i asks instead, and without a yes the copy fails as before.
+    let asked = temp.child("asked");
+    cpx(&["-i"], asked.path())
+        .failure()
+        .stderr(predicate::str::contains("create directory"));
+    asked.assert(predicate::path::missing());
+    assert_cmd::Command::new(cargo::cargo_bin!("cpx"))
+        .arg("-i")
+        .arg(temp.child("a.txt").path())
+        .arg(temp.child("b.txt").path())
+        .arg(asked.path())
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    asked.child("a.txt").assert("a");
+}
+
+#[test]
+fn test_dereference_modes_follow_only_the_links_they_should() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    temp.child("real/file.txt").write_str("data").unwrap();
+    temp.child("other/inner.txt").write_str("inner").unwrap();
+    symlink("file.txt", temp.child("real/file_link").path()).unwrap();
+    temp.child("real/sub").create_dir_all().unwrap();
+    symlink("../../other", temp.child("real/sub/dir_link").path()).unwrap();
+    symlink("real", temp.child("top").path()).unwrap();
+    let copy = |flag: &str| {
+        let dest = temp.child(format!("dest{}", flag));
+        fs::create_dir(dest.path()).unwrap();
+        Command::new(cargo::cargo_bin!("cpx"))
+            .args(["-r", flag])
+            .arg(temp.child("top").path())
+            .arg(dest.path())
+            .assert()
+            .success();
+        dest.child("top")
+    };
+    let is_link = |path: &Path| fs::symlink_metadata(path).unwrap().file_type().is_symlink();
+
+    // -P copies the link named on the command line as a link.
+    let top = copy("-P");
+    assert!(is_link(top.path()));
+    assert_eq!(fs::read_link(top.path()).unwrap(), Path::new("real"));
+
+    // -H follows it, and copies every link below it as a link.
+    let top = copy("-H");
+    assert!(!is_link(top.path()) && top.path().is_dir());
+    assert!(!is_link(top.child("file.txt").path()));
+    assert!(is_link(top.child("file_link").path()));
+    assert_eq!(
+        fs::read_link(top.child("file_link").path()).unwrap(),
+        Path::new("file.txt")
+    );
+    assert!(!is_link(top.child("sub").path()));
+    assert!(is_link(top.child("sub/dir_link").path()));
+
+    // -L follows them all.
+    let top = copy("-L");
+    assert!(!is_link(top.path()) && top.path().is_dir());
+    assert!(!is_link(top.child("file_link").path()));
+    top.child("file_link").assert("data");
+    assert!(!is_link(top.child("sub/dir_link").path()));
+    top.child("sub/dir_link/inner.txt").assert("inner");
+}
@@ -0,0 +1,112 @@
+#![cfg(unix)]
+
+mod support;
+
+use proptest::prelude::*;
+use support::{Case, EXCLUDES, Entry, Flags, Kind, MAX_ENTRIES, NAMES, Sources, Target};
+use tempfile::TempDir;
+
+fn target() -> impl Strategy<Value = Target> {
+    prop_oneof![
+        Just(Target::Missing),
+        Just(Target::Up),
+        Just(Target::Root),
+        any::<usize>().prop_map(Target::Dir),
+        any::<usize>().prop_map(Target::File),
+    ]
+}
+
+/// Shrinks toward small plain files, so a failing tree loses its links and
+/// directories first.
+fn entry() -> impl Strategy<Value = Entry> {
+    let kind = prop_oneof![
+        4 => (0..64usize).prop_map(Kind::File),
+        3 => Just(Kind::Dir),
+        1 => any::<usize>().prop_map(Kind::HardLink),
+        2 => target().prop_map(Kind::Symlink),
+    ];
+    (any::<usize>(), 0..NAMES.len(), kind).prop_map(|(parent, name, kind)| Entry {
+        parent,
+        name,
+        kind,
+    })
+}
+
+fn tree() -> impl Strategy<Value = Vec<Entry>> {
+    prop::collection::vec(entry(), 1..=MAX_ENTRIES)
+}
+
+fn flags() -> impl Strategy<Value = Flags> {
+    (
+        (0..3usize, 0..4usize, 0..3usize),
+        (
+            prop::bool::weighted(0.2),
+            prop::bool::weighted(0.15),
+            any::<bool>(),
+            any::<bool>(),
+            prop::bool::weighted(0.2),
+        ),
+        prop_oneof![8 => Just(0usize), 1 => Just(1), 1 => Just(2)],
+        prop::collection::vec(0..EXCLUDES.len(), 0..=2),
+    )
+        .prop_map(
+            |(
+                (follow, collision, order),
+                (parents, flatten, itemize, links, reproducible),
+                link_mode,
+                excludes,
+            )| Flags {
+                follow,
+                collision,
+                order,
+                parents,
+                flatten,
+                itemize,
+                links,
+                reproducible,
+                link_mode,
+                excludes,
+            },
+        )
+}
+
+fn sources() -> impl Strategy<Value = Sources> {
+    let name = (0..2usize, 0..NAMES.len());
+    prop_oneof![
+        Just(Sources::Tree),
+        name.clone()
+            .prop_map(|(tree, name)| Sources::One { tree, name }),
+        prop::collection::vec(name, 1..=5).prop_map(Sources::Several),
+    ]
+}
+
+fn case() -> impl Strategy<Value = Case> {
+    (tree(), tree(), flags(), sources()).prop_map(|(one, two, flags, sources)| Case {
+        trees: [one, two],
+        flags,
+        sources,
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(300))]
+
+    /// Plan random trees with random options and check what every plan
+    /// must hold whatever the options: everything lands under the
+    /// destination, nothing is written twice, the totals match the tasks,
+    /// nothing excluded is planned, and without `-L` no symlink is
+    /// followed. `PROPTEST_CASES` runs more cases than the default.
+    #[test]
+    fn test_random_plans_hold_their_invariants(case in case()) {
+        let temp = TempDir::new().unwrap();
+        // A refused case has nothing to check.
+        if let Some((plan, dest, options)) = case.plan(temp.path()) {
+            let violations = support::violations(&plan, temp.path(), &dest, &options);
+            prop_assert!(
+                violations.is_empty(),
+                "planned wrongly:\n  {}",
+                violations.join("\n  ")
+            );
+        }
+    }
+}
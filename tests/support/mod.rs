@@ -0,0 +1,285 @@
+use cpx::cli::args::{CollisionPolicy, CopyOptions, CopyOrder, FollowSymlink, SymlinkMode};
+use cpx::utility::exclude::{build_exclude_rules, parse_exclude_pattern_list, should_exclude};
+use cpx::utility::preprocess::{
+    CopyPlan, flatten, preprocess_directory, preprocess_file, preprocess_multiple,
+};
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+/// Names are few so that siblings and sources collide, and some match the
+/// exclude patterns.
+pub const NAMES: &[&str] = &["a", "b", "d", "e", "x.tmp", "y.txt"];
+pub const EXCLUDES: &[&str] = &["x.tmp", "*.tmp", "d/", "e", "*.txt"];
+
+/// Most entries in one tree.
+pub const MAX_ENTRIES: usize = 24;
+
+/// One entry of a random tree. Every index picks, wrapping around, among
+/// the names or what the tree holds by the time the entry is made, so any
+/// number is a valid choice.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// A directory made so far, the tree's root first.
+    pub parent: usize,
+    pub name: usize,
+    pub kind: Kind,
+}
+
+#[derive(Debug, Clone)]
+pub enum Kind {
+    /// A file of this many bytes.
+    File(usize),
+    Dir,
+    /// A hard link to a file made so far, or a new file if there is none.
+    HardLink(usize),
+    Symlink(Target),
+}
+
+/// Where a symlink points.
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// Nowhere.
+    Missing,
+    /// The directory it is in, or the one above: a loop.
+    Up,
+    /// The tree's root, by absolute path: a loop as well.
+    Root,
+    /// A directory made so far, relative to the link.
+    Dir(usize),
+    /// A file made so far, relative to the link; dangling if there is none.
+    File(usize),
+}
+
+/// The options a case plans with.
+#[derive(Debug, Clone, Default)]
+pub struct Flags {
+    pub follow: usize,
+    pub collision: usize,
+    pub order: usize,
+    pub parents: bool,
+    /// Ignored along with `parents`.
+    pub flatten: bool,
+    pub itemize: bool,
+    pub links: bool,
+    pub reproducible: bool,
+    /// Copy, `-l` or `-s`.
+    pub link_mode: usize,
+    /// Exclude patterns, if any.
+    pub excludes: Vec<usize>,
+}
+
+/// What is named on the command line.
+#[derive(Debug, Clone)]
+pub enum Sources {
+    /// The first tree, as one directory.
+    Tree,
+    /// One name in one of the trees.
+    One { tree: usize, name: usize },
+    /// Names in either tree, into a destination directory.
+    Several(Vec<(usize, usize)>),
+}
+
+/// Two trees to copy from, the options and the sources: everything the
+/// planner is given.
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub trees: [Vec<Entry>; 2],
+    pub flags: Flags,
+    pub sources: Sources,
+}
+
+fn pick<T>(items: &[T], index: usize) -> &T {
+    &items[index % items.len()]
+}
+
+/// Make `entries` under `root`. Entries whose name is taken are skipped.
+pub fn make_tree(entries: &[Entry], root: &Path) {
+    fs::create_dir_all(root).unwrap();
+    let mut dirs = vec![PathBuf::new()];
+    let mut files: Vec<PathBuf> = Vec::new();
+    for entry in entries {
+        let parent = pick(&dirs, entry.parent).clone();
+        let relative = parent.join(pick(NAMES, entry.name));
+        let path = root.join(&relative);
+        if fs::symlink_metadata(&path).is_ok() {
+            continue;
+        }
+        match &entry.kind {
+            Kind::HardLink(file) if !files.is_empty() => {
+                fs::hard_link(root.join(pick(&files, *file)), &path).unwrap();
+                files.push(relative);
+            }
+            Kind::File(size) | Kind::HardLink(size) => {
+                fs::write(&path, vec![b'x'; size % 64]).unwrap();
+                files.push(relative);
+            }
+            Kind::Dir => {
+                fs::create_dir(&path).unwrap();
+                dirs.push(relative);
+            }
+            Kind::Symlink(target) => {
+                let up = PathBuf::from("../".repeat(parent.components().count()));
+                let target = match target {
+                    Target::Missing => PathBuf::from("missing"),
+                    Target::Up if parent.as_os_str().is_empty() => PathBuf::from("."),
+                    Target::Up => PathBuf::from(".."),
+                    Target::Root => root.to_path_buf(),
+                    Target::Dir(dir) => up.join(pick(&dirs, *dir)),
+                    Target::File(_) if files.is_empty() => PathBuf::from("missing"),
+                    Target::File(file) => up.join(pick(&files, *file)),
+                };
+                let target = if target.as_os_str().is_empty() {
+                    PathBuf::from(".")
+                } else {
+                    target
+                };
+                symlink(target, &path).unwrap();
+            }
+        }
+    }
+}
+
+impl Flags {
+    pub fn options(&self) -> CopyOptions {
+        let mut options = CopyOptions::none();
+        options.recursive = true;
+        options.follow_symlink = *pick(
+            &[
+                FollowSymlink::NoDereference,
+                FollowSymlink::Dereference,
+                FollowSymlink::CommandLineSymlink,
+            ],
+            self.follow,
+        );
+        options.on_collision = *pick(
+            &[
+                CollisionPolicy::Error,
+                CollisionPolicy::Number,
+                CollisionPolicy::Skip,
+                CollisionPolicy::LastWins,
+            ],
+            self.collision,
+        );
+        options.order = *pick(
+            &[CopyOrder::Size, CopyOrder::Name, CopyOrder::None],
+            self.order,
+        );
+        options.parents = self.parents;
+        options.flatten = self.flatten && !self.parents;
+        options.itemize = self.itemize;
+        options.preserve.links = self.links;
+        options.overrides.reproducible = self.reproducible;
+        match self.link_mode % 3 {
+            1 => options.hard_link = true,
+            2 => options.symbolic_link = Some(SymlinkMode::Absolute),
+            _ => {}
+        }
+        if !self.excludes.is_empty() {
+            let patterns: Vec<&str> = self.excludes.iter().map(|i| *pick(EXCLUDES, *i)).collect();
+            let patterns = parse_exclude_pattern_list(&patterns.join(",")).unwrap();
+            options.exclude_rules = build_exclude_rules(patterns).unwrap();
+        }
+        options
+    }
+}
+
+impl Case {
+    /// Make the trees under `temp` and plan the copy, returning the plan,
+    /// the directory it copies into and the options. `None` when the
+    /// planner refused it, as it may: colliding sources under
+    /// `--on-collision=error`, for one.
+    pub fn plan(&self, temp: &Path) -> Option<(CopyPlan, PathBuf, CopyOptions)> {
+        let roots = [temp.join("one"), temp.join("two")];
+        for (entries, root) in self.trees.iter().zip(&roots) {
+            make_tree(entries, root);
+        }
+        let options = self.flags.options();
+        let dest = temp.join("dest");
+
+        let result = match &self.sources {
+            Sources::Tree => preprocess_directory(&roots[0], temp, &dest, &options),
+            Sources::One { tree, name } => {
+                fs::create_dir(&dest).unwrap();
+                let source = pick(&roots, *tree).join(pick(NAMES, *name));
+                let metadata = match options.follow_symlink {
+                    FollowSymlink::NoDereference => fs::symlink_metadata(&source),
+                    _ => fs::metadata(&source),
+                }
+                .ok()?;
+                let parent = source.parent().unwrap();
+                if metadata.is_dir() {
+                    preprocess_directory(&source, parent, &dest, &options)
+                } else {
+                    preprocess_file(
+                        &source,
+                        parent,
+                        &dest,
+                        &options,
+                        metadata,
+                        fs::metadata(&dest).ok(),
+                    )
+                }
+            }
+            Sources::Several(names) => {
+                fs::create_dir(&dest).unwrap();
+                let sources: Vec<PathBuf> = names
+                    .iter()
+                    .map(|(tree, name)| pick(&roots, *tree).join(pick(NAMES, *name)))
+                    .collect();
+                if sources.is_empty() {
+                    return None;
+                }
+                preprocess_multiple(&sources, &dest, &options)
+            }
+        };
+        let mut plan = result.ok()?;
+        if options.flatten {
+            flatten(&mut plan, &dest, options.on_collision).ok()?;
+        }
+        Some((plan, dest, options))
+    }
+}
+
+/// Whether anything from `from` down to `path` is a symlink.
+fn through_symlink(from: &Path, path: &Path) -> bool {
+    path.ancestors()
+        .take_while(|a| a.starts_with(from) && *a != from)
+        .any(|a| fs::symlink_metadata(a).is_ok_and(|m| m.file_type().is_symlink()))
+}
+
+/// What is wrong with `plan`, made from trees under `temp` to copy into
+/// `dest`: what [`CopyPlan::violations`] finds, excluded names that are
+/// planned anyway, and without `-L` anything reached through a symlink.
+pub fn violations(plan: &CopyPlan, temp: &Path, dest: &Path, options: &CopyOptions) -> Vec<String> {
+    let mut violations = plan.violations(dest);
+    // Every exclude pattern is a name, and names are kept in the copy, so
+    // they are matched there: the sources of a walk through a followed
+    // link are where the link leads, under other names.
+    if let Some(rules) = &options.exclude_rules {
+        let destinations = plan
+            .written()
+            .chain(plan.directories.iter().map(|t| t.destination.as_path()));
+        for destination in destinations {
+            if should_exclude(destination, dest, rules) {
+                violations.push(format!(
+                    "'{}' is excluded but planned",
+                    destination.display()
+                ));
+            }
+        }
+    }
+    if matches!(options.follow_symlink, FollowSymlink::NoDereference) {
+        let sources = (plan.files.iter().map(|t| t.source.as_path()))
+            .chain(plan.directories.iter().filter_map(|t| t.source.as_deref()))
+            .chain(plan.hardlinks.iter().map(|t| t.source.as_path()))
+            .chain(plan.specials.iter().map(|t| t.source.as_path()));
+        for source in sources.filter(|source| through_symlink(temp, source)) {
+            violations.push(format!(
+                "'{}' is reached through a symlink without -L",
+                source.display()
+            ));
+        }
+    }
+    violations
+}